@@ -0,0 +1,135 @@
+//! Counts the heap allocations made while serving a discovery of all
+//! characteristics (a READ_BY_TYPE_REQ for the characteristic declaration
+//! type) against a 100-attribute database, by the handler of the server and by
+//! the one it replaced, which assembled the response out of the PDL builders
+//! one element at a time. Both run on the same database and request, so only
+//! the way the response is built differs.
+//!
+//! Run with `cargo bench --features benchmarking`.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    hint::black_box,
+    rc::Rc,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+use bluetooth_core::{
+    core::{shared_box::SharedBox, uuid::Uuid},
+    gatt::{
+        ids::{AttHandle, TransportIndex},
+        mocks::mock_datastore::MockDatastore,
+        server::{
+            benchmarking::{read_by_type, read_by_type_with_builders},
+            gatt_database::{
+                AttPermissions, CharacteristicBuilder, GattDatabase, ServiceBuilder,
+                CHARACTERISTIC_UUID,
+            },
+        },
+    },
+    packets::{
+        AttReadByTypeRequestBuilder, AttReadByTypeResponseView, OwnedAttView, OwnedPacket, Packet,
+    },
+    utils::packet::build_view_or_crash,
+};
+use tokio::runtime::Builder;
+
+/// Counts every allocation (and reallocation) made by the process
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const TCB_IDX: TransportIndex = TransportIndex(1);
+const MTU: usize = 517;
+const ITERATIONS: usize = 10_000;
+
+/// Each service occupies 1 + 2 * CHARACTERISTICS_PER_SERVICE handles, so this
+/// gives 100 attributes in total
+const SERVICES: u32 = 4;
+const CHARACTERISTICS_PER_SERVICE: u32 = 12;
+
+fn make_db() -> SharedBox<GattDatabase> {
+    let gatt_db = SharedBox::new(GattDatabase::new());
+    let (datastore, _) = MockDatastore::new();
+    let datastore = Rc::new(datastore);
+    for service in 0..SERVICES {
+        let mut builder = ServiceBuilder::new(Uuid::new(0x1800 + service));
+        for characteristic in 0..CHARACTERISTICS_PER_SERVICE {
+            builder = builder.characteristic(
+                CharacteristicBuilder::new(
+                    Uuid::new(0x2A00 + characteristic),
+                    AttPermissions::READABLE,
+                )
+                .static_value(vec![characteristic as u8; 4]),
+            );
+        }
+        gatt_db.add_service(builder, datastore.clone()).unwrap();
+    }
+    gatt_db
+}
+
+/// Run the operation ITERATIONS times, and report the allocations made by
+/// (and the time taken for) each run
+fn measure(name: &str, mut operation: impl FnMut()) {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        operation();
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    println!(
+        "{name}: {} allocations, {:?} per response",
+        allocations / ITERATIONS,
+        elapsed / ITERATIONS as u32
+    );
+}
+
+fn main() {
+    let rt = Builder::new_current_thread().build().unwrap();
+    let gatt_db = make_db();
+    let db = gatt_db.get_att_database(TCB_IDX);
+    let request = build_view_or_crash(AttReadByTypeRequestBuilder {
+        starting_handle: AttHandle(1).into(),
+        ending_handle: AttHandle::MAX.into(),
+        attribute_type: CHARACTERISTIC_UUID.into(),
+    });
+
+    // both handlers must serve the same response, or the comparison is moot
+    let response = rt.block_on(read_by_type(request.view(), MTU, &db)).unwrap();
+    let baseline = rt.block_on(read_by_type_with_builders(request.view(), MTU, &db)).unwrap();
+    assert_eq!(response, baseline);
+    let response = OwnedAttView::try_parse(response.into_boxed_slice()).unwrap();
+    let response = AttReadByTypeResponseView::try_parse(response.view()).unwrap();
+    println!("{} characteristics discovered", response.get_data_iter().count());
+
+    measure("builders (baseline)", || {
+        let response = rt.block_on(read_by_type_with_builders(request.view(), MTU, &db));
+        black_box(response.unwrap());
+    });
+
+    measure("single buffer", || {
+        let response = rt.block_on(read_by_type(request.view(), MTU, &db));
+        black_box(response.unwrap());
+    });
+}
@@ -0,0 +1,10 @@
+//! Fuzzes the ATT server with untrusted PDUs. See
+//! bluetooth_core::gatt::server::fuzzing for the format of the input.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    bluetooth_core::gatt::server::fuzzing::fuzz_att_server(data);
+});
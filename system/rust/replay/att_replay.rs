@@ -0,0 +1,33 @@
+//! Replays the PDUs that clients sent in a btsnoop capture into the ATT server,
+//! and reports the replies that differ from the captured ones. See
+//! bluetooth_core::gatt::server::replay for the format of the database
+//! description.
+//!
+//! Usage: att_replay <capture> <database description>
+
+use std::{env, fs, process::ExitCode};
+
+use anyhow::{bail, Context, Result};
+
+use bluetooth_core::gatt::server::replay::{replay_capture, DatabaseDescription};
+
+/// The MTU offered by the replayed server, the largest allowed (as on Android)
+const SERVER_RX_MTU: usize = 517;
+
+fn main() -> Result<ExitCode> {
+    let args = env::args().collect::<Vec<_>>();
+    let [_, capture, database] = args.as_slice() else {
+        bail!("usage: att_replay <capture> <database description>");
+    };
+    let capture = fs::read(capture).with_context(|| format!("reading {capture}"))?;
+    let database = fs::read_to_string(database)
+        .with_context(|| format!("reading {database}"))?
+        .parse::<DatabaseDescription>()?;
+
+    let report = replay_capture(&capture, &database, SERVER_RX_MTU)?;
+    for mismatch in &report.mismatches {
+        println!("{mismatch}");
+    }
+    println!("{} request(s) replayed, {} mismatch(es)", report.requests, report.mismatches.len());
+    Ok(if report.mismatches.is_empty() { ExitCode::SUCCESS } else { ExitCode::FAILURE })
+}
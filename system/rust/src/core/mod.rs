@@ -12,7 +12,9 @@ use cxx::UniquePtr;
 
 use crate::{
     connection::{LeAclManagerImpl, LeAclManagerShim},
-    gatt::ffi::{AttTransportImpl, GattCallbacksImpl},
+    gatt::ffi::{
+        AttTransportImpl, GattCallbacksImpl, LinkSecurityRequesterImpl, SecurityManagerImpl,
+    },
     GlobalModuleRegistry, MainThreadTxMessage, GLOBAL_MODULE_REGISTRY,
 };
 
@@ -27,6 +29,7 @@ fn start(
         GlobalModuleRegistry::start(
             Rc::new(GattCallbacksImpl(gatt_server_callbacks)),
             Rc::new(AttTransportImpl()),
+            Rc::new(SecurityManagerImpl(LinkSecurityRequesterImpl())),
             LeAclManagerImpl(le_acl_manager),
             || {
                 future_ready(on_started);
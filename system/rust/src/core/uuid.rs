@@ -1,5 +1,9 @@
 //! A UUID (See Core Spec 5.3 Vol 1E 2.9.1. Basic Types)
 
+use std::{fmt, str::FromStr};
+
+use anyhow::{anyhow, bail};
+
 use crate::packets::{
     ParseError, Uuid128Builder, Uuid128View, Uuid16Builder, Uuid16View, UuidBuilder, UuidView,
 };
@@ -9,12 +13,16 @@ use crate::packets::{
 /// Note that the underlying storage is BIG-ENDIAN! But this should be viewed
 /// as an implementation detail for C++ interop ONLY - all converters etc.
 /// should act as though the backing storage is LITTLE-ENDIAN.
-#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
 #[repr(transparent)]
 pub struct Uuid([u8; 16]);
 
 const BASE_UUID: u128 = 0x00000000_0000_1000_8000_0080_5F9B_34FB;
 
+/// The bits of a UUID that are fixed by the Bluetooth Base UUID, for those
+/// with a 16-bit or 32-bit form
+const BASE_UUID_MASK: u128 = (1u128 << 96) - 1;
+
 impl Uuid {
     /// Constructor from a u32.
     pub const fn new(val: u32) -> Self {
@@ -31,21 +39,80 @@ impl Uuid {
         out.reverse();
         out
     }
+
+    /// The 32-bit form of this UUID, if it is derived from the Bluetooth Base
+    /// UUID (Core Spec 5.3 Vol 3B 2.5.1)
+    pub fn to_u32(&self) -> Option<u32> {
+        let backing = u128::from_be_bytes(self.0);
+        (backing & BASE_UUID_MASK == BASE_UUID).then_some((backing >> 96) as u32)
+    }
+
+    /// The 16-bit form of this UUID, if it is derived from the Bluetooth Base
+    /// UUID and fits
+    pub fn to_u16(&self) -> Option<u16> {
+        self.to_u32().and_then(|val| val.try_into().ok())
+    }
+
+    /// Parse a little-endian UUID of any of the lengths used by ATT (16, 32,
+    /// or 128 bits), or return None if the length is invalid.
+    pub fn try_from_le_slice(bytes: &[u8]) -> Option<Self> {
+        Some(match *bytes {
+            [b0, b1] => Self::new(u16::from_le_bytes([b0, b1]) as u32),
+            [b0, b1, b2, b3] => Self::new(u32::from_le_bytes([b0, b1, b2, b3])),
+            // TODO(aryarahul) - should we handle >16 byte Uuids and drop extra bytes?
+            _ => Self::new_from_le_bytes(bytes.try_into().ok()?),
+        })
+    }
+}
+
+/// Parses the 16-bit ("180a") and 32-bit ("0000180a") forms, as well as the
+/// full 128-bit form ("0000180a-0000-1000-8000-00805f9b34fb")
+impl FromStr for Uuid {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |digits: &str| {
+            if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+                bail!("invalid UUID {s:?}");
+            }
+            u128::from_str_radix(digits, 16).map_err(|_| anyhow!("invalid UUID {s:?}"))
+        };
+        match s.len() {
+            4 | 8 => Ok(Self::new(parse(s)? as u32)),
+            36 => {
+                let groups = s.split('-').collect::<Vec<_>>();
+                if groups.iter().map(|group| group.len()).ne([8, 4, 4, 4, 12]) {
+                    bail!("invalid UUID {s:?}");
+                }
+                Ok(Self(parse(&groups.concat())?.to_be_bytes()))
+            }
+            _ => bail!("invalid UUID {s:?}"),
+        }
+    }
+}
+
+/// Formats the full 128-bit form, e.g. "0000180a-0000-1000-8000-00805f9b34fb"
+impl fmt::Display for Uuid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let val = u128::from_be_bytes(self.0);
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+            val >> 96,
+            (val >> 80) & 0xFFFF,
+            (val >> 64) & 0xFFFF,
+            (val >> 48) & 0xFFFF,
+            val & 0xFFFF_FFFF_FFFF
+        )
+    }
 }
 
 impl TryFrom<UuidView<'_>> for Uuid {
     type Error = ParseError;
 
     fn try_from(value: UuidView<'_>) -> Result<Self, ParseError> {
-        let bytes = value.get_data_iter().collect::<Vec<_>>();
-        Ok(match bytes.len() {
-            2 => Self::new(u16::from_le_bytes([bytes[0], bytes[1]]) as u32),
-            4 => Self::new(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
-            // TODO(aryarahul) - should we handle >16 byte Uuids and drop extra bytes?
-            _ => Self::new_from_le_bytes(
-                bytes.try_into().map_err(|_| ParseError::OutOfBoundsAccess)?,
-            ),
-        })
+        Self::try_from_le_slice(&value.get_data_iter().collect::<Vec<_>>())
+            .ok_or(ParseError::OutOfBoundsAccess)
     }
 }
 
@@ -66,10 +133,14 @@ impl From<Uuid128View<'_>> for Uuid {
     }
 }
 
+/// Uses the 16-bit form where possible. Otherwise the 128-bit form is used,
+/// since ATT does not permit 32-bit UUIDs (Core Spec 5.3 Vol 3F 3.2.1).
 impl From<Uuid> for UuidBuilder {
     fn from(value: Uuid) -> Self {
-        // TODO(aryarahul): compress to UUID-16 if possible
-        UuidBuilder { data: value.le_bytes().into_iter().collect() }
+        match value.to_u16() {
+            Some(data) => UuidBuilder { data: data.to_le_bytes().into() },
+            None => UuidBuilder { data: value.le_bytes().into_iter().collect() },
+        }
     }
 }
 
@@ -77,13 +148,7 @@ impl TryFrom<Uuid> for Uuid16Builder {
     type Error = Uuid;
 
     fn try_from(value: Uuid) -> Result<Self, Self::Error> {
-        let backing = u128::from_be_bytes(value.0);
-        if backing & ((1u128 << 96) - 1) == BASE_UUID {
-            if let Ok(data) = u16::try_from(backing >> 96) {
-                return Ok(Uuid16Builder { data });
-            }
-        }
-        Err(value)
+        value.to_u16().map(|data| Uuid16Builder { data }).ok_or(value)
     }
 }
 
@@ -138,6 +203,56 @@ mod test {
         assert_eq!(builder.data[12..], [4, 3, 2, 1]);
     }
 
+    #[test]
+    fn test_uuid_builder_compressed() {
+        let uuid = Uuid::new(0x0102);
+        let builder: UuidBuilder = uuid.into();
+        assert_eq!(*builder.data, [2, 1]);
+    }
+
+    #[test]
+    fn test_uuid_builder_not_base_derived() {
+        let uuid = Uuid::new_from_le_bytes([1; 16]);
+        let builder: UuidBuilder = uuid.into();
+        assert_eq!(*builder.data, [1; 16]);
+    }
+
+    #[test]
+    fn test_short_forms() {
+        assert_eq!(Uuid::new(0x0102).to_u16(), Some(0x0102));
+        assert_eq!(Uuid::new(0x0102).to_u32(), Some(0x0102));
+        assert_eq!(Uuid::new(0x01020304).to_u16(), None);
+        assert_eq!(Uuid::new(0x01020304).to_u32(), Some(0x01020304));
+        assert_eq!(Uuid::new_from_le_bytes([1; 16]).to_u32(), None);
+    }
+
+    #[test]
+    fn test_parse_short_forms() {
+        assert_eq!("180A".parse::<Uuid>().unwrap(), Uuid::new(0x180A));
+        assert_eq!("0102abcd".parse::<Uuid>().unwrap(), Uuid::new(0x0102ABCD));
+    }
+
+    #[test]
+    fn test_parse_long_form() {
+        let uuid = "0000180a-0000-1000-8000-00805f9b34fb".parse::<Uuid>().unwrap();
+        assert_eq!(uuid, Uuid::new(0x180A));
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        for s in ["", "180", "+180", "18 0A", "0000180a00000-1000-8000-00805f9b34fb", "xyz0"] {
+            assert!(s.parse::<Uuid>().is_err(), "{s:?}");
+        }
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        let uuid = Uuid::new_from_le_bytes([1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16]);
+        let s = uuid.to_string();
+        assert_eq!(s, "100f0e0d-0c0b-0a09-0807-060504030201");
+        assert_eq!(s.parse::<Uuid>().unwrap(), uuid);
+    }
+
     #[test]
     fn test_uuid_from_16_fixed_view() {
         let expected = Uuid::new(0x0102);
@@ -187,4 +302,11 @@ mod test {
         let res = Uuid::try_from(packet.view());
         assert!(res.is_err());
     }
+
+    #[test]
+    fn test_uuid_from_le_slice_in_both_formats() {
+        let short = Uuid::try_from_le_slice(&[0x00, 0x18]).unwrap();
+        let long = Uuid::try_from_le_slice(&Uuid::new(0x1800).le_bytes()).unwrap();
+        assert_eq!(short, long);
+    }
 }
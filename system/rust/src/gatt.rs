@@ -4,11 +4,14 @@
 pub mod arbiter;
 pub mod callbacks;
 pub mod channel;
+pub mod client;
 pub mod ffi;
+pub mod identity_resolver;
 pub mod ids;
 pub mod mocks;
 mod mtu;
 pub mod opcode_types;
+pub mod security_manager;
 pub mod server;
 
 pub use self::callbacks::GattCallbacks;
@@ -1,5 +1,9 @@
 //! This module handles "arbitration" of ATT packets, to determine whether they
-//! should be handled by the primary stack or by the Rust stack
+//! should be handled by the primary stack or by the Rust stack. Connections to
+//! isolated servers are handed to the Rust stack as a whole, while those shared
+//! by both stacks are arbitrated PDU by PDU (see the shared_connection module).
+
+pub mod shared_connection;
 
 use std::sync::{Arc, Mutex};
 
@@ -12,8 +16,8 @@ use crate::{
 };
 
 use super::{
-    ffi::{InterceptAction, StoreCallbacksFromRust},
-    ids::{AdvertiserId, TransportIndex},
+    ffi::{clear_link_security, on_link_security_changed, InterceptAction, StoreCallbacksFromRust},
+    ids::{AdvertiserId, EattCid, TransportIndex},
     mtu::MtuEvent,
     opcode_types::{classify_opcode, OperationType},
     server::isolation_manager::IsolationManager,
@@ -31,10 +35,16 @@ pub fn initialize_arbiter() -> Arc<Mutex<IsolationManager>> {
     StoreCallbacksFromRust(
         on_le_connect,
         on_le_disconnect,
+        on_br_edr_connect,
+        on_br_edr_disconnect,
         intercept_packet,
         |tcb_idx| on_mtu_event(TransportIndex(tcb_idx), MtuEvent::OutgoingRequest),
         |tcb_idx, mtu| on_mtu_event(TransportIndex(tcb_idx), MtuEvent::IncomingResponse(mtu)),
         |tcb_idx, mtu| on_mtu_event(TransportIndex(tcb_idx), MtuEvent::IncomingRequest(mtu)),
+        on_eatt_bearer_open,
+        on_eatt_bearer_close,
+        intercept_eatt_packet,
+        on_link_security_changed,
     );
 
     arbiter
@@ -80,6 +90,24 @@ fn try_parse_att_server_packet(
     }
 }
 
+/// Like try_parse_att_server_packet(), but for a packet received on an EATT
+/// bearer. The MTU of an EATT bearer is fixed by L2CAP rather than exchanged,
+/// so MTU requests are not snooped on, but handed over (and rejected) as well.
+fn try_parse_eatt_server_packet(
+    isolation_manager: &IsolationManager,
+    tcb_idx: TransportIndex,
+    packet: Box<[u8]>,
+) -> Option<OwnedAttView> {
+    isolation_manager.get_server_id(tcb_idx)?;
+
+    let att = OwnedAttView::try_parse(packet).ok()?;
+
+    match classify_opcode(att.view().get_opcode()) {
+        OperationType::Command | OperationType::Request | OperationType::Confirmation => Some(att),
+        _ => None,
+    }
+}
+
 fn on_le_connect(tcb_idx: u8, advertiser: u8) {
     let tcb_idx = TransportIndex(tcb_idx);
     let advertiser = AdvertiserId(advertiser);
@@ -94,6 +122,8 @@ fn on_le_connect(tcb_idx: u8, advertiser: u8) {
 }
 
 fn on_le_disconnect(tcb_idx: u8) {
+    clear_link_security(TransportIndex(tcb_idx));
+
     // Events may be received after a FactoryReset
     // is initiated for Bluetooth and the rust arbiter is taken
     // down.
@@ -113,6 +143,58 @@ fn on_le_disconnect(tcb_idx: u8) {
     }
 }
 
+#[cfg(not(feature = "le_only"))]
+fn on_br_edr_connect(tcb_idx: u8, mtu: u16) {
+    if !has_arbiter() {
+        warn!("arbiter is not yet initialized");
+        return;
+    }
+
+    let tcb_idx = TransportIndex(tcb_idx);
+    // connections over BR/EDR are not made to an advertising set, so they are
+    // isolated to the server selected by the upper layer, if any
+    if let Some(server_id) = with_arbiter(|arbiter| arbiter.br_edr_server()) {
+        do_in_rust_thread(move |modules| {
+            if let Err(err) = modules.gatt_module.on_br_edr_connect(tcb_idx, server_id, mtu.into())
+            {
+                error!("{err:?}")
+            }
+        })
+    }
+}
+
+#[cfg(not(feature = "le_only"))]
+fn on_br_edr_disconnect(tcb_idx: u8) {
+    clear_link_security(TransportIndex(tcb_idx));
+
+    if !has_arbiter() {
+        warn!("arbiter is not yet initialized");
+        return;
+    }
+
+    let tcb_idx = TransportIndex(tcb_idx);
+    let was_isolated = with_arbiter(|arbiter| arbiter.is_connection_isolated(tcb_idx));
+    if was_isolated {
+        do_in_rust_thread(move |modules| {
+            if let Err(err) = modules.gatt_module.on_le_disconnect(tcb_idx) {
+                error!("{err:?}")
+            }
+        })
+    }
+}
+
+// the C++ stack may still report BR/EDR links (e.g. if the controller supports
+// them after all), so these are left to it
+#[cfg(feature = "le_only")]
+fn on_br_edr_connect(tcb_idx: u8, _mtu: u16) {
+    warn!("leaving connection over BR/EDR on tcb_idx {tcb_idx} to the legacy stack in an LE-only build");
+}
+
+#[cfg(feature = "le_only")]
+fn on_br_edr_disconnect(tcb_idx: u8) {
+    clear_link_security(TransportIndex(tcb_idx));
+}
+
 fn intercept_packet(tcb_idx: u8, packet: Vec<u8>) -> InterceptAction {
     // Events may be received after a FactoryReset
     // is initiated for Bluetooth and the rust arbiter is taken
@@ -140,6 +222,65 @@ fn intercept_packet(tcb_idx: u8, packet: Vec<u8>) -> InterceptAction {
     }
 }
 
+fn on_eatt_bearer_open(tcb_idx: u8, cid: u16, mtu: u16) {
+    if !has_arbiter() {
+        warn!("arbiter is not yet initialized");
+        return;
+    }
+
+    let tcb_idx = TransportIndex(tcb_idx);
+    let cid = EattCid(cid);
+    if with_arbiter(|arbiter| arbiter.is_connection_isolated(tcb_idx)) {
+        do_in_rust_thread(move |modules| {
+            if let Err(err) = modules.gatt_module.on_eatt_bearer_open(tcb_idx, cid, mtu.into()) {
+                error!("{err:?}")
+            }
+        })
+    }
+}
+
+fn on_eatt_bearer_close(tcb_idx: u8, cid: u16) {
+    if !has_arbiter() {
+        warn!("arbiter is not yet initialized");
+        return;
+    }
+
+    let tcb_idx = TransportIndex(tcb_idx);
+    let cid = EattCid(cid);
+    if with_arbiter(|arbiter| arbiter.is_connection_isolated(tcb_idx)) {
+        do_in_rust_thread(move |modules| {
+            if let Err(err) = modules.gatt_module.on_eatt_bearer_close(tcb_idx, cid) {
+                error!("{err:?}")
+            }
+        })
+    }
+}
+
+fn intercept_eatt_packet(tcb_idx: u8, cid: u16, packet: Vec<u8>) -> InterceptAction {
+    if !has_arbiter() {
+        warn!("arbiter is not yet initialized");
+        return InterceptAction::Drop;
+    }
+
+    let tcb_idx = TransportIndex(tcb_idx);
+    let cid = EattCid(cid);
+    if let Some(att) = with_arbiter(|arbiter| {
+        try_parse_eatt_server_packet(arbiter, tcb_idx, packet.into_boxed_slice())
+    }) {
+        do_in_rust_thread(move |modules| {
+            trace!("pushing packet to GATT on EATT bearer {cid:?}");
+            if let Some(bearer) = modules.gatt_module.get_eatt_bearer(tcb_idx, cid) {
+                bearer.handle_packet(att.view())
+            } else {
+                error!("EATT bearer {cid:?} on {tcb_idx:?} not found");
+            }
+        });
+        InterceptAction::Drop
+    } else {
+        InterceptAction::Forward
+    }
+}
+
 fn on_mtu_event(tcb_idx: TransportIndex, event: MtuEvent) {
     if with_arbiter(|arbiter| arbiter.is_connection_isolated(tcb_idx)) {
         do_in_rust_thread(move |modules| {
@@ -247,4 +388,38 @@ mod test {
 
         assert!(out.is_none());
     }
+
+    #[test]
+    fn test_eatt_mtu_request_captured_when_isolated() {
+        let isolation_manager = create_manager_with_isolated_connection(TCB_IDX, SERVER_ID);
+        let packet = AttBuilder {
+            opcode: AttOpcode::EXCHANGE_MTU_REQUEST,
+            _child_: AttExchangeMtuRequestBuilder { mtu: 64 }.into(),
+        };
+
+        let out = try_parse_eatt_server_packet(
+            &isolation_manager,
+            TCB_IDX,
+            packet.to_vec().unwrap().into(),
+        );
+
+        assert!(out.is_some());
+    }
+
+    #[test]
+    fn test_eatt_packet_bypass_when_not_isolated() {
+        let isolation_manager = IsolationManager::new();
+        let packet = AttBuilder {
+            opcode: AttOpcode::READ_REQUEST,
+            _child_: AttReadRequestBuilder { attribute_handle: AttHandle(1).into() }.into(),
+        };
+
+        let out = try_parse_eatt_server_packet(
+            &isolation_manager,
+            TCB_IDX,
+            packet.to_vec().unwrap().into(),
+        );
+
+        assert!(out.is_none());
+    }
 }
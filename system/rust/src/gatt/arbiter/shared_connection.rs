@@ -0,0 +1,529 @@
+//! This module arbitrates the ATT traffic of connections shared by the legacy
+//! C++ GATT server and the Rust one, during the migration. Rather than handing
+//! a whole connection to one of the stacks (as for isolated servers), each PDU
+//! from the client goes to the stack owning the handles it targets: those of
+//! the services registered with the Rust server go to it, and all the others
+//! to the legacy stack.
+//!
+//! Since a client may only have a single request outstanding (Core Spec 5.3
+//! Vol 3F 3.3.2), the arbiter records which stack owns the current
+//! transaction, and only lets that stack reply to it. In particular:
+//!  - The legacy stack owns the MTU exchange. The Rust server snoops on it (see
+//!    MtuEvent), so the two always agree on the MTU.
+//!  - Discovery requests over a handle range are narrowed to the run of
+//!    handles owned by the stack holding the start of the range. If that stack
+//!    finds nothing there, the request is reissued over the following runs,
+//!    so the client sees a single reply covering the whole range.
+//!  - Prepared writes may be queued on both stacks, in which case an Execute
+//!    Write Request is reissued to the second stack once the first succeeds.
+//!
+//! The arbiter is sans-IO: it is handed each PDU, and returns where it goes.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::RangeInclusive,
+};
+
+use log::warn;
+
+use crate::{
+    gatt::{
+        ids::{AttHandle, TransportIndex},
+        opcode_types::{classify_opcode, OperationType},
+    },
+    packets::{AttErrorCode, AttOpcode},
+};
+
+/// One of the GATT servers sharing a connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Stack {
+    /// The legacy C++ GATT server
+    Legacy,
+    /// The Rust GATT server
+    Rust,
+}
+
+/// What to do with a PDU received from the client
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Routing {
+    /// Hand the PDU to the given stack
+    Forward(Stack),
+    /// Hand this rewritten PDU to the given stack instead
+    ForwardRewritten(Stack, Vec<u8>),
+    /// Drop the PDU, which neither stack may handle
+    Drop,
+}
+
+/// What to do with a PDU that one of the stacks is about to send to the client
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReplyRouting {
+    /// Send the PDU to the client
+    Send,
+    /// Discard the PDU, and hand the given request to the given stack instead.
+    /// Its reply is then to be routed in turn.
+    Reissue(Stack, Vec<u8>),
+    /// Discard the PDU, which the stack may not send
+    Drop,
+}
+
+/// The transaction outstanding on a connection
+struct Transaction {
+    owner: Stack,
+    opcode: AttOpcode,
+    /// The request as received from the client
+    request: Vec<u8>,
+    /// For a discovery request, the end of the range it was narrowed to
+    narrowed_end: Option<AttHandle>,
+}
+
+#[derive(Default)]
+struct ConnectionState {
+    transaction: Option<Transaction>,
+    /// The stacks holding prepared writes, in the order they were prepared
+    prepared: Vec<Stack>,
+    /// The stack whose indication awaits confirmation
+    indicating: Option<Stack>,
+}
+
+/// Routes the ATT PDUs of the connections shared by both stacks
+#[derive(Default)]
+pub struct SharedConnectionArbiter {
+    /// The handle ranges of the services registered with the Rust server, by
+    /// start handle
+    rust_services: BTreeMap<AttHandle, AttHandle>,
+    connections: HashMap<TransportIndex, ConnectionState>,
+}
+
+impl SharedConnectionArbiter {
+    /// Constructor, with all handles owned by the legacy stack
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A service was registered with the Rust server, over the given handles
+    pub fn on_rust_service_added(&mut self, handles: RangeInclusive<AttHandle>) {
+        self.rust_services.insert(*handles.start(), *handles.end());
+    }
+
+    /// A service was removed from the Rust server, from the given handles
+    pub fn on_rust_service_removed(&mut self, handles: RangeInclusive<AttHandle>) {
+        self.rust_services.remove(handles.start());
+    }
+
+    /// The connection on the given transport was closed
+    pub fn on_le_disconnect(&mut self, tcb_idx: TransportIndex) {
+        self.connections.remove(&tcb_idx);
+    }
+
+    /// The stack owning the given handle
+    pub fn owner(&self, handle: AttHandle) -> Stack {
+        match self.rust_services.range(..=handle).next_back() {
+            Some((_, end)) if handle <= *end => Stack::Rust,
+            _ => Stack::Legacy,
+        }
+    }
+
+    /// The last handle of the run of consecutive handles with the same owner
+    /// as the given one
+    fn end_of_run(&self, handle: AttHandle) -> AttHandle {
+        let owner = self.owner(handle);
+        let mut end = handle;
+        loop {
+            let run_end = match owner {
+                Stack::Rust => self.rust_services.range(..=end).next_back().map(|(_, end)| *end),
+                Stack::Legacy => {
+                    self.rust_services.range(end..).next().map(|(start, _)| AttHandle(start.0 - 1))
+                }
+            }
+            .unwrap_or(AttHandle(0xFFFF));
+            // adjacent Rust services form a single run
+            if run_end == AttHandle(0xFFFF) || self.owner(AttHandle(run_end.0 + 1)) != owner {
+                return run_end;
+            }
+            end = AttHandle(run_end.0 + 1);
+        }
+    }
+
+    /// Decide which stack handles a PDU received from the client on the given
+    /// transport
+    pub fn route_from_client(&mut self, tcb_idx: TransportIndex, pdu: &[u8]) -> Routing {
+        let Some(opcode) = pdu.first().and_then(|opcode| AttOpcode::try_from(*opcode).ok()) else {
+            // the legacy stack rejects what cannot be parsed, as it always has
+            return Routing::Forward(Stack::Legacy);
+        };
+        match classify_opcode(opcode) {
+            OperationType::Command => match handle_at(pdu, 1) {
+                Some(handle) => Routing::Forward(self.owner(handle)),
+                None => Routing::Forward(Stack::Legacy),
+            },
+            OperationType::Confirmation => {
+                let connection = self.connections.entry(tcb_idx).or_default();
+                match connection.indicating.take() {
+                    Some(stack) => Routing::Forward(stack),
+                    None => {
+                        warn!("dropping unexpected confirmation on {tcb_idx:?}");
+                        Routing::Drop
+                    }
+                }
+            }
+            OperationType::Request => self.route_request(tcb_idx, opcode, pdu),
+            // the client should never send these
+            _ => Routing::Forward(Stack::Legacy),
+        }
+    }
+
+    fn route_request(&mut self, tcb_idx: TransportIndex, opcode: AttOpcode, pdu: &[u8]) -> Routing {
+        if self.connections.get(&tcb_idx).and_then(|c| c.transaction.as_ref()).is_some() {
+            warn!("dropping {opcode:?} on {tcb_idx:?}, since a transaction is outstanding");
+            return Routing::Drop;
+        }
+        let (routing, narrowed_end) = match opcode {
+            AttOpcode::FIND_INFORMATION_REQUEST
+            | AttOpcode::FIND_BY_TYPE_VALUE_REQUEST
+            | AttOpcode::READ_BY_TYPE_REQUEST
+            | AttOpcode::READ_BY_GROUP_TYPE_REQUEST => match self.narrow(pdu, None) {
+                Some((routing, end)) => (routing, Some(end)),
+                None => (Routing::Forward(Stack::Legacy), None),
+            },
+            AttOpcode::READ_REQUEST
+            | AttOpcode::READ_BLOB_REQUEST
+            | AttOpcode::WRITE_REQUEST
+            | AttOpcode::PREPARE_WRITE_REQUEST => {
+                let stack = handle_at(pdu, 1).map(|handle| self.owner(handle));
+                (Routing::Forward(stack.unwrap_or(Stack::Legacy)), None)
+            }
+            AttOpcode::READ_MULTIPLE_REQUEST | AttOpcode::READ_MULTIPLE_VARIABLE_REQUEST => {
+                // a set mixing the handles of both stacks is left to the owner
+                // of the first one, which rejects the others
+                let stack = handle_at(pdu, 1).map(|handle| self.owner(handle));
+                (Routing::Forward(stack.unwrap_or(Stack::Legacy)), None)
+            }
+            AttOpcode::EXECUTE_WRITE_REQUEST => {
+                let connection = self.connections.entry(tcb_idx).or_default();
+                let stack = connection.prepared.first().copied().unwrap_or(Stack::Legacy);
+                (Routing::Forward(stack), None)
+            }
+            // the legacy stack owns the MTU exchange
+            _ => (Routing::Forward(Stack::Legacy), None),
+        };
+        let owner = match &routing {
+            Routing::Forward(stack) | Routing::ForwardRewritten(stack, _) => *stack,
+            Routing::Drop => return routing,
+        };
+        let connection = self.connections.entry(tcb_idx).or_default();
+        if opcode == AttOpcode::PREPARE_WRITE_REQUEST && !connection.prepared.contains(&owner) {
+            connection.prepared.push(owner);
+        }
+        connection.transaction =
+            Some(Transaction { owner, opcode, request: pdu.to_vec(), narrowed_end });
+        routing
+    }
+
+    /// Narrow a discovery request to the run of handles owned by the stack
+    /// holding the start of its range (or the given start handle instead).
+    /// Returns None if the range is invalid, so the request should be left as
+    /// is for the legacy stack to reject.
+    fn narrow(&self, pdu: &[u8], start: Option<AttHandle>) -> Option<(Routing, AttHandle)> {
+        let start = start.or_else(|| handle_at(pdu, 1))?;
+        let end = handle_at(pdu, 3)?;
+        if start == AttHandle(0) || start > end {
+            return None;
+        }
+        let stack = self.owner(start);
+        let narrowed_end = self.end_of_run(start).min(end);
+        if start == handle_at(pdu, 1)? && narrowed_end == end {
+            return Some((Routing::Forward(stack), end));
+        }
+        let mut rewritten = pdu.to_vec();
+        rewritten[1..3].copy_from_slice(&start.0.to_le_bytes());
+        rewritten[3..5].copy_from_slice(&narrowed_end.0.to_le_bytes());
+        Some((Routing::ForwardRewritten(stack, rewritten), narrowed_end))
+    }
+
+    /// Decide whether a PDU that the given stack is about to send to the
+    /// client on the given transport may be sent
+    pub fn route_to_client(
+        &mut self,
+        tcb_idx: TransportIndex,
+        from: Stack,
+        pdu: &[u8],
+    ) -> ReplyRouting {
+        let Some(opcode) = pdu.first().and_then(|opcode| AttOpcode::try_from(*opcode).ok()) else {
+            warn!("dropping malformed PDU from {from:?} on {tcb_idx:?}");
+            return ReplyRouting::Drop;
+        };
+        let connection = self.connections.entry(tcb_idx).or_default();
+        match classify_opcode(opcode) {
+            OperationType::Response => {}
+            OperationType::Indication => {
+                if connection.indicating.is_some() {
+                    warn!("dropping indication from {from:?} on {tcb_idx:?}, one is outstanding");
+                    return ReplyRouting::Drop;
+                }
+                connection.indicating = Some(from);
+                return ReplyRouting::Send;
+            }
+            _ => return ReplyRouting::Send,
+        }
+
+        let Some(transaction) = connection.transaction.take() else {
+            warn!("dropping {opcode:?} from {from:?} on {tcb_idx:?} outside of any transaction");
+            return ReplyRouting::Drop;
+        };
+        if transaction.owner != from {
+            warn!("dropping {opcode:?} from {from:?} on {tcb_idx:?}, which it does not own");
+            connection.transaction = Some(transaction);
+            return ReplyRouting::Drop;
+        }
+
+        if transaction.opcode == AttOpcode::EXECUTE_WRITE_REQUEST {
+            connection.prepared.retain(|stack| *stack != from);
+            let Some(next) = connection.prepared.first().copied() else {
+                return ReplyRouting::Send;
+            };
+            if opcode != AttOpcode::EXECUTE_WRITE_RESPONSE {
+                // the prepared writes of the other stack would otherwise linger
+                connection.prepared.clear();
+                return ReplyRouting::Send;
+            }
+            let request = transaction.request.clone();
+            connection.transaction = Some(Transaction { owner: next, ..transaction });
+            return ReplyRouting::Reissue(next, request);
+        }
+
+        let Some(narrowed_end) = transaction.narrowed_end else {
+            return ReplyRouting::Send;
+        };
+        let original_end = handle_at(&transaction.request, 3).unwrap_or(narrowed_end);
+        if error_code(pdu) != Some(AttErrorCode::ATTRIBUTE_NOT_FOUND)
+            || narrowed_end >= original_end
+        {
+            return ReplyRouting::Send;
+        }
+        // nothing matched in this run, so carry on with the next one
+        let next_start = AttHandle(narrowed_end.0 + 1);
+        let Some((routing, end)) = self.narrow(&transaction.request, Some(next_start)) else {
+            return ReplyRouting::Send;
+        };
+        let (stack, reissued) = match routing {
+            Routing::Forward(stack) => (stack, transaction.request.clone()),
+            Routing::ForwardRewritten(stack, pdu) => (stack, pdu),
+            Routing::Drop => return ReplyRouting::Send,
+        };
+        self.connections.entry(tcb_idx).or_default().transaction =
+            Some(Transaction { owner: stack, narrowed_end: Some(end), ..transaction });
+        ReplyRouting::Reissue(stack, reissued)
+    }
+}
+
+/// The handle at the given offset in a PDU, if it is long enough
+fn handle_at(pdu: &[u8], offset: usize) -> Option<AttHandle> {
+    let bytes = pdu.get(offset..offset + 2)?;
+    Some(AttHandle(u16::from_le_bytes([bytes[0], bytes[1]])))
+}
+
+/// The error code of an Error Response
+fn error_code(pdu: &[u8]) -> Option<AttErrorCode> {
+    if pdu.first() != Some(&AttOpcode::ERROR_RESPONSE.into()) {
+        return None;
+    }
+    AttErrorCode::try_from(*pdu.get(4)?).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TCB_IDX: TransportIndex = TransportIndex(1);
+
+    /// The Rust server owns handles 10 to 20
+    fn arbiter() -> SharedConnectionArbiter {
+        let mut arbiter = SharedConnectionArbiter::new();
+        arbiter.on_rust_service_added(AttHandle(10)..=AttHandle(20));
+        arbiter
+    }
+
+    fn read_request(handle: u16) -> Vec<u8> {
+        let [low, high] = handle.to_le_bytes();
+        vec![AttOpcode::READ_REQUEST.into(), low, high]
+    }
+
+    fn read_by_type_request(start: u16, end: u16) -> Vec<u8> {
+        let [start_low, start_high] = start.to_le_bytes();
+        let [end_low, end_high] = end.to_le_bytes();
+        vec![AttOpcode::READ_BY_TYPE_REQUEST.into(), start_low, start_high, end_low, end_high]
+            .into_iter()
+            .chain([0x03, 0x28])
+            .collect()
+    }
+
+    fn not_found(opcode: AttOpcode, handle: u16) -> Vec<u8> {
+        let [low, high] = handle.to_le_bytes();
+        vec![
+            AttOpcode::ERROR_RESPONSE.into(),
+            opcode.into(),
+            low,
+            high,
+            AttErrorCode::ATTRIBUTE_NOT_FOUND.into(),
+        ]
+    }
+
+    #[test]
+    fn test_requests_routed_by_handle_owner() {
+        let mut arbiter = arbiter();
+
+        let rust = arbiter.route_from_client(TCB_IDX, &read_request(15));
+        arbiter.route_to_client(TCB_IDX, Stack::Rust, &[AttOpcode::READ_RESPONSE.into()]);
+        let legacy = arbiter.route_from_client(TCB_IDX, &read_request(21));
+
+        assert_eq!(rust, Routing::Forward(Stack::Rust));
+        assert_eq!(legacy, Routing::Forward(Stack::Legacy));
+    }
+
+    #[test]
+    fn test_mtu_exchange_owned_by_legacy() {
+        let mut arbiter = arbiter();
+
+        let routing =
+            arbiter.route_from_client(TCB_IDX, &[AttOpcode::EXCHANGE_MTU_REQUEST.into(), 0, 2]);
+        let rust_reply = arbiter.route_to_client(
+            TCB_IDX,
+            Stack::Rust,
+            &[AttOpcode::EXCHANGE_MTU_RESPONSE.into(), 0, 2],
+        );
+        let legacy_reply = arbiter.route_to_client(
+            TCB_IDX,
+            Stack::Legacy,
+            &[AttOpcode::EXCHANGE_MTU_RESPONSE.into(), 0, 2],
+        );
+
+        assert_eq!(routing, Routing::Forward(Stack::Legacy));
+        assert_eq!(rust_reply, ReplyRouting::Drop);
+        assert_eq!(legacy_reply, ReplyRouting::Send);
+    }
+
+    #[test]
+    fn test_concurrent_request_dropped() {
+        let mut arbiter = arbiter();
+        arbiter.route_from_client(TCB_IDX, &read_request(15));
+
+        let routing = arbiter.route_from_client(TCB_IDX, &read_request(21));
+
+        assert_eq!(routing, Routing::Drop);
+    }
+
+    #[test]
+    fn test_discovery_narrowed_to_run() {
+        let mut arbiter = arbiter();
+
+        let routing = arbiter.route_from_client(TCB_IDX, &read_by_type_request(1, 0xFFFF));
+
+        assert_eq!(routing, Routing::ForwardRewritten(Stack::Legacy, read_by_type_request(1, 9)));
+    }
+
+    #[test]
+    fn test_discovery_reissued_over_next_run() {
+        // arrange: the legacy stack finds nothing in the first run
+        let mut arbiter = arbiter();
+        arbiter.route_from_client(TCB_IDX, &read_by_type_request(1, 0xFFFF));
+
+        // act
+        let first = arbiter.route_to_client(
+            TCB_IDX,
+            Stack::Legacy,
+            &not_found(AttOpcode::READ_BY_TYPE_REQUEST, 1),
+        );
+        let second = arbiter.route_to_client(
+            TCB_IDX,
+            Stack::Rust,
+            &not_found(AttOpcode::READ_BY_TYPE_REQUEST, 10),
+        );
+        let last = arbiter.route_to_client(
+            TCB_IDX,
+            Stack::Legacy,
+            &not_found(AttOpcode::READ_BY_TYPE_REQUEST, 21),
+        );
+
+        // assert: each run was tried in turn, and the client only gets the last reply
+        assert_eq!(first, ReplyRouting::Reissue(Stack::Rust, read_by_type_request(10, 20)));
+        assert_eq!(second, ReplyRouting::Reissue(Stack::Legacy, read_by_type_request(21, 0xFFFF)));
+        assert_eq!(last, ReplyRouting::Send);
+    }
+
+    #[test]
+    fn test_discovery_reply_within_run_sent() {
+        let mut arbiter = arbiter();
+        arbiter.route_from_client(TCB_IDX, &read_by_type_request(10, 0xFFFF));
+
+        let reply = arbiter.route_to_client(
+            TCB_IDX,
+            Stack::Rust,
+            &[AttOpcode::READ_BY_TYPE_RESPONSE.into(), 7, 11, 0, 2, 12, 0, 0x34, 0x12],
+        );
+
+        assert_eq!(reply, ReplyRouting::Send);
+    }
+
+    #[test]
+    fn test_execute_write_reissued_to_both_stacks() {
+        // arrange: writes are prepared on both stacks
+        let mut arbiter = arbiter();
+        for handle in [15u16, 21] {
+            let [low, high] = handle.to_le_bytes();
+            arbiter.route_from_client(
+                TCB_IDX,
+                &[AttOpcode::PREPARE_WRITE_REQUEST.into(), low, high, 0, 0, 1],
+            );
+            let owner = arbiter.owner(AttHandle(handle));
+            arbiter.route_to_client(
+                TCB_IDX,
+                owner,
+                &[AttOpcode::PREPARE_WRITE_RESPONSE.into(), low, high, 0, 0, 1],
+            );
+        }
+        let execute = [AttOpcode::EXECUTE_WRITE_REQUEST.into(), 0x01];
+
+        // act
+        let routing = arbiter.route_from_client(TCB_IDX, &execute);
+        let first = arbiter.route_to_client(
+            TCB_IDX,
+            Stack::Rust,
+            &[AttOpcode::EXECUTE_WRITE_RESPONSE.into()],
+        );
+        let second = arbiter.route_to_client(
+            TCB_IDX,
+            Stack::Legacy,
+            &[AttOpcode::EXECUTE_WRITE_RESPONSE.into()],
+        );
+
+        // assert
+        assert_eq!(routing, Routing::Forward(Stack::Rust));
+        assert_eq!(first, ReplyRouting::Reissue(Stack::Legacy, execute.to_vec()));
+        assert_eq!(second, ReplyRouting::Send);
+    }
+
+    #[test]
+    fn test_confirmation_routed_to_indicating_stack() {
+        let mut arbiter = arbiter();
+        let indication = [AttOpcode::HANDLE_VALUE_INDICATION.into(), 15, 0, 1];
+
+        let first = arbiter.route_to_client(TCB_IDX, Stack::Rust, &indication);
+        let second = arbiter.route_to_client(TCB_IDX, Stack::Legacy, &indication);
+        let confirmation =
+            arbiter.route_from_client(TCB_IDX, &[AttOpcode::HANDLE_VALUE_CONFIRMATION.into()]);
+
+        assert_eq!(first, ReplyRouting::Send);
+        assert_eq!(second, ReplyRouting::Drop);
+        assert_eq!(confirmation, Routing::Forward(Stack::Rust));
+    }
+
+    #[test]
+    fn test_adjacent_rust_services_form_one_run() {
+        let mut arbiter = arbiter();
+        arbiter.on_rust_service_added(AttHandle(21)..=AttHandle(30));
+
+        assert_eq!(arbiter.end_of_run(AttHandle(10)), AttHandle(30));
+        assert_eq!(arbiter.end_of_run(AttHandle(31)), AttHandle(0xFFFF));
+        assert_eq!(arbiter.end_of_run(AttHandle(1)), AttHandle(9));
+    }
+}
@@ -1,10 +1,19 @@
 //! These are the interfaces between the GattModule and JNI. The synchronous
 //! interface is mapped to the asynchronous interface using the
-//! CallbackTransactionManager;
+//! CallbackTransactionManager; datastores responding to requests from another
+//! task may instead defer their responses (see DeferredResponseDatastore), and
+//! slow datastores of rarely changing values may cache them (see ReadCache).
 
 mod callback_transaction_manager;
+mod deferred_response;
+mod read_cache;
 
 pub use callback_transaction_manager::{CallbackResponseError, CallbackTransactionManager};
+pub use deferred_response::{
+    deferred_response, DatastoreResponse, DeferredResponseDatastore, DeferringGattDatastore,
+    PendingResponse, Responder,
+};
+pub use read_cache::{ReadCache, ReadCacheMetrics, DEFAULT_TTL};
 
 use async_trait::async_trait;
 use log::warn;
@@ -73,8 +82,11 @@ pub enum GattWriteType {
 /// The types of write requests (that need responses)
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum GattWriteRequestType {
-    /// Atomic (WRITE_REQ)
-    Request,
+    /// Atomic (WRITE_REQ, or an executed queue of PREPARE_WRITE_REQs)
+    Request {
+        /// The byte offset from which the value is replaced
+        offset: u32,
+    },
     /// Transactional, should not be committed yet (PREPARE_WRITE_REQ)
     Prepare {
         /// The byte offset at which to write
@@ -130,6 +142,11 @@ pub trait RawGattDatastore {
         tcb_idx: TransportIndex,
         decision: TransactionDecision,
     ) -> Result<(), AttErrorCode>;
+
+    /// The client on the specified connection has disconnected. Its in-flight
+    /// operations have been cancelled, so any state kept for them should be
+    /// released.
+    fn on_peer_disconnected(&self, _tcb_idx: TransportIndex) {}
 }
 
 /// This interface simplifies the interface of RawGattDatastore by rejecting all unsupported
@@ -152,6 +169,10 @@ pub trait GattDatastore {
         attr_type: AttributeBackingType,
         data: &[u8],
     ) -> Result<(), AttErrorCode>;
+
+    /// The client on the specified connection has disconnected, so any state
+    /// kept for it may be released.
+    fn on_peer_disconnected(&self, _tcb_idx: TransportIndex) {}
 }
 
 #[async_trait(?Send)]
@@ -164,11 +185,13 @@ impl<T: GattDatastore + ?Sized> RawGattDatastore for T {
         offset: u32,
         attr_type: AttributeBackingType,
     ) -> Result<Vec<u8>, AttErrorCode> {
-        if offset != 0 {
-            warn!("got read blob request for non-long attribute {handle:?}");
-            return Err(AttErrorCode::ATTRIBUTE_NOT_LONG);
+        // the full value is read, and only the part after the offset is returned
+        let mut value = self.read(tcb_idx, handle, attr_type).await?;
+        if offset as usize > value.len() {
+            warn!("got read of {handle:?} at offset {offset} past the end of its value");
+            return Err(AttErrorCode::INVALID_OFFSET);
         }
-        self.read(tcb_idx, handle, attr_type).await
+        Ok(value.split_off(offset as usize))
     }
 
     /// Write data to a given characteristic on the specified connection.
@@ -185,7 +208,13 @@ impl<T: GattDatastore + ?Sized> RawGattDatastore for T {
                 warn!("got prepare write attempt on {tcb_idx:?} to characteristic {handle:?} not supporting write_without_response");
                 Err(AttErrorCode::WRITE_REQUEST_REJECTED)
             }
-            GattWriteRequestType::Request => self.write(tcb_idx, handle, attr_type, data).await,
+            GattWriteRequestType::Request { offset: 0 } => {
+                self.write(tcb_idx, handle, attr_type, data).await
+            }
+            GattWriteRequestType::Request { .. } => {
+                warn!("got write at non-zero offset on {tcb_idx:?} to non-long characteristic {handle:?}");
+                Err(AttErrorCode::ATTRIBUTE_NOT_LONG)
+            }
         }
     }
 
@@ -205,6 +234,10 @@ impl<T: GattDatastore + ?Sized> RawGattDatastore for T {
         // we never do prepared writes, so who cares
         Ok(())
     }
+
+    fn on_peer_disconnected(&self, tcb_idx: TransportIndex) {
+        GattDatastore::on_peer_disconnected(self, tcb_idx)
+    }
 }
 
 #[cfg(test)]
@@ -278,23 +311,57 @@ mod test {
     }
 
     #[test]
-    fn test_rejected_read_blob() {
-        // arrange
-        let (datastore, mut rx) = MockDatastore::new();
+    fn test_read_blob_slices_value() {
+        block_on_locally(async {
+            // arrange
+            let (datastore, mut rx) = MockDatastore::new();
 
-        // act: send read blob request
-        let resp = block_on_locally(RawGattDatastore::read(
-            &datastore,
-            TCB_IDX,
-            HANDLE,
-            1,
-            AttributeBackingType::Characteristic,
-        ));
+            // act: send read blob request, and supply the full value
+            let pending = spawn_local(async move {
+                RawGattDatastore::read(
+                    &datastore,
+                    TCB_IDX,
+                    HANDLE,
+                    1,
+                    AttributeBackingType::Characteristic,
+                )
+                .await
+            });
+            let MockDatastoreEvents::Read(_, _, _, resp) = rx.recv().await.unwrap() else {
+                unreachable!();
+            };
+            resp.send(Ok(DATA.to_vec())).unwrap();
 
-        // assert: got the correct error code
-        assert_eq!(resp, Err(AttErrorCode::ATTRIBUTE_NOT_LONG));
-        // assert: no pending events
-        assert_eq!(rx.try_recv().unwrap_err(), TryRecvError::Empty);
+            // assert: only the part after the offset was returned
+            assert_eq!(pending.await.unwrap(), Ok(DATA[1..].to_vec()));
+        });
+    }
+
+    #[test]
+    fn test_read_blob_past_end() {
+        block_on_locally(async {
+            // arrange
+            let (datastore, mut rx) = MockDatastore::new();
+
+            // act: send read blob request past the end of the value
+            let pending = spawn_local(async move {
+                RawGattDatastore::read(
+                    &datastore,
+                    TCB_IDX,
+                    HANDLE,
+                    DATA.len() as u32 + 1,
+                    AttributeBackingType::Characteristic,
+                )
+                .await
+            });
+            let MockDatastoreEvents::Read(_, _, _, resp) = rx.recv().await.unwrap() else {
+                unreachable!();
+            };
+            resp.send(Ok(DATA.to_vec())).unwrap();
+
+            // assert: got the correct error code
+            assert_eq!(pending.await.unwrap(), Err(AttErrorCode::INVALID_OFFSET));
+        });
     }
 
     #[test]
@@ -310,7 +377,7 @@ mod test {
                     TCB_IDX,
                     HANDLE,
                     AttributeBackingType::Characteristic,
-                    GattWriteRequestType::Request,
+                    GattWriteRequestType::Request { offset: 0 },
                     &DATA,
                 )
                 .await
@@ -344,7 +411,7 @@ mod test {
                     TCB_IDX,
                     HANDLE,
                     AttributeBackingType::Characteristic,
-                    GattWriteRequestType::Request,
+                    GattWriteRequestType::Request { offset: 0 },
                     &DATA,
                 )
                 .await
@@ -381,6 +448,27 @@ mod test {
         assert_eq!(rx.try_recv().unwrap_err(), TryRecvError::Empty);
     }
 
+    #[test]
+    fn test_rejected_write_at_offset() {
+        // arrange
+        let (datastore, mut rx) = MockDatastore::new();
+
+        // act: send a write request at a non-zero offset
+        let resp = block_on_locally(RawGattDatastore::write(
+            &datastore,
+            TCB_IDX,
+            HANDLE,
+            AttributeBackingType::Characteristic,
+            GattWriteRequestType::Request { offset: 1 },
+            &DATA,
+        ));
+
+        // assert: got the correct error code
+        assert_eq!(resp, Err(AttErrorCode::ATTRIBUTE_NOT_LONG));
+        // assert: no event sent up
+        assert_eq!(rx.try_recv().unwrap_err(), TryRecvError::Empty);
+    }
+
     #[test]
     fn test_dropped_write_command() {
         // arrange
@@ -2,7 +2,7 @@ use std::{cell::RefCell, collections::HashMap, rc::Rc, time::Duration};
 
 use async_trait::async_trait;
 use log::{trace, warn};
-use tokio::{sync::oneshot, time::timeout};
+use tokio::sync::oneshot;
 
 use crate::{
     gatt::{
@@ -10,6 +10,7 @@ use crate::{
         GattCallbacks,
     },
     packets::AttErrorCode,
+    utils::clock::{timeout, Clock, TokioClock},
 };
 
 use super::{
@@ -33,6 +34,7 @@ struct PendingTransactionWatcher {
 pub struct CallbackTransactionManager {
     callbacks: Rc<dyn GattCallbacks>,
     pending_transactions: RefCell<PendingTransactionsState>,
+    clock: Rc<dyn Clock>,
 }
 
 struct PendingTransactionsState {
@@ -43,7 +45,7 @@ struct PendingTransactionsState {
 /// We expect all responses to be provided within this timeout
 /// It should be less than 30s, as that is the ATT timeout that causes
 /// the client to disconnect.
-const TIMEOUT: Duration = Duration::from_secs(15);
+pub const TIMEOUT: Duration = Duration::from_secs(15);
 
 /// The cause of a failure to dispatch a call to send_response()
 #[derive(Debug, PartialEq, Eq)]
@@ -58,12 +60,19 @@ impl CallbackTransactionManager {
     /// Constructor, wrapping a GattCallbacks instance with the GattDatastore
     /// interface
     pub fn new(callbacks: Rc<dyn GattCallbacks>) -> Self {
+        Self::new_with_clock(callbacks, Rc::new(TokioClock))
+    }
+
+    /// Constructor, measuring the timeout of each transaction with the given
+    /// Clock
+    pub fn new_with_clock(callbacks: Rc<dyn GattCallbacks>, clock: Rc<dyn Clock>) -> Self {
         Self {
             callbacks,
             pending_transactions: RefCell::new(PendingTransactionsState {
                 pending_transactions: HashMap::new(),
                 next_transaction_id: 1,
             }),
+            clock,
         }
     }
 
@@ -87,6 +96,16 @@ impl CallbackTransactionManager {
         }
     }
 
+    /// Whether any transaction on the given connection is still waiting for
+    /// its response
+    pub fn has_pending_transactions(&self, conn_id: ConnectionId) -> bool {
+        self.pending_transactions
+            .borrow()
+            .pending_transactions
+            .keys()
+            .any(|(pending_conn_id, _)| *pending_conn_id == conn_id)
+    }
+
     /// Get an impl GattDatastore tied to a particular server
     pub fn get_datastore(self: &Rc<Self>, server_id: ServerId) -> impl RawGattDatastore {
         GattDatastoreImpl { callback_transaction_manager: self.clone(), server_id }
@@ -112,7 +131,7 @@ impl PendingTransactionWatcher {
     /// Wait for the transaction to resolve, or to hit the timeout. If the
     /// timeout is reached, clean up state related to transaction watching.
     async fn wait(self, manager: &CallbackTransactionManager) -> Result<Vec<u8>, AttErrorCode> {
-        if let Ok(Ok(result)) = timeout(TIMEOUT, self.rx).await {
+        if let Ok(Ok(result)) = timeout(&*manager.clock, TIMEOUT, self.rx).await {
             result
         } else {
             manager
@@ -233,4 +252,15 @@ impl RawGattDatastore for GattDatastoreImpl {
         // the data passed back is irrelevant for execute requests
         pending_transaction.wait(&self.callback_transaction_manager).await.map(|_| ())
     }
+
+    fn on_peer_disconnected(&self, tcb_idx: TransportIndex) {
+        // the requests awaiting these responses were cancelled with the bearer, so
+        // late responses from Java are rejected as for unknown transactions
+        let conn_id = ConnectionId::new(tcb_idx, self.server_id);
+        self.callback_transaction_manager
+            .pending_transactions
+            .borrow_mut()
+            .pending_transactions
+            .retain(|(pending_conn_id, _), _| *pending_conn_id != conn_id);
+    }
 }
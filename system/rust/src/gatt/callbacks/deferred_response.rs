@@ -0,0 +1,314 @@
+//! This module lets a datastore respond to a request after returning from the
+//! call that delivered it, as apps do when they call sendResponse() from
+//! another thread. The datastore returns DatastoreResponse::Pending with one
+//! half of a pair made by deferred_response(), and hands the Responder to
+//! whoever will answer. The ATT transaction stays open until the Responder is
+//! resolved (from any task), or the response times out.
+
+use std::{rc::Rc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::warn;
+use tokio::sync::oneshot;
+
+use crate::{
+    gatt::{
+        ffi::AttributeBackingType,
+        ids::{AttHandle, TransportIndex},
+    },
+    packets::AttErrorCode,
+    utils::clock::{timeout, Clock, TokioClock},
+};
+
+use super::{callback_transaction_manager::TIMEOUT, GattDatastore};
+
+/// Resolves a deferred response. Dropping it without resolving it fails the
+/// request with UNLIKELY_ERROR.
+#[derive(Debug)]
+pub struct Responder<T> {
+    tx: oneshot::Sender<Result<T, AttErrorCode>>,
+}
+
+impl<T> Responder<T> {
+    /// Complete the request with the given result. Fails if the server no
+    /// longer awaits it (since it timed out, or the client disconnected).
+    pub fn resolve(self, result: Result<T, AttErrorCode>) -> Result<()> {
+        self.tx.send(result).map_err(|_| anyhow!("the request is no longer awaiting a response"))
+    }
+}
+
+/// The response to a request, awaited by the server
+#[derive(Debug)]
+pub struct PendingResponse<T> {
+    rx: oneshot::Receiver<Result<T, AttErrorCode>>,
+}
+
+/// Create a Responder, and the pending response it resolves
+pub fn deferred_response<T>() -> (Responder<T>, PendingResponse<T>) {
+    let (tx, rx) = oneshot::channel();
+    (Responder { tx }, PendingResponse { rx })
+}
+
+/// The response of a DeferringGattDatastore to a request
+#[derive(Debug)]
+pub enum DatastoreResponse<T> {
+    /// The request has completed
+    Ready(Result<T, AttErrorCode>),
+    /// The request completes once the corresponding Responder is resolved
+    Pending(PendingResponse<T>),
+}
+
+impl<T> From<Result<T, AttErrorCode>> for DatastoreResponse<T> {
+    fn from(result: Result<T, AttErrorCode>) -> Self {
+        Self::Ready(result)
+    }
+}
+
+/// A variant of GattDatastore whose responses may be deferred. Wrap it in a
+/// DeferredResponseDatastore to register it with the GattDatabase.
+pub trait DeferringGattDatastore {
+    /// Read a characteristic from the specified connection at the given handle.
+    fn read(
+        &self,
+        tcb_idx: TransportIndex,
+        handle: AttHandle,
+        attr_type: AttributeBackingType,
+    ) -> DatastoreResponse<Vec<u8>>;
+
+    /// Write data to a given characteristic on the specified connection.
+    fn write(
+        &self,
+        tcb_idx: TransportIndex,
+        handle: AttHandle,
+        attr_type: AttributeBackingType,
+        data: &[u8],
+    ) -> DatastoreResponse<()>;
+
+    /// The client on the specified connection has disconnected. The requests
+    /// still pending on it have been cancelled, so resolving their Responders
+    /// fails.
+    fn on_peer_disconnected(&self, _tcb_idx: TransportIndex) {}
+}
+
+/// Adapts a DeferringGattDatastore to the GattDatastore interface, by
+/// awaiting its pending responses
+pub struct DeferredResponseDatastore<T> {
+    datastore: T,
+    clock: Rc<dyn Clock>,
+    timeout: Duration,
+}
+
+impl<T: DeferringGattDatastore> DeferredResponseDatastore<T> {
+    /// Constructor, with the same timeout as the responses from JNI
+    pub fn new(datastore: T) -> Self {
+        Self::new_with_clock(datastore, Rc::new(TokioClock))
+    }
+
+    /// Constructor, measuring the timeout of each response with the given
+    /// Clock
+    pub fn new_with_clock(datastore: T, clock: Rc<dyn Clock>) -> Self {
+        Self { datastore, clock, timeout: TIMEOUT }
+    }
+
+    /// Set how long a pending response is awaited, before the request fails
+    /// with UNLIKELY_ERROR. This should stay below the ATT transaction
+    /// timeout, after which the client disconnects.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The wrapped datastore
+    pub fn datastore(&self) -> &T {
+        &self.datastore
+    }
+
+    async fn wait<V>(
+        &self,
+        handle: AttHandle,
+        response: DatastoreResponse<V>,
+    ) -> Result<V, AttErrorCode> {
+        let pending = match response {
+            DatastoreResponse::Ready(result) => return result,
+            DatastoreResponse::Pending(pending) => pending,
+        };
+        match timeout(&*self.clock, self.timeout, pending.rx).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => {
+                warn!("responder for {handle:?} dropped without a response - returning UNLIKELY_ERROR");
+                Err(AttErrorCode::UNLIKELY_ERROR)
+            }
+            Err(_) => {
+                warn!("no response for {handle:?} after timeout - returning UNLIKELY_ERROR");
+                Err(AttErrorCode::UNLIKELY_ERROR)
+            }
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<T: DeferringGattDatastore> GattDatastore for DeferredResponseDatastore<T> {
+    async fn read(
+        &self,
+        tcb_idx: TransportIndex,
+        handle: AttHandle,
+        attr_type: AttributeBackingType,
+    ) -> Result<Vec<u8>, AttErrorCode> {
+        self.wait(handle, self.datastore.read(tcb_idx, handle, attr_type)).await
+    }
+
+    async fn write(
+        &self,
+        tcb_idx: TransportIndex,
+        handle: AttHandle,
+        attr_type: AttributeBackingType,
+        data: &[u8],
+    ) -> Result<(), AttErrorCode> {
+        self.wait(handle, self.datastore.write(tcb_idx, handle, attr_type, data)).await
+    }
+
+    fn on_peer_disconnected(&self, tcb_idx: TransportIndex) {
+        self.datastore.on_peer_disconnected(tcb_idx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+
+    use tokio::task::spawn_local;
+
+    use crate::{
+        gatt::callbacks::RawGattDatastore,
+        utils::task::{block_on_locally, try_await},
+    };
+
+    use super::*;
+
+    const TCB_IDX: TransportIndex = TransportIndex(1);
+    const HANDLE: AttHandle = AttHandle(3);
+
+    /// Defers every write, keeping the Responders for the test to resolve
+    #[derive(Default)]
+    struct TestDatastore {
+        responders: RefCell<Vec<Responder<()>>>,
+    }
+
+    impl DeferringGattDatastore for Rc<TestDatastore> {
+        fn read(
+            &self,
+            _: TransportIndex,
+            _: AttHandle,
+            _: AttributeBackingType,
+        ) -> DatastoreResponse<Vec<u8>> {
+            Ok(vec![1, 2]).into()
+        }
+
+        fn write(
+            &self,
+            _: TransportIndex,
+            _: AttHandle,
+            _: AttributeBackingType,
+            _: &[u8],
+        ) -> DatastoreResponse<()> {
+            let (responder, pending) = deferred_response();
+            self.responders.borrow_mut().push(responder);
+            DatastoreResponse::Pending(pending)
+        }
+    }
+
+    fn start_write(
+        datastore: &Rc<DeferredResponseDatastore<Rc<TestDatastore>>>,
+    ) -> impl std::future::Future<Output = Result<(), AttErrorCode>> {
+        let datastore = datastore.clone();
+        async move {
+            GattDatastore::write(
+                &*datastore,
+                TCB_IDX,
+                HANDLE,
+                AttributeBackingType::Characteristic,
+                &[1],
+            )
+            .await
+        }
+    }
+
+    #[test]
+    fn test_ready_response() {
+        block_on_locally(async {
+            let datastore = DeferredResponseDatastore::new(Rc::new(TestDatastore::default()));
+
+            let value = RawGattDatastore::read(
+                &datastore,
+                TCB_IDX,
+                HANDLE,
+                0,
+                AttributeBackingType::Characteristic,
+            )
+            .await;
+
+            assert_eq!(value, Ok(vec![1, 2]));
+        });
+    }
+
+    #[test]
+    fn test_write_held_until_resolved() {
+        block_on_locally(async {
+            // arrange
+            let test_datastore = Rc::new(TestDatastore::default());
+            let datastore = Rc::new(DeferredResponseDatastore::new(test_datastore.clone()));
+
+            // act: start a write, and resolve it later from another task
+            let Err(pending) = try_await(start_write(&datastore)).await else {
+                unreachable!("write completed before it was resolved");
+            };
+            let responder = test_datastore.responders.borrow_mut().pop().unwrap();
+            spawn_local(async move { responder.resolve(Err(AttErrorCode::VALUE_NOT_ALLOWED)) })
+                .await
+                .unwrap()
+                .unwrap();
+
+            // assert: the write completed with the supplied result
+            assert_eq!(pending.await, Err(AttErrorCode::VALUE_NOT_ALLOWED));
+        });
+    }
+
+    #[test]
+    fn test_write_times_out() {
+        block_on_locally(async {
+            // arrange
+            let test_datastore = Rc::new(TestDatastore::default());
+            let datastore = Rc::new(
+                DeferredResponseDatastore::new(test_datastore.clone())
+                    .with_timeout(Duration::from_secs(5)),
+            );
+
+            // act: let the write time out, then try to resolve it
+            let result = start_write(&datastore).await;
+            let responder = test_datastore.responders.borrow_mut().pop().unwrap();
+
+            // assert
+            assert_eq!(result, Err(AttErrorCode::UNLIKELY_ERROR));
+            assert!(responder.resolve(Ok(())).is_err());
+        });
+    }
+
+    #[test]
+    fn test_dropped_responder() {
+        block_on_locally(async {
+            // arrange
+            let test_datastore = Rc::new(TestDatastore::default());
+            let datastore = Rc::new(DeferredResponseDatastore::new(test_datastore.clone()));
+
+            // act: start a write, and drop its responder
+            let Err(pending) = try_await(start_write(&datastore)).await else {
+                unreachable!("write completed before it was resolved");
+            };
+            test_datastore.responders.borrow_mut().clear();
+
+            // assert
+            assert_eq!(pending.await, Err(AttErrorCode::UNLIKELY_ERROR));
+        });
+    }
+}
@@ -0,0 +1,418 @@
+//! This module lets a datastore serve repeated reads from a cache, rather
+//! than asking the upper layer each time. It is meant for values that are slow
+//! to read (e.g. since the app answers from another thread) but rarely change,
+//! when many clients read them at once (e.g. as they all reconnect and
+//! rediscover the database). The cached values are shared between clients, so
+//! only wrap datastores whose values are the same for every client.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+
+use crate::{
+    gatt::{
+        ffi::AttributeBackingType,
+        ids::{AttHandle, TransportIndex},
+    },
+    packets::AttErrorCode,
+    utils::clock::{Clock, TokioClock},
+};
+
+use super::GattDatastore;
+
+/// How long a value read from the datastore is cached, unless configured
+/// otherwise
+pub const DEFAULT_TTL: Duration = Duration::from_secs(30);
+
+/// The counters of a ReadCache
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReadCacheMetrics {
+    /// The number of reads served from the cache
+    pub hits: u64,
+    /// The number of reads passed on to the datastore
+    pub misses: u64,
+}
+
+struct CachedValue {
+    value: Vec<u8>,
+    // None if the value is pinned
+    expires_at: Option<Instant>,
+}
+
+/// Wraps a GattDatastore, caching the values read from it. A cached value is
+/// served until its TTL expires, it is invalidated, or a client writes the
+/// attribute. Errors are never cached.
+pub struct ReadCache<T> {
+    datastore: T,
+    clock: Rc<dyn Clock>,
+    ttl: Duration,
+    values: RefCell<HashMap<AttHandle, CachedValue>>,
+    // bumped on each invalidation, so that a read already in progress at the
+    // time does not cache the (possibly stale) value it gets
+    generation: Cell<u64>,
+    metrics: Cell<ReadCacheMetrics>,
+}
+
+impl<T: GattDatastore> ReadCache<T> {
+    /// Constructor, caching values for the DEFAULT_TTL
+    pub fn new(datastore: T) -> Self {
+        Self::new_with_clock(datastore, Rc::new(TokioClock))
+    }
+
+    /// Constructor, measuring the TTL of each value with the given Clock
+    pub fn new_with_clock(datastore: T, clock: Rc<dyn Clock>) -> Self {
+        Self {
+            datastore,
+            clock,
+            ttl: DEFAULT_TTL,
+            values: Default::default(),
+            generation: Cell::new(0),
+            metrics: Default::default(),
+        }
+    }
+
+    /// Set how long a value read from the datastore is cached
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// The wrapped datastore
+    pub fn datastore(&self) -> &T {
+        &self.datastore
+    }
+
+    /// Cache the given value of an attribute ahead of its first read, as if it
+    /// had just been read from the datastore (so it expires after the TTL)
+    pub fn prewarm(&self, handle: AttHandle, value: Vec<u8>) {
+        let expires_at = self.clock.now() + self.ttl;
+        self.values
+            .borrow_mut()
+            .insert(handle, CachedValue { value, expires_at: Some(expires_at) });
+    }
+
+    /// Cache the given value of an attribute until it is invalidated (or the
+    /// attribute is written), regardless of the TTL
+    pub fn pin(&self, handle: AttHandle, value: Vec<u8>) {
+        self.values.borrow_mut().insert(handle, CachedValue { value, expires_at: None });
+    }
+
+    /// Drop the cached value of an attribute (even if pinned), e.g. since the
+    /// upper layer changed it, so that the next read goes to the datastore
+    pub fn invalidate(&self, handle: AttHandle) {
+        self.values.borrow_mut().remove(&handle);
+        self.generation.set(self.generation.get() + 1);
+    }
+
+    /// Whether a value is currently cached for the given attribute
+    pub fn is_cached(&self, handle: AttHandle) -> bool {
+        self.lookup(handle).is_some()
+    }
+
+    /// The reads served from the cache, and those passed on to the datastore,
+    /// so far
+    pub fn metrics(&self) -> ReadCacheMetrics {
+        self.metrics.get()
+    }
+
+    fn lookup(&self, handle: AttHandle) -> Option<Vec<u8>> {
+        let mut values = self.values.borrow_mut();
+        let cached = values.get(&handle)?;
+        if cached.expires_at.map(|expires_at| expires_at <= self.clock.now()).unwrap_or(false) {
+            values.remove(&handle);
+            return None;
+        }
+        Some(cached.value.clone())
+    }
+
+    fn record(&self, update: impl FnOnce(&mut ReadCacheMetrics)) {
+        let mut metrics = self.metrics.get();
+        update(&mut metrics);
+        self.metrics.set(metrics);
+    }
+}
+
+#[async_trait(?Send)]
+impl<T: GattDatastore> GattDatastore for ReadCache<T> {
+    async fn read(
+        &self,
+        tcb_idx: TransportIndex,
+        handle: AttHandle,
+        attr_type: AttributeBackingType,
+    ) -> Result<Vec<u8>, AttErrorCode> {
+        if let Some(value) = self.lookup(handle) {
+            self.record(|metrics| metrics.hits += 1);
+            return Ok(value);
+        }
+        self.record(|metrics| metrics.misses += 1);
+        let generation = self.generation.get();
+        let value = self.datastore.read(tcb_idx, handle, attr_type).await?;
+        if self.generation.get() == generation {
+            self.prewarm(handle, value.clone());
+        }
+        Ok(value)
+    }
+
+    async fn write(
+        &self,
+        tcb_idx: TransportIndex,
+        handle: AttHandle,
+        attr_type: AttributeBackingType,
+        data: &[u8],
+    ) -> Result<(), AttErrorCode> {
+        let result = self.datastore.write(tcb_idx, handle, attr_type, data).await;
+        // even a failed write may have changed the value
+        self.invalidate(handle);
+        result
+    }
+
+    fn on_peer_disconnected(&self, tcb_idx: TransportIndex) {
+        self.datastore.on_peer_disconnected(tcb_idx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::oneshot;
+
+    use crate::utils::{
+        clock::VirtualClock,
+        task::{block_on_locally, try_await},
+    };
+
+    use super::*;
+
+    const TCB_IDX: TransportIndex = TransportIndex(1);
+    const ANOTHER_TCB_IDX: TransportIndex = TransportIndex(2);
+    const HANDLE: AttHandle = AttHandle(3);
+    const TTL: Duration = Duration::from_secs(10);
+
+    /// Serves the current value, counting the reads reaching it. If a gate is
+    /// set, the next read waits for it to open.
+    struct TestDatastore {
+        value: RefCell<Result<Vec<u8>, AttErrorCode>>,
+        reads: Cell<usize>,
+        gate: RefCell<Option<oneshot::Receiver<()>>>,
+    }
+
+    impl TestDatastore {
+        fn new(value: Vec<u8>) -> Rc<Self> {
+            Rc::new(Self {
+                value: RefCell::new(Ok(value)),
+                reads: Cell::new(0),
+                gate: RefCell::new(None),
+            })
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl GattDatastore for Rc<TestDatastore> {
+        async fn read(
+            &self,
+            _: TransportIndex,
+            _: AttHandle,
+            _: AttributeBackingType,
+        ) -> Result<Vec<u8>, AttErrorCode> {
+            self.reads.set(self.reads.get() + 1);
+            let gate = self.gate.borrow_mut().take();
+            if let Some(gate) = gate {
+                gate.await.unwrap();
+            }
+            self.value.borrow().clone()
+        }
+
+        async fn write(
+            &self,
+            _: TransportIndex,
+            _: AttHandle,
+            _: AttributeBackingType,
+            data: &[u8],
+        ) -> Result<(), AttErrorCode> {
+            *self.value.borrow_mut() = Ok(data.to_vec());
+            Ok(())
+        }
+    }
+
+    fn make_cache(
+        datastore: &Rc<TestDatastore>,
+        clock: &VirtualClock,
+    ) -> Rc<ReadCache<Rc<TestDatastore>>> {
+        Rc::new(ReadCache::new_with_clock(datastore.clone(), Rc::new(clock.clone())).with_ttl(TTL))
+    }
+
+    async fn read(
+        cache: &ReadCache<Rc<TestDatastore>>,
+        tcb_idx: TransportIndex,
+    ) -> Result<Vec<u8>, AttErrorCode> {
+        GattDatastore::read(cache, tcb_idx, HANDLE, AttributeBackingType::Characteristic).await
+    }
+
+    #[test]
+    fn test_repeated_read_served_from_cache() {
+        block_on_locally(async {
+            // arrange
+            let datastore = TestDatastore::new(vec![1, 2]);
+            let cache = make_cache(&datastore, &VirtualClock::new());
+
+            // act: read from two clients
+            let first = read(&cache, TCB_IDX).await;
+            let second = read(&cache, ANOTHER_TCB_IDX).await;
+
+            // assert: only the first read reached the datastore
+            assert_eq!(first, Ok(vec![1, 2]));
+            assert_eq!(second, Ok(vec![1, 2]));
+            assert_eq!(datastore.reads.get(), 1);
+            assert_eq!(cache.metrics(), ReadCacheMetrics { hits: 1, misses: 1 });
+        });
+    }
+
+    #[test]
+    fn test_cached_value_expires() {
+        block_on_locally(async {
+            // arrange
+            let datastore = TestDatastore::new(vec![1, 2]);
+            let clock = VirtualClock::new();
+            let cache = make_cache(&datastore, &clock);
+            read(&cache, TCB_IDX).await.unwrap();
+
+            // act
+            clock.advance(TTL).await;
+
+            // assert
+            assert!(!cache.is_cached(HANDLE));
+            read(&cache, TCB_IDX).await.unwrap();
+            assert_eq!(datastore.reads.get(), 2);
+        });
+    }
+
+    #[test]
+    fn test_invalidated_value_read_again() {
+        block_on_locally(async {
+            // arrange
+            let datastore = TestDatastore::new(vec![1, 2]);
+            let cache = make_cache(&datastore, &VirtualClock::new());
+            read(&cache, TCB_IDX).await.unwrap();
+            *datastore.value.borrow_mut() = Ok(vec![3]);
+
+            // act
+            cache.invalidate(HANDLE);
+            let value = read(&cache, TCB_IDX).await;
+
+            // assert
+            assert_eq!(value, Ok(vec![3]));
+            assert_eq!(cache.metrics(), ReadCacheMetrics { hits: 0, misses: 2 });
+        });
+    }
+
+    #[test]
+    fn test_pinned_value_never_expires() {
+        block_on_locally(async {
+            // arrange
+            let datastore = TestDatastore::new(vec![1, 2]);
+            let clock = VirtualClock::new();
+            let cache = make_cache(&datastore, &clock);
+
+            // act
+            cache.pin(HANDLE, vec![4, 5]);
+            clock.advance(TTL * 10).await;
+            let value = read(&cache, TCB_IDX).await;
+
+            // assert: the datastore was never read
+            assert_eq!(value, Ok(vec![4, 5]));
+            assert_eq!(datastore.reads.get(), 0);
+        });
+    }
+
+    #[test]
+    fn test_prewarmed_value_served_until_expiry() {
+        block_on_locally(async {
+            // arrange
+            let datastore = TestDatastore::new(vec![1, 2]);
+            let clock = VirtualClock::new();
+            let cache = make_cache(&datastore, &clock);
+            cache.prewarm(HANDLE, vec![4, 5]);
+
+            // act
+            let before_expiry = read(&cache, TCB_IDX).await;
+            clock.advance(TTL).await;
+            let after_expiry = read(&cache, TCB_IDX).await;
+
+            // assert
+            assert_eq!(before_expiry, Ok(vec![4, 5]));
+            assert_eq!(after_expiry, Ok(vec![1, 2]));
+        });
+    }
+
+    #[test]
+    fn test_write_invalidates() {
+        block_on_locally(async {
+            // arrange
+            let datastore = TestDatastore::new(vec![1, 2]);
+            let cache = make_cache(&datastore, &VirtualClock::new());
+            cache.pin(HANDLE, vec![1, 2]);
+
+            // act
+            GattDatastore::write(
+                &*cache,
+                TCB_IDX,
+                HANDLE,
+                AttributeBackingType::Characteristic,
+                &[6],
+            )
+            .await
+            .unwrap();
+
+            // assert
+            assert_eq!(read(&cache, ANOTHER_TCB_IDX).await, Ok(vec![6]));
+        });
+    }
+
+    #[test]
+    fn test_errors_not_cached() {
+        block_on_locally(async {
+            // arrange
+            let datastore = TestDatastore::new(vec![]);
+            *datastore.value.borrow_mut() = Err(AttErrorCode::UNLIKELY_ERROR);
+            let cache = make_cache(&datastore, &VirtualClock::new());
+
+            // act
+            let result = read(&cache, TCB_IDX).await;
+
+            // assert
+            assert_eq!(result, Err(AttErrorCode::UNLIKELY_ERROR));
+            assert!(!cache.is_cached(HANDLE));
+        });
+    }
+
+    #[test]
+    fn test_read_in_progress_during_invalidation_not_cached() {
+        block_on_locally(async {
+            // arrange: a read held up in the datastore
+            let datastore = TestDatastore::new(vec![1, 2]);
+            let (open_gate, gate) = oneshot::channel();
+            *datastore.gate.borrow_mut() = Some(gate);
+            let cache = make_cache(&datastore, &VirtualClock::new());
+            let Err(pending) = try_await({
+                let cache = cache.clone();
+                async move { read(&cache, TCB_IDX).await }
+            })
+            .await
+            else {
+                unreachable!("the read did not wait for the datastore");
+            };
+
+            // act: invalidate the value before the read completes
+            cache.invalidate(HANDLE);
+            open_gate.send(()).unwrap();
+
+            // assert
+            assert_eq!(pending.await, Ok(vec![1, 2]));
+            assert!(!cache.is_cached(HANDLE));
+        });
+    }
+}
@@ -1,9 +1,33 @@
 //! This represents the TX end of an ATT Transport, to be either mocked (in
 //! test) or linked to FFI (in production).
 
-use crate::packets::{AttBuilder, SerializeError};
+use std::time::Duration;
 
-use super::ids::TransportIndex;
+use async_trait::async_trait;
+
+use crate::packets::{AttBuilder, AttOpcode, SerializeError};
+
+use super::ids::{BearerId, EattCid, TransportIndex};
+
+/// The PSM on which the L2CAP enhanced credit-based channels carrying EATT
+/// bearers are established (Core Spec 5.3 Vol 3A 4.2, Assigned Numbers 2.4)
+pub const EATT_PSM: u16 = 0x0027;
+
+/// The ATT transaction timeout (Core Spec 5.3 Vol 3F 3.3.3)
+pub const ATT_TRANSACTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Describes an ATT transaction that was not completed within the
+/// ATT_TRANSACTION_TIMEOUT, causing its bearer to be closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransactionTimeoutEvent {
+    /// The transport on which the transaction took place
+    pub tcb_idx: TransportIndex,
+    /// The EATT bearer on which the transaction took place, or None for the
+    /// unenhanced bearer
+    pub cid: Option<EattCid>,
+    /// The opcode of the request that was not answered
+    pub opcode: AttOpcode,
+}
 
 /// An instance of this trait will be provided to the GattModule on
 /// initialization.
@@ -19,4 +43,34 @@ pub trait AttTransport {
         tcb_idx: TransportIndex,
         packet: AttBuilder,
     ) -> Result<(), SerializeError>;
+
+    /// Serializes and sends a packet on an EATT bearer of the device associated
+    /// with the specified transport. As above, the packet may be dropped if the
+    /// channel is closed.
+    fn send_eatt_packet(
+        &self,
+        tcb_idx: TransportIndex,
+        cid: EattCid,
+        packet: AttBuilder,
+    ) -> Result<(), SerializeError>;
+
+    /// Closes the bearer on which a transaction timed out, since no further
+    /// ATT PDUs may be sent on it (5.3 3F 3.3.3). This is the LE link itself
+    /// for the unenhanced bearer, or the L2CAP channel of an EATT bearer.
+    fn close_bearer(&self, event: TransactionTimeoutEvent);
+}
+
+/// Reports the transmit capacity of the transport (e.g. the ACL buffer credits
+/// of the controller, or the credits of an EATT channel), optionally provided
+/// to the GattModule alongside the AttTransport. Notifications and indications
+/// then wait for credits, rather than being queued below us without bound.
+#[async_trait(?Send)]
+pub trait TransmitBackpressure {
+    /// Resolves once the transport can accept another packet on the given
+    /// bearer
+    async fn wait_for_credit(&self, bearer: BearerId);
+
+    /// The given bearer has closed, so it no longer waits for credits, and any
+    /// reserved for it may be released
+    fn on_bearer_closed(&self, _bearer: BearerId) {}
 }
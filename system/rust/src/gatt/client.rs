@@ -0,0 +1,111 @@
+//! This module is a GATT client, which discovers and accesses the attributes of
+//! a remote GATT server over an ATT bearer. It is independent of the server,
+//! and is not yet connected to the native stack (whose C++ GATT client still
+//! owns the client role on the shared ATT channel).
+
+pub mod att_client_bearer;
+pub mod discovery;
+pub mod discovery_cache;
+pub mod operations;
+
+#[cfg(test)]
+mod test;
+
+use bitflags::bitflags;
+
+use crate::{
+    core::uuid::Uuid,
+    gatt::ids::AttHandle,
+    packets::{AttErrorCode, SerializeError},
+};
+
+bitflags! {
+    /// The properties of a remote characteristic, from its declaration (Core
+    /// Spec 5.3 Vol 3G 3.3.1.1 Characteristic Properties)
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub struct CharacteristicProperties : u8 {
+        /// The value may be broadcast in advertising data
+        const BROADCAST = 0x01;
+        /// The value may be read
+        const READ = 0x02;
+        /// The value may be written using WRITE_CMD
+        const WRITE_WITHOUT_RESPONSE = 0x04;
+        /// The value may be written using WRITE_REQ
+        const WRITE = 0x08;
+        /// The value may be notified
+        const NOTIFY = 0x10;
+        /// The value may be indicated
+        const INDICATE = 0x20;
+        /// The value may be written using SIGNED_WRITE_CMD
+        const AUTHENTICATED_SIGNED_WRITES = 0x40;
+        /// Further properties are in the Characteristic Extended Properties descriptor
+        const EXTENDED_PROPERTIES = 0x80;
+    }
+}
+
+/// A service on the remote server
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GattServiceInfo {
+    /// The handle of the service declaration
+    pub handle: AttHandle,
+    /// The handle of the last attribute in the service
+    pub end_handle: AttHandle,
+    /// The UUID of the service
+    pub type_: Uuid,
+}
+
+/// A characteristic on the remote server
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GattCharacteristicInfo {
+    /// The handle of the characteristic declaration
+    pub declaration_handle: AttHandle,
+    /// The handle of the characteristic value
+    pub value_handle: AttHandle,
+    /// The properties from the characteristic declaration
+    pub properties: CharacteristicProperties,
+    /// The UUID of the characteristic
+    pub type_: Uuid,
+}
+
+/// A descriptor on the remote server
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GattDescriptorInfo {
+    /// The handle of the descriptor
+    pub handle: AttHandle,
+    /// The UUID of the descriptor
+    pub type_: Uuid,
+}
+
+/// The errors that can occur during a GATT client procedure
+#[derive(Debug, PartialEq)]
+pub enum GattClientError {
+    /// The server rejected a request with an ATT_ERROR_RSP
+    AttError {
+        /// The handle in error, as reported by the server
+        handle: AttHandle,
+        /// The error code
+        error_code: AttErrorCode,
+    },
+    /// The server did not respond within the ATT transaction timeout, so no
+    /// further requests can be sent on this bearer (Core Spec 5.3 Vol 3F
+    /// 3.3.3)
+    Timeout,
+    /// The bearer was closed
+    ConnectionDropped,
+    /// The request failed to serialize
+    SerializeError(SerializeError),
+    /// The value is too long to be sent in a single PDU with the current MTU
+    DataExceedsMtu {
+        /// The actual max value size permitted
+        mtu: usize,
+    },
+    /// The value is longer than any attribute may be (Core Spec 5.3 Vol 3F
+    /// 3.2.9)
+    ValueTooLong {
+        /// The maximum length of an attribute value
+        max_len: usize,
+    },
+    /// The server sent a malformed response, or one that does not match the
+    /// request
+    InvalidResponse,
+}
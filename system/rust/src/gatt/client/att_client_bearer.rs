@@ -0,0 +1,553 @@
+//! This module handles the client side of a single ATT bearer. Requests are
+//! sent one at a time, each waiting for its response (or the ATT transaction
+//! timeout). Notifications and indications from the server are demultiplexed by
+//! handle to the registered listeners.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+};
+
+use log::{trace, warn};
+use tokio::{
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot, Mutex,
+    },
+    time::timeout,
+};
+
+use crate::{
+    gatt::{
+        channel::ATT_TRANSACTION_TIMEOUT,
+        ids::AttHandle,
+        mtu::DEFAULT_ATT_MTU,
+        opcode_types::{classify_opcode, OperationType},
+    },
+    packets::{
+        AttBuilder, AttChild, AttErrorResponseView, AttExchangeMtuRequestBuilder,
+        AttExchangeMtuResponseView, AttHandleValueConfirmationBuilder,
+        AttHandleValueIndicationView, AttHandleValueNotificationView, AttOpcode, AttView,
+        OwnedAttView, Packet, SerializeError,
+    },
+    utils::packet::HACK_child_to_opcode,
+};
+
+use super::GattClientError;
+
+/// The client side of a single ATT bearer
+pub struct AttClientBearer {
+    send_packet: Box<dyn Fn(AttBuilder) -> Result<(), SerializeError>>,
+    mtu: Cell<usize>,
+
+    // only one request may be outstanding at a time (Core Spec 5.3 Vol 3F 3.3.2)
+    request_lock: Mutex<()>,
+    // the sender for the response to the outstanding request, if any
+    pending_response: RefCell<Option<oneshot::Sender<OwnedAttView>>>,
+    // once a transaction times out, no further requests may be sent
+    timed_out: Cell<bool>,
+
+    listeners: RefCell<HashMap<AttHandle, Vec<UnboundedSender<Vec<u8>>>>>,
+}
+
+impl AttClientBearer {
+    /// Constructor, wrapping an ATT channel (for outgoing packets). Incoming
+    /// packets must be passed to handle_packet().
+    pub fn new(send_packet: impl Fn(AttBuilder) -> Result<(), SerializeError> + 'static) -> Self {
+        Self {
+            send_packet: Box::new(send_packet),
+            mtu: Cell::new(DEFAULT_ATT_MTU),
+            request_lock: Mutex::new(()),
+            pending_response: RefCell::new(None),
+            timed_out: Cell::new(false),
+            listeners: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Get the MTU currently in use on this bearer (the default, until it is
+    /// exchanged)
+    pub fn get_mtu(&self) -> usize {
+        self.mtu.get()
+    }
+
+    /// Exchange the MTU with the server, offering our client_rx_mtu, and
+    /// return the MTU now in use
+    pub async fn exchange_mtu(&self, client_rx_mtu: usize) -> Result<usize, GattClientError> {
+        let response =
+            self.send_request(AttExchangeMtuRequestBuilder { mtu: client_rx_mtu as u16 }).await?;
+        let server_rx_mtu = AttExchangeMtuResponseView::try_parse(response.view())
+            .map_err(|_| GattClientError::InvalidResponse)?
+            .get_mtu() as usize;
+        // Core Spec 5.3 Vol 3F 3.4.2.2
+        let mtu = client_rx_mtu.min(server_rx_mtu).max(DEFAULT_ATT_MTU);
+        trace!("MTU exchanged, now using {mtu}");
+        self.mtu.set(mtu);
+        Ok(mtu)
+    }
+
+    /// Register to receive the values notified or indicated by the server for
+    /// a given handle. Note that the server will only send these if the
+    /// corresponding CCCD has been written.
+    pub fn register_listener(&self, handle: AttHandle) -> UnboundedReceiver<Vec<u8>> {
+        let (tx, rx) = unbounded_channel();
+        self.listeners.borrow_mut().entry(handle).or_default().push(tx);
+        rx
+    }
+
+    /// Send a request, and wait for its response. If the server replies with
+    /// an ATT_ERROR_RSP, it is returned as a GattClientError::AttError.
+    pub async fn send_request(
+        &self,
+        request: impl Into<AttChild>,
+    ) -> Result<OwnedAttView, GattClientError> {
+        let request = request.into();
+        let opcode = HACK_child_to_opcode(&request);
+        let _guard = self.request_lock.lock().await;
+
+        // if a previous request was abandoned by its caller, its response must
+        // still be received before the next request can be sent
+        if self.pending_response.borrow().is_some() {
+            trace!("waiting for the response to an abandoned request");
+            self.wait_for_response().await?;
+        }
+        if self.timed_out.get() {
+            return Err(GattClientError::Timeout);
+        }
+
+        let response = self.wait_for_response();
+        (self.send_packet)(AttBuilder { opcode, _child_: request })
+            .map_err(GattClientError::SerializeError)?;
+        let response = response.await?;
+
+        let response_opcode = response.view().get_opcode();
+        if response_opcode == AttOpcode::ERROR_RESPONSE {
+            let error = AttErrorResponseView::try_parse(response.view())
+                .map_err(|_| GattClientError::InvalidResponse)?;
+            if error.get_opcode_in_error() != opcode {
+                warn!(
+                    "got error response for {:?}, expected {opcode:?}",
+                    error.get_opcode_in_error()
+                );
+                return Err(GattClientError::InvalidResponse);
+            }
+            return Err(GattClientError::AttError {
+                handle: error.get_handle_in_error().into(),
+                error_code: error.get_error_code(),
+            });
+        }
+        if Some(response_opcode) != Self::expected_response(opcode) {
+            warn!("got {response_opcode:?} in response to {opcode:?}");
+            return Err(GattClientError::InvalidResponse);
+        }
+        Ok(response)
+    }
+
+    /// Send a command, which the server does not respond to
+    pub fn send_command(&self, command: impl Into<AttChild>) -> Result<(), GattClientError> {
+        if self.timed_out.get() {
+            return Err(GattClientError::Timeout);
+        }
+        self.send_packet(command)
+    }
+
+    /// Handle an incoming packet from the server
+    pub fn handle_packet(&self, packet: AttView<'_>) {
+        match classify_opcode(packet.get_opcode()) {
+            OperationType::Response => {
+                let Some(pending_response) = self.pending_response.take() else {
+                    warn!("got unexpected response {:?}, dropping", packet.get_opcode());
+                    return;
+                };
+                // if the request was abandoned, the response is simply dropped
+                let _ = pending_response.send(packet.to_owned_packet());
+            }
+            OperationType::Notification => {
+                let Ok(notification) = AttHandleValueNotificationView::try_parse(packet) else {
+                    warn!("dropping malformed notification");
+                    return;
+                };
+                self.dispatch(
+                    notification.get_handle().into(),
+                    notification.get_value().get_raw_payload().collect(),
+                );
+            }
+            OperationType::Indication => {
+                let Ok(indication) = AttHandleValueIndicationView::try_parse(packet) else {
+                    warn!("dropping malformed indication");
+                    return;
+                };
+                self.dispatch(
+                    indication.get_handle().into(),
+                    indication.get_value().get_raw_payload().collect(),
+                );
+                if let Err(err) = self.send_packet(AttHandleValueConfirmationBuilder {}) {
+                    warn!("failed to confirm indication: {err:?}");
+                }
+            }
+            OperationType::Command | OperationType::Request | OperationType::Confirmation => {
+                unreachable!("the arbiter should not let us receive these packet types")
+            }
+        }
+    }
+
+    fn dispatch(&self, handle: AttHandle, value: Vec<u8>) {
+        let mut listeners = self.listeners.borrow_mut();
+        let Some(handle_listeners) = listeners.get_mut(&handle) else {
+            trace!("no listener for value of {handle:?}, dropping");
+            return;
+        };
+        // listeners are removed once their receivers are dropped
+        handle_listeners.retain(|listener| listener.send(value.clone()).is_ok());
+        if handle_listeners.is_empty() {
+            listeners.remove(&handle);
+        }
+    }
+
+    /// Register for the response to the outstanding request, and wait for it
+    /// (or the transaction timeout)
+    fn wait_for_response(
+        &self,
+    ) -> impl std::future::Future<Output = Result<OwnedAttView, GattClientError>> + '_ {
+        let (tx, rx) = oneshot::channel();
+        self.pending_response.replace(Some(tx));
+        async move {
+            match timeout(ATT_TRANSACTION_TIMEOUT, rx).await {
+                Ok(Ok(response)) => Ok(response),
+                Ok(Err(_)) => Err(GattClientError::ConnectionDropped),
+                Err(_) => {
+                    warn!("ATT transaction timed out, no further requests can be sent");
+                    self.timed_out.set(true);
+                    self.pending_response.take();
+                    Err(GattClientError::Timeout)
+                }
+            }
+        }
+    }
+
+    fn send_packet(&self, packet: impl Into<AttChild>) -> Result<(), GattClientError> {
+        let child = packet.into();
+        let packet = AttBuilder { opcode: HACK_child_to_opcode(&child), _child_: child };
+        (self.send_packet)(packet).map_err(GattClientError::SerializeError)
+    }
+
+    /// The response opcode expected for each request opcode (Core Spec 5.3 Vol
+    /// 3F 3.4.8)
+    fn expected_response(request: AttOpcode) -> Option<AttOpcode> {
+        Some(match request {
+            AttOpcode::EXCHANGE_MTU_REQUEST => AttOpcode::EXCHANGE_MTU_RESPONSE,
+            AttOpcode::FIND_INFORMATION_REQUEST => AttOpcode::FIND_INFORMATION_RESPONSE,
+            AttOpcode::FIND_BY_TYPE_VALUE_REQUEST => AttOpcode::FIND_BY_TYPE_VALUE_RESPONSE,
+            AttOpcode::READ_BY_TYPE_REQUEST => AttOpcode::READ_BY_TYPE_RESPONSE,
+            AttOpcode::READ_REQUEST => AttOpcode::READ_RESPONSE,
+            AttOpcode::READ_BLOB_REQUEST => AttOpcode::READ_BLOB_RESPONSE,
+            AttOpcode::READ_MULTIPLE_REQUEST => AttOpcode::READ_MULTIPLE_RESPONSE,
+            AttOpcode::READ_BY_GROUP_TYPE_REQUEST => AttOpcode::READ_BY_GROUP_TYPE_RESPONSE,
+            AttOpcode::WRITE_REQUEST => AttOpcode::WRITE_RESPONSE,
+            AttOpcode::PREPARE_WRITE_REQUEST => AttOpcode::PREPARE_WRITE_RESPONSE,
+            AttOpcode::EXECUTE_WRITE_REQUEST => AttOpcode::EXECUTE_WRITE_RESPONSE,
+            AttOpcode::READ_MULTIPLE_VARIABLE_REQUEST => AttOpcode::READ_MULTIPLE_VARIABLE_RESPONSE,
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::{task::spawn_local, time::Instant};
+
+    use crate::{
+        gatt::client::test::mock_server::MockServer,
+        packets::{
+            AttAttributeDataChild, AttErrorCode, AttErrorResponseBuilder,
+            AttExchangeMtuResponseBuilder, AttHandleValueIndicationBuilder,
+            AttHandleValueNotificationBuilder, AttReadRequestBuilder, AttReadResponseBuilder,
+            AttReadResponseView, AttWriteResponseBuilder,
+        },
+        utils::{packet::build_att_data, task::block_on_locally},
+    };
+
+    use super::*;
+
+    const HANDLE: AttHandle = AttHandle(3);
+    const ANOTHER_HANDLE: AttHandle = AttHandle(5);
+
+    fn read_request() -> AttReadRequestBuilder {
+        AttReadRequestBuilder { attribute_handle: HANDLE.into() }
+    }
+
+    fn read_response(value: &[u8]) -> AttReadResponseBuilder {
+        AttReadResponseBuilder {
+            value: build_att_data(AttAttributeDataChild::RawData(value.into())),
+        }
+    }
+
+    fn read_value(response: OwnedAttView) -> Vec<u8> {
+        AttReadResponseView::try_parse(response.view())
+            .unwrap()
+            .get_value()
+            .get_raw_payload()
+            .collect()
+    }
+
+    #[test]
+    fn test_request_and_response() {
+        block_on_locally(async {
+            // arrange
+            let mut server = MockServer::new();
+
+            // act: send a request, and reply to it
+            let pending = spawn_local({
+                let bearer = server.bearer.clone();
+                async move { bearer.send_request(read_request()).await }
+            });
+            server.expect_and_reply(read_request(), read_response(&[1, 2])).await;
+
+            // assert: the response was returned
+            assert_eq!(read_value(pending.await.unwrap().unwrap()), vec![1, 2]);
+        });
+    }
+
+    #[test]
+    fn test_error_response() {
+        block_on_locally(async {
+            // arrange
+            let mut server = MockServer::new();
+
+            // act
+            let pending = spawn_local({
+                let bearer = server.bearer.clone();
+                async move { bearer.send_request(read_request()).await }
+            });
+            server
+                .expect_and_reply(
+                    read_request(),
+                    AttErrorResponseBuilder {
+                        opcode_in_error: AttOpcode::READ_REQUEST,
+                        handle_in_error: HANDLE.into(),
+                        error_code: AttErrorCode::READ_NOT_PERMITTED,
+                    },
+                )
+                .await;
+
+            // assert: the error is surfaced
+            assert_eq!(
+                pending.await.unwrap().unwrap_err(),
+                GattClientError::AttError {
+                    handle: HANDLE,
+                    error_code: AttErrorCode::READ_NOT_PERMITTED
+                }
+            );
+        });
+    }
+
+    #[test]
+    fn test_mismatched_response() {
+        block_on_locally(async {
+            // arrange
+            let mut server = MockServer::new();
+
+            // act: reply to a read with a write response
+            let pending = spawn_local({
+                let bearer = server.bearer.clone();
+                async move { bearer.send_request(read_request()).await }
+            });
+            server.expect_and_reply(read_request(), AttWriteResponseBuilder {}).await;
+
+            // assert
+            assert_eq!(pending.await.unwrap().unwrap_err(), GattClientError::InvalidResponse);
+        });
+    }
+
+    #[test]
+    fn test_requests_are_serialized() {
+        block_on_locally(async {
+            // arrange
+            let mut server = MockServer::new();
+
+            // act: send two requests at once
+            let first = spawn_local({
+                let bearer = server.bearer.clone();
+                async move { bearer.send_request(read_request()).await }
+            });
+            let second = spawn_local({
+                let bearer = server.bearer.clone();
+                async move { bearer.send_request(read_request()).await }
+            });
+            let first_request = server.recv().await;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+
+            // assert: the second request is only sent once the first is answered
+            assert_eq!(first_request._child_, read_request().into());
+            assert!(!server.has_pending_packets());
+            server.reply(read_response(&[1]));
+            server.expect_and_reply(read_request(), read_response(&[2])).await;
+            assert_eq!(read_value(first.await.unwrap().unwrap()), vec![1]);
+            assert_eq!(read_value(second.await.unwrap().unwrap()), vec![2]);
+        });
+    }
+
+    #[test]
+    fn test_transaction_timeout() {
+        block_on_locally(async {
+            // arrange
+            let mut server = MockServer::new();
+            let time_sent = Instant::now();
+
+            // act: send a request, and never reply
+            let res = server.bearer.send_request(read_request()).await;
+            let time_failed = Instant::now();
+            let next = server.bearer.send_request(read_request()).await;
+
+            // assert: the request timed out, and no further requests are sent
+            assert_eq!(res.unwrap_err(), GattClientError::Timeout);
+            assert!(time_failed.duration_since(time_sent) >= ATT_TRANSACTION_TIMEOUT);
+            assert_eq!(next.unwrap_err(), GattClientError::Timeout);
+            server.recv().await;
+            assert!(!server.has_pending_packets());
+        });
+    }
+
+    #[test]
+    fn test_abandoned_request() {
+        block_on_locally(async {
+            // arrange: send a request, and then abandon it
+            let mut server = MockServer::new();
+            let abandoned = spawn_local({
+                let bearer = server.bearer.clone();
+                async move { bearer.send_request(read_request()).await }
+            });
+            server.recv().await;
+            abandoned.abort();
+
+            // act: send another request, and reply to both
+            let pending = spawn_local({
+                let bearer = server.bearer.clone();
+                async move { bearer.send_request(read_request()).await }
+            });
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            let sent_before_stale_reply = server.has_pending_packets();
+            server.reply(read_response(&[1]));
+            server.expect_and_reply(read_request(), read_response(&[2])).await;
+
+            // assert: the new request waited for the stale response, and got its own
+            assert!(!sent_before_stale_reply);
+            assert_eq!(read_value(pending.await.unwrap().unwrap()), vec![2]);
+        });
+    }
+
+    #[test]
+    fn test_exchange_mtu() {
+        block_on_locally(async {
+            // arrange
+            let mut server = MockServer::new();
+
+            // act: offer a larger MTU than the server supports
+            let pending = spawn_local({
+                let bearer = server.bearer.clone();
+                async move { bearer.exchange_mtu(512).await }
+            });
+            server
+                .expect_and_reply(
+                    AttExchangeMtuRequestBuilder { mtu: 512 },
+                    AttExchangeMtuResponseBuilder { mtu: 100 },
+                )
+                .await;
+
+            // assert: the smaller of the two is used
+            assert_eq!(pending.await.unwrap(), Ok(100));
+            assert_eq!(server.bearer.get_mtu(), 100);
+        });
+    }
+
+    #[test]
+    fn test_notifications_demultiplexed_by_handle() {
+        block_on_locally(async {
+            // arrange
+            let server = MockServer::new();
+            let mut listener = server.bearer.register_listener(HANDLE);
+            let mut another_listener = server.bearer.register_listener(ANOTHER_HANDLE);
+
+            // act: notify both handles
+            server.reply(AttHandleValueNotificationBuilder {
+                handle: HANDLE.into(),
+                value: build_att_data(AttAttributeDataChild::RawData([1].into())),
+            });
+            server.reply(AttHandleValueNotificationBuilder {
+                handle: ANOTHER_HANDLE.into(),
+                value: build_att_data(AttAttributeDataChild::RawData([2].into())),
+            });
+
+            // assert: each listener only received its own value
+            assert_eq!(listener.recv().await, Some(vec![1]));
+            assert_eq!(another_listener.recv().await, Some(vec![2]));
+            assert!(listener.is_empty());
+            assert!(another_listener.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_dropped_listener_is_removed() {
+        block_on_locally(async {
+            // arrange
+            let server = MockServer::new();
+            let dropped = server.bearer.register_listener(HANDLE);
+            let mut listener = server.bearer.register_listener(HANDLE);
+            drop(dropped);
+
+            // act
+            server.reply(AttHandleValueNotificationBuilder {
+                handle: HANDLE.into(),
+                value: build_att_data(AttAttributeDataChild::RawData([1].into())),
+            });
+            assert_eq!(listener.recv().await, Some(vec![1]));
+            drop(listener);
+            server.reply(AttHandleValueNotificationBuilder {
+                handle: HANDLE.into(),
+                value: build_att_data(AttAttributeDataChild::RawData([2].into())),
+            });
+
+            // assert: no listeners remain for the handle
+            assert!(server.bearer.listeners.borrow().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_indication_is_confirmed() {
+        block_on_locally(async {
+            // arrange
+            let mut server = MockServer::new();
+            let mut listener = server.bearer.register_listener(HANDLE);
+
+            // act
+            server.reply(AttHandleValueIndicationBuilder {
+                handle: HANDLE.into(),
+                value: build_att_data(AttAttributeDataChild::RawData([1, 2].into())),
+            });
+
+            // assert: the value was delivered, and the indication confirmed
+            assert_eq!(listener.recv().await, Some(vec![1, 2]));
+            assert_eq!(server.recv().await._child_, AttHandleValueConfirmationBuilder {}.into());
+        });
+    }
+
+    #[test]
+    fn test_unexpected_response_dropped() {
+        block_on_locally(async {
+            // arrange
+            let mut server = MockServer::new();
+
+            // act: reply with no request outstanding, then send a real request
+            server.reply(read_response(&[1]));
+            let pending = spawn_local({
+                let bearer = server.bearer.clone();
+                async move { bearer.send_request(read_request()).await }
+            });
+            server.expect_and_reply(read_request(), read_response(&[2])).await;
+
+            // assert: only the second response is used
+            assert_eq!(read_value(pending.await.unwrap().unwrap()), vec![2]);
+        });
+    }
+}
@@ -0,0 +1,601 @@
+//! This module implements the GATT discovery procedures (Core Spec 5.3 Vol 3G
+//! 4.4 - 4.7). Each procedure repeats its request, starting after the last
+//! attribute returned, until the server reports that there are no more.
+
+use crate::{
+    core::uuid::Uuid,
+    gatt::{
+        ids::AttHandle,
+        server::{
+            gatt_database::{CHARACTERISTIC_UUID, PRIMARY_SERVICE_DECLARATION_UUID},
+            robust_caching::{DatabaseHash, DATABASE_HASH_UUID},
+        },
+    },
+    packets::{
+        AttErrorCode, AttFindByTypeValueRequestBuilder, AttFindByTypeValueResponseView,
+        AttFindInformationLongResponseView, AttFindInformationRequestBuilder,
+        AttFindInformationResponseFormat, AttFindInformationResponseView,
+        AttFindInformationShortResponseView, AttReadByGroupTypeRequestBuilder,
+        AttReadByGroupTypeResponseView, AttReadByTypeRequestBuilder, AttReadByTypeResponseView,
+        Packet, UuidAsAttDataBuilder,
+    },
+    utils::packet::build_att_data,
+};
+
+use super::{
+    att_client_bearer::AttClientBearer, CharacteristicProperties, GattCharacteristicInfo,
+    GattClientError, GattDescriptorInfo, GattServiceInfo,
+};
+
+/// A service discovered on the remote server, with all its characteristics
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiscoveredService {
+    /// The service itself
+    pub info: GattServiceInfo,
+    /// The characteristics of the service, in handle order
+    pub characteristics: Vec<DiscoveredCharacteristic>,
+}
+
+/// A characteristic discovered on the remote server, with all its descriptors
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiscoveredCharacteristic {
+    /// The characteristic itself
+    pub info: GattCharacteristicInfo,
+    /// The descriptors of the characteristic, in handle order
+    pub descriptors: Vec<GattDescriptorInfo>,
+}
+
+/// Discover All Primary Services (Core Spec 5.3 Vol 3G 4.4.1)
+pub async fn discover_all_primary_services(
+    bearer: &AttClientBearer,
+) -> Result<Vec<GattServiceInfo>, GattClientError> {
+    let mut services = vec![];
+    let mut start = AttHandle::MIN;
+    loop {
+        let response = match bearer
+            .send_request(AttReadByGroupTypeRequestBuilder {
+                starting_handle: start.into(),
+                ending_handle: AttHandle::MAX.into(),
+                attribute_group_type: PRIMARY_SERVICE_DECLARATION_UUID.into(),
+            })
+            .await
+        {
+            Err(err) if is_end_of_procedure(&err) => return Ok(services),
+            response => response?,
+        };
+        let response = AttReadByGroupTypeResponseView::try_parse(response.view())
+            .map_err(|_| GattClientError::InvalidResponse)?;
+
+        let mut last_end_handle = None;
+        for element in response.get_data_iter() {
+            let value = element.get_value().get_raw_payload().collect::<Vec<_>>();
+            let service = GattServiceInfo {
+                handle: element.get_handle().into(),
+                end_handle: element.get_end_group_handle().into(),
+                type_: Uuid::try_from_le_slice(&value).ok_or(GattClientError::InvalidResponse)?,
+            };
+            last_end_handle = Some(service.end_handle);
+            services.push(service);
+        }
+
+        match next_start_handle(start, last_end_handle)? {
+            Some(next) => start = next,
+            None => return Ok(services),
+        }
+    }
+}
+
+/// Discover Primary Service by Service UUID (Core Spec 5.3 Vol 3G 4.4.2)
+pub async fn discover_primary_services_by_uuid(
+    bearer: &AttClientBearer,
+    uuid: Uuid,
+) -> Result<Vec<GattServiceInfo>, GattClientError> {
+    let mut services = vec![];
+    let mut start = AttHandle::MIN;
+    loop {
+        let response = match bearer
+            .send_request(AttFindByTypeValueRequestBuilder {
+                starting_handle: start.into(),
+                ending_handle: AttHandle::MAX.into(),
+                attribute_type: PRIMARY_SERVICE_DECLARATION_UUID
+                    .try_into()
+                    .expect("the primary service UUID is a 16-bit UUID"),
+                attribute_value: build_att_data(UuidAsAttDataBuilder { uuid: uuid.into() }),
+            })
+            .await
+        {
+            Err(err) if is_end_of_procedure(&err) => return Ok(services),
+            response => response?,
+        };
+        let response = AttFindByTypeValueResponseView::try_parse(response.view())
+            .map_err(|_| GattClientError::InvalidResponse)?;
+
+        let mut last_end_handle = None;
+        for range in response.get_handles_info_iter() {
+            let service = GattServiceInfo {
+                handle: range.get_found_attribute_handle().into(),
+                end_handle: range.get_group_end_handle().into(),
+                type_: uuid,
+            };
+            last_end_handle = Some(service.end_handle);
+            services.push(service);
+        }
+
+        match next_start_handle(start, last_end_handle)? {
+            Some(next) => start = next,
+            None => return Ok(services),
+        }
+    }
+}
+
+/// Discover All Characteristics of a Service (Core Spec 5.3 Vol 3G 4.6.1)
+pub async fn discover_characteristics(
+    bearer: &AttClientBearer,
+    service: &GattServiceInfo,
+) -> Result<Vec<GattCharacteristicInfo>, GattClientError> {
+    let mut characteristics = vec![];
+    let mut start = service.handle;
+    while start <= service.end_handle {
+        let response = match bearer
+            .send_request(AttReadByTypeRequestBuilder {
+                starting_handle: start.into(),
+                ending_handle: service.end_handle.into(),
+                attribute_type: CHARACTERISTIC_UUID.into(),
+            })
+            .await
+        {
+            Err(err) if is_end_of_procedure(&err) => break,
+            response => response?,
+        };
+        let response = AttReadByTypeResponseView::try_parse(response.view())
+            .map_err(|_| GattClientError::InvalidResponse)?;
+
+        let mut last_handle = None;
+        for element in response.get_data_iter() {
+            let declaration_handle = element.get_handle().into();
+            let value = element.get_value().get_raw_payload().collect::<Vec<_>>();
+            characteristics.push(parse_characteristic_declaration(declaration_handle, &value)?);
+            last_handle = Some(declaration_handle);
+        }
+
+        match next_start_handle(start, last_handle)? {
+            Some(next) => start = next,
+            None => break,
+        }
+    }
+    Ok(characteristics)
+}
+
+/// Discover All Characteristic Descriptors in the given (inclusive) range
+/// (Core Spec 5.3 Vol 3G 4.7.1). The range should start after the
+/// characteristic value, and end before the next characteristic declaration
+/// (or at the end of the service).
+pub async fn discover_descriptors(
+    bearer: &AttClientBearer,
+    start_handle: AttHandle,
+    end_handle: AttHandle,
+) -> Result<Vec<GattDescriptorInfo>, GattClientError> {
+    let mut descriptors = vec![];
+    let mut start = start_handle;
+    while start <= end_handle {
+        let response = match bearer
+            .send_request(AttFindInformationRequestBuilder {
+                starting_handle: start.into(),
+                ending_handle: end_handle.into(),
+            })
+            .await
+        {
+            Err(err) if is_end_of_procedure(&err) => break,
+            response => response?,
+        };
+        let response = AttFindInformationResponseView::try_parse(response.view())
+            .map_err(|_| GattClientError::InvalidResponse)?;
+
+        let len_before = descriptors.len();
+        match response.get_format() {
+            AttFindInformationResponseFormat::SHORT => {
+                let response = AttFindInformationShortResponseView::try_parse(response)
+                    .map_err(|_| GattClientError::InvalidResponse)?;
+                descriptors.extend(response.get_data_iter().map(|entry| GattDescriptorInfo {
+                    handle: entry.get_handle().into(),
+                    type_: entry.get_uuid().into(),
+                }));
+            }
+            AttFindInformationResponseFormat::LONG => {
+                let response = AttFindInformationLongResponseView::try_parse(response)
+                    .map_err(|_| GattClientError::InvalidResponse)?;
+                descriptors.extend(response.get_data_iter().map(|entry| GattDescriptorInfo {
+                    handle: entry.get_handle().into(),
+                    type_: entry.get_uuid().into(),
+                }));
+            }
+        }
+
+        let last_handle = descriptors[len_before..].last().map(|descriptor| descriptor.handle);
+        match next_start_handle(start, last_handle)? {
+            Some(next) => start = next,
+            None => break,
+        }
+    }
+    Ok(descriptors)
+}
+
+/// Discover all primary services on the server, along with their
+/// characteristics and descriptors
+pub async fn discover_all(
+    bearer: &AttClientBearer,
+) -> Result<Vec<DiscoveredService>, GattClientError> {
+    let mut out = vec![];
+    for service in discover_all_primary_services(bearer).await? {
+        let characteristics = discover_characteristics(bearer, &service).await?;
+
+        let mut discovered_characteristics = vec![];
+        for (i, characteristic) in characteristics.iter().enumerate() {
+            // the descriptors lie between the value and the next declaration
+            let end_handle = match characteristics.get(i + 1) {
+                Some(next) => AttHandle(next.declaration_handle.0 - 1),
+                None => service.end_handle,
+            };
+            let descriptors = if characteristic.value_handle < end_handle {
+                discover_descriptors(
+                    bearer,
+                    AttHandle(characteristic.value_handle.0 + 1),
+                    end_handle,
+                )
+                .await?
+            } else {
+                vec![]
+            };
+            discovered_characteristics
+                .push(DiscoveredCharacteristic { info: characteristic.clone(), descriptors });
+        }
+
+        out.push(DiscoveredService { info: service, characteristics: discovered_characteristics });
+    }
+    Ok(out)
+}
+
+/// Read the Database Hash of the server (Core Spec 5.3 Vol 3G 7.3), using the
+/// Read Using Characteristic UUID procedure. Returns None if the server does
+/// not expose one.
+pub async fn read_database_hash(
+    bearer: &AttClientBearer,
+) -> Result<Option<DatabaseHash>, GattClientError> {
+    let response = match bearer
+        .send_request(AttReadByTypeRequestBuilder {
+            starting_handle: AttHandle::MIN.into(),
+            ending_handle: AttHandle::MAX.into(),
+            attribute_type: DATABASE_HASH_UUID.into(),
+        })
+        .await
+    {
+        Err(err) if is_end_of_procedure(&err) => return Ok(None),
+        response => response?,
+    };
+    let response = AttReadByTypeResponseView::try_parse(response.view())
+        .map_err(|_| GattClientError::InvalidResponse)?;
+    let Some(element) = response.get_data_iter().next() else {
+        return Err(GattClientError::InvalidResponse);
+    };
+    let value = element.get_value().get_raw_payload().collect::<Vec<_>>();
+    Ok(Some(value.try_into().map_err(|_| GattClientError::InvalidResponse)?))
+}
+
+/// Parse the value of a characteristic declaration (Core Spec 5.3 Vol 3G
+/// 3.3.1)
+fn parse_characteristic_declaration(
+    declaration_handle: AttHandle,
+    value: &[u8],
+) -> Result<GattCharacteristicInfo, GattClientError> {
+    let [properties, handle_lo, handle_hi, uuid @ ..] = value else {
+        return Err(GattClientError::InvalidResponse);
+    };
+    Ok(GattCharacteristicInfo {
+        declaration_handle,
+        value_handle: AttHandle(u16::from_le_bytes([*handle_lo, *handle_hi])),
+        properties: CharacteristicProperties::from_bits_retain(*properties),
+        type_: Uuid::try_from_le_slice(uuid).ok_or(GattClientError::InvalidResponse)?,
+    })
+}
+
+/// ATTRIBUTE_NOT_FOUND marks the successful end of a discovery procedure
+fn is_end_of_procedure(err: &GattClientError) -> bool {
+    matches!(err, GattClientError::AttError { error_code: AttErrorCode::ATTRIBUTE_NOT_FOUND, .. })
+}
+
+/// Where a discovery procedure should resume, given the last handle returned in
+/// the previous response, or None if the procedure is complete. A response
+/// that does not advance past the previous start handle is rejected, so a
+/// misbehaving server cannot keep us looping forever.
+fn next_start_handle(
+    start: AttHandle,
+    last_handle: Option<AttHandle>,
+) -> Result<Option<AttHandle>, GattClientError> {
+    let Some(last_handle) = last_handle else {
+        return Err(GattClientError::InvalidResponse);
+    };
+    if last_handle < start {
+        return Err(GattClientError::InvalidResponse);
+    }
+    if last_handle == AttHandle::MAX {
+        return Ok(None);
+    }
+    Ok(Some(AttHandle(last_handle.0 + 1)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use tokio::task::spawn_local;
+
+    use crate::{
+        core::shared_box::SharedBox,
+        gatt::{
+            client::test::{loopback::connect_to_database, mock_server::MockServer},
+            mocks::mock_datastore::MockDatastore,
+            mtu::DEFAULT_ATT_MTU,
+            server::gatt_database::{
+                AttPermissions, GattCharacteristicWithHandle, GattDatabase,
+                GattDescriptorWithHandle, GattServiceWithHandle,
+                CLIENT_CHARACTERISTIC_CONFIGURATION_UUID,
+            },
+        },
+        packets::{
+            AttOpcode, AttReadByGroupTypeDataElementBuilder, AttReadByGroupTypeResponseBuilder,
+            GattServiceDeclarationValueBuilder,
+        },
+        utils::task::block_on_locally,
+    };
+
+    use super::*;
+
+    const SHORT_SERVICE_TYPE: Uuid = Uuid::new(0x180F);
+    const LONG_SERVICE_TYPE: Uuid = Uuid::new(0x12345678);
+    const CHARACTERISTIC_TYPE: Uuid = Uuid::new(0x2A19);
+    const ANOTHER_CHARACTERISTIC_TYPE: Uuid = Uuid::new(0x2A1A);
+    const LONG_DESCRIPTOR_TYPE: Uuid = Uuid::new(0x9ABCDEF0);
+
+    /// A database with a service using a 16-bit UUID (handles 1-6) and one
+    /// using a 128-bit UUID (handles 10-13)
+    fn make_db() -> SharedBox<GattDatabase> {
+        let db = SharedBox::new(GattDatabase::new());
+        db.add_service_with_handles(
+            GattServiceWithHandle {
+                handle: AttHandle(1),
+                type_: SHORT_SERVICE_TYPE,
+                characteristics: vec![
+                    GattCharacteristicWithHandle {
+                        handle: AttHandle(3),
+                        type_: CHARACTERISTIC_TYPE,
+                        permissions: AttPermissions::READABLE | AttPermissions::NOTIFY,
+                        // the CCCD is added at handle 4
+                        descriptors: vec![],
+                    },
+                    GattCharacteristicWithHandle {
+                        handle: AttHandle(6),
+                        type_: ANOTHER_CHARACTERISTIC_TYPE,
+                        permissions: AttPermissions::WRITABLE_WITH_RESPONSE,
+                        descriptors: vec![],
+                    },
+                ],
+            },
+            Rc::new(MockDatastore::new().0),
+        )
+        .unwrap();
+        db.add_service_with_handles(
+            GattServiceWithHandle {
+                handle: AttHandle(10),
+                type_: LONG_SERVICE_TYPE,
+                characteristics: vec![GattCharacteristicWithHandle {
+                    handle: AttHandle(12),
+                    type_: CHARACTERISTIC_TYPE,
+                    permissions: AttPermissions::READABLE,
+                    descriptors: vec![GattDescriptorWithHandle {
+                        handle: AttHandle(13),
+                        type_: LONG_DESCRIPTOR_TYPE,
+                        permissions: AttPermissions::READABLE,
+                    }],
+                }],
+            },
+            Rc::new(MockDatastore::new().0),
+        )
+        .unwrap();
+        db
+    }
+
+    fn short_service() -> GattServiceInfo {
+        GattServiceInfo {
+            handle: AttHandle(1),
+            end_handle: AttHandle(6),
+            type_: SHORT_SERVICE_TYPE,
+        }
+    }
+
+    fn long_service() -> GattServiceInfo {
+        GattServiceInfo {
+            handle: AttHandle(10),
+            end_handle: AttHandle(13),
+            type_: LONG_SERVICE_TYPE,
+        }
+    }
+
+    #[test]
+    fn test_discover_all_primary_services() {
+        block_on_locally(async {
+            // arrange
+            let db = make_db();
+            let (client, _server) = connect_to_database(&db, DEFAULT_ATT_MTU);
+
+            // act
+            let services = discover_all_primary_services(&client).await;
+
+            // assert: both services were found, despite their different UUID sizes
+            assert_eq!(services, Ok(vec![short_service(), long_service()]));
+        });
+    }
+
+    #[test]
+    fn test_discover_primary_services_by_uuid() {
+        block_on_locally(async {
+            // arrange
+            let db = make_db();
+            let (client, _server) = connect_to_database(&db, DEFAULT_ATT_MTU);
+
+            // act
+            let short = discover_primary_services_by_uuid(&client, SHORT_SERVICE_TYPE).await;
+            let long = discover_primary_services_by_uuid(&client, LONG_SERVICE_TYPE).await;
+            let missing = discover_primary_services_by_uuid(&client, Uuid::new(0x1801)).await;
+
+            // assert
+            assert_eq!(short, Ok(vec![short_service()]));
+            assert_eq!(long, Ok(vec![long_service()]));
+            assert_eq!(missing, Ok(vec![]));
+        });
+    }
+
+    #[test]
+    fn test_discover_characteristics() {
+        block_on_locally(async {
+            // arrange
+            let db = make_db();
+            let (client, _server) = connect_to_database(&db, DEFAULT_ATT_MTU);
+
+            // act
+            let characteristics = discover_characteristics(&client, &short_service()).await;
+
+            // assert
+            assert_eq!(
+                characteristics,
+                Ok(vec![
+                    GattCharacteristicInfo {
+                        declaration_handle: AttHandle(2),
+                        value_handle: AttHandle(3),
+                        properties: CharacteristicProperties::READ
+                            | CharacteristicProperties::NOTIFY,
+                        type_: CHARACTERISTIC_TYPE,
+                    },
+                    GattCharacteristicInfo {
+                        declaration_handle: AttHandle(5),
+                        value_handle: AttHandle(6),
+                        properties: CharacteristicProperties::WRITE,
+                        type_: ANOTHER_CHARACTERISTIC_TYPE,
+                    }
+                ])
+            );
+        });
+    }
+
+    #[test]
+    fn test_discover_descriptors() {
+        block_on_locally(async {
+            // arrange
+            let db = make_db();
+            let (client, _server) = connect_to_database(&db, DEFAULT_ATT_MTU);
+
+            // act: discover the descriptors of each service, one of which has a
+            // 16-bit type and one a 128-bit type
+            let short = discover_descriptors(&client, AttHandle(4), AttHandle(4)).await;
+            let long = discover_descriptors(&client, AttHandle(13), AttHandle(13)).await;
+
+            // assert
+            assert_eq!(
+                short,
+                Ok(vec![GattDescriptorInfo {
+                    handle: AttHandle(4),
+                    type_: CLIENT_CHARACTERISTIC_CONFIGURATION_UUID
+                }])
+            );
+            assert_eq!(
+                long,
+                Ok(vec![GattDescriptorInfo { handle: AttHandle(13), type_: LONG_DESCRIPTOR_TYPE }])
+            );
+        });
+    }
+
+    #[test]
+    fn test_discover_all() {
+        block_on_locally(async {
+            // arrange
+            let db = make_db();
+            let (client, _server) = connect_to_database(&db, DEFAULT_ATT_MTU);
+
+            // act
+            let services = discover_all(&client).await.unwrap();
+
+            // assert: descriptors are attributed to the right characteristics
+            assert_eq!(services.len(), 2);
+            assert_eq!(services[0].info, short_service());
+            assert_eq!(services[0].characteristics.len(), 2);
+            assert_eq!(
+                services[0].characteristics[0].descriptors,
+                vec![GattDescriptorInfo {
+                    handle: AttHandle(4),
+                    type_: CLIENT_CHARACTERISTIC_CONFIGURATION_UUID
+                }]
+            );
+            assert_eq!(services[0].characteristics[1].descriptors, vec![]);
+            assert_eq!(services[1].info, long_service());
+            assert_eq!(services[1].characteristics.len(), 1);
+            assert_eq!(
+                services[1].characteristics[0].descriptors,
+                vec![GattDescriptorInfo { handle: AttHandle(13), type_: LONG_DESCRIPTOR_TYPE }]
+            );
+        });
+    }
+
+    #[test]
+    fn test_discover_empty_database() {
+        block_on_locally(async {
+            // arrange
+            let db = SharedBox::new(GattDatabase::new());
+            let (client, _server) = connect_to_database(&db, DEFAULT_ATT_MTU);
+
+            // act
+            let services = discover_all(&client).await;
+
+            // assert
+            assert_eq!(services, Ok(vec![]));
+        });
+    }
+
+    #[test]
+    fn test_discovery_must_make_progress() {
+        block_on_locally(async {
+            // arrange
+            let mut server = MockServer::new();
+            let pending = spawn_local({
+                let bearer = server.bearer.clone();
+                async move { discover_all_primary_services(&bearer).await }
+            });
+            let service = |handle, end_group_handle| AttReadByGroupTypeResponseBuilder {
+                data: [AttReadByGroupTypeDataElementBuilder {
+                    handle: AttHandle(handle).into(),
+                    end_group_handle: AttHandle(end_group_handle).into(),
+                    value: build_att_data(GattServiceDeclarationValueBuilder {
+                        uuid: SHORT_SERVICE_TYPE.into(),
+                    }),
+                }]
+                .into(),
+            };
+
+            // act: return a service, then one ending before the next start handle
+            let request = server.recv().await;
+            assert_eq!(request.opcode, AttOpcode::READ_BY_GROUP_TYPE_REQUEST);
+            server.reply(service(1, 5));
+            server.recv().await;
+            server.reply(service(2, 3));
+
+            // assert: the procedure is aborted
+            assert_eq!(pending.await.unwrap(), Err(GattClientError::InvalidResponse));
+        });
+    }
+
+    #[test]
+    fn test_malformed_characteristic_declaration() {
+        // the declaration is missing its UUID
+        let res = parse_characteristic_declaration(AttHandle(2), &[0x02, 0x03, 0x00]);
+
+        assert_eq!(res, Err(GattClientError::InvalidResponse));
+    }
+}
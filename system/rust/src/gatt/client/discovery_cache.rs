@@ -0,0 +1,514 @@
+//! This module caches the discovered database of bonded servers, so that it
+//! need not be rediscovered on every connection (Core Spec 5.3 Vol 3G 2.5.2
+//! Attribute Caching).
+//!
+//! A cached database is trusted until the server indicates Service Changed,
+//! or (if the server exposes one) its Database Hash no longer matches the one
+//! seen when the database was discovered. Cached databases are persisted to
+//! storage via a DiscoveryCacheStorage, so that they survive restarts.
+
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use log::{info, warn};
+
+use crate::{
+    core::{address::AddressWithType, uuid::Uuid},
+    gatt::{ids::AttHandle, server::robust_caching::DatabaseHash},
+    packets::Uuid128Builder,
+};
+
+use super::{
+    att_client_bearer::AttClientBearer,
+    discovery::{discover_all, read_database_hash, DiscoveredCharacteristic, DiscoveredService},
+    CharacteristicProperties, GattCharacteristicInfo, GattClientError, GattDescriptorInfo,
+    GattServiceInfo,
+};
+
+/// The version of the serialized format, to be bumped on incompatible changes
+const SERIALIZATION_VERSION: u8 = 1;
+
+/// Persists serialized cached databases, keyed by the identity address of the
+/// bonded server. An instance of this trait will be provided to the
+/// DiscoveryCache on initialization.
+pub trait DiscoveryCacheStorage {
+    /// Load the cached database of the given peer, if any
+    fn load(&self, peer: AddressWithType) -> Option<Vec<u8>>;
+
+    /// Store the cached database of the given peer, replacing any previous one
+    fn store(&self, peer: AddressWithType, data: Vec<u8>);
+
+    /// Remove the cached database of the given peer
+    fn remove(&self, peer: AddressWithType);
+}
+
+/// The database of a single server, as discovered
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CachedDatabase {
+    /// The Database Hash of the server at the time of discovery, if it exposes
+    /// one
+    pub database_hash: Option<DatabaseHash>,
+    /// The discovered services
+    pub services: Vec<DiscoveredService>,
+}
+
+/// Caches the discovered databases of bonded servers
+pub struct DiscoveryCache {
+    storage: Rc<dyn DiscoveryCacheStorage>,
+    databases: RefCell<HashMap<AddressWithType, CachedDatabase>>,
+}
+
+impl DiscoveryCache {
+    /// Constructor
+    pub fn new(storage: Rc<dyn DiscoveryCacheStorage>) -> Self {
+        Self { storage, databases: RefCell::new(HashMap::new()) }
+    }
+
+    /// Get the cached database of a bonded server, loading it from storage if
+    /// needed. A stored database that cannot be parsed is discarded.
+    pub fn get(&self, peer: AddressWithType) -> Option<CachedDatabase> {
+        if let Some(database) = self.databases.borrow().get(&peer) {
+            return Some(database.clone());
+        }
+        let data = self.storage.load(peer)?;
+        let Some(database) = deserialize(&data) else {
+            warn!("discarding malformed cached database of {peer:?}");
+            self.storage.remove(peer);
+            return None;
+        };
+        self.databases.borrow_mut().insert(peer, database.clone());
+        Some(database)
+    }
+
+    /// Cache the discovered database of a bonded server, replacing any
+    /// previous one
+    pub fn insert(&self, peer: AddressWithType, database: CachedDatabase) {
+        self.storage.store(peer, serialize(&database));
+        self.databases.borrow_mut().insert(peer, database);
+    }
+
+    /// Discard the cached database of a server, e.g. since it is no longer
+    /// bonded
+    pub fn invalidate(&self, peer: AddressWithType) {
+        if self.databases.borrow_mut().remove(&peer).is_some() {
+            info!("invalidating cached database of {peer:?}");
+        }
+        self.storage.remove(peer);
+    }
+
+    /// The server indicated Service Changed, so its cached database can no
+    /// longer be trusted
+    pub fn on_service_changed(&self, peer: AddressWithType) {
+        self.invalidate(peer);
+    }
+
+    /// Get the database of a bonded server, from the cache if it is still
+    /// valid, or else by discovering (and caching) it
+    pub async fn discover(
+        &self,
+        bearer: &AttClientBearer,
+        peer: AddressWithType,
+    ) -> Result<Vec<DiscoveredService>, GattClientError> {
+        let database_hash = read_database_hash(bearer).await?;
+        if let Some(cached) = self.get(peer) {
+            if cached.database_hash == database_hash {
+                info!("using cached database of {peer:?}");
+                return Ok(cached.services);
+            }
+            info!("database hash of {peer:?} changed, rediscovering");
+            self.invalidate(peer);
+        }
+
+        let services = discover_all(bearer).await?;
+        self.insert(peer, CachedDatabase { database_hash, services: services.clone() });
+        Ok(services)
+    }
+}
+
+fn serialize(database: &CachedDatabase) -> Vec<u8> {
+    let mut out = vec![SERIALIZATION_VERSION];
+    match database.database_hash {
+        Some(database_hash) => {
+            out.push(1);
+            out.extend(database_hash);
+        }
+        None => out.push(0),
+    }
+    out.extend((database.services.len() as u16).to_le_bytes());
+    for service in &database.services {
+        push_handle(&mut out, service.info.handle);
+        push_handle(&mut out, service.info.end_handle);
+        push_uuid(&mut out, service.info.type_);
+        out.extend((service.characteristics.len() as u16).to_le_bytes());
+        for characteristic in &service.characteristics {
+            push_handle(&mut out, characteristic.info.declaration_handle);
+            push_handle(&mut out, characteristic.info.value_handle);
+            out.push(characteristic.info.properties.bits());
+            push_uuid(&mut out, characteristic.info.type_);
+            out.extend((characteristic.descriptors.len() as u16).to_le_bytes());
+            for descriptor in &characteristic.descriptors {
+                push_handle(&mut out, descriptor.handle);
+                push_uuid(&mut out, descriptor.type_);
+            }
+        }
+    }
+    out
+}
+
+fn push_handle(out: &mut Vec<u8>, handle: AttHandle) {
+    out.extend(handle.0.to_le_bytes());
+}
+
+fn push_uuid(out: &mut Vec<u8>, uuid: Uuid) {
+    out.extend(Uuid128Builder::from(uuid).data.iter());
+}
+
+fn deserialize(data: &[u8]) -> Option<CachedDatabase> {
+    let mut reader = Reader(data);
+    if reader.u8()? != SERIALIZATION_VERSION {
+        return None;
+    }
+    let database_hash = match reader.u8()? {
+        0 => None,
+        1 => Some(reader.bytes(16)?.try_into().ok()?),
+        _ => return None,
+    };
+
+    let mut services = vec![];
+    for _ in 0..reader.u16()? {
+        let info = GattServiceInfo {
+            handle: reader.handle()?,
+            end_handle: reader.handle()?,
+            type_: reader.uuid()?,
+        };
+        let mut characteristics = vec![];
+        for _ in 0..reader.u16()? {
+            let info = GattCharacteristicInfo {
+                declaration_handle: reader.handle()?,
+                value_handle: reader.handle()?,
+                properties: CharacteristicProperties::from_bits_retain(reader.u8()?),
+                type_: reader.uuid()?,
+            };
+            let mut descriptors = vec![];
+            for _ in 0..reader.u16()? {
+                descriptors
+                    .push(GattDescriptorInfo { handle: reader.handle()?, type_: reader.uuid()? });
+            }
+            characteristics.push(DiscoveredCharacteristic { info, descriptors });
+        }
+        services.push(DiscoveredService { info, characteristics });
+    }
+
+    if !reader.0.is_empty() {
+        return None;
+    }
+    Some(CachedDatabase { database_hash, services })
+}
+
+/// Reads little-endian fields from the front of a buffer
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+
+    fn handle(&mut self) -> Option<AttHandle> {
+        Some(AttHandle(self.u16()?))
+    }
+
+    fn uuid(&mut self) -> Option<Uuid> {
+        Uuid::try_from_le_slice(self.bytes(16)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::task::spawn_local;
+
+    use crate::{
+        core::{address::AddressType, shared_box::SharedBox},
+        gatt::{
+            client::test::{loopback::connect_to_database, mock_server::MockServer},
+            mocks::{
+                mock_datastore::MockDatastore,
+                mock_discovery_cache_storage::MockDiscoveryCacheStorage,
+            },
+            mtu::DEFAULT_ATT_MTU,
+            server::gatt_database::{
+                AttPermissions, GattCharacteristicWithHandle, GattDatabase, GattServiceWithHandle,
+            },
+        },
+        packets::{
+            AttAttributeDataChild, AttReadByTypeDataElementBuilder, AttReadByTypeRequestBuilder,
+            AttReadByTypeResponseBuilder, UuidBuilder,
+        },
+        utils::{packet::build_att_data, task::block_on_locally},
+    };
+
+    use super::*;
+
+    const PEER: AddressWithType =
+        AddressWithType { address: [1, 2, 3, 4, 5, 6], address_type: AddressType::Public };
+    const DATABASE_HASH: DatabaseHash = [7; 16];
+
+    fn make_services() -> Vec<DiscoveredService> {
+        vec![
+            DiscoveredService {
+                info: GattServiceInfo {
+                    handle: AttHandle(1),
+                    end_handle: AttHandle(4),
+                    type_: Uuid::new(0x180F),
+                },
+                characteristics: vec![DiscoveredCharacteristic {
+                    info: GattCharacteristicInfo {
+                        declaration_handle: AttHandle(2),
+                        value_handle: AttHandle(3),
+                        properties: CharacteristicProperties::READ
+                            | CharacteristicProperties::NOTIFY,
+                        type_: Uuid::new(0x2A19),
+                    },
+                    descriptors: vec![GattDescriptorInfo {
+                        handle: AttHandle(4),
+                        type_: Uuid::new(0x2902),
+                    }],
+                }],
+            },
+            DiscoveredService {
+                info: GattServiceInfo {
+                    handle: AttHandle(10),
+                    end_handle: AttHandle::MAX,
+                    type_: Uuid::new(0x12345678),
+                },
+                characteristics: vec![],
+            },
+        ]
+    }
+
+    fn make_db() -> SharedBox<GattDatabase> {
+        let db = SharedBox::new(GattDatabase::new());
+        db.add_service_with_handles(
+            GattServiceWithHandle {
+                handle: AttHandle(1),
+                type_: Uuid::new(0x1234),
+                characteristics: vec![GattCharacteristicWithHandle {
+                    handle: AttHandle(3),
+                    type_: Uuid::new(0x5678),
+                    permissions: AttPermissions::READABLE,
+                    descriptors: vec![],
+                }],
+            },
+            Rc::new(MockDatastore::new().0),
+        )
+        .unwrap();
+        db
+    }
+
+    fn make_cache() -> (DiscoveryCache, Rc<MockDiscoveryCacheStorage>) {
+        let storage = Rc::new(MockDiscoveryCacheStorage::new());
+        (DiscoveryCache::new(storage.clone()), storage)
+    }
+
+    #[test]
+    fn test_serialization_round_trip() {
+        let database =
+            CachedDatabase { database_hash: Some(DATABASE_HASH), services: make_services() };
+
+        let res = deserialize(&serialize(&database));
+
+        assert_eq!(res, Some(database));
+    }
+
+    #[test]
+    fn test_serialization_round_trip_without_hash() {
+        let database = CachedDatabase { database_hash: None, services: make_services() };
+
+        let res = deserialize(&serialize(&database));
+
+        assert_eq!(res, Some(database));
+    }
+
+    #[test]
+    fn test_deserialize_truncated() {
+        let database =
+            CachedDatabase { database_hash: Some(DATABASE_HASH), services: make_services() };
+        let data = serialize(&database);
+
+        let res = deserialize(&data[..data.len() - 1]);
+
+        assert_eq!(res, None);
+    }
+
+    #[test]
+    fn test_deserialize_unknown_version() {
+        let mut data = serialize(&CachedDatabase { database_hash: None, services: vec![] });
+        data[0] = SERIALIZATION_VERSION + 1;
+
+        let res = deserialize(&data);
+
+        assert_eq!(res, None);
+    }
+
+    #[test]
+    fn test_cache_persisted() {
+        // arrange: cache a database, and then drop the in-memory cache
+        let (cache, storage) = make_cache();
+        let database = CachedDatabase { database_hash: None, services: make_services() };
+        cache.insert(PEER, database.clone());
+        drop(cache);
+
+        // act: load it into a new cache
+        let cache = DiscoveryCache::new(storage);
+        let res = cache.get(PEER);
+
+        // assert
+        assert_eq!(res, Some(database));
+    }
+
+    #[test]
+    fn test_malformed_storage_discarded() {
+        // arrange
+        let (cache, storage) = make_cache();
+        storage.set(PEER, vec![SERIALIZATION_VERSION, 2]);
+
+        // act
+        let res = cache.get(PEER);
+
+        // assert: the bad data was removed from storage
+        assert_eq!(res, None);
+        assert_eq!(storage.get(PEER), None);
+    }
+
+    #[test]
+    fn test_service_changed_invalidates() {
+        // arrange
+        let (cache, storage) = make_cache();
+        cache.insert(PEER, CachedDatabase { database_hash: None, services: make_services() });
+
+        // act
+        cache.on_service_changed(PEER);
+
+        // assert: the database was removed from memory and storage
+        assert_eq!(cache.get(PEER), None);
+        assert_eq!(storage.get(PEER), None);
+    }
+
+    #[test]
+    fn test_discover_populates_cache() {
+        block_on_locally(async {
+            // arrange
+            let (cache, storage) = make_cache();
+            let db = make_db();
+            let (client, _server) = connect_to_database(&db, DEFAULT_ATT_MTU);
+
+            // act
+            let services = cache.discover(&client, PEER).await.unwrap();
+
+            // assert: the discovered database was cached and stored
+            assert_eq!(services.len(), 1);
+            assert_eq!(services[0].info.type_, Uuid::new(0x1234));
+            assert_eq!(
+                cache.get(PEER),
+                Some(CachedDatabase { database_hash: None, services: services.clone() })
+            );
+            assert!(storage.get(PEER).is_some());
+        });
+    }
+
+    #[test]
+    fn test_reconnection_uses_cache() {
+        block_on_locally(async {
+            // arrange: discover a server, and then reconnect to an empty server
+            // with the same identity and no Database Hash
+            let (cache, _) = make_cache();
+            let db = make_db();
+            let (client, _server) = connect_to_database(&db, DEFAULT_ATT_MTU);
+            let services = cache.discover(&client, PEER).await.unwrap();
+            let empty_db = SharedBox::new(GattDatabase::new());
+            let (client, _server) = connect_to_database(&empty_db, DEFAULT_ATT_MTU);
+
+            // act
+            let res = cache.discover(&client, PEER).await;
+
+            // assert: the cached database was used, without rediscovery
+            assert_eq!(res, Ok(services));
+        });
+    }
+
+    #[test]
+    fn test_database_hash_mismatch_rediscovers() {
+        block_on_locally(async {
+            // arrange: a database cached along with a hash the server no longer has
+            let (cache, _) = make_cache();
+            cache.insert(
+                PEER,
+                CachedDatabase { database_hash: Some(DATABASE_HASH), services: make_services() },
+            );
+            let db = make_db();
+            let (client, _server) = connect_to_database(&db, DEFAULT_ATT_MTU);
+
+            // act
+            let services = cache.discover(&client, PEER).await.unwrap();
+
+            // assert: the server was rediscovered
+            assert_eq!(services.len(), 1);
+            assert_eq!(services[0].info.type_, Uuid::new(0x1234));
+            assert_eq!(cache.get(PEER).unwrap().database_hash, None);
+        });
+    }
+
+    #[test]
+    fn test_database_hash_match_uses_cache() {
+        block_on_locally(async {
+            // arrange
+            let mut server = MockServer::new();
+            let cache = Rc::new(make_cache().0);
+            cache.insert(
+                PEER,
+                CachedDatabase { database_hash: Some(DATABASE_HASH), services: make_services() },
+            );
+
+            // act: the server reports the same Database Hash as was cached
+            let pending = spawn_local({
+                let bearer = server.bearer.clone();
+                let cache = cache.clone();
+                async move { cache.discover(&bearer, PEER).await }
+            });
+            server
+                .expect_and_reply(
+                    AttReadByTypeRequestBuilder {
+                        starting_handle: AttHandle::MIN.into(),
+                        ending_handle: AttHandle::MAX.into(),
+                        // the Database Hash UUID is sent in its 16-bit form
+                        attribute_type: UuidBuilder { data: [0x2A, 0x2B].into() },
+                    },
+                    AttReadByTypeResponseBuilder {
+                        data: [AttReadByTypeDataElementBuilder {
+                            handle: AttHandle(20).into(),
+                            value: build_att_data(AttAttributeDataChild::RawData(
+                                DATABASE_HASH.into(),
+                            )),
+                        }]
+                        .into(),
+                    },
+                )
+                .await;
+
+            // assert: the cached database was used, without any further requests
+            assert_eq!(pending.await.unwrap(), Ok(make_services()));
+            assert!(!server.has_pending_packets());
+        });
+    }
+}
@@ -0,0 +1,782 @@
+//! This module implements the GATT procedures to read and write remote
+//! attributes (Core Spec 5.3 Vol 3G 4.8 - 4.12), and to subscribe to their
+//! notifications and indications.
+
+use log::warn;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use crate::{
+    gatt::ids::AttHandle,
+    packets::{
+        AttAttributeDataChild, AttErrorCode, AttExecuteWriteFlags, AttExecuteWriteRequestBuilder,
+        AttPrepareWriteRequestBuilder, AttPrepareWriteResponseView, AttReadBlobRequestBuilder,
+        AttReadBlobResponseView, AttReadRequestBuilder, AttReadResponseView,
+        AttWriteCommandBuilder, AttWriteRequestBuilder, Packet,
+    },
+    utils::packet::build_att_data,
+};
+
+use super::{att_client_bearer::AttClientBearer, GattClientError};
+
+/// The longest value an attribute may have (Core Spec 5.3 Vol 3F 3.2.9)
+const MAX_VALUE_LEN: usize = 512;
+
+/// The kind of server-initiated updates to subscribe to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubscriptionType {
+    /// Notifications, which are not confirmed
+    Notification,
+    /// Indications, which are confirmed
+    Indication,
+}
+
+impl SubscriptionType {
+    /// The value of the Client Characteristic Configuration descriptor enabling
+    /// this subscription (Core Spec 5.3 Vol 3G 3.3.3.3)
+    fn cccd_value(self) -> [u8; 2] {
+        match self {
+            SubscriptionType::Notification => [0x01, 0x00],
+            SubscriptionType::Indication => [0x02, 0x00],
+        }
+    }
+}
+
+/// Read Characteristic Value (Core Spec 5.3 Vol 3G 4.8.1). Only the first
+/// (MTU - 1) bytes are returned.
+pub async fn read(bearer: &AttClientBearer, handle: AttHandle) -> Result<Vec<u8>, GattClientError> {
+    let response =
+        bearer.send_request(AttReadRequestBuilder { attribute_handle: handle.into() }).await?;
+    let response = AttReadResponseView::try_parse(response.view())
+        .map_err(|_| GattClientError::InvalidResponse)?;
+    Ok(response.get_value().get_raw_payload().collect())
+}
+
+/// Read Long Characteristic Values (Core Spec 5.3 Vol 3G 4.8.3). The value is
+/// read in blobs, until the server returns one shorter than the MTU allows.
+pub async fn read_long(
+    bearer: &AttClientBearer,
+    handle: AttHandle,
+) -> Result<Vec<u8>, GattClientError> {
+    let mut value = read(bearer, handle).await?;
+    // the MTU can't change mid-procedure, since all requests go through this bearer
+    let max_blob_len = bearer.get_mtu() - 1;
+    let mut last_blob_len = value.len();
+
+    while last_blob_len == max_blob_len && value.len() < MAX_VALUE_LEN {
+        let response = match bearer
+            .send_request(AttReadBlobRequestBuilder {
+                attribute_handle: handle.into(),
+                offset: value.len() as u16,
+            })
+            .await
+        {
+            // the value was exactly as long as the first read
+            Err(GattClientError::AttError {
+                error_code: AttErrorCode::ATTRIBUTE_NOT_LONG | AttErrorCode::INVALID_OFFSET,
+                ..
+            }) => break,
+            response => response?,
+        };
+        let response = AttReadBlobResponseView::try_parse(response.view())
+            .map_err(|_| GattClientError::InvalidResponse)?;
+        let blob = response.get_value().get_raw_payload().collect::<Vec<_>>();
+        last_blob_len = blob.len();
+        value.extend(blob);
+    }
+    Ok(value)
+}
+
+/// Write Characteristic Value (Core Spec 5.3 Vol 3G 4.9.3)
+pub async fn write(
+    bearer: &AttClientBearer,
+    handle: AttHandle,
+    value: &[u8],
+) -> Result<(), GattClientError> {
+    check_value_fits(bearer, value)?;
+    bearer
+        .send_request(AttWriteRequestBuilder {
+            handle: handle.into(),
+            value: build_att_data(AttAttributeDataChild::RawData(value.into())),
+        })
+        .await?;
+    Ok(())
+}
+
+/// Write Without Response (Core Spec 5.3 Vol 3G 4.9.1)
+pub fn write_without_response(
+    bearer: &AttClientBearer,
+    handle: AttHandle,
+    value: &[u8],
+) -> Result<(), GattClientError> {
+    check_value_fits(bearer, value)?;
+    bearer.send_command(AttWriteCommandBuilder {
+        handle: handle.into(),
+        value: build_att_data(AttAttributeDataChild::RawData(value.into())),
+    })
+}
+
+/// Write Long Characteristic Values (Core Spec 5.3 Vol 3G 4.9.4). The value is
+/// queued on the server in parts, which are then executed together. If the
+/// server does not echo a part back correctly, the queued writes are
+/// cancelled.
+pub async fn write_long(
+    bearer: &AttClientBearer,
+    handle: AttHandle,
+    value: &[u8],
+) -> Result<(), GattClientError> {
+    reliable_write(bearer, &[(handle, value)]).await
+}
+
+/// Reliable Writes (Core Spec 5.3 Vol 3G 4.9.5). The values of several
+/// characteristics are queued on the server in parts, and every echoed part is
+/// checked to be byte-exact. Only if all of them are is the queue executed, so
+/// either all the values are written or none are.
+///
+/// Values longer than an attribute may be are rejected before anything is
+/// sent. An empty value is written with a single empty part.
+pub async fn reliable_write(
+    bearer: &AttClientBearer,
+    writes: &[(AttHandle, &[u8])],
+) -> Result<(), GattClientError> {
+    // the offsets of the parts must also fit in the 16 bits of their field
+    if writes.iter().any(|(_, value)| value.len() > MAX_VALUE_LEN) {
+        return Err(GattClientError::ValueTooLong { max_len: MAX_VALUE_LEN });
+    }
+
+    // MTU-5 limit comes from Core Spec 5.3 Vol 3F 3.4.6.1
+    let max_part_len = bearer.get_mtu() - 5;
+
+    for &(handle, value) in writes {
+        let parts = if value.is_empty() {
+            vec![(0, value)]
+        } else {
+            value
+                .chunks(max_part_len)
+                .enumerate()
+                .map(|(i, part)| (i * max_part_len, part))
+                .collect()
+        };
+        for (offset, part) in parts {
+            if let Err(err) = prepare_write(bearer, handle, offset as u16, part).await {
+                cancel_prepared_writes(bearer).await;
+                return Err(err);
+            }
+        }
+    }
+
+    bearer
+        .send_request(AttExecuteWriteRequestBuilder { flags: AttExecuteWriteFlags::EXECUTE })
+        .await?;
+    Ok(())
+}
+
+/// Subscribe to the notifications or indications of a characteristic, by
+/// writing its Client Characteristic Configuration descriptor. The listener is
+/// registered first, so no updates sent after the write can be missed.
+pub async fn subscribe(
+    bearer: &AttClientBearer,
+    value_handle: AttHandle,
+    cccd_handle: AttHandle,
+    subscription_type: SubscriptionType,
+) -> Result<UnboundedReceiver<Vec<u8>>, GattClientError> {
+    let listener = bearer.register_listener(value_handle);
+    write(bearer, cccd_handle, &subscription_type.cccd_value()).await?;
+    Ok(listener)
+}
+
+/// Stop receiving notifications and indications from a characteristic, by
+/// clearing its Client Characteristic Configuration descriptor. Any listeners
+/// will no longer receive updates, and should be dropped.
+pub async fn unsubscribe(
+    bearer: &AttClientBearer,
+    cccd_handle: AttHandle,
+) -> Result<(), GattClientError> {
+    write(bearer, cccd_handle, &[0x00, 0x00]).await
+}
+
+fn check_value_fits(bearer: &AttClientBearer, value: &[u8]) -> Result<(), GattClientError> {
+    // MTU-3 limit comes from Core Spec 5.3 Vol 3F 3.4.5.1
+    let max_len = bearer.get_mtu() - 3;
+    if value.len() > max_len {
+        return Err(GattClientError::DataExceedsMtu { mtu: max_len });
+    }
+    Ok(())
+}
+
+async fn prepare_write(
+    bearer: &AttClientBearer,
+    handle: AttHandle,
+    offset: u16,
+    part: &[u8],
+) -> Result<(), GattClientError> {
+    let response = bearer
+        .send_request(AttPrepareWriteRequestBuilder {
+            handle: handle.into(),
+            offset,
+            value: build_att_data(AttAttributeDataChild::RawData(part.into())),
+        })
+        .await?;
+    let response = AttPrepareWriteResponseView::try_parse(response.view())
+        .map_err(|_| GattClientError::InvalidResponse)?;
+
+    // Core Spec 5.3 Vol 3G 4.9.5 - the echoed part must match what we sent
+    if AttHandle::from(response.get_handle()) != handle
+        || response.get_offset() != offset
+        || !response.get_value().get_raw_payload().eq(part.iter().copied())
+    {
+        warn!("prepared write to {handle:?} at offset {offset} was not echoed correctly");
+        return Err(GattClientError::InvalidResponse);
+    }
+    Ok(())
+}
+
+async fn cancel_prepared_writes(bearer: &AttClientBearer) {
+    if let Err(err) = bearer
+        .send_request(AttExecuteWriteRequestBuilder { flags: AttExecuteWriteFlags::CANCEL })
+        .await
+    {
+        warn!("failed to cancel prepared writes: {err:?}");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::task::spawn_local;
+
+    use crate::{
+        gatt::client::test::mock_server::MockServer,
+        packets::{
+            AttAttributeDataBuilder, AttChild, AttErrorResponseBuilder,
+            AttExecuteWriteResponseBuilder, AttHandleValueNotificationBuilder, AttOpcode,
+            AttPrepareWriteResponseBuilder, AttReadBlobResponseBuilder, AttReadResponseBuilder,
+            AttWriteResponseBuilder,
+        },
+        utils::task::block_on_locally,
+    };
+
+    use super::*;
+
+    const HANDLE: AttHandle = AttHandle(3);
+    const CCCD_HANDLE: AttHandle = AttHandle(4);
+    const OTHER_HANDLE: AttHandle = AttHandle(6);
+    const RELIABLE_WRITES: [(AttHandle, &[u8]); 2] = [(HANDLE, &[1, 2]), (OTHER_HANDLE, &[3])];
+
+    fn data(value: &[u8]) -> AttAttributeDataBuilder {
+        build_att_data(AttAttributeDataChild::RawData(value.into()))
+    }
+
+    fn error(opcode_in_error: AttOpcode, error_code: AttErrorCode) -> AttChild {
+        AttErrorResponseBuilder { opcode_in_error, handle_in_error: HANDLE.into(), error_code }
+            .into()
+    }
+
+    fn prepare_write_request(offset: u16, value: &[u8]) -> AttPrepareWriteRequestBuilder {
+        AttPrepareWriteRequestBuilder { handle: HANDLE.into(), offset, value: data(value) }
+    }
+
+    fn prepare_write_response(offset: u16, value: &[u8]) -> AttPrepareWriteResponseBuilder {
+        AttPrepareWriteResponseBuilder { handle: HANDLE.into(), offset, value: data(value) }
+    }
+
+    #[test]
+    fn test_read() {
+        block_on_locally(async {
+            // arrange
+            let mut server = MockServer::new();
+
+            // act
+            let pending = spawn_local({
+                let bearer = server.bearer.clone();
+                async move { read(&bearer, HANDLE).await }
+            });
+            server
+                .expect_and_reply(
+                    AttReadRequestBuilder { attribute_handle: HANDLE.into() },
+                    AttReadResponseBuilder { value: data(&[1, 2, 3]) },
+                )
+                .await;
+
+            // assert
+            assert_eq!(pending.await.unwrap(), Ok(vec![1, 2, 3]));
+        });
+    }
+
+    #[test]
+    fn test_read_long() {
+        block_on_locally(async {
+            // arrange: a value spanning two full blobs and a partial one
+            let mut server = MockServer::new();
+            let value = (0..49).collect::<Vec<u8>>();
+
+            // act
+            let pending = spawn_local({
+                let bearer = server.bearer.clone();
+                async move { read_long(&bearer, HANDLE).await }
+            });
+            server
+                .expect_and_reply(
+                    AttReadRequestBuilder { attribute_handle: HANDLE.into() },
+                    AttReadResponseBuilder { value: data(&value[..22]) },
+                )
+                .await;
+            server
+                .expect_and_reply(
+                    AttReadBlobRequestBuilder { attribute_handle: HANDLE.into(), offset: 22 },
+                    AttReadBlobResponseBuilder { value: data(&value[22..44]) },
+                )
+                .await;
+            server
+                .expect_and_reply(
+                    AttReadBlobRequestBuilder { attribute_handle: HANDLE.into(), offset: 44 },
+                    AttReadBlobResponseBuilder { value: data(&value[44..]) },
+                )
+                .await;
+
+            // assert: the blobs were reassembled
+            assert_eq!(pending.await.unwrap(), Ok(value));
+        });
+    }
+
+    #[test]
+    fn test_read_long_exactly_one_pdu() {
+        block_on_locally(async {
+            // arrange
+            let mut server = MockServer::new();
+
+            // act: the server reports that the value is not long after a full read
+            let pending = spawn_local({
+                let bearer = server.bearer.clone();
+                async move { read_long(&bearer, HANDLE).await }
+            });
+            server
+                .expect_and_reply(
+                    AttReadRequestBuilder { attribute_handle: HANDLE.into() },
+                    AttReadResponseBuilder { value: data(&[1; 22]) },
+                )
+                .await;
+            server
+                .expect_and_reply(
+                    AttReadBlobRequestBuilder { attribute_handle: HANDLE.into(), offset: 22 },
+                    error(AttOpcode::READ_BLOB_REQUEST, AttErrorCode::ATTRIBUTE_NOT_LONG),
+                )
+                .await;
+
+            // assert
+            assert_eq!(pending.await.unwrap(), Ok(vec![1; 22]));
+        });
+    }
+
+    #[test]
+    fn test_write() {
+        block_on_locally(async {
+            // arrange
+            let mut server = MockServer::new();
+
+            // act
+            let pending = spawn_local({
+                let bearer = server.bearer.clone();
+                async move { write(&bearer, HANDLE, &[1, 2]).await }
+            });
+            server
+                .expect_and_reply(
+                    AttWriteRequestBuilder { handle: HANDLE.into(), value: data(&[1, 2]) },
+                    AttWriteResponseBuilder {},
+                )
+                .await;
+
+            // assert
+            assert_eq!(pending.await.unwrap(), Ok(()));
+        });
+    }
+
+    #[test]
+    fn test_write_exceeding_mtu() {
+        block_on_locally(async {
+            // arrange
+            let mut server = MockServer::new();
+
+            // act: write a value longer than MTU - 3
+            let res = write(&server.bearer, HANDLE, &[0; 21]).await;
+            let res_without_response = write_without_response(&server.bearer, HANDLE, &[0; 21]);
+
+            // assert: nothing was sent
+            assert_eq!(res, Err(GattClientError::DataExceedsMtu { mtu: 20 }));
+            assert_eq!(res_without_response, Err(GattClientError::DataExceedsMtu { mtu: 20 }));
+            assert!(!server.has_pending_packets());
+        });
+    }
+
+    #[test]
+    fn test_write_without_response() {
+        block_on_locally(async {
+            // arrange
+            let mut server = MockServer::new();
+
+            // act
+            let res = write_without_response(&server.bearer, HANDLE, &[1, 2]);
+
+            // assert
+            assert_eq!(res, Ok(()));
+            assert_eq!(
+                server.recv().await._child_,
+                AttWriteCommandBuilder { handle: HANDLE.into(), value: data(&[1, 2]) }.into()
+            );
+        });
+    }
+
+    #[test]
+    fn test_write_long() {
+        block_on_locally(async {
+            // arrange: a value spanning two prepared writes
+            let mut server = MockServer::new();
+            let value = (0..30).collect::<Vec<u8>>();
+
+            // act
+            let pending = spawn_local({
+                let bearer = server.bearer.clone();
+                let value = value.clone();
+                async move { write_long(&bearer, HANDLE, &value).await }
+            });
+            server
+                .expect_and_reply(
+                    prepare_write_request(0, &value[..18]),
+                    prepare_write_response(0, &value[..18]),
+                )
+                .await;
+            server
+                .expect_and_reply(
+                    prepare_write_request(18, &value[18..]),
+                    prepare_write_response(18, &value[18..]),
+                )
+                .await;
+            server
+                .expect_and_reply(
+                    AttExecuteWriteRequestBuilder { flags: AttExecuteWriteFlags::EXECUTE },
+                    AttExecuteWriteResponseBuilder {},
+                )
+                .await;
+
+            // assert
+            assert_eq!(pending.await.unwrap(), Ok(()));
+        });
+    }
+
+    #[test]
+    fn test_write_long_cancelled_on_bad_echo() {
+        block_on_locally(async {
+            // arrange
+            let mut server = MockServer::new();
+
+            // act: the server echoes back a different value
+            let pending = spawn_local({
+                let bearer = server.bearer.clone();
+                async move { write_long(&bearer, HANDLE, &[1, 2, 3]).await }
+            });
+            server
+                .expect_and_reply(
+                    prepare_write_request(0, &[1, 2, 3]),
+                    prepare_write_response(0, &[1, 2, 4]),
+                )
+                .await;
+            server
+                .expect_and_reply(
+                    AttExecuteWriteRequestBuilder { flags: AttExecuteWriteFlags::CANCEL },
+                    AttExecuteWriteResponseBuilder {},
+                )
+                .await;
+
+            // assert: the queued writes were cancelled
+            assert_eq!(pending.await.unwrap(), Err(GattClientError::InvalidResponse));
+        });
+    }
+
+    #[test]
+    fn test_write_long_cancelled_on_error() {
+        block_on_locally(async {
+            // arrange
+            let mut server = MockServer::new();
+
+            // act: the server rejects the prepared write
+            let pending = spawn_local({
+                let bearer = server.bearer.clone();
+                async move { write_long(&bearer, HANDLE, &[1, 2, 3]).await }
+            });
+            server
+                .expect_and_reply(
+                    prepare_write_request(0, &[1, 2, 3]),
+                    error(AttOpcode::PREPARE_WRITE_REQUEST, AttErrorCode::PREPARE_QUEUE_FULL),
+                )
+                .await;
+            server
+                .expect_and_reply(
+                    AttExecuteWriteRequestBuilder { flags: AttExecuteWriteFlags::CANCEL },
+                    AttExecuteWriteResponseBuilder {},
+                )
+                .await;
+
+            // assert
+            assert_eq!(
+                pending.await.unwrap(),
+                Err(GattClientError::AttError {
+                    handle: HANDLE,
+                    error_code: AttErrorCode::PREPARE_QUEUE_FULL
+                })
+            );
+        });
+    }
+
+    #[test]
+    fn test_reliable_write() {
+        block_on_locally(async {
+            // arrange
+            let mut server = MockServer::new();
+
+            // act
+            let pending = spawn_local({
+                let bearer = server.bearer.clone();
+                async move { reliable_write(&bearer, &RELIABLE_WRITES).await }
+            });
+            server
+                .expect_and_reply(
+                    prepare_write_request(0, &[1, 2]),
+                    prepare_write_response(0, &[1, 2]),
+                )
+                .await;
+            server
+                .expect_and_reply(
+                    AttPrepareWriteRequestBuilder {
+                        handle: OTHER_HANDLE.into(),
+                        offset: 0,
+                        value: data(&[3]),
+                    },
+                    AttPrepareWriteResponseBuilder {
+                        handle: OTHER_HANDLE.into(),
+                        offset: 0,
+                        value: data(&[3]),
+                    },
+                )
+                .await;
+            server
+                .expect_and_reply(
+                    AttExecuteWriteRequestBuilder { flags: AttExecuteWriteFlags::EXECUTE },
+                    AttExecuteWriteResponseBuilder {},
+                )
+                .await;
+
+            // assert
+            assert_eq!(pending.await.unwrap(), Ok(()));
+        });
+    }
+
+    #[test]
+    fn test_reliable_write_too_long() {
+        block_on_locally(async {
+            // arrange
+            let mut server = MockServer::new();
+            let value = [0; MAX_VALUE_LEN + 1];
+
+            // act: one of the values is longer than any attribute may be
+            let res =
+                reliable_write(&server.bearer, &[(HANDLE, &[1]), (OTHER_HANDLE, &value)]).await;
+
+            // assert: nothing was sent
+            assert_eq!(res, Err(GattClientError::ValueTooLong { max_len: MAX_VALUE_LEN }));
+            assert!(!server.has_pending_packets());
+        });
+    }
+
+    #[test]
+    fn test_write_long_of_max_len() {
+        block_on_locally(async {
+            // arrange
+            let mut server = MockServer::new();
+            let value = (0..MAX_VALUE_LEN).map(|i| i as u8).collect::<Vec<_>>();
+
+            // act
+            let pending = spawn_local({
+                let bearer = server.bearer.clone();
+                let value = value.clone();
+                async move { write_long(&bearer, HANDLE, &value).await }
+            });
+            for (i, part) in value.chunks(18).enumerate() {
+                let offset = (i * 18) as u16;
+                server
+                    .expect_and_reply(
+                        prepare_write_request(offset, part),
+                        prepare_write_response(offset, part),
+                    )
+                    .await;
+            }
+            server
+                .expect_and_reply(
+                    AttExecuteWriteRequestBuilder { flags: AttExecuteWriteFlags::EXECUTE },
+                    AttExecuteWriteResponseBuilder {},
+                )
+                .await;
+
+            // assert
+            assert_eq!(pending.await.unwrap(), Ok(()));
+        });
+    }
+
+    #[test]
+    fn test_write_long_empty_value() {
+        block_on_locally(async {
+            // arrange
+            let mut server = MockServer::new();
+
+            // act
+            let pending = spawn_local({
+                let bearer = server.bearer.clone();
+                async move { write_long(&bearer, HANDLE, &[]).await }
+            });
+            server
+                .expect_and_reply(prepare_write_request(0, &[]), prepare_write_response(0, &[]))
+                .await;
+            server
+                .expect_and_reply(
+                    AttExecuteWriteRequestBuilder { flags: AttExecuteWriteFlags::EXECUTE },
+                    AttExecuteWriteResponseBuilder {},
+                )
+                .await;
+
+            // assert: a single empty part was queued, then executed
+            assert_eq!(pending.await.unwrap(), Ok(()));
+        });
+    }
+
+    fn run_reliable_write_with_echo(
+        echo: AttPrepareWriteResponseBuilder,
+    ) -> Result<(), GattClientError> {
+        block_on_locally(async {
+            let mut server = MockServer::new();
+
+            let pending = spawn_local({
+                let bearer = server.bearer.clone();
+                async move { reliable_write(&bearer, &RELIABLE_WRITES).await }
+            });
+            server
+                .expect_and_reply(
+                    prepare_write_request(0, &[1, 2]),
+                    prepare_write_response(0, &[1, 2]),
+                )
+                .await;
+            server
+                .expect_and_reply(
+                    AttPrepareWriteRequestBuilder {
+                        handle: OTHER_HANDLE.into(),
+                        offset: 0,
+                        value: data(&[3]),
+                    },
+                    echo,
+                )
+                .await;
+            // nothing is executed, since the second write was not echoed back exactly
+            server
+                .expect_and_reply(
+                    AttExecuteWriteRequestBuilder { flags: AttExecuteWriteFlags::CANCEL },
+                    AttExecuteWriteResponseBuilder {},
+                )
+                .await;
+
+            pending.await.unwrap()
+        })
+    }
+
+    #[test]
+    fn test_reliable_write_detects_mismatched_value() {
+        let res = run_reliable_write_with_echo(AttPrepareWriteResponseBuilder {
+            handle: OTHER_HANDLE.into(),
+            offset: 0,
+            value: data(&[4]),
+        });
+
+        assert_eq!(res, Err(GattClientError::InvalidResponse));
+    }
+
+    #[test]
+    fn test_reliable_write_detects_truncated_value() {
+        let res = run_reliable_write_with_echo(AttPrepareWriteResponseBuilder {
+            handle: OTHER_HANDLE.into(),
+            offset: 0,
+            value: data(&[]),
+        });
+
+        assert_eq!(res, Err(GattClientError::InvalidResponse));
+    }
+
+    #[test]
+    fn test_reliable_write_detects_mismatched_handle() {
+        let res = run_reliable_write_with_echo(AttPrepareWriteResponseBuilder {
+            handle: HANDLE.into(),
+            offset: 0,
+            value: data(&[3]),
+        });
+
+        assert_eq!(res, Err(GattClientError::InvalidResponse));
+    }
+
+    #[test]
+    fn test_reliable_write_detects_mismatched_offset() {
+        let res = run_reliable_write_with_echo(AttPrepareWriteResponseBuilder {
+            handle: OTHER_HANDLE.into(),
+            offset: 1,
+            value: data(&[3]),
+        });
+
+        assert_eq!(res, Err(GattClientError::InvalidResponse));
+    }
+
+    #[test]
+    fn test_subscribe() {
+        block_on_locally(async {
+            // arrange
+            let mut server = MockServer::new();
+
+            // act: subscribe to indications, and then notify the value
+            let pending = spawn_local({
+                let bearer = server.bearer.clone();
+                async move {
+                    subscribe(&bearer, HANDLE, CCCD_HANDLE, SubscriptionType::Indication).await
+                }
+            });
+            server
+                .expect_and_reply(
+                    AttWriteRequestBuilder { handle: CCCD_HANDLE.into(), value: data(&[2, 0]) },
+                    AttWriteResponseBuilder {},
+                )
+                .await;
+            let mut listener = pending.await.unwrap().unwrap();
+            server.reply(AttHandleValueNotificationBuilder {
+                handle: HANDLE.into(),
+                value: data(&[5, 6]),
+            });
+
+            // assert: the listener received the value
+            assert_eq!(listener.recv().await, Some(vec![5, 6]));
+        });
+    }
+
+    #[test]
+    fn test_unsubscribe() {
+        block_on_locally(async {
+            // arrange
+            let mut server = MockServer::new();
+
+            // act
+            let pending = spawn_local({
+                let bearer = server.bearer.clone();
+                async move { unsubscribe(&bearer, CCCD_HANDLE).await }
+            });
+            server
+                .expect_and_reply(
+                    AttWriteRequestBuilder { handle: CCCD_HANDLE.into(), value: data(&[0, 0]) },
+                    AttWriteResponseBuilder {},
+                )
+                .await;
+
+            // assert
+            assert_eq!(pending.await.unwrap(), Ok(()));
+        });
+    }
+}
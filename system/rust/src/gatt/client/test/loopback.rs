@@ -0,0 +1,67 @@
+use std::rc::Rc;
+
+use tokio::{sync::mpsc::unbounded_channel, task::spawn_local};
+
+use crate::{
+    core::shared_box::SharedBox,
+    gatt::{
+        client::att_client_bearer::AttClientBearer,
+        ids::TransportIndex,
+        mocks::mock_security_manager::MockSecurityManager,
+        server::{
+            att_server_bearer::AttServerBearer,
+            gatt_database::{AttDatabaseImpl, GattDatabase},
+            security_elevation::SecurityElevation,
+            signature_verifier::SignatureVerifier,
+        },
+    },
+    utils::packet::build_view_or_crash,
+};
+
+const TCB_IDX: TransportIndex = TransportIndex(1);
+
+/// Connect a client bearer to a server bearer for the given database. Packets
+/// are forwarded between them by tasks on the current LocalSet.
+pub fn connect_to_database(
+    db: &SharedBox<GattDatabase>,
+    server_rx_mtu: usize,
+) -> (Rc<AttClientBearer>, SharedBox<AttServerBearer<AttDatabaseImpl>>) {
+    let (client_tx, mut client_rx) = unbounded_channel();
+    let (server_tx, mut server_rx) = unbounded_channel();
+
+    let client = Rc::new(AttClientBearer::new(move |packet| {
+        client_tx.send(packet).unwrap();
+        Ok(())
+    }));
+    let security_manager = Rc::new(MockSecurityManager::new());
+    let server = SharedBox::new(AttServerBearer::new(
+        db.get_att_database(TCB_IDX),
+        SignatureVerifier::new(TCB_IDX, security_manager.clone()),
+        SecurityElevation::new(TCB_IDX, security_manager),
+        server_rx_mtu,
+        move |packet| {
+            server_tx.send(packet).unwrap();
+            Ok(())
+        },
+    ));
+
+    spawn_local({
+        let server = server.downgrade();
+        async move {
+            while let Some(packet) = client_rx.recv().await {
+                let packet = build_view_or_crash(packet);
+                server.with(|server| server.map(|server| server.handle_packet(packet.view())));
+            }
+        }
+    });
+    spawn_local({
+        let client = client.clone();
+        async move {
+            while let Some(packet) = server_rx.recv().await {
+                client.handle_packet(build_view_or_crash(packet).view());
+            }
+        }
+    });
+
+    (client, server)
+}
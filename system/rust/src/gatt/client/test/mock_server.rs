@@ -0,0 +1,52 @@
+use std::rc::Rc;
+
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+use crate::{
+    gatt::client::att_client_bearer::AttClientBearer,
+    packets::{AttBuilder, AttChild},
+    utils::packet::build_att_view_or_crash,
+};
+
+/// A client bearer whose outgoing packets are captured, so that a test can
+/// play the part of the server
+pub struct MockServer {
+    pub bearer: Rc<AttClientBearer>,
+    rx: UnboundedReceiver<AttBuilder>,
+}
+
+impl MockServer {
+    pub fn new() -> Self {
+        let (tx, rx) = unbounded_channel();
+        let bearer = Rc::new(AttClientBearer::new(move |packet| {
+            tx.send(packet).unwrap();
+            Ok(())
+        }));
+        Self { bearer, rx }
+    }
+
+    /// Wait for the next packet sent by the client
+    pub async fn recv(&mut self) -> AttBuilder {
+        self.rx.recv().await.unwrap()
+    }
+
+    /// Whether the client has sent any packets that were not yet received
+    pub fn has_pending_packets(&mut self) -> bool {
+        !self.rx.is_empty()
+    }
+
+    /// Send a packet to the client
+    pub fn reply(&self, packet: impl Into<AttChild>) {
+        self.bearer.handle_packet(build_att_view_or_crash(packet).view());
+    }
+
+    /// Wait for the next packet sent by the client, and reply to it
+    pub async fn expect_and_reply(
+        &mut self,
+        expected: impl Into<AttChild>,
+        reply: impl Into<AttChild>,
+    ) {
+        assert_eq!(self.recv().await._child_, expected.into());
+        self.reply(reply);
+    }
+}
@@ -1,7 +1,7 @@
 //! FFI interfaces for the GATT module. Some structs are exported so that
 //! core::init can instantiate and pass them into the main loop.
 
-use std::iter::Peekable;
+use std::{collections::BTreeMap, iter::Peekable, sync::Mutex};
 
 use anyhow::{bail, Result};
 use bt_common::init_flags::always_use_private_gatt_for_debugging_is_enabled;
@@ -11,21 +11,27 @@ use log::{error, info, trace, warn};
 use tokio::task::spawn_local;
 
 use crate::{
+    core::shared_box::WeakBoxRef,
     do_in_rust_thread,
     packets::{AttAttributeDataChild, AttBuilder, AttErrorCode, Serializable, SerializeError},
 };
 
 use super::{
-    arbiter::with_arbiter,
+    arbiter::{has_arbiter, with_arbiter},
     callbacks::{GattWriteRequestType, GattWriteType, TransactionDecision},
-    channel::AttTransport,
-    ids::{AdvertiserId, AttHandle, ConnectionId, ServerId, TransactionId, TransportIndex},
+    channel::{AttTransport, TransactionTimeoutEvent},
+    ids::{
+        AdvertiserId, AttHandle, ConnectionId, EattCid, ServerId, TransactionId, TransportIndex,
+    },
+    security_manager::{PeerSigningKey, SecurityLevel, SecurityManager},
     server::{
+        att_server_bearer::AttServerBearer,
         gatt_database::{
-            AttPermissions, GattCharacteristicWithHandle, GattDescriptorWithHandle,
-            GattServiceWithHandle,
+            AttDatabaseImpl, AttPermissions, GattCharacteristicWithHandle,
+            GattDescriptorWithHandle, GattServiceWithHandle,
         },
-        IndicationError,
+        notification_handler::Priority,
+        GattModule, IndicationError,
     },
     GattCallbacks,
 };
@@ -115,6 +121,21 @@ mod inner {
         Drop = 1u32,
     }
 
+    /// The security level of a link, as tracked by the legacy stack
+    #[namespace = "bluetooth::shim::arbiter"]
+    #[derive(Debug)]
+    enum LinkSecurityLevel {
+        /// The link is not encrypted
+        #[cxx_name = "NO_SECURITY"]
+        NoSecurity = 0u32,
+        /// The link is encrypted with an unauthenticated key
+        #[cxx_name = "ENCRYPTED"]
+        Encrypted = 1u32,
+        /// The link is encrypted with an authenticated (MITM-protected) key
+        #[cxx_name = "AUTHENTICATED"]
+        Authenticated = 2u32,
+    }
+
     /// The type of GATT record supplied over FFI
     #[derive(Debug)]
     #[namespace = "bluetooth::gatt"]
@@ -144,19 +165,52 @@ mod inner {
     unsafe extern "C++" {
         include!("stack/arbiter/acl_arbiter.h");
         type InterceptAction;
+        type LinkSecurityLevel;
 
         /// Register callbacks from C++ into Rust within the Arbiter
         fn StoreCallbacksFromRust(
             on_le_connect: fn(tcb_idx: u8, advertiser: u8),
             on_le_disconnect: fn(tcb_idx: u8),
+            on_br_edr_connect: fn(tcb_idx: u8, mtu: u16),
+            on_br_edr_disconnect: fn(tcb_idx: u8),
             intercept_packet: fn(tcb_idx: u8, packet: Vec<u8>) -> InterceptAction,
             on_outgoing_mtu_req: fn(tcb_idx: u8),
             on_incoming_mtu_resp: fn(tcb_idx: u8, mtu: usize),
             on_incoming_mtu_req: fn(tcb_idx: u8, mtu: usize),
+            on_eatt_bearer_open: fn(tcb_idx: u8, cid: u16, mtu: u16),
+            on_eatt_bearer_close: fn(tcb_idx: u8, cid: u16),
+            intercept_eatt_packet: fn(tcb_idx: u8, cid: u16, packet: Vec<u8>) -> InterceptAction,
+            on_link_security_changed: fn(
+                tcb_idx: u8,
+                security_level: LinkSecurityLevel,
+                key_size: u8,
+                csrk: &[u8],
+                sign_counter: u32,
+            ),
         );
 
         /// Send an outgoing packet on the specified tcb_idx
         fn SendPacketToPeer(tcb_idx: u8, packet: Vec<u8>);
+
+        /// Send an outgoing packet on the specified L2CAP channel (an EATT
+        /// bearer) of the specified tcb_idx
+        fn SendPacketToPeerOnChannel(tcb_idx: u8, cid: u16, packet: Vec<u8>);
+
+        /// Close the unenhanced ATT bearer of the specified tcb_idx
+        fn ClosePeerBearer(tcb_idx: u8);
+
+        /// Close the specified L2CAP channel (an EATT bearer) of the specified
+        /// tcb_idx
+        fn ClosePeerBearerOnChannel(tcb_idx: u8, cid: u16);
+
+        /// Update the lowest sign counter that may be accepted from the peer on
+        /// the specified tcb_idx
+        fn SetPeerSignCounter(tcb_idx: u8, sign_counter: u32);
+
+        /// Request that the security of the specified tcb_idx be raised to (at
+        /// least) the given level. The new security of the link is reported
+        /// once the attempt has completed, whether or not it was successful.
+        fn RequestSecurityElevation(tcb_idx: u8, security_level: LinkSecurityLevel);
     }
 
     #[namespace = "bluetooth::gatt"]
@@ -170,6 +224,7 @@ mod inner {
         // att operations
         fn send_response(server_id: u8, conn_id: u16, trans_id: u32, status: u8, value: &[u8]);
         fn send_indication(_server_id: u8, handle: u16, conn_id: u16, value: &[u8]);
+        fn send_notification(_server_id: u8, handle: u16, conn_id: u16, value: &[u8]);
 
         // connection
         fn is_connection_isolated(conn_id: u16) -> bool;
@@ -177,6 +232,8 @@ mod inner {
         // arbitration
         fn associate_server_with_advertiser(server_id: u8, advertiser_id: u8);
         fn clear_advertiser(advertiser_id: u8);
+        fn associate_server_with_br_edr(server_id: u8);
+        fn clear_br_edr_server();
     }
 }
 
@@ -221,7 +278,10 @@ impl GattCallbacks for GattCallbacksImpl {
             handle.0,
             attr_type,
             match write_type {
-                GattWriteType::Request(GattWriteRequestType::Prepare { offset }) => offset,
+                GattWriteType::Request(
+                    GattWriteRequestType::Request { offset }
+                    | GattWriteRequestType::Prepare { offset },
+                ) => offset,
                 _ => 0,
             },
             matches!(write_type, GattWriteType::Request { .. }),
@@ -275,6 +335,168 @@ impl AttTransport for AttTransportImpl {
         SendPacketToPeer(tcb_idx.0, packet.to_vec()?);
         Ok(())
     }
+
+    fn send_eatt_packet(
+        &self,
+        tcb_idx: TransportIndex,
+        cid: EattCid,
+        packet: AttBuilder,
+    ) -> Result<(), SerializeError> {
+        SendPacketToPeerOnChannel(tcb_idx.0, cid.0, packet.to_vec()?);
+        Ok(())
+    }
+
+    fn close_bearer(&self, event: TransactionTimeoutEvent) {
+        match event.cid {
+            Some(cid) => ClosePeerBearerOnChannel(event.tcb_idx.0, cid.0),
+            None => ClosePeerBearer(event.tcb_idx.0),
+        }
+    }
+}
+
+/// The security of a link, as last reported by the C++ security manager
+#[derive(Clone, Copy, Debug)]
+struct LinkSecurity {
+    security_level: SecurityLevel,
+    key_size: Option<u8>,
+    signing_key: Option<PeerSigningKey>,
+}
+
+/// The C++ security manager owns the security of each link, and reports it
+/// (from the main thread) when the link is connected and whenever it changes.
+/// It is cached here so that SecurityManagerImpl can be queried synchronously
+/// (from the Rust thread).
+static LINK_SECURITY: Mutex<BTreeMap<u8, LinkSecurity>> = Mutex::new(BTreeMap::new());
+
+/// Handle a report of the security of a link from the C++ security manager.
+/// An empty CSRK means that the peer has not distributed one.
+pub fn on_link_security_changed(
+    tcb_idx: u8,
+    security_level: LinkSecurityLevel,
+    key_size: u8,
+    csrk: &[u8],
+    sign_counter: u32,
+) {
+    let security_level = match security_level {
+        LinkSecurityLevel::Encrypted => SecurityLevel::Encrypted,
+        LinkSecurityLevel::Authenticated => SecurityLevel::Authenticated,
+        _ => SecurityLevel::NoSecurity,
+    };
+    let key_size = (security_level != SecurityLevel::NoSecurity).then_some(key_size);
+    let mut signing_key = csrk.try_into().ok().map(|csrk| PeerSigningKey { csrk, sign_counter });
+
+    let mut link_security = LINK_SECURITY.lock().unwrap();
+    if let (Some(signing_key), Some(LinkSecurity { signing_key: Some(cached), .. })) =
+        (signing_key.as_mut(), link_security.get(&tcb_idx))
+    {
+        // our own updates of the sign counter may not have reached C++ yet
+        if signing_key.csrk == cached.csrk {
+            signing_key.sign_counter = signing_key.sign_counter.max(cached.sign_counter);
+        }
+    }
+    link_security.insert(tcb_idx, LinkSecurity { security_level, key_size, signing_key });
+    drop(link_security);
+
+    let tcb_idx = TransportIndex(tcb_idx);
+    if !has_arbiter() || !with_arbiter(|arbiter| arbiter.is_connection_isolated(tcb_idx)) {
+        return;
+    }
+    do_in_rust_thread(move |modules| {
+        if let Err(err) = modules.gatt_module.on_security_level_changed(tcb_idx) {
+            error!("{err:?}")
+        }
+    })
+}
+
+/// Forget the security of a link once it is disconnected
+pub fn clear_link_security(tcb_idx: TransportIndex) {
+    LINK_SECURITY.lock().unwrap().remove(&tcb_idx.0);
+}
+
+fn with_link_security<T>(
+    tcb_idx: TransportIndex,
+    f: impl FnOnce(&mut LinkSecurity) -> T,
+) -> Option<T> {
+    LINK_SECURITY.lock().unwrap().get_mut(&tcb_idx.0).map(f)
+}
+
+/// The requests that SecurityManagerImpl forwards to the C++ security manager
+pub trait LinkSecurityRequester {
+    /// Store the sign counter of the peer's CSRK along with its bond
+    fn set_peer_sign_counter(&self, tcb_idx: TransportIndex, sign_counter: u32);
+
+    /// Start encrypting (or pairing over) the link at the given security level
+    fn request_security_elevation(
+        &self,
+        tcb_idx: TransportIndex,
+        security_level: LinkSecurityLevel,
+    );
+}
+
+/// Implementation of LinkSecurityRequester for the native stack
+pub struct LinkSecurityRequesterImpl();
+
+impl LinkSecurityRequester for LinkSecurityRequesterImpl {
+    fn set_peer_sign_counter(&self, tcb_idx: TransportIndex, sign_counter: u32) {
+        SetPeerSignCounter(tcb_idx.0, sign_counter);
+    }
+
+    fn request_security_elevation(
+        &self,
+        tcb_idx: TransportIndex,
+        security_level: LinkSecurityLevel,
+    ) {
+        RequestSecurityElevation(tcb_idx.0, security_level);
+    }
+}
+
+/// Implementation of SecurityManager for the native stack, backed by the link
+/// security reported by the C++ security manager. The legacy stack has no
+/// notion of authorization, so no peer is ever authorized.
+pub struct SecurityManagerImpl<T: LinkSecurityRequester = LinkSecurityRequesterImpl>(pub T);
+
+impl<T: LinkSecurityRequester> SecurityManager for SecurityManagerImpl<T> {
+    fn get_peer_signing_key(&self, tcb_idx: TransportIndex) -> Option<PeerSigningKey> {
+        with_link_security(tcb_idx, |link| link.signing_key).flatten()
+    }
+
+    fn set_peer_sign_counter(&self, tcb_idx: TransportIndex, sign_counter: u32) {
+        with_link_security(tcb_idx, |link| {
+            if let Some(signing_key) = &mut link.signing_key {
+                signing_key.sign_counter = sign_counter;
+            }
+        });
+        self.0.set_peer_sign_counter(tcb_idx, sign_counter);
+    }
+
+    fn get_security_level(&self, tcb_idx: TransportIndex) -> SecurityLevel {
+        with_link_security(tcb_idx, |link| link.security_level).unwrap_or(SecurityLevel::NoSecurity)
+    }
+
+    fn get_encryption_key_size(&self, tcb_idx: TransportIndex) -> Option<u8> {
+        with_link_security(tcb_idx, |link| link.key_size).flatten()
+    }
+
+    fn is_authorized(&self, _tcb_idx: TransportIndex) -> bool {
+        false
+    }
+
+    fn request_security_elevation(
+        &self,
+        tcb_idx: TransportIndex,
+        security_level: SecurityLevel,
+    ) -> bool {
+        if with_link_security(tcb_idx, |_| ()).is_none() {
+            return false;
+        }
+        let security_level = match security_level {
+            SecurityLevel::NoSecurity => return false,
+            SecurityLevel::Encrypted => LinkSecurityLevel::Encrypted,
+            SecurityLevel::Authenticated => LinkSecurityLevel::Authenticated,
+        };
+        self.0.request_security_elevation(tcb_idx, security_level);
+        true
+    }
 }
 
 fn open_server(server_id: u8) {
@@ -341,7 +563,7 @@ fn records_to_service(service_records: &[GattRecord]) -> Result<GattServiceWithH
                 characteristics.push(GattCharacteristicWithHandle {
                     handle: AttHandle(record.attribute_handle),
                     type_: record.uuid,
-                    permissions: AttPermissions::from_bits_truncate(record.properties),
+                    permissions: AttPermissions::from_bits_truncate(record.properties.into()),
                     descriptors: consume_descriptors(&mut service_records),
                 });
             }
@@ -415,11 +637,8 @@ fn is_connection_isolated(conn_id: u16) -> bool {
 fn send_response(_server_id: u8, conn_id: u16, trans_id: u32, status: u8, value: &[u8]) {
     // TODO(aryarahul): fixup error codes to allow app-specific values (i.e. don't
     // make it an enum in PDL)
-    let value = if status == 0 {
-        Ok(value.to_vec())
-    } else {
-        Err(AttErrorCode::try_from(status).unwrap_or(AttErrorCode::UNLIKELY_ERROR))
-    };
+    let value =
+        if status == 0 { Ok(value.to_vec()) } else { Err(status_to_att_error_code(status)) };
 
     trace!("send_response {conn_id:?}, {trans_id:?}, {:?}", value.as_ref().err());
 
@@ -435,6 +654,29 @@ fn send_response(_server_id: u8, conn_id: u16, trans_id: u32, status: u8, value:
     })
 }
 
+/// Convert the status supplied by the upper layer in a response into the error
+/// code sent to the client. Statuses in the range reserved for application
+/// errors (Core Spec 5.3 Vol 3F 3.4.1.1) are all sent as APPLICATION_ERROR,
+/// since only known error codes can be serialized.
+fn status_to_att_error_code(status: u8) -> AttErrorCode {
+    AttErrorCode::try_from(status).unwrap_or(match status {
+        0x80..=0x9F => AttErrorCode::APPLICATION_ERROR,
+        _ => AttErrorCode::UNLIKELY_ERROR,
+    })
+}
+
+/// Get the unenhanced bearer of the connection identified by the upper layer,
+/// provided that it still exists (with the same server_id)
+fn get_connection_bearer(
+    gatt_module: &GattModule,
+    conn_id: ConnectionId,
+) -> Option<WeakBoxRef<'_, AttServerBearer<AttDatabaseImpl>>> {
+    if gatt_module.get_connection_id(conn_id.get_tcb_idx()) != Some(conn_id) {
+        return None;
+    }
+    gatt_module.get_bearer(conn_id.get_tcb_idx())
+}
+
 fn send_indication(_server_id: u8, handle: u16, conn_id: u16, value: &[u8]) {
     let handle = AttHandle(handle);
     let conn_id = ConnectionId(conn_id);
@@ -443,7 +685,7 @@ fn send_indication(_server_id: u8, handle: u16, conn_id: u16, value: &[u8]) {
     trace!("send_indication {handle:?}, {conn_id:?}");
 
     do_in_rust_thread(move |modules| {
-        let Some(bearer) = modules.gatt_module.get_bearer(conn_id.get_tcb_idx()) else {
+        let Some(bearer) = get_connection_bearer(modules.gatt_module, conn_id) else {
             error!("connection {conn_id:?} does not exist");
             return;
         };
@@ -456,6 +698,27 @@ fn send_indication(_server_id: u8, handle: u16, conn_id: u16, value: &[u8]) {
     })
 }
 
+fn send_notification(_server_id: u8, handle: u16, conn_id: u16, value: &[u8]) {
+    let handle = AttHandle(handle);
+    let conn_id = ConnectionId(conn_id);
+    let value = AttAttributeDataChild::RawData(value.into());
+
+    trace!("send_notification {handle:?}, {conn_id:?}");
+
+    do_in_rust_thread(move |modules| {
+        let Some(bearer) = get_connection_bearer(modules.gatt_module, conn_id) else {
+            error!("connection {conn_id:?} does not exist");
+            return;
+        };
+        let pending_notification = bearer.send_notification(handle, value, Priority::Normal);
+        spawn_local(async move {
+            if let Err(err) = pending_notification.await {
+                warn!("failed to send notification for {handle:?} on {conn_id:?}: {err:?}");
+            }
+        });
+    })
+}
+
 fn associate_server_with_advertiser(server_id: u8, advertiser_id: u8) {
     let server_id = ServerId(server_id);
     let advertiser_id = AdvertiserId(advertiser_id);
@@ -475,8 +738,23 @@ fn clear_advertiser(advertiser_id: u8) {
     })
 }
 
+fn associate_server_with_br_edr(server_id: u8) {
+    let server_id = ServerId(server_id);
+    do_in_rust_thread(move |modules| {
+        modules.gatt_module.get_isolation_manager().associate_server_with_br_edr(server_id);
+    })
+}
+
+fn clear_br_edr_server() {
+    do_in_rust_thread(move |modules| {
+        modules.gatt_module.get_isolation_manager().clear_br_edr_server();
+    })
+}
+
 #[cfg(test)]
 mod test {
+    use std::cell::RefCell;
+
     use super::*;
 
     const SERVICE_HANDLE: AttHandle = AttHandle(1);
@@ -524,6 +802,27 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_known_status_to_att_error_code() {
+        assert_eq!(status_to_att_error_code(0x03), AttErrorCode::WRITE_NOT_PERMITTED);
+        assert_eq!(
+            status_to_att_error_code(0xFD),
+            AttErrorCode::CLIENT_CHARACTERISTIC_CONFIGURATION_DESCRIPTOR_IMPROPERLY_CONFIGURED
+        );
+    }
+
+    #[test]
+    fn test_application_status_to_att_error_code() {
+        assert_eq!(status_to_att_error_code(0x85), AttErrorCode::APPLICATION_ERROR);
+        assert_eq!(status_to_att_error_code(0x9F), AttErrorCode::APPLICATION_ERROR);
+    }
+
+    #[test]
+    fn test_unknown_status_to_att_error_code() {
+        assert_eq!(status_to_att_error_code(0x11), AttErrorCode::UNLIKELY_ERROR);
+        assert_eq!(status_to_att_error_code(0xA0), AttErrorCode::UNLIKELY_ERROR);
+    }
+
     #[test]
     fn test_empty_records() {
         let res = records_to_service(&[]);
@@ -687,4 +986,103 @@ mod test {
 
         assert!(res.is_err());
     }
+
+    // each test uses its own tcb_idx, since the link security is global
+    const CSRK: [u8; 16] = [0x5A; 16];
+
+    /// Records the requests instead of forwarding them to C++
+    #[derive(Default)]
+    struct MockLinkSecurityRequester {
+        sign_counters: RefCell<Vec<(TransportIndex, u32)>>,
+        elevations: RefCell<Vec<(TransportIndex, LinkSecurityLevel)>>,
+    }
+
+    impl LinkSecurityRequester for MockLinkSecurityRequester {
+        fn set_peer_sign_counter(&self, tcb_idx: TransportIndex, sign_counter: u32) {
+            self.sign_counters.borrow_mut().push((tcb_idx, sign_counter));
+        }
+
+        fn request_security_elevation(
+            &self,
+            tcb_idx: TransportIndex,
+            security_level: LinkSecurityLevel,
+        ) {
+            self.elevations.borrow_mut().push((tcb_idx, security_level));
+        }
+    }
+
+    fn make_security_manager() -> SecurityManagerImpl<MockLinkSecurityRequester> {
+        SecurityManagerImpl(MockLinkSecurityRequester::default())
+    }
+
+    #[test]
+    fn test_reported_link_security() {
+        let tcb_idx = TransportIndex(0xF0);
+
+        on_link_security_changed(tcb_idx.0, LinkSecurityLevel::Authenticated, 16, &CSRK, 7);
+
+        let security_manager = make_security_manager();
+        assert_eq!(security_manager.get_security_level(tcb_idx), SecurityLevel::Authenticated);
+        assert_eq!(security_manager.get_encryption_key_size(tcb_idx), Some(16));
+        assert_eq!(
+            security_manager.get_peer_signing_key(tcb_idx),
+            Some(PeerSigningKey { csrk: CSRK, sign_counter: 7 })
+        );
+    }
+
+    #[test]
+    fn test_unencrypted_link_without_signing_key() {
+        let tcb_idx = TransportIndex(0xF1);
+
+        on_link_security_changed(tcb_idx.0, LinkSecurityLevel::NoSecurity, 0, &[], 0);
+
+        let security_manager = make_security_manager();
+        assert_eq!(security_manager.get_security_level(tcb_idx), SecurityLevel::NoSecurity);
+        assert_eq!(security_manager.get_encryption_key_size(tcb_idx), None);
+        assert_eq!(security_manager.get_peer_signing_key(tcb_idx), None);
+    }
+
+    #[test]
+    fn test_stale_report_does_not_roll_back_sign_counter() {
+        let tcb_idx = TransportIndex(0xF2);
+        on_link_security_changed(tcb_idx.0, LinkSecurityLevel::NoSecurity, 0, &CSRK, 10);
+        with_link_security(tcb_idx, |link| link.signing_key.as_mut().unwrap().sign_counter = 12);
+
+        // the update of the sign counter has not reached C++ yet
+        on_link_security_changed(tcb_idx.0, LinkSecurityLevel::Encrypted, 16, &CSRK, 10);
+
+        let signing_key = make_security_manager().get_peer_signing_key(tcb_idx).unwrap();
+        assert_eq!(signing_key.sign_counter, 12);
+    }
+
+    #[test]
+    fn test_link_security_cleared() {
+        let tcb_idx = TransportIndex(0xF3);
+        on_link_security_changed(tcb_idx.0, LinkSecurityLevel::Encrypted, 16, &CSRK, 0);
+
+        clear_link_security(tcb_idx);
+
+        let security_manager = make_security_manager();
+        assert_eq!(security_manager.get_security_level(tcb_idx), SecurityLevel::NoSecurity);
+        assert_eq!(security_manager.get_peer_signing_key(tcb_idx), None);
+        assert!(!security_manager.request_security_elevation(tcb_idx, SecurityLevel::Encrypted));
+        assert!(security_manager.0.elevations.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_security_elevation_forwarded() {
+        let tcb_idx = TransportIndex(0xF4);
+        on_link_security_changed(tcb_idx.0, LinkSecurityLevel::NoSecurity, 0, &CSRK, 0);
+
+        let security_manager = make_security_manager();
+        assert!(security_manager.request_security_elevation(tcb_idx, SecurityLevel::Authenticated));
+        security_manager.set_peer_sign_counter(tcb_idx, 3);
+
+        assert_eq!(
+            *security_manager.0.elevations.borrow(),
+            [(tcb_idx, LinkSecurityLevel::Authenticated)]
+        );
+        assert_eq!(*security_manager.0.sign_counters.borrow(), [(tcb_idx, 3)]);
+        assert_eq!(security_manager.get_peer_signing_key(tcb_idx).unwrap().sign_counter, 3);
+    }
 }
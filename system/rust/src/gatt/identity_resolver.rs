@@ -0,0 +1,19 @@
+//! The IdentityResolver tells the GATT server which bonded peer is behind a
+//! connection. Peers usually connect from resolvable private addresses (Core
+//! Spec 5.3 Vol 6B 1.3.2.2), which change from one connection to the next, so
+//! the state retained for a bonded peer (e.g. its CCCD configuration, and its
+//! client supported features) is keyed on its identity address instead.
+
+use crate::{core::address::AddressWithType, gatt::ids::TransportIndex};
+
+/// Resolves the identity address of peers, from the IRKs distributed when
+/// bonding
+pub trait IdentityResolver {
+    /// The identity address of the peer on the specified LE transport, if it
+    /// is bonded and the address it connected from has been resolved
+    fn resolve_identity(&self, tcb_idx: TransportIndex) -> Option<AddressWithType>;
+
+    /// The identity address behind the given address of a peer, if it is
+    /// known. An identity address resolves to itself.
+    fn resolve_address(&self, address: AddressWithType) -> Option<AddressWithType>;
+}
@@ -1,5 +1,8 @@
 //! These are strongly-typed identifiers representing the various objects
-//! interacted with, mostly over FFI
+//! interacted with, mostly over FFI. Their Display impls are compact, for use
+//! in logs, traces, and dumps.
+
+use std::fmt;
 
 /// The ID of a connection at the GATT layer.
 /// A ConnectionId is logically a (TransportIndex, ServerId) tuple,
@@ -24,22 +27,153 @@ impl ConnectionId {
     }
 }
 
+impl From<(TransportIndex, ServerId)> for ConnectionId {
+    fn from((tcb_idx, server_id): (TransportIndex, ServerId)) -> Self {
+        ConnectionId::new(tcb_idx, server_id)
+    }
+}
+
+impl From<ConnectionId> for (TransportIndex, ServerId) {
+    fn from(conn_id: ConnectionId) -> Self {
+        (conn_id.get_tcb_idx(), conn_id.get_server_id())
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.get_tcb_idx(), self.get_server_id())
+    }
+}
+
+/// The physical transport underlying a connection. The unenhanced ATT bearer
+/// runs on the fixed ATT channel of the LE link, or on the L2CAP channel opened
+/// on the ATT PSM of the BR/EDR ACL link (unless built with the le_only
+/// feature).
+#[derive(Debug, Copy, Clone, PartialEq, Hash, Eq)]
+pub enum Transport {
+    /// Bluetooth Low Energy
+    Le,
+    /// BR/EDR (Classic)
+    #[cfg(not(feature = "le_only"))]
+    BrEdr,
+}
+
 /// The server_if of a GATT server registered in legacy
 #[derive(Debug, Copy, Clone, PartialEq, Hash, Eq)]
 pub struct ServerId(pub u8);
 
+impl fmt::Display for ServerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "server{}", self.0)
+    }
+}
+
+/// An application sharing a GATT server with others, in which it registers
+/// services of its own
+#[derive(Debug, Copy, Clone, PartialEq, Hash, Eq)]
+pub struct AppId(pub u32);
+
+impl fmt::Display for AppId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "app{}", self.0)
+    }
+}
+
+/// The client_if of a GATT client registered in legacy
+#[derive(Debug, Copy, Clone, PartialEq, Hash, Eq)]
+pub struct ClientId(pub u8);
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "client{}", self.0)
+    }
+}
+
 /// An arbitrary id representing a GATT transaction (request/response)
 #[derive(Debug, Copy, Clone, PartialEq, Hash, Eq)]
 pub struct TransactionId(pub u32);
 
+impl fmt::Display for TransactionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "trans{}", self.0)
+    }
+}
+
 /// The TCB index in legacy GATT
 #[derive(Debug, Copy, Clone, PartialEq, Hash, Eq)]
 pub struct TransportIndex(pub u8);
 
+impl fmt::Display for TransportIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "tcb{}", self.0)
+    }
+}
+
+/// The local CID of the L2CAP enhanced credit-based channel underlying an EATT
+/// bearer. A connection may have several EATT bearers, in addition to the
+/// unenhanced bearer on the fixed ATT channel.
+#[derive(Debug, Copy, Clone, PartialEq, Hash, Eq)]
+pub struct EattCid(pub u16);
+
+impl fmt::Display for EattCid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cid{:#06x}", self.0)
+    }
+}
+
+/// An ATT bearer of a transport: either the unenhanced bearer, or one of its
+/// EATT bearers
+#[derive(Debug, Copy, Clone, PartialEq, Hash, Eq)]
+pub struct BearerId {
+    /// The transport carrying the bearer
+    pub tcb_idx: TransportIndex,
+    /// The EATT bearer, or None for the unenhanced bearer
+    pub cid: Option<EattCid>,
+}
+
+impl BearerId {
+    /// The unenhanced bearer of a transport
+    pub const fn unenhanced(tcb_idx: TransportIndex) -> Self {
+        Self { tcb_idx, cid: None }
+    }
+
+    /// An EATT bearer of a transport
+    pub const fn eatt(tcb_idx: TransportIndex, cid: EattCid) -> Self {
+        Self { tcb_idx, cid: Some(cid) }
+    }
+}
+
+impl From<TransportIndex> for BearerId {
+    fn from(tcb_idx: TransportIndex) -> Self {
+        Self::unenhanced(tcb_idx)
+    }
+}
+
+impl From<(TransportIndex, EattCid)> for BearerId {
+    fn from((tcb_idx, cid): (TransportIndex, EattCid)) -> Self {
+        Self::eatt(tcb_idx, cid)
+    }
+}
+
+impl fmt::Display for BearerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.cid {
+            Some(cid) => write!(f, "{}/{cid}", self.tcb_idx),
+            None => write!(f, "{}/unenhanced", self.tcb_idx),
+        }
+    }
+}
+
 /// An advertising set ID (zero-based)
 #[derive(Debug, Copy, Clone, PartialEq, Hash, Eq)]
 pub struct AdvertiserId(pub u8);
 
+impl fmt::Display for AdvertiserId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "adv{}", self.0)
+    }
+}
+
 /// The handle of a given ATT attribute
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct AttHandle(pub u16);
@@ -52,3 +186,43 @@ impl AttHandle {
     /// The largest valid AttHandle
     pub const MAX: Self = AttHandle(0xFFFF);
 }
+
+impl fmt::Display for AttHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#06x}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_connection_id_conversions() {
+        let conn_id = ConnectionId::from((TransportIndex(1), ServerId(2)));
+
+        assert_eq!(conn_id, ConnectionId(0x0102));
+        assert_eq!(<(TransportIndex, ServerId)>::from(conn_id), (TransportIndex(1), ServerId(2)));
+    }
+
+    #[test]
+    fn test_bearer_id_conversions() {
+        assert_eq!(BearerId::from(TransportIndex(1)), BearerId::unenhanced(TransportIndex(1)));
+        assert_eq!(
+            BearerId::from((TransportIndex(1), EattCid(0x40))),
+            BearerId { tcb_idx: TransportIndex(1), cid: Some(EattCid(0x40)) }
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(ConnectionId(0x0102).to_string(), "tcb1/server2");
+        assert_eq!(ClientId(3).to_string(), "client3");
+        assert_eq!(AppId(6).to_string(), "app6");
+        assert_eq!(TransactionId(4).to_string(), "trans4");
+        assert_eq!(AdvertiserId(5).to_string(), "adv5");
+        assert_eq!(AttHandle(0x0d).to_string(), "0x000d");
+        assert_eq!(BearerId::unenhanced(TransportIndex(1)).to_string(), "tcb1/unenhanced");
+        assert_eq!(BearerId::eatt(TransportIndex(1), EattCid(0x40)).to_string(), "tcb1/cid0x0040");
+    }
+}
@@ -1,6 +1,14 @@
-//! Mocks for the GattDatastore + AttTransport traits, for use in test
+//! Mocks for the GattDatastore + AttTransport + SecurityManager +
+//! IdentityResolver + DiscoveryCacheStorage + HandleAssignmentStorage +
+//! UserDescriptionStorage + PeerInfoProvider traits, for use in test
 pub mod mock_callbacks;
 pub mod mock_database_callbacks;
 pub mod mock_datastore;
+pub mod mock_discovery_cache_storage;
+pub mod mock_handle_assignment_storage;
+pub mod mock_identity_resolver;
+pub mod mock_peer_info_provider;
 pub mod mock_raw_datastore;
+pub mod mock_security_manager;
 pub mod mock_transport;
+pub mod mock_user_description_storage;
@@ -3,11 +3,15 @@
 use std::ops::RangeInclusive;
 
 use crate::{
-    core::shared_box::{WeakBox, WeakBoxRef},
+    core::{
+        address::AddressWithType,
+        shared_box::{WeakBox, WeakBoxRef},
+    },
     gatt::{
         ids::{AttHandle, TransportIndex},
         server::{
             att_server_bearer::AttServerBearer,
+            client_configuration::ClientConfiguration,
             gatt_database::{AttDatabaseImpl, GattDatabaseCallbacks},
         },
     },
@@ -31,8 +35,12 @@ pub enum MockCallbackEvents {
     OnLeConnect(TransportIndex, WeakBox<AttServerBearer<AttDatabaseImpl>>),
     /// GattDatabaseCallbacks#on_le_disconnect invoked
     OnLeDisconnect(TransportIndex),
+    /// GattDatabaseCallbacks#on_le_bonded invoked
+    OnLeBonded(TransportIndex, AddressWithType),
     /// GattDatabaseCallbacks#on_service_change invoked
     OnServiceChange(RangeInclusive<AttHandle>),
+    /// GattDatabaseCallbacks#on_client_configuration_change invoked
+    OnClientConfigurationChange(TransportIndex, AttHandle, ClientConfiguration),
 }
 
 impl GattDatabaseCallbacks for MockCallbacks {
@@ -48,7 +56,23 @@ impl GattDatabaseCallbacks for MockCallbacks {
         self.0.send(MockCallbackEvents::OnLeDisconnect(tcb_idx)).ok().unwrap();
     }
 
+    fn on_le_bonded(&self, tcb_idx: TransportIndex, peer: AddressWithType) {
+        self.0.send(MockCallbackEvents::OnLeBonded(tcb_idx, peer)).ok().unwrap();
+    }
+
     fn on_service_change(&self, range: RangeInclusive<AttHandle>) {
         self.0.send(MockCallbackEvents::OnServiceChange(range)).ok().unwrap();
     }
+
+    fn on_client_configuration_change(
+        &self,
+        tcb_idx: TransportIndex,
+        handle: AttHandle,
+        configuration: ClientConfiguration,
+    ) {
+        self.0
+            .send(MockCallbackEvents::OnClientConfigurationChange(tcb_idx, handle, configuration))
+            .ok()
+            .unwrap();
+    }
 }
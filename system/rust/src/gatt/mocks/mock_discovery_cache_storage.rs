@@ -0,0 +1,43 @@
+//! Mocked implementation of DiscoveryCacheStorage for use in test
+
+use std::{cell::RefCell, collections::HashMap};
+
+use crate::{core::address::AddressWithType, gatt::client::discovery_cache::DiscoveryCacheStorage};
+
+/// Stores serialized cached databases in memory, so that the test can inspect
+/// and corrupt them
+#[derive(Default)]
+pub struct MockDiscoveryCacheStorage {
+    stored: RefCell<HashMap<AddressWithType, Vec<u8>>>,
+}
+
+impl MockDiscoveryCacheStorage {
+    /// Constructor. Initially, nothing is stored.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the data stored for the given peer
+    pub fn get(&self, peer: AddressWithType) -> Option<Vec<u8>> {
+        self.stored.borrow().get(&peer).cloned()
+    }
+
+    /// Replace the data stored for the given peer
+    pub fn set(&self, peer: AddressWithType, data: Vec<u8>) {
+        self.stored.borrow_mut().insert(peer, data);
+    }
+}
+
+impl DiscoveryCacheStorage for MockDiscoveryCacheStorage {
+    fn load(&self, peer: AddressWithType) -> Option<Vec<u8>> {
+        self.get(peer)
+    }
+
+    fn store(&self, peer: AddressWithType, data: Vec<u8>) {
+        self.set(peer, data);
+    }
+
+    fn remove(&self, peer: AddressWithType) {
+        self.stored.borrow_mut().remove(&peer);
+    }
+}
@@ -0,0 +1,43 @@
+//! Mocked implementation of IdentityResolver for use in test
+
+use std::{cell::RefCell, collections::HashMap};
+
+use crate::{
+    core::address::AddressWithType,
+    gatt::{identity_resolver::IdentityResolver, ids::TransportIndex},
+};
+
+/// Resolves the addresses of peers as set by the test
+#[derive(Default)]
+pub struct MockIdentityResolver {
+    connections: RefCell<HashMap<TransportIndex, AddressWithType>>,
+    addresses: RefCell<HashMap<AddressWithType, AddressWithType>>,
+}
+
+impl MockIdentityResolver {
+    /// Constructor. Initially, no address can be resolved.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the identity address of the peer on the specified transport
+    pub fn set_identity(&self, tcb_idx: TransportIndex, identity: AddressWithType) {
+        self.connections.borrow_mut().insert(tcb_idx, identity);
+    }
+
+    /// Resolve the given address (e.g. a resolvable private address) into
+    /// the given identity address
+    pub fn add_address(&self, address: AddressWithType, identity: AddressWithType) {
+        self.addresses.borrow_mut().insert(address, identity);
+    }
+}
+
+impl IdentityResolver for MockIdentityResolver {
+    fn resolve_identity(&self, tcb_idx: TransportIndex) -> Option<AddressWithType> {
+        self.connections.borrow().get(&tcb_idx).copied()
+    }
+
+    fn resolve_address(&self, address: AddressWithType) -> Option<AddressWithType> {
+        self.addresses.borrow().get(&address).copied()
+    }
+}
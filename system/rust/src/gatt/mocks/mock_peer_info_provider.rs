@@ -0,0 +1,32 @@
+//! Mocked implementation of PeerInfoProvider for use in test
+
+use std::{cell::RefCell, collections::HashMap};
+
+use crate::gatt::{
+    ids::TransportIndex,
+    server::interop::{PeerInfo, PeerInfoProvider},
+};
+
+/// Describes the peers as set by the test
+#[derive(Default)]
+pub struct MockPeerInfoProvider {
+    peers: RefCell<HashMap<TransportIndex, PeerInfo>>,
+}
+
+impl MockPeerInfoProvider {
+    /// Constructor. Initially, nothing is known of any peer.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set what is known of the peer on the specified transport
+    pub fn set_peer_info(&self, tcb_idx: TransportIndex, peer: PeerInfo) {
+        self.peers.borrow_mut().insert(tcb_idx, peer);
+    }
+}
+
+impl PeerInfoProvider for MockPeerInfoProvider {
+    fn peer_info(&self, tcb_idx: TransportIndex) -> PeerInfo {
+        self.peers.borrow().get(&tcb_idx).cloned().unwrap_or_default()
+    }
+}
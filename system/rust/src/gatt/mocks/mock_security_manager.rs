@@ -0,0 +1,104 @@
+//! Mocked implementation of SecurityManager for use in test
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+};
+
+use crate::gatt::{
+    ids::TransportIndex,
+    security_manager::{PeerSigningKey, SecurityLevel, SecurityManager},
+};
+
+/// Stores the security state of peers in memory, as set by the test
+#[derive(Default)]
+pub struct MockSecurityManager {
+    signing_keys: RefCell<HashMap<TransportIndex, PeerSigningKey>>,
+    security_levels: RefCell<HashMap<TransportIndex, SecurityLevel>>,
+    encryption_key_sizes: RefCell<HashMap<TransportIndex, u8>>,
+    authorized: RefCell<HashSet<TransportIndex>>,
+    security_elevation_supported: Cell<bool>,
+    security_elevation_requests: RefCell<Vec<(TransportIndex, SecurityLevel)>>,
+}
+
+impl MockSecurityManager {
+    /// Constructor. Initially, no peer has distributed a signing key, every
+    /// link is unencrypted and unauthorized, and security elevation requests
+    /// are refused.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the signing key distributed by the peer on the specified transport
+    pub fn set_peer_signing_key(&self, tcb_idx: TransportIndex, key: PeerSigningKey) {
+        self.signing_keys.borrow_mut().insert(tcb_idx, key);
+    }
+
+    /// Set the security level of the specified transport
+    pub fn set_security_level(&self, tcb_idx: TransportIndex, security_level: SecurityLevel) {
+        self.security_levels.borrow_mut().insert(tcb_idx, security_level);
+    }
+
+    /// Set the size of the key encrypting the specified transport. Unless
+    /// set, an encrypted link uses a key of the maximum size (16 octets).
+    pub fn set_encryption_key_size(&self, tcb_idx: TransportIndex, key_size: u8) {
+        self.encryption_key_sizes.borrow_mut().insert(tcb_idx, key_size);
+    }
+
+    /// Set whether the peer on the specified transport is authorized
+    pub fn set_authorized(&self, tcb_idx: TransportIndex, authorized: bool) {
+        if authorized {
+            self.authorized.borrow_mut().insert(tcb_idx);
+        } else {
+            self.authorized.borrow_mut().remove(&tcb_idx);
+        }
+    }
+
+    /// Set whether security elevation requests are accepted
+    pub fn set_security_elevation_supported(&self, supported: bool) {
+        self.security_elevation_supported.set(supported);
+    }
+
+    /// Take the security elevation requests accepted so far
+    pub fn take_security_elevation_requests(&self) -> Vec<(TransportIndex, SecurityLevel)> {
+        self.security_elevation_requests.take()
+    }
+}
+
+impl SecurityManager for MockSecurityManager {
+    fn get_peer_signing_key(&self, tcb_idx: TransportIndex) -> Option<PeerSigningKey> {
+        self.signing_keys.borrow().get(&tcb_idx).copied()
+    }
+
+    fn set_peer_sign_counter(&self, tcb_idx: TransportIndex, sign_counter: u32) {
+        if let Some(key) = self.signing_keys.borrow_mut().get_mut(&tcb_idx) {
+            key.sign_counter = sign_counter;
+        }
+    }
+
+    fn get_security_level(&self, tcb_idx: TransportIndex) -> SecurityLevel {
+        self.security_levels.borrow().get(&tcb_idx).copied().unwrap_or(SecurityLevel::NoSecurity)
+    }
+
+    fn get_encryption_key_size(&self, tcb_idx: TransportIndex) -> Option<u8> {
+        if self.get_security_level(tcb_idx) == SecurityLevel::NoSecurity {
+            return None;
+        }
+        Some(self.encryption_key_sizes.borrow().get(&tcb_idx).copied().unwrap_or(16))
+    }
+
+    fn is_authorized(&self, tcb_idx: TransportIndex) -> bool {
+        self.authorized.borrow().contains(&tcb_idx)
+    }
+
+    fn request_security_elevation(
+        &self,
+        tcb_idx: TransportIndex,
+        security_level: SecurityLevel,
+    ) -> bool {
+        if self.security_elevation_supported.get() {
+            self.security_elevation_requests.borrow_mut().push((tcb_idx, security_level));
+        }
+        self.security_elevation_supported.get()
+    }
+}
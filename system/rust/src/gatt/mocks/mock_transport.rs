@@ -1,20 +1,41 @@
 //! Mocked implementation of AttTransport for use in test
 
+use std::cell::RefCell;
+
 use crate::{
-    gatt::{channel::AttTransport, ids::TransportIndex},
+    gatt::{
+        channel::{AttTransport, TransactionTimeoutEvent},
+        ids::{EattCid, TransportIndex},
+    },
     packets::{AttBuilder, Serializable, SerializeError},
 };
 use tokio::sync::mpsc::{self, unbounded_channel, UnboundedReceiver};
 
-/// Routes calls to AttTransport into a channel containing AttBuilders
-pub struct MockAttTransport(mpsc::UnboundedSender<(TransportIndex, AttBuilder)>);
+/// Routes calls to AttTransport into channels containing AttBuilders, and
+/// records the bearers that were closed
+pub struct MockAttTransport {
+    tx: mpsc::UnboundedSender<(TransportIndex, AttBuilder)>,
+    eatt_tx: mpsc::UnboundedSender<(TransportIndex, EattCid, AttBuilder)>,
+    closed_bearers: RefCell<Vec<TransactionTimeoutEvent>>,
+}
 
 impl MockAttTransport {
-    /// Constructor. Returns Self and the RX side of a channel containing
-    /// AttBuilders sent on TransportIndices
-    pub fn new() -> (Self, UnboundedReceiver<(TransportIndex, AttBuilder)>) {
+    /// Constructor. Returns Self and the RX sides of two channels, containing
+    /// AttBuilders sent on TransportIndices, and on EATT bearers respectively
+    #[allow(clippy::type_complexity)]
+    pub fn new() -> (
+        Self,
+        UnboundedReceiver<(TransportIndex, AttBuilder)>,
+        UnboundedReceiver<(TransportIndex, EattCid, AttBuilder)>,
+    ) {
         let (tx, rx) = unbounded_channel();
-        (Self(tx), rx)
+        let (eatt_tx, eatt_rx) = unbounded_channel();
+        (Self { tx, eatt_tx, closed_bearers: RefCell::new(vec![]) }, rx, eatt_rx)
+    }
+
+    /// Take the events passed to close_bearer() since the last call
+    pub fn take_closed_bearers(&self) -> Vec<TransactionTimeoutEvent> {
+        self.closed_bearers.take()
     }
 }
 
@@ -25,7 +46,22 @@ impl AttTransport for MockAttTransport {
         packet: AttBuilder,
     ) -> Result<(), SerializeError> {
         packet.to_vec()?; // trigger SerializeError if needed
-        self.0.send((tcb_idx, packet)).unwrap();
+        self.tx.send((tcb_idx, packet)).unwrap();
+        Ok(())
+    }
+
+    fn send_eatt_packet(
+        &self,
+        tcb_idx: TransportIndex,
+        cid: EattCid,
+        packet: AttBuilder,
+    ) -> Result<(), SerializeError> {
+        packet.to_vec()?; // trigger SerializeError if needed
+        self.eatt_tx.send((tcb_idx, cid, packet)).unwrap();
         Ok(())
     }
+
+    fn close_bearer(&self, event: TransactionTimeoutEvent) {
+        self.closed_bearers.borrow_mut().push(event);
+    }
 }
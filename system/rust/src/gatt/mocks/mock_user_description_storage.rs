@@ -0,0 +1,39 @@
+//! Mocked implementation of UserDescriptionStorage for use in test
+
+use std::cell::RefCell;
+
+use crate::gatt::server::user_descriptions::UserDescriptionStorage;
+
+/// Stores serialized user descriptions in memory, so that the test can
+/// "restart" a server with them, and inspect or corrupt them
+#[derive(Default)]
+pub struct MockUserDescriptionStorage {
+    stored: RefCell<Option<Vec<u8>>>,
+}
+
+impl MockUserDescriptionStorage {
+    /// Constructor. Initially, nothing is stored.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the stored data
+    pub fn get(&self) -> Option<Vec<u8>> {
+        self.stored.borrow().clone()
+    }
+
+    /// Replace the stored data
+    pub fn set(&self, data: Vec<u8>) {
+        self.stored.replace(Some(data));
+    }
+}
+
+impl UserDescriptionStorage for MockUserDescriptionStorage {
+    fn load(&self) -> Option<Vec<u8>> {
+        self.get()
+    }
+
+    fn store(&self, data: Vec<u8>) {
+        self.set(data);
+    }
+}
@@ -1,5 +1,6 @@
-//! The MTU on an ATT bearer is determined either by L2CAP (if EATT) or by the
-//! ATT_EXCHANGE_MTU procedure (if on an unenhanced bearer).
+//! The MTU on an ATT bearer is determined either by L2CAP (if EATT, or over
+//! BR/EDR) or by the ATT_EXCHANGE_MTU procedure (if on an unenhanced LE
+//! bearer).
 //!
 //! In the latter case, the MTU may be either (1) unset, (2) pending, or (3)
 //! set. If the MTU is pending, ATT notifications/indications may not be sent.
@@ -23,8 +24,11 @@ pub enum MtuEvent {
     IncomingRequest(usize),
 }
 
-/// The state of MTU negotiation on an unenhanced ATT bearer
+/// The state of MTU negotiation on an ATT bearer
 pub struct AttMtu {
+    /// Whether this is an enhanced bearer, or a bearer over BR/EDR, whose MTU
+    /// is fixed by L2CAP when the channel is established
+    configured_by_l2cap: bool,
     /// The MTU we have committed to (i.e. sent a REQ and got a RESP, or
     /// vice-versa)
     previous_mtu: Cell<usize>,
@@ -33,18 +37,42 @@ pub struct AttMtu {
     stable_mtu: SharedMutex<usize>,
     /// Lock guard held if we are currrently performing MTU negotiation
     pending_exchange: Cell<Option<OwnedMutexGuard<usize>>>,
+    /// Whether the client has already exchanged the MTU on this bearer
+    exchanged_by_client: Cell<bool>,
 }
 
-// NOTE: this is only true for ATT, not EATT
-const DEFAULT_ATT_MTU: usize = 23;
+// NOTE: this is only true for ATT over LE, not EATT
+pub const DEFAULT_ATT_MTU: usize = 23;
+
+/// The smallest MTU that L2CAP may configure for ATT over BR/EDR (Core Spec
+/// 5.3 Vol 3F 3.2.8)
+pub const MIN_BR_EDR_ATT_MTU: usize = 48;
+
+/// The largest MTU that is useful on an ATT bearer, since attribute values are
+/// at most 512 octets (Core Spec 5.3 Vol 3F 3.2.9)
+pub const MAX_ATT_MTU: usize = 517;
 
 impl AttMtu {
-    /// Constructor
+    /// Constructor, for an unenhanced bearer
     pub fn new() -> Self {
         Self {
+            configured_by_l2cap: false,
             previous_mtu: Cell::new(DEFAULT_ATT_MTU),
             stable_mtu: SharedMutex::new(DEFAULT_ATT_MTU),
             pending_exchange: Cell::new(None),
+            exchanged_by_client: Cell::new(false),
+        }
+    }
+
+    /// Constructor, for a bearer whose MTU was configured by L2CAP (i.e. an
+    /// enhanced bearer, or any bearer over BR/EDR)
+    pub fn new_configured(mtu: usize) -> Self {
+        Self {
+            configured_by_l2cap: true,
+            previous_mtu: Cell::new(mtu),
+            stable_mtu: SharedMutex::new(mtu),
+            pending_exchange: Cell::new(None),
+            exchanged_by_client: Cell::new(false),
         }
     }
 
@@ -63,6 +91,11 @@ impl AttMtu {
 
     /// Handle an MtuEvent and update the stored MTU
     pub fn handle_event(&self, event: MtuEvent) -> Result<()> {
+        // As per Core Spec 5.3 Vol 3F 3.4.2.1, ATT_EXCHANGE_MTU_REQ shall not be
+        // sent on an enhanced bearer, nor (as per Vol 3G 4.3.1) over BR/EDR
+        if self.configured_by_l2cap {
+            bail!("MTU exchange is not permitted on an ATT bearer configured by L2CAP");
+        }
         match event {
             MtuEvent::OutgoingRequest => self.on_outgoing_request(),
             MtuEvent::IncomingResponse(mtu) => self.on_incoming_response(mtu),
@@ -73,6 +106,24 @@ impl AttMtu {
         }
     }
 
+    /// Handle an ATT_EXCHANGE_MTU_REQ received by the server on this bearer,
+    /// and return the resulting MTU. As per Core Spec 5.3 Vol 3F 3.4.2.1, the
+    /// client may exchange the MTU at most once per bearer, and never on an
+    /// enhanced bearer or over BR/EDR.
+    pub fn on_exchange_request(&self, client_rx_mtu: usize, server_rx_mtu: usize) -> Result<usize> {
+        if self.configured_by_l2cap {
+            bail!("MTU exchange is not permitted on an ATT bearer configured by L2CAP");
+        }
+        if self.exchanged_by_client.get() {
+            bail!("the client has already exchanged the MTU on this bearer");
+        }
+        // as per 3.4.2.2, the MTU is the minimum of the two RX MTUs, but at least
+        // the default
+        let mtu = client_rx_mtu.min(server_rx_mtu).max(DEFAULT_ATT_MTU);
+        self.on_incoming_request(mtu);
+        Ok(mtu)
+    }
+
     fn on_outgoing_request(&self) -> Result<()> {
         let Ok(pending_mtu) = self.stable_mtu.try_lock() else {
             bail!("Sent ATT_EXCHANGE_MTU_REQ while an existing MTU exchange is taking place");
@@ -95,6 +146,7 @@ impl AttMtu {
     }
 
     fn on_incoming_request(&self, mtu: usize) {
+        self.exchanged_by_client.set(true);
         self.previous_mtu.set(mtu);
         if let Ok(mut stable_mtu) = self.stable_mtu.try_lock() {
             info!("Accepted an MTU_REQ of {mtu:?}");
@@ -125,6 +177,30 @@ mod test {
         assert_eq!(latest_value, DEFAULT_ATT_MTU);
     }
 
+    #[test]
+    fn test_enhanced_bearer_mtu() {
+        let mtu = AttMtu::new_configured(NEW_MTU);
+
+        let stable_value = mtu.snapshot_or_default();
+        let latest_value = tokio_test::block_on(mtu.snapshot()).unwrap();
+
+        assert_eq!(stable_value, NEW_MTU);
+        assert_eq!(latest_value, NEW_MTU);
+    }
+
+    #[test]
+    fn test_no_exchange_on_enhanced_bearer() {
+        // arrange
+        let mtu = AttMtu::new_configured(NEW_MTU);
+
+        // act: try to exchange the MTU
+        let res = mtu.handle_event(MtuEvent::IncomingRequest(ANOTHER_NEW_MTU));
+
+        // assert: the exchange was rejected, and the MTU is unchanged
+        assert!(res.is_err());
+        assert_eq!(mtu.snapshot_or_default(), NEW_MTU);
+    }
+
     #[test]
     fn test_guaranteed_mtu_during_client_negotiation() {
         // arrange
@@ -173,6 +249,64 @@ mod test {
         });
     }
 
+    #[test]
+    fn test_exchange_request() {
+        let mtu = AttMtu::new();
+
+        let res = mtu.on_exchange_request(ANOTHER_NEW_MTU, NEW_MTU);
+
+        // the smaller of the two RX MTUs is used
+        assert_eq!(res.unwrap(), NEW_MTU);
+        assert_eq!(mtu.snapshot_or_default(), NEW_MTU);
+    }
+
+    #[test]
+    fn test_exchange_request_below_default() {
+        let mtu = AttMtu::new();
+
+        let res = mtu.on_exchange_request(10, NEW_MTU);
+
+        assert_eq!(res.unwrap(), DEFAULT_ATT_MTU);
+        assert_eq!(mtu.snapshot_or_default(), DEFAULT_ATT_MTU);
+    }
+
+    #[test]
+    fn test_second_exchange_request_rejected() {
+        // arrange: the client has already exchanged the MTU
+        let mtu = AttMtu::new();
+        mtu.on_exchange_request(NEW_MTU, MAX_ATT_MTU).unwrap();
+
+        // act: it tries to exchange it again
+        let res = mtu.on_exchange_request(ANOTHER_NEW_MTU, MAX_ATT_MTU);
+
+        // assert: the exchange was rejected, and the MTU is unchanged
+        assert!(res.is_err());
+        assert_eq!(mtu.snapshot_or_default(), NEW_MTU);
+    }
+
+    #[test]
+    fn test_exchange_request_after_snooped_exchange_rejected() {
+        // arrange: the client has exchanged the MTU with the native stack
+        let mtu = AttMtu::new();
+        mtu.handle_event(MtuEvent::IncomingRequest(NEW_MTU)).unwrap();
+
+        // act: it tries to exchange it again
+        let res = mtu.on_exchange_request(ANOTHER_NEW_MTU, MAX_ATT_MTU);
+
+        // assert: the exchange was rejected
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_exchange_request_on_enhanced_bearer_rejected() {
+        let mtu = AttMtu::new_configured(NEW_MTU);
+
+        let res = mtu.on_exchange_request(ANOTHER_NEW_MTU, MAX_ATT_MTU);
+
+        assert!(res.is_err());
+        assert_eq!(mtu.snapshot_or_default(), NEW_MTU);
+    }
+
     #[test]
     fn test_client_then_server_negotiation() {
         block_on_locally(async move {
@@ -54,6 +54,7 @@ pub fn classify_opcode(opcode: AttOpcode) -> OperationType {
         AttOpcode::SIGNED_WRITE_COMMAND => OperationType::Command,
 
         AttOpcode::HANDLE_VALUE_NOTIFICATION => OperationType::Notification,
+        AttOpcode::MULTIPLE_HANDLE_VALUE_NOTIFICATION => OperationType::Notification,
 
         AttOpcode::HANDLE_VALUE_INDICATION => OperationType::Indication,
 
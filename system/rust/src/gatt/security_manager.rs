@@ -0,0 +1,62 @@
+//! This represents the security state (link security level and signing keys)
+//! of connected peers, to be either mocked (in test) or linked to FFI (in
+//! production).
+
+use super::ids::TransportIndex;
+
+/// The signing state of a peer that has distributed its Connection Signature
+/// Resolving Key (Core Spec 5.3 Vol 3H 2.4.2.2)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerSigningKey {
+    /// The CSRK of the peer, in little-endian order (as distributed by SMP)
+    pub csrk: [u8; 16],
+    /// The lowest sign counter that may be accepted in the next signed PDU
+    pub sign_counter: u32,
+}
+
+/// The security level of a link, in increasing order of strength (Core Spec
+/// 5.3 Vol 3C 10.2.1)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SecurityLevel {
+    /// The link is not encrypted
+    NoSecurity,
+    /// The link is encrypted with an unauthenticated key
+    Encrypted,
+    /// The link is encrypted with an authenticated (MITM-protected) key
+    Authenticated,
+}
+
+/// An instance of this trait will be provided to the GattModule on
+/// initialization.
+pub trait SecurityManager {
+    /// Get the signing key distributed by the peer on the specified transport,
+    /// or None if the peer has not distributed one
+    fn get_peer_signing_key(&self, tcb_idx: TransportIndex) -> Option<PeerSigningKey>;
+
+    /// Update the lowest sign counter that may be accepted from the peer on the
+    /// specified transport, after a signed PDU has been accepted
+    fn set_peer_sign_counter(&self, tcb_idx: TransportIndex, sign_counter: u32);
+
+    /// Get the current security level of the specified transport
+    fn get_security_level(&self, tcb_idx: TransportIndex) -> SecurityLevel;
+
+    /// Get the size (in octets, from 7 to 16) of the key encrypting the
+    /// specified transport, or None if it is not encrypted (Core Spec 5.3 Vol
+    /// 3H 2.3.4)
+    fn get_encryption_key_size(&self, tcb_idx: TransportIndex) -> Option<u8>;
+
+    /// Whether the peer on the specified transport has been authorized to
+    /// access attributes requiring authorization
+    fn is_authorized(&self, tcb_idx: TransportIndex) -> bool;
+
+    /// Request that the security of the specified transport be raised to (at
+    /// least) the given level, e.g. by encrypting the link or pairing. The GATT
+    /// server must then be informed once the attempt has completed, whether or
+    /// not it was successful. Returns false if the request cannot be made, in
+    /// which case the server will not wait for it.
+    fn request_security_elevation(
+        &self,
+        tcb_idx: TransportIndex,
+        security_level: SecurityLevel,
+    ) -> bool;
+}
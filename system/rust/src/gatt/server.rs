@@ -1,88 +1,361 @@
 //! This module is a simple GATT server that shares the ATT channel with the
 //! existing C++ GATT client.
 
-mod att_database;
+pub mod access_journal;
+pub mod access_policy;
+pub mod apps;
+pub mod att_database;
+pub mod att_error;
 pub mod att_server_bearer;
+pub mod att_server_core;
+pub mod authorization;
+#[cfg(feature = "benchmarking")]
+pub mod benchmarking;
+pub mod characteristic_streams;
+pub mod client_configuration;
+pub mod composite_att_database;
+pub mod config;
+#[cfg(any(test, feature = "conformance"))]
+pub mod conformance;
+pub mod data_length;
+pub mod events;
+#[cfg(any(test, feature = "fuzzing"))]
+pub mod fuzzing;
 pub mod gatt_database;
+pub mod handle_assignments;
 mod indication_handler;
+pub mod interceptors;
+pub mod interop;
+pub mod notification_handler;
+pub mod opcode_policy;
+pub mod pdu_decoder;
+pub mod pdu_processor;
+#[cfg(any(test, feature = "replay"))]
+pub mod replay;
 mod request_handler;
+pub mod robust_caching;
+pub mod schema;
+pub mod security_elevation;
 pub mod services;
+pub mod signature_verifier;
+pub mod static_service;
+pub mod trace;
 mod transactions;
+pub mod user_descriptions;
+pub mod write_locks;
+pub mod write_validation;
 
 mod command_handler;
 pub mod isolation_manager;
-#[cfg(test)]
+pub mod metrics;
+pub mod mtu_audit;
+#[cfg(any(test, feature = "fuzzing", feature = "replay", feature = "conformance"))]
 mod test;
 
 use std::{
     collections::HashMap,
+    fmt,
+    future::Future,
+    ops::RangeInclusive,
     rc::Rc,
     sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, SystemTime},
 };
 
 use crate::{
-    core::shared_box::{SharedBox, WeakBox, WeakBoxRef},
+    core::{
+        address::AddressWithType,
+        shared_box::{SharedBox, WeakBox, WeakBoxRef},
+        uuid::Uuid,
+    },
     gatt::server::gatt_database::GattDatabase,
+    packets::AttAttributeDataChild,
+    utils::{
+        clock::{Clock, TokioClock},
+        executor::{Executor, TokioExecutor},
+    },
 };
 
 use self::{
-    super::ids::ServerId,
-    att_server_bearer::AttServerBearer,
-    gatt_database::{AttDatabaseImpl, GattServiceWithHandle},
+    super::ids::{AppId, ServerId},
+    access_journal::AccessJournal,
+    access_policy::AccessInterceptor,
+    apps::AppRegistry,
+    att_server_bearer::{AttServerBearer, BearerEvent, SendError, DEFAULT_REQUEST_TIMEOUT},
+    authorization::AuthorizationProvider,
+    characteristic_streams::CharacteristicStreams,
+    client_configuration::ClientConfiguration,
+    config::GattServerConfig,
+    data_length::{recommended_notification_payload, validate_data_length, DEFAULT_LE_DATA_LENGTH},
+    events::{GattServerEvent, GattServerEventListener, GattServerEvents},
+    gatt_database::{
+        AttDatabaseImpl, GattDatabaseCallbacks, GattServiceWithHandle, ServiceBuilder, ServiceToken,
+    },
+    handle_assignments::HandleAssignmentStorage,
+    interceptors::Interceptor,
+    interop::{InteropRegistry, PeerInfoProvider, Quirks},
     isolation_manager::IsolationManager,
-    services::register_builtin_services,
+    metrics::{BearerMetrics, ConnectionMetrics, ConnectionStats, MetricsSnapshot},
+    notification_handler::Priority,
+    opcode_policy::OpcodePolicy,
+    security_elevation::SecurityElevation,
+    services::{
+        dis::DeviceInformationProvider,
+        gap::{DefaultGapConfiguration, GapConfiguration},
+        gatt::ServerSupportedFeatures,
+        register_builtin_services,
+    },
+    signature_verifier::SignatureVerifier,
+    trace::{AttTraceEvent, AttTracer, SpanEvent, SpanId, TransactionTracer},
+    user_descriptions::UserDescriptionStorage,
 };
 
+#[cfg(not(feature = "le_only"))]
+use super::mtu::MIN_BR_EDR_ATT_MTU;
 use super::{
     callbacks::RawGattDatastore,
-    channel::AttTransport,
-    ids::{AdvertiserId, AttHandle, TransportIndex},
+    channel::{AttTransport, TransactionTimeoutEvent, TransmitBackpressure},
+    identity_resolver::IdentityResolver,
+    ids::{AdvertiserId, AttHandle, BearerId, ConnectionId, EattCid, Transport, TransportIndex},
+    mtu::{DEFAULT_ATT_MTU, MAX_ATT_MTU},
+    security_manager::SecurityManager,
 };
 use anyhow::{anyhow, bail, Result};
 use bt_common::init_flags::always_use_private_gatt_for_debugging_is_enabled;
 use log::info;
+use tokio::sync::oneshot;
 
 pub use indication_handler::IndicationError;
+pub use notification_handler::NotificationError;
 
 #[allow(missing_docs)]
 pub struct GattModule {
-    connections: HashMap<TransportIndex, GattConnection>,
+    connections: HashMap<ConnectionId, GattConnection>,
     databases: HashMap<ServerId, SharedBox<GattDatabase>>,
+    apps: HashMap<ServerId, AppRegistry>,
     transport: Rc<dyn AttTransport>,
+    security_manager: Rc<dyn SecurityManager>,
+    identity_resolver: Option<Rc<dyn IdentityResolver>>,
+    interop: Option<(Rc<InteropRegistry>, Rc<dyn PeerInfoProvider>)>,
+    server_rx_mtu: usize,
+    request_timeout: Duration,
+    gap_configuration: Rc<dyn GapConfiguration>,
+    device_information: Option<Rc<dyn DeviceInformationProvider>>,
+    eatt_supported: bool,
+    config: GattServerConfig,
+    tracer: Option<Rc<dyn AttTracer>>,
+    transaction_tracer: Option<Rc<dyn TransactionTracer>>,
+    access_journal: Option<Rc<AccessJournal>>,
+    backpressure: Option<Rc<dyn TransmitBackpressure>>,
+    clock: Rc<dyn Clock>,
+    executor: Rc<dyn Executor>,
+    opcode_policy: Option<Rc<dyn OpcodePolicy>>,
+    events: GattServerEvents,
     // NOTE: this is logically owned by the GattModule. We share it behind a Mutex just so we
     // can use it as part of the Arbiter. Once the Arbiter is removed, this should be owned
     // fully by the GattModule.
     isolation_manager: Arc<Mutex<IsolationManager>>,
 }
 
+/// The state of a single connection to a server. Each of its bearers has its
+/// own transaction state, prepared write queue, and MTU, so a misbehaving
+/// connection (or bearer) does not affect any other.
 struct GattConnection {
+    transport: Transport,
     bearer: SharedBox<AttServerBearer<AttDatabaseImpl>>,
+    eatt_bearers: HashMap<EattCid, SharedBox<AttServerBearer<AttDatabaseImpl>>>,
     database: WeakBox<GattDatabase>,
+    /// The LE data length of the underlying link, in the TX direction
+    data_length: usize,
+    /// The identity address of the peer, once it is known to be bonded
+    identity: Option<AddressWithType>,
+    /// The quirks of the peer, worked around on each bearer
+    quirks: Quirks,
+}
+
+/// Forwards the events on each bearer of a connection as GattServerEvents
+fn forward_bearer_events(
+    bearer: &AttServerBearer<AttDatabaseImpl>,
+    events: GattServerEvents,
+    conn_id: ConnectionId,
+) {
+    bearer.set_on_event(move |event| {
+        events.emit(match event {
+            BearerEvent::MtuChanged(mtu) => GattServerEvent::MtuChanged { conn_id, mtu },
+            BearerEvent::IndicationConfirmed(handle) => {
+                GattServerEvent::IndicationConfirmed { conn_id, handle }
+            }
+            BearerEvent::Congestion(congested) => {
+                GattServerEvent::Congestion { conn_id, congested }
+            }
+            BearerEvent::ErrorRateExceeded(errors) => {
+                GattServerEvent::ErrorRateExceeded { conn_id, errors }
+            }
+        })
+    });
+}
+
+/// Passes every PDU on a bearer to the tracer and to the access journal (if
+/// either is set), with its connection and bearer
+fn trace_bearer_pdus(
+    bearer: &AttServerBearer<AttDatabaseImpl>,
+    tracer: Option<Rc<dyn AttTracer>>,
+    journal: Option<(Rc<AccessJournal>, Rc<dyn SecurityManager>)>,
+    conn_id: ConnectionId,
+    cid: Option<EattCid>,
+) {
+    if tracer.is_none() && journal.is_none() {
+        return;
+    }
+    bearer.set_on_pdu(move |direction, pdu| {
+        let event = AttTraceEvent { conn_id, cid, direction, timestamp: SystemTime::now(), pdu };
+        if let Some(tracer) = &tracer {
+            tracer.on_pdu(&event);
+        }
+        if let Some((journal, security_manager)) = &journal {
+            journal.on_pdu(&event, security_manager.get_security_level(conn_id.get_tcb_idx()));
+        }
+    });
+}
+
+/// Passes the span events of every transaction on a bearer to the tracer (if
+/// set), with its connection and bearer
+fn trace_bearer_transactions(
+    bearer: &AttServerBearer<AttDatabaseImpl>,
+    tracer: Option<Rc<dyn TransactionTracer>>,
+    conn_id: ConnectionId,
+    cid: Option<EattCid>,
+) {
+    let Some(tracer) = tracer else {
+        return;
+    };
+    bearer.set_on_span(move |seq, opcode, phase, timestamp| {
+        let span = SpanId { conn_id, cid, seq };
+        tracer.on_span_event(&SpanEvent { span, opcode, phase, timestamp });
+    });
+}
+
+/// Forwards the CCCD writes to the database of a server as GattServerEvents
+struct ClientConfigurationForwarder {
+    server_id: ServerId,
+    events: GattServerEvents,
+}
+
+impl GattDatabaseCallbacks for ClientConfigurationForwarder {
+    fn on_le_connect(
+        &self,
+        _tcb_idx: TransportIndex,
+        _bearer: WeakBoxRef<AttServerBearer<AttDatabaseImpl>>,
+    ) {
+    }
+
+    fn on_le_disconnect(&self, _tcb_idx: TransportIndex) {}
+
+    fn on_le_bonded(&self, _tcb_idx: TransportIndex, _peer: AddressWithType) {}
+
+    fn on_service_change(&self, _range: RangeInclusive<AttHandle>) {}
+
+    fn on_client_configuration_change(
+        &self,
+        tcb_idx: TransportIndex,
+        handle: AttHandle,
+        configuration: ClientConfiguration,
+    ) {
+        self.events.emit(GattServerEvent::CccdChanged {
+            conn_id: ConnectionId::new(tcb_idx, self.server_id),
+            handle,
+            bits: configuration,
+        });
+    }
 }
 
 impl GattModule {
     /// Constructor.
     pub fn new(
         transport: Rc<dyn AttTransport>,
+        security_manager: Rc<dyn SecurityManager>,
         isolation_manager: Arc<Mutex<IsolationManager>>,
     ) -> Self {
         Self {
             connections: HashMap::new(),
             databases: HashMap::new(),
+            apps: HashMap::new(),
             transport,
+            security_manager,
+            identity_resolver: None,
+            interop: None,
+            server_rx_mtu: MAX_ATT_MTU,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            gap_configuration: Rc::new(DefaultGapConfiguration),
+            device_information: None,
+            eatt_supported: false,
+            config: GattServerConfig::default(),
+            tracer: None,
+            transaction_tracer: None,
+            access_journal: None,
+            backpressure: None,
+            clock: Rc::new(TokioClock),
+            executor: Rc::new(TokioExecutor),
+            opcode_policy: None,
+            events: GattServerEvents::new(),
             isolation_manager,
         }
     }
 
-    /// Handle LE link connect
+    /// Register a listener for the GattServerEvents on every server and
+    /// connection
+    pub fn register_event_listener(&self, listener: Rc<dyn GattServerEventListener>) {
+        self.events.register_listener(listener);
+    }
+
+    /// Handle LE link connect. If the IdentityResolver (if any) knows the peer
+    /// to be bonded, the state saved for it is restored right away.
     pub fn on_le_connect(
         &mut self,
         tcb_idx: TransportIndex,
         advertiser_id: Option<AdvertiserId>,
     ) -> Result<()> {
         info!("connected on tcb_idx {tcb_idx:?}");
+        if self.get_connection(tcb_idx).is_some() {
+            bail!("got connection on {tcb_idx:?} but it is already connected");
+        }
         self.isolation_manager.lock().unwrap().on_le_connect(tcb_idx, advertiser_id);
+        self.open_connection(tcb_idx, Transport::Le, None)
+    }
+
+    /// Handle a BR/EDR link connect, once its ATT channel (on the ATT PSM) has
+    /// been configured with the given MTU. The connection is exposed to the
+    /// given server.
+    #[cfg(not(feature = "le_only"))]
+    pub fn on_br_edr_connect(
+        &mut self,
+        tcb_idx: TransportIndex,
+        server_id: ServerId,
+        mtu: usize,
+    ) -> Result<()> {
+        info!("connected over BR/EDR on tcb_idx {tcb_idx:?} with MTU {mtu}");
+        if self.get_connection(tcb_idx).is_some() {
+            bail!("got connection on {tcb_idx:?} but it is already connected");
+        }
+        if mtu < MIN_BR_EDR_ATT_MTU {
+            bail!("got connection over BR/EDR with MTU {mtu}, below the minimum of {MIN_BR_EDR_ATT_MTU}");
+        }
+        if !self.databases.contains_key(&server_id) {
+            bail!("got connection to {server_id:?} but this server does not exist!");
+        }
+        self.isolation_manager.lock().unwrap().on_br_edr_connect(tcb_idx, server_id);
+        self.open_connection(tcb_idx, Transport::BrEdr, Some(mtu))
+    }
 
+    /// Open the unenhanced bearer of a connection to an isolated server. Its
+    /// MTU is configured by L2CAP if provided (over BR/EDR), otherwise it is
+    /// exchanged.
+    fn open_connection(
+        &mut self,
+        tcb_idx: TransportIndex,
+        transport_type: Transport,
+        configured_mtu: Option<usize>,
+    ) -> Result<()> {
         let Some(server_id) = self.isolation_manager.lock().unwrap().get_server_id(tcb_idx) else {
             bail!("non-isolated servers are not yet supported (b/274945531)")
         };
@@ -92,25 +365,245 @@ impl GattModule {
         };
 
         let transport = self.transport.clone();
-        let bearer = SharedBox::new(AttServerBearer::new(
-            database.get_att_database(tcb_idx),
-            move |packet| transport.send_packet(tcb_idx, packet),
-        ));
+        let send_packet = move |packet| transport.send_packet(tcb_idx, packet);
+        let db = database.get_att_database_over(tcb_idx, transport_type);
+        let signature_verifier = SignatureVerifier::new(tcb_idx, self.security_manager.clone());
+        let security_elevation = SecurityElevation::new(tcb_idx, self.security_manager.clone());
+        let quirks = self.quirks_for(tcb_idx, transport_type);
+        let bearer = SharedBox::new(match configured_mtu {
+            #[cfg(not(feature = "le_only"))]
+            Some(mtu) => AttServerBearer::new_br_edr(
+                db,
+                signature_verifier,
+                security_elevation,
+                quirks.cap_mtu(mtu),
+                send_packet,
+            ),
+            _ => AttServerBearer::new(
+                db,
+                signature_verifier,
+                security_elevation,
+                quirks.cap_mtu(self.server_rx_mtu),
+                send_packet,
+            ),
+        });
+        bearer.set_quirks(quirks);
+        bearer.set_request_timeout(self.request_timeout);
+        bearer.set_clock(self.clock.clone());
+        bearer.set_executor(self.executor.clone());
+        if let Some(policy) = &self.opcode_policy {
+            bearer.set_opcode_policy(policy.clone(), tcb_idx);
+        }
+        let transport = self.transport.clone();
+        bearer.set_on_transaction_timeout(move |opcode| {
+            transport.close_bearer(TransactionTimeoutEvent { tcb_idx, cid: None, opcode })
+        });
+        let conn_id = ConnectionId::new(tcb_idx, server_id);
+        forward_bearer_events(&bearer, self.events.clone(), conn_id);
+        trace_bearer_pdus(&bearer, self.tracer.clone(), self.journal(), conn_id, None);
+        trace_bearer_transactions(&bearer, self.transaction_tracer.clone(), conn_id, None);
+        if let Some(backpressure) = &self.backpressure {
+            bearer.set_backpressure(backpressure.clone(), BearerId::unenhanced(tcb_idx));
+        }
         database.on_bearer_ready(tcb_idx, bearer.as_ref());
-        self.connections.insert(tcb_idx, GattConnection { bearer, database: database.downgrade() });
+        self.connections.insert(
+            conn_id,
+            GattConnection {
+                transport: transport_type,
+                bearer,
+                eatt_bearers: HashMap::new(),
+                database: database.downgrade(),
+                data_length: DEFAULT_LE_DATA_LENGTH,
+                identity: None,
+                quirks,
+            },
+        );
+        // a bonded peer may already be known, from the address it connected from
+        let identity = match (&self.identity_resolver, transport_type) {
+            (Some(resolver), Transport::Le) => resolver.resolve_identity(tcb_idx),
+            _ => None,
+        };
+        if let Some(identity) = identity {
+            self.identify_peer(tcb_idx, identity)?;
+        }
+        self.events.emit(GattServerEvent::ConnectionOpened { conn_id, transport: transport_type });
         Ok(())
     }
 
-    /// Handle an LE link disconnect
+    /// Handle a link disconnect, over either transport. The transactions in
+    /// flight on its bearers are cancelled (flushing their prepared writes),
+    /// its non-persistent state is released, and then every service of its
+    /// server is told that the peer disconnected.
     pub fn on_le_disconnect(&mut self, tcb_idx: TransportIndex) -> Result<()> {
         info!("disconnected conn_id {tcb_idx:?}");
         self.isolation_manager.lock().unwrap().on_le_disconnect(tcb_idx);
-        let connection = self.connections.remove(&tcb_idx);
-        let Some(connection) = connection else {
+        let Some(conn_id) = self.get_connection_id(tcb_idx) else {
             bail!("got disconnection from {tcb_idx:?} but bearer does not exist");
         };
+        // only the state of this connection is torn down
+        let connection = self.connections.remove(&conn_id).unwrap();
+        let bearers = connection
+            .eatt_bearers
+            .keys()
+            .map(|cid| BearerId::eatt(tcb_idx, *cid))
+            .chain([BearerId::unenhanced(tcb_idx)])
+            .collect::<Vec<_>>();
+        drop(connection.eatt_bearers);
         drop(connection.bearer);
+        if let Some(backpressure) = &self.backpressure {
+            for bearer in bearers {
+                backpressure.on_bearer_closed(bearer);
+            }
+        }
         connection.database.with(|db| db.map(|db| db.on_bearer_dropped(tcb_idx)));
+        if let Some(journal) = &self.access_journal {
+            journal.on_le_disconnect(tcb_idx);
+        }
+        debug_assert!(
+            !self.has_connection_state(tcb_idx),
+            "state of {tcb_idx:?} leaked past its disconnection"
+        );
+        self.events.emit(GattServerEvent::ConnectionClosed { conn_id });
+        Ok(())
+    }
+
+    /// Whether any state is kept for the given transport, i.e. it is connected
+    /// (or isolated to a server) or its state leaked past its disconnection
+    pub fn has_connection_state(&self, tcb_idx: TransportIndex) -> bool {
+        self.get_connection_id(tcb_idx).is_some()
+            || self.isolation_manager.lock().unwrap().is_connection_isolated(tcb_idx)
+            || self.databases.values().any(|database| database.has_connection_state(tcb_idx))
+    }
+
+    /// Handle an EATT bearer (an L2CAP enhanced credit-based channel on the
+    /// EATT PSM) being established on an existing LE or BR/EDR link, with the
+    /// given MTU. Requests on this bearer are processed independently of those
+    /// on the other bearers of the link.
+    pub fn on_eatt_bearer_open(
+        &mut self,
+        tcb_idx: TransportIndex,
+        cid: EattCid,
+        mtu: usize,
+    ) -> Result<()> {
+        info!("EATT bearer {} opened with MTU {mtu}", BearerId::eatt(tcb_idx, cid));
+        let Some(server_id) = self.isolation_manager.lock().unwrap().get_server_id(tcb_idx) else {
+            bail!("non-isolated servers are not yet supported (b/274945531)")
+        };
+        let Some(database) = self.databases.get(&server_id) else {
+            bail!("got EATT bearer to {server_id:?} but this server does not exist!");
+        };
+        let journal = self.journal();
+        let Some(connection) = self.connections.get_mut(&ConnectionId::new(tcb_idx, server_id))
+        else {
+            bail!("got EATT bearer on {tcb_idx:?} but the connection does not exist");
+        };
+        if connection.eatt_bearers.contains_key(&cid) {
+            bail!("EATT bearer {cid:?} on {tcb_idx:?} already exists");
+        }
+        let config = database.config();
+        if !config.eatt_enabled {
+            bail!("got EATT bearer {cid:?} on {tcb_idx:?} but EATT is disabled");
+        }
+        if connection.eatt_bearers.len() >= config.max_eatt_bearers {
+            bail!(
+                "got EATT bearer {cid:?} on {tcb_idx:?} but it already has {} of them",
+                connection.eatt_bearers.len()
+            );
+        }
+
+        let transport = self.transport.clone();
+        let bearer = SharedBox::new(AttServerBearer::new_enhanced(
+            database.get_att_database_over(tcb_idx, connection.transport),
+            SignatureVerifier::new(tcb_idx, self.security_manager.clone()),
+            SecurityElevation::new(tcb_idx, self.security_manager.clone()),
+            connection.quirks.cap_mtu(mtu),
+            move |packet| transport.send_eatt_packet(tcb_idx, cid, packet),
+        ));
+        bearer.set_quirks(connection.quirks);
+        bearer.set_request_timeout(self.request_timeout);
+        bearer.set_clock(self.clock.clone());
+        bearer.set_executor(self.executor.clone());
+        if let Some(policy) = &self.opcode_policy {
+            bearer.set_opcode_policy(policy.clone(), tcb_idx);
+        }
+        let transport = self.transport.clone();
+        bearer.set_on_transaction_timeout(move |opcode| {
+            transport.close_bearer(TransactionTimeoutEvent { tcb_idx, cid: Some(cid), opcode })
+        });
+        let conn_id = ConnectionId::new(tcb_idx, server_id);
+        forward_bearer_events(&bearer, self.events.clone(), conn_id);
+        trace_bearer_pdus(&bearer, self.tracer.clone(), journal, conn_id, Some(cid));
+        trace_bearer_transactions(&bearer, self.transaction_tracer.clone(), conn_id, Some(cid));
+        if let Some(backpressure) = &self.backpressure {
+            bearer.set_backpressure(backpressure.clone(), BearerId::eatt(tcb_idx, cid));
+        }
+        // the database already tracks this connection through its unenhanced bearer,
+        // so on_bearer_ready() is not invoked again
+        connection.eatt_bearers.insert(cid, bearer);
+        Ok(())
+    }
+
+    /// Handle an EATT bearer being closed. Any transaction pending on it is
+    /// dropped.
+    pub fn on_eatt_bearer_close(&mut self, tcb_idx: TransportIndex, cid: EattCid) -> Result<()> {
+        info!("EATT bearer {} closed", BearerId::eatt(tcb_idx, cid));
+        let Some(connection) = self.get_connection_mut(tcb_idx) else {
+            bail!("got EATT bearer closure on {tcb_idx:?} but the connection does not exist");
+        };
+        if connection.eatt_bearers.remove(&cid).is_none() {
+            bail!("got closure of EATT bearer {cid:?} on {tcb_idx:?} but it does not exist");
+        }
+        if let Some(backpressure) = &self.backpressure {
+            backpressure.on_bearer_closed(BearerId::eatt(tcb_idx, cid));
+        }
+        Ok(())
+    }
+
+    /// Handle the peer on an LE link being identified as bonded (i.e. the link
+    /// is encrypted with a bonded key), so that its CCCD configuration is
+    /// restored and retained across connections. The given address is first
+    /// resolved into the identity address of the peer if an IdentityResolver
+    /// is set, since the address the peer connected from may not outlive the
+    /// connection.
+    pub fn on_le_bonded(&mut self, tcb_idx: TransportIndex, peer: AddressWithType) -> Result<()> {
+        let identity = self
+            .identity_resolver
+            .as_ref()
+            .and_then(|resolver| resolver.resolve_address(peer))
+            .unwrap_or(peer);
+        self.identify_peer(tcb_idx, identity)
+    }
+
+    /// Key the state retained for the peer on the given connection on its
+    /// identity address, restoring what was saved under it. Nothing is done if
+    /// the peer was already identified as such (e.g. when the connection was
+    /// set up).
+    fn identify_peer(&mut self, tcb_idx: TransportIndex, identity: AddressWithType) -> Result<()> {
+        let Some(connection) = self.get_connection_mut(tcb_idx) else {
+            bail!("got bonding identity for {tcb_idx:?} but bearer does not exist");
+        };
+        if connection.identity == Some(identity) {
+            return Ok(());
+        }
+        connection.identity = Some(identity);
+        connection.database.with(|db| db.map(|db| db.on_le_bonded(tcb_idx, identity)));
+        if let Some(journal) = &self.access_journal {
+            journal.on_le_bonded(tcb_idx, identity);
+        }
+        Ok(())
+    }
+
+    /// Handle a change in the security level of a link, or the completion
+    /// of an attempt to change it (whether or not it succeeded), so that any
+    /// request parked until the link is secure enough can be replayed
+    pub fn on_security_level_changed(&mut self, tcb_idx: TransportIndex) -> Result<()> {
+        let Some(connection) = self.get_connection(tcb_idx) else {
+            bail!("got security level change for {tcb_idx:?} but bearer does not exist");
+        };
+        connection.bearer.on_security_level_changed();
+        for bearer in connection.eatt_bearers.values() {
+            bearer.on_security_level_changed();
+        }
         Ok(())
     }
 
@@ -133,16 +626,133 @@ impl GattModule {
         server_id: ServerId,
         service_handle: AttHandle,
     ) -> Result<()> {
+        if let Some(app_id) = self.apps.get(&server_id).and_then(|apps| apps.owner(service_handle))
+        {
+            bail!("service at {service_handle} is owned by {app_id}, which must remove it");
+        }
         self.databases
             .get(&server_id)
             .ok_or_else(|| anyhow!("server {server_id:?} not opened"))?
             .remove_service_at_handle(service_handle)
     }
 
+    /// Add a GATT service described by a ServiceBuilder on a given server,
+    /// provisioning its handles. Connected clients are notified through the
+    /// Service Changed characteristic.
+    pub fn add_gatt_service(
+        &mut self,
+        server_id: ServerId,
+        service: ServiceBuilder,
+        datastore: impl RawGattDatastore + 'static,
+    ) -> Result<ServiceToken> {
+        self.databases
+            .get(&server_id)
+            .ok_or_else(|| anyhow!("server {server_id:?} not opened"))?
+            .add_service(service, Rc::new(datastore))
+    }
+
+    /// Add a GATT service described by a ServiceBuilder on a given server, as
+    /// with add_gatt_service(), but implemented by the given
+    /// CharacteristicStreams. This must be done before clients connect, for
+    /// its notifications to reach them.
+    pub fn add_streaming_gatt_service(
+        &mut self,
+        server_id: ServerId,
+        service: ServiceBuilder,
+        streams: &CharacteristicStreams,
+    ) -> Result<ServiceToken> {
+        let database = self
+            .databases
+            .get(&server_id)
+            .ok_or_else(|| anyhow!("server {server_id:?} not opened"))?;
+        streams.add_to(database, service)
+    }
+
+    /// Remove a GATT service added with add_gatt_service(). Transactions on
+    /// its attributes that are still in progress fail with INVALID_HANDLE.
+    pub fn remove_gatt_service(&mut self, server_id: ServerId, token: ServiceToken) -> Result<()> {
+        self.databases
+            .get(&server_id)
+            .ok_or_else(|| anyhow!("server {server_id:?} not opened"))?
+            .remove_service(token)
+    }
+
+    /// Register an application on a given server, which can then add services
+    /// of its own. All of its reads and writes are routed to the supplied
+    /// datastore.
+    pub fn register_gatt_app(
+        &mut self,
+        server_id: ServerId,
+        app_id: AppId,
+        datastore: impl RawGattDatastore + 'static,
+    ) -> Result<()> {
+        self.apps
+            .get_mut(&server_id)
+            .ok_or_else(|| anyhow!("server {server_id:?} not opened"))?
+            .register_app(app_id, Rc::new(datastore))
+    }
+
+    /// Unregister an application from a given server (e.g. since it died),
+    /// removing all of its services at once. Connected clients are told of a
+    /// single change covering all of them.
+    pub fn unregister_gatt_app(&mut self, server_id: ServerId, app_id: AppId) -> Result<()> {
+        let database = self
+            .databases
+            .get(&server_id)
+            .ok_or_else(|| anyhow!("server {server_id:?} not opened"))?;
+        self.apps.get_mut(&server_id).unwrap().unregister_app(database, app_id)
+    }
+
+    /// Add a GATT service owned by an application registered on a given
+    /// server, provisioning its handles. Returns the handle of the service.
+    pub fn add_gatt_app_service(
+        &mut self,
+        server_id: ServerId,
+        app_id: AppId,
+        service: ServiceBuilder,
+    ) -> Result<AttHandle> {
+        let database = self
+            .databases
+            .get(&server_id)
+            .ok_or_else(|| anyhow!("server {server_id:?} not opened"))?;
+        self.apps.get_mut(&server_id).unwrap().add_service(database, app_id, service)
+    }
+
+    /// Remove a GATT service added with add_gatt_app_service(), which must be
+    /// owned by the given application
+    pub fn remove_gatt_app_service(
+        &mut self,
+        server_id: ServerId,
+        app_id: AppId,
+        service_handle: AttHandle,
+    ) -> Result<()> {
+        let database = self
+            .databases
+            .get(&server_id)
+            .ok_or_else(|| anyhow!("server {server_id:?} not opened"))?;
+        self.apps.get_mut(&server_id).unwrap().remove_service(database, app_id, service_handle)
+    }
+
     /// Open a GATT server
     pub fn open_gatt_server(&mut self, server_id: ServerId) -> Result<()> {
-        let mut db = GattDatabase::new();
-        register_builtin_services(&mut db)?;
+        let mut db = GattDatabase::new_with_security_manager(self.security_manager.clone())
+            .with_config(self.config);
+        let mut server_supported_features = ServerSupportedFeatures::empty();
+        server_supported_features.set(
+            ServerSupportedFeatures::EATT_SUPPORTED,
+            self.eatt_supported && self.config.eatt_enabled,
+        );
+        register_builtin_services(
+            &mut db,
+            self.gap_configuration.clone(),
+            server_supported_features,
+            self.device_information.as_deref(),
+        )?;
+        db.register_listener(Rc::new(ClientConfigurationForwarder {
+            server_id,
+            events: self.events.clone(),
+        }));
+        self.apps.insert(server_id, AppRegistry::new());
         let old = self.databases.insert(server_id, db.into());
         if old.is_some() {
             bail!("GATT server {server_id:?} already exists but was re-opened, clobbering old value...")
@@ -153,6 +763,7 @@ impl GattModule {
     /// Close a GATT server
     pub fn close_gatt_server(&mut self, server_id: ServerId) -> Result<()> {
         let old = self.databases.remove(&server_id);
+        self.apps.remove(&server_id);
         if old.is_none() {
             bail!("GATT server {server_id:?} did not exist")
         };
@@ -169,7 +780,499 @@ impl GattModule {
         &self,
         tcb_idx: TransportIndex,
     ) -> Option<WeakBoxRef<AttServerBearer<AttDatabaseImpl>>> {
-        self.connections.get(&tcb_idx).map(|x| x.bearer.as_ref())
+        self.get_connection(tcb_idx).map(|x| x.bearer.as_ref())
+    }
+
+    /// Get the MTU currently in use on the unenhanced bearer of a particular
+    /// connection
+    pub fn get_mtu(&self, tcb_idx: TransportIndex) -> Option<usize> {
+        self.get_connection(tcb_idx).map(|x| x.bearer.get_mtu())
+    }
+
+    /// Notify a value of a characteristic (whose CCCD is managed by the
+    /// database of the given server) to every client of that server that has
+    /// subscribed to its notifications, on their unenhanced bearer. As per Core
+    /// Spec 5.3 Vol 3F 3.4.7.1, the value is truncated to the ATT_MTU-3 of
+    /// each connection (or to its max_notification_payload(), if the server
+    /// config enforces it), and to the MaxNotificationPayload of its peer.
+    ///
+    /// The notifications are sent concurrently on the executor, and are not
+    /// cancelled if the returned future is dropped. It resolves to the outcome
+    /// on each subscribed connection once they have all completed.
+    pub fn notify_all(
+        &self,
+        server_id: ServerId,
+        handle: AttHandle,
+        value: &[u8],
+    ) -> Result<impl Future<Output = HashMap<ConnectionId, Result<(), NotificationError>>>> {
+        let Some(database) = self.databases.get(&server_id) else {
+            bail!("server {server_id:?} not opened");
+        };
+        // the long reads of the previous value must not pick up the new one
+        database.on_value_changed(handle);
+        // connections with the same MTU share the truncated value
+        let mut truncated_values: HashMap<usize, Box<[u8]>> = HashMap::new();
+        let mut pending = vec![];
+        for (conn_id, connection) in &self.connections {
+            if conn_id.get_server_id() != server_id {
+                continue;
+            }
+            let subscribed = database.subscriptions(conn_id.get_tcb_idx()).iter().any(
+                |(subscribed_handle, configuration)| {
+                    *subscribed_handle == handle
+                        && configuration.contains(ClientConfiguration::NOTIFICATION)
+                },
+            );
+            if !subscribed {
+                continue;
+            }
+            // an MTU exchange in progress can only increase the MTU
+            let mtu = connection.bearer.get_mtu();
+            let max_len = connection.quirks.cap_notification_payload(
+                if database.config().enforce_notification_payload {
+                    recommended_notification_payload(mtu, connection.data_length)
+                } else {
+                    mtu - 3
+                },
+            );
+            let len = value.len().min(max_len);
+            let value = truncated_values.entry(len).or_insert_with(|| value[..len].into()).clone();
+            let notification = connection.bearer.as_ref().send_notification(
+                handle,
+                AttAttributeDataChild::RawData(value),
+                Priority::Normal,
+            );
+            let (tx, rx) = oneshot::channel();
+            self.executor
+                .spawn(Box::pin(async move {
+                    let _ = tx.send(notification.await);
+                }))
+                .detach();
+            pending.push((*conn_id, rx));
+        }
+        Ok(async move {
+            let mut results = HashMap::new();
+            for (conn_id, rx) in pending {
+                // the result is only lost if the executor dropped the task
+                let result = rx
+                    .await
+                    .unwrap_or(Err(NotificationError::SendError(SendError::ConnectionDropped)));
+                results.insert(conn_id, result);
+            }
+            results
+        })
+    }
+
+    /// Set the MTU the server offers when a client exchanges the MTU. This only
+    /// applies to subsequent connections.
+    pub fn set_server_rx_mtu(&mut self, mtu: usize) -> Result<()> {
+        if !(DEFAULT_ATT_MTU..=MAX_ATT_MTU).contains(&mtu) {
+            bail!("server RX MTU {mtu} must be between {DEFAULT_ATT_MTU} and {MAX_ATT_MTU}");
+        }
+        self.server_rx_mtu = mtu;
+        Ok(())
+    }
+
+    /// Set the time within which attribute reads and writes must complete
+    /// before the request is failed with UNLIKELY_ERROR. This only applies to
+    /// subsequent connections and EATT bearers.
+    pub fn set_request_timeout(&mut self, request_timeout: Duration) -> Result<()> {
+        if request_timeout.is_zero() {
+            bail!("request timeout must be nonzero");
+        }
+        self.request_timeout = request_timeout;
+        Ok(())
+    }
+
+    /// Set the configuration supplying the values exposed by the GAP service.
+    /// This only applies to subsequently opened servers.
+    pub fn set_gap_configuration(&mut self, gap_configuration: Rc<dyn GapConfiguration>) {
+        self.gap_configuration = gap_configuration;
+    }
+
+    /// Enable the Device Information Service, exposing the values from the
+    /// given provider, or disable it if None. This only applies to
+    /// subsequently opened servers, which read the values when they open.
+    pub fn set_device_information_provider(
+        &mut self,
+        device_information: Option<Rc<dyn DeviceInformationProvider>>,
+    ) {
+        self.device_information = device_information;
+    }
+
+    /// Set the AuthorizationProvider consulted when a client accesses an
+    /// attribute of the given server requiring authorization
+    pub fn set_authorization_provider(
+        &mut self,
+        server_id: ServerId,
+        provider: Rc<dyn AuthorizationProvider>,
+    ) -> Result<()> {
+        self.databases
+            .get(&server_id)
+            .ok_or_else(|| anyhow!("server {server_id:?} not opened"))?
+            .set_authorization_provider(provider);
+        Ok(())
+    }
+
+    /// Set the AccessInterceptor consulted on every read and write of the
+    /// attributes of the given server (e.g. BondedClientsOnly, to keep
+    /// unbonded peers out of all but the GAP and GATT services)
+    pub fn set_access_interceptor(
+        &mut self,
+        server_id: ServerId,
+        interceptor: Rc<dyn AccessInterceptor>,
+    ) -> Result<()> {
+        self.databases
+            .get(&server_id)
+            .ok_or_else(|| anyhow!("server {server_id:?} not opened"))?
+            .set_access_interceptor(interceptor);
+        Ok(())
+    }
+
+    /// Pass the reads and writes of the attributes of the given server in the
+    /// given range through the interceptor (e.g. a LoggingInterceptor), after
+    /// those with a lower or equal order
+    pub fn add_interceptor(
+        &mut self,
+        server_id: ServerId,
+        range: RangeInclusive<AttHandle>,
+        order: i32,
+        interceptor: Rc<dyn Interceptor>,
+    ) -> Result<()> {
+        self.databases
+            .get(&server_id)
+            .ok_or_else(|| anyhow!("server {server_id:?} not opened"))?
+            .add_interceptor(range, order, interceptor);
+        Ok(())
+    }
+
+    /// Remove an interceptor added with add_interceptor() from the given server
+    pub fn remove_interceptor(
+        &mut self,
+        server_id: ServerId,
+        interceptor: &Rc<dyn Interceptor>,
+    ) -> Result<()> {
+        self.databases
+            .get(&server_id)
+            .ok_or_else(|| anyhow!("server {server_id:?} not opened"))?
+            .remove_interceptor(interceptor)
+    }
+
+    /// Persist the handles of the services added with add_gatt_service() (or
+    /// by applications) on the given server to the given storage, so that they
+    /// get the same handles after a restart. This must be set before adding
+    /// any such services.
+    pub fn set_handle_assignment_storage(
+        &mut self,
+        server_id: ServerId,
+        storage: Rc<dyn HandleAssignmentStorage>,
+    ) -> Result<()> {
+        self.databases
+            .get(&server_id)
+            .ok_or_else(|| anyhow!("server {server_id:?} not opened"))?
+            .set_handle_assignment_storage(storage)
+    }
+
+    /// Persist what clients write to the user descriptions managed by the given
+    /// server (for the services added by applications) to the given storage,
+    /// so that they survive a restart. This must be set before adding any
+    /// such services.
+    pub fn set_user_description_storage(
+        &mut self,
+        server_id: ServerId,
+        storage: Rc<dyn UserDescriptionStorage>,
+    ) -> Result<()> {
+        self.databases
+            .get(&server_id)
+            .ok_or_else(|| anyhow!("server {server_id:?} not opened"))?
+            .set_user_description_storage(storage)
+    }
+
+    /// Set whether the server advertises support for EATT bearers, through the
+    /// Server Supported Features characteristic (unless EATT is disabled by
+    /// the GattServerConfig). This only applies to subsequently opened
+    /// servers.
+    pub fn set_eatt_supported(&mut self, eatt_supported: bool) {
+        self.eatt_supported = eatt_supported;
+    }
+
+    /// Set the configuration (e.g. the maximum attribute length, or the
+    /// optional features enabled) enforced by the server. This only applies to
+    /// subsequently opened servers, and the bearers opened on them.
+    pub fn set_config(&mut self, config: GattServerConfig) -> Result<()> {
+        config.validate()?;
+        self.config = config;
+        Ok(())
+    }
+
+    /// Set the IdentityResolver, which identifies the bonded peer behind each
+    /// LE connection as it is set up, and resolves the addresses passed to
+    /// on_le_bonded(). The state retained for a bonded peer is then keyed on
+    /// its identity address, rather than on an address that may rotate.
+    pub fn set_identity_resolver(&mut self, identity_resolver: Rc<dyn IdentityResolver>) {
+        self.identity_resolver = Some(identity_resolver);
+    }
+
+    /// Set the InteropRegistry holding the quirks of known peers, and the
+    /// PeerInfoProvider telling which peer is behind each connection. If the
+    /// provider does not know the address of an LE peer, its identity address
+    /// is taken from the IdentityResolver (if any). This only applies to
+    /// subsequent connections.
+    pub fn set_interop(
+        &mut self,
+        registry: Rc<InteropRegistry>,
+        peer_info_provider: Rc<dyn PeerInfoProvider>,
+    ) {
+        self.interop = Some((registry, peer_info_provider));
+    }
+
+    /// The quirks of the peer connecting on the given transport
+    fn quirks_for(&self, tcb_idx: TransportIndex, transport: Transport) -> Quirks {
+        let Some((registry, peer_info_provider)) = &self.interop else {
+            return Quirks::default();
+        };
+        let mut peer = peer_info_provider.peer_info(tcb_idx);
+        if peer.address.is_none() && transport == Transport::Le {
+            peer.address = self
+                .identity_resolver
+                .as_ref()
+                .and_then(|resolver| resolver.resolve_identity(tcb_idx));
+        }
+        let quirks = registry.quirks_for(&peer);
+        if quirks != Quirks::default() {
+            info!("working around {quirks:?} of the peer on {tcb_idx:?}");
+        }
+        quirks
+    }
+
+    /// Set the tracer passed every ATT PDU received or sent by the server. This
+    /// only applies to subsequent connections and EATT bearers.
+    pub fn set_tracer(&mut self, tracer: Rc<dyn AttTracer>) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Set the tracer following every ATT transaction processed by the server.
+    /// This only applies to subsequent connections and EATT bearers.
+    pub fn set_transaction_tracer(&mut self, tracer: Rc<dyn TransactionTracer>) {
+        self.transaction_tracer = Some(tracer);
+    }
+
+    /// Set the journal recording every access to an attribute of any server,
+    /// for security auditing. This only applies to subsequent connections and
+    /// EATT bearers.
+    pub fn set_access_journal(&mut self, journal: Rc<AccessJournal>) {
+        self.access_journal = Some(journal);
+    }
+
+    /// The access journal (if any), with the SecurityManager from which it
+    /// samples the security level of each access
+    fn journal(&self) -> Option<(Rc<AccessJournal>, Rc<dyn SecurityManager>)> {
+        Some((self.access_journal.clone()?, self.security_manager.clone()))
+    }
+
+    /// Set the TransmitBackpressure of the transport, from which notifications
+    /// and indications must then obtain credits before being sent. This only
+    /// applies to subsequent connections and EATT bearers.
+    pub fn set_backpressure(&mut self, backpressure: Rc<dyn TransmitBackpressure>) {
+        self.backpressure = Some(backpressure);
+    }
+
+    /// Set the Clock measuring the timeouts of transactions, indications and
+    /// security elevation (e.g. a VirtualClock, to test them deterministically).
+    /// This only applies to subsequent connections and EATT bearers.
+    pub fn set_clock(&mut self, clock: Rc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Set the OpcodePolicy deciding which ATT operations each connection may
+    /// perform (e.g. RestrictedPeers). This only applies to subsequent
+    /// connections and EATT bearers.
+    pub fn set_opcode_policy(&mut self, policy: Rc<dyn OpcodePolicy>) {
+        self.opcode_policy = Some(policy);
+    }
+
+    /// Set the Executor on which the background tasks of each bearer are
+    /// spawned (e.g. an adapter to the main loop of the host). This only
+    /// applies to subsequent connections and EATT bearers.
+    pub fn set_executor(&mut self, executor: Rc<dyn Executor>) {
+        self.executor = executor;
+    }
+
+    /// Get an EATT bearer for a particular connection
+    pub fn get_eatt_bearer(
+        &self,
+        tcb_idx: TransportIndex,
+        cid: EattCid,
+    ) -> Option<WeakBoxRef<'_, AttServerBearer<AttDatabaseImpl>>> {
+        self.get_connection(tcb_idx)?.eatt_bearers.get(&cid).map(|x| x.as_ref())
+    }
+
+    /// Get the ConnectionId of the connection on a given transport. A transport
+    /// is connected to at most one server.
+    pub fn get_connection_id(&self, tcb_idx: TransportIndex) -> Option<ConnectionId> {
+        self.connections.keys().find(|conn_id| conn_id.get_tcb_idx() == tcb_idx).copied()
+    }
+
+    /// Get the transport underlying a connection
+    pub fn get_connection_transport(&self, conn_id: ConnectionId) -> Option<Transport> {
+        self.connections.get(&conn_id).map(|connection| connection.transport)
+    }
+
+    /// Handle the controller reporting a new LE data length (the maximum LL
+    /// payload it transmits) on a link, e.g. after the Data Length Update
+    /// procedure. It applies to every connection over that link.
+    pub fn on_le_data_length_changed(
+        &mut self,
+        tcb_idx: TransportIndex,
+        tx_octets: usize,
+    ) -> Result<()> {
+        validate_data_length(tx_octets)?;
+        let mut found = false;
+        for (conn_id, connection) in &mut self.connections {
+            if conn_id.get_tcb_idx() == tcb_idx {
+                connection.data_length = tx_octets;
+                found = true;
+            }
+        }
+        if !found {
+            bail!("got data length change on {tcb_idx:?} but bearer does not exist");
+        }
+        Ok(())
+    }
+
+    /// The length of notified values that makes the best use of a connection,
+    /// given the MTU of its unenhanced bearer and the LE data length of its
+    /// link, so streaming profiles can size their values without guessing.
+    /// Longer values (up to ATT_MTU-3) may still be notified, at the cost of a
+    /// partially filled LL PDU, unless the peer has a MaxNotificationPayload.
+    pub fn max_notification_payload(&self, conn_id: ConnectionId) -> Option<usize> {
+        let connection = self.connections.get(&conn_id)?;
+        Some(connection.quirks.cap_notification_payload(recommended_notification_payload(
+            connection.bearer.get_mtu(),
+            connection.data_length,
+        )))
+    }
+
+    /// Sample the current state of a connection (across all of its bearers),
+    /// e.g. for connection-quality monitoring
+    pub fn connection_stats(&self, conn_id: ConnectionId) -> Option<ConnectionStats> {
+        let connection = self.connections.get(&conn_id)?;
+        let bearers = std::iter::once(&connection.bearer)
+            .chain(connection.eatt_bearers.values())
+            .collect::<Vec<_>>();
+        let mut stats = ConnectionStats {
+            mtu: connection.bearer.get_mtu(),
+            bearers: bearers.len(),
+            outstanding_indications: 0,
+            queued_notifications: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            last_activity: connection.bearer.last_activity(),
+        };
+        for bearer in bearers {
+            let metrics = bearer.metrics();
+            stats.outstanding_indications += bearer.outstanding_indications();
+            stats.queued_notifications += bearer.queued_notifications();
+            stats.bytes_sent += metrics.bytes_sent;
+            stats.bytes_received += metrics.bytes_received;
+            stats.last_activity = stats.last_activity.max(bearer.last_activity());
+        }
+        Some(stats)
+    }
+
+    /// Take a snapshot of the metrics of every connection (across all of its
+    /// bearers), e.g. for inclusion in a bugreport
+    pub fn dump(&self) -> MetricsSnapshot {
+        let mut connections = self
+            .connections
+            .iter()
+            .map(|(conn_id, connection)| {
+                let mut eatt_bearers = connection.eatt_bearers.iter().collect::<Vec<_>>();
+                eatt_bearers.sort_by_key(|(cid, _)| cid.0);
+                let bearers = std::iter::once(&connection.bearer)
+                    .chain(eatt_bearers.into_iter().map(|(_, bearer)| bearer));
+                let mut mtus = vec![];
+                let mut counters = BearerMetrics::default();
+                for bearer in bearers {
+                    mtus.push(bearer.get_mtu());
+                    counters.merge(&bearer.metrics());
+                }
+                ConnectionMetrics {
+                    conn_id: *conn_id,
+                    transport: connection.transport,
+                    mtus,
+                    counters,
+                }
+            })
+            .collect::<Vec<_>>();
+        connections.sort_by_key(|connection| connection.conn_id);
+        MetricsSnapshot { connections }
+    }
+
+    /// Print the state of the module in a human-readable form, for dumpsys
+    /// (and so for bugreports): the services of each open server, and every
+    /// live connection with its bearers, security level, subscriptions, and
+    /// pending transactions, followed by the state of the tracer and of the
+    /// access journal (if any)
+    pub fn debug_dump(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        let mut server_ids = self.databases.keys().copied().collect::<Vec<_>>();
+        server_ids.sort_by_key(|server_id| server_id.0);
+        writeln!(out, "GATT server: {} server(s) open", server_ids.len())?;
+        for server_id in server_ids {
+            let services = self.databases[&server_id].services();
+            writeln!(out, "  {server_id}: {} service(s)", services.len())?;
+            for (range, type_) in services {
+                writeln!(out, "    {}..={} {}", range.start(), range.end(), format_uuid(type_))?;
+            }
+        }
+
+        let mut conn_ids = self.connections.keys().copied().collect::<Vec<_>>();
+        conn_ids.sort();
+        writeln!(out, "{} connection(s)", conn_ids.len())?;
+        for conn_id in conn_ids {
+            let connection = &self.connections[&conn_id];
+            let tcb_idx = conn_id.get_tcb_idx();
+            writeln!(out, "  {conn_id} over {:?}", connection.transport)?;
+            writeln!(
+                out,
+                "    MTU {}, {} bearer(s), security level {:?}",
+                connection.bearer.get_mtu(),
+                1 + connection.eatt_bearers.len(),
+                self.security_manager.get_security_level(tcb_idx)
+            )?;
+            let subscriptions = connection
+                .database
+                .with(|database| database.map(|database| database.subscriptions(tcb_idx)))
+                .unwrap_or_default();
+            for (handle, configuration) in subscriptions {
+                writeln!(out, "    subscribed to {handle}: {configuration:?}")?;
+            }
+            let mut eatt_bearers = connection.eatt_bearers.iter().collect::<Vec<_>>();
+            eatt_bearers.sort_by_key(|(cid, _)| cid.0);
+            let bearers = std::iter::once(("unenhanced bearer".to_string(), &connection.bearer))
+                .chain(eatt_bearers.into_iter().map(|(cid, bearer)| (cid.to_string(), bearer)));
+            for (name, bearer) in bearers {
+                if let Some((opcode, elapsed)) = bearer.pending_request() {
+                    writeln!(out, "    pending {opcode:?} on {name} for {elapsed:?}")?;
+                }
+            }
+        }
+        if let Some(tracer) = &self.tracer {
+            tracer.debug_dump(out)?;
+        }
+        if let Some(journal) = &self.access_journal {
+            journal.debug_dump(out)?;
+        }
+        Ok(())
+    }
+
+    fn get_connection(&self, tcb_idx: TransportIndex) -> Option<&GattConnection> {
+        self.connections.get(&self.get_connection_id(tcb_idx)?)
+    }
+
+    fn get_connection_mut(&mut self, tcb_idx: TransportIndex) -> Option<&mut GattConnection> {
+        let conn_id = self.get_connection_id(tcb_idx)?;
+        self.connections.get_mut(&conn_id)
     }
 
     /// Get the IsolationManager to manage associations between servers + advertisers
@@ -177,3 +1280,13 @@ impl GattModule {
         self.isolation_manager.lock().unwrap()
     }
 }
+
+/// Formats a UUID in its 16-bit or 32-bit form if it has one, as dumpsys
+/// output is mostly read by people looking for assigned numbers
+fn format_uuid(uuid: Uuid) -> String {
+    match (uuid.to_u16(), uuid.to_u32()) {
+        (Some(uuid), _) => format!("{uuid:04x}"),
+        (None, Some(uuid)) => format!("{uuid:08x}"),
+        (None, None) => uuid.to_string(),
+    }
+}
@@ -0,0 +1,570 @@
+//! This module provides a journal of the most recent accesses to attributes,
+//! so that the integrator can audit which peers read or wrote sensitive
+//! characteristics, with which outcome, and how secure their link was at the
+//! time. Unlike the tracer, it retains each access (rather than each PDU), so
+//! it spans a much longer period for the same memory.
+//!
+//! Peers are identified by their identity address once bonded, which may be
+//! replaced by a keyed hash so the journal can be collected without exposing
+//! it.
+
+use std::{
+    cell::RefCell,
+    collections::{hash_map::RandomState, HashMap, VecDeque},
+    fmt,
+    hash::BuildHasher,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+
+use crate::{
+    core::address::AddressWithType,
+    gatt::{
+        ids::{AttHandle, BearerId, ConnectionId, EattCid, ServerId, TransportIndex},
+        security_manager::SecurityLevel,
+    },
+    packets::{
+        AttErrorCode, AttErrorResponseView, AttOpcode, AttPrepareWriteRequestView,
+        AttReadBlobRequestView, AttReadMultipleRequestView, AttReadMultipleVariableRequestView,
+        AttReadRequestView, AttSignedWriteCommandView, AttView, AttWriteCommandView,
+        AttWriteRequestView, Packet, ParseError,
+    },
+};
+
+use super::trace::{AttTraceEvent, Direction};
+
+/// The number of accesses retained by an AccessJournal by default
+pub const DEFAULT_JOURNAL_CAPACITY: usize = 256;
+
+/// How the journal records the identity of bonded peers
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AddressMode {
+    /// Record their identity address as is
+    #[default]
+    Plain,
+    /// Record a hash of their identity address, keyed with a secret drawn when
+    /// the journal is created. Accesses by the same peer can still be
+    /// correlated within the journal, but not with any other record of it.
+    Hashed,
+}
+
+/// The peer that accessed an attribute
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerIdentity {
+    /// The peer had not been identified as bonded
+    Unidentified,
+    /// The identity address of the bonded peer
+    Address(AddressWithType),
+    /// The keyed hash of the identity address of the bonded peer
+    Hashed(u64),
+}
+
+impl fmt::Display for PeerIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerIdentity::Unidentified => write!(f, "unidentified"),
+            PeerIdentity::Address(AddressWithType { address, address_type }) => {
+                for (i, byte) in address.iter().rev().enumerate() {
+                    if i > 0 {
+                        write!(f, ":")?;
+                    }
+                    write!(f, "{byte:02X}")?;
+                }
+                write!(f, " ({address_type:?})")
+            }
+            PeerIdentity::Hashed(hash) => write!(f, "#{hash:016x}"),
+        }
+    }
+}
+
+/// The outcome of an access
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessOutcome {
+    /// The server accepted the request
+    Succeeded,
+    /// The server rejected the request with the given error
+    Failed(AttErrorCode),
+    /// The access was a command, to which the server does not reply
+    Unacknowledged,
+}
+
+/// An access to an attribute retained by the AccessJournal
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccessRecord {
+    /// When the request or command was received
+    pub timestamp: SystemTime,
+    /// The connection on which the attribute was accessed
+    pub conn_id: ConnectionId,
+    /// The EATT bearer on which the attribute was accessed, or None for the
+    /// unenhanced bearer
+    pub cid: Option<EattCid>,
+    /// The peer that accessed the attribute
+    pub peer: PeerIdentity,
+    /// The attribute accessed
+    pub handle: AttHandle,
+    /// The opcode of the request or command
+    pub opcode: AttOpcode,
+    /// The outcome of the access
+    pub outcome: AccessOutcome,
+    /// The security level of the link when the request or command was received
+    pub security_level: SecurityLevel,
+}
+
+/// A request awaiting its reply on a bearer
+struct PendingAccess {
+    timestamp: SystemTime,
+    peer: PeerIdentity,
+    handles: Vec<AttHandle>,
+    opcode: AttOpcode,
+    security_level: SecurityLevel,
+}
+
+/// Retains the most recent accesses to attributes (i.e. those read or written
+/// by handle), up to a fixed capacity. It is passed every PDU on the bearers of
+/// the GattModule, as well as the identity of bonded peers.
+pub struct AccessJournal {
+    capacity: usize,
+    address_mode: AddressMode,
+    hash_key: RandomState,
+    peers: RefCell<HashMap<TransportIndex, AddressWithType>>,
+    pending: RefCell<HashMap<BearerId, PendingAccess>>,
+    records: RefCell<VecDeque<AccessRecord>>,
+}
+
+impl Default for AccessJournal {
+    fn default() -> Self {
+        Self::new(DEFAULT_JOURNAL_CAPACITY, AddressMode::default())
+    }
+}
+
+impl AccessJournal {
+    /// Constructor, retaining at most the given number of accesses
+    pub fn new(capacity: usize, address_mode: AddressMode) -> Self {
+        Self {
+            capacity,
+            address_mode,
+            hash_key: RandomState::new(),
+            peers: HashMap::new().into(),
+            pending: HashMap::new().into(),
+            records: VecDeque::with_capacity(capacity).into(),
+        }
+    }
+
+    /// The retained accesses, oldest first
+    pub fn records(&self) -> Vec<AccessRecord> {
+        self.records.borrow().iter().copied().collect()
+    }
+
+    /// The retained accesses to the given attribute of a server, oldest first
+    pub fn accesses_to(&self, server_id: ServerId, handle: AttHandle) -> Vec<AccessRecord> {
+        self.records
+            .borrow()
+            .iter()
+            .filter(|record| record.conn_id.get_server_id() == server_id && record.handle == handle)
+            .copied()
+            .collect()
+    }
+
+    /// The peer on the given transport has been identified as bonded
+    pub fn on_le_bonded(&self, tcb_idx: TransportIndex, peer: AddressWithType) {
+        self.peers.borrow_mut().insert(tcb_idx, peer);
+    }
+
+    /// The peer on the given transport has disconnected, so its requests will
+    /// never be answered, and its transport may be reused by another peer
+    pub fn on_le_disconnect(&self, tcb_idx: TransportIndex) {
+        self.peers.borrow_mut().remove(&tcb_idx);
+        self.pending.borrow_mut().retain(|bearer, _| bearer.tcb_idx != tcb_idx);
+    }
+
+    /// Record the access made by a PDU on a bearer (if any), given the security
+    /// level of its link
+    pub fn on_pdu(&self, event: &AttTraceEvent<'_>, security_level: SecurityLevel) {
+        let bearer = BearerId { tcb_idx: event.conn_id.get_tcb_idx(), cid: event.cid };
+        match event.direction {
+            Direction::Rx => {
+                let opcode = event.pdu.get_opcode();
+                let handles = match accessed_handles(event.pdu) {
+                    Ok(handles) if !handles.is_empty() => handles,
+                    Ok(_) => return,
+                    Err(err) => {
+                        // the server rejects it without accessing anything
+                        warn!("not journaling malformed {opcode:?}: {err:?}");
+                        return;
+                    }
+                };
+                let access = PendingAccess {
+                    timestamp: event.timestamp,
+                    peer: self.peer_identity(bearer.tcb_idx),
+                    handles,
+                    opcode,
+                    security_level,
+                };
+                if matches!(opcode, AttOpcode::WRITE_COMMAND | AttOpcode::SIGNED_WRITE_COMMAND) {
+                    self.record(event.conn_id, event.cid, access, AccessOutcome::Unacknowledged);
+                } else {
+                    self.pending.borrow_mut().insert(bearer, access);
+                }
+            }
+            Direction::Tx => {
+                let outcome = match event.pdu.get_opcode() {
+                    AttOpcode::ERROR_RESPONSE => match AttErrorResponseView::try_parse(event.pdu) {
+                        Ok(response) => AccessOutcome::Failed(response.get_error_code()),
+                        Err(_) => return,
+                    },
+                    AttOpcode::READ_RESPONSE
+                    | AttOpcode::READ_BLOB_RESPONSE
+                    | AttOpcode::READ_MULTIPLE_RESPONSE
+                    | AttOpcode::READ_MULTIPLE_VARIABLE_RESPONSE
+                    | AttOpcode::WRITE_RESPONSE
+                    | AttOpcode::PREPARE_WRITE_RESPONSE => AccessOutcome::Succeeded,
+                    // notifications and indications do not answer the client
+                    _ => return,
+                };
+                let Some(access) = self.pending.borrow_mut().remove(&bearer) else {
+                    return;
+                };
+                self.record(event.conn_id, event.cid, access, outcome);
+            }
+        }
+    }
+
+    /// Print the retained accesses as part of the debug dump of the server
+    pub fn debug_dump(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        let records = self.records.borrow();
+        writeln!(out, "last {} attribute access(es):", records.len())?;
+        for record in records.iter() {
+            let timestamp = record.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+            let bearer = BearerId { tcb_idx: record.conn_id.get_tcb_idx(), cid: record.cid };
+            writeln!(
+                out,
+                "  {}.{:03} {} ({bearer}) by {} at {:?}: {:?} of {} -> {:?}",
+                timestamp.as_secs(),
+                timestamp.subsec_millis(),
+                record.conn_id,
+                record.peer,
+                record.security_level,
+                record.opcode,
+                record.handle,
+                record.outcome
+            )?;
+        }
+        Ok(())
+    }
+
+    fn peer_identity(&self, tcb_idx: TransportIndex) -> PeerIdentity {
+        let Some(peer) = self.peers.borrow().get(&tcb_idx).copied() else {
+            return PeerIdentity::Unidentified;
+        };
+        match self.address_mode {
+            AddressMode::Plain => PeerIdentity::Address(peer),
+            AddressMode::Hashed => PeerIdentity::Hashed(self.hash_key.hash_one(peer)),
+        }
+    }
+
+    fn record(
+        &self,
+        conn_id: ConnectionId,
+        cid: Option<EattCid>,
+        access: PendingAccess,
+        outcome: AccessOutcome,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut records = self.records.borrow_mut();
+        for handle in access.handles {
+            if records.len() == self.capacity {
+                records.pop_front();
+            }
+            records.push_back(AccessRecord {
+                timestamp: access.timestamp,
+                conn_id,
+                cid,
+                peer: access.peer,
+                handle,
+                opcode: access.opcode,
+                outcome,
+                security_level: access.security_level,
+            });
+        }
+    }
+}
+
+/// The attributes accessed by a request or command received from the client.
+/// Discovery and queued writes are not attributed to any attribute, since the
+/// latter were already journaled when they were prepared.
+pub fn accessed_handles(pdu: AttView<'_>) -> Result<Vec<AttHandle>, ParseError> {
+    Ok(match pdu.get_opcode() {
+        AttOpcode::READ_REQUEST => {
+            vec![AttReadRequestView::try_parse(pdu)?.get_attribute_handle().into()]
+        }
+        AttOpcode::READ_BLOB_REQUEST => {
+            vec![AttReadBlobRequestView::try_parse(pdu)?.get_attribute_handle().into()]
+        }
+        AttOpcode::READ_MULTIPLE_REQUEST => AttReadMultipleRequestView::try_parse(pdu)?
+            .get_children_iter()
+            .map(AttHandle::from)
+            .collect(),
+        AttOpcode::READ_MULTIPLE_VARIABLE_REQUEST => {
+            AttReadMultipleVariableRequestView::try_parse(pdu)?
+                .get_children_iter()
+                .map(AttHandle::from)
+                .collect()
+        }
+        AttOpcode::WRITE_REQUEST => vec![AttWriteRequestView::try_parse(pdu)?.get_handle().into()],
+        AttOpcode::PREPARE_WRITE_REQUEST => {
+            vec![AttPrepareWriteRequestView::try_parse(pdu)?.get_handle().into()]
+        }
+        AttOpcode::WRITE_COMMAND => vec![AttWriteCommandView::try_parse(pdu)?.get_handle().into()],
+        AttOpcode::SIGNED_WRITE_COMMAND => {
+            vec![AttSignedWriteCommandView::try_parse(pdu)?.get_handle().into()]
+        }
+        _ => vec![],
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        core::address::AddressType,
+        packets::{
+            AttAttributeDataChild, AttChild, AttErrorResponseBuilder,
+            AttHandleValueNotificationBuilder, AttReadMultipleRequestBuilder,
+            AttReadMultipleResponseBuilder, AttReadRequestBuilder, AttReadResponseBuilder,
+            AttWriteCommandBuilder,
+        },
+        utils::packet::{build_att_data, build_att_view_or_crash},
+    };
+
+    use super::*;
+
+    const TCB_IDX: TransportIndex = TransportIndex(1);
+    const CONN_ID: ConnectionId = ConnectionId::new(TCB_IDX, ServerId(2));
+    const HANDLE: AttHandle = AttHandle(3);
+    const ANOTHER_HANDLE: AttHandle = AttHandle(5);
+    const PEER: AddressWithType =
+        AddressWithType { address: [1, 2, 3, 4, 5, 6], address_type: AddressType::Public };
+
+    fn pdu(journal: &AccessJournal, direction: Direction, pdu: impl Into<AttChild>) {
+        let pdu = build_att_view_or_crash(pdu);
+        journal.on_pdu(
+            &AttTraceEvent {
+                conn_id: CONN_ID,
+                cid: None,
+                direction,
+                timestamp: UNIX_EPOCH,
+                pdu: pdu.view(),
+            },
+            SecurityLevel::Encrypted,
+        );
+    }
+
+    fn read_request(handle: AttHandle) -> AttReadRequestBuilder {
+        AttReadRequestBuilder { attribute_handle: handle.into() }
+    }
+
+    fn read_response() -> AttReadResponseBuilder {
+        AttReadResponseBuilder { value: build_att_data(AttAttributeDataChild::RawData([1].into())) }
+    }
+
+    fn write_command(handle: AttHandle) -> AttWriteCommandBuilder {
+        AttWriteCommandBuilder {
+            handle: handle.into(),
+            value: build_att_data(AttAttributeDataChild::RawData([1].into())),
+        }
+    }
+
+    #[test]
+    fn test_records_answered_request() {
+        // arrange
+        let journal = AccessJournal::default();
+
+        // act
+        pdu(&journal, Direction::Rx, read_request(HANDLE));
+        pdu(&journal, Direction::Tx, read_response());
+
+        // assert
+        assert_eq!(
+            journal.records(),
+            vec![AccessRecord {
+                timestamp: UNIX_EPOCH,
+                conn_id: CONN_ID,
+                cid: None,
+                peer: PeerIdentity::Unidentified,
+                handle: HANDLE,
+                opcode: AttOpcode::READ_REQUEST,
+                outcome: AccessOutcome::Succeeded,
+                security_level: SecurityLevel::Encrypted,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_records_rejected_request() {
+        // arrange
+        let journal = AccessJournal::default();
+
+        // act
+        pdu(&journal, Direction::Rx, read_request(HANDLE));
+        pdu(
+            &journal,
+            Direction::Tx,
+            AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::READ_REQUEST,
+                handle_in_error: HANDLE.into(),
+                error_code: AttErrorCode::INSUFFICIENT_AUTHENTICATION,
+            },
+        );
+
+        // assert
+        assert_eq!(
+            journal.records()[0].outcome,
+            AccessOutcome::Failed(AttErrorCode::INSUFFICIENT_AUTHENTICATION)
+        );
+    }
+
+    #[test]
+    fn test_notification_does_not_answer_request() {
+        // arrange
+        let journal = AccessJournal::default();
+        pdu(&journal, Direction::Rx, read_request(HANDLE));
+
+        // act
+        pdu(
+            &journal,
+            Direction::Tx,
+            AttHandleValueNotificationBuilder {
+                handle: ANOTHER_HANDLE.into(),
+                value: build_att_data(AttAttributeDataChild::RawData([1].into())),
+            },
+        );
+
+        // assert
+        assert_eq!(journal.records(), vec![]);
+    }
+
+    #[test]
+    fn test_command_recorded_immediately() {
+        let journal = AccessJournal::default();
+
+        pdu(&journal, Direction::Rx, write_command(HANDLE));
+
+        assert_eq!(journal.records()[0].outcome, AccessOutcome::Unacknowledged);
+    }
+
+    #[test]
+    fn test_read_multiple_recorded_per_handle() {
+        // arrange
+        let journal = AccessJournal::default();
+
+        // act
+        pdu(
+            &journal,
+            Direction::Rx,
+            AttReadMultipleRequestBuilder {
+                children: vec![HANDLE.into(), ANOTHER_HANDLE.into()].into(),
+            },
+        );
+        pdu(
+            &journal,
+            Direction::Tx,
+            AttReadMultipleResponseBuilder {
+                value: build_att_data(AttAttributeDataChild::RawData([1].into())),
+            },
+        );
+
+        // assert
+        assert_eq!(journal.accesses_to(ServerId(2), HANDLE).len(), 1);
+        assert_eq!(journal.accesses_to(ServerId(2), ANOTHER_HANDLE).len(), 1);
+        assert_eq!(journal.accesses_to(ServerId(3), HANDLE), vec![]);
+    }
+
+    #[test]
+    fn test_bonded_peer_identified() {
+        let journal = AccessJournal::default();
+        journal.on_le_bonded(TCB_IDX, PEER);
+
+        pdu(&journal, Direction::Rx, read_request(HANDLE));
+        pdu(&journal, Direction::Tx, read_response());
+
+        assert_eq!(journal.records()[0].peer, PeerIdentity::Address(PEER));
+    }
+
+    #[test]
+    fn test_hashed_peer_identity() {
+        // arrange
+        let journal = AccessJournal::new(DEFAULT_JOURNAL_CAPACITY, AddressMode::Hashed);
+        journal.on_le_bonded(TCB_IDX, PEER);
+
+        // act: the peer makes two accesses
+        for _ in 0..2 {
+            pdu(&journal, Direction::Rx, write_command(HANDLE));
+        }
+
+        // assert: they are correlated, without exposing the address
+        let records = journal.records();
+        assert!(matches!(records[0].peer, PeerIdentity::Hashed(_)));
+        assert_eq!(records[0].peer, records[1].peer);
+        let mut dump = String::new();
+        journal.debug_dump(&mut dump).unwrap();
+        assert!(!dump.contains("06:05:04:03:02:01"), "{dump}");
+    }
+
+    #[test]
+    fn test_disconnect_forgets_peer_and_pending_request() {
+        // arrange
+        let journal = AccessJournal::default();
+        journal.on_le_bonded(TCB_IDX, PEER);
+        pdu(&journal, Direction::Rx, read_request(HANDLE));
+
+        // act: another peer reuses the transport
+        journal.on_le_disconnect(TCB_IDX);
+        pdu(&journal, Direction::Tx, read_response());
+        pdu(&journal, Direction::Rx, read_request(HANDLE));
+        pdu(&journal, Direction::Tx, read_response());
+
+        // assert
+        let records = journal.records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].peer, PeerIdentity::Unidentified);
+    }
+
+    #[test]
+    fn test_oldest_access_evicted_at_capacity() {
+        let journal = AccessJournal::new(1, AddressMode::Plain);
+
+        pdu(&journal, Direction::Rx, read_request(HANDLE));
+        pdu(&journal, Direction::Tx, read_response());
+        pdu(&journal, Direction::Rx, read_request(ANOTHER_HANDLE));
+        pdu(&journal, Direction::Tx, read_response());
+
+        let records = journal.records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].handle, ANOTHER_HANDLE);
+    }
+
+    #[test]
+    fn test_debug_dump() {
+        // arrange
+        let journal = AccessJournal::default();
+        journal.on_le_bonded(TCB_IDX, PEER);
+        pdu(&journal, Direction::Rx, read_request(HANDLE));
+        pdu(&journal, Direction::Tx, read_response());
+
+        // act
+        let mut dump = String::new();
+        journal.debug_dump(&mut dump).unwrap();
+
+        // assert
+        assert!(dump.contains("last 1 attribute access(es)"), "{dump}");
+        assert!(
+            dump.contains(&format!(
+                "0.000 tcb1/server2 (tcb1/unenhanced) by 06:05:04:03:02:01 (Public) at Encrypted: {:?} of {HANDLE} -> Succeeded",
+                AttOpcode::READ_REQUEST
+            )),
+            "{dump}"
+        );
+    }
+}
@@ -0,0 +1,141 @@
+//! A GattDatabase may have an AccessInterceptor, which decides whether each
+//! read or write of an attribute may proceed at all, before its own
+//! permissions are checked. This lets a policy span the whole database (e.g.
+//! restricting unbonded peers to the GAP and GATT services) without flagging
+//! every attribute. Declarations are never intercepted, so that clients can
+//! still discover all the services.
+
+use std::collections::HashSet;
+
+use log::warn;
+
+use crate::{
+    core::uuid::Uuid,
+    gatt::ids::{AttHandle, TransportIndex},
+    packets::AttErrorCode,
+};
+
+use super::{
+    authorization::AttributeAccess,
+    services::{gap::GAP_SERVICE_UUID, gatt::GATT_SERVICE_UUID},
+};
+
+/// An access to an attribute, as presented to an AccessInterceptor
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccessContext {
+    /// The transport of the client
+    pub tcb_idx: TransportIndex,
+    /// Whether the client has been identified as a bonded peer on this
+    /// connection
+    pub bonded: bool,
+    /// The type of the service containing the attribute
+    pub service_type: Uuid,
+    /// The attribute being accessed
+    pub handle: AttHandle,
+    /// Whether the attribute is being read or written
+    pub access: AttributeAccess,
+}
+
+/// Decides whether a client may access an attribute, across the whole
+/// database
+pub trait AccessInterceptor {
+    /// Check whether the access may proceed. Otherwise, returns the error with
+    /// which to reject it (writes without response are dropped instead).
+    fn check_access(&self, context: &AccessContext) -> Result<(), AttErrorCode>;
+}
+
+/// Rejects the reads and writes of unbonded clients with
+/// INSUFFICIENT_AUTHENTICATION (so that they may pair, and then retry), except
+/// to the GAP and GATT services and to those explicitly allowed
+#[derive(Clone, Debug)]
+pub struct BondedClientsOnly {
+    allowed_services: HashSet<Uuid>,
+}
+
+impl Default for BondedClientsOnly {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BondedClientsOnly {
+    /// Constructor, only allowing unbonded clients to access the GAP and GATT
+    /// services
+    pub fn new() -> Self {
+        Self { allowed_services: HashSet::from([GAP_SERVICE_UUID, GATT_SERVICE_UUID]) }
+    }
+
+    /// Also allow unbonded clients to access services of the given type
+    pub fn allow_service(mut self, service_type: Uuid) -> Self {
+        self.allowed_services.insert(service_type);
+        self
+    }
+}
+
+impl AccessInterceptor for BondedClientsOnly {
+    fn check_access(&self, context: &AccessContext) -> Result<(), AttErrorCode> {
+        if context.bonded || self.allowed_services.contains(&context.service_type) {
+            return Ok(());
+        }
+        warn!(
+            "rejecting {:?} of {} in service {} from unbonded client on {}",
+            context.access, context.handle, context.service_type, context.tcb_idx
+        );
+        Err(AttErrorCode::INSUFFICIENT_AUTHENTICATION)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TCB_IDX: TransportIndex = TransportIndex(1);
+    const HANDLE: AttHandle = AttHandle(3);
+    const SERVICE_TYPE: Uuid = Uuid::new(0x1234);
+
+    fn context(bonded: bool, service_type: Uuid) -> AccessContext {
+        AccessContext {
+            tcb_idx: TCB_IDX,
+            bonded,
+            service_type,
+            handle: HANDLE,
+            access: AttributeAccess::Read,
+        }
+    }
+
+    #[test]
+    fn test_unbonded_client_rejected() {
+        let policy = BondedClientsOnly::new();
+
+        assert_eq!(
+            policy.check_access(&context(false, SERVICE_TYPE)),
+            Err(AttErrorCode::INSUFFICIENT_AUTHENTICATION)
+        );
+    }
+
+    #[test]
+    fn test_bonded_client_allowed() {
+        let policy = BondedClientsOnly::new();
+
+        assert_eq!(policy.check_access(&context(true, SERVICE_TYPE)), Ok(()));
+    }
+
+    #[test]
+    fn test_gap_and_gatt_always_allowed() {
+        let policy = BondedClientsOnly::new();
+
+        assert_eq!(policy.check_access(&context(false, GAP_SERVICE_UUID)), Ok(()));
+        assert_eq!(policy.check_access(&context(false, GATT_SERVICE_UUID)), Ok(()));
+    }
+
+    #[test]
+    fn test_allow_listed_service() {
+        let policy = BondedClientsOnly::new().allow_service(SERVICE_TYPE);
+
+        assert_eq!(policy.check_access(&context(false, SERVICE_TYPE)), Ok(()));
+        assert_eq!(
+            policy.check_access(&context(false, Uuid::new(0x5678))),
+            Err(AttErrorCode::INSUFFICIENT_AUTHENTICATION)
+        );
+    }
+}
@@ -0,0 +1,250 @@
+//! Lets several applications share a single GATT server. Each registers a
+//! datastore, backing all the services it then adds, so reads and writes of
+//! its attributes are only ever routed to it. When it unregisters (e.g. since
+//! it died), all of its services are removed at once.
+
+use std::{collections::HashMap, rc::Rc};
+
+use anyhow::{anyhow, bail, Result};
+use log::info;
+
+use crate::gatt::{
+    callbacks::RawGattDatastore,
+    ids::{AppId, AttHandle},
+};
+
+use super::gatt_database::{GattDatabase, ServiceBuilder, ServiceToken};
+
+struct App {
+    datastore: Rc<dyn RawGattDatastore>,
+    services: Vec<ServiceToken>,
+}
+
+/// The applications registered on a GattDatabase, and the services each of
+/// them owns
+#[derive(Default)]
+pub struct AppRegistry {
+    apps: HashMap<AppId, App>,
+}
+
+impl AppRegistry {
+    /// Constructor
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Register an application, whose services will be backed by the given
+    /// datastore
+    pub fn register_app(
+        &mut self,
+        app_id: AppId,
+        datastore: Rc<dyn RawGattDatastore>,
+    ) -> Result<()> {
+        if self.apps.contains_key(&app_id) {
+            bail!("{app_id} is already registered");
+        }
+        info!("registering {app_id}");
+        self.apps.insert(app_id, App { datastore, services: vec![] });
+        Ok(())
+    }
+
+    /// Add a service owned by a registered application to the database.
+    /// Returns the handle of the service, with which it can later be removed.
+    pub fn add_service(
+        &mut self,
+        database: &GattDatabase,
+        app_id: AppId,
+        service: ServiceBuilder,
+    ) -> Result<AttHandle> {
+        let app = self.apps.get_mut(&app_id).ok_or_else(|| anyhow!("{app_id} not registered"))?;
+        let token = database.add_service(service, app.datastore.clone())?;
+        let handle = token.handle();
+        app.services.push(token);
+        Ok(handle)
+    }
+
+    /// Remove a service added by an application. Fails if the service is
+    /// owned by another application.
+    pub fn remove_service(
+        &mut self,
+        database: &GattDatabase,
+        app_id: AppId,
+        service_handle: AttHandle,
+    ) -> Result<()> {
+        let app = self.apps.get_mut(&app_id).ok_or_else(|| anyhow!("{app_id} not registered"))?;
+        let Some(index) = app.services.iter().position(|token| token.handle() == service_handle)
+        else {
+            bail!("{app_id} does not own a service at {service_handle}");
+        };
+        database.remove_service(app.services.remove(index))
+    }
+
+    /// Unregister an application, removing all of its services from the
+    /// database at once
+    pub fn unregister_app(&mut self, database: &GattDatabase, app_id: AppId) -> Result<()> {
+        let app = self.apps.remove(&app_id).ok_or_else(|| anyhow!("{app_id} not registered"))?;
+        info!("unregistering {app_id}, removing {} service(s)", app.services.len());
+        database.remove_services(app.services)
+    }
+
+    /// The application owning the service at the given handle, if any
+    pub fn owner(&self, service_handle: AttHandle) -> Option<AppId> {
+        self.apps.iter().find_map(|(app_id, app)| {
+            app.services.iter().any(|token| token.handle() == service_handle).then_some(*app_id)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::mpsc::error::TryRecvError;
+
+    use crate::{
+        core::{shared_box::SharedBox, uuid::Uuid},
+        gatt::{
+            ids::TransportIndex,
+            mocks::{
+                mock_database_callbacks::{MockCallbackEvents, MockCallbacks},
+                mock_datastore::{MockDatastore, MockDatastoreEvents},
+            },
+            server::{
+                att_database::AttDatabase,
+                gatt_database::{AttPermissions, CharacteristicBuilder},
+            },
+        },
+        utils::task::block_on_locally,
+    };
+
+    use super::*;
+
+    const APP: AppId = AppId(1);
+    const ANOTHER_APP: AppId = AppId(2);
+    const TCB_IDX: TransportIndex = TransportIndex(1);
+    const SERVICE_TYPE: Uuid = Uuid::new(0x1234);
+    const CHARACTERISTIC_TYPE: Uuid = Uuid::new(0x5678);
+
+    fn service() -> ServiceBuilder {
+        ServiceBuilder::new(SERVICE_TYPE).characteristic(CharacteristicBuilder::new(
+            CHARACTERISTIC_TYPE,
+            AttPermissions::READABLE,
+        ))
+    }
+
+    fn value_handle(service_handle: AttHandle) -> AttHandle {
+        AttHandle(service_handle.0 + 2)
+    }
+
+    #[test]
+    fn test_reads_routed_to_owning_app() {
+        block_on_locally(async {
+            // arrange: two apps, each with a service
+            let database = SharedBox::new(GattDatabase::new());
+            let mut apps = AppRegistry::new();
+            let (datastore, mut data_rx) = MockDatastore::new();
+            let (another_datastore, mut another_data_rx) = MockDatastore::new();
+            apps.register_app(APP, Rc::new(datastore)).unwrap();
+            apps.register_app(ANOTHER_APP, Rc::new(another_datastore)).unwrap();
+            apps.add_service(&database, APP, service()).unwrap();
+            let service_handle = apps.add_service(&database, ANOTHER_APP, service()).unwrap();
+
+            // act: read the characteristic of the second app
+            let att_db = database.get_att_database(TCB_IDX);
+            let pending_read = tokio::task::spawn_local(async move {
+                att_db.read_attribute(value_handle(service_handle)).await
+            });
+            let MockDatastoreEvents::Read(_, handle, _, resp) =
+                another_data_rx.recv().await.unwrap()
+            else {
+                unreachable!()
+            };
+            resp.send(Ok(vec![1, 2])).unwrap();
+
+            // assert: only the owner of the service saw the read
+            assert_eq!(handle, value_handle(service_handle));
+            assert_eq!(pending_read.await.unwrap(), Ok(vec![1, 2].into()));
+            assert_eq!(data_rx.try_recv().unwrap_err(), TryRecvError::Empty);
+        });
+    }
+
+    #[test]
+    fn test_unregister_removes_all_services_at_once() {
+        // arrange
+        let database = GattDatabase::new();
+        let mut apps = AppRegistry::new();
+        let (datastore, _data_rx) = MockDatastore::new();
+        let (another_datastore, _another_data_rx) = MockDatastore::new();
+        apps.register_app(APP, Rc::new(datastore)).unwrap();
+        apps.register_app(ANOTHER_APP, Rc::new(another_datastore)).unwrap();
+        let first = apps.add_service(&database, APP, service()).unwrap();
+        let other = apps.add_service(&database, ANOTHER_APP, service()).unwrap();
+        let second = apps.add_service(&database, APP, service()).unwrap();
+        let (callbacks, mut callbacks_rx) = MockCallbacks::new();
+        database.register_listener(Rc::new(callbacks));
+
+        // act
+        apps.unregister_app(&database, APP).unwrap();
+
+        // assert: a single change was reported, and only the other app's
+        // service remains
+        let Ok(MockCallbackEvents::OnServiceChange(range)) = callbacks_rx.try_recv() else {
+            unreachable!()
+        };
+        assert_eq!(range, first..=value_handle(second));
+        assert!(callbacks_rx.try_recv().is_err());
+        assert_eq!(
+            database.services().into_iter().map(|(range, _)| *range.start()).collect::<Vec<_>>(),
+            vec![other]
+        );
+        assert_eq!(apps.owner(other), Some(ANOTHER_APP));
+        assert_eq!(apps.owner(first), None);
+    }
+
+    #[test]
+    fn test_cannot_remove_service_of_another_app() {
+        let database = GattDatabase::new();
+        let mut apps = AppRegistry::new();
+        let (datastore, _data_rx) = MockDatastore::new();
+        let (another_datastore, _another_data_rx) = MockDatastore::new();
+        apps.register_app(APP, Rc::new(datastore)).unwrap();
+        apps.register_app(ANOTHER_APP, Rc::new(another_datastore)).unwrap();
+        let service_handle = apps.add_service(&database, APP, service()).unwrap();
+
+        let res = apps.remove_service(&database, ANOTHER_APP, service_handle);
+
+        assert!(res.is_err());
+        assert_eq!(database.services().len(), 1);
+    }
+
+    #[test]
+    fn test_remove_service() {
+        let database = GattDatabase::new();
+        let mut apps = AppRegistry::new();
+        let (datastore, _data_rx) = MockDatastore::new();
+        apps.register_app(APP, Rc::new(datastore)).unwrap();
+        let service_handle = apps.add_service(&database, APP, service()).unwrap();
+
+        apps.remove_service(&database, APP, service_handle).unwrap();
+
+        assert!(database.services().is_empty());
+        assert_eq!(apps.owner(service_handle), None);
+    }
+
+    #[test]
+    fn test_duplicate_registration() {
+        let mut apps = AppRegistry::new();
+        let (datastore, _data_rx) = MockDatastore::new();
+        let datastore = Rc::new(datastore);
+        apps.register_app(APP, datastore.clone()).unwrap();
+
+        assert!(apps.register_app(APP, datastore).is_err());
+    }
+
+    #[test]
+    fn test_unregistered_app() {
+        let database = GattDatabase::new();
+        let mut apps = AppRegistry::new();
+
+        assert!(apps.add_service(&database, APP, service()).is_err());
+        assert!(apps.unregister_app(&database, APP).is_err());
+    }
+}
@@ -1,12 +1,19 @@
 use async_trait::async_trait;
+use std::{ops::Deref, rc::Rc};
+
 use bitflags::bitflags;
 
 use crate::{
     core::uuid::Uuid,
-    gatt::ids::AttHandle,
+    gatt::{ids::AttHandle, security_manager::SecurityLevel},
     packets::{AttErrorCode, AttHandleBuilder, AttHandleView},
 };
 
+use super::{
+    att_error::AttError, client_configuration::ClientConfiguration, config::GattServerConfig,
+    robust_caching::ClientSupportedFeatures,
+};
+
 impl From<AttHandleView<'_>> for AttHandle {
     fn from(value: AttHandleView) -> Self {
         AttHandle(value.get_handle())
@@ -19,6 +26,9 @@ impl From<AttHandle> for AttHandleBuilder {
     }
 }
 
+/// The maximum length of an attribute value (Core Spec 5.3 Vol 3F 3.2.9)
+pub const MAX_ATTRIBUTE_VALUE_LEN: usize = 512;
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct AttAttribute {
     pub handle: AttHandle,
@@ -30,18 +40,29 @@ bitflags! {
     /// The attribute properties supported by the current GATT server implementation
     /// Unimplemented properties will default to false.
     ///
-    /// These values are from Core Spec 5.3 Vol 3G 3.3.1.1 Characteristic Properties,
-    /// and also match what Android uses in JNI.
+    /// The low octet is from Core Spec 5.3 Vol 3G 3.3.1.1 Characteristic Properties,
+    /// and also matches what Android uses in JNI. The high octet holds the
+    /// security requirements for accessing the attribute value (Core Spec 5.3
+    /// Vol 3F 3.2.5), which apply to both reads and writes.
     #[derive(Copy, Clone, Debug, PartialEq, Eq)]
-    pub struct AttPermissions : u8 {
+    pub struct AttPermissions : u16 {
         /// Attribute can be read using READ_REQ
         const READABLE = 0x02;
         /// Attribute can be written to using WRITE_CMD
         const WRITABLE_WITHOUT_RESPONSE = 0x04;
         /// Attribute can be written to using WRITE_REQ
         const WRITABLE_WITH_RESPONSE = 0x08;
+        /// Attribute value may be sent using notifications
+        const NOTIFY = 0x10;
         /// Attribute value may be sent using indications
         const INDICATE = 0x20;
+        /// Attribute value may only be accessed over an encrypted link
+        const ENCRYPTION_REQUIRED = 0x100;
+        /// Attribute value may only be accessed over a link encrypted with an
+        /// authenticated key
+        const AUTHENTICATION_REQUIRED = 0x200;
+        /// Attribute value may only be accessed by an authorized client
+        const AUTHORIZATION_REQUIRED = 0x400;
     }
 }
 
@@ -58,19 +79,162 @@ impl AttPermissions {
     pub fn writable_without_response(&self) -> bool {
         self.contains(AttPermissions::WRITABLE_WITHOUT_RESPONSE)
     }
+    /// Attribute value may be sent using notifications
+    pub fn notify(&self) -> bool {
+        self.contains(AttPermissions::NOTIFY)
+    }
     /// Attribute value may be sent using indications
     pub fn indicate(&self) -> bool {
         self.contains(AttPermissions::INDICATE)
     }
+    /// Check that the attribute value may be accessed by a client with the
+    /// given link security, as per Core Spec 5.3 Vol 3C 10.3
+    pub fn check_security(
+        &self,
+        security_level: SecurityLevel,
+        authorized: bool,
+    ) -> Result<(), AttErrorCode> {
+        if self.contains(AttPermissions::AUTHENTICATION_REQUIRED)
+            && security_level < SecurityLevel::Authenticated
+        {
+            return Err(AttErrorCode::INSUFFICIENT_AUTHENTICATION);
+        }
+        if self.contains(AttPermissions::ENCRYPTION_REQUIRED)
+            && security_level < SecurityLevel::Encrypted
+        {
+            return Err(AttErrorCode::INSUFFICIENT_ENCRYPTION);
+        }
+        if self.contains(AttPermissions::AUTHORIZATION_REQUIRED) && !authorized {
+            return Err(AttErrorCode::INSUFFICIENT_AUTHORIZATION);
+        }
+        Ok(())
+    }
+}
+
+/// The value of an attribute. It is reference-counted, so that a value read
+/// repeatedly (e.g. a long value read in chunks using ATT_READ_BLOB_REQ) is
+/// shared rather than copied out of the database on every read. Only the part
+/// of the value that fits in a response is ever copied.
+#[derive(Clone, Default)]
+pub struct AttAttributeValue {
+    data: Rc<[u8]>,
+    start: usize,
+    end: usize,
+}
+
+impl AttAttributeValue {
+    /// Truncate the value to at most len bytes, without copying it
+    pub fn truncated(self, len: usize) -> Self {
+        Self { end: self.end.min(self.start + len), ..self }
+    }
+
+    /// Skip the first offset bytes of the value, without copying it. Fails
+    /// with INVALID_OFFSET if the offset exceeds the length of the value.
+    pub fn skipped(self, offset: usize) -> Result<Self, AttErrorCode> {
+        if offset > self.len() {
+            return Err(AttErrorCode::INVALID_OFFSET);
+        }
+        Ok(Self { start: self.start + offset, ..self })
+    }
+}
+
+impl Deref for AttAttributeValue {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data[self.start..self.end]
+    }
+}
+
+impl From<Rc<[u8]>> for AttAttributeValue {
+    fn from(data: Rc<[u8]>) -> Self {
+        Self { start: 0, end: data.len(), data }
+    }
+}
+
+impl From<Vec<u8>> for AttAttributeValue {
+    fn from(value: Vec<u8>) -> Self {
+        Rc::<[u8]>::from(value).into()
+    }
+}
+
+impl From<&[u8]> for AttAttributeValue {
+    fn from(value: &[u8]) -> Self {
+        Rc::<[u8]>::from(value).into()
+    }
+}
+
+impl std::fmt::Debug for AttAttributeValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("AttAttributeValue").field(&&**self).finish()
+    }
+}
+
+impl PartialEq for AttAttributeValue {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for AttAttributeValue {}
+
+impl PartialEq<Vec<u8>> for AttAttributeValue {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        **self == **other
+    }
 }
 
 #[async_trait(?Send)]
 pub trait AttDatabase {
-    /// Read an attribute by handle
-    async fn read_attribute(&self, handle: AttHandle) -> Result<Vec<u8>, AttErrorCode>;
+    /// Read an attribute by handle. Errors carry the context in which they
+    /// arose, which is logged (but not sent to the client) when the error
+    /// response is built.
+    async fn read_attribute(&self, handle: AttHandle) -> Result<AttAttributeValue, AttError>;
 
-    /// Write to an attribute by handle
-    async fn write_attribute(&self, handle: AttHandle, data: &[u8]) -> Result<(), AttErrorCode>;
+    /// Read the part of an attribute value from the given offset onwards (e.g.
+    /// for an ATT_READ_BLOB_REQ).
+    ///
+    /// Fails with INVALID_OFFSET if the offset exceeds the length of the
+    /// value. Databases whose values are produced on demand (e.g. by the upper
+    /// layer) should override this, so that only the requested part of the
+    /// value is produced, and the offset is validated by whoever owns it.
+    async fn read_attribute_at(
+        &self,
+        handle: AttHandle,
+        offset: u32,
+    ) -> Result<AttAttributeValue, AttError> {
+        self.read_attribute(handle)
+            .await?
+            .skipped(offset as usize)
+            .map_err(|code| AttError::from(code).for_handle(handle).at_offset(offset))
+    }
+
+    /// Write to an attribute by handle, replacing its value from the given
+    /// offset onwards.
+    ///
+    /// Fails with INVALID_OFFSET if the offset exceeds the current length of
+    /// the value, INVALID_ATTRIBUTE_VALUE_LENGTH if the resulting value would
+    /// be too long, WRITE_NOT_PERMITTED if the attribute is not writable, or
+    /// any error (e.g. INSUFFICIENT_AUTHORIZATION) returned by its owner.
+    async fn write_attribute(
+        &self,
+        handle: AttHandle,
+        offset: u32,
+        data: &[u8],
+    ) -> Result<(), AttError>;
+
+    /// Check, without writing anything, whether a write_attribute() with the
+    /// same arguments would be rejected before reaching the owner of the value
+    /// (e.g. since the attribute is not writable, the link is not secure
+    /// enough, or the offset or length is invalid). Used to validate a queue of
+    /// prepared writes before committing any of them.
+    ///
+    /// The owner of the value may still reject the write itself, as may the
+    /// AuthorizationProvider, which is only consulted once the value is
+    /// written.
+    fn check_write(&self, _handle: AttHandle, _offset: u32, _data: &[u8]) -> Result<(), AttError> {
+        Ok(())
+    }
 
     /// Write to an attribute by handle
     fn write_no_response_attribute(&self, handle: AttHandle, data: &[u8]);
@@ -80,6 +244,79 @@ pub trait AttDatabase {
     /// Expected to return them in sorted order.
     fn list_attributes(&self) -> Vec<AttAttribute>;
 
+    /// List the attributes with handles in the given (inclusive) range, and
+    /// of the given type if a filter is supplied, in sorted order.
+    ///
+    /// Discovery requests only ever cover part of the database, so
+    /// implementations that can look up a range without visiting every
+    /// attribute should override this.
+    fn attributes_in_range(
+        &self,
+        start: AttHandle,
+        end: AttHandle,
+        type_filter: Option<Uuid>,
+    ) -> Vec<AttAttribute> {
+        self.list_attributes()
+            .into_iter()
+            .filter(|attr| (start..=end).contains(&attr.handle))
+            .filter(|attr| type_filter.map(|type_| attr.type_ == type_).unwrap_or(true))
+            .collect()
+    }
+
+    /// Get the client configuration of the characteristic at the given value
+    /// handle, if its CCCD is managed by this database.
+    ///
+    /// Returns None if the characteristic has no CCCD, or if its CCCD is
+    /// managed by the upper layer.
+    fn client_configuration(&self, _handle: AttHandle) -> Option<ClientConfiguration> {
+        None
+    }
+
+    /// Whether the notifications of the characteristic at the given value
+    /// handle are coalesced: a queued notification that is yet to be sent
+    /// takes the value of the next one, rather than both being sent
+    fn coalesces_notifications(&self, _handle: AttHandle) -> bool {
+        false
+    }
+
+    /// Whether the client is change-aware, as per the robust caching rules of
+    /// Core Spec 5.3 Vol 3G 2.5.2.1. Requests from a change-unaware client
+    /// must be rejected with DATABASE_OUT_OF_SYNC.
+    fn is_change_aware(&self) -> bool {
+        true
+    }
+
+    /// Consider the client change-aware from now on
+    fn mark_change_aware(&self) {}
+
+    /// The features the client enabled by writing the Client Supported
+    /// Features characteristic (e.g. whether it accepts Multiple Handle Value
+    /// Notifications)
+    fn client_supported_features(&self) -> ClientSupportedFeatures {
+        ClientSupportedFeatures::empty()
+    }
+
+    /// The limits enforced on the attributes of this database (e.g. their
+    /// maximum length)
+    fn server_config(&self) -> GattServerConfig {
+        GattServerConfig::default()
+    }
+
+    /// A token that changes whenever the value of the attribute does, if the
+    /// database keeps track of it. A long read compares it across its
+    /// segments, so that it is not served a value stitched from two versions
+    /// (see config::TornReadPolicy).
+    fn value_version(&self, _handle: AttHandle) -> Option<u64> {
+        None
+    }
+
+    /// Handle the writes of the client to managed CCCDs from now on as a
+    /// burst (see GattServerConfig::client_configuration_burst_window)
+    fn begin_client_configuration_burst(&self) {}
+
+    /// End the burst of writes to managed CCCDs in progress, if any
+    fn end_client_configuration_burst(&self) {}
+
     /// Produce an implementation of StableAttDatabase
     fn snapshot(&self) -> SnapshottedAttDatabase<'_>
     where
@@ -108,12 +345,29 @@ pub struct SnapshottedAttDatabase<'a> {
 
 #[async_trait(?Send)]
 impl AttDatabase for SnapshottedAttDatabase<'_> {
-    async fn read_attribute(&self, handle: AttHandle) -> Result<Vec<u8>, AttErrorCode> {
+    async fn read_attribute(&self, handle: AttHandle) -> Result<AttAttributeValue, AttError> {
         self.backing.read_attribute(handle).await
     }
 
-    async fn write_attribute(&self, handle: AttHandle, data: &[u8]) -> Result<(), AttErrorCode> {
-        self.backing.write_attribute(handle, data).await
+    async fn read_attribute_at(
+        &self,
+        handle: AttHandle,
+        offset: u32,
+    ) -> Result<AttAttributeValue, AttError> {
+        self.backing.read_attribute_at(handle, offset).await
+    }
+
+    async fn write_attribute(
+        &self,
+        handle: AttHandle,
+        offset: u32,
+        data: &[u8],
+    ) -> Result<(), AttError> {
+        self.backing.write_attribute(handle, offset, data).await
+    }
+
+    fn check_write(&self, handle: AttHandle, offset: u32, data: &[u8]) -> Result<(), AttError> {
+        self.backing.check_write(handle, offset, data)
     }
 
     fn write_no_response_attribute(&self, handle: AttHandle, data: &[u8]) {
@@ -123,6 +377,235 @@ impl AttDatabase for SnapshottedAttDatabase<'_> {
     fn list_attributes(&self) -> Vec<AttAttribute> {
         self.attributes.clone()
     }
+
+    fn attributes_in_range(
+        &self,
+        start: AttHandle,
+        end: AttHandle,
+        type_filter: Option<Uuid>,
+    ) -> Vec<AttAttribute> {
+        // the snapshot is sorted, so we can skip straight to the range
+        let first = self.attributes.partition_point(|attr| attr.handle < start);
+        self.attributes[first..]
+            .iter()
+            .take_while(|attr| attr.handle <= end)
+            .filter(|attr| type_filter.map(|type_| attr.type_ == type_).unwrap_or(true))
+            .copied()
+            .collect()
+    }
+
+    fn client_configuration(&self, handle: AttHandle) -> Option<ClientConfiguration> {
+        self.backing.client_configuration(handle)
+    }
+
+    fn coalesces_notifications(&self, handle: AttHandle) -> bool {
+        self.backing.coalesces_notifications(handle)
+    }
+
+    fn is_change_aware(&self) -> bool {
+        self.backing.is_change_aware()
+    }
+
+    fn mark_change_aware(&self) {
+        self.backing.mark_change_aware()
+    }
+
+    fn client_supported_features(&self) -> ClientSupportedFeatures {
+        self.backing.client_supported_features()
+    }
+
+    fn server_config(&self) -> GattServerConfig {
+        self.backing.server_config()
+    }
+
+    fn value_version(&self, handle: AttHandle) -> Option<u64> {
+        self.backing.value_version(handle)
+    }
+
+    fn begin_client_configuration_burst(&self) {
+        self.backing.begin_client_configuration_burst()
+    }
+
+    fn end_client_configuration_burst(&self) {
+        self.backing.end_client_configuration_burst()
+    }
 }
 
 impl StableAttDatabase for SnapshottedAttDatabase<'_> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::gatt::server::test::test_att_db::TestAttDatabase;
+
+    const UUID: Uuid = Uuid::new(1234);
+    const ANOTHER_UUID: Uuid = Uuid::new(2345);
+
+    fn make_db() -> TestAttDatabase {
+        TestAttDatabase::new(
+            [(1, UUID), (2, ANOTHER_UUID), (3, UUID), (5, UUID), (6, ANOTHER_UUID)]
+                .into_iter()
+                .map(|(handle, type_)| {
+                    (
+                        AttAttribute {
+                            handle: AttHandle(handle),
+                            type_,
+                            permissions: AttPermissions::READABLE,
+                        },
+                        vec![],
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    fn handles(attrs: Vec<AttAttribute>) -> Vec<u16> {
+        attrs.into_iter().map(|attr| attr.handle.0).collect()
+    }
+
+    #[test]
+    fn test_no_security_required() {
+        let permissions = AttPermissions::READABLE;
+
+        assert_eq!(permissions.check_security(SecurityLevel::NoSecurity, false), Ok(()));
+    }
+
+    #[test]
+    fn test_encryption_required() {
+        let permissions = AttPermissions::READABLE | AttPermissions::ENCRYPTION_REQUIRED;
+
+        assert_eq!(
+            permissions.check_security(SecurityLevel::NoSecurity, false),
+            Err(AttErrorCode::INSUFFICIENT_ENCRYPTION)
+        );
+        assert_eq!(permissions.check_security(SecurityLevel::Encrypted, false), Ok(()));
+        assert_eq!(permissions.check_security(SecurityLevel::Authenticated, false), Ok(()));
+    }
+
+    #[test]
+    fn test_authentication_required() {
+        let permissions = AttPermissions::READABLE | AttPermissions::AUTHENTICATION_REQUIRED;
+
+        assert_eq!(
+            permissions.check_security(SecurityLevel::NoSecurity, false),
+            Err(AttErrorCode::INSUFFICIENT_AUTHENTICATION)
+        );
+        assert_eq!(
+            permissions.check_security(SecurityLevel::Encrypted, false),
+            Err(AttErrorCode::INSUFFICIENT_AUTHENTICATION)
+        );
+        assert_eq!(permissions.check_security(SecurityLevel::Authenticated, false), Ok(()));
+    }
+
+    #[test]
+    fn test_authorization_required() {
+        let permissions = AttPermissions::READABLE | AttPermissions::AUTHORIZATION_REQUIRED;
+
+        assert_eq!(
+            permissions.check_security(SecurityLevel::Authenticated, false),
+            Err(AttErrorCode::INSUFFICIENT_AUTHORIZATION)
+        );
+        assert_eq!(permissions.check_security(SecurityLevel::NoSecurity, true), Ok(()));
+    }
+
+    #[test]
+    fn test_authentication_checked_before_authorization() {
+        let permissions =
+            AttPermissions::AUTHENTICATION_REQUIRED | AttPermissions::AUTHORIZATION_REQUIRED;
+
+        assert_eq!(
+            permissions.check_security(SecurityLevel::NoSecurity, false),
+            Err(AttErrorCode::INSUFFICIENT_AUTHENTICATION)
+        );
+    }
+
+    #[test]
+    fn test_truncated_value_shares_data() {
+        let value = AttAttributeValue::from(vec![1, 2, 3, 4]);
+
+        let truncated = value.clone().truncated(2);
+
+        assert_eq!(truncated, vec![1, 2]);
+        assert_eq!(truncated.as_ptr(), value.as_ptr());
+    }
+
+    #[test]
+    fn test_truncated_value_not_extended() {
+        let value = AttAttributeValue::from(vec![1, 2]);
+
+        let truncated = value.truncated(2).truncated(5);
+
+        assert_eq!(truncated, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_skipped_value_shares_data() {
+        let value = AttAttributeValue::from(vec![1, 2, 3, 4]);
+
+        let skipped = value.clone().skipped(1).unwrap().truncated(2);
+
+        assert_eq!(skipped, vec![2, 3]);
+        assert_eq!(skipped.as_ptr(), value[1..].as_ptr());
+    }
+
+    #[test]
+    fn test_skipped_to_end() {
+        let value = AttAttributeValue::from(vec![1, 2]);
+
+        assert_eq!(value.clone().skipped(2), Ok(vec![].into()));
+        assert_eq!(value.skipped(3), Err(AttErrorCode::INVALID_OFFSET));
+    }
+
+    #[test]
+    fn test_default_read_attribute_at() {
+        let db = TestAttDatabase::new(vec![(
+            AttAttribute {
+                handle: AttHandle(1),
+                type_: UUID,
+                permissions: AttPermissions::READABLE,
+            },
+            vec![1, 2, 3],
+        )]);
+
+        let tail = tokio_test::block_on(db.read_attribute_at(AttHandle(1), 1));
+        let past_end = tokio_test::block_on(db.read_attribute_at(AttHandle(1), 4));
+
+        assert_eq!(tail, Ok(vec![2, 3].into()));
+        assert_eq!(past_end, Err(AttErrorCode::INVALID_OFFSET.into()));
+    }
+
+    #[test]
+    fn test_attributes_in_range() {
+        // the mutable database falls back to the default, listing every attribute
+        let db = make_db().into_mutable();
+
+        let attrs = db.attributes_in_range(AttHandle(2), AttHandle(5), None);
+
+        assert_eq!(handles(attrs), vec![2, 3, 5]);
+    }
+
+    #[test]
+    fn test_attributes_in_range_with_type_filter() {
+        let db = make_db().into_mutable();
+
+        let attrs = db.attributes_in_range(AttHandle(2), AttHandle(6), Some(UUID));
+
+        assert_eq!(handles(attrs), vec![3, 5]);
+    }
+
+    #[test]
+    fn test_snapshot_attributes_in_range() {
+        let db = make_db();
+        let snapshot = db.snapshot();
+
+        for (start, end) in [(1, 6), (2, 4), (4, 4), (7, 0xFFFF)] {
+            for type_filter in [None, Some(UUID), Some(ANOTHER_UUID)] {
+                assert_eq!(
+                    snapshot.attributes_in_range(AttHandle(start), AttHandle(end), type_filter),
+                    db.attributes_in_range(AttHandle(start), AttHandle(end), type_filter)
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,233 @@
+//! The errors returned by an AttDatabase. A client only ever sees the bare
+//! AttErrorCode, which rarely says why its request was rejected, so within
+//! the server each error also carries the layer that rejected the access, and
+//! what it was rejecting. The context is dropped when the error response is
+//! built (see AttError::report()), after logging it.
+
+use std::fmt;
+
+use log::info;
+
+use crate::{
+    gatt::{ids::AttHandle, security_manager::SecurityLevel},
+    packets::{AttErrorCode, AttOpcode},
+};
+
+use super::att_database::AttPermissions;
+
+/// The layer of the server that rejected an access
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ErrorLayer {
+    /// The database itself (e.g. the attribute does not exist, or does not
+    /// support the access)
+    Database,
+    /// The AccessInterceptor of the database
+    AccessInterceptor,
+    /// The security requirements of the attribute
+    Security,
+    /// The AuthorizationProvider of the database
+    Authorization,
+    /// The WriteValidator of the characteristic
+    WriteValidator,
+    /// One of the Interceptors of the attribute
+    Interceptor,
+    /// The datastore owning the value (i.e. the upper layer)
+    Datastore,
+}
+
+impl fmt::Display for ErrorLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ErrorLayer::Database => "database",
+            ErrorLayer::AccessInterceptor => "access interceptor",
+            ErrorLayer::Security => "security requirements",
+            ErrorLayer::Authorization => "authorization provider",
+            ErrorLayer::WriteValidator => "write validator",
+            ErrorLayer::Interceptor => "interceptor",
+            ErrorLayer::Datastore => "datastore",
+        })
+    }
+}
+
+/// A security requirement of an attribute (Core Spec 5.3 Vol 3F 3.2.5) that
+/// the client did not meet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnmetRequirement {
+    /// The permission of the attribute setting the requirement (e.g.
+    /// ENCRYPTION_REQUIRED)
+    pub required: AttPermissions,
+    /// The security level of the link at the time
+    pub security_level: SecurityLevel,
+}
+
+impl UnmetRequirement {
+    /// The requirement behind an error returned by
+    /// AttPermissions::check_security(), if it is one of its errors
+    pub fn from_security_error(code: AttErrorCode, security_level: SecurityLevel) -> Option<Self> {
+        let required = match code {
+            AttErrorCode::INSUFFICIENT_AUTHENTICATION => AttPermissions::AUTHENTICATION_REQUIRED,
+            AttErrorCode::INSUFFICIENT_ENCRYPTION => AttPermissions::ENCRYPTION_REQUIRED,
+            AttErrorCode::INSUFFICIENT_AUTHORIZATION => AttPermissions::AUTHORIZATION_REQUIRED,
+            _ => return None,
+        };
+        Some(Self { required, security_level })
+    }
+}
+
+/// An error code, with the context in which it arose.
+///
+/// Errors compare equal if their codes do, since that is all the client can
+/// tell apart. The context is only for diagnosis.
+#[derive(Clone, Copy, Debug)]
+pub struct AttError {
+    /// The code sent to the client
+    pub code: AttErrorCode,
+    /// The layer that rejected the access
+    pub layer: ErrorLayer,
+    /// The attribute accessed, if known
+    pub handle: Option<AttHandle>,
+    /// The offset accessed, if any
+    pub offset: Option<u32>,
+    /// The security requirement that was not met, if that is why the access
+    /// was rejected
+    pub unmet_requirement: Option<UnmetRequirement>,
+}
+
+impl AttError {
+    /// Constructor, for an error raised by the given layer
+    pub fn new(code: AttErrorCode, layer: ErrorLayer) -> Self {
+        Self { code, layer, handle: None, offset: None, unmet_requirement: None }
+    }
+
+    /// Record the attribute that was accessed
+    pub fn for_handle(self, handle: AttHandle) -> Self {
+        Self { handle: Some(handle), ..self }
+    }
+
+    /// Record the offset that was accessed
+    pub fn at_offset(self, offset: u32) -> Self {
+        Self { offset: Some(offset), ..self }
+    }
+
+    /// Record the security requirement that was not met
+    pub fn with_unmet_requirement(self, requirement: UnmetRequirement) -> Self {
+        Self { unmet_requirement: Some(requirement), ..self }
+    }
+
+    /// Convert the error into the code sent to the client in response to a
+    /// request with the given opcode, logging the context that is lost
+    pub fn report(self, opcode: AttOpcode) -> AttErrorCode {
+        info!("rejecting {opcode:?}: {self}");
+        self.code
+    }
+}
+
+impl fmt::Display for AttError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} from the {}", self.code, self.layer)?;
+        if let Some(handle) = self.handle {
+            write!(f, " on {handle:?}")?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {offset}")?;
+        }
+        if let Some(UnmetRequirement { required, security_level }) = self.unmet_requirement {
+            write!(f, ", which requires {required:?} (the link is at {security_level:?})")?;
+        }
+        Ok(())
+    }
+}
+
+impl From<AttErrorCode> for AttError {
+    /// An error raised by the database, without further context
+    fn from(code: AttErrorCode) -> Self {
+        Self::new(code, ErrorLayer::Database)
+    }
+}
+
+impl From<AttError> for AttErrorCode {
+    fn from(error: AttError) -> Self {
+        error.code
+    }
+}
+
+impl PartialEq for AttError {
+    fn eq(&self, other: &Self) -> bool {
+        self.code == other.code
+    }
+}
+
+impl Eq for AttError {}
+
+impl PartialEq<AttErrorCode> for AttError {
+    fn eq(&self, other: &AttErrorCode) -> bool {
+        self.code == *other
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_display_with_context() {
+        let error = AttError::new(AttErrorCode::INSUFFICIENT_ENCRYPTION, ErrorLayer::Security)
+            .for_handle(AttHandle(3))
+            .at_offset(4)
+            .with_unmet_requirement(UnmetRequirement {
+                required: AttPermissions::ENCRYPTION_REQUIRED,
+                security_level: SecurityLevel::NoSecurity,
+            });
+
+        assert_eq!(
+            error.to_string(),
+            "INSUFFICIENT_ENCRYPTION from the security requirements on AttHandle(3) at offset 4, \
+             which requires AttPermissions(ENCRYPTION_REQUIRED) (the link is at NoSecurity)"
+        );
+    }
+
+    #[test]
+    fn test_display_without_context() {
+        let error = AttError::from(AttErrorCode::INVALID_HANDLE);
+
+        assert_eq!(error.to_string(), "INVALID_HANDLE from the database");
+    }
+
+    #[test]
+    fn test_compares_by_code() {
+        let error = AttError::new(AttErrorCode::UNLIKELY_ERROR, ErrorLayer::Datastore)
+            .for_handle(AttHandle(3));
+
+        assert_eq!(error, AttError::from(AttErrorCode::UNLIKELY_ERROR));
+        assert_eq!(error, AttErrorCode::UNLIKELY_ERROR);
+        assert_ne!(error, AttErrorCode::INVALID_HANDLE);
+    }
+
+    #[test]
+    fn test_report_returns_code() {
+        let error = AttError::new(AttErrorCode::VALUE_NOT_ALLOWED, ErrorLayer::WriteValidator);
+
+        assert_eq!(error.report(AttOpcode::WRITE_REQUEST), AttErrorCode::VALUE_NOT_ALLOWED);
+    }
+
+    #[test]
+    fn test_unmet_requirement_from_security_error() {
+        assert_eq!(
+            UnmetRequirement::from_security_error(
+                AttErrorCode::INSUFFICIENT_AUTHENTICATION,
+                SecurityLevel::Encrypted
+            ),
+            Some(UnmetRequirement {
+                required: AttPermissions::AUTHENTICATION_REQUIRED,
+                security_level: SecurityLevel::Encrypted
+            })
+        );
+        assert_eq!(
+            UnmetRequirement::from_security_error(
+                AttErrorCode::INVALID_HANDLE,
+                SecurityLevel::Encrypted
+            ),
+            None
+        );
+    }
+}
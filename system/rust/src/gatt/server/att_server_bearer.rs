@@ -1,12 +1,20 @@
 //! This module handles an individual connection on the ATT fixed channel.
 //! It handles ATT transactions and unacknowledged operations, backed by an
 //! AttDatabase (that may in turn be backed by an upper-layer protocol)
-
-use std::{cell::Cell, future::Future};
+//!
+//! The decisions are taken by the sans-IO AttServerCore (see the
+//! att_server_core module). This module carries out the Actions it returns,
+//! on the tokio runtime.
+
+use std::{
+    cell::{Cell, RefCell},
+    future::Future,
+    rc::Rc,
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
 use log::{error, trace, warn};
-use tokio::task::spawn_local;
 
 use crate::{
     core::{
@@ -14,30 +22,66 @@ use crate::{
         shared_mutex::SharedMutex,
     },
     gatt::{
-        ids::AttHandle,
+        channel::TransmitBackpressure,
+        ids::{AttHandle, BearerId, TransportIndex},
         mtu::{AttMtu, MtuEvent},
-        opcode_types::{classify_opcode, OperationType},
     },
     packets::{
         AttAttributeDataChild, AttBuilder, AttChild, AttErrorCode, AttErrorResponseBuilder,
-        AttView, Packet, SerializeError,
+        AttOpcode, AttView, OwnedAttView, OwnedPacket, Serializable, SerializeError,
+    },
+    utils::{
+        clock::{timeout, timeout_at, Clock, TokioClock},
+        executor::{Executor, TaskHandle, TokioExecutor},
+        packet::HACK_child_to_opcode,
     },
-    utils::{owned_handle::OwnedHandle, packet::HACK_child_to_opcode},
 };
 
 use super::{
+    access_journal::accessed_handles,
     att_database::AttDatabase,
+    att_server_core::{Action, AttServerCore},
     command_handler::AttCommandHandler,
     indication_handler::{ConfirmationWatcher, IndicationError, IndicationHandler},
+    interop::Quirks,
+    metrics::BearerMetrics,
+    mtu_audit::audit_outgoing_pdu,
+    notification_handler::{send_batch, NotificationError, NotificationHandler, Priority},
+    opcode_policy::OpcodePolicy,
+    pdu_decoder::{decode_pdu, DecodedPdu},
     request_handler::AttRequestHandler,
+    security_elevation::SecurityElevation,
+    signature_verifier::SignatureVerifier,
+    trace::{Direction, SpanPhase},
 };
 
+type TransactionTimeoutHandler = Box<dyn Fn(AttOpcode)>;
+type EventHandler = Rc<dyn Fn(BearerEvent)>;
+type PduHandler = Box<dyn Fn(Direction, AttView<'_>)>;
+type SpanHandler = Rc<dyn Fn(u64, AttOpcode, SpanPhase, Instant)>;
+
 enum AttRequestState<T: AttDatabase> {
     Idle(AttRequestHandler<T>),
-    Pending { _task: OwnedHandle<()> },
+    Pending { _task: TaskHandle },
     Replacing,
 }
 
+/// Counts an indication as outstanding until it completes, or is cancelled
+struct OutstandingIndication(Rc<Cell<usize>>);
+
+impl OutstandingIndication {
+    fn new(outstanding: Rc<Cell<usize>>) -> Self {
+        outstanding.set(outstanding.get() + 1);
+        Self(outstanding)
+    }
+}
+
+impl Drop for OutstandingIndication {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() - 1);
+    }
+}
+
 /// The errors that can occur while trying to send a packet
 #[derive(Debug)]
 pub enum SendError {
@@ -47,69 +91,384 @@ pub enum SendError {
     ConnectionDropped,
 }
 
-/// This represents a single ATT bearer (currently, always the unenhanced fixed
-/// channel on LE) The AttRequestState ensures that only one transaction can
-/// take place at a time
+/// An event on a bearer, of interest to the upper layers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BearerEvent {
+    /// The MTU was exchanged, and this is the MTU now in use
+    MtuChanged(usize),
+    /// The client confirmed an indication of the given characteristic
+    IndicationConfirmed(AttHandle),
+    /// The connection became congested (since the notification queue filled
+    /// up past its high watermark, or the transport withheld credits for
+    /// longer than the TRANSMIT_CONGESTION_THRESHOLD), or is no longer
+    Congestion(bool),
+    /// The client exceeded its ErrorRateLimit, having made this many failed
+    /// requests within the last second, so its requests are now held back
+    ErrorRateExceeded(usize),
+}
+
+/// If the transport withholds the credits needed to send a notification or
+/// indication for longer than this, the connection is reported as congested
+/// until it catches up.
+pub const TRANSMIT_CONGESTION_THRESHOLD: Duration = Duration::from_millis(500);
+
+/// The default time within which the AttDatabase must produce the reply to a
+/// request. It is less than the 30s ATT transaction timeout, after which the
+/// client would disconnect (5.3 3F 3.3.3).
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// This represents a single ATT bearer (either the unenhanced fixed channel on
+/// LE, or an EATT bearer). The AttServerCore ensures that only one transaction
+/// can take place at a time on each bearer, but transactions on different
+/// bearers proceed independently.
 pub struct AttServerBearer<T: AttDatabase> {
     // general
     send_packet: Box<dyn Fn(AttBuilder) -> Result<(), SerializeError>>,
-    mtu: AttMtu,
+    core: RefCell<AttServerCore>,
+    enhanced: bool,
 
     // request state
     curr_request: Cell<AttRequestState<T>>,
+    request_timeout: Cell<Duration>,
+    on_transaction_timeout: RefCell<Option<TransactionTimeoutHandler>>,
+    on_event: RefCell<Option<EventHandler>>,
+    on_pdu: RefCell<Option<PduHandler>>,
+    on_span: RefCell<Option<SpanHandler>>,
+    next_span: Cell<u64>,
+    backpressure: RefCell<Option<(Rc<dyn TransmitBackpressure>, BearerId)>>,
+    security_elevation: Rc<SecurityElevation>,
+    clock: RefCell<Rc<dyn Clock>>,
+    executor: RefCell<Rc<dyn Executor>>,
+    quirks: Cell<Quirks>,
+    client_configuration_burst_window: Option<Duration>,
+    client_configuration_burst_timer: RefCell<Option<TaskHandle>>,
 
     // indication state
     indication_handler: SharedMutex<IndicationHandler<T>>,
     pending_confirmation: ConfirmationWatcher,
+    outstanding_indications: Rc<Cell<usize>>,
+
+    // notification state
+    notification_handler: NotificationHandler<T>,
 
     // command handler (across all bearers)
     command_handler: AttCommandHandler<T>,
+
+    // metrics
+    opened_at: Cell<Instant>,
+    last_activity: Cell<Instant>,
+    last_rx: Cell<Instant>,
 }
 
 impl<T: AttDatabase + Clone + 'static> AttServerBearer<T> {
     /// Constructor, wrapping an ATT channel (for outgoing packets) and an
-    /// AttDatabase
+    /// AttDatabase. Signed commands are verified using the supplied
+    /// SignatureVerifier, requests rejected due to insufficient link security
+    /// are parked by the SecurityElevation until the link is upgraded, and the
+    /// server_rx_mtu is offered to the client if it exchanges the MTU.
     pub fn new(
         db: T,
+        signature_verifier: SignatureVerifier,
+        security_elevation: SecurityElevation,
+        server_rx_mtu: usize,
+        send_packet: impl Fn(AttBuilder) -> Result<(), SerializeError> + 'static,
+    ) -> Self {
+        Self::new_with_mtu(
+            db,
+            signature_verifier,
+            security_elevation,
+            AttMtu::new(),
+            server_rx_mtu,
+            false,
+            send_packet,
+        )
+    }
+
+    /// Constructor for an EATT bearer, whose MTU was configured when its L2CAP
+    /// channel was established
+    pub fn new_enhanced(
+        db: T,
+        signature_verifier: SignatureVerifier,
+        security_elevation: SecurityElevation,
+        mtu: usize,
+        send_packet: impl Fn(AttBuilder) -> Result<(), SerializeError> + 'static,
+    ) -> Self {
+        Self::new_with_mtu(
+            db,
+            signature_verifier,
+            security_elevation,
+            AttMtu::new_configured(mtu),
+            mtu,
+            true,
+            send_packet,
+        )
+    }
+
+    /// Constructor for the unenhanced bearer on the ATT channel over BR/EDR,
+    /// whose MTU was configured when the channel was established
+    #[cfg(not(feature = "le_only"))]
+    pub fn new_br_edr(
+        db: T,
+        signature_verifier: SignatureVerifier,
+        security_elevation: SecurityElevation,
+        mtu: usize,
+        send_packet: impl Fn(AttBuilder) -> Result<(), SerializeError> + 'static,
+    ) -> Self {
+        Self::new_with_mtu(
+            db,
+            signature_verifier,
+            security_elevation,
+            AttMtu::new_configured(mtu),
+            mtu,
+            false,
+            send_packet,
+        )
+    }
+
+    fn new_with_mtu(
+        db: T,
+        signature_verifier: SignatureVerifier,
+        security_elevation: SecurityElevation,
+        mtu: AttMtu,
+        server_rx_mtu: usize,
+        enhanced: bool,
         send_packet: impl Fn(AttBuilder) -> Result<(), SerializeError> + 'static,
     ) -> Self {
         let (indication_handler, pending_confirmation) = IndicationHandler::new(db.clone());
+        let mut core = AttServerCore::new(mtu, server_rx_mtu);
+        core.set_duplicate_request_policy(db.server_config().duplicate_requests);
+        core.set_error_rate_limit(db.server_config().error_rate_limit);
         Self {
             send_packet: Box::new(send_packet),
-            mtu: AttMtu::new(),
+            core: core.into(),
+            enhanced,
 
             curr_request: AttRequestState::Idle(AttRequestHandler::new(db.clone())).into(),
+            request_timeout: DEFAULT_REQUEST_TIMEOUT.into(),
+            on_transaction_timeout: None.into(),
+            on_event: None.into(),
+            on_pdu: None.into(),
+            on_span: None.into(),
+            next_span: 0.into(),
+            backpressure: None.into(),
+            security_elevation: Rc::new(security_elevation),
+            clock: RefCell::new(Rc::new(TokioClock)),
+            executor: RefCell::new(Rc::new(TokioExecutor)),
+            quirks: Quirks::default().into(),
+            client_configuration_burst_window: db.server_config().client_configuration_burst_window,
+            client_configuration_burst_timer: None.into(),
 
             indication_handler: SharedMutex::new(indication_handler),
             pending_confirmation,
+            outstanding_indications: Rc::default(),
+
+            notification_handler: NotificationHandler::new(db.clone()),
+
+            command_handler: AttCommandHandler::new(db, signature_verifier),
+
+            opened_at: TokioClock.now().into(),
+            last_activity: TokioClock.now().into(),
+            last_rx: TokioClock.now().into(),
+        }
+    }
+
+    /// Get the MTU currently in use on this bearer (the default, until it is
+    /// exchanged)
+    pub fn get_mtu(&self) -> usize {
+        self.core.borrow().mtu().snapshot_or_default()
+    }
+
+    /// Set the time within which the AttDatabase must produce the reply to a
+    /// request (e.g. when an attribute value is supplied by an app over IPC).
+    /// Requests that do not complete in time are answered with
+    /// UNLIKELY_ERROR, so the bearer is free for the next transaction.
+    /// Applies to requests received after this call.
+    pub fn set_request_timeout(&self, request_timeout: Duration) {
+        self.request_timeout.set(request_timeout);
+    }
+
+    /// Set the handler invoked with the opcode of a request to which no reply
+    /// could be sent within the ATT transaction timeout. The bearer is closed
+    /// at that point, so the handler should tear down the underlying channel.
+    pub fn set_on_transaction_timeout(&self, handler: impl Fn(AttOpcode) + 'static) {
+        self.on_transaction_timeout.replace(Some(Box::new(handler)));
+    }
+
+    /// Set the handler invoked on each BearerEvent
+    pub fn set_on_event(&self, handler: impl Fn(BearerEvent) + 'static) {
+        let handler: Rc<dyn Fn(BearerEvent)> = Rc::new(handler);
+        self.notification_handler.set_on_congestion({
+            let handler = handler.clone();
+            move |congested| handler(BearerEvent::Congestion(congested))
+        });
+        self.on_event.replace(Some(handler));
+    }
+
+    /// Set the handler invoked with every PDU received or sent on this bearer,
+    /// e.g. to trace them
+    pub fn set_on_pdu(&self, handler: impl Fn(Direction, AttView<'_>) + 'static) {
+        self.on_pdu.replace(Some(Box::new(handler)));
+    }
+
+    /// Set the handler invoked as each transaction on this bearer reaches each
+    /// SpanPhase, with the sequence number of the transaction (counting from 0
+    /// on this bearer), its opcode, and the time (as measured by the Clock)
+    pub fn set_on_span(&self, handler: impl Fn(u64, AttOpcode, SpanPhase, Instant) + 'static) {
+        self.on_span.replace(Some(Rc::new(handler)));
+    }
+
+    /// Wait for credits from the given TransmitBackpressure (for this bearer)
+    /// before sending each notification or indication
+    pub fn set_backpressure(&self, backpressure: Rc<dyn TransmitBackpressure>, bearer: BearerId) {
+        self.backpressure.replace(Some((backpressure, bearer)));
+    }
+
+    /// Work around the given quirks of the peer, in the requests received and
+    /// the notifications and indications sent from now on. The MTU offered to
+    /// the client is set at construction, so it is capped by the caller.
+    pub fn set_quirks(&self, quirks: Quirks) {
+        self.quirks.set(quirks);
+    }
+
+    /// Consult the given OpcodePolicy, for the client on the given transport,
+    /// before dispatching each request or command received on this bearer
+    pub fn set_opcode_policy(&self, policy: Rc<dyn OpcodePolicy>, tcb_idx: TransportIndex) {
+        self.core.borrow_mut().set_opcode_policy(policy, tcb_idx);
+    }
+
+    /// Use the given Clock for all the timers on this bearer, rather than the
+    /// TokioClock (e.g. so that tests can advance a VirtualClock). Should be
+    /// set before the bearer is used, since its uptime restarts from here.
+    pub fn set_clock(&self, clock: Rc<dyn Clock>) {
+        self.opened_at.set(clock.now());
+        self.last_activity.set(clock.now());
+        self.last_rx.set(clock.now());
+        self.clock.replace(clock);
+    }
+
+    /// Spawn the background tasks of this bearer (transactions, queued
+    /// commands) on the given Executor, rather than on the TokioExecutor.
+    /// Should be set before the bearer is used.
+    pub fn set_executor(&self, executor: Rc<dyn Executor>) {
+        self.command_handler.set_executor(executor.clone());
+        self.executor.replace(executor);
+    }
+
+    /// The Executor on which tasks related to this bearer should be spawned
+    pub fn executor(&self) -> Rc<dyn Executor> {
+        self.executor.borrow().clone()
+    }
 
-            command_handler: AttCommandHandler::new(db),
+    fn emit_event(&self, event: BearerEvent) {
+        if let Some(handler) = self.on_event.borrow().as_ref() {
+            handler(event);
         }
     }
 
+    /// The opcode of the request currently being processed on this bearer,
+    /// if any, and how long ago it was received
+    pub fn pending_request(&self) -> Option<(AttOpcode, Duration)> {
+        let now = self.clock.borrow().now();
+        self.core.borrow().pending_request(now)
+    }
+
+    /// A snapshot of the counters of the traffic on this bearer
+    pub fn metrics(&self) -> BearerMetrics {
+        let mut metrics = self.core.borrow().metrics().clone();
+        metrics.uptime = self.clock.borrow().now() - self.opened_at.get();
+        metrics
+    }
+
+    /// The number of indications sent or queued on this bearer, and not yet
+    /// confirmed (or failed)
+    pub fn outstanding_indications(&self) -> usize {
+        self.outstanding_indications.get()
+    }
+
+    /// The number of notifications queued on this bearer, and not yet sent
+    pub fn queued_notifications(&self) -> usize {
+        self.notification_handler.queued()
+    }
+
+    /// When a PDU was last sent or received on this bearer (or it was opened,
+    /// if none has been)
+    pub fn last_activity(&self) -> Instant {
+        self.last_activity.get()
+    }
+
+    /// Whether this bearer was closed, since a transaction on it timed out.
+    /// Once closed, incoming packets are dropped, and no packets are sent.
+    pub fn is_closed(&self) -> bool {
+        self.core.borrow().is_closed()
+    }
+
+    /// Handle a change in the security level of the link (or the completion of
+    /// an attempt to change it), so that a request parked while waiting for
+    /// it can be replayed
+    pub fn on_security_level_changed(&self) {
+        self.security_elevation.on_security_level_changed();
+    }
+
     fn send_packet(&self, packet: impl Into<AttChild>) -> Result<(), SerializeError> {
         let child = packet.into();
         let packet = AttBuilder { opcode: HACK_child_to_opcode(&child), _child_: child };
-        (self.send_packet)(packet)
+        self.transmit(packet)
+    }
+
+    fn transmit(&self, packet: AttBuilder) -> Result<(), SerializeError> {
+        let len = packet.size_in_bits()? / 8;
+        audit_outgoing_pdu(&packet, self.get_mtu());
+        if let Some(handler) = self.on_pdu.borrow().as_ref() {
+            // the PDU is parsed back from its serialized form, so that the handler sees
+            // exactly what is sent
+            match packet.to_vec().map(|bytes| OwnedAttView::try_parse(bytes.into_boxed_slice())) {
+                Ok(Ok(pdu)) => handler(Direction::Tx, pdu.view()),
+                _ => warn!("failed to parse outgoing {:?} for tracing", packet.opcode),
+            }
+        }
+        (self.send_packet)(packet)?;
+        self.core.borrow_mut().metrics_mut().on_pdu_sent(len);
+        self.last_activity.set(self.clock.borrow().now());
+        Ok(())
+    }
+
+    fn trace_rx(&self, packet: AttView<'_>) {
+        if let Some(handler) = self.on_pdu.borrow().as_ref() {
+            handler(Direction::Rx, packet);
+        }
+    }
+
+    fn record_rx(&self, len: usize, now: Instant) {
+        self.core.borrow_mut().metrics_mut().on_pdu_received(len);
+        self.last_activity.set(now);
+        self.last_rx.set(now);
     }
 }
 
 impl<T: AttDatabase + Clone + 'static> WeakBoxRef<'_, AttServerBearer<T>> {
+    /// Handle an incoming PDU that has not been parsed yet, rejecting or
+    /// dropping it if it is malformed (see the pdu_decoder module)
+    pub fn handle_raw_packet(&self, pdu: &[u8]) {
+        if self.on_pdu.borrow().is_some() {
+            if let DecodedPdu::Parsed(packet) = decode_pdu(pdu) {
+                self.trace_rx(packet.view());
+            }
+        }
+        let now = self.clock.borrow().now();
+        self.record_rx(pdu.len(), now);
+        let actions = self.core.borrow_mut().handle_rx_pdu(pdu, now);
+        self.execute(actions);
+    }
+
     /// Handle an incoming packet, and send outgoing packets as appropriate
     /// using the owned ATT channel.
     pub fn handle_packet(&self, packet: AttView<'_>) {
-        match classify_opcode(packet.get_opcode()) {
-            OperationType::Command => {
-                self.command_handler.process_packet(packet);
-            }
-            OperationType::Request => {
-                self.handle_request(packet);
-            }
-            OperationType::Confirmation => self.pending_confirmation.on_confirmation(),
-            OperationType::Response | OperationType::Notification | OperationType::Indication => {
-                unreachable!("the arbiter should not let us receive these packet types")
-            }
-        }
+        self.trace_rx(packet);
+        let now = self.clock.borrow().now();
+        // the opcode, and then the parameters
+        self.record_rx(1 + packet.get_raw_payload().count(), now);
+        let actions = self.core.borrow_mut().handle_rx_packet(packet, now);
+        self.execute(actions);
     }
 
     /// Send an indication, wait for the peer confirmation, and return the
@@ -123,10 +482,14 @@ impl<T: AttDatabase + Clone + 'static> WeakBoxRef<'_, AttServerBearer<T>> {
         trace!("sending indication for handle {handle:?}");
 
         let locked_indication_handler = self.indication_handler.lock();
-        let pending_mtu = self.mtu.snapshot();
+        let pending_mtu = self.core.borrow().mtu().snapshot();
+        let quirks = self.quirks.get();
+        let clock = self.clock.borrow().clone();
         let this = self.downgrade();
+        let outstanding = OutstandingIndication::new(self.outstanding_indications.clone());
 
         async move {
+            let _outstanding = outstanding;
             // first wait until we are at the head of the queue and are ready to send
             // indications
             let mut indication_handler = locked_indication_handler
@@ -142,107 +505,444 @@ impl<T: AttDatabase + Clone + 'static> WeakBoxRef<'_, AttServerBearer<T>> {
                     warn!("indication for handle {handle:?} cancelled while waiting for MTU exchange to complete since the connection dropped");
                     IndicationError::SendError(SendError::ConnectionDropped)
                 })?;
+            let mtu = quirks.cap_notification_mtu(mtu);
+            // then wait until the transport can take it
+            this.wait_for_transmit_credit().await;
             // finally, send, and wait for a response
-            indication_handler.send(handle, data, mtu, |packet| this.try_send_packet(packet)).await
+            let result = indication_handler
+                .send(handle, data, mtu, &*clock, |packet| this.try_send_packet(packet))
+                .await;
+            if result.is_ok() {
+                this.with(|this| {
+                    if let Some(this) = this {
+                        this.emit_event(BearerEvent::IndicationConfirmed(handle));
+                    }
+                });
+            }
+            result
+        }
+    }
+
+    /// Send a notification. Notifications are not acknowledged by the peer, so
+    /// this resolves once the packet has been handed to the transport (after
+    /// waiting for a credit, if it reports its backpressure). If too
+    /// many notifications are already queued on this connection (or by the
+    /// service containing the handle), fails immediately with
+    /// NotificationError::Congested (or ServiceQuotaExceeded).
+    ///
+    /// Queued notifications are sent in order of priority. If the notifications
+    /// of the characteristic are coalesced (see
+    /// AttDatabase::coalesces_notifications()) and one is still queued, it
+    /// takes the given value instead, and this resolves immediately.
+    pub fn send_notification(
+        &self,
+        handle: AttHandle,
+        data: AttAttributeDataChild,
+        priority: Priority,
+    ) -> impl Future<Output = Result<(), NotificationError>> {
+        trace!("sending notification for handle {handle:?} with priority {priority:?}");
+
+        let data = self.notification_handler.try_coalesce(handle, data);
+        if data.is_none() {
+            trace!("coalesced notification for handle {handle:?} into the one queued");
+            self.core.borrow_mut().metrics_mut().on_notification_coalesced();
+        }
+        let permit = data
+            .map(|data| {
+                self.notification_handler
+                    .try_reserve(handle, priority)
+                    .map(|permit| permit.hold_value(handle, data))
+            })
+            .transpose();
+        let pending_mtu = self.core.borrow().mtu().snapshot();
+        let quirks = self.quirks.get();
+        let this = self.downgrade();
+
+        async move {
+            let Some(mut permit) = permit? else {
+                return Ok(());
+            };
+            // if MTU negotiation is taking place, wait for it to complete
+            let mtu = pending_mtu
+                .await
+                .ok_or_else(|| {
+                    warn!("notification for handle {handle:?} cancelled while waiting for MTU exchange to complete since the connection dropped");
+                    NotificationError::SendError(SendError::ConnectionDropped)
+                })?;
+            let mtu = quirks.cap_notification_mtu(mtu);
+            permit.wait_for_turn().await;
+            // the permit is held meanwhile, so if the transport stalls the queue fills
+            // up and further notifications are rejected
+            this.wait_for_transmit_credit().await;
+            // the latest value, if later notifications were coalesced into this one
+            let data = permit.take_value().expect("the permit holds a value");
+            let len = data.size_in_bits().unwrap_or(0) / 8;
+            permit.send(handle, data, mtu, |packet| this.try_send_packet(packet))?;
+            this.with(|this| {
+                if let Some(this) = this {
+                    this.core.borrow_mut().metrics_mut().on_notification_sent(len);
+                }
+            });
+            Ok(())
+        }
+    }
+
+    /// Send a batch of notifications, in order. On an EATT bearer whose client
+    /// accepts them (as per its Client Supported Features), they are packed
+    /// into Multiple Handle Value Notifications; otherwise, each is sent
+    /// individually. As above, each notification takes a slot in the queue,
+    /// and if any of them cannot be queued or is invalid, none are sent.
+    pub fn send_multiple_notifications(
+        &self,
+        notifications: Vec<(AttHandle, AttAttributeDataChild)>,
+        priority: Priority,
+    ) -> impl Future<Output = Result<(), NotificationError>> {
+        trace!("sending {} notifications with priority {priority:?}", notifications.len());
+
+        let pack =
+            self.enhanced && self.notification_handler.client_supports_multiple_notifications();
+        let batch = notifications
+            .into_iter()
+            .map(|(handle, data)| {
+                let permit = self.notification_handler.try_reserve(handle, priority)?;
+                let value = data
+                    .to_vec()
+                    .map_err(SendError::SerializeError)
+                    .map_err(NotificationError::SendError)?;
+                Ok((permit, handle, value))
+            })
+            .collect::<Result<Vec<_>, NotificationError>>();
+        let pending_mtu = self.core.borrow().mtu().snapshot();
+        let quirks = self.quirks.get();
+        let this = self.downgrade();
+
+        async move {
+            let batch = batch?;
+            // if MTU negotiation is taking place, wait for it to complete
+            let mtu = pending_mtu
+                .await
+                .ok_or_else(|| {
+                    warn!("notifications cancelled while waiting for MTU exchange to complete since the connection dropped");
+                    NotificationError::SendError(SendError::ConnectionDropped)
+                })?;
+            let mtu = quirks.cap_notification_mtu(mtu);
+            // the notifications of the batch share a priority
+            if let Some((permit, _, _)) = batch.first() {
+                permit.wait_for_turn().await;
+            }
+            this.wait_for_transmit_credit().await;
+            let lens = batch.iter().map(|(_, _, value)| value.len()).collect::<Vec<_>>();
+            send_batch(batch, mtu, pack, |packet| this.try_send_packet(packet))?;
+            this.with(|this| {
+                if let Some(this) = this {
+                    let mut core = this.core.borrow_mut();
+                    for len in lens {
+                        core.metrics_mut().on_notification_sent(len);
+                    }
+                }
+            });
+            Ok(())
         }
     }
 
     /// Handle a snooped MTU event, to update the MTU we use for our various
     /// operations
     pub fn handle_mtu_event(&self, mtu_event: MtuEvent) -> Result<()> {
-        self.mtu.handle_event(mtu_event)
+        let actions = self.core.borrow_mut().handle_mtu_event(mtu_event)?;
+        self.execute(actions);
+        Ok(())
+    }
+
+    /// Carry out the Actions returned by the AttServerCore
+    fn execute(&self, actions: Vec<Action>) {
+        for action in actions {
+            match action {
+                Action::Send(packet) => {
+                    if let Err(err) = self.transmit(packet) {
+                        error!("serializer failure {err:?}, dropping packet");
+                    }
+                }
+                Action::StartTransaction { request, mtu, deadline, not_before } => {
+                    self.start_transaction(request, mtu, deadline, not_before)
+                }
+                Action::ProcessCommand(command) => {
+                    self.command_handler.process_packet(command.view())
+                }
+                Action::ConfirmIndication => self.pending_confirmation.on_confirmation(),
+                Action::Event(event) => self.emit_event(event),
+                Action::Close(opcode) => {
+                    if let Some(handler) = self.on_transaction_timeout.borrow().as_ref() {
+                        handler(opcode);
+                    }
+                }
+            }
+        }
     }
 
-    fn handle_request(&self, packet: AttView<'_>) {
+    /// Process a request against the AttDatabase, and hand the reply back to
+    /// the AttServerCore (or tell it once the deadline has passed)
+    fn start_transaction(
+        &self,
+        packet: OwnedAttView,
+        mtu: usize,
+        deadline: Instant,
+        not_before: Option<Instant>,
+    ) {
         let curr_request = self.curr_request.replace(AttRequestState::Replacing);
         self.curr_request.replace(match curr_request {
             AttRequestState::Idle(mut request_handler) => {
-                // even if the MTU is updated afterwards, 5.3 3F 3.4.2.2 states that the
-                // request-time MTU should be used
-                let mtu = self.mtu.snapshot_or_default();
-                let packet = packet.to_owned_packet();
+                request_handler.set_quirks(self.quirks.get());
+                // the client is not idle, so a burst of CCCD writes goes on
+                self.client_configuration_burst_timer.take();
                 let this = self.downgrade();
-                let task = spawn_local(async move {
+                let security_elevation = self.security_elevation.clone();
+                let request_timeout = self.request_timeout.get();
+                let clock = self.clock.borrow().clone();
+                let span = self.on_span.borrow().clone().map(|on_span| {
+                    // the request was received by the PDU that started this transaction
+                    let seq = self.next_span.replace(self.next_span.get() + 1);
+                    let opcode = packet.view().get_opcode();
+                    on_span(seq, opcode, SpanPhase::RequestReceived, self.last_rx.get());
+                    move |phase: SpanPhase, at: Instant| on_span(seq, opcode, phase, at)
+                });
+                let task = self.executor.borrow().spawn(Box::pin(async move {
                     trace!("starting ATT transaction");
-                    let reply = request_handler.process_packet(packet.view(), mtu).await;
+                    let trace_span = |phase: SpanPhase| {
+                        if let Some(span) = &span {
+                            span(phase, clock.now());
+                        }
+                    };
+                    let reply = timeout_at(&*clock, deadline, async {
+                        let handler = &mut request_handler;
+                        let clock = &*clock;
+                        if let Some(not_before) = not_before {
+                            trace!("client is throttled, holding back request");
+                            clock.sleep_until(not_before).await;
+                        }
+                        trace_span(SpanPhase::Dispatched);
+                        let mut reply =
+                            process_request(handler, packet.view(), mtu, request_timeout, clock)
+                                .await;
+                        if security_elevation.try_elevate(&reply._child_, clock).await {
+                            trace!("link security elevated, replaying request");
+                            reply = process_request(
+                                handler,
+                                packet.view(),
+                                mtu,
+                                request_timeout,
+                                clock,
+                            )
+                            .await;
+                        }
+                        trace_span(SpanPhase::DatabaseResolved);
+                        reply
+                    })
+                    .await;
                     this.with(|this| {
-                        this.map(|this| {
-                            match this.send_packet(reply) {
-                                Ok(_) => {
-                                    trace!("reply packet sent")
-                                }
-                                Err(err) => {
-                                    error!("serializer failure {err:?}, dropping packet and sending failed reply");
-                                    // if this also fails, we're stuck
-                                    if let Err(err) = this.send_packet(AttErrorResponseBuilder {
-                                        opcode_in_error: packet.view().get_opcode(),
-                                        handle_in_error: AttHandle(0).into(),
-                                        error_code: AttErrorCode::UNLIKELY_ERROR,
-                                    }) {
-                                        panic!("unexpected serialize error for known-good packet {err:?}")
-                                    }
-                                }
-                            };
-                            // ready for next transaction
-                            this.curr_request.replace(AttRequestState::Idle(request_handler));
-                        })
+                        let Some(this) = this else {
+                            return;
+                        };
+                        let now = clock.now();
+                        let Ok(reply) = reply else {
+                            trace_span(SpanPhase::TimedOut);
+                            let actions = this.core.borrow_mut().handle_timeout(now);
+                            this.execute(actions);
+                            return;
+                        };
+                        let actions = this.core.borrow_mut().complete_transaction(
+                            reply,
+                            request_handler.prepared_write_queue_depth(),
+                            now,
+                        );
+                        let in_burst = request_handler.in_client_configuration_burst();
+                        // ready for next transaction
+                        this.curr_request.replace(AttRequestState::Idle(request_handler));
+                        if in_burst {
+                            this.end_client_configuration_burst_when_idle();
+                        }
+                        this.execute(actions);
+                        trace_span(SpanPhase::ResponseSent);
                     });
-                });
-                AttRequestState::Pending { _task: task.into() }
+                }));
+                AttRequestState::Pending { _task: task }
             }
             AttRequestState::Pending { .. } => {
-                warn!("multiple ATT operations cannot simultaneously take place, dropping one");
-                // TODO(aryarahul) - disconnect connection here;
+                error!("the previous transaction is still being processed, dropping request");
                 curr_request
             }
             AttRequestState::Replacing => {
-              panic!("Replacing is an ephemeral state");
+                panic!("Replacing is an ephemeral state");
             }
         });
     }
+
+    /// End the burst of CCCD writes in progress if the client makes no other
+    /// request within the burst window
+    fn end_client_configuration_burst_when_idle(&self) {
+        let Some(window) = self.client_configuration_burst_window else {
+            return;
+        };
+        let this = self.downgrade();
+        let clock = self.clock.borrow().clone();
+        let deadline = clock.now() + window;
+        let task = self.executor.borrow().spawn(Box::pin(async move {
+            clock.sleep_until(deadline).await;
+            this.with(|this| {
+                let Some(this) = this else {
+                    return;
+                };
+                let curr_request = this.curr_request.replace(AttRequestState::Replacing);
+                this.curr_request.replace(match curr_request {
+                    AttRequestState::Idle(mut request_handler) => {
+                        trace!("client idle, ending burst of CCCD writes");
+                        request_handler.end_client_configuration_burst();
+                        AttRequestState::Idle(request_handler)
+                    }
+                    // a new transaction has started, and will end the burst
+                    curr_request => curr_request,
+                });
+            });
+        }));
+        self.client_configuration_burst_timer.replace(Some(task));
+    }
+}
+
+/// Process a single request, failing it with UNLIKELY_ERROR if the
+/// AttDatabase does not produce a reply within the request_timeout. The
+/// stalled operation is cancelled, so a late completion from its backend is
+/// discarded rather than being confused with a later transaction.
+async fn process_request<T: AttDatabase + Clone + 'static>(
+    request_handler: &mut AttRequestHandler<T>,
+    packet: AttView<'_>,
+    mtu: usize,
+    request_timeout: Duration,
+    clock: &dyn Clock,
+) -> AttBuilder {
+    match timeout(clock, request_timeout, request_handler.process_packet(packet, mtu)).await {
+        Ok(reply) => reply,
+        Err(_) => {
+            warn!("{:?} not completed within {request_timeout:?}, failing it", packet.get_opcode());
+            AttBuilder {
+                opcode: AttOpcode::ERROR_RESPONSE,
+                _child_: AttErrorResponseBuilder {
+                    opcode_in_error: packet.get_opcode(),
+                    handle_in_error: requested_handle(packet).into(),
+                    error_code: AttErrorCode::UNLIKELY_ERROR,
+                }
+                .into(),
+            }
+        }
+    }
+}
+
+/// The first attribute named by a request, to be reported as the handle in
+/// error, or AttHandle(0) if it names none
+fn requested_handle(packet: AttView<'_>) -> AttHandle {
+    accessed_handles(packet)
+        .ok()
+        .and_then(|handles| handles.first().copied())
+        .unwrap_or(AttHandle(0))
 }
 
 impl<T: AttDatabase + Clone + 'static> WeakBox<AttServerBearer<T>> {
+    /// Wait until the transport can accept another packet on this bearer, if
+    /// it reports its backpressure. If that takes longer than the
+    /// TRANSMIT_CONGESTION_THRESHOLD, the connection is reported as congested
+    /// until it can.
+    async fn wait_for_transmit_credit(&self) {
+        let Some((backpressure, bearer, clock)) = self.with(|this| {
+            this.and_then(|this| {
+                let (backpressure, bearer) = this.backpressure.borrow().clone()?;
+                Some((backpressure, bearer, this.clock.borrow().clone()))
+            })
+        }) else {
+            return;
+        };
+        let mut credit = backpressure.wait_for_credit(bearer);
+        if timeout(&*clock, TRANSMIT_CONGESTION_THRESHOLD, &mut credit).await.is_ok() {
+            return;
+        }
+        warn!("transport withheld credits on {bearer} for {TRANSMIT_CONGESTION_THRESHOLD:?}");
+        self.set_transport_congested(true);
+        credit.await;
+        self.set_transport_congested(false);
+    }
+
+    fn set_transport_congested(&self, congested: bool) {
+        self.with(|this| {
+            if let Some(this) = this {
+                this.notification_handler.set_transport_congested(congested);
+            }
+        });
+    }
+
     fn try_send_packet(&self, packet: impl Into<AttChild>) -> Result<(), SendError> {
         self.with(|this| {
-            this.ok_or_else(|| {
+            let this = this.ok_or_else(|| {
                 warn!("connection dropped before packet sent");
                 SendError::ConnectionDropped
-            })?
-            .send_packet(packet)
-            .map_err(SendError::SerializeError)
+            })?;
+            if this.is_closed() {
+                warn!("bearer closed before packet sent");
+                return Err(SendError::ConnectionDropped);
+            }
+            this.send_packet(packet).map_err(SendError::SerializeError)
         })
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::rc::Rc;
+    use std::{collections::BTreeMap, future::pending, time::Duration};
 
-    use tokio::sync::mpsc::{error::TryRecvError, unbounded_channel, UnboundedReceiver};
+    use async_trait::async_trait;
+    use tokio::{
+        sync::{
+            mpsc::{error::TryRecvError, unbounded_channel, UnboundedReceiver},
+            Notify,
+        },
+        task::{spawn_local, yield_now},
+    };
 
     use super::*;
 
+    #[cfg(not(feature = "le_only"))]
+    use crate::gatt::mtu::MIN_BR_EDR_ATT_MTU;
+
     use crate::{
         core::{shared_box::SharedBox, uuid::Uuid},
         gatt::{
+            channel::ATT_TRANSACTION_TIMEOUT,
             ffi::AttributeBackingType,
             ids::TransportIndex,
-            mocks::mock_datastore::{MockDatastore, MockDatastoreEvents},
+            mocks::{
+                mock_datastore::{MockDatastore, MockDatastoreEvents},
+                mock_security_manager::MockSecurityManager,
+            },
+            mtu::MAX_ATT_MTU,
+            security_manager::SecurityLevel,
             server::{
-                att_database::{AttAttribute, AttPermissions},
+                att_database::{AttAttribute, AttAttributeValue, AttPermissions},
+                att_error::AttError,
                 gatt_database::{
-                    GattCharacteristicWithHandle, GattDatabase, GattServiceWithHandle,
+                    AttDatabaseImpl, GattCharacteristicWithHandle, GattDatabase,
+                    GattServiceWithHandle,
                 },
+                notification_handler::MAX_QUEUED_NOTIFICATIONS,
+                robust_caching::ClientSupportedFeatures,
                 test::test_att_db::TestAttDatabase,
             },
         },
         packets::{
-            AttAttributeDataBuilder, AttAttributeDataChild, AttHandleValueConfirmationBuilder,
-            AttOpcode, AttReadRequestBuilder, AttReadResponseBuilder,
+            AttAttributeDataBuilder, AttAttributeDataChild, AttExchangeMtuRequestBuilder,
+            AttExchangeMtuResponseBuilder, AttExecuteWriteFlags, AttExecuteWriteRequestBuilder,
+            AttHandleValueConfirmationBuilder, AttHandleValueNotificationBuilder, AttOpcode,
+            AttPrepareWriteRequestBuilder, AttReadRequestBuilder, AttReadResponseBuilder,
+            AttWriteRequestBuilder,
         },
         utils::{
-            packet::build_att_view_or_crash,
+            clock::VirtualClock,
+            packet::{build_att_data, build_att_view_or_crash},
             task::{block_on_locally, try_await},
         },
     };
@@ -253,14 +953,28 @@ mod test {
 
     const TCB_IDX: TransportIndex = TransportIndex(1);
 
+    fn make_signature_verifier() -> SignatureVerifier {
+        SignatureVerifier::new(TCB_IDX, Rc::new(MockSecurityManager::new()))
+    }
+
+    fn make_security_elevation() -> SecurityElevation {
+        SecurityElevation::new(TCB_IDX, Rc::new(MockSecurityManager::new()))
+    }
+
     fn open_connection(
     ) -> (SharedBox<AttServerBearer<TestAttDatabase>>, UnboundedReceiver<AttBuilder>) {
-        let db = TestAttDatabase::new(vec![
+        open_connection_to(make_test_db())
+    }
+
+    fn make_test_db() -> TestAttDatabase {
+        TestAttDatabase::new(vec![
             (
                 AttAttribute {
                     handle: VALID_HANDLE,
                     type_: Uuid::new(0x1234),
-                    permissions: AttPermissions::READABLE | AttPermissions::INDICATE,
+                    permissions: AttPermissions::READABLE
+                        | AttPermissions::NOTIFY
+                        | AttPermissions::INDICATE,
                 },
                 vec![5, 6],
             ),
@@ -272,12 +986,23 @@ mod test {
                 },
                 vec![5, 6],
             ),
-        ]);
+        ])
+    }
+
+    fn open_connection_to(
+        db: TestAttDatabase,
+    ) -> (SharedBox<AttServerBearer<TestAttDatabase>>, UnboundedReceiver<AttBuilder>) {
         let (tx, rx) = unbounded_channel();
-        let conn = AttServerBearer::new(db, move |packet| {
-            tx.send(packet).unwrap();
-            Ok(())
-        })
+        let conn = AttServerBearer::new(
+            db,
+            make_signature_verifier(),
+            make_security_elevation(),
+            MAX_ATT_MTU,
+            move |packet| {
+                tx.send(packet).unwrap();
+                Ok(())
+            },
+        )
         .into();
         (conn, rx)
     }
@@ -297,6 +1022,120 @@ mod test {
         });
     }
 
+    type RecordedSpans = Rc<RefCell<Vec<(u64, AttOpcode, SpanPhase, Instant)>>>;
+
+    fn record_spans<T: AttDatabase + Clone + 'static>(
+        conn: &SharedBox<AttServerBearer<T>>,
+    ) -> RecordedSpans {
+        let spans = Rc::new(RefCell::new(vec![]));
+        conn.set_on_span({
+            let spans = spans.clone();
+            move |seq, opcode, phase, at| spans.borrow_mut().push((seq, opcode, phase, at))
+        });
+        spans
+    }
+
+    #[test]
+    fn test_transaction_spans() {
+        block_on_locally(async {
+            // arrange
+            let (conn, mut rx) = open_connection();
+            let spans = record_spans(&conn);
+
+            // act: two transactions, one after the other
+            for _ in 0..2 {
+                conn.as_ref().handle_packet(
+                    build_att_view_or_crash(AttReadRequestBuilder {
+                        attribute_handle: VALID_HANDLE.into(),
+                    })
+                    .view(),
+                );
+                rx.recv().await.unwrap();
+            }
+
+            // assert: each went through every phase, in order
+            let phases = spans
+                .borrow()
+                .iter()
+                .map(|(seq, opcode, phase, _)| (*seq, *opcode, *phase))
+                .collect::<Vec<_>>();
+            let expected = |seq| {
+                [
+                    SpanPhase::RequestReceived,
+                    SpanPhase::Dispatched,
+                    SpanPhase::DatabaseResolved,
+                    SpanPhase::ResponseSent,
+                ]
+                .map(|phase| (seq, AttOpcode::READ_REQUEST, phase))
+            };
+            assert_eq!(phases, [expected(0), expected(1)].concat());
+        });
+    }
+
+    #[test]
+    fn test_timed_out_transaction_span() {
+        block_on_locally(async {
+            // arrange
+            let (conn, _rx, _) = open_stalled_connection();
+            let clock = VirtualClock::new();
+            conn.set_clock(Rc::new(clock.clone()));
+            conn.set_request_timeout(ATT_TRANSACTION_TIMEOUT * 2);
+            let spans = record_spans(&conn);
+
+            // act: send a request that the database never completes
+            send_stalled_read_request(&conn);
+            clock.advance(ATT_TRANSACTION_TIMEOUT).await;
+
+            // assert: the span ended once the transaction timed out, without
+            // the database ever resolving it
+            let spans = spans.borrow();
+            let phases = spans.iter().map(|(_, _, phase, _)| *phase).collect::<Vec<_>>();
+            assert_eq!(
+                phases,
+                vec![SpanPhase::RequestReceived, SpanPhase::Dispatched, SpanPhase::TimedOut]
+            );
+            assert_eq!(spans[2].3 - spans[0].3, ATT_TRANSACTION_TIMEOUT);
+        });
+    }
+
+    #[test]
+    fn test_unknown_request_rejected() {
+        block_on_locally(async {
+            let (conn, mut rx) = open_connection();
+
+            conn.as_ref().handle_raw_packet(&[0x30, 0x01]);
+
+            assert_eq!(
+                rx.recv().await.unwrap().to_vec().unwrap(),
+                vec![0x01, 0x30, 0x00, 0x00, u8::from(AttErrorCode::REQUEST_NOT_SUPPORTED)]
+            );
+            assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        });
+    }
+
+    #[test]
+    fn test_unknown_command_dropped() {
+        block_on_locally(async {
+            let (conn, mut rx) = open_connection();
+
+            conn.as_ref().handle_raw_packet(&[0x70, 0x01]);
+
+            assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        });
+    }
+
+    #[test]
+    fn test_response_from_client_dropped() {
+        block_on_locally(async {
+            let (conn, mut rx) = open_connection();
+
+            // a WRITE_RESPONSE, which a client should never send
+            conn.as_ref().handle_raw_packet(&[0x13]);
+
+            assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        });
+    }
+
     #[test]
     fn test_sequential_transactions() {
         block_on_locally(async {
@@ -322,19 +1161,236 @@ mod test {
     }
 
     #[test]
-    fn test_concurrent_transaction_failure() {
-        // arrange: AttServerBearer linked to a backing datastore and packet queue, with
-        // two characteristics in the database
-        let (datastore, mut data_rx) = MockDatastore::new();
-        let datastore = Rc::new(datastore);
-        let db = SharedBox::new(GattDatabase::new());
-        db.add_service_with_handles(
-            GattServiceWithHandle {
-                handle: AttHandle(1),
-                type_: Uuid::new(1),
-                characteristics: vec![
-                    GattCharacteristicWithHandle {
-                        handle: VALID_HANDLE,
+    fn test_enhanced_bearer_uses_configured_mtu() {
+        block_on_locally(async {
+            // arrange: an EATT bearer with an MTU larger than the default
+            let db = TestAttDatabase::new(vec![(
+                AttAttribute {
+                    handle: VALID_HANDLE,
+                    type_: Uuid::new(0x1234),
+                    permissions: AttPermissions::READABLE,
+                },
+                vec![1; 100],
+            )]);
+            let (tx, mut rx) = unbounded_channel();
+            let conn = SharedBox::new(AttServerBearer::new_enhanced(
+                db,
+                make_signature_verifier(),
+                make_security_elevation(),
+                64,
+                move |packet| {
+                    tx.send(packet).unwrap();
+                    Ok(())
+                },
+            ));
+
+            // act: read a long attribute
+            conn.as_ref().handle_packet(
+                build_att_view_or_crash(AttReadRequestBuilder {
+                    attribute_handle: VALID_HANDLE.into(),
+                })
+                .view(),
+            );
+            let reply = rx.recv().await.unwrap();
+
+            // assert: the value was truncated to the configured MTU, less the opcode
+            assert_eq!(
+                reply,
+                AttBuilder {
+                    opcode: AttOpcode::READ_RESPONSE,
+                    _child_: AttReadResponseBuilder {
+                        value: AttAttributeDataBuilder {
+                            _child_: AttAttributeDataChild::RawData([1; 63].into()),
+                        },
+                    }
+                    .into(),
+                }
+            );
+        });
+    }
+
+    fn exchange_mtu(conn: &SharedBox<AttServerBearer<TestAttDatabase>>, mtu: u16) {
+        conn.as_ref()
+            .handle_packet(build_att_view_or_crash(AttExchangeMtuRequestBuilder { mtu }).view());
+    }
+
+    #[test]
+    fn test_mtu_exchange() {
+        block_on_locally(async {
+            // arrange
+            let (conn, mut rx) = open_connection();
+
+            // act: the client exchanges the MTU
+            exchange_mtu(&conn, 100);
+
+            // assert: the server replied with its RX MTU, and now uses the smaller one
+            assert_eq!(
+                rx.recv().await.unwrap(),
+                AttBuilder {
+                    opcode: AttOpcode::EXCHANGE_MTU_RESPONSE,
+                    _child_: AttExchangeMtuResponseBuilder { mtu: MAX_ATT_MTU as u16 }.into(),
+                }
+            );
+            assert_eq!(conn.get_mtu(), 100);
+        });
+    }
+
+    #[test]
+    fn test_second_mtu_exchange_rejected() {
+        block_on_locally(async {
+            // arrange: the client has already exchanged the MTU
+            let (conn, mut rx) = open_connection();
+            exchange_mtu(&conn, 100);
+            rx.recv().await.unwrap();
+
+            // act: it tries to exchange it again
+            exchange_mtu(&conn, 200);
+
+            // assert: the exchange was rejected, and the MTU is unchanged
+            assert_eq!(
+                rx.recv().await.unwrap(),
+                AttBuilder {
+                    opcode: AttOpcode::ERROR_RESPONSE,
+                    _child_: AttErrorResponseBuilder {
+                        opcode_in_error: AttOpcode::EXCHANGE_MTU_REQUEST,
+                        handle_in_error: AttHandle(0).into(),
+                        error_code: AttErrorCode::REQUEST_NOT_SUPPORTED,
+                    }
+                    .into(),
+                }
+            );
+            assert_eq!(conn.get_mtu(), 100);
+        });
+    }
+
+    #[test]
+    fn test_mtu_exchange_on_enhanced_bearer_rejected() {
+        block_on_locally(async {
+            // arrange: an EATT bearer
+            let (tx, mut rx) = unbounded_channel();
+            let conn = SharedBox::new(AttServerBearer::new_enhanced(
+                TestAttDatabase::new(vec![]),
+                make_signature_verifier(),
+                make_security_elevation(),
+                64,
+                move |packet| {
+                    tx.send(packet).unwrap();
+                    Ok(())
+                },
+            ));
+
+            // act: the client tries to exchange the MTU
+            exchange_mtu(&conn, 100);
+
+            // assert: the exchange was rejected
+            assert_eq!(rx.recv().await.unwrap().opcode, AttOpcode::ERROR_RESPONSE);
+            assert_eq!(conn.get_mtu(), 64);
+        });
+    }
+
+    #[test]
+    #[cfg(not(feature = "le_only"))]
+    fn test_mtu_exchange_over_br_edr_rejected() {
+        block_on_locally(async {
+            // arrange: a bearer over BR/EDR, with the MTU configured by L2CAP
+            let (tx, mut rx) = unbounded_channel();
+            let conn = SharedBox::new(AttServerBearer::new_br_edr(
+                TestAttDatabase::new(vec![]),
+                make_signature_verifier(),
+                make_security_elevation(),
+                MIN_BR_EDR_ATT_MTU,
+                move |packet| {
+                    tx.send(packet).unwrap();
+                    Ok(())
+                },
+            ));
+
+            // act: the client tries to exchange the MTU
+            exchange_mtu(&conn, 100);
+
+            // assert: the exchange was rejected, and the configured MTU is still used
+            assert_eq!(rx.recv().await.unwrap().opcode, AttOpcode::ERROR_RESPONSE);
+            assert_eq!(conn.get_mtu(), MIN_BR_EDR_ATT_MTU);
+        });
+    }
+
+    #[test]
+    fn test_read_truncated_to_exchanged_mtu() {
+        block_on_locally(async {
+            // arrange: a bearer with a long attribute, on which the MTU is exchanged
+            let db = TestAttDatabase::new(vec![(
+                AttAttribute {
+                    handle: VALID_HANDLE,
+                    type_: Uuid::new(0x1234),
+                    permissions: AttPermissions::READABLE,
+                },
+                vec![1; 100],
+            )]);
+            let (tx, mut rx) = unbounded_channel();
+            let conn = SharedBox::new(AttServerBearer::new(
+                db,
+                make_signature_verifier(),
+                make_security_elevation(),
+                MAX_ATT_MTU,
+                move |packet| {
+                    tx.send(packet).unwrap();
+                    Ok(())
+                },
+            ));
+            exchange_mtu(&conn, 30);
+            rx.recv().await.unwrap();
+
+            // act: read the attribute
+            conn.as_ref().handle_packet(
+                build_att_view_or_crash(AttReadRequestBuilder {
+                    attribute_handle: VALID_HANDLE.into(),
+                })
+                .view(),
+            );
+
+            // assert: the value was truncated to the exchanged MTU, less the opcode
+            assert_eq!(
+                rx.recv().await.unwrap()._child_,
+                AttReadResponseBuilder {
+                    value: AttAttributeDataBuilder {
+                        _child_: AttAttributeDataChild::RawData([1; 29].into()),
+                    },
+                }
+                .into()
+            );
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "exceeds the MTU")]
+    fn test_oversized_pdu_caught_before_transmit() {
+        block_on_locally(async {
+            // arrange
+            let (conn, _rx) = open_connection();
+
+            // act: send a response that a handler forgot to truncate
+            let _ = conn.as_ref().send_packet(AttReadResponseBuilder {
+                value: AttAttributeDataBuilder {
+                    _child_: AttAttributeDataChild::RawData([1; 100].into()),
+                },
+            });
+        });
+    }
+
+    #[test]
+    fn test_concurrent_transaction_failure() {
+        // arrange: AttServerBearer linked to a backing datastore and packet queue, with
+        // two characteristics in the database
+        let (datastore, mut data_rx) = MockDatastore::new();
+        let datastore = Rc::new(datastore);
+        let db = SharedBox::new(GattDatabase::new());
+        db.add_service_with_handles(
+            GattServiceWithHandle {
+                handle: AttHandle(1),
+                type_: Uuid::new(1),
+                characteristics: vec![
+                    GattCharacteristicWithHandle {
+                        handle: VALID_HANDLE,
                         type_: Uuid::new(2),
                         permissions: AttPermissions::READABLE,
                         descriptors: vec![],
@@ -355,7 +1411,13 @@ mod test {
             tx.send(packet).unwrap();
             Ok(())
         };
-        let conn = SharedBox::new(AttServerBearer::new(db.get_att_database(TCB_IDX), send_packet));
+        let conn = SharedBox::new(AttServerBearer::new(
+            db.get_att_database(TCB_IDX),
+            make_signature_verifier(),
+            make_security_elevation(),
+            MAX_ATT_MTU,
+            send_packet,
+        ));
         let data = [1, 2];
 
         // act: send two read requests before replying to either read
@@ -406,112 +1468,571 @@ mod test {
         });
     }
 
+    fn open_connection_requiring_encryption(
+        security_manager: Rc<MockSecurityManager>,
+    ) -> (
+        SharedBox<GattDatabase>,
+        SharedBox<AttServerBearer<AttDatabaseImpl>>,
+        UnboundedReceiver<MockDatastoreEvents>,
+        UnboundedReceiver<AttBuilder>,
+    ) {
+        let (datastore, data_rx) = MockDatastore::new();
+        let db = SharedBox::new(GattDatabase::new_with_security_manager(security_manager.clone()));
+        db.add_service_with_handles(
+            GattServiceWithHandle {
+                handle: AttHandle(1),
+                type_: Uuid::new(1),
+                characteristics: vec![GattCharacteristicWithHandle {
+                    handle: VALID_HANDLE,
+                    type_: Uuid::new(2),
+                    permissions: AttPermissions::READABLE | AttPermissions::ENCRYPTION_REQUIRED,
+                    descriptors: vec![],
+                }],
+            },
+            Rc::new(datastore),
+        )
+        .unwrap();
+        let (tx, rx) = unbounded_channel();
+        let conn = SharedBox::new(AttServerBearer::new(
+            db.get_att_database(TCB_IDX),
+            make_signature_verifier(),
+            SecurityElevation::new(TCB_IDX, security_manager),
+            MAX_ATT_MTU,
+            move |packet| {
+                tx.send(packet).unwrap();
+                Ok(())
+            },
+        ));
+        (db, conn, data_rx, rx)
+    }
+
     #[test]
-    fn test_indication_confirmation() {
+    fn test_request_replayed_after_security_elevation() {
         block_on_locally(async {
             // arrange
-            let (conn, mut rx) = open_connection();
+            let security_manager = Rc::new(MockSecurityManager::new());
+            security_manager.set_security_elevation_supported(true);
+            let (_db, conn, mut data_rx, mut rx) =
+                open_connection_requiring_encryption(security_manager.clone());
 
-            // act: send an indication
-            let pending_send =
-                spawn_local(conn.as_ref().send_indication(
-                    VALID_HANDLE,
-                    AttAttributeDataChild::RawData([1, 2, 3].into()),
-                ));
-            assert_eq!(rx.recv().await.unwrap().opcode, AttOpcode::HANDLE_VALUE_INDICATION);
-            assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
-            // and the confirmation
+            // act: read an attribute requiring encryption, then encrypt the link
             conn.as_ref().handle_packet(
-                build_att_view_or_crash(AttHandleValueConfirmationBuilder {}).view(),
+                build_att_view_or_crash(AttReadRequestBuilder {
+                    attribute_handle: VALID_HANDLE.into(),
+                })
+                .view(),
             );
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+            security_manager.set_security_level(TCB_IDX, SecurityLevel::Encrypted);
+            conn.on_security_level_changed();
+            let MockDatastoreEvents::Read(TCB_IDX, VALID_HANDLE, _, data_resp) =
+                data_rx.recv().await.unwrap()
+            else {
+                unreachable!();
+            };
+            data_resp.send(Ok(vec![1, 2])).unwrap();
 
-            // assert: the indication was correctly sent
-            assert!(matches!(pending_send.await.unwrap(), Ok(())));
+            // assert: encryption was requested, and the replayed request was served
+            assert_eq!(
+                security_manager.take_security_elevation_requests(),
+                vec![(TCB_IDX, SecurityLevel::Encrypted)]
+            );
+            assert_eq!(
+                rx.recv().await.unwrap()._child_,
+                AttReadResponseBuilder {
+                    value: AttAttributeDataBuilder {
+                        _child_: AttAttributeDataChild::RawData([1, 2].into()),
+                    },
+                }
+                .into()
+            );
+            assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
         });
     }
 
     #[test]
-    fn test_sequential_indications() {
+    fn test_security_elevation_timeout() {
         block_on_locally(async {
             // arrange
-            let (conn, mut rx) = open_connection();
+            let security_manager = Rc::new(MockSecurityManager::new());
+            security_manager.set_security_elevation_supported(true);
+            let (_db, conn, mut data_rx, mut rx) =
+                open_connection_requiring_encryption(security_manager);
 
-            // act: send the first indication
-            let pending_send1 =
-                spawn_local(conn.as_ref().send_indication(
-                    VALID_HANDLE,
-                    AttAttributeDataChild::RawData([1, 2, 3].into()),
-                ));
-            // wait for/capture the outgoing packet
-            let sent1 = rx.recv().await.unwrap();
-            // send the response
-            conn.as_ref().handle_packet(
-                build_att_view_or_crash(AttHandleValueConfirmationBuilder {}).view(),
-            );
-            // send the second indication
-            let pending_send2 =
-                spawn_local(conn.as_ref().send_indication(
-                    VALID_HANDLE,
-                    AttAttributeDataChild::RawData([1, 2, 3].into()),
-                ));
-            // wait for/capture the outgoing packet
-            let sent2 = rx.recv().await.unwrap();
-            // and the response
+            // act: read an attribute requiring encryption, and never encrypt the link
             conn.as_ref().handle_packet(
-                build_att_view_or_crash(AttHandleValueConfirmationBuilder {}).view(),
+                build_att_view_or_crash(AttReadRequestBuilder {
+                    attribute_handle: VALID_HANDLE.into(),
+                })
+                .view(),
             );
+            let reply = rx.recv().await.unwrap();
 
-            // assert: exactly two indications were sent
-            assert_eq!(sent1.opcode, AttOpcode::HANDLE_VALUE_INDICATION);
-            assert_eq!(sent2.opcode, AttOpcode::HANDLE_VALUE_INDICATION);
-            assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
-            // and that both got successful responses
-            assert!(matches!(pending_send1.await.unwrap(), Ok(())));
-            assert!(matches!(pending_send2.await.unwrap(), Ok(())));
+            // assert: the original error is returned, and no data was read
+            assert_eq!(
+                reply._child_,
+                AttErrorResponseBuilder {
+                    opcode_in_error: AttOpcode::READ_REQUEST,
+                    handle_in_error: VALID_HANDLE.into(),
+                    error_code: AttErrorCode::INSUFFICIENT_ENCRYPTION,
+                }
+                .into()
+            );
+            assert_eq!(data_rx.try_recv().unwrap_err(), TryRecvError::Empty);
         });
     }
 
+    fn open_connection_with_datastore() -> (
+        SharedBox<GattDatabase>,
+        SharedBox<AttServerBearer<AttDatabaseImpl>>,
+        UnboundedReceiver<MockDatastoreEvents>,
+        UnboundedReceiver<AttBuilder>,
+    ) {
+        let (datastore, data_rx) = MockDatastore::new();
+        let db = SharedBox::new(GattDatabase::new());
+        db.add_service_with_handles(
+            GattServiceWithHandle {
+                handle: AttHandle(1),
+                type_: Uuid::new(1),
+                characteristics: vec![GattCharacteristicWithHandle {
+                    handle: VALID_HANDLE,
+                    type_: Uuid::new(2),
+                    permissions: AttPermissions::READABLE | AttPermissions::WRITABLE_WITH_RESPONSE,
+                    descriptors: vec![],
+                }],
+            },
+            Rc::new(datastore),
+        )
+        .unwrap();
+        let (tx, rx) = unbounded_channel();
+        let conn = SharedBox::new(AttServerBearer::new(
+            db.get_att_database(TCB_IDX),
+            make_signature_verifier(),
+            make_security_elevation(),
+            MAX_ATT_MTU,
+            move |packet| {
+                tx.send(packet).unwrap();
+                Ok(())
+            },
+        ));
+        (db, conn, data_rx, rx)
+    }
+
+    fn send_read_request(conn: &SharedBox<AttServerBearer<AttDatabaseImpl>>) {
+        conn.as_ref().handle_packet(
+            build_att_view_or_crash(AttReadRequestBuilder {
+                attribute_handle: VALID_HANDLE.into(),
+            })
+            .view(),
+        );
+    }
+
     #[test]
-    fn test_queued_indications_only_one_sent() {
+    fn test_stalled_read_times_out() {
         block_on_locally(async {
             // arrange
-            let (conn, mut rx) = open_connection();
+            let (_db, conn, mut data_rx, mut rx) = open_connection_with_datastore();
+            let start = tokio::time::Instant::now();
 
-            // act: send two indications simultaneously
-            let pending_send1 =
-                spawn_local(conn.as_ref().send_indication(
-                    VALID_HANDLE,
-                    AttAttributeDataChild::RawData([1, 2, 3].into()),
-                ));
-            let pending_send2 = spawn_local(conn.as_ref().send_indication(
-                ANOTHER_VALID_HANDLE,
-                AttAttributeDataChild::RawData([1, 2, 3].into()),
-            ));
-            // assert: only one was initially sent
-            assert_eq!(rx.recv().await.unwrap().opcode, AttOpcode::HANDLE_VALUE_INDICATION);
-            assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
-            // and both are still pending
-            assert!(!pending_send1.is_finished());
-            assert!(!pending_send2.is_finished());
+            // act: read an attribute, and never supply its value
+            send_read_request(&conn);
+            let MockDatastoreEvents::Read(TCB_IDX, VALID_HANDLE, _, _data_resp) =
+                data_rx.recv().await.unwrap()
+            else {
+                unreachable!();
+            };
+            let reply = rx.recv().await.unwrap();
+
+            // assert: the request failed once the default timeout elapsed
+            assert!(start.elapsed() >= DEFAULT_REQUEST_TIMEOUT);
+            assert_eq!(
+                reply._child_,
+                AttErrorResponseBuilder {
+                    opcode_in_error: AttOpcode::READ_REQUEST,
+                    handle_in_error: VALID_HANDLE.into(),
+                    error_code: AttErrorCode::UNLIKELY_ERROR,
+                }
+                .into()
+            );
         });
     }
 
     #[test]
-    fn test_queued_indications_dequeue_second() {
+    fn test_stalled_write_times_out_after_configured_timeout() {
         block_on_locally(async {
             // arrange
-            let (conn, mut rx) = open_connection();
+            let (_db, conn, mut data_rx, mut rx) = open_connection_with_datastore();
+            conn.set_request_timeout(Duration::from_secs(1));
+            let start = tokio::time::Instant::now();
 
-            // act: send two indications simultaneously
-            let pending_send1 =
-                spawn_local(conn.as_ref().send_indication(
-                    VALID_HANDLE,
-                    AttAttributeDataChild::RawData([1, 2, 3].into()),
-                ));
-            let pending_send2 = spawn_local(conn.as_ref().send_indication(
-                ANOTHER_VALID_HANDLE,
-                AttAttributeDataChild::RawData([1, 2, 3].into()),
-            ));
+            // act: write an attribute, and never complete the write
+            conn.as_ref().handle_packet(
+                build_att_view_or_crash(AttWriteRequestBuilder {
+                    handle: VALID_HANDLE.into(),
+                    value: build_att_data(AttAttributeDataChild::RawData([1, 2].into())),
+                })
+                .view(),
+            );
+            let MockDatastoreEvents::Write(TCB_IDX, VALID_HANDLE, _, _, _data_resp) =
+                data_rx.recv().await.unwrap()
+            else {
+                unreachable!();
+            };
+            let reply = rx.recv().await.unwrap();
+
+            // assert: the request failed once the configured timeout elapsed
+            assert!(start.elapsed() >= Duration::from_secs(1));
+            assert!(start.elapsed() < DEFAULT_REQUEST_TIMEOUT);
+            assert_eq!(
+                reply._child_,
+                AttErrorResponseBuilder {
+                    opcode_in_error: AttOpcode::WRITE_REQUEST,
+                    handle_in_error: VALID_HANDLE.into(),
+                    error_code: AttErrorCode::UNLIKELY_ERROR,
+                }
+                .into()
+            );
+        });
+    }
+
+    #[test]
+    fn test_late_reply_after_timeout_does_not_affect_next_transaction() {
+        block_on_locally(async {
+            // arrange: a read that timed out
+            let (_db, conn, mut data_rx, mut rx) = open_connection_with_datastore();
+            send_read_request(&conn);
+            let MockDatastoreEvents::Read(_, _, _, stale_resp) = data_rx.recv().await.unwrap()
+            else {
+                unreachable!();
+            };
+            assert_eq!(rx.recv().await.unwrap().opcode, AttOpcode::ERROR_RESPONSE);
+
+            // act: start a second read, then complete the first one late
+            send_read_request(&conn);
+            let MockDatastoreEvents::Read(_, _, _, data_resp) = data_rx.recv().await.unwrap()
+            else {
+                unreachable!();
+            };
+            let stale_result = stale_resp.send(Ok(vec![1, 2]));
+            data_resp.send(Ok(vec![3, 4])).unwrap();
+
+            // assert: the late value was discarded, and the second read got its own value
+            assert!(stale_result.is_err());
+            assert_eq!(
+                rx.recv().await.unwrap()._child_,
+                AttReadResponseBuilder {
+                    value: AttAttributeDataBuilder {
+                        _child_: AttAttributeDataChild::RawData([3, 4].into()),
+                    },
+                }
+                .into()
+            );
+            assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        });
+    }
+
+    /// An AttDatabase whose reads and writes never complete
+    #[derive(Clone)]
+    struct StalledAttDatabase;
+
+    #[async_trait(?Send)]
+    impl AttDatabase for StalledAttDatabase {
+        async fn read_attribute(&self, _: AttHandle) -> Result<AttAttributeValue, AttError> {
+            pending().await
+        }
+
+        async fn write_attribute(&self, _: AttHandle, _: u32, _: &[u8]) -> Result<(), AttError> {
+            pending().await
+        }
+
+        fn write_no_response_attribute(&self, _: AttHandle, _: &[u8]) {}
+
+        fn list_attributes(&self) -> Vec<AttAttribute> {
+            vec![AttAttribute {
+                handle: VALID_HANDLE,
+                type_: Uuid::new(0x1234),
+                permissions: AttPermissions::READABLE | AttPermissions::NOTIFY,
+            }]
+        }
+    }
+
+    /// The opcodes of the transactions that timed out
+    type TimedOutTransactions = Rc<RefCell<Vec<AttOpcode>>>;
+
+    fn open_stalled_connection() -> (
+        SharedBox<AttServerBearer<StalledAttDatabase>>,
+        UnboundedReceiver<AttBuilder>,
+        TimedOutTransactions,
+    ) {
+        let (tx, rx) = unbounded_channel();
+        let conn = SharedBox::new(AttServerBearer::new(
+            StalledAttDatabase,
+            make_signature_verifier(),
+            make_security_elevation(),
+            MAX_ATT_MTU,
+            move |packet| {
+                tx.send(packet).unwrap();
+                Ok(())
+            },
+        ));
+        let timed_out = Rc::new(RefCell::new(vec![]));
+        conn.set_on_transaction_timeout({
+            let timed_out = timed_out.clone();
+            move |opcode| timed_out.borrow_mut().push(opcode)
+        });
+        (conn, rx, timed_out)
+    }
+
+    fn send_stalled_read_request(conn: &SharedBox<AttServerBearer<StalledAttDatabase>>) {
+        conn.as_ref().handle_packet(
+            build_att_view_or_crash(AttReadRequestBuilder {
+                attribute_handle: VALID_HANDLE.into(),
+            })
+            .view(),
+        );
+    }
+
+    #[test]
+    fn test_stalled_transaction_closes_bearer() {
+        block_on_locally(async {
+            // arrange: a request timeout longer than the ATT transaction timeout
+            let (conn, mut rx, timed_out) = open_stalled_connection();
+            conn.set_request_timeout(ATT_TRANSACTION_TIMEOUT * 2);
+
+            // act: send a request that the database never completes
+            send_stalled_read_request(&conn);
+            tokio::time::sleep(ATT_TRANSACTION_TIMEOUT + Duration::from_millis(1)).await;
+
+            // assert: the bearer was closed without replying
+            assert!(conn.is_closed());
+            assert_eq!(*timed_out.borrow(), vec![AttOpcode::READ_REQUEST]);
+            assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        });
+    }
+
+    #[test]
+    fn test_bearer_open_until_transaction_timeout() {
+        block_on_locally(async {
+            // arrange
+            let (conn, _rx, timed_out) = open_stalled_connection();
+            conn.set_request_timeout(ATT_TRANSACTION_TIMEOUT * 2);
+
+            // act: send a request that the database never completes
+            send_stalled_read_request(&conn);
+            tokio::time::sleep(ATT_TRANSACTION_TIMEOUT - Duration::from_millis(1)).await;
+
+            // assert: the bearer is still open
+            assert!(!conn.is_closed());
+            assert!(timed_out.borrow().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_transaction_timeout_on_virtual_clock() {
+        block_on_locally(async {
+            // arrange: a bearer whose timers run on a virtual clock
+            let (conn, mut rx, timed_out) = open_stalled_connection();
+            let clock = VirtualClock::new();
+            conn.set_clock(Rc::new(clock.clone()));
+            conn.set_request_timeout(ATT_TRANSACTION_TIMEOUT * 2);
+
+            // act: send a request that the database never completes, and advance the
+            // clock up to the ATT transaction timeout
+            send_stalled_read_request(&conn);
+            clock.advance(ATT_TRANSACTION_TIMEOUT - Duration::from_millis(1)).await;
+            let pending = conn.pending_request();
+            let closed_early = conn.is_closed();
+            clock.advance(Duration::from_millis(1)).await;
+
+            // assert: the bearer was only closed once the timeout elapsed
+            assert_eq!(
+                pending,
+                Some((AttOpcode::READ_REQUEST, ATT_TRANSACTION_TIMEOUT - Duration::from_millis(1)))
+            );
+            assert!(!closed_early);
+            assert!(conn.is_closed());
+            assert_eq!(*timed_out.borrow(), vec![AttOpcode::READ_REQUEST]);
+            assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        });
+    }
+
+    #[test]
+    fn test_request_timeout_preempts_bearer_teardown() {
+        block_on_locally(async {
+            // arrange
+            let (conn, mut rx, timed_out) = open_stalled_connection();
+
+            // act: send a request that the database never completes, with the default
+            // request timeout
+            send_stalled_read_request(&conn);
+            let reply = rx.recv().await.unwrap();
+            tokio::time::sleep(ATT_TRANSACTION_TIMEOUT).await;
+
+            // assert: the request failed, and the bearer remains open
+            assert_eq!(reply.opcode, AttOpcode::ERROR_RESPONSE);
+            assert!(!conn.is_closed());
+            assert!(timed_out.borrow().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_closed_bearer_drops_packets() {
+        block_on_locally(async {
+            // arrange: a bearer closed by a stalled transaction
+            let (conn, mut rx, timed_out) = open_stalled_connection();
+            conn.set_request_timeout(ATT_TRANSACTION_TIMEOUT * 2);
+            send_stalled_read_request(&conn);
+            tokio::time::sleep(ATT_TRANSACTION_TIMEOUT + Duration::from_millis(1)).await;
+
+            // act: send another request, and an MTU exchange
+            send_stalled_read_request(&conn);
+            exchange_mtu_on_stalled_connection(&conn);
+            tokio::time::sleep(ATT_TRANSACTION_TIMEOUT * 3).await;
+
+            // assert: neither was answered, and the bearer was only closed once
+            assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+            assert_eq!(timed_out.borrow().len(), 1);
+        });
+    }
+
+    fn exchange_mtu_on_stalled_connection(conn: &SharedBox<AttServerBearer<StalledAttDatabase>>) {
+        conn.as_ref().handle_packet(
+            build_att_view_or_crash(AttExchangeMtuRequestBuilder { mtu: 64 }).view(),
+        );
+    }
+
+    #[test]
+    fn test_notification_fails_on_closed_bearer() {
+        block_on_locally(async {
+            // arrange: a bearer closed by a stalled transaction
+            let (conn, mut rx, _) = open_stalled_connection();
+            conn.set_request_timeout(ATT_TRANSACTION_TIMEOUT * 2);
+            send_stalled_read_request(&conn);
+            tokio::time::sleep(ATT_TRANSACTION_TIMEOUT + Duration::from_millis(1)).await;
+
+            // act: send a notification
+            let res = conn
+                .as_ref()
+                .send_notification(
+                    VALID_HANDLE,
+                    AttAttributeDataChild::RawData([1, 2].into()),
+                    Priority::Normal,
+                )
+                .await;
+
+            // assert: it was not sent
+            assert!(matches!(res, Err(NotificationError::SendError(SendError::ConnectionDropped))));
+            assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        });
+    }
+
+    #[test]
+    fn test_indication_confirmation() {
+        block_on_locally(async {
+            // arrange
+            let (conn, mut rx) = open_connection();
+
+            // act: send an indication
+            let pending_send =
+                spawn_local(conn.as_ref().send_indication(
+                    VALID_HANDLE,
+                    AttAttributeDataChild::RawData([1, 2, 3].into()),
+                ));
+            assert_eq!(rx.recv().await.unwrap().opcode, AttOpcode::HANDLE_VALUE_INDICATION);
+            assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+            // and the confirmation
+            conn.as_ref().handle_packet(
+                build_att_view_or_crash(AttHandleValueConfirmationBuilder {}).view(),
+            );
+
+            // assert: the indication was correctly sent
+            assert!(matches!(pending_send.await.unwrap(), Ok(())));
+        });
+    }
+
+    #[test]
+    fn test_sequential_indications() {
+        block_on_locally(async {
+            // arrange
+            let (conn, mut rx) = open_connection();
+
+            // act: send the first indication
+            let pending_send1 =
+                spawn_local(conn.as_ref().send_indication(
+                    VALID_HANDLE,
+                    AttAttributeDataChild::RawData([1, 2, 3].into()),
+                ));
+            // wait for/capture the outgoing packet
+            let sent1 = rx.recv().await.unwrap();
+            // send the response
+            conn.as_ref().handle_packet(
+                build_att_view_or_crash(AttHandleValueConfirmationBuilder {}).view(),
+            );
+            // send the second indication
+            let pending_send2 =
+                spawn_local(conn.as_ref().send_indication(
+                    VALID_HANDLE,
+                    AttAttributeDataChild::RawData([1, 2, 3].into()),
+                ));
+            // wait for/capture the outgoing packet
+            let sent2 = rx.recv().await.unwrap();
+            // and the response
+            conn.as_ref().handle_packet(
+                build_att_view_or_crash(AttHandleValueConfirmationBuilder {}).view(),
+            );
+
+            // assert: exactly two indications were sent
+            assert_eq!(sent1.opcode, AttOpcode::HANDLE_VALUE_INDICATION);
+            assert_eq!(sent2.opcode, AttOpcode::HANDLE_VALUE_INDICATION);
+            assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+            // and that both got successful responses
+            assert!(matches!(pending_send1.await.unwrap(), Ok(())));
+            assert!(matches!(pending_send2.await.unwrap(), Ok(())));
+        });
+    }
+
+    #[test]
+    fn test_queued_indications_only_one_sent() {
+        block_on_locally(async {
+            // arrange
+            let (conn, mut rx) = open_connection();
+
+            // act: send two indications simultaneously
+            let pending_send1 =
+                spawn_local(conn.as_ref().send_indication(
+                    VALID_HANDLE,
+                    AttAttributeDataChild::RawData([1, 2, 3].into()),
+                ));
+            let pending_send2 = spawn_local(conn.as_ref().send_indication(
+                ANOTHER_VALID_HANDLE,
+                AttAttributeDataChild::RawData([1, 2, 3].into()),
+            ));
+            // assert: only one was initially sent
+            assert_eq!(rx.recv().await.unwrap().opcode, AttOpcode::HANDLE_VALUE_INDICATION);
+            assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+            // and both are still pending
+            assert!(!pending_send1.is_finished());
+            assert!(!pending_send2.is_finished());
+        });
+    }
+
+    #[test]
+    fn test_queued_indications_dequeue_second() {
+        block_on_locally(async {
+            // arrange
+            let (conn, mut rx) = open_connection();
+
+            // act: send two indications simultaneously
+            let pending_send1 =
+                spawn_local(conn.as_ref().send_indication(
+                    VALID_HANDLE,
+                    AttAttributeDataChild::RawData([1, 2, 3].into()),
+                ));
+            let pending_send2 = spawn_local(conn.as_ref().send_indication(
+                ANOTHER_VALID_HANDLE,
+                AttAttributeDataChild::RawData([1, 2, 3].into()),
+            ));
             // wait for/capture the outgoing packet
             let sent1 = rx.recv().await.unwrap();
             // send response for the first one
@@ -594,6 +2115,858 @@ mod test {
         });
     }
 
+    #[test]
+    fn test_notification_sent() {
+        block_on_locally(async {
+            // arrange
+            let (conn, mut rx) = open_connection();
+
+            // act: send a notification
+            let res = conn
+                .as_ref()
+                .send_notification(
+                    VALID_HANDLE,
+                    AttAttributeDataChild::RawData([1, 2, 3].into()),
+                    Priority::Normal,
+                )
+                .await;
+
+            // assert: the notification was sent without waiting for a confirmation
+            assert!(matches!(res, Ok(())));
+            assert_eq!(rx.recv().await.unwrap().opcode, AttOpcode::HANDLE_VALUE_NOTIFICATION);
+            assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        });
+    }
+
+    /// Open a bearer (enhanced or not) to a client with the given features,
+    /// on a database with two notifiable attributes
+    fn open_connection_with_client_features(
+        enhanced: bool,
+        features: ClientSupportedFeatures,
+    ) -> (SharedBox<AttServerBearer<TestAttDatabase>>, UnboundedReceiver<AttBuilder>) {
+        let db = TestAttDatabase::new(
+            [VALID_HANDLE, ANOTHER_VALID_HANDLE]
+                .into_iter()
+                .map(|handle| {
+                    let permissions = AttPermissions::READABLE | AttPermissions::NOTIFY;
+                    (AttAttribute { handle, type_: Uuid::new(0x1234), permissions }, vec![])
+                })
+                .collect(),
+        );
+        db.set_client_supported_features(features);
+        let (tx, rx) = unbounded_channel();
+        let send_packet = move |packet| {
+            tx.send(packet).unwrap();
+            Ok(())
+        };
+        let conn = if enhanced {
+            AttServerBearer::new_enhanced(
+                db,
+                make_signature_verifier(),
+                make_security_elevation(),
+                64,
+                send_packet,
+            )
+        } else {
+            AttServerBearer::new(
+                db,
+                make_signature_verifier(),
+                make_security_elevation(),
+                MAX_ATT_MTU,
+                send_packet,
+            )
+        };
+        (conn.into(), rx)
+    }
+
+    fn batch() -> Vec<(AttHandle, AttAttributeDataChild)> {
+        vec![
+            (VALID_HANDLE, AttAttributeDataChild::RawData([1, 2].into())),
+            (ANOTHER_VALID_HANDLE, AttAttributeDataChild::RawData([3].into())),
+        ]
+    }
+
+    #[test]
+    fn test_multiple_notifications_packed_on_enhanced_bearer() {
+        block_on_locally(async {
+            // arrange
+            let (conn, mut rx) = open_connection_with_client_features(
+                true,
+                ClientSupportedFeatures::MULTIPLE_HANDLE_VALUE_NOTIFICATIONS,
+            );
+
+            // act
+            let res = conn.as_ref().send_multiple_notifications(batch(), Priority::Normal).await;
+
+            // assert: a single PDU carries both tuples
+            assert!(matches!(res, Ok(())));
+            assert_eq!(
+                rx.recv().await.unwrap().to_vec().unwrap(),
+                vec![0x23, 0x03, 0x00, 0x02, 0x00, 1, 2, 0x0A, 0x00, 0x01, 0x00, 3]
+            );
+            assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+            assert_eq!(conn.metrics().notifications_sent, 2);
+        });
+    }
+
+    #[test]
+    fn test_multiple_notifications_not_packed_unless_client_supports_them() {
+        block_on_locally(async {
+            // arrange
+            let (conn, mut rx) =
+                open_connection_with_client_features(true, ClientSupportedFeatures::empty());
+
+            // act
+            let res = conn.as_ref().send_multiple_notifications(batch(), Priority::Normal).await;
+
+            // assert
+            assert!(matches!(res, Ok(())));
+            assert_eq!(rx.recv().await.unwrap().opcode, AttOpcode::HANDLE_VALUE_NOTIFICATION);
+            assert_eq!(rx.recv().await.unwrap().opcode, AttOpcode::HANDLE_VALUE_NOTIFICATION);
+        });
+    }
+
+    #[test]
+    fn test_multiple_notifications_not_packed_on_unenhanced_bearer() {
+        block_on_locally(async {
+            // arrange
+            let (conn, mut rx) = open_connection_with_client_features(
+                false,
+                ClientSupportedFeatures::MULTIPLE_HANDLE_VALUE_NOTIFICATIONS,
+            );
+
+            // act
+            let res = conn.as_ref().send_multiple_notifications(batch(), Priority::Normal).await;
+
+            // assert: the notifications are sent individually, in order
+            assert!(matches!(res, Ok(())));
+            assert_eq!(rx.recv().await.unwrap().to_vec().unwrap(), vec![0x1B, 0x03, 0x00, 1, 2]);
+            assert_eq!(rx.recv().await.unwrap().to_vec().unwrap(), vec![0x1B, 0x0A, 0x00, 3]);
+        });
+    }
+
+    #[test]
+    fn test_notification_not_blocked_by_pending_indication() {
+        block_on_locally(async {
+            // arrange: an outstanding indication
+            let (conn, mut rx) = open_connection();
+            let _ =
+                try_await(conn.as_ref().send_indication(
+                    VALID_HANDLE,
+                    AttAttributeDataChild::RawData([1, 2, 3].into()),
+                ))
+                .await;
+            rx.recv().await.unwrap(); // flush rx_queue
+
+            // act: send a notification
+            let res = conn
+                .as_ref()
+                .send_notification(
+                    VALID_HANDLE,
+                    AttAttributeDataChild::RawData([1, 2, 3].into()),
+                    Priority::Normal,
+                )
+                .await;
+
+            // assert: the notification was sent
+            assert!(matches!(res, Ok(())));
+            assert_eq!(rx.recv().await.unwrap().opcode, AttOpcode::HANDLE_VALUE_NOTIFICATION);
+        });
+    }
+
+    #[test]
+    fn test_notification_unsupported() {
+        block_on_locally(async {
+            // arrange
+            let (conn, mut rx) = open_connection();
+
+            // act: send a notification on a handle that does not support them
+            let res = conn
+                .as_ref()
+                .send_notification(
+                    ANOTHER_VALID_HANDLE,
+                    AttAttributeDataChild::RawData([1, 2, 3].into()),
+                    Priority::Normal,
+                )
+                .await;
+
+            // assert: nothing was sent
+            assert!(matches!(res, Err(NotificationError::NotificationsNotSupported)));
+            assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        });
+    }
+
+    #[test]
+    fn test_notifications_congested_while_pending_mtu() {
+        block_on_locally(async {
+            // arrange: pending MTU negotiation, with the notification queue full
+            let (conn, mut rx) = open_connection();
+            conn.as_ref().handle_mtu_event(MtuEvent::OutgoingRequest).unwrap();
+            let mut pending = vec![];
+            for _ in 0..MAX_QUEUED_NOTIFICATIONS {
+                pending.push(spawn_local(conn.as_ref().send_notification(
+                    VALID_HANDLE,
+                    AttAttributeDataChild::RawData([1, 2, 3].into()),
+                    Priority::Normal,
+                )));
+            }
+
+            // act: try to send another notification
+            let res = conn
+                .as_ref()
+                .send_notification(
+                    VALID_HANDLE,
+                    AttAttributeDataChild::RawData([1, 2, 3].into()),
+                    Priority::Normal,
+                )
+                .await;
+            // then resolve the MTU negotiation
+            conn.as_ref().handle_mtu_event(MtuEvent::IncomingResponse(100)).unwrap();
+
+            // assert: the extra notification was rejected, but the queued ones were sent
+            assert!(matches!(res, Err(NotificationError::Congested)));
+            for pending in pending {
+                assert!(matches!(pending.await.unwrap(), Ok(())));
+                assert_eq!(rx.recv().await.unwrap().opcode, AttOpcode::HANDLE_VALUE_NOTIFICATION);
+            }
+            assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        });
+    }
+
+    #[test]
+    fn test_coalesced_notifications_while_pending_mtu() {
+        block_on_locally(async {
+            // arrange: a characteristic whose notifications are coalesced, with a
+            // notification queued during MTU negotiation
+            let db = make_test_db();
+            db.coalesce_notifications(VALID_HANDLE);
+            let (conn, mut rx) = open_connection_to(db);
+            conn.as_ref().handle_mtu_event(MtuEvent::OutgoingRequest).unwrap();
+            let queued = spawn_local(conn.as_ref().send_notification(
+                VALID_HANDLE,
+                AttAttributeDataChild::RawData([1].into()),
+                Priority::Normal,
+            ));
+            yield_now().await;
+
+            // act: send newer values, then resolve the MTU negotiation
+            let second = conn
+                .as_ref()
+                .send_notification(
+                    VALID_HANDLE,
+                    AttAttributeDataChild::RawData([2].into()),
+                    Priority::Normal,
+                )
+                .await;
+            let third = conn
+                .as_ref()
+                .send_notification(
+                    VALID_HANDLE,
+                    AttAttributeDataChild::RawData([3].into()),
+                    Priority::Normal,
+                )
+                .await;
+            conn.as_ref().handle_mtu_event(MtuEvent::IncomingResponse(100)).unwrap();
+
+            // assert: only the latest value was sent, and the others were counted
+            assert!(matches!(second, Ok(())));
+            assert!(matches!(third, Ok(())));
+            assert!(matches!(queued.await.unwrap(), Ok(())));
+            assert_eq!(
+                rx.recv().await.unwrap(),
+                AttBuilder {
+                    opcode: AttOpcode::HANDLE_VALUE_NOTIFICATION,
+                    _child_: AttHandleValueNotificationBuilder {
+                        handle: VALID_HANDLE.into(),
+                        value: build_att_data(AttAttributeDataChild::RawData([3].into())),
+                    }
+                    .into()
+                }
+            );
+            assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+            assert_eq!(conn.metrics().notifications_coalesced, 2);
+            assert_eq!(conn.metrics().notifications_sent, 1);
+        });
+    }
+
+    #[test]
+    fn test_queued_notifications_sent_by_priority() {
+        block_on_locally(async {
+            // arrange: pending MTU negotiation, with a low priority notification
+            // queued ahead of a high priority one
+            let (conn, mut rx) = open_connection();
+            conn.as_ref().handle_mtu_event(MtuEvent::OutgoingRequest).unwrap();
+            let pending_low = spawn_local(conn.as_ref().send_notification(
+                VALID_HANDLE,
+                AttAttributeDataChild::RawData([1].into()),
+                Priority::Low,
+            ));
+            let pending_high = spawn_local(conn.as_ref().send_notification(
+                VALID_HANDLE,
+                AttAttributeDataChild::RawData([2].into()),
+                Priority::High,
+            ));
+
+            // act: resolve the MTU negotiation
+            conn.as_ref().handle_mtu_event(MtuEvent::IncomingResponse(100)).unwrap();
+            pending_low.await.unwrap().unwrap();
+            pending_high.await.unwrap().unwrap();
+
+            // assert: the high priority notification was sent first
+            let AttChild::AttHandleValueNotification(first) = rx.recv().await.unwrap()._child_
+            else {
+                unreachable!();
+            };
+            let AttChild::AttHandleValueNotification(second) = rx.recv().await.unwrap()._child_
+            else {
+                unreachable!();
+            };
+            assert_eq!(first.value, build_att_data(AttAttributeDataChild::RawData([2].into())));
+            assert_eq!(second.value, build_att_data(AttAttributeDataChild::RawData([1].into())));
+        });
+    }
+
+    #[test]
+    fn test_pdus_traced_in_both_directions() {
+        block_on_locally(async {
+            // arrange
+            let (conn, mut rx) = open_connection();
+            let traced = Rc::new(RefCell::new(vec![]));
+            conn.set_on_pdu({
+                let traced = traced.clone();
+                move |direction, pdu| traced.borrow_mut().push((direction, pdu.get_opcode()))
+            });
+
+            // act: a request, and a notification
+            conn.as_ref().handle_packet(
+                build_att_view_or_crash(AttReadRequestBuilder {
+                    attribute_handle: VALID_HANDLE.into(),
+                })
+                .view(),
+            );
+            rx.recv().await.unwrap();
+            conn.as_ref()
+                .send_notification(
+                    VALID_HANDLE,
+                    AttAttributeDataChild::RawData([1].into()),
+                    Priority::Normal,
+                )
+                .await
+                .unwrap();
+
+            // assert
+            assert_eq!(
+                *traced.borrow(),
+                vec![
+                    (Direction::Rx, AttOpcode::READ_REQUEST),
+                    (Direction::Tx, AttOpcode::READ_RESPONSE),
+                    (Direction::Tx, AttOpcode::HANDLE_VALUE_NOTIFICATION),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn test_pending_request_tracked_until_reply() {
+        block_on_locally(async {
+            // arrange: a read whose value has not yet been supplied
+            let (_db, conn, mut data_rx, mut rx) = open_connection_with_datastore();
+            send_read_request(&conn);
+            let MockDatastoreEvents::Read(_, _, _, data_resp) = data_rx.recv().await.unwrap()
+            else {
+                unreachable!();
+            };
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            assert_eq!(
+                conn.pending_request(),
+                Some((AttOpcode::READ_REQUEST, Duration::from_millis(10)))
+            );
+
+            // act
+            data_resp.send(Ok(vec![1, 2])).unwrap();
+            rx.recv().await.unwrap();
+
+            // assert
+            assert_eq!(conn.pending_request(), None);
+        });
+    }
+
+    #[test]
+    fn test_pending_request_cleared_on_timeout() {
+        block_on_locally(async {
+            // arrange: a read whose value is never supplied
+            let (_db, conn, mut data_rx, mut rx) = open_connection_with_datastore();
+            send_read_request(&conn);
+            let MockDatastoreEvents::Read(_, _, _, _data_resp) = data_rx.recv().await.unwrap()
+            else {
+                unreachable!();
+            };
+
+            // act
+            rx.recv().await.unwrap();
+
+            // assert
+            assert_eq!(conn.pending_request(), None);
+        });
+    }
+
+    #[test]
+    fn test_metrics_count_requests_and_errors() {
+        block_on_locally(async {
+            // arrange
+            let (conn, mut rx) = open_connection();
+
+            // act: one successful read, and one failing
+            for handle in [VALID_HANDLE, INVALID_HANDLE] {
+                conn.as_ref().handle_packet(
+                    build_att_view_or_crash(AttReadRequestBuilder {
+                        attribute_handle: handle.into(),
+                    })
+                    .view(),
+                );
+                rx.recv().await.unwrap();
+            }
+
+            // assert
+            let metrics = conn.metrics();
+            assert_eq!(metrics.requests, BTreeMap::from([(u8::from(AttOpcode::READ_REQUEST), 2)]));
+            assert_eq!(
+                metrics.error_responses,
+                BTreeMap::from([(u8::from(AttErrorCode::INVALID_HANDLE), 1)])
+            );
+            assert_eq!(metrics.transactions, 2);
+        });
+    }
+
+    #[test]
+    fn test_metrics_count_bytes() {
+        block_on_locally(async {
+            // arrange
+            let (conn, mut rx) = open_connection();
+
+            // act
+            conn.as_ref().handle_packet(
+                build_att_view_or_crash(AttReadRequestBuilder {
+                    attribute_handle: VALID_HANDLE.into(),
+                })
+                .view(),
+            );
+            let reply = rx.recv().await.unwrap();
+
+            // assert: the opcode and handle were received, and the reply sent
+            let metrics = conn.metrics();
+            assert_eq!(metrics.bytes_received, 3);
+            assert_eq!(metrics.bytes_sent, reply.to_vec().unwrap().len() as u64);
+        });
+    }
+
+    #[test]
+    fn test_outstanding_indication_counted_until_confirmed() {
+        block_on_locally(async {
+            // arrange
+            let (conn, mut rx) = open_connection();
+
+            // act: send an indication, and confirm it
+            let pending_send =
+                spawn_local(conn.as_ref().send_indication(
+                    VALID_HANDLE,
+                    AttAttributeDataChild::RawData([1, 2, 3].into()),
+                ));
+            rx.recv().await.unwrap();
+            let outstanding_before_confirmation = conn.outstanding_indications();
+            conn.as_ref().handle_packet(
+                build_att_view_or_crash(AttHandleValueConfirmationBuilder {}).view(),
+            );
+            pending_send.await.unwrap().unwrap();
+
+            // assert
+            assert_eq!(outstanding_before_confirmation, 1);
+            assert_eq!(conn.outstanding_indications(), 0);
+        });
+    }
+
+    #[test]
+    fn test_metrics_record_transaction_latency() {
+        block_on_locally(async {
+            // arrange
+            let (_db, conn, mut data_rx, mut rx) = open_connection_with_datastore();
+
+            // act: supply the value of a read after 100ms
+            send_read_request(&conn);
+            let MockDatastoreEvents::Read(_, _, _, data_resp) = data_rx.recv().await.unwrap()
+            else {
+                unreachable!();
+            };
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            data_resp.send(Ok(vec![1, 2])).unwrap();
+            rx.recv().await.unwrap();
+
+            // assert
+            assert_eq!(
+                conn.metrics().average_transaction_latency(),
+                Some(Duration::from_millis(100))
+            );
+        });
+    }
+
+    #[test]
+    fn test_metrics_track_prepared_write_queue_depth() {
+        block_on_locally(async {
+            // arrange
+            let (_db, conn, _data_rx, mut rx) = open_connection_with_datastore();
+
+            // act: prepare two writes, then cancel them
+            for offset in [0, 2] {
+                conn.as_ref().handle_packet(
+                    build_att_view_or_crash(AttPrepareWriteRequestBuilder {
+                        handle: VALID_HANDLE.into(),
+                        offset,
+                        value: build_att_data(AttAttributeDataChild::RawData([1, 2].into())),
+                    })
+                    .view(),
+                );
+                rx.recv().await.unwrap();
+            }
+
+            let metrics_before_cancel = conn.metrics();
+            conn.as_ref().handle_packet(
+                build_att_view_or_crash(AttExecuteWriteRequestBuilder {
+                    flags: AttExecuteWriteFlags::CANCEL,
+                })
+                .view(),
+            );
+            rx.recv().await.unwrap();
+
+            // assert: the queue was drained by the cancellation, but its peak kept
+            assert_eq!(metrics_before_cancel.prepared_writes, 2);
+            assert_eq!(metrics_before_cancel.max_prepared_writes, 2);
+            let metrics = conn.metrics();
+            assert_eq!(metrics.prepared_writes, 0);
+            assert_eq!(metrics.max_prepared_writes, 2);
+        });
+    }
+
+    #[test]
+    fn test_metrics_count_notifications() {
+        block_on_locally(async {
+            // arrange
+            let (conn, _rx) = open_connection();
+
+            // act
+            conn.as_ref()
+                .send_notification(
+                    VALID_HANDLE,
+                    AttAttributeDataChild::RawData([1, 2, 3].into()),
+                    Priority::Normal,
+                )
+                .await
+                .unwrap();
+
+            // assert
+            let metrics = conn.metrics();
+            assert_eq!(metrics.notifications_sent, 1);
+            assert_eq!(metrics.notification_bytes_sent, 3);
+        });
+    }
+
+    #[test]
+    fn test_metrics_exclude_failed_notifications() {
+        block_on_locally(async {
+            // arrange
+            let (conn, _rx) = open_connection();
+
+            // act: notify a characteristic that does not support notifications
+            let _ = conn
+                .as_ref()
+                .send_notification(
+                    ANOTHER_VALID_HANDLE,
+                    AttAttributeDataChild::RawData([1].into()),
+                    Priority::Normal,
+                )
+                .await;
+
+            // assert
+            assert_eq!(conn.metrics().notifications_sent, 0);
+        });
+    }
+
+    fn record_events(
+        conn: &SharedBox<AttServerBearer<TestAttDatabase>>,
+    ) -> Rc<RefCell<Vec<BearerEvent>>> {
+        let events = Rc::new(RefCell::new(vec![]));
+        conn.set_on_event({
+            let events = events.clone();
+            move |event| events.borrow_mut().push(event)
+        });
+        events
+    }
+
+    #[test]
+    fn test_mtu_exchange_emits_event() {
+        block_on_locally(async {
+            // arrange
+            let (conn, mut rx) = open_connection();
+            let events = record_events(&conn);
+
+            // act
+            exchange_mtu(&conn, 100);
+            rx.recv().await.unwrap();
+
+            // assert
+            assert_eq!(*events.borrow(), vec![BearerEvent::MtuChanged(100)]);
+        });
+    }
+
+    #[test]
+    fn test_snooped_mtu_response_emits_event() {
+        block_on_locally(async {
+            // arrange
+            let (conn, _rx) = open_connection();
+            let events = record_events(&conn);
+
+            // act
+            conn.as_ref().handle_mtu_event(MtuEvent::OutgoingRequest).unwrap();
+            conn.as_ref().handle_mtu_event(MtuEvent::IncomingResponse(100)).unwrap();
+
+            // assert: only the completed exchange is reported
+            assert_eq!(*events.borrow(), vec![BearerEvent::MtuChanged(100)]);
+        });
+    }
+
+    #[test]
+    fn test_indication_confirmation_emits_event() {
+        block_on_locally(async {
+            // arrange
+            let (conn, mut rx) = open_connection();
+            let events = record_events(&conn);
+            let pending_send =
+                spawn_local(conn.as_ref().send_indication(
+                    VALID_HANDLE,
+                    AttAttributeDataChild::RawData([1, 2, 3].into()),
+                ));
+            rx.recv().await.unwrap();
+            assert!(events.borrow().is_empty());
+
+            // act
+            conn.as_ref().handle_packet(
+                build_att_view_or_crash(AttHandleValueConfirmationBuilder {}).view(),
+            );
+            pending_send.await.unwrap().unwrap();
+
+            // assert
+            assert_eq!(*events.borrow(), vec![BearerEvent::IndicationConfirmed(VALID_HANDLE)]);
+        });
+    }
+
+    #[test]
+    fn test_notification_congestion_emits_events() {
+        block_on_locally(async {
+            // arrange: pending MTU negotiation, so that notifications are queued
+            let (conn, mut rx) = open_connection();
+            let events = record_events(&conn);
+            conn.as_ref().handle_mtu_event(MtuEvent::OutgoingRequest).unwrap();
+            let mut pending = vec![];
+            for _ in 0..MAX_QUEUED_NOTIFICATIONS {
+                pending.push(spawn_local(conn.as_ref().send_notification(
+                    VALID_HANDLE,
+                    AttAttributeDataChild::RawData([1, 2, 3].into()),
+                    Priority::Normal,
+                )));
+            }
+
+            // act: overflow the queue, then drain it
+            let _ = conn
+                .as_ref()
+                .send_notification(
+                    VALID_HANDLE,
+                    AttAttributeDataChild::RawData([1, 2, 3].into()),
+                    Priority::Normal,
+                )
+                .await;
+            conn.as_ref().handle_mtu_event(MtuEvent::IncomingResponse(100)).unwrap();
+            for pending in pending {
+                pending.await.unwrap().unwrap();
+                rx.recv().await.unwrap();
+            }
+
+            // assert
+            assert_eq!(
+                *events.borrow(),
+                vec![
+                    BearerEvent::Congestion(true),
+                    BearerEvent::MtuChanged(100),
+                    BearerEvent::Congestion(false)
+                ]
+            );
+        });
+    }
+
+    /// A transport granting each credit on demand
+    #[derive(Default)]
+    struct TestBackpressure {
+        credits: Cell<usize>,
+        granted: Notify,
+    }
+
+    impl TestBackpressure {
+        fn grant(&self) {
+            self.credits.set(self.credits.get() + 1);
+            self.granted.notify_waiters();
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl TransmitBackpressure for TestBackpressure {
+        async fn wait_for_credit(&self, bearer: BearerId) {
+            assert_eq!(bearer, BearerId::unenhanced(TCB_IDX));
+            loop {
+                let granted = self.granted.notified();
+                if self.credits.get() > 0 {
+                    self.credits.set(self.credits.get() - 1);
+                    return;
+                }
+                granted.await;
+            }
+        }
+    }
+
+    fn open_connection_with_backpressure() -> (
+        SharedBox<AttServerBearer<TestAttDatabase>>,
+        UnboundedReceiver<AttBuilder>,
+        Rc<TestBackpressure>,
+    ) {
+        let (conn, rx) = open_connection();
+        let backpressure = Rc::new(TestBackpressure::default());
+        conn.set_backpressure(backpressure.clone(), BearerId::unenhanced(TCB_IDX));
+        (conn, rx, backpressure)
+    }
+
+    #[test]
+    fn test_notification_waits_for_transmit_credit() {
+        block_on_locally(async {
+            // arrange
+            let (conn, mut rx, backpressure) = open_connection_with_backpressure();
+
+            // act: send a notification, and only then grant a credit
+            let pending = spawn_local(conn.as_ref().send_notification(
+                VALID_HANDLE,
+                AttAttributeDataChild::RawData([1, 2, 3].into()),
+                Priority::Normal,
+            ));
+            yield_now().await;
+            let sent_before_credit = rx.try_recv();
+            backpressure.grant();
+
+            // assert: the notification was only sent once the credit was granted
+            assert_eq!(sent_before_credit, Err(TryRecvError::Empty));
+            assert!(matches!(pending.await.unwrap(), Ok(())));
+            assert_eq!(rx.recv().await.unwrap().opcode, AttOpcode::HANDLE_VALUE_NOTIFICATION);
+        });
+    }
+
+    #[test]
+    fn test_indication_waits_for_transmit_credit() {
+        block_on_locally(async {
+            // arrange
+            let (conn, mut rx, backpressure) = open_connection_with_backpressure();
+
+            // act: send an indication, and only then grant a credit
+            let _pending =
+                spawn_local(conn.as_ref().send_indication(
+                    VALID_HANDLE,
+                    AttAttributeDataChild::RawData([1, 2, 3].into()),
+                ));
+            yield_now().await;
+            let sent_before_credit = rx.try_recv();
+            backpressure.grant();
+
+            // assert
+            assert_eq!(sent_before_credit, Err(TryRecvError::Empty));
+            assert_eq!(rx.recv().await.unwrap().opcode, AttOpcode::HANDLE_VALUE_INDICATION);
+        });
+    }
+
+    #[test]
+    fn test_stalled_transport_rejects_further_notifications() {
+        block_on_locally(async {
+            // arrange: a transport that grants no credits
+            let (conn, mut rx, _backpressure) = open_connection_with_backpressure();
+            let mut pending = vec![];
+            for _ in 0..MAX_QUEUED_NOTIFICATIONS {
+                pending.push(spawn_local(conn.as_ref().send_notification(
+                    VALID_HANDLE,
+                    AttAttributeDataChild::RawData([1, 2, 3].into()),
+                    Priority::Normal,
+                )));
+            }
+
+            // act
+            let res = conn
+                .as_ref()
+                .send_notification(
+                    VALID_HANDLE,
+                    AttAttributeDataChild::RawData([1, 2, 3].into()),
+                    Priority::Normal,
+                )
+                .await;
+
+            // assert: nothing was sent, and the queue did not grow past its bound
+            assert!(matches!(res, Err(NotificationError::Congested)));
+            assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        });
+    }
+
+    #[test]
+    fn test_stalled_transport_emits_congestion_events() {
+        block_on_locally(async {
+            // arrange
+            let (conn, mut rx, backpressure) = open_connection_with_backpressure();
+            let events = record_events(&conn);
+
+            // act: the transport withholds a credit for longer than the threshold
+            let pending = spawn_local(conn.as_ref().send_notification(
+                VALID_HANDLE,
+                AttAttributeDataChild::RawData([1, 2, 3].into()),
+                Priority::Normal,
+            ));
+            tokio::time::sleep(TRANSMIT_CONGESTION_THRESHOLD + Duration::from_millis(1)).await;
+            let events_while_stalled = events.borrow().clone();
+            backpressure.grant();
+            pending.await.unwrap().unwrap();
+            rx.recv().await.unwrap();
+
+            // assert: congestion was reported while stalled, and cleared after
+            assert_eq!(events_while_stalled, vec![BearerEvent::Congestion(true)]);
+            assert_eq!(
+                *events.borrow(),
+                vec![BearerEvent::Congestion(true), BearerEvent::Congestion(false)]
+            );
+        });
+    }
+
+    #[test]
+    fn test_brief_transport_stall_not_reported() {
+        block_on_locally(async {
+            // arrange
+            let (conn, mut rx, backpressure) = open_connection_with_backpressure();
+            let events = record_events(&conn);
+
+            // act: the credit is granted within the threshold
+            let pending = spawn_local(conn.as_ref().send_notification(
+                VALID_HANDLE,
+                AttAttributeDataChild::RawData([1, 2, 3].into()),
+                Priority::Normal,
+            ));
+            tokio::time::sleep(TRANSMIT_CONGESTION_THRESHOLD / 2).await;
+            backpressure.grant();
+            pending.await.unwrap().unwrap();
+            rx.recv().await.unwrap();
+
+            // assert
+            assert!(events.borrow().is_empty());
+        });
+    }
+
     #[test]
     fn test_single_indication_pending_mtu() {
         block_on_locally(async {
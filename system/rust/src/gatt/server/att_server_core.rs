@@ -0,0 +1,849 @@
+//! This module contains the sans-IO core of an ATT server bearer. It decides
+//! what to do with each PDU received from the client, and with the reply
+//! produced for each request, and tracks the state those decisions depend on:
+//! the MTU, the single transaction that may be outstanding (with its ATT
+//! transaction timeout), whether the bearer was closed as a result, and the
+//! rate at which the client makes failing requests.
+//!
+//! The core performs no I/O and never waits. Each input returns the Actions
+//! that the caller must carry out, and the current time is passed in
+//! explicitly. The AttServerBearer layers the async glue (the AttDatabase, the
+//! transport, and the timers of the runtime) on top of it, and the same logic
+//! can be driven synchronously by tests, fuzzers, or a tool replaying
+//! captured packets.
+
+use std::{
+    collections::VecDeque,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use log::{error, warn};
+
+use crate::{
+    gatt::{
+        channel::ATT_TRANSACTION_TIMEOUT,
+        ids::{AttHandle, TransportIndex},
+        mtu::{AttMtu, MtuEvent},
+        opcode_types::{classify_opcode, OperationType},
+    },
+    packets::{
+        AttBuilder, AttChild, AttErrorCode, AttErrorResponseBuilder, AttOpcode, AttView,
+        OwnedAttView, Packet, Serializable,
+    },
+    utils::packet::HACK_child_to_opcode,
+};
+
+use super::{
+    att_server_bearer::BearerEvent,
+    config::{DuplicateRequestPolicy, ErrorRateLimit},
+    metrics::BearerMetrics,
+    opcode_policy::{is_exempt, OpcodePolicy},
+    pdu_decoder::{build_unsupported_request_reply, decode_pdu, DecodedPdu},
+    transactions::exchange_mtu_request::handle_exchange_mtu_request,
+};
+
+/// Something the caller of the AttServerCore must do
+pub enum Action {
+    /// Send the given PDU to the client
+    Send(AttBuilder),
+    /// Process the given request against the AttDatabase, with the given MTU
+    /// (that in use when the request arrived, as per 5.3 3F 3.4.2.2), and pass
+    /// the reply to complete_transaction(). If that has not happened by the
+    /// deadline, call handle_timeout() then.
+    StartTransaction {
+        /// The request
+        request: OwnedAttView,
+        /// The MTU with which to build the reply
+        mtu: usize,
+        /// The end of the ATT transaction timeout
+        deadline: Instant,
+        /// If the client exceeded its ErrorRateLimit, the time until which the
+        /// request is to be held back before being processed
+        not_before: Option<Instant>,
+    },
+    /// Process the given command, which gets no reply
+    ProcessCommand(OwnedAttView),
+    /// Complete the outstanding indication, which the client confirmed
+    ConfirmIndication,
+    /// Report the given event to the upper layers
+    Event(BearerEvent),
+    /// No reply to the request with the given opcode could be sent within the
+    /// ATT transaction timeout (5.3 3F 3.3.3), so the bearer is now closed, and
+    /// the underlying channel should be torn down
+    Close(AttOpcode),
+}
+
+struct PendingTransaction {
+    opcode: AttOpcode,
+    started_at: Instant,
+    deadline: Instant,
+    /// The request PDU (opcode and parameters), if it is to be remembered
+    /// once answered, to recognize its retransmission
+    pdu: Option<Vec<u8>>,
+}
+
+/// The last request answered on the bearer, with the reply that was sent
+struct AnsweredRequest {
+    pdu: Vec<u8>,
+    reply_opcode: AttOpcode,
+    reply_payload: Vec<u8>,
+    answered_at: Instant,
+}
+
+/// The state machine of a single ATT server bearer
+pub struct AttServerCore {
+    mtu: AttMtu,
+    server_rx_mtu: usize,
+    pending: Option<PendingTransaction>,
+    closed: bool,
+    metrics: BearerMetrics,
+    opcode_policy: Option<(Rc<dyn OpcodePolicy>, TransportIndex)>,
+    duplicate_request_policy: DuplicateRequestPolicy,
+    last_answered: Option<AnsweredRequest>,
+    error_rate_limit: Option<ErrorRateLimit>,
+    /// When the failed requests counting towards the ErrorRateLimit were
+    /// answered, within the last second
+    recent_errors: VecDeque<Instant>,
+    throttled: bool,
+    /// Set when the client was just found to exceed its ErrorRateLimit, until
+    /// the event is returned
+    pending_throttle_event: Option<BearerEvent>,
+}
+
+/// The window over which the rate of failed requests is measured
+const ERROR_RATE_WINDOW: Duration = Duration::from_secs(1);
+
+impl AttServerCore {
+    /// Constructor, with the MTU state of the bearer. The server_rx_mtu is
+    /// offered to the client if it exchanges the MTU.
+    pub fn new(mtu: AttMtu, server_rx_mtu: usize) -> Self {
+        Self {
+            mtu,
+            server_rx_mtu,
+            pending: None,
+            closed: false,
+            metrics: Default::default(),
+            opcode_policy: None,
+            duplicate_request_policy: DuplicateRequestPolicy::Process,
+            last_answered: None,
+            error_rate_limit: None,
+            recent_errors: VecDeque::new(),
+            throttled: false,
+            pending_throttle_event: None,
+        }
+    }
+
+    /// Consult the given OpcodePolicy, for the client on the given transport,
+    /// before dispatching each subsequent request or command
+    pub fn set_opcode_policy(&mut self, policy: Rc<dyn OpcodePolicy>, tcb_idx: TransportIndex) {
+        self.opcode_policy = Some((policy, tcb_idx));
+    }
+
+    /// Set what to do with subsequent write requests identical to the last
+    /// request answered
+    pub fn set_duplicate_request_policy(&mut self, policy: DuplicateRequestPolicy) {
+        self.duplicate_request_policy = policy;
+    }
+
+    /// Set the limit on the rate of failed requests of the client, if any
+    pub fn set_error_rate_limit(&mut self, limit: Option<ErrorRateLimit>) {
+        self.error_rate_limit = limit;
+    }
+
+    /// Whether the client currently exceeds its ErrorRateLimit, so its
+    /// requests are held back
+    pub fn is_throttled(&self) -> bool {
+        self.throttled
+    }
+
+    /// The MTU state of the bearer
+    pub fn mtu(&self) -> &AttMtu {
+        &self.mtu
+    }
+
+    /// Whether the bearer was closed, since a transaction on it timed out.
+    /// Once closed, incoming PDUs are dropped.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// The opcode of the request currently being processed, if any, and how
+    /// long ago it was received
+    pub fn pending_request(&self, now: Instant) -> Option<(AttOpcode, Duration)> {
+        self.pending.as_ref().map(|pending| (pending.opcode, now - pending.started_at))
+    }
+
+    /// The time at which handle_timeout() must next be called, if any
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.as_ref().filter(|_| !self.closed).map(|pending| pending.deadline)
+    }
+
+    /// The counters of the traffic on the bearer (except its uptime)
+    pub fn metrics(&self) -> &BearerMetrics {
+        &self.metrics
+    }
+
+    /// The counters of the traffic on the bearer, to record the traffic that
+    /// does not go through the core (i.e. notifications)
+    pub fn metrics_mut(&mut self) -> &mut BearerMetrics {
+        &mut self.metrics
+    }
+
+    /// Handle a PDU received from the client that has not been parsed yet,
+    /// rejecting or dropping it if it is malformed (see the pdu_decoder
+    /// module)
+    pub fn handle_rx_pdu(&mut self, pdu: &[u8], now: Instant) -> Vec<Action> {
+        let actions = match decode_pdu(pdu) {
+            DecodedPdu::Parsed(packet) => self.dispatch_rx_packet(packet.view(), now),
+            DecodedPdu::UnsupportedRequest(opcode) => {
+                if self.closed {
+                    warn!("dropping request {opcode:#04x} received on closed bearer");
+                    return vec![];
+                }
+                self.record_error(AttErrorCode::REQUEST_NOT_SUPPORTED, now);
+                vec![Action::Send(build_unsupported_request_reply(opcode))]
+            }
+            DecodedPdu::Ignored => vec![],
+        };
+        self.with_throttle_event(actions)
+    }
+
+    /// Handle a packet received from the client
+    pub fn handle_rx_packet(&mut self, packet: AttView<'_>, now: Instant) -> Vec<Action> {
+        let actions = self.dispatch_rx_packet(packet, now);
+        self.with_throttle_event(actions)
+    }
+
+    fn dispatch_rx_packet(&mut self, packet: AttView<'_>, now: Instant) -> Vec<Action> {
+        let opcode = packet.get_opcode();
+        if self.closed {
+            warn!("dropping {opcode:?} received on closed bearer");
+            return vec![];
+        }
+        match classify_opcode(opcode) {
+            OperationType::Command => {
+                self.metrics.on_request(opcode);
+                if let Err(error_code) = self.check_opcode(opcode) {
+                    warn!("dropping {opcode:?} rejected by the opcode policy ({error_code:?})");
+                    return vec![];
+                }
+                vec![Action::ProcessCommand(packet.to_owned_packet())]
+            }
+            OperationType::Request if opcode == AttOpcode::EXCHANGE_MTU_REQUEST => {
+                self.metrics.on_request(opcode);
+                self.last_answered = None;
+                let reply = handle_exchange_mtu_request(packet, &self.mtu, self.server_rx_mtu);
+                let exchanged = matches!(reply, AttChild::AttExchangeMtuResponse(_));
+                let mut actions = vec![self.reply(reply, now)];
+                if exchanged {
+                    actions.push(Action::Event(BearerEvent::MtuChanged(
+                        self.mtu.snapshot_or_default(),
+                    )));
+                }
+                actions
+            }
+            OperationType::Request => {
+                self.metrics.on_request(opcode);
+                if self.pending.is_some() {
+                    warn!("multiple ATT operations cannot simultaneously take place, dropping one");
+                    // TODO(aryarahul) - disconnect connection here;
+                    return vec![];
+                }
+                if let Err(error_code) = self.check_opcode(opcode) {
+                    return vec![self.reply(
+                        AttErrorResponseBuilder {
+                            opcode_in_error: opcode,
+                            handle_in_error: AttHandle(0).into(),
+                            error_code,
+                        }
+                        .into(),
+                        now,
+                    )];
+                }
+                let pdu = self.remembered_pdu(packet);
+                if let Some(reply) = self.reply_to_retransmission(pdu.as_deref(), now) {
+                    warn!("resending the reply to {opcode:?} retransmitted by the client");
+                    return vec![Action::Send(reply)];
+                }
+                self.last_answered = None;
+                let deadline = now + ATT_TRANSACTION_TIMEOUT;
+                self.pending = Some(PendingTransaction { opcode, started_at: now, deadline, pdu });
+                vec![Action::StartTransaction {
+                    request: packet.to_owned_packet(),
+                    mtu: self.mtu.snapshot_or_default(),
+                    deadline,
+                    not_before: self.throttle_until(now),
+                }]
+            }
+            OperationType::Confirmation => vec![Action::ConfirmIndication],
+            OperationType::Response | OperationType::Notification | OperationType::Indication => {
+                // a client should never send these to a server, so drop them silently
+                warn!("dropping unexpected {opcode:?} sent by client");
+                vec![]
+            }
+        }
+    }
+
+    /// Complete the outstanding transaction with the reply produced for its
+    /// request, with the depth that the prepared write queue reached. If the
+    /// reply cannot be serialized, the request is failed with UNLIKELY_ERROR
+    /// instead, so the client is not left waiting.
+    pub fn complete_transaction(
+        &mut self,
+        reply: AttBuilder,
+        prepared_write_queue_depth: usize,
+        now: Instant,
+    ) -> Vec<Action> {
+        if self.closed {
+            warn!("dropping reply to {:?} on closed bearer", reply.opcode);
+            return vec![];
+        }
+        let Some(pending) = self.pending.take() else {
+            error!("dropping {:?} produced outside of any transaction", reply.opcode);
+            return vec![];
+        };
+        let (reply, serialized) = match reply.to_vec() {
+            Ok(serialized) => (reply, Some(serialized)),
+            Err(err) => {
+                error!("serializer failure {err:?}, dropping packet and sending failed reply");
+                let reply = AttBuilder {
+                    opcode: AttOpcode::ERROR_RESPONSE,
+                    _child_: AttErrorResponseBuilder {
+                        opcode_in_error: pending.opcode,
+                        handle_in_error: AttHandle(0).into(),
+                        error_code: AttErrorCode::UNLIKELY_ERROR,
+                    }
+                    .into(),
+                };
+                (reply, None)
+            }
+        };
+        if let (Some(pdu), Some(serialized)) = (pending.pdu, serialized) {
+            self.last_answered = Some(AnsweredRequest {
+                pdu,
+                reply_opcode: reply.opcode,
+                reply_payload: serialized[1..].to_vec(),
+                answered_at: now,
+            });
+        }
+        self.record_reply(&reply._child_, now);
+        self.metrics.on_transaction_complete(now - pending.started_at, prepared_write_queue_depth);
+        self.with_throttle_event(vec![Action::Send(reply)])
+    }
+
+    /// Handle the passage of time. If the outstanding transaction is past its
+    /// deadline, the bearer is closed.
+    pub fn handle_timeout(&mut self, now: Instant) -> Vec<Action> {
+        match &self.pending {
+            Some(pending) if !self.closed && now >= pending.deadline => {
+                error!(
+                    "no reply to {:?} within {ATT_TRANSACTION_TIMEOUT:?}, closing bearer",
+                    pending.opcode
+                );
+                self.closed = true;
+                vec![Action::Close(pending.opcode)]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Handle a snooped MTU event, to update the MTU we use for our various
+    /// operations
+    pub fn handle_mtu_event(&mut self, mtu_event: MtuEvent) -> Result<Vec<Action>> {
+        let exchanged =
+            matches!(mtu_event, MtuEvent::IncomingResponse(_) | MtuEvent::IncomingRequest(_));
+        self.mtu.handle_event(mtu_event)?;
+        if exchanged {
+            return Ok(vec![Action::Event(BearerEvent::MtuChanged(
+                self.mtu.snapshot_or_default(),
+            ))]);
+        }
+        Ok(vec![])
+    }
+
+    /// The PDU of the given request, if its retransmission is to be recognized
+    /// once it is answered (i.e. it is a write, and duplicates are not simply
+    /// processed again)
+    fn remembered_pdu(&self, packet: AttView<'_>) -> Option<Vec<u8>> {
+        let opcode = packet.get_opcode();
+        if self.duplicate_request_policy == DuplicateRequestPolicy::Process
+            || !matches!(
+                opcode,
+                AttOpcode::WRITE_REQUEST
+                    | AttOpcode::PREPARE_WRITE_REQUEST
+                    | AttOpcode::EXECUTE_WRITE_REQUEST
+            )
+        {
+            return None;
+        }
+        Some(std::iter::once(u8::from(opcode)).chain(packet.get_raw_payload()).collect())
+    }
+
+    /// The reply to resend, if the request with the given PDU is a
+    /// retransmission of the last request answered
+    fn reply_to_retransmission(&self, pdu: Option<&[u8]>, now: Instant) -> Option<AttBuilder> {
+        let DuplicateRequestPolicy::ResendResponse { window } = self.duplicate_request_policy
+        else {
+            return None;
+        };
+        let answered = self.last_answered.as_ref()?;
+        if pdu? != answered.pdu || now.saturating_duration_since(answered.answered_at) > window {
+            return None;
+        }
+        Some(AttBuilder {
+            opcode: answered.reply_opcode,
+            _child_: AttChild::RawData(answered.reply_payload.clone().into()),
+        })
+    }
+
+    fn check_opcode(&self, opcode: AttOpcode) -> Result<(), AttErrorCode> {
+        match &self.opcode_policy {
+            Some((policy, tcb_idx)) if !is_exempt(opcode) => policy.check_opcode(*tcb_idx, opcode),
+            _ => Ok(()),
+        }
+    }
+
+    fn reply(&mut self, reply: AttChild, now: Instant) -> Action {
+        self.record_reply(&reply, now);
+        Action::Send(AttBuilder { opcode: HACK_child_to_opcode(&reply), _child_: reply })
+    }
+
+    fn record_reply(&mut self, reply: &AttChild, now: Instant) {
+        if let AttChild::AttErrorResponse(error_response) = reply {
+            self.record_error(error_response.error_code, now);
+        }
+    }
+
+    /// Count a failed request towards the ErrorRateLimit, and start
+    /// throttling the client if it is now over it
+    fn record_error(&mut self, error_code: AttErrorCode, now: Instant) {
+        self.metrics.on_error_response(error_code);
+        let Some(limit) = self.error_rate_limit else {
+            return;
+        };
+        if !ErrorRateLimit::counts(error_code) {
+            return;
+        }
+        self.recent_errors.push_back(now);
+        self.forget_old_errors(now);
+        if !self.throttled && self.recent_errors.len() > limit.max_errors_per_second {
+            warn!(
+                "client made {} failing requests within {ERROR_RATE_WINDOW:?}, throttling it",
+                self.recent_errors.len()
+            );
+            self.throttled = true;
+            self.pending_throttle_event =
+                Some(BearerEvent::ErrorRateExceeded(self.recent_errors.len()));
+        }
+    }
+
+    fn forget_old_errors(&mut self, now: Instant) {
+        while let Some(at) = self.recent_errors.front() {
+            if now.saturating_duration_since(*at) < ERROR_RATE_WINDOW {
+                break;
+            }
+            self.recent_errors.pop_front();
+        }
+    }
+
+    /// The time until which a request received now is to be held back, if
+    /// the client is (still) over its ErrorRateLimit
+    fn throttle_until(&mut self, now: Instant) -> Option<Instant> {
+        let limit = self.error_rate_limit?;
+        self.forget_old_errors(now);
+        if self.recent_errors.len() <= limit.max_errors_per_second {
+            self.throttled = false;
+        }
+        self.throttled.then_some(now + limit.response_delay)
+    }
+
+    fn with_throttle_event(&mut self, mut actions: Vec<Action>) -> Vec<Action> {
+        if let Some(event) = self.pending_throttle_event.take() {
+            actions.push(Action::Event(event));
+        }
+        actions
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        gatt::{mtu::DEFAULT_ATT_MTU, server::opcode_policy::RestrictedPeers},
+        packets::{
+            AttAttributeDataChild, AttExchangeMtuRequestBuilder, AttHandleValueConfirmationBuilder,
+            AttReadRequestBuilder, AttReadResponseBuilder, AttWriteCommandBuilder,
+            AttWriteRequestBuilder, AttWriteResponseBuilder,
+        },
+        utils::packet::{build_att_data, build_att_view_or_crash},
+    };
+
+    use super::*;
+
+    const SERVER_RX_MTU: usize = 64;
+
+    fn read_request() -> Vec<u8> {
+        AttBuilder {
+            opcode: AttOpcode::READ_REQUEST,
+            _child_: AttReadRequestBuilder { attribute_handle: AttHandle(3).into() }.into(),
+        }
+        .to_vec()
+        .unwrap()
+    }
+
+    fn read_response() -> AttBuilder {
+        AttBuilder {
+            opcode: AttOpcode::READ_RESPONSE,
+            _child_: AttReadResponseBuilder {
+                value: build_att_data(AttAttributeDataChild::RawData([1, 2].into())),
+            }
+            .into(),
+        }
+    }
+
+    fn sent(actions: &[Action]) -> Vec<Vec<u8>> {
+        actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::Send(packet) => Some(packet.to_vec().unwrap()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_request_starts_transaction() {
+        // arrange
+        let mut core = AttServerCore::new(AttMtu::new(), SERVER_RX_MTU);
+        let now = Instant::now();
+
+        // act
+        let actions = core.handle_rx_pdu(&read_request(), now);
+
+        // assert
+        let [Action::StartTransaction { request, mtu, deadline, not_before: None }] =
+            actions.as_slice()
+        else {
+            unreachable!()
+        };
+        assert_eq!(request.view().get_opcode(), AttOpcode::READ_REQUEST);
+        assert_eq!(*mtu, DEFAULT_ATT_MTU);
+        assert_eq!(*deadline, now + ATT_TRANSACTION_TIMEOUT);
+        assert_eq!(core.next_deadline(), Some(now + ATT_TRANSACTION_TIMEOUT));
+    }
+
+    #[test]
+    fn test_reply_completes_transaction() {
+        // arrange
+        let mut core = AttServerCore::new(AttMtu::new(), SERVER_RX_MTU);
+        let now = Instant::now();
+        core.handle_rx_pdu(&read_request(), now);
+
+        // act
+        let actions = core.complete_transaction(read_response(), 0, now + Duration::from_secs(1));
+
+        // assert
+        assert_eq!(sent(&actions), vec![read_response().to_vec().unwrap()]);
+        assert!(core.pending_request(now).is_none());
+        assert_eq!(core.metrics().transactions, 1);
+    }
+
+    #[test]
+    fn test_concurrent_request_dropped() {
+        let mut core = AttServerCore::new(AttMtu::new(), SERVER_RX_MTU);
+        let now = Instant::now();
+        core.handle_rx_pdu(&read_request(), now);
+
+        let actions = core.handle_rx_pdu(&read_request(), now);
+
+        assert!(actions.is_empty());
+    }
+
+    #[test]
+    fn test_transaction_timeout_closes_bearer() {
+        // arrange
+        let mut core = AttServerCore::new(AttMtu::new(), SERVER_RX_MTU);
+        let now = Instant::now();
+        core.handle_rx_pdu(&read_request(), now);
+        let early = core.handle_timeout(now + ATT_TRANSACTION_TIMEOUT - Duration::from_millis(1));
+
+        // act
+        let actions = core.handle_timeout(now + ATT_TRANSACTION_TIMEOUT);
+
+        // assert: the bearer is closed, so a late reply and later requests are dropped
+        assert!(early.is_empty());
+        assert!(matches!(actions.as_slice(), [Action::Close(AttOpcode::READ_REQUEST)]));
+        assert!(core.is_closed());
+        assert_eq!(core.next_deadline(), None);
+        assert!(core.complete_transaction(read_response(), 0, now).is_empty());
+        assert!(core.handle_rx_pdu(&read_request(), now).is_empty());
+    }
+
+    const DUPLICATE_WINDOW: Duration = Duration::from_secs(1);
+
+    fn write_request(value: u8) -> OwnedAttView {
+        build_att_view_or_crash(AttWriteRequestBuilder {
+            handle: AttHandle(3).into(),
+            value: build_att_data(AttAttributeDataChild::RawData([value].into())),
+        })
+    }
+
+    fn write_response() -> AttBuilder {
+        AttBuilder { opcode: AttOpcode::WRITE_RESPONSE, _child_: AttWriteResponseBuilder {}.into() }
+    }
+
+    fn core_answering_retransmissions() -> AttServerCore {
+        let mut core = AttServerCore::new(AttMtu::new(), SERVER_RX_MTU);
+        core.set_duplicate_request_policy(DuplicateRequestPolicy::ResendResponse {
+            window: DUPLICATE_WINDOW,
+        });
+        core
+    }
+
+    #[test]
+    fn test_retransmitted_write_answered_again() {
+        // arrange: a write that was answered
+        let mut core = core_answering_retransmissions();
+        let now = Instant::now();
+        core.handle_rx_packet(write_request(1).view(), now);
+        core.complete_transaction(write_response(), 0, now);
+
+        // act: the client sends it again
+        let actions = core.handle_rx_packet(write_request(1).view(), now + DUPLICATE_WINDOW);
+
+        // assert: the reply is resent without processing the write again
+        assert_eq!(sent(&actions), vec![write_response().to_vec().unwrap()]);
+        assert!(core.pending_request(now).is_none());
+    }
+
+    #[test]
+    fn test_duplicate_write_processed_by_default() {
+        let mut core = AttServerCore::new(AttMtu::new(), SERVER_RX_MTU);
+        let now = Instant::now();
+        core.handle_rx_packet(write_request(1).view(), now);
+        core.complete_transaction(write_response(), 0, now);
+
+        let actions = core.handle_rx_packet(write_request(1).view(), now);
+
+        assert!(matches!(actions.as_slice(), [Action::StartTransaction { .. }]));
+    }
+
+    #[test]
+    fn test_distinct_or_late_write_processed() {
+        // arrange
+        let mut core = core_answering_retransmissions();
+        let now = Instant::now();
+        core.handle_rx_packet(write_request(1).view(), now);
+        core.complete_transaction(write_response(), 0, now);
+
+        // act: write another value, and then that one again after the window
+        let distinct = core.handle_rx_packet(write_request(2).view(), now);
+        core.complete_transaction(write_response(), 0, now);
+        let later = now + DUPLICATE_WINDOW + Duration::from_millis(1);
+        let late = core.handle_rx_packet(write_request(2).view(), later);
+
+        // assert: both were processed
+        assert!(matches!(distinct.as_slice(), [Action::StartTransaction { .. }]));
+        assert!(matches!(late.as_slice(), [Action::StartTransaction { .. }]));
+    }
+
+    #[test]
+    fn test_repeated_read_processed() {
+        let mut core = core_answering_retransmissions();
+        let now = Instant::now();
+        core.handle_rx_pdu(&read_request(), now);
+        core.complete_transaction(read_response(), 0, now);
+
+        let actions = core.handle_rx_pdu(&read_request(), now);
+
+        assert!(matches!(actions.as_slice(), [Action::StartTransaction { .. }]));
+    }
+
+    #[test]
+    fn test_mtu_exchange() {
+        let mut core = AttServerCore::new(AttMtu::new(), SERVER_RX_MTU);
+        let request = build_att_view_or_crash(AttExchangeMtuRequestBuilder { mtu: 128 });
+
+        let actions = core.handle_rx_packet(request.view(), Instant::now());
+
+        assert!(matches!(
+            actions.as_slice(),
+            [Action::Send(_), Action::Event(BearerEvent::MtuChanged(SERVER_RX_MTU))]
+        ));
+        assert_eq!(core.mtu().snapshot_or_default(), SERVER_RX_MTU);
+    }
+
+    #[test]
+    fn test_unsupported_request_rejected() {
+        let mut core = AttServerCore::new(AttMtu::new(), SERVER_RX_MTU);
+
+        let actions = core.handle_rx_pdu(&[0x14, 0x01, 0x02], Instant::now());
+
+        assert_eq!(sent(&actions), vec![vec![0x01, 0x14, 0x00, 0x00, 0x06]]);
+    }
+
+    #[test]
+    fn test_commands_and_confirmations_dispatched() {
+        let mut core = AttServerCore::new(AttMtu::new(), SERVER_RX_MTU);
+        let command = build_att_view_or_crash(AttWriteCommandBuilder {
+            handle: AttHandle(3).into(),
+            value: build_att_data(AttAttributeDataChild::RawData([1].into())),
+        });
+        let confirmation = build_att_view_or_crash(AttHandleValueConfirmationBuilder {});
+
+        let command_actions = core.handle_rx_packet(command.view(), Instant::now());
+        let confirmation_actions = core.handle_rx_packet(confirmation.view(), Instant::now());
+
+        assert!(matches!(command_actions.as_slice(), [Action::ProcessCommand(_)]));
+        assert!(matches!(confirmation_actions.as_slice(), [Action::ConfirmIndication]));
+        assert!(core.next_deadline().is_none());
+    }
+
+    #[test]
+    fn test_request_rejected_by_opcode_policy() {
+        // arrange: a restricted client, which may read but not write
+        let mut core = AttServerCore::new(AttMtu::new(), SERVER_RX_MTU);
+        let policy = RestrictedPeers::new();
+        policy.restrict(TransportIndex(1));
+        core.set_opcode_policy(Rc::new(policy), TransportIndex(1));
+        let write = build_att_view_or_crash(AttWriteRequestBuilder {
+            handle: AttHandle(3).into(),
+            value: build_att_data(AttAttributeDataChild::RawData([1].into())),
+        });
+
+        // act
+        let write_actions = core.handle_rx_packet(write.view(), Instant::now());
+        let read_actions = core.handle_rx_pdu(&read_request(), Instant::now());
+
+        // assert: the write is rejected without starting a transaction
+        assert_eq!(sent(&write_actions), vec![vec![0x01, 0x12, 0x00, 0x00, 0x06]]);
+        assert!(matches!(read_actions.as_slice(), [Action::StartTransaction { .. }]));
+    }
+
+    #[test]
+    fn test_command_dropped_by_opcode_policy() {
+        let mut core = AttServerCore::new(AttMtu::new(), SERVER_RX_MTU);
+        let policy = RestrictedPeers::new();
+        policy.restrict(TransportIndex(1));
+        core.set_opcode_policy(Rc::new(policy), TransportIndex(1));
+        let command = build_att_view_or_crash(AttWriteCommandBuilder {
+            handle: AttHandle(3).into(),
+            value: build_att_data(AttAttributeDataChild::RawData([1].into())),
+        });
+
+        let actions = core.handle_rx_packet(command.view(), Instant::now());
+
+        assert!(actions.is_empty());
+    }
+
+    const ERROR_RATE_LIMIT: ErrorRateLimit =
+        ErrorRateLimit { max_errors_per_second: 2, response_delay: Duration::from_millis(100) };
+
+    fn error_response(error_code: AttErrorCode) -> AttBuilder {
+        AttBuilder {
+            opcode: AttOpcode::ERROR_RESPONSE,
+            _child_: AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::READ_REQUEST,
+                handle_in_error: AttHandle(3).into(),
+                error_code,
+            }
+            .into(),
+        }
+    }
+
+    /// Make a read request failing with the given error, returning the actions
+    /// of its completion
+    fn failed_read(
+        core: &mut AttServerCore,
+        error_code: AttErrorCode,
+        now: Instant,
+    ) -> Vec<Action> {
+        core.handle_rx_pdu(&read_request(), now);
+        core.complete_transaction(error_response(error_code), 0, now)
+    }
+
+    #[test]
+    fn test_error_rate_limit_throttles_client() {
+        // arrange
+        let mut core = AttServerCore::new(AttMtu::new(), SERVER_RX_MTU);
+        core.set_error_rate_limit(Some(ERROR_RATE_LIMIT));
+        let now = Instant::now();
+
+        // act: fail three requests within a second
+        let under_limit = failed_read(&mut core, AttErrorCode::READ_NOT_PERMITTED, now);
+        failed_read(&mut core, AttErrorCode::INVALID_HANDLE, now);
+        let over_limit = failed_read(&mut core, AttErrorCode::INVALID_HANDLE, now);
+        let actions = core.handle_rx_pdu(&read_request(), now);
+
+        // assert: the event is emitted once the limit is exceeded, and the next
+        // request is held back
+        assert!(matches!(under_limit.as_slice(), [Action::Send(_)]));
+        assert!(matches!(
+            over_limit.as_slice(),
+            [Action::Send(_), Action::Event(BearerEvent::ErrorRateExceeded(3))]
+        ));
+        assert!(core.is_throttled());
+        let [Action::StartTransaction { not_before, .. }] = actions.as_slice() else {
+            unreachable!()
+        };
+        assert_eq!(*not_before, Some(now + ERROR_RATE_LIMIT.response_delay));
+    }
+
+    #[test]
+    fn test_throttling_lifted_once_rate_drops() {
+        // arrange: a throttled client
+        let mut core = AttServerCore::new(AttMtu::new(), SERVER_RX_MTU);
+        core.set_error_rate_limit(Some(ERROR_RATE_LIMIT));
+        let now = Instant::now();
+        for _ in 0..3 {
+            failed_read(&mut core, AttErrorCode::INVALID_HANDLE, now);
+        }
+
+        // act: wait for a second before the next request
+        let actions = core.handle_rx_pdu(&read_request(), now + ERROR_RATE_WINDOW);
+
+        // assert
+        assert!(matches!(actions.as_slice(), [Action::StartTransaction { not_before: None, .. }]));
+        assert!(!core.is_throttled());
+    }
+
+    #[test]
+    fn test_discovery_errors_not_counted() {
+        let mut core = AttServerCore::new(AttMtu::new(), SERVER_RX_MTU);
+        core.set_error_rate_limit(Some(ERROR_RATE_LIMIT));
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            failed_read(&mut core, AttErrorCode::ATTRIBUTE_NOT_FOUND, now);
+        }
+
+        assert!(!core.is_throttled());
+    }
+
+    #[test]
+    fn test_unsupported_requests_counted() {
+        let mut core = AttServerCore::new(AttMtu::new(), SERVER_RX_MTU);
+        core.set_error_rate_limit(Some(ERROR_RATE_LIMIT));
+        let now = Instant::now();
+
+        let actions = (0..3).map(|_| core.handle_rx_pdu(&[0x14], now)).last().unwrap();
+
+        assert!(matches!(
+            actions.as_slice(),
+            [Action::Send(_), Action::Event(BearerEvent::ErrorRateExceeded(3))]
+        ));
+    }
+
+    #[test]
+    fn test_no_error_rate_limit_by_default() {
+        let mut core = AttServerCore::new(AttMtu::new(), SERVER_RX_MTU);
+        let now = Instant::now();
+
+        for _ in 0..10 {
+            failed_read(&mut core, AttErrorCode::INVALID_HANDLE, now);
+        }
+
+        assert!(!core.is_throttled());
+    }
+}
@@ -0,0 +1,116 @@
+//! Attributes flagged as requiring authorization may only be accessed by
+//! clients that the application (or the user) has approved. The application
+//! is consulted through an AuthorizationProvider, and its grants are cached
+//! for the rest of the connection.
+
+use std::collections::{HashMap, HashSet};
+
+use async_trait::async_trait;
+
+use crate::gatt::ids::{AttHandle, TransportIndex};
+
+/// The kind of access to an attribute that requires authorization
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AttributeAccess {
+    /// The client is reading the attribute value
+    Read,
+    /// The client is writing the attribute value
+    Write,
+}
+
+/// Decides whether a client may access an attribute requiring authorization
+#[async_trait(?Send)]
+pub trait AuthorizationProvider {
+    /// Whether the client on the given transport may access the given
+    /// attribute. This may take arbitrarily long (e.g. to ask the user), and
+    /// the request is held until it resolves. If authorization is denied, the
+    /// request fails with INSUFFICIENT_AUTHORIZATION.
+    async fn authorize(
+        &self,
+        tcb_idx: TransportIndex,
+        handle: AttHandle,
+        access: AttributeAccess,
+    ) -> bool;
+}
+
+/// The authorization grants of each connected client. Grants last until the
+/// client disconnects, and are tied to the registration of the attribute, so
+/// they do not carry over to a new attribute reusing the handle.
+#[derive(Default)]
+pub struct AuthorizationGrants {
+    grants: HashMap<TransportIndex, HashSet<(AttHandle, u64)>>,
+}
+
+impl AuthorizationGrants {
+    /// Whether the client on the given transport has been authorized to access
+    /// the given attribute, registered as specified
+    pub fn is_granted(
+        &self,
+        tcb_idx: TransportIndex,
+        handle: AttHandle,
+        registration: u64,
+    ) -> bool {
+        self.grants
+            .get(&tcb_idx)
+            .map(|grants| grants.contains(&(handle, registration)))
+            .unwrap_or(false)
+    }
+
+    /// Record that the client on the given transport has been authorized to
+    /// access the given attribute
+    pub fn grant(&mut self, tcb_idx: TransportIndex, handle: AttHandle, registration: u64) {
+        self.grants.entry(tcb_idx).or_default().insert((handle, registration));
+    }
+
+    /// Whether the client on the given transport holds any grant
+    pub fn has_grants(&self, tcb_idx: TransportIndex) -> bool {
+        self.grants.contains_key(&tcb_idx)
+    }
+
+    /// Forget all the grants of a client, once it disconnects
+    pub fn on_le_disconnect(&mut self, tcb_idx: TransportIndex) {
+        self.grants.remove(&tcb_idx);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TCB_IDX: TransportIndex = TransportIndex(1);
+    const ANOTHER_TCB_IDX: TransportIndex = TransportIndex(2);
+    const HANDLE: AttHandle = AttHandle(3);
+    const REGISTRATION: u64 = 4;
+
+    #[test]
+    fn test_grant() {
+        let mut grants = AuthorizationGrants::default();
+
+        grants.grant(TCB_IDX, HANDLE, REGISTRATION);
+
+        assert!(grants.is_granted(TCB_IDX, HANDLE, REGISTRATION));
+        assert!(!grants.is_granted(ANOTHER_TCB_IDX, HANDLE, REGISTRATION));
+    }
+
+    #[test]
+    fn test_grant_not_reused_by_new_registration() {
+        let mut grants = AuthorizationGrants::default();
+
+        grants.grant(TCB_IDX, HANDLE, REGISTRATION);
+
+        assert!(!grants.is_granted(TCB_IDX, HANDLE, REGISTRATION + 1));
+    }
+
+    #[test]
+    fn test_grants_cleared_on_disconnect() {
+        let mut grants = AuthorizationGrants::default();
+        grants.grant(TCB_IDX, HANDLE, REGISTRATION);
+        grants.grant(ANOTHER_TCB_IDX, HANDLE, REGISTRATION);
+
+        grants.on_le_disconnect(TCB_IDX);
+
+        assert!(!grants.is_granted(TCB_IDX, HANDLE, REGISTRATION));
+        assert!(!grants.has_grants(TCB_IDX));
+        assert!(grants.is_granted(ANOTHER_TCB_IDX, HANDLE, REGISTRATION));
+    }
+}
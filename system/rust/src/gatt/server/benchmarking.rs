@@ -0,0 +1,31 @@
+//! This module exposes the handlers compared by benches/read_by_type.rs: the
+//! handler of READ_BY_TYPE_REQ, and the one it replaced. Both are run on the
+//! same database and request, and serialize their response as the bearer would
+//! before sending it.
+
+use crate::packets::{AttReadByTypeRequestView, ParseError, Serializable};
+
+use super::{att_database::AttDatabase, transactions::read_by_type_request};
+
+/// Serve a READ_BY_TYPE_REQ by writing the response into a single MTU-sized
+/// buffer, as the server does
+pub async fn read_by_type(
+    request: AttReadByTypeRequestView<'_>,
+    mtu: usize,
+    db: &impl AttDatabase,
+) -> Result<Vec<u8>, ParseError> {
+    let response = read_by_type_request::handle_read_by_type_request(request, mtu, db).await?;
+    Ok(response.to_vec().expect("the response should serialize"))
+}
+
+/// Serve a READ_BY_TYPE_REQ by assembling the response out of the PDL
+/// builders, as the server used to
+pub async fn read_by_type_with_builders(
+    request: AttReadByTypeRequestView<'_>,
+    mtu: usize,
+    db: &impl AttDatabase,
+) -> Result<Vec<u8>, ParseError> {
+    let response =
+        read_by_type_request::handle_read_by_type_request_with_builders(request, mtu, db).await?;
+    Ok(response.to_vec().expect("the response should serialize"))
+}
@@ -0,0 +1,569 @@
+//! This module lets a service be implemented as a set of async streams, rather
+//! than a datastore answering each request: the writes of clients to each
+//! characteristic are consumed from a WriteStream (a futures Stream), and its
+//! notifications are fed to a NotificationSink (a futures Sink). Reads are
+//! served from the last value set or notified, without involving the service.
+//!
+//! The streams are a layer over the existing plumbing. CharacteristicStreams
+//! is registered with the GattDatabase as the datastore of the service, and as
+//! a listener tracking the bearer of each client. They must be added to a
+//! single server, before the clients connect, since the bearers of earlier
+//! connections are not known.
+//!
+//! The writes of a client reach the stream one at a time per characteristic
+//! (see write_locks), and each ATT_WRITE_REQ stays open until its WriteEvent is
+//! answered, dropped (failing it with UNLIKELY_ERROR), or the request times
+//! out. Accepted writes do not update the value served to reads; the service
+//! does so with set_value(), if it should.
+
+use std::{
+    cell::{RefCell, RefMut},
+    collections::HashMap,
+    convert::Infallible,
+    future::Future,
+    ops::RangeInclusive,
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
+};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use futures_core::Stream;
+use futures_sink::Sink;
+use log::warn;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    core::{
+        address::AddressWithType,
+        shared_box::{WeakBox, WeakBoxRef},
+    },
+    gatt::{
+        callbacks::{GattWriteRequestType, RawGattDatastore, TransactionDecision},
+        ffi::AttributeBackingType,
+        ids::{AttHandle, TransportIndex},
+    },
+    packets::{AttAttributeDataChild, AttErrorCode},
+};
+
+use super::{
+    att_server_bearer::AttServerBearer,
+    client_configuration::ClientConfiguration,
+    gatt_database::{
+        AttDatabaseImpl, GattDatabase, GattDatabaseCallbacks, ServiceBuilder, ServiceToken,
+    },
+    notification_handler::{NotificationError, Priority},
+};
+
+/// A write of a client to a characteristic
+#[derive(Debug)]
+pub struct WriteEvent {
+    /// The transport of the client
+    pub tcb_idx: TransportIndex,
+    /// The handle of the characteristic value
+    pub handle: AttHandle,
+    /// The value written
+    pub value: Vec<u8>,
+    /// Set for an ATT_WRITE_REQ, awaiting its response
+    responder: Option<oneshot::Sender<Result<(), AttErrorCode>>>,
+}
+
+impl WriteEvent {
+    /// Whether the client awaits a response (i.e. this is not an
+    /// ATT_WRITE_CMD)
+    pub fn expects_response(&self) -> bool {
+        self.responder.is_some()
+    }
+
+    /// Complete the write with the given result. Fails if the server no longer
+    /// awaits it (since it timed out, or the client disconnected). Write
+    /// commands have no response, so this does nothing for them.
+    pub fn respond(mut self, result: Result<(), AttErrorCode>) -> Result<()> {
+        match self.responder.take() {
+            Some(responder) => responder
+                .send(result)
+                .map_err(|_| anyhow!("the write is no longer awaiting a response")),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The writes of clients to a characteristic, in the order they complete
+/// their checks
+#[derive(Debug)]
+pub struct WriteStream {
+    rx: mpsc::UnboundedReceiver<WriteEvent>,
+}
+
+impl WriteStream {
+    /// The next write, or None once the CharacteristicStreams are dropped
+    pub async fn recv(&mut self) -> Option<WriteEvent> {
+        self.rx.recv().await
+    }
+}
+
+impl Stream for WriteStream {
+    type Item = WriteEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<WriteEvent>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+type PendingNotification = Pin<Box<dyn Future<Output = Result<(), NotificationError>>>>;
+
+/// Notifies the subscribed clients of the values of a characteristic. Each
+/// value is sent to every connected client (truncated to its MTU), and
+/// becomes the value served to reads. The clients that have not subscribed
+/// are skipped.
+///
+/// Notifications that fail (e.g. since the connection is congested) are
+/// logged and dropped, so the sink itself never fails.
+pub struct NotificationSink {
+    handle: AttHandle,
+    shared: Rc<Shared>,
+    in_flight: Vec<PendingNotification>,
+}
+
+impl NotificationSink {
+    /// Notify the given value, and wait until it has been handed to each
+    /// transport
+    pub async fn notify(&mut self, value: Vec<u8>) {
+        self.start_notification(value);
+        std::future::poll_fn(|cx| self.poll_in_flight(cx)).await
+    }
+
+    fn start_notification(&mut self, value: Vec<u8>) {
+        let bearers = self.shared.clients.borrow().values().cloned().collect::<Vec<_>>();
+        for bearer in bearers {
+            bearer.with(|bearer| {
+                let Some(bearer) = bearer else {
+                    return;
+                };
+                let len = value.len().min(bearer.get_mtu() - 3);
+                self.in_flight.push(Box::pin(bearer.send_notification(
+                    self.handle,
+                    AttAttributeDataChild::RawData(value[..len].into()),
+                    Priority::Normal,
+                )));
+            });
+        }
+        self.shared.characteristic(self.handle).value = value;
+    }
+
+    fn poll_in_flight(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let handle = self.handle;
+        self.in_flight.retain_mut(|notification| match notification.as_mut().poll(cx) {
+            Poll::Pending => true,
+            Poll::Ready(Ok(())) | Poll::Ready(Err(NotificationError::ClientNotSubscribed)) => false,
+            Poll::Ready(Err(err)) => {
+                warn!("dropped notification of {handle:?}: {err:?}");
+                false
+            }
+        });
+        if self.in_flight.is_empty() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Sink<Vec<u8>> for NotificationSink {
+    type Error = Infallible;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        // one value is in flight at a time, so that they reach each client in order
+        self.poll_in_flight(cx).map(Ok)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, value: Vec<u8>) -> Result<(), Infallible> {
+        self.start_notification(value);
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        self.poll_in_flight(cx).map(Ok)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        self.poll_flush(cx)
+    }
+}
+
+#[derive(Default)]
+struct Characteristic {
+    value: Vec<u8>,
+    writes: Option<mpsc::UnboundedSender<WriteEvent>>,
+}
+
+#[derive(Default)]
+struct Shared {
+    characteristics: RefCell<HashMap<AttHandle, Characteristic>>,
+    clients: RefCell<HashMap<TransportIndex, WeakBox<AttServerBearer<AttDatabaseImpl>>>>,
+}
+
+impl Shared {
+    fn characteristic(&self, handle: AttHandle) -> RefMut<'_, Characteristic> {
+        RefMut::map(self.characteristics.borrow_mut(), |characteristics| {
+            characteristics.entry(handle).or_default()
+        })
+    }
+
+    fn writes(&self, handle: AttHandle) -> Option<mpsc::UnboundedSender<WriteEvent>> {
+        self.characteristics.borrow().get(&handle).and_then(|characteristic| {
+            characteristic.writes.clone().filter(|writes| !writes.is_closed())
+        })
+    }
+}
+
+/// The streams of the characteristics of a service (see the module
+/// documentation). Characteristics are identified by the handle of their
+/// value, and get their streams on demand.
+#[derive(Clone, Default)]
+pub struct CharacteristicStreams {
+    shared: Rc<Shared>,
+}
+
+impl CharacteristicStreams {
+    /// Constructor, with empty values and no streams
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the given service to the database, backed by these streams, and
+    /// track the bearers of the clients that connect to it from now on
+    pub fn add_to(&self, database: &GattDatabase, service: ServiceBuilder) -> Result<ServiceToken> {
+        let token =
+            database.add_service(service, Rc::new(StreamingDatastore(self.shared.clone())))?;
+        database.register_listener(Rc::new(BearerTracker(self.shared.clone())));
+        Ok(token)
+    }
+
+    /// The writes of clients to the characteristic with the given value
+    /// handle. Until this is taken, they are rejected with
+    /// WRITE_NOT_PERMITTED, and it can only be taken again once dropped.
+    pub fn writes(&self, handle: AttHandle) -> Result<WriteStream> {
+        let mut characteristic = self.shared.characteristic(handle);
+        if characteristic.writes.as_ref().map(|writes| !writes.is_closed()).unwrap_or(false) {
+            bail!("the writes to {handle:?} are already being consumed");
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        characteristic.writes = Some(tx);
+        Ok(WriteStream { rx })
+    }
+
+    /// A sink of the notifications of the characteristic with the given value
+    /// handle
+    pub fn notifications(&self, handle: AttHandle) -> NotificationSink {
+        NotificationSink { handle, shared: self.shared.clone(), in_flight: vec![] }
+    }
+
+    /// Set the value served to reads of the characteristic with the given
+    /// value handle, without notifying it
+    pub fn set_value(&self, handle: AttHandle, value: &[u8]) {
+        self.shared.characteristic(handle).value = value.to_vec();
+    }
+
+    /// The value served to reads of the characteristic with the given value
+    /// handle
+    pub fn value(&self, handle: AttHandle) -> Vec<u8> {
+        self.shared
+            .characteristics
+            .borrow()
+            .get(&handle)
+            .map(|characteristic| characteristic.value.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// Serves the reads from the stored values, and forwards the writes to the
+/// streams
+struct StreamingDatastore(Rc<Shared>);
+
+#[async_trait(?Send)]
+impl RawGattDatastore for StreamingDatastore {
+    async fn read(
+        &self,
+        _: TransportIndex,
+        handle: AttHandle,
+        offset: u32,
+        _: AttributeBackingType,
+    ) -> Result<Vec<u8>, AttErrorCode> {
+        let characteristics = self.0.characteristics.borrow();
+        let value = characteristics
+            .get(&handle)
+            .map(|characteristic| characteristic.value.as_slice())
+            .unwrap_or_default();
+        if offset as usize > value.len() {
+            warn!("got read of {handle:?} at offset {offset} past the end of its value");
+            return Err(AttErrorCode::INVALID_OFFSET);
+        }
+        Ok(value[offset as usize..].to_vec())
+    }
+
+    async fn write(
+        &self,
+        tcb_idx: TransportIndex,
+        handle: AttHandle,
+        _: AttributeBackingType,
+        write_type: GattWriteRequestType,
+        data: &[u8],
+    ) -> Result<(), AttErrorCode> {
+        match write_type {
+            GattWriteRequestType::Prepare { .. } => {
+                warn!("got prepare write attempt on {tcb_idx:?} to streamed characteristic {handle:?}");
+                return Err(AttErrorCode::WRITE_REQUEST_REJECTED);
+            }
+            GattWriteRequestType::Request { offset: 0 } => {}
+            GattWriteRequestType::Request { .. } => {
+                warn!("got write at non-zero offset on {tcb_idx:?} to streamed characteristic {handle:?}");
+                return Err(AttErrorCode::ATTRIBUTE_NOT_LONG);
+            }
+        }
+        let Some(writes) = self.0.writes(handle) else {
+            warn!("got write on {tcb_idx:?} to {handle:?}, whose writes are not consumed");
+            return Err(AttErrorCode::WRITE_NOT_PERMITTED);
+        };
+        let (tx, rx) = oneshot::channel();
+        let event = WriteEvent { tcb_idx, handle, value: data.to_vec(), responder: Some(tx) };
+        if writes.send(event).is_err() {
+            return Err(AttErrorCode::WRITE_NOT_PERMITTED);
+        }
+        // if the transaction times out first, the response is dropped with it
+        rx.await.unwrap_or_else(|_| {
+            warn!("write of {handle:?} dropped without a response - returning UNLIKELY_ERROR");
+            Err(AttErrorCode::UNLIKELY_ERROR)
+        })
+    }
+
+    fn write_no_response(
+        &self,
+        tcb_idx: TransportIndex,
+        handle: AttHandle,
+        _: AttributeBackingType,
+        data: &[u8],
+    ) {
+        let event = WriteEvent { tcb_idx, handle, value: data.to_vec(), responder: None };
+        if self.0.writes(handle).map(|writes| writes.send(event).is_err()).unwrap_or(true) {
+            // silently drop, since there's no way to return an error
+            warn!("got write command on {tcb_idx:?} to {handle:?}, whose writes are not consumed");
+        }
+    }
+
+    async fn execute(&self, _: TransportIndex, _: TransactionDecision) -> Result<(), AttErrorCode> {
+        // prepared writes are rejected, so there is nothing to execute
+        Ok(())
+    }
+}
+
+/// Tracks the bearer of each client, to send it notifications
+struct BearerTracker(Rc<Shared>);
+
+impl GattDatabaseCallbacks for BearerTracker {
+    fn on_le_connect(
+        &self,
+        tcb_idx: TransportIndex,
+        bearer: WeakBoxRef<AttServerBearer<AttDatabaseImpl>>,
+    ) {
+        self.0.clients.borrow_mut().insert(tcb_idx, bearer.downgrade());
+    }
+
+    fn on_le_disconnect(&self, tcb_idx: TransportIndex) {
+        self.0.clients.borrow_mut().remove(&tcb_idx);
+    }
+
+    fn on_le_bonded(&self, _tcb_idx: TransportIndex, _peer: AddressWithType) {}
+
+    fn on_service_change(&self, _range: RangeInclusive<AttHandle>) {}
+
+    fn on_client_configuration_change(
+        &self,
+        _tcb_idx: TransportIndex,
+        _handle: AttHandle,
+        _configuration: ClientConfiguration,
+    ) {
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::future::poll_fn;
+
+    use tokio::task::spawn_local;
+
+    use crate::utils::task::{block_on_locally, try_await};
+
+    use super::*;
+
+    const TCB_IDX: TransportIndex = TransportIndex(1);
+    const HANDLE: AttHandle = AttHandle(3);
+    const ANOTHER_HANDLE: AttHandle = AttHandle(5);
+
+    fn start_write(
+        streams: &CharacteristicStreams,
+        handle: AttHandle,
+        value: &[u8],
+    ) -> impl Future<Output = Result<(), AttErrorCode>> {
+        let datastore = StreamingDatastore(streams.shared.clone());
+        let value = value.to_vec();
+        async move {
+            datastore
+                .write(
+                    TCB_IDX,
+                    handle,
+                    AttributeBackingType::Characteristic,
+                    GattWriteRequestType::Request { offset: 0 },
+                    &value,
+                )
+                .await
+        }
+    }
+
+    #[test]
+    fn test_write_answered_through_stream() {
+        block_on_locally(async {
+            // arrange
+            let streams = CharacteristicStreams::new();
+            let mut writes = streams.writes(HANDLE).unwrap();
+
+            // act: a write is started, and answered by another task
+            let pending = spawn_local(start_write(&streams, HANDLE, &[1, 2]));
+            let event = poll_fn(|cx| Pin::new(&mut writes).poll_next(cx)).await.unwrap();
+            assert_eq!(
+                (event.tcb_idx, event.handle, event.value.clone()),
+                (TCB_IDX, HANDLE, vec![1, 2])
+            );
+            assert!(event.expects_response());
+            event.respond(Err(AttErrorCode::VALUE_NOT_ALLOWED)).unwrap();
+
+            // assert
+            assert_eq!(pending.await.unwrap(), Err(AttErrorCode::VALUE_NOT_ALLOWED));
+        });
+    }
+
+    #[test]
+    fn test_write_held_until_answered() {
+        block_on_locally(async {
+            // arrange
+            let streams = CharacteristicStreams::new();
+            let mut writes = streams.writes(HANDLE).unwrap();
+
+            // act
+            let Err(pending) = try_await(start_write(&streams, HANDLE, &[1])).await else {
+                unreachable!("write completed before it was answered");
+            };
+            writes.recv().await.unwrap().respond(Ok(())).unwrap();
+
+            // assert
+            assert_eq!(pending.await, Ok(()));
+        });
+    }
+
+    #[test]
+    fn test_dropped_event_fails_write() {
+        block_on_locally(async {
+            // arrange
+            let streams = CharacteristicStreams::new();
+            let mut writes = streams.writes(HANDLE).unwrap();
+
+            // act
+            let pending = spawn_local(start_write(&streams, HANDLE, &[1]));
+            drop(writes.recv().await.unwrap());
+
+            // assert
+            assert_eq!(pending.await.unwrap(), Err(AttErrorCode::UNLIKELY_ERROR));
+        });
+    }
+
+    #[test]
+    fn test_write_without_stream_rejected() {
+        block_on_locally(async {
+            // arrange: the writes of one characteristic are consumed, then dropped
+            let streams = CharacteristicStreams::new();
+            drop(streams.writes(ANOTHER_HANDLE).unwrap());
+
+            // act
+            let unconsumed = start_write(&streams, HANDLE, &[1]).await;
+            let dropped = start_write(&streams, ANOTHER_HANDLE, &[1]).await;
+
+            // assert
+            assert_eq!(unconsumed, Err(AttErrorCode::WRITE_NOT_PERMITTED));
+            assert_eq!(dropped, Err(AttErrorCode::WRITE_NOT_PERMITTED));
+        });
+    }
+
+    #[test]
+    fn test_write_command_delivered_without_response() {
+        block_on_locally(async {
+            // arrange
+            let streams = CharacteristicStreams::new();
+            let mut writes = streams.writes(HANDLE).unwrap();
+
+            // act
+            StreamingDatastore(streams.shared.clone()).write_no_response(
+                TCB_IDX,
+                HANDLE,
+                AttributeBackingType::Characteristic,
+                &[1, 2],
+            );
+
+            // assert
+            let event = writes.recv().await.unwrap();
+            assert_eq!(event.value, vec![1, 2]);
+            assert!(!event.expects_response());
+            assert!(event.respond(Ok(())).is_ok());
+        });
+    }
+
+    #[test]
+    fn test_writes_taken_once() {
+        let streams = CharacteristicStreams::new();
+
+        let writes = streams.writes(HANDLE).unwrap();
+        assert!(streams.writes(HANDLE).is_err());
+        drop(writes);
+        assert!(streams.writes(HANDLE).is_ok());
+    }
+
+    #[test]
+    fn test_read_served_from_value() {
+        block_on_locally(async {
+            // arrange
+            let streams = CharacteristicStreams::new();
+            streams.set_value(HANDLE, &[1, 2, 3]);
+            let datastore = StreamingDatastore(streams.shared.clone());
+
+            // act
+            let read = |offset| {
+                datastore.read(TCB_IDX, HANDLE, offset, AttributeBackingType::Characteristic)
+            };
+            let from_start = read(0).await;
+            let from_offset = read(2).await;
+            let past_end = read(4).await;
+
+            // assert
+            assert_eq!(from_start, Ok(vec![1, 2, 3]));
+            assert_eq!(from_offset, Ok(vec![3]));
+            assert_eq!(past_end, Err(AttErrorCode::INVALID_OFFSET));
+        });
+    }
+
+    #[test]
+    fn test_notified_value_served() {
+        block_on_locally(async {
+            // arrange: no client is connected
+            let streams = CharacteristicStreams::new();
+            let mut sink = streams.notifications(HANDLE);
+
+            // act
+            poll_fn(|cx| Pin::new(&mut sink).poll_ready(cx)).await.unwrap();
+            Pin::new(&mut sink).start_send(vec![4, 5]).unwrap();
+            poll_fn(|cx| Pin::new(&mut sink).poll_flush(cx)).await.unwrap();
+
+            // assert
+            assert_eq!(streams.value(HANDLE), vec![4, 5]);
+        });
+    }
+}
@@ -0,0 +1,320 @@
+//! This module tracks the Client Characteristic Configuration (i.e. the
+//! notification/indication subscriptions) of every connected client, for
+//! characteristics whose CCCD is managed by the GattDatabase rather than by the
+//! upper layer.
+//!
+//! As per Core Spec 5.3 Vol 3G 3.3.3.3, the configuration of a bonded client is
+//! retained across connections, so it is saved under the identity of the peer.
+
+use std::{collections::HashMap, ops::RangeInclusive};
+
+use bitflags::bitflags;
+
+use crate::{
+    core::address::AddressWithType,
+    gatt::ids::{AttHandle, TransportIndex},
+};
+
+bitflags! {
+    /// The Client Characteristic Configuration bits, from Core Spec 5.3 Vol 3G
+    /// 3.3.3.3 Client Characteristic Configuration
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+    pub struct ClientConfiguration : u16 {
+        /// The characteristic value shall be notified
+        const NOTIFICATION = 0x0001;
+        /// The characteristic value shall be indicated
+        const INDICATION = 0x0002;
+    }
+}
+
+/// The client configuration of each characteristic, keyed by value handle
+type Configurations = HashMap<AttHandle, ClientConfiguration>;
+
+#[derive(Default)]
+struct ClientState {
+    peer: Option<AddressWithType>,
+    configurations: Configurations,
+}
+
+/// Stores the client configuration of every connected client, as well as that
+/// of bonded clients that are not currently connected
+#[derive(Default)]
+pub struct ClientConfigurationStore {
+    clients: HashMap<TransportIndex, ClientState>,
+    bonded: HashMap<AddressWithType, Configurations>,
+}
+
+impl ClientConfigurationStore {
+    /// Get the configuration of the characteristic at the given value handle
+    pub fn get(&self, tcb_idx: TransportIndex, handle: AttHandle) -> ClientConfiguration {
+        self.clients
+            .get(&tcb_idx)
+            .and_then(|client| client.configurations.get(&handle))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// The characteristics to which a client is subscribed (i.e. whose
+    /// configuration is not empty), in order of value handle
+    pub fn subscriptions(&self, tcb_idx: TransportIndex) -> Vec<(AttHandle, ClientConfiguration)> {
+        let mut subscriptions = self
+            .clients
+            .get(&tcb_idx)
+            .into_iter()
+            .flat_map(|client| &client.configurations)
+            .filter(|(_, configuration)| !configuration.is_empty())
+            .map(|(handle, configuration)| (*handle, *configuration))
+            .collect::<Vec<_>>();
+        subscriptions.sort_by_key(|(handle, _)| *handle);
+        subscriptions
+    }
+
+    /// Set the configuration of the characteristic at the given value handle.
+    /// If the client is bonded, the configuration is saved for future
+    /// connections.
+    pub fn set(
+        &mut self,
+        tcb_idx: TransportIndex,
+        handle: AttHandle,
+        configuration: ClientConfiguration,
+    ) {
+        self.set_unsaved(tcb_idx, handle, configuration);
+        self.save(tcb_idx);
+    }
+
+    /// Set the configuration of the characteristic at the given value handle,
+    /// without saving it for future connections yet, so that a burst of
+    /// changes is saved at once with save()
+    pub fn set_unsaved(
+        &mut self,
+        tcb_idx: TransportIndex,
+        handle: AttHandle,
+        configuration: ClientConfiguration,
+    ) {
+        self.clients.entry(tcb_idx).or_default().configurations.insert(handle, configuration);
+    }
+
+    /// Save the configuration of the client for future connections, if it is
+    /// bonded
+    pub fn save(&mut self, tcb_idx: TransportIndex) {
+        let Some(client) = self.clients.get(&tcb_idx) else {
+            return;
+        };
+        if let Some(peer) = client.peer {
+            self.bonded.insert(peer, client.configurations.clone());
+        }
+    }
+
+    /// Whether the client on the given transport has been identified as a
+    /// bonded peer
+    pub fn is_bonded(&self, tcb_idx: TransportIndex) -> bool {
+        self.clients.get(&tcb_idx).map(|client| client.peer.is_some()).unwrap_or(false)
+    }
+
+    /// Whether any state is kept for the client on the given transport (i.e.
+    /// it is connected)
+    pub fn has_client(&self, tcb_idx: TransportIndex) -> bool {
+        self.clients.contains_key(&tcb_idx)
+    }
+
+    /// A client has connected. Until it is known to be bonded, all its
+    /// characteristics are unconfigured.
+    pub fn on_le_connect(&mut self, tcb_idx: TransportIndex) {
+        self.clients.insert(tcb_idx, ClientState::default());
+    }
+
+    /// A client has disconnected, so its configuration is discarded (unless it
+    /// is bonded, in which case it has already been saved)
+    pub fn on_le_disconnect(&mut self, tcb_idx: TransportIndex) {
+        self.clients.remove(&tcb_idx);
+    }
+
+    /// A client has been identified as a bonded peer (i.e. the link is
+    /// encrypted with a bonded key). Its saved configuration is restored, and
+    /// any changes made so far in this connection take precedence.
+    pub fn on_le_bonded(&mut self, tcb_idx: TransportIndex, peer: AddressWithType) {
+        let client = self.clients.entry(tcb_idx).or_default();
+        let mut configurations = self.bonded.remove(&peer).unwrap_or_default();
+        configurations.extend(client.configurations.drain());
+        client.configurations = configurations.clone();
+        client.peer = Some(peer);
+        self.bonded.insert(peer, configurations);
+    }
+
+    /// The characteristics in the given range have been removed, so their
+    /// configuration is cleared for all clients, including bonded ones
+    pub fn on_handles_removed(&mut self, range: RangeInclusive<AttHandle>) {
+        for configurations in self
+            .clients
+            .values_mut()
+            .map(|client| &mut client.configurations)
+            .chain(self.bonded.values_mut())
+        {
+            configurations.retain(|handle, _| !range.contains(handle));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::core::address::AddressType;
+
+    use super::*;
+
+    const TCB_IDX: TransportIndex = TransportIndex(1);
+    const ANOTHER_TCB_IDX: TransportIndex = TransportIndex(2);
+    const HANDLE: AttHandle = AttHandle(3);
+    const ANOTHER_HANDLE: AttHandle = AttHandle(5);
+    const PEER: AddressWithType =
+        AddressWithType { address: [1, 2, 3, 4, 5, 6], address_type: AddressType::Public };
+
+    #[test]
+    fn test_unconfigured_by_default() {
+        let mut store = ClientConfigurationStore::default();
+        store.on_le_connect(TCB_IDX);
+
+        assert_eq!(store.get(TCB_IDX, HANDLE), ClientConfiguration::empty());
+    }
+
+    #[test]
+    fn test_configuration_per_client() {
+        // arrange: two connected clients
+        let mut store = ClientConfigurationStore::default();
+        store.on_le_connect(TCB_IDX);
+        store.on_le_connect(ANOTHER_TCB_IDX);
+
+        // act: only one of them subscribes
+        store.set(TCB_IDX, HANDLE, ClientConfiguration::NOTIFICATION);
+
+        // assert
+        assert_eq!(store.get(TCB_IDX, HANDLE), ClientConfiguration::NOTIFICATION);
+        assert_eq!(store.get(ANOTHER_TCB_IDX, HANDLE), ClientConfiguration::empty());
+        assert_eq!(store.get(TCB_IDX, ANOTHER_HANDLE), ClientConfiguration::empty());
+    }
+
+    #[test]
+    fn test_subscriptions() {
+        // arrange
+        let mut store = ClientConfigurationStore::default();
+        store.on_le_connect(TCB_IDX);
+        store.set(TCB_IDX, ANOTHER_HANDLE, ClientConfiguration::INDICATION);
+        store.set(TCB_IDX, HANDLE, ClientConfiguration::NOTIFICATION);
+
+        // act: unsubscribe from one characteristic
+        store.set(TCB_IDX, ANOTHER_HANDLE, ClientConfiguration::empty());
+
+        // assert
+        assert_eq!(store.subscriptions(TCB_IDX), vec![(HANDLE, ClientConfiguration::NOTIFICATION)]);
+        assert_eq!(store.subscriptions(ANOTHER_TCB_IDX), vec![]);
+    }
+
+    #[test]
+    fn test_unbonded_configuration_discarded_on_disconnect() {
+        // arrange: an unbonded client subscribes
+        let mut store = ClientConfigurationStore::default();
+        store.on_le_connect(TCB_IDX);
+        store.set(TCB_IDX, HANDLE, ClientConfiguration::INDICATION);
+
+        // act: it reconnects
+        store.on_le_disconnect(TCB_IDX);
+        store.on_le_connect(TCB_IDX);
+
+        // assert: it is no longer subscribed
+        assert_eq!(store.get(TCB_IDX, HANDLE), ClientConfiguration::empty());
+    }
+
+    #[test]
+    fn test_client_state_released_on_disconnect() {
+        // arrange: a bonded client subscribes
+        let mut store = ClientConfigurationStore::default();
+        store.on_le_connect(TCB_IDX);
+        store.on_le_bonded(TCB_IDX, PEER);
+        store.set(TCB_IDX, HANDLE, ClientConfiguration::INDICATION);
+
+        // act
+        store.on_le_disconnect(TCB_IDX);
+
+        // assert: nothing is kept for the transport, though the bonded
+        // configuration is saved
+        assert!(!store.has_client(TCB_IDX));
+        assert!(store.bonded.contains_key(&PEER));
+    }
+
+    #[test]
+    fn test_bonded_configuration_restored() {
+        // arrange: a bonded client subscribes
+        let mut store = ClientConfigurationStore::default();
+        store.on_le_connect(TCB_IDX);
+        store.on_le_bonded(TCB_IDX, PEER);
+        store.set(TCB_IDX, HANDLE, ClientConfiguration::INDICATION);
+        store.on_le_disconnect(TCB_IDX);
+
+        // act: it reconnects on a different transport, and is identified again
+        store.on_le_connect(ANOTHER_TCB_IDX);
+        assert_eq!(store.get(ANOTHER_TCB_IDX, HANDLE), ClientConfiguration::empty());
+        store.on_le_bonded(ANOTHER_TCB_IDX, PEER);
+
+        // assert: its subscription was restored
+        assert_eq!(store.get(ANOTHER_TCB_IDX, HANDLE), ClientConfiguration::INDICATION);
+    }
+
+    #[test]
+    fn test_configuration_before_bonding_is_saved() {
+        // arrange: a client subscribes, and is then identified as bonded
+        let mut store = ClientConfigurationStore::default();
+        store.on_le_connect(TCB_IDX);
+        store.set(TCB_IDX, HANDLE, ClientConfiguration::NOTIFICATION);
+        store.on_le_bonded(TCB_IDX, PEER);
+
+        // act: it reconnects
+        store.on_le_disconnect(TCB_IDX);
+        store.on_le_connect(TCB_IDX);
+        store.on_le_bonded(TCB_IDX, PEER);
+
+        // assert: the subscription persisted
+        assert_eq!(store.get(TCB_IDX, HANDLE), ClientConfiguration::NOTIFICATION);
+    }
+
+    #[test]
+    fn test_unsaved_configuration_saved_at_once() {
+        // arrange: a bonded client subscribes to two characteristics in a burst
+        let mut store = ClientConfigurationStore::default();
+        store.on_le_connect(TCB_IDX);
+        store.on_le_bonded(TCB_IDX, PEER);
+        store.set_unsaved(TCB_IDX, HANDLE, ClientConfiguration::NOTIFICATION);
+        store.set_unsaved(TCB_IDX, ANOTHER_HANDLE, ClientConfiguration::INDICATION);
+        assert_eq!(store.get(TCB_IDX, HANDLE), ClientConfiguration::NOTIFICATION);
+        assert_eq!(store.bonded[&PEER].get(&HANDLE), None);
+
+        // act
+        store.save(TCB_IDX);
+
+        // assert
+        assert_eq!(store.bonded[&PEER].get(&HANDLE), Some(&ClientConfiguration::NOTIFICATION));
+        assert_eq!(
+            store.bonded[&PEER].get(&ANOTHER_HANDLE),
+            Some(&ClientConfiguration::INDICATION)
+        );
+    }
+
+    #[test]
+    fn test_configuration_cleared_on_removal() {
+        // arrange: a bonded client subscribes to two characteristics
+        let mut store = ClientConfigurationStore::default();
+        store.on_le_connect(TCB_IDX);
+        store.on_le_bonded(TCB_IDX, PEER);
+        store.set(TCB_IDX, HANDLE, ClientConfiguration::NOTIFICATION);
+        store.set(TCB_IDX, ANOTHER_HANDLE, ClientConfiguration::NOTIFICATION);
+
+        // act: one characteristic is removed, and the client reconnects
+        store.on_handles_removed(AttHandle(1)..=AttHandle(4));
+        assert_eq!(store.get(TCB_IDX, HANDLE), ClientConfiguration::empty());
+        store.on_le_disconnect(TCB_IDX);
+        store.on_le_connect(TCB_IDX);
+        store.on_le_bonded(TCB_IDX, PEER);
+
+        // assert: only the remaining subscription was restored
+        assert_eq!(store.get(TCB_IDX, HANDLE), ClientConfiguration::empty());
+        assert_eq!(store.get(TCB_IDX, ANOTHER_HANDLE), ClientConfiguration::NOTIFICATION);
+    }
+}
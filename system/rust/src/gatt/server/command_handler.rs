@@ -1,100 +1,467 @@
-use log::warn;
+use std::{cell::RefCell, rc::Rc};
 
-use crate::packets::{AttOpcode, AttView, AttWriteCommandView, Packet};
+use log::{error, info, warn};
+use tokio::{sync::mpsc, task::yield_now};
 
-use super::att_database::AttDatabase;
+use crate::{
+    gatt::ids::AttHandle,
+    packets::{
+        AttOpcode, AttSignedWriteCommandView, AttView, AttWriteCommandView, OwnedAttView, Packet,
+    },
+    utils::executor::{Executor, TaskHandle, TokioExecutor},
+};
+
+use super::{
+    att_database::AttDatabase,
+    signature_verifier::{SignatureVerifier, SIGNATURE_LEN},
+};
+
+/// The maximum number of commands that may be queued on a single bearer before
+/// further commands are dropped. Commands are unacknowledged, so the client
+/// cannot tell whether they were processed.
+pub const MAX_QUEUED_COMMANDS: usize = 32;
+
+/// The opcode of ATT_SIGNED_WRITE_CMD (Core Spec 5.3 Vol 3F 3.4.8)
+const SIGNED_WRITE_COMMAND_OPCODE: u8 = 0xD2;
 
 /// This struct handles all ATT commands.
+///
+/// Commands are processed in order by a background task, which yields between
+/// commands so that a flood of commands cannot starve requests on the same
+/// bearer.
 pub struct AttCommandHandler<Db: AttDatabase> {
     db: Db,
+    signature_verifier: Rc<SignatureVerifier>,
+    queue: RefCell<Option<CommandQueue>>,
+    executor: RefCell<Rc<dyn Executor>>,
 }
 
-impl<Db: AttDatabase> AttCommandHandler<Db> {
-    pub fn new(db: Db) -> Self {
-        Self { db }
+struct CommandQueue {
+    tx: mpsc::Sender<OwnedAttView>,
+    _task: TaskHandle,
+}
+
+impl<Db: AttDatabase + Clone + 'static> AttCommandHandler<Db> {
+    pub fn new(db: Db, signature_verifier: SignatureVerifier) -> Self {
+        Self {
+            db,
+            signature_verifier: Rc::new(signature_verifier),
+            queue: RefCell::new(None),
+            executor: RefCell::new(Rc::new(TokioExecutor)),
+        }
+    }
+
+    /// Process the queued commands on the given Executor, rather than on the
+    /// TokioExecutor. Should be set before the first command arrives.
+    pub fn set_executor(&self, executor: Rc<dyn Executor>) {
+        self.executor.replace(executor);
     }
 
+    /// Queue an incoming command for processing. If too many commands are
+    /// already queued, it is dropped.
     pub fn process_packet(&self, packet: AttView<'_>) {
-        let snapshotted_db = self.db.snapshot();
-        match packet.get_opcode() {
-            AttOpcode::WRITE_COMMAND => {
-                let Ok(packet) = AttWriteCommandView::try_parse(packet) else {
-                    warn!("failed to parse WRITE_COMMAND packet");
-                    return;
-                };
-                snapshotted_db.write_no_response_attribute(
-                    packet.get_handle().into(),
-                    &packet.get_value().get_raw_payload().collect::<Vec<_>>(),
-                );
+        let mut queue = self.queue.borrow_mut();
+        // the task is started lazily, since we may not be in an async context yet
+        // when the bearer is constructed
+        let queue = queue.get_or_insert_with(|| {
+            let (tx, mut rx) = mpsc::channel::<OwnedAttView>(MAX_QUEUED_COMMANDS);
+            let db = self.db.clone();
+            let signature_verifier = self.signature_verifier.clone();
+            let task = self.executor.borrow().spawn(Box::pin(async move {
+                while let Some(packet) = rx.recv().await {
+                    process_command(&db, &signature_verifier, packet.view());
+                    // let any pending request make progress before the next command
+                    yield_now().await;
+                }
+            }));
+            CommandQueue { tx, _task: task }
+        });
+        if queue.tx.try_send(packet.to_owned_packet()).is_err() {
+            warn!("too many commands are queued, dropping {:?}", packet.get_opcode());
+        }
+    }
+}
+
+/// Process a single command to completion
+pub fn process_command(
+    db: &impl AttDatabase,
+    signature_verifier: &SignatureVerifier,
+    packet: AttView<'_>,
+) {
+    // As per Core Spec 5.3 Vol 3G 2.5.2.1, commands from a change-unaware
+    // client are ignored
+    if !db.is_change_aware() {
+        info!("dropping {:?} from change-unaware client", packet.get_opcode());
+        return;
+    }
+    let snapshotted_db = db.snapshot();
+    match packet.get_opcode() {
+        AttOpcode::WRITE_COMMAND => {
+            let Ok(packet) = AttWriteCommandView::try_parse(packet) else {
+                warn!("failed to parse WRITE_COMMAND packet");
+                return;
+            };
+            // As per Core Spec 5.3 Vol 3F 3.4.5.3, no response is sent, even if the
+            // handle is invalid or not writable, so the database must drop such writes
+            snapshotted_db.write_no_response_attribute(
+                packet.get_handle().into(),
+                &packet.get_value().get_raw_payload().collect::<Vec<_>>(),
+            );
+        }
+        AttOpcode::SIGNED_WRITE_COMMAND => {
+            if !db.server_config().signed_writes_enabled {
+                info!("dropping SIGNED_WRITE_COMMAND, since signed writes are disabled");
+                return;
             }
-            _ => {
-                warn!("Dropping unsupported opcode {:?}", packet.get_opcode());
+            let Ok(packet) = AttSignedWriteCommandView::try_parse(packet) else {
+                warn!("failed to parse SIGNED_WRITE_COMMAND packet");
+                return;
+            };
+            let handle = AttHandle::from(packet.get_handle());
+            let mut value = packet.get_value().get_raw_payload().collect::<Vec<_>>();
+            if value.len() < SIGNATURE_LEN {
+                warn!("dropping SIGNED_WRITE_COMMAND to {handle:?} without a signature");
+                return;
             }
+
+            // the signature covers the entire PDU, starting from the opcode
+            let mut pdu = vec![SIGNED_WRITE_COMMAND_OPCODE];
+            pdu.extend_from_slice(&handle.0.to_le_bytes());
+            pdu.extend_from_slice(&value);
+            // As per Core Spec 5.3 Vol 3F 3.4.5.4, a command with an invalid
+            // signature is dropped without a response
+            if let Err(err) = signature_verifier.verify(&pdu) {
+                error!(
+                    "dropping SIGNED_WRITE_COMMAND to {handle:?} with invalid signature: {err:?}"
+                );
+                return;
+            }
+
+            value.truncate(value.len() - SIGNATURE_LEN);
+            snapshotted_db.write_no_response_attribute(handle, &value);
+        }
+        _ => {
+            warn!("Dropping unsupported opcode {:?}", packet.get_opcode());
         }
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::{rc::Rc, time::Duration};
+
     use crate::{
         core::uuid::Uuid,
         gatt::{
-            ids::AttHandle,
+            ids::{AttHandle, TransportIndex},
+            mocks::mock_security_manager::MockSecurityManager,
+            security_manager::PeerSigningKey,
             server::{
                 att_database::{AttAttribute, AttDatabase},
-                command_handler::AttCommandHandler,
+                command_handler::{AttCommandHandler, MAX_QUEUED_COMMANDS},
+                config::GattServerConfig,
                 gatt_database::AttPermissions,
+                signature_verifier::SignatureVerifier,
                 test::test_att_db::TestAttDatabase,
             },
         },
         packets::{
             AttAttributeDataBuilder, AttAttributeDataChild, AttErrorCode, AttErrorResponseBuilder,
-            AttOpcode, AttWriteCommandBuilder,
+            AttOpcode, AttSignedWriteCommandBuilder, AttWriteCommandBuilder,
         },
         utils::{packet::build_att_view_or_crash, task::block_on_locally},
     };
 
-    #[test]
-    fn test_write_command() {
-        // arrange
-        let db = TestAttDatabase::new(vec![(
-            AttAttribute {
-                handle: AttHandle(3),
-                type_: Uuid::new(0x1234),
-                permissions: AttPermissions::READABLE | AttPermissions::WRITABLE_WITHOUT_RESPONSE,
-            },
-            vec![1, 2, 3],
-        )]);
-        let handler = AttCommandHandler { db: db.clone() };
-        let data = [1, 2];
+    const HANDLE: AttHandle = AttHandle(3);
+    const UNWRITABLE_HANDLE: AttHandle = AttHandle(4);
+    const TCB_IDX: TransportIndex = TransportIndex(1);
+    const CSRK: [u8; 16] = [
+        0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e,
+        0x1f,
+    ];
+    // the value [4, 5] signed with CSRK and sign counter 0, for HANDLE and for
+    // UNWRITABLE_HANDLE respectively
+    const SIGNED_VALUE: [u8; 14] =
+        [0x04, 0x05, 0x00, 0x00, 0x00, 0x00, 0x1f, 0x9b, 0x29, 0xc3, 0x28, 0xd0, 0x82, 0x17];
+    const SIGNED_VALUE_FOR_UNWRITABLE_HANDLE: [u8; 14] =
+        [0x04, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x58, 0x5d, 0x6f, 0x5c, 0x90, 0x8a, 0x35];
 
-        // act: send write command
+    fn make_db() -> TestAttDatabase {
+        TestAttDatabase::new(vec![
+            (
+                AttAttribute {
+                    handle: HANDLE,
+                    type_: Uuid::new(0x1234),
+                    permissions: AttPermissions::READABLE
+                        | AttPermissions::WRITABLE_WITHOUT_RESPONSE,
+                },
+                vec![1, 2, 3],
+            ),
+            (
+                AttAttribute {
+                    handle: UNWRITABLE_HANDLE,
+                    type_: Uuid::new(0x1234),
+                    permissions: AttPermissions::READABLE,
+                },
+                vec![1, 2, 3],
+            ),
+        ])
+    }
+
+    fn make_handler(db: &TestAttDatabase) -> AttCommandHandler<TestAttDatabase> {
+        let security_manager = Rc::new(MockSecurityManager::new());
+        security_manager
+            .set_peer_signing_key(TCB_IDX, PeerSigningKey { csrk: CSRK, sign_counter: 0 });
+        AttCommandHandler::new(db.clone(), SignatureVerifier::new(TCB_IDX, security_manager))
+    }
+
+    fn send_write_command(
+        handler: &AttCommandHandler<TestAttDatabase>,
+        handle: AttHandle,
+        data: &[u8],
+    ) {
         let att_view = build_att_view_or_crash(AttWriteCommandBuilder {
-            handle: AttHandle(3).into(),
+            handle: handle.into(),
             value: AttAttributeDataBuilder {
                 _child_: AttAttributeDataChild::RawData(data.to_vec().into_boxed_slice()),
             },
         });
         handler.process_packet(att_view.view());
+    }
+
+    fn send_signed_write_command(
+        handler: &AttCommandHandler<TestAttDatabase>,
+        handle: AttHandle,
+        signed_value: &[u8],
+    ) {
+        let att_view = build_att_view_or_crash(AttSignedWriteCommandBuilder {
+            handle: handle.into(),
+            value: AttAttributeDataBuilder {
+                _child_: AttAttributeDataChild::RawData(signed_value.to_vec().into_boxed_slice()),
+            },
+        });
+        handler.process_packet(att_view.view());
+    }
 
-        // assert: the db has been updated
-        assert_eq!(block_on_locally(db.read_attribute(AttHandle(3))).unwrap(), data);
+    /// Wait until all queued commands have been processed
+    async fn flush() {
+        tokio::time::sleep(Duration::from_millis(1)).await;
     }
 
     #[test]
-    fn test_unsupported_command() {
-        // arrange
-        let db = TestAttDatabase::new(vec![]);
-        let handler = AttCommandHandler { db };
-
-        // act: send a packet that should not be handled here
-        let att_view = build_att_view_or_crash(AttErrorResponseBuilder {
-            opcode_in_error: AttOpcode::EXCHANGE_MTU_REQUEST,
-            handle_in_error: AttHandle(1).into(),
-            error_code: AttErrorCode::UNLIKELY_ERROR,
+    fn test_write_command() {
+        block_on_locally(async {
+            // arrange
+            let db = make_db();
+            let handler = make_handler(&db);
+            let data = [1, 2];
+
+            // act: send write command
+            send_write_command(&handler, HANDLE, &data);
+            flush().await;
+
+            // assert: the db has been updated
+            assert_eq!(*db.read_attribute(HANDLE).await.unwrap(), data);
         });
-        handler.process_packet(att_view.view());
+    }
+
+    #[test]
+    fn test_write_command_to_unwritable_handle() {
+        block_on_locally(async {
+            // arrange
+            let db = make_db();
+            let handler = make_handler(&db);
+
+            // act: send write command to a handle that does not support it
+            send_write_command(&handler, UNWRITABLE_HANDLE, &[1, 2]);
+            flush().await;
+
+            // assert: the command was silently dropped
+            assert_eq!(db.read_attribute(UNWRITABLE_HANDLE).await.unwrap(), vec![1, 2, 3]);
+        });
+    }
+
+    #[test]
+    fn test_write_commands_processed_in_order() {
+        block_on_locally(async {
+            // arrange
+            let db = make_db();
+            let handler = make_handler(&db);
+
+            // act: send two write commands back-to-back
+            send_write_command(&handler, HANDLE, &[1]);
+            send_write_command(&handler, HANDLE, &[2]);
+            flush().await;
+
+            // assert: the last one wins
+            assert_eq!(db.read_attribute(HANDLE).await.unwrap(), vec![2]);
+        });
+    }
+
+    #[test]
+    fn test_too_many_queued_commands() {
+        block_on_locally(async {
+            // arrange
+            let db = make_db();
+            let handler = make_handler(&db);
+
+            // act: flood the handler with more commands than it can queue
+            for i in 0..=MAX_QUEUED_COMMANDS {
+                send_write_command(&handler, HANDLE, &[i as u8]);
+            }
+            flush().await;
+
+            // assert: the last command was dropped
+            assert_eq!(
+                db.read_attribute(HANDLE).await.unwrap(),
+                vec![(MAX_QUEUED_COMMANDS - 1) as u8]
+            );
+
+            // act: send another command once the queue has drained
+            send_write_command(&handler, HANDLE, &[0xFF]);
+            flush().await;
+
+            // assert: it was processed
+            assert_eq!(db.read_attribute(HANDLE).await.unwrap(), vec![0xFF]);
+        });
+    }
+
+    #[test]
+    fn test_write_command_from_change_unaware_client() {
+        block_on_locally(async {
+            // arrange: a change-unaware client
+            let db = make_db();
+            db.set_change_aware(false);
+            let handler = make_handler(&db);
+
+            // act: send write command
+            send_write_command(&handler, HANDLE, &[1, 2]);
+            flush().await;
+
+            // assert: the command was ignored, and the client is still change-unaware
+            assert_eq!(db.read_attribute(HANDLE).await.unwrap(), vec![1, 2, 3]);
+            assert!(!db.is_change_aware());
+        });
+    }
+
+    #[test]
+    fn test_signed_write_command() {
+        block_on_locally(async {
+            // arrange
+            let db = make_db();
+            let handler = make_handler(&db);
+
+            // act: send signed write command
+            send_signed_write_command(&handler, HANDLE, &SIGNED_VALUE);
+            flush().await;
+
+            // assert: the db has been updated, without the signature
+            assert_eq!(db.read_attribute(HANDLE).await.unwrap(), vec![4, 5]);
+        });
+    }
+
+    #[test]
+    fn test_signed_write_command_when_disabled() {
+        block_on_locally(async {
+            // arrange
+            let db = make_db();
+            db.set_config(GattServerConfig { signed_writes_enabled: false, ..Default::default() });
+            let handler = make_handler(&db);
+
+            // act: send a correctly signed write command
+            send_signed_write_command(&handler, HANDLE, &SIGNED_VALUE);
+            flush().await;
+
+            // assert: it was dropped
+            assert_eq!(db.read_attribute(HANDLE).await.unwrap(), vec![1, 2, 3]);
+        });
+    }
+
+    #[test]
+    fn test_signed_write_command_with_invalid_signature() {
+        block_on_locally(async {
+            // arrange
+            let db = make_db();
+            let handler = make_handler(&db);
+            let mut signed_value = SIGNED_VALUE;
+            signed_value[0] = 6;
 
-        // assert: nothing happens (we crash if anything is unhandled within a mock)
+            // act: send signed write command whose value does not match its signature
+            send_signed_write_command(&handler, HANDLE, &signed_value);
+            flush().await;
+
+            // assert: the command was dropped
+            assert_eq!(db.read_attribute(HANDLE).await.unwrap(), vec![1, 2, 3]);
+        });
+    }
+
+    #[test]
+    fn test_signed_write_command_signed_for_another_handle() {
+        block_on_locally(async {
+            // arrange
+            let db = make_db();
+            let handler = make_handler(&db);
+
+            // act: send signed write command with a signature for a different handle
+            send_signed_write_command(&handler, HANDLE, &SIGNED_VALUE_FOR_UNWRITABLE_HANDLE);
+            flush().await;
+
+            // assert: the command was dropped
+            assert_eq!(db.read_attribute(HANDLE).await.unwrap(), vec![1, 2, 3]);
+        });
+    }
+
+    #[test]
+    fn test_replayed_signed_write_command() {
+        block_on_locally(async {
+            // arrange: a signed write command is accepted, then the value is overwritten
+            let db = make_db();
+            let handler = make_handler(&db);
+            send_signed_write_command(&handler, HANDLE, &SIGNED_VALUE);
+            send_write_command(&handler, HANDLE, &[1, 2, 3]);
+
+            // act: replay the signed write command
+            send_signed_write_command(&handler, HANDLE, &SIGNED_VALUE);
+            flush().await;
+
+            // assert: the replay was dropped
+            assert_eq!(db.read_attribute(HANDLE).await.unwrap(), vec![1, 2, 3]);
+        });
+    }
+
+    #[test]
+    fn test_signed_write_command_without_signature() {
+        block_on_locally(async {
+            // arrange
+            let db = make_db();
+            let handler = make_handler(&db);
+
+            // act: send signed write command that is too short to be signed
+            send_signed_write_command(&handler, HANDLE, &[4, 5]);
+            flush().await;
+
+            // assert: the command was dropped
+            assert_eq!(db.read_attribute(HANDLE).await.unwrap(), vec![1, 2, 3]);
+        });
+    }
+
+    #[test]
+    fn test_unsupported_command() {
+        block_on_locally(async {
+            // arrange
+            let db = TestAttDatabase::new(vec![]);
+            let handler = make_handler(&db);
+
+            // act: send a packet that should not be handled here
+            let att_view = build_att_view_or_crash(AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::EXCHANGE_MTU_REQUEST,
+                handle_in_error: AttHandle(1).into(),
+                error_code: AttErrorCode::UNLIKELY_ERROR,
+            });
+            handler.process_packet(att_view.view());
+            flush().await;
+
+            // assert: nothing happens (we crash if anything is unhandled within a mock)
+        });
     }
 }
@@ -0,0 +1,546 @@
+//! This module composes several AttDatabases (e.g. natively-provided GAP/GATT
+//! services, and app services provided via callbacks) into a single handle
+//! space. Each backend owns a non-overlapping range of handles, reserved from
+//! a HandleAllocator, and all accesses to a handle are routed to its owner.
+
+use std::{ops::RangeInclusive, rc::Rc};
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use log::warn;
+
+use crate::{core::uuid::Uuid, gatt::ids::AttHandle, packets::AttErrorCode};
+
+use super::{
+    att_database::{AttAttribute, AttAttributeValue, AttDatabase},
+    att_error::AttError,
+    client_configuration::ClientConfiguration,
+    robust_caching::ClientSupportedFeatures,
+};
+
+/// Tracks which handle ranges have been reserved, so that each backend of a
+/// CompositeAttDatabase gets a disjoint range
+#[derive(Clone, Debug, Default)]
+pub struct HandleAllocator {
+    // sorted by start handle, and non-overlapping
+    reserved: Vec<RangeInclusive<AttHandle>>,
+}
+
+impl HandleAllocator {
+    /// Constructor. Initially, all valid handles are free.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reserve the lowest free range with the given number of handles, or
+    /// return None if no free range is large enough
+    pub fn allocate(&mut self, count: u16) -> Option<RangeInclusive<AttHandle>> {
+        if count == 0 {
+            return None;
+        }
+        let mut start = AttHandle::MIN.0 as u32;
+        for (i, range) in self.reserved.iter().enumerate() {
+            if range.start().0 as u32 - start >= count as u32 {
+                let allocated = Self::range_from(start, count);
+                self.reserved.insert(i, allocated.clone());
+                return Some(allocated);
+            }
+            start = range.end().0 as u32 + 1;
+        }
+        if AttHandle::MAX.0 as u32 + 1 - start >= count as u32 {
+            let allocated = Self::range_from(start, count);
+            self.reserved.push(allocated.clone());
+            return Some(allocated);
+        }
+        None
+    }
+
+    /// Reserve the given range, failing if any of it is already reserved
+    pub fn reserve(&mut self, range: RangeInclusive<AttHandle>) -> Result<()> {
+        if range.is_empty() || *range.start() == AttHandle::RESERVED {
+            bail!("invalid handle range {range:?}");
+        }
+        let i = self.reserved.partition_point(|reserved| reserved.end() < range.start());
+        if let Some(next) = self.reserved.get(i) {
+            if next.start() <= range.end() {
+                bail!("handle range {range:?} overlaps with {next:?}");
+            }
+        }
+        self.reserved.insert(i, range);
+        Ok(())
+    }
+
+    /// Release a previously reserved range, so that it can be allocated again
+    pub fn release(&mut self, range: &RangeInclusive<AttHandle>) {
+        self.reserved.retain(|reserved| reserved != range);
+    }
+
+    fn range_from(start: u32, count: u16) -> RangeInclusive<AttHandle> {
+        AttHandle(start as u16)..=AttHandle((start + count as u32 - 1) as u16)
+    }
+}
+
+#[derive(Clone)]
+struct Backend {
+    range: RangeInclusive<AttHandle>,
+    db: Rc<dyn AttDatabase>,
+}
+
+/// An AttDatabase routing each access to the backend owning the handle. Like
+/// any AttDatabase, it is associated with a single connection, so each
+/// backend should be the view of its database for that connection.
+#[derive(Clone, Default)]
+pub struct CompositeAttDatabase {
+    allocator: HandleAllocator,
+    // sorted by start handle
+    backends: Vec<Backend>,
+}
+
+impl CompositeAttDatabase {
+    /// Constructor, with no backends
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reserve a free range with the given number of handles, for a backend
+    /// that will be added later (once it has been populated with handles in
+    /// this range)
+    pub fn allocate_range(&mut self, count: u16) -> Option<RangeInclusive<AttHandle>> {
+        self.allocator.allocate(count)
+    }
+
+    /// Add a backend owning the given range of handles. The range must either
+    /// have been returned by allocate_range(), or not overlap with that of any
+    /// other backend.
+    pub fn add_backend(
+        &mut self,
+        range: RangeInclusive<AttHandle>,
+        db: Rc<dyn AttDatabase>,
+    ) -> Result<()> {
+        if self.backends.iter().any(|backend| backend.range == range) {
+            bail!("handle range {range:?} already has a backend");
+        }
+        if !self.allocator.reserved.contains(&range) {
+            self.allocator.reserve(range.clone())?;
+        }
+        let i = self.backends.partition_point(|backend| backend.range.start() < range.start());
+        self.backends.insert(i, Backend { range, db });
+        Ok(())
+    }
+
+    /// Remove the backend owning the given range, releasing its handles
+    pub fn remove_backend(&mut self, range: &RangeInclusive<AttHandle>) -> Result<()> {
+        let Some(i) = self.backends.iter().position(|backend| backend.range == *range) else {
+            bail!("no backend owns handle range {range:?}");
+        };
+        self.backends.remove(i);
+        self.allocator.release(range);
+        Ok(())
+    }
+
+    fn backend_for(&self, handle: AttHandle) -> Option<&Backend> {
+        self.backends.iter().find(|backend| backend.range.contains(&handle))
+    }
+}
+
+#[async_trait(?Send)]
+impl AttDatabase for CompositeAttDatabase {
+    async fn read_attribute(&self, handle: AttHandle) -> Result<AttAttributeValue, AttError> {
+        let Some(backend) = self.backend_for(handle) else {
+            return Err(AttError::from(AttErrorCode::INVALID_HANDLE).for_handle(handle));
+        };
+        backend.db.read_attribute(handle).await
+    }
+
+    async fn read_attribute_at(
+        &self,
+        handle: AttHandle,
+        offset: u32,
+    ) -> Result<AttAttributeValue, AttError> {
+        let Some(backend) = self.backend_for(handle) else {
+            return Err(AttError::from(AttErrorCode::INVALID_HANDLE).for_handle(handle));
+        };
+        backend.db.read_attribute_at(handle, offset).await
+    }
+
+    async fn write_attribute(
+        &self,
+        handle: AttHandle,
+        offset: u32,
+        data: &[u8],
+    ) -> Result<(), AttError> {
+        let Some(backend) = self.backend_for(handle) else {
+            return Err(AttError::from(AttErrorCode::INVALID_HANDLE).for_handle(handle));
+        };
+        backend.db.write_attribute(handle, offset, data).await
+    }
+
+    fn check_write(&self, handle: AttHandle, offset: u32, data: &[u8]) -> Result<(), AttError> {
+        let Some(backend) = self.backend_for(handle) else {
+            return Err(AttError::from(AttErrorCode::INVALID_HANDLE).for_handle(handle));
+        };
+        backend.db.check_write(handle, offset, data)
+    }
+
+    fn write_no_response_attribute(&self, handle: AttHandle, data: &[u8]) {
+        let Some(backend) = self.backend_for(handle) else {
+            warn!("dropping write command to unknown handle {handle:?}");
+            return;
+        };
+        backend.db.write_no_response_attribute(handle, data);
+    }
+
+    fn list_attributes(&self) -> Vec<AttAttribute> {
+        let mut out = vec![];
+        for backend in &self.backends {
+            for attribute in backend.db.list_attributes() {
+                // a backend can't shadow the handles of another
+                if backend.range.contains(&attribute.handle) {
+                    out.push(attribute);
+                } else {
+                    warn!(
+                        "ignoring attribute {:?} outside of backend range {:?}",
+                        attribute.handle, backend.range
+                    );
+                }
+            }
+        }
+        out
+    }
+
+    fn attributes_in_range(
+        &self,
+        start: AttHandle,
+        end: AttHandle,
+        type_filter: Option<Uuid>,
+    ) -> Vec<AttAttribute> {
+        let mut out = vec![];
+        for backend in &self.backends {
+            // only ask each backend about the part of the range that it owns
+            let start = start.max(*backend.range.start());
+            let end = end.min(*backend.range.end());
+            if start <= end {
+                out.extend(backend.db.attributes_in_range(start, end, type_filter));
+            }
+        }
+        out
+    }
+
+    fn client_configuration(&self, handle: AttHandle) -> Option<ClientConfiguration> {
+        self.backend_for(handle)?.db.client_configuration(handle)
+    }
+
+    fn coalesces_notifications(&self, handle: AttHandle) -> bool {
+        self.backend_for(handle)
+            .map(|backend| backend.db.coalesces_notifications(handle))
+            .unwrap_or(false)
+    }
+
+    fn value_version(&self, handle: AttHandle) -> Option<u64> {
+        self.backend_for(handle)?.db.value_version(handle)
+    }
+
+    fn is_change_aware(&self) -> bool {
+        self.backends.iter().all(|backend| backend.db.is_change_aware())
+    }
+
+    fn mark_change_aware(&self) {
+        for backend in &self.backends {
+            backend.db.mark_change_aware();
+        }
+    }
+
+    fn begin_client_configuration_burst(&self) {
+        for backend in &self.backends {
+            backend.db.begin_client_configuration_burst();
+        }
+    }
+
+    fn end_client_configuration_burst(&self) {
+        for backend in &self.backends {
+            backend.db.end_client_configuration_burst();
+        }
+    }
+
+    fn client_supported_features(&self) -> ClientSupportedFeatures {
+        self.backends.iter().fold(ClientSupportedFeatures::empty(), |all, backend| {
+            all | backend.db.client_supported_features()
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        core::uuid::Uuid,
+        gatt::server::{att_database::AttPermissions, test::test_att_db::TestAttDatabase},
+    };
+
+    use super::*;
+
+    fn attr(handle: u16, permissions: AttPermissions) -> AttAttribute {
+        AttAttribute { handle: AttHandle(handle), type_: Uuid::new(0x1234), permissions }
+    }
+
+    fn range(start: u16, end: u16) -> RangeInclusive<AttHandle> {
+        AttHandle(start)..=AttHandle(end)
+    }
+
+    fn make_backend(handles: &[u16], value: u8) -> Rc<TestAttDatabase> {
+        Rc::new(TestAttDatabase::new(
+            handles
+                .iter()
+                .map(|handle| {
+                    (
+                        attr(
+                            *handle,
+                            AttPermissions::READABLE | AttPermissions::WRITABLE_WITH_RESPONSE,
+                        ),
+                        vec![value],
+                    )
+                })
+                .collect(),
+        ))
+    }
+
+    #[test]
+    fn test_allocate_sequential() {
+        let mut allocator = HandleAllocator::new();
+
+        let first = allocator.allocate(5);
+        let second = allocator.allocate(3);
+
+        assert_eq!(first, Some(range(1, 5)));
+        assert_eq!(second, Some(range(6, 8)));
+    }
+
+    #[test]
+    fn test_allocate_fills_gap() {
+        // arrange: reserve two ranges with a gap of 4 handles between them
+        let mut allocator = HandleAllocator::new();
+        allocator.reserve(range(1, 5)).unwrap();
+        allocator.reserve(range(10, 20)).unwrap();
+
+        // act
+        let too_large = allocator.allocate(5);
+        let fits = allocator.allocate(4);
+
+        // assert: the large range went after the reserved ones, the small one in the gap
+        assert_eq!(too_large, Some(range(21, 25)));
+        assert_eq!(fits, Some(range(6, 9)));
+    }
+
+    #[test]
+    fn test_allocate_last_handle() {
+        let mut allocator = HandleAllocator::new();
+        allocator.reserve(range(1, 0xFFFD)).unwrap();
+
+        let last = allocator.allocate(2);
+        let none = allocator.allocate(1);
+
+        assert_eq!(last, Some(range(0xFFFE, 0xFFFF)));
+        assert_eq!(none, None);
+    }
+
+    #[test]
+    fn test_allocate_zero_handles() {
+        let mut allocator = HandleAllocator::new();
+
+        assert_eq!(allocator.allocate(0), None);
+    }
+
+    #[test]
+    fn test_reserve_overlapping() {
+        let mut allocator = HandleAllocator::new();
+        allocator.reserve(range(5, 10)).unwrap();
+
+        assert!(allocator.reserve(range(10, 12)).is_err());
+        assert!(allocator.reserve(range(1, 5)).is_err());
+        assert!(allocator.reserve(range(6, 7)).is_err());
+        assert!(allocator.reserve(range(1, 4)).is_ok());
+        assert!(allocator.reserve(range(11, 12)).is_ok());
+    }
+
+    #[test]
+    fn test_reserve_invalid_range() {
+        let mut allocator = HandleAllocator::new();
+
+        assert!(allocator.reserve(range(0, 3)).is_err());
+        assert!(allocator.reserve(range(5, 4)).is_err());
+    }
+
+    #[test]
+    fn test_release() {
+        let mut allocator = HandleAllocator::new();
+        let first = allocator.allocate(5).unwrap();
+        allocator.allocate(5).unwrap();
+
+        allocator.release(&first);
+        let reallocated = allocator.allocate(3);
+
+        assert_eq!(reallocated, Some(range(1, 3)));
+    }
+
+    #[test]
+    fn test_list_attributes_in_handle_order() {
+        // arrange: add the backends out of order
+        let mut db = CompositeAttDatabase::new();
+        db.add_backend(range(10, 19), make_backend(&[10, 11], 2)).unwrap();
+        db.add_backend(range(1, 9), make_backend(&[1, 2], 1)).unwrap();
+
+        // act
+        let handles =
+            db.list_attributes().into_iter().map(|attr| attr.handle.0).collect::<Vec<_>>();
+
+        // assert
+        assert_eq!(handles, vec![1, 2, 10, 11]);
+    }
+
+    #[test]
+    fn test_attributes_outside_range_ignored() {
+        // arrange: a backend with an attribute beyond its range
+        let mut db = CompositeAttDatabase::new();
+        db.add_backend(range(1, 9), make_backend(&[1, 10], 1)).unwrap();
+        db.add_backend(range(10, 19), make_backend(&[10], 2)).unwrap();
+
+        // act
+        let handles =
+            db.list_attributes().into_iter().map(|attr| attr.handle.0).collect::<Vec<_>>();
+
+        // assert: only the owner of handle 10 exposes it
+        assert_eq!(handles, vec![1, 10]);
+        assert_eq!(tokio_test::block_on(db.read_attribute(AttHandle(10))), Ok(vec![2].into()));
+    }
+
+    #[test]
+    fn test_attributes_in_range_across_backends() {
+        // arrange: a backend with an attribute beyond its range
+        let mut db = CompositeAttDatabase::new();
+        db.add_backend(range(1, 9), make_backend(&[1, 2, 10], 1)).unwrap();
+        db.add_backend(range(10, 19), make_backend(&[10, 11, 12], 2)).unwrap();
+
+        // act
+        let handles = db
+            .attributes_in_range(AttHandle(2), AttHandle(11), None)
+            .into_iter()
+            .map(|attr| attr.handle.0)
+            .collect::<Vec<_>>();
+
+        // assert: each backend only contributes the part of the range it owns
+        assert_eq!(handles, vec![2, 10, 11]);
+    }
+
+    #[test]
+    fn test_read_routed_to_owner() {
+        let mut db = CompositeAttDatabase::new();
+        db.add_backend(range(1, 9), make_backend(&[1], 1)).unwrap();
+        db.add_backend(range(10, 19), make_backend(&[10], 2)).unwrap();
+
+        assert_eq!(tokio_test::block_on(db.read_attribute(AttHandle(1))), Ok(vec![1].into()));
+        assert_eq!(tokio_test::block_on(db.read_attribute(AttHandle(10))), Ok(vec![2].into()));
+    }
+
+    #[test]
+    fn test_read_unowned_handle() {
+        let mut db = CompositeAttDatabase::new();
+        db.add_backend(range(1, 9), make_backend(&[1], 1)).unwrap();
+
+        let res = tokio_test::block_on(db.read_attribute(AttHandle(20)));
+
+        assert_eq!(res, Err(AttErrorCode::INVALID_HANDLE.into()));
+    }
+
+    #[test]
+    fn test_write_routed_to_owner() {
+        // arrange
+        let mut db = CompositeAttDatabase::new();
+        let first = make_backend(&[1], 1);
+        let second = make_backend(&[10], 2);
+        db.add_backend(range(1, 9), first.clone()).unwrap();
+        db.add_backend(range(10, 19), second.clone()).unwrap();
+
+        // act
+        let res = tokio_test::block_on(db.write_attribute(AttHandle(10), 0, &[3, 4]));
+
+        // assert: only the owner was written
+        assert_eq!(res, Ok(()));
+        assert_eq!(
+            tokio_test::block_on(second.read_attribute(AttHandle(10))),
+            Ok(vec![3, 4].into())
+        );
+        assert_eq!(tokio_test::block_on(first.read_attribute(AttHandle(1))), Ok(vec![1].into()));
+    }
+
+    #[test]
+    fn test_write_unowned_handle() {
+        let db = CompositeAttDatabase::new();
+
+        let res = tokio_test::block_on(db.write_attribute(AttHandle(1), 0, &[1]));
+
+        assert_eq!(res, Err(AttErrorCode::INVALID_HANDLE.into()));
+    }
+
+    #[test]
+    fn test_overlapping_backend_rejected() {
+        let mut db = CompositeAttDatabase::new();
+        db.add_backend(range(1, 9), make_backend(&[1], 1)).unwrap();
+
+        let res = db.add_backend(range(5, 15), make_backend(&[10], 2));
+
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_backend_in_allocated_range() {
+        // arrange
+        let mut db = CompositeAttDatabase::new();
+        db.add_backend(range(1, 9), make_backend(&[1], 1)).unwrap();
+
+        // act: allocate a range, and populate a backend in it
+        let allocated = db.allocate_range(5).unwrap();
+        let res = db.add_backend(allocated.clone(), make_backend(&[allocated.start().0], 2));
+
+        // assert
+        assert_eq!(allocated, range(10, 14));
+        assert!(res.is_ok());
+        assert_eq!(tokio_test::block_on(db.read_attribute(AttHandle(10))), Ok(vec![2].into()));
+    }
+
+    #[test]
+    fn test_remove_backend() {
+        // arrange
+        let mut db = CompositeAttDatabase::new();
+        db.add_backend(range(1, 9), make_backend(&[1], 1)).unwrap();
+
+        // act
+        db.remove_backend(&range(1, 9)).unwrap();
+
+        // assert: the handles are gone, and can be reused
+        assert_eq!(db.list_attributes(), vec![]);
+        assert_eq!(
+            tokio_test::block_on(db.read_attribute(AttHandle(1))),
+            Err(AttErrorCode::INVALID_HANDLE.into())
+        );
+        assert_eq!(db.allocate_range(9), Some(range(1, 9)));
+    }
+
+    #[test]
+    fn test_change_awareness_aggregated() {
+        // arrange
+        let mut db = CompositeAttDatabase::new();
+        let first = make_backend(&[1], 1);
+        let second = make_backend(&[10], 2);
+        db.add_backend(range(1, 9), first.clone()).unwrap();
+        db.add_backend(range(10, 19), second.clone()).unwrap();
+
+        // act: one backend is change-unaware
+        second.set_change_aware(false);
+        let before = db.is_change_aware();
+        db.mark_change_aware();
+
+        // assert
+        assert!(!before);
+        assert!(db.is_change_aware());
+        assert!(second.is_change_aware());
+    }
+}
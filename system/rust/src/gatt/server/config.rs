@@ -0,0 +1,293 @@
+//! The limits enforced by a GATT server, which the integrator may tighten
+//! below those allowed by the spec, and the optional features it may turn
+//! off (e.g. behind a flag, while rolling them out).
+
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+
+use crate::{gatt::channel::ATT_TRANSACTION_TIMEOUT, packets::AttErrorCode};
+
+use super::{
+    att_database::MAX_ATTRIBUTE_VALUE_LEN, notification_handler::MAX_QUEUED_NOTIFICATIONS,
+};
+
+/// The number of prepared writes a client may buffer on a bearer by default
+pub const DEFAULT_MAX_PREPARED_WRITES: usize = 256;
+
+/// The total size of the values of the prepared writes a client may buffer on
+/// a bearer by default, in bytes
+pub const DEFAULT_MAX_PREPARED_WRITE_BYTES: usize = 8 * MAX_ATTRIBUTE_VALUE_LEN;
+
+/// The number of EATT bearers a client may open on a connection by default
+pub const DEFAULT_MAX_EATT_BEARERS: usize = 5;
+
+/// What a bearer does with a write request identical to the last request it
+/// answered, e.g. one retransmitted by a misbehaving client after receiving
+/// the reply. Such a request cannot be told apart from a client legitimately
+/// writing the same value again.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DuplicateRequestPolicy {
+    /// Process it again, like any other request
+    #[default]
+    Process,
+    /// If it arrives within the given window after the reply was sent, resend
+    /// that reply without processing the request again, so a non-idempotent
+    /// write is not executed twice. Read requests are always processed again,
+    /// since they have no side effects and the value read may have changed.
+    ResendResponse {
+        /// How long after the reply a duplicate is considered a retransmission
+        window: Duration,
+    },
+}
+
+/// What a bearer does when the value of an attribute changes in the middle of
+/// a long read, i.e. between the ATT_READ_REQ (or the ATT_READ_BLOB_REQ at
+/// offset 0) and the ATT_READ_BLOB_REQs reading the rest of it. The change is
+/// noticed when the length of the value differs, or when the database reports
+/// another version of it (see AttDatabase::value_version()).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TornReadPolicy {
+    /// Fail the segment with UNLIKELY_ERROR, rather than let the client stitch
+    /// the value together from two versions. The client may then restart the
+    /// long read from offset 0, which reads the new version throughout.
+    #[default]
+    Fail,
+    /// Serve the segment from the new version anyway, as before such changes
+    /// were noticed
+    Serve,
+}
+
+/// Limits the rate at which a client may make requests that fail because they
+/// are malformed or not permitted (see ErrorRateLimit::counts()), as a
+/// scanner probing every handle would. Once a client makes more of them
+/// within a second, each of its requests is held back for the response delay
+/// before being processed, until its rate drops back down. Requests rejected
+/// before being processed (e.g. with an unknown opcode) are still answered
+/// right away.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ErrorRateLimit {
+    /// The number of failed requests a client may make within a second
+    pub max_errors_per_second: usize,
+    /// How long each request of a client over the limit is held back
+    pub response_delay: Duration,
+}
+
+impl ErrorRateLimit {
+    /// Whether a request failing with the given error counts towards the
+    /// limit. Errors that well-behaved clients run into during discovery and
+    /// long reads (e.g. ATTRIBUTE_NOT_FOUND) do not.
+    pub fn counts(error_code: AttErrorCode) -> bool {
+        matches!(
+            error_code,
+            AttErrorCode::INVALID_HANDLE
+                | AttErrorCode::READ_NOT_PERMITTED
+                | AttErrorCode::WRITE_NOT_PERMITTED
+                | AttErrorCode::INVALID_PDU
+                | AttErrorCode::INSUFFICIENT_AUTHENTICATION
+                | AttErrorCode::REQUEST_NOT_SUPPORTED
+                | AttErrorCode::INSUFFICIENT_AUTHORIZATION
+                | AttErrorCode::INSUFFICIENT_ENCRYPTION
+        )
+    }
+}
+
+/// The configuration of a GATT server
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GattServerConfig {
+    /// The maximum length of an attribute value, in bytes. Values written by
+    /// clients (including those assembled from prepared writes) and static
+    /// values registered with a service may not exceed it.
+    pub max_attribute_length: usize,
+    /// The maximum number of prepared writes buffered on a bearer, beyond which
+    /// further ones are rejected with PREPARE_QUEUE_FULL
+    pub max_prepared_writes: usize,
+    /// The maximum total size of the values of the prepared writes buffered on
+    /// a bearer, in bytes, beyond which further ones are rejected with
+    /// PREPARE_QUEUE_FULL
+    pub max_prepared_write_bytes: usize,
+    /// What to do with a write request identical to the last request answered
+    /// on the same bearer
+    pub duplicate_requests: DuplicateRequestPolicy,
+    /// Whether values notified to every client with GattModule::notify_all()
+    /// are truncated to the payload recommended for the MTU and LE data length
+    /// of each connection, rather than to its ATT_MTU-3
+    pub enforce_notification_payload: bool,
+    /// The limit on the rate of failed requests of each client, if any
+    pub error_rate_limit: Option<ErrorRateLimit>,
+    /// Whether clients may open EATT bearers. If not, the server does not
+    /// advertise support for them, and rejects those that are opened anyway.
+    pub eatt_enabled: bool,
+    /// The maximum number of EATT bearers a client may open on a connection
+    pub max_eatt_bearers: usize,
+    /// Whether clients may enable robust caching through the Client Supported
+    /// Features characteristic. If not, the bit is ignored, so every client
+    /// stays change-aware and relies on Service Changed indications alone.
+    pub robust_caching_enabled: bool,
+    /// Whether ATT_SIGNED_WRITE_CMDs are processed. If not, they are dropped.
+    pub signed_writes_enabled: bool,
+    /// The maximum number of notifications queued on a connection, beyond
+    /// which further ones are rejected
+    pub max_queued_notifications: usize,
+    /// What to do when a value changes in the middle of a long read
+    pub torn_reads: TornReadPolicy,
+    /// If set, the consecutive writes of a client to managed CCCDs (e.g. those
+    /// of a bonded client restoring its subscriptions as it reconnects) are
+    /// handled as a burst, which ends with its next other request, or once it
+    /// makes no request for this long. The subscriptions take effect right
+    /// away, but those of a bonded client are saved once, and the listeners
+    /// of the database are told of them together, when the burst ends.
+    pub client_configuration_burst_window: Option<Duration>,
+}
+
+impl Default for GattServerConfig {
+    fn default() -> Self {
+        Self {
+            max_attribute_length: MAX_ATTRIBUTE_VALUE_LEN,
+            max_prepared_writes: DEFAULT_MAX_PREPARED_WRITES,
+            max_prepared_write_bytes: DEFAULT_MAX_PREPARED_WRITE_BYTES,
+            duplicate_requests: DuplicateRequestPolicy::Process,
+            enforce_notification_payload: false,
+            error_rate_limit: None,
+            eatt_enabled: true,
+            max_eatt_bearers: DEFAULT_MAX_EATT_BEARERS,
+            robust_caching_enabled: true,
+            signed_writes_enabled: true,
+            max_queued_notifications: MAX_QUEUED_NOTIFICATIONS,
+            torn_reads: TornReadPolicy::Fail,
+            client_configuration_burst_window: None,
+        }
+    }
+}
+
+impl GattServerConfig {
+    /// Check that the configuration is usable, and within the limits of the
+    /// spec
+    pub fn validate(&self) -> Result<()> {
+        if !(1..=MAX_ATTRIBUTE_VALUE_LEN).contains(&self.max_attribute_length) {
+            bail!(
+                "max attribute length {} must be between 1 and {MAX_ATTRIBUTE_VALUE_LEN}",
+                self.max_attribute_length
+            );
+        }
+        if self.max_queued_notifications == 0 {
+            bail!("at least one notification must be allowed to be queued");
+        }
+        if let Some(window) = self.client_configuration_burst_window {
+            if window >= ATT_TRANSACTION_TIMEOUT {
+                bail!("CCCD burst window {window:?} must be below the ATT transaction timeout");
+            }
+        }
+        if let Some(limit) = self.error_rate_limit {
+            if limit.response_delay >= ATT_TRANSACTION_TIMEOUT {
+                bail!(
+                    "response delay {:?} must be below the ATT transaction timeout",
+                    limit.response_delay
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that a value of the given length, written from the given offset
+    /// onwards, would fit within the maximum attribute length. Otherwise,
+    /// fails with INVALID_ATTRIBUTE_VALUE_LENGTH.
+    pub fn check_attribute_length(&self, offset: usize, len: usize) -> Result<(), AttErrorCode> {
+        if offset.saturating_add(len) > self.max_attribute_length {
+            return Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH);
+        }
+        Ok(())
+    }
+
+    /// Check that a prepared write of the given length can be buffered in a
+    /// queue already holding the given number of writes, of the given total
+    /// length. Otherwise, fails with PREPARE_QUEUE_FULL.
+    pub fn check_prepared_write(
+        &self,
+        queued_writes: usize,
+        queued_bytes: usize,
+        len: usize,
+    ) -> Result<(), AttErrorCode> {
+        if queued_writes >= self.max_prepared_writes
+            || queued_bytes.saturating_add(len) > self.max_prepared_write_bytes
+        {
+            return Err(AttErrorCode::PREPARE_QUEUE_FULL);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_default_allows_spec_maximum() {
+        let config = GattServerConfig::default();
+
+        assert_eq!(config.check_attribute_length(0, MAX_ATTRIBUTE_VALUE_LEN), Ok(()));
+        assert_eq!(
+            config.check_attribute_length(1, MAX_ATTRIBUTE_VALUE_LEN),
+            Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH)
+        );
+    }
+
+    #[test]
+    fn test_configured_maximum() {
+        let config = GattServerConfig { max_attribute_length: 10, ..Default::default() };
+
+        assert_eq!(config.check_attribute_length(5, 5), Ok(()));
+        assert_eq!(
+            config.check_attribute_length(5, 6),
+            Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH)
+        );
+    }
+
+    #[test]
+    fn test_prepared_write_limits() {
+        let config = GattServerConfig {
+            max_prepared_writes: 2,
+            max_prepared_write_bytes: 10,
+            ..Default::default()
+        };
+
+        assert_eq!(config.check_prepared_write(1, 5, 5), Ok(()));
+        assert_eq!(config.check_prepared_write(2, 0, 0), Err(AttErrorCode::PREPARE_QUEUE_FULL));
+        assert_eq!(config.check_prepared_write(1, 5, 6), Err(AttErrorCode::PREPARE_QUEUE_FULL));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(GattServerConfig::default().validate().is_ok());
+        assert!(GattServerConfig { max_attribute_length: 1, ..Default::default() }
+            .validate()
+            .is_ok());
+        assert!(GattServerConfig { max_attribute_length: 0, ..Default::default() }
+            .validate()
+            .is_err());
+        assert!(GattServerConfig {
+            max_attribute_length: MAX_ATTRIBUTE_VALUE_LEN + 1,
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+        assert!(GattServerConfig { max_queued_notifications: 0, ..Default::default() }
+            .validate()
+            .is_err());
+        assert!(GattServerConfig {
+            error_rate_limit: Some(ErrorRateLimit {
+                max_errors_per_second: 10,
+                response_delay: ATT_TRANSACTION_TIMEOUT,
+            }),
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+        assert!(GattServerConfig {
+            client_configuration_burst_window: Some(ATT_TRANSACTION_TIMEOUT),
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+    }
+}
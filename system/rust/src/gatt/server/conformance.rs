@@ -0,0 +1,450 @@
+//! This module contains a self-test of the behavior of the ATT server against
+//! a library of scenarios derived from the GATT server test cases of the
+//! Bluetooth test suite (GATT/SR/..., as run by PTS during qualification).
+//!
+//! Each scenario runs against a fresh AttPduProcessor, backed by a
+//! TestAttDatabase with a fixed layout, and consists of a script of PDUs sent
+//! by the client, together with the exact reply (if any) that the server must
+//! send to each. Since the PDUs are written out by hand, the scenarios also
+//! pin down the wire format of the replies, so a change that would regress
+//! qualification fails here first.
+//!
+//! The database holds two primary services:
+//!
+//! | Handle | Attribute                                 | Permissions       |
+//! |--------|-------------------------------------------|-------------------|
+//! | 0x0001 | Primary Service 0x180F                    | read              |
+//! | 0x0002 | Characteristic (read, write, write cmd)   | read              |
+//! | 0x0003 | 0x2A19 value (1 octet)                    | read, write (cmd) |
+//! | 0x0004 | Characteristic (read)                     | read              |
+//! | 0x0005 | 0x2A00 value (40 octets)                  | read              |
+//! | 0x0006 | Characteristic User Description           | read              |
+//! | 0x0007 | Primary Service 0x180A                    | read              |
+//! | 0x0008 | Characteristic (write)                    | read              |
+//! | 0x0009 | 0x2A29 value (empty)                      | write             |
+
+use std::{fmt, rc::Rc};
+
+use anyhow::{anyhow, bail, Result};
+use tokio::runtime::Builder;
+
+use crate::{
+    core::uuid::Uuid,
+    gatt::{
+        ids::{AttHandle, TransportIndex},
+        mocks::mock_security_manager::MockSecurityManager,
+        mtu::MAX_ATT_MTU,
+    },
+    packets::Serializable,
+};
+
+use super::{
+    att_database::{AttAttribute, AttPermissions},
+    gatt_database::{
+        CHARACTERISTIC_USER_DESCRIPTION_UUID, CHARACTERISTIC_UUID, PRIMARY_SERVICE_DECLARATION_UUID,
+    },
+    pdu_processor::AttPduProcessor,
+    signature_verifier::SignatureVerifier,
+    test::test_att_db::TestAttDatabase,
+};
+
+/// The length of the value of the long characteristic (at 0x0005), which does
+/// not fit in a single ATT_READ_RSP at the default MTU
+const LONG_VALUE_LEN: usize = 40;
+
+fn long_value() -> Vec<u8> {
+    (0..LONG_VALUE_LEN as u8).collect()
+}
+
+fn make_db() -> TestAttDatabase {
+    let attribute =
+        |handle, type_, permissions| AttAttribute { handle: AttHandle(handle), type_, permissions };
+    TestAttDatabase::new(vec![
+        (
+            attribute(0x0001, PRIMARY_SERVICE_DECLARATION_UUID, AttPermissions::READABLE),
+            vec![0x0F, 0x18],
+        ),
+        (
+            attribute(0x0002, CHARACTERISTIC_UUID, AttPermissions::READABLE),
+            vec![0x0E, 0x03, 0x00, 0x19, 0x2A],
+        ),
+        (
+            attribute(
+                0x0003,
+                Uuid::new(0x2A19),
+                AttPermissions::READABLE
+                    | AttPermissions::WRITABLE_WITH_RESPONSE
+                    | AttPermissions::WRITABLE_WITHOUT_RESPONSE,
+            ),
+            vec![0x64],
+        ),
+        (
+            attribute(0x0004, CHARACTERISTIC_UUID, AttPermissions::READABLE),
+            vec![0x02, 0x05, 0x00, 0x00, 0x2A],
+        ),
+        (attribute(0x0005, Uuid::new(0x2A00), AttPermissions::READABLE), long_value()),
+        (
+            attribute(0x0006, CHARACTERISTIC_USER_DESCRIPTION_UUID, AttPermissions::READABLE),
+            b"name".to_vec(),
+        ),
+        (
+            attribute(0x0007, PRIMARY_SERVICE_DECLARATION_UUID, AttPermissions::READABLE),
+            vec![0x0A, 0x18],
+        ),
+        (
+            attribute(0x0008, CHARACTERISTIC_UUID, AttPermissions::READABLE),
+            vec![0x08, 0x09, 0x00, 0x29, 0x2A],
+        ),
+        (attribute(0x0009, Uuid::new(0x2A29), AttPermissions::WRITABLE_WITH_RESPONSE), vec![]),
+    ])
+}
+
+/// A PDU sent by the client, and the reply expected from the server (None if
+/// it must not reply, as for commands)
+#[derive(Clone, Debug)]
+pub struct Step {
+    /// The PDU sent by the client
+    pub request: Vec<u8>,
+    /// The PDU the server must reply with
+    pub reply: Option<Vec<u8>>,
+}
+
+fn request(request: &[u8], reply: &[u8]) -> Step {
+    Step { request: request.to_vec(), reply: Some(reply.to_vec()) }
+}
+
+fn command(request: &[u8]) -> Step {
+    Step { request: request.to_vec(), reply: None }
+}
+
+fn concat(parts: &[&[u8]]) -> Vec<u8> {
+    parts.concat()
+}
+
+/// A test case, run against a freshly connected client (so at the default
+/// MTU, unless it exchanges it)
+#[derive(Clone, Debug)]
+pub struct Scenario {
+    /// The id of the test case this is derived from
+    pub id: &'static str,
+    /// The name of the procedure under test
+    pub name: &'static str,
+    /// The PDUs exchanged, in order
+    pub steps: Vec<Step>,
+}
+
+/// The library of scenarios, covering the server side of the discovery, read,
+/// and write procedures (Core Spec 5.3 Vol 3G 4.3 to 4.9), and their errors
+pub fn scenarios() -> Vec<Scenario> {
+    let value = long_value();
+    vec![
+        Scenario {
+            id: "GATT/SR/GAC/BV-01-C",
+            name: "Server Exchange MTU",
+            steps: vec![request(&[0x02, 0x40, 0x00], &[0x03, 0x05, 0x02])],
+        },
+        Scenario {
+            id: "GATT/SR/GAD/BV-01-C",
+            name: "Discover All Primary Services",
+            steps: vec![
+                request(
+                    &[0x10, 0x01, 0x00, 0xFF, 0xFF, 0x00, 0x28],
+                    &[
+                        0x11, 0x06, //
+                        0x01, 0x00, 0x06, 0x00, 0x0F, 0x18, //
+                        0x07, 0x00, 0x09, 0x00, 0x0A, 0x18,
+                    ],
+                ),
+                request(
+                    &[0x10, 0x0A, 0x00, 0xFF, 0xFF, 0x00, 0x28],
+                    &[0x01, 0x10, 0x0A, 0x00, 0x0A],
+                ),
+            ],
+        },
+        Scenario {
+            id: "GATT/SR/GAD/BV-02-C",
+            name: "Discover Primary Services by Service UUID",
+            steps: vec![
+                request(
+                    &[0x06, 0x01, 0x00, 0xFF, 0xFF, 0x00, 0x28, 0x0A, 0x18],
+                    &[0x07, 0x07, 0x00, 0x09, 0x00],
+                ),
+                request(
+                    &[0x06, 0x0A, 0x00, 0xFF, 0xFF, 0x00, 0x28, 0x0A, 0x18],
+                    &[0x01, 0x06, 0x0A, 0x00, 0x0A],
+                ),
+            ],
+        },
+        Scenario {
+            id: "GATT/SR/GAD/BV-04-C",
+            name: "Discover All Characteristics of a Service",
+            steps: vec![
+                request(
+                    &[0x08, 0x01, 0x00, 0x06, 0x00, 0x03, 0x28],
+                    &[
+                        0x09, 0x07, //
+                        0x02, 0x00, 0x0E, 0x03, 0x00, 0x19, 0x2A, //
+                        0x04, 0x00, 0x02, 0x05, 0x00, 0x00, 0x2A,
+                    ],
+                ),
+                request(
+                    &[0x08, 0x05, 0x00, 0x06, 0x00, 0x03, 0x28],
+                    &[0x01, 0x08, 0x05, 0x00, 0x0A],
+                ),
+            ],
+        },
+        Scenario {
+            id: "GATT/SR/GAD/BV-06-C",
+            name: "Discover All Characteristic Descriptors",
+            steps: vec![request(
+                &[0x04, 0x06, 0x00, 0x06, 0x00],
+                &[0x05, 0x01, 0x06, 0x00, 0x01, 0x29],
+            )],
+        },
+        Scenario {
+            id: "GATT/SR/GAR/BV-01-C",
+            name: "Read Characteristic Value",
+            steps: vec![request(&[0x0A, 0x03, 0x00], &[0x0B, 0x64])],
+        },
+        Scenario {
+            id: "GATT/SR/GAR/BI-01-C",
+            name: "Read Characteristic Value - Invalid Handle",
+            steps: vec![request(&[0x0A, 0x20, 0x00], &[0x01, 0x0A, 0x20, 0x00, 0x01])],
+        },
+        Scenario {
+            id: "GATT/SR/GAR/BI-02-C",
+            name: "Read Characteristic Value - Read Not Permitted",
+            steps: vec![request(&[0x0A, 0x09, 0x00], &[0x01, 0x0A, 0x09, 0x00, 0x02])],
+        },
+        Scenario {
+            id: "GATT/SR/GAR/BV-03-C",
+            name: "Read Using Characteristic UUID",
+            steps: vec![request(
+                &[0x08, 0x01, 0x00, 0xFF, 0xFF, 0x19, 0x2A],
+                &[0x09, 0x03, 0x03, 0x00, 0x64],
+            )],
+        },
+        Scenario {
+            id: "GATT/SR/GAR/BV-04-C",
+            name: "Read Long Characteristic Value",
+            steps: vec![
+                // the ATT_READ_RSP is truncated to ATT_MTU-1
+                request(&[0x0A, 0x05, 0x00], &concat(&[&[0x0B], &value[..22]])),
+                request(&[0x0C, 0x05, 0x00, 0x16, 0x00], &concat(&[&[0x0D], &value[22..]])),
+            ],
+        },
+        Scenario {
+            id: "GATT/SR/GAR/BI-13-C",
+            name: "Read Long Characteristic Value - Invalid Offset",
+            steps: vec![request(&[0x0C, 0x05, 0x00, 0x29, 0x00], &[0x01, 0x0C, 0x05, 0x00, 0x07])],
+        },
+        Scenario {
+            id: "GATT/SR/GAW/BV-01-C",
+            name: "Write Without Response",
+            steps: vec![
+                command(&[0x52, 0x03, 0x00, 0x32]),
+                request(&[0x0A, 0x03, 0x00], &[0x0B, 0x32]),
+            ],
+        },
+        Scenario {
+            id: "GATT/SR/GAW/BV-03-C",
+            name: "Write Characteristic Value",
+            steps: vec![
+                request(&[0x12, 0x03, 0x00, 0x50], &[0x13]),
+                request(&[0x0A, 0x03, 0x00], &[0x0B, 0x50]),
+            ],
+        },
+        Scenario {
+            id: "GATT/SR/GAW/BI-02-C",
+            name: "Write Characteristic Value - Invalid Handle",
+            steps: vec![request(&[0x12, 0x20, 0x00, 0x01], &[0x01, 0x12, 0x20, 0x00, 0x01])],
+        },
+        Scenario {
+            id: "GATT/SR/GAW/BI-03-C",
+            name: "Write Characteristic Value - Write Not Permitted",
+            steps: vec![request(&[0x12, 0x05, 0x00, 0x01], &[0x01, 0x12, 0x05, 0x00, 0x03])],
+        },
+        Scenario {
+            id: "GATT/SR/GAW/BV-05-C",
+            name: "Write Long Characteristic Values",
+            steps: vec![
+                // each ATT_PREPARE_WRITE_RSP echoes its request
+                request(
+                    &concat(&[&[0x16, 0x03, 0x00, 0x00, 0x00], &value[..18]]),
+                    &concat(&[&[0x17, 0x03, 0x00, 0x00, 0x00], &value[..18]]),
+                ),
+                request(
+                    &concat(&[&[0x16, 0x03, 0x00, 0x12, 0x00], &value[18..28]]),
+                    &concat(&[&[0x17, 0x03, 0x00, 0x12, 0x00], &value[18..28]]),
+                ),
+                request(&[0x18, 0x01], &[0x19]),
+                request(&[0x0A, 0x03, 0x00], &concat(&[&[0x0B], &value[..22]])),
+                request(&[0x0C, 0x03, 0x00, 0x16, 0x00], &concat(&[&[0x0D], &value[22..28]])),
+            ],
+        },
+        Scenario {
+            id: "GATT/SR/GAW/BI-07-C",
+            name: "Write Long Characteristic Values - Invalid Handle",
+            steps: vec![request(
+                &[0x16, 0x20, 0x00, 0x00, 0x00, 0x01],
+                &[0x01, 0x16, 0x20, 0x00, 0x01],
+            )],
+        },
+        Scenario {
+            id: "GATT/SR/GAW/BI-08-C",
+            name: "Write Long Characteristic Values - Write Not Permitted",
+            steps: vec![request(
+                &[0x16, 0x05, 0x00, 0x00, 0x00, 0x01],
+                &[0x01, 0x16, 0x05, 0x00, 0x03],
+            )],
+        },
+        Scenario {
+            id: "GATT/SR/UNS/BI-01-C",
+            name: "Unsupported Request",
+            // 0x14 is a reserved request opcode
+            steps: vec![request(&[0x14, 0x01, 0x00], &[0x01, 0x14, 0x00, 0x00, 0x06])],
+        },
+    ]
+}
+
+/// Run a scenario against a fresh server, failing at the first reply that
+/// differs from the one expected
+pub fn run_scenario(scenario: &Scenario) -> Result<()> {
+    let rt = Builder::new_current_thread().enable_time().build()?;
+    rt.block_on(async {
+        let mut processor = AttPduProcessor::new(
+            make_db(),
+            SignatureVerifier::new(TransportIndex(1), Rc::new(MockSecurityManager::new())),
+            MAX_ATT_MTU,
+        );
+        for (i, Step { request, reply: expected }) in scenario.steps.iter().enumerate() {
+            let reply = match processor.process_pdu(request).await {
+                Some(reply) => Some(reply.to_vec().map_err(|e| anyhow!("{e:?}"))?),
+                None => None,
+            };
+            if reply != *expected {
+                bail!(
+                    "step {}: {request:02x?} got reply {reply:02x?}, expected {expected:02x?}",
+                    i + 1
+                );
+            }
+        }
+        Ok(())
+    })
+}
+
+/// The outcome of a single scenario
+#[derive(Debug)]
+pub struct ScenarioResult {
+    /// The id of the test case
+    pub id: &'static str,
+    /// The name of the procedure under test
+    pub name: &'static str,
+    /// Why the scenario failed, if it did
+    pub failure: Option<String>,
+}
+
+/// The outcome of every scenario in the library, which displays as a
+/// pass/fail line per test case followed by a summary
+#[derive(Debug)]
+pub struct ConformanceReport {
+    /// The results, in the order the scenarios ran
+    pub results: Vec<ScenarioResult>,
+}
+
+impl ConformanceReport {
+    /// Whether every scenario passed
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(|result| result.failure.is_none())
+    }
+
+    /// The scenarios that failed
+    pub fn failures(&self) -> impl Iterator<Item = &ScenarioResult> {
+        self.results.iter().filter(|result| result.failure.is_some())
+    }
+}
+
+impl fmt::Display for ConformanceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for ScenarioResult { id, name, failure } in &self.results {
+            match failure {
+                None => writeln!(f, "PASS {id} {name}")?,
+                Some(failure) => writeln!(f, "FAIL {id} {name}: {failure}")?,
+            }
+        }
+        let failed = self.failures().count();
+        write!(f, "{} passed, {failed} failed", self.results.len() - failed)
+    }
+}
+
+/// Run every scenario in the library
+pub fn run_conformance_suite() -> ConformanceReport {
+    ConformanceReport {
+        results: scenarios()
+            .iter()
+            .map(|scenario| ScenarioResult {
+                id: scenario.id,
+                name: scenario.name,
+                failure: run_scenario(scenario).err().map(|err| format!("{err:#}")),
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_conformance_suite_passes() {
+        let report = run_conformance_suite();
+
+        assert!(report.passed(), "{report}");
+    }
+
+    #[test]
+    fn test_scenario_ids_unique() {
+        let mut ids = scenarios().iter().map(|scenario| scenario.id).collect::<Vec<_>>();
+        let count = ids.len();
+
+        ids.sort();
+        ids.dedup();
+
+        assert_eq!(ids.len(), count);
+    }
+
+    #[test]
+    fn test_mismatched_reply_reported() {
+        // arrange: a scenario expecting the wrong value
+        let scenario = Scenario {
+            id: "GATT/SR/GAR/BV-01-C",
+            name: "Read Characteristic Value",
+            steps: vec![request(&[0x0A, 0x03, 0x00], &[0x0B, 0x65])],
+        };
+
+        // act
+        let report = ConformanceReport {
+            results: vec![ScenarioResult {
+                id: scenario.id,
+                name: scenario.name,
+                failure: run_scenario(&scenario).err().map(|err| format!("{err:#}")),
+            }],
+        };
+
+        // assert
+        assert!(!report.passed());
+        assert!(report.to_string().starts_with("FAIL GATT/SR/GAR/BV-01-C"));
+        assert!(report.to_string().ends_with("0 passed, 1 failed"));
+    }
+
+    #[test]
+    fn test_unexpected_reply_to_command_reported() {
+        let scenario = Scenario {
+            id: "GATT/SR/GAW/BV-01-C",
+            name: "Write Without Response",
+            steps: vec![request(&[0x52, 0x03, 0x00, 0x32], &[0x13])],
+        };
+
+        assert!(run_scenario(&scenario).is_err());
+    }
+}
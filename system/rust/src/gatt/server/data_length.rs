@@ -0,0 +1,85 @@
+//! Notifications are carried in L2CAP basic frames, which the link layer
+//! fragments into PDUs of at most the negotiated LE data length (Core Spec 5.3
+//! Vol 6B 4.5.10). A value that overflows the last of these PDUs by a few bytes
+//! costs a whole connection event slot for them, so streaming profiles get the
+//! best throughput from values that exactly fill the PDUs they are sent in.
+
+use anyhow::{bail, Result};
+
+/// The LE data length (maximum LL payload) of a link until the controller
+/// reports a larger one (Core Spec 5.3 Vol 6B 4.5.10)
+pub const DEFAULT_LE_DATA_LENGTH: usize = 27;
+
+/// The largest LE data length a controller may negotiate (Core Spec 5.3 Vol
+/// 6B 4.5.10)
+pub const MAX_LE_DATA_LENGTH: usize = 251;
+
+/// The length of the header of an L2CAP basic frame (Core Spec 5.3 Vol 3A 3.1)
+const L2CAP_HEADER_LEN: usize = 4;
+
+/// The length of the opcode and handle of an ATT_HANDLE_VALUE_NTF (Core Spec
+/// 5.3 Vol 3F 3.4.7.1)
+const NOTIFICATION_HEADER_LEN: usize = 3;
+
+/// Check that a data length reported by the controller is within the limits
+/// of the spec
+pub fn validate_data_length(data_length: usize) -> Result<()> {
+    if !(DEFAULT_LE_DATA_LENGTH..=MAX_LE_DATA_LENGTH).contains(&data_length) {
+        bail!(
+            "LE data length {data_length} must be between {DEFAULT_LE_DATA_LENGTH} and {MAX_LE_DATA_LENGTH}"
+        );
+    }
+    Ok(())
+}
+
+/// The length of the largest notified value that fits in the given MTU and
+/// exactly fills the LL PDUs it is fragmented into, given the LE data length
+/// of the link. If even a single LL PDU does not fit in the MTU, this is the
+/// largest value allowed by the MTU (ATT_MTU-3).
+pub fn recommended_notification_payload(mtu: usize, data_length: usize) -> usize {
+    let max_payload = mtu - NOTIFICATION_HEADER_LEN;
+    let pdus = (L2CAP_HEADER_LEN + mtu) / data_length;
+    if pdus == 0 {
+        return max_payload;
+    }
+    (pdus * data_length - L2CAP_HEADER_LEN - NOTIFICATION_HEADER_LEN).min(max_payload)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::gatt::mtu::{DEFAULT_ATT_MTU, MAX_ATT_MTU};
+
+    #[test]
+    fn test_default_mtu_and_data_length() {
+        // the whole frame fits in a single LL PDU
+        assert_eq!(recommended_notification_payload(DEFAULT_ATT_MTU, DEFAULT_LE_DATA_LENGTH), 20);
+    }
+
+    #[test]
+    fn test_mtu_matched_to_data_length() {
+        // 247 + 4 octets fill a single LL PDU of the maximum length
+        assert_eq!(recommended_notification_payload(247, MAX_LE_DATA_LENGTH), 244);
+    }
+
+    #[test]
+    fn test_trailing_fragment_avoided() {
+        // a 97-octet value would need a fourth LL PDU for its last 23 octets
+        assert_eq!(recommended_notification_payload(100, DEFAULT_LE_DATA_LENGTH), 74);
+        assert_eq!(recommended_notification_payload(MAX_ATT_MTU, MAX_LE_DATA_LENGTH), 495);
+    }
+
+    #[test]
+    fn test_mtu_smaller_than_data_length() {
+        assert_eq!(recommended_notification_payload(100, MAX_LE_DATA_LENGTH), 97);
+    }
+
+    #[test]
+    fn test_validate_data_length() {
+        assert!(validate_data_length(DEFAULT_LE_DATA_LENGTH).is_ok());
+        assert!(validate_data_length(MAX_LE_DATA_LENGTH).is_ok());
+        assert!(validate_data_length(26).is_err());
+        assert!(validate_data_length(252).is_err());
+    }
+}
@@ -0,0 +1,160 @@
+//! This module defines the events that the GATT server surfaces to the upper
+//! layers (e.g. the shims implementing the Android GATT server callbacks), and
+//! fans them out to every registered listener.
+
+use std::{cell::RefCell, rc::Rc};
+
+use crate::gatt::ids::{AttHandle, ConnectionId, Transport};
+
+use super::client_configuration::ClientConfiguration;
+
+/// An event on a connection to a GATT server
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GattServerEvent {
+    /// A client connected to the server
+    ConnectionOpened {
+        /// The connection
+        conn_id: ConnectionId,
+        /// The transport the client connected over
+        transport: Transport,
+    },
+    /// A client disconnected from the server
+    ConnectionClosed {
+        /// The connection
+        conn_id: ConnectionId,
+    },
+    /// The MTU of the unenhanced bearer of a connection was exchanged
+    MtuChanged {
+        /// The connection
+        conn_id: ConnectionId,
+        /// The MTU now in use
+        mtu: usize,
+    },
+    /// A client wrote to a CCCD managed by the server
+    CccdChanged {
+        /// The connection
+        conn_id: ConnectionId,
+        /// The handle of the characteristic whose CCCD was written
+        handle: AttHandle,
+        /// The new configuration of the characteristic for this client
+        bits: ClientConfiguration,
+    },
+    /// A client confirmed an indication
+    IndicationConfirmed {
+        /// The connection
+        conn_id: ConnectionId,
+        /// The indicated characteristic
+        handle: AttHandle,
+    },
+    /// The notification queue of a connection filled up past its high
+    /// watermark, or drained back down to its low watermark
+    Congestion {
+        /// The connection
+        conn_id: ConnectionId,
+        /// Whether the connection is now congested
+        congested: bool,
+    },
+    /// A client exceeded the ErrorRateLimit of the server (e.g. a scanner
+    /// probing every handle), so its requests are now held back. The upper
+    /// layer may decide to disconnect it.
+    ErrorRateExceeded {
+        /// The connection
+        conn_id: ConnectionId,
+        /// The number of failed requests it made within the last second
+        errors: usize,
+    },
+}
+
+/// A listener for the events on a GATT server
+pub trait GattServerEventListener {
+    /// Invoked on every event, in the order in which they occurred
+    fn on_server_event(&self, event: GattServerEvent);
+}
+
+/// The listeners registered for the events on a GATT server. Clones share the
+/// same set of listeners.
+#[derive(Clone, Default)]
+pub struct GattServerEvents {
+    listeners: Rc<RefCell<Vec<Rc<dyn GattServerEventListener>>>>,
+}
+
+impl GattServerEvents {
+    /// Constructor, with no listeners
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a listener, which will receive all subsequent events
+    pub fn register_listener(&self, listener: Rc<dyn GattServerEventListener>) {
+        self.listeners.borrow_mut().push(listener);
+    }
+
+    /// Dispatch an event to all listeners
+    pub fn emit(&self, event: GattServerEvent) {
+        // a listener may register another listener while handling the event
+        let listeners = self.listeners.borrow().clone();
+        for listener in listeners {
+            listener.on_server_event(event.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::gatt::ids::{ServerId, TransportIndex};
+
+    use super::*;
+
+    const CONN_ID: ConnectionId = ConnectionId::new(TransportIndex(1), ServerId(2));
+
+    #[derive(Default)]
+    struct RecordingListener(RefCell<Vec<GattServerEvent>>);
+
+    impl GattServerEventListener for RecordingListener {
+        fn on_server_event(&self, event: GattServerEvent) {
+            self.0.borrow_mut().push(event);
+        }
+    }
+
+    #[test]
+    fn test_event_dispatched_to_all_listeners() {
+        // arrange
+        let events = GattServerEvents::new();
+        let first = Rc::new(RecordingListener::default());
+        let second = Rc::new(RecordingListener::default());
+        events.register_listener(first.clone());
+        events.register_listener(second.clone());
+
+        // act
+        events
+            .emit(GattServerEvent::ConnectionOpened { conn_id: CONN_ID, transport: Transport::Le });
+
+        // assert
+        for listener in [first, second] {
+            assert_eq!(
+                *listener.0.borrow(),
+                vec![GattServerEvent::ConnectionOpened {
+                    conn_id: CONN_ID,
+                    transport: Transport::Le
+                }]
+            );
+        }
+    }
+
+    #[test]
+    fn test_clones_share_listeners() {
+        // arrange
+        let events = GattServerEvents::new();
+        let listener = Rc::new(RecordingListener::default());
+        events.clone().register_listener(listener.clone());
+
+        // act
+        events.emit(GattServerEvent::MtuChanged { conn_id: CONN_ID, mtu: 100 });
+
+        // assert
+        assert_eq!(
+            *listener.0.borrow(),
+            vec![GattServerEvent::MtuChanged { conn_id: CONN_ID, mtu: 100 }]
+        );
+    }
+}
@@ -0,0 +1,290 @@
+//! This module contains the fuzzing harness for the ATT server, which feeds
+//! arbitrary PDUs into an AttPduProcessor backed by a TestAttDatabase and
+//! checks that every request gets exactly one response, and every command
+//! none.
+//!
+//! The input is consumed as a sequence of PDUs. Each is either taken verbatim
+//! from the input, or built from a well-formed header (a client opcode, plus
+//! the handles it expects, chosen mostly from those in the database) followed
+//! by arbitrary parameters, so that the fuzzer reaches the transaction
+//! handlers rather than being rejected by the parser.
+
+use std::rc::Rc;
+
+use tokio::runtime::Builder;
+
+use crate::{
+    core::uuid::Uuid,
+    gatt::{
+        ids::{AttHandle, TransportIndex},
+        mocks::mock_security_manager::MockSecurityManager,
+        mtu::MAX_ATT_MTU,
+        opcode_types::{classify_opcode, OperationType},
+    },
+    packets::{AttBuilder, AttChild, AttOpcode, OwnedAttView, OwnedPacket, Serializable},
+};
+
+use super::{
+    att_database::{AttAttribute, AttPermissions, MAX_ATTRIBUTE_VALUE_LEN},
+    gatt_database::{CHARACTERISTIC_UUID, PRIMARY_SERVICE_DECLARATION_UUID},
+    pdu_decoder::{build_unsupported_request_reply, decode_pdu, DecodedPdu},
+    pdu_processor::AttPduProcessor,
+    signature_verifier::SignatureVerifier,
+    test::test_att_db::TestAttDatabase,
+};
+
+/// The opcodes a client may send to a server
+const CLIENT_OPCODES: [AttOpcode; 15] = [
+    AttOpcode::EXCHANGE_MTU_REQUEST,
+    AttOpcode::FIND_INFORMATION_REQUEST,
+    AttOpcode::FIND_BY_TYPE_VALUE_REQUEST,
+    AttOpcode::READ_BY_TYPE_REQUEST,
+    AttOpcode::READ_REQUEST,
+    AttOpcode::READ_BLOB_REQUEST,
+    AttOpcode::READ_MULTIPLE_REQUEST,
+    AttOpcode::READ_BY_GROUP_TYPE_REQUEST,
+    AttOpcode::WRITE_REQUEST,
+    AttOpcode::PREPARE_WRITE_REQUEST,
+    AttOpcode::EXECUTE_WRITE_REQUEST,
+    AttOpcode::READ_MULTIPLE_VARIABLE_REQUEST,
+    AttOpcode::WRITE_COMMAND,
+    AttOpcode::SIGNED_WRITE_COMMAND,
+    AttOpcode::HANDLE_VALUE_CONFIRMATION,
+];
+
+/// The handles in the test database, plus the boundary values of the handle
+/// range
+const HANDLES: [AttHandle; 8] = [
+    AttHandle(0x0000),
+    AttHandle(0x0001),
+    AttHandle(0x0002),
+    AttHandle(0x0003),
+    AttHandle(0x0004),
+    AttHandle(0x0005),
+    AttHandle(0x0006),
+    AttHandle(0xFFFF),
+];
+
+fn make_db() -> TestAttDatabase {
+    TestAttDatabase::new(vec![
+        (
+            AttAttribute {
+                handle: AttHandle(1),
+                type_: PRIMARY_SERVICE_DECLARATION_UUID,
+                permissions: AttPermissions::READABLE,
+            },
+            vec![0x34, 0x12],
+        ),
+        (
+            AttAttribute {
+                handle: AttHandle(2),
+                type_: CHARACTERISTIC_UUID,
+                permissions: AttPermissions::READABLE,
+            },
+            vec![0x0A, 0x03, 0x00, 0x34, 0x12],
+        ),
+        (
+            AttAttribute {
+                handle: AttHandle(3),
+                type_: Uuid::new(0x1234),
+                permissions: AttPermissions::READABLE
+                    | AttPermissions::WRITABLE_WITH_RESPONSE
+                    | AttPermissions::WRITABLE_WITHOUT_RESPONSE,
+            },
+            vec![0xAB; MAX_ATTRIBUTE_VALUE_LEN],
+        ),
+        (
+            AttAttribute {
+                handle: AttHandle(4),
+                type_: Uuid::new(0x5678),
+                permissions: AttPermissions::READABLE,
+            },
+            vec![1, 2, 3],
+        ),
+        (
+            AttAttribute {
+                handle: AttHandle(5),
+                type_: Uuid::new(0x5678),
+                permissions: AttPermissions::WRITABLE_WITH_RESPONSE,
+            },
+            vec![],
+        ),
+    ])
+}
+
+/// Splits the fuzzer input into PDUs
+struct PduReader<'a> {
+    input: &'a [u8],
+}
+
+impl PduReader<'_> {
+    fn take(&mut self, len: usize) -> &[u8] {
+        let (head, tail) = self.input.split_at(len.min(self.input.len()));
+        self.input = tail;
+        head
+    }
+
+    fn take_u8(&mut self) -> Option<u8> {
+        self.take(1).first().copied()
+    }
+
+    fn take_handle(&mut self) -> AttHandle {
+        match self.take_u8() {
+            // mostly pick handles that exist, so transactions get past validation
+            Some(selector) if selector < 0xF0 => HANDLES[selector as usize % HANDLES.len()],
+            _ => {
+                let bytes = self.take(2);
+                AttHandle(bytes.iter().rev().fold(0, |acc, b| (acc << 8) | *b as u16))
+            }
+        }
+    }
+
+    /// The number of handles at the start of the parameters of this opcode
+    fn handle_count(&mut self, opcode: AttOpcode) -> usize {
+        match opcode {
+            AttOpcode::EXCHANGE_MTU_REQUEST
+            | AttOpcode::EXECUTE_WRITE_REQUEST
+            | AttOpcode::HANDLE_VALUE_CONFIRMATION => 0,
+            AttOpcode::FIND_INFORMATION_REQUEST
+            | AttOpcode::FIND_BY_TYPE_VALUE_REQUEST
+            | AttOpcode::READ_BY_TYPE_REQUEST
+            | AttOpcode::READ_BY_GROUP_TYPE_REQUEST => 2,
+            AttOpcode::READ_MULTIPLE_REQUEST | AttOpcode::READ_MULTIPLE_VARIABLE_REQUEST => {
+                self.take_u8().unwrap_or(0) as usize % 8
+            }
+            _ => 1,
+        }
+    }
+
+    /// Get the next PDU, or None if the input is exhausted
+    fn next_pdu(&mut self) -> Option<Vec<u8>> {
+        let selector = self.take_u8()?;
+        let param_len = self.take_u8().unwrap_or(0) as usize;
+        if selector & 1 == 0 {
+            // an arbitrary PDU
+            return Some(self.take(param_len).to_vec());
+        }
+        let opcode = CLIENT_OPCODES[(selector >> 1) as usize % CLIENT_OPCODES.len()];
+        let mut pdu = vec![u8::from(opcode)];
+        for _ in 0..self.handle_count(opcode) {
+            pdu.extend_from_slice(&self.take_handle().0.to_le_bytes());
+        }
+        pdu.extend_from_slice(self.take(param_len));
+        Some(pdu)
+    }
+}
+
+/// Check that the reply (if any) to a PDU is appropriate
+fn check_reply(pdu: &[u8], reply: Option<AttBuilder>, mtu: usize) {
+    let Ok(request) = OwnedAttView::try_parse(pdu.to_vec().into_boxed_slice()) else {
+        match decode_pdu(pdu) {
+            DecodedPdu::UnsupportedRequest(opcode) => {
+                let Some(reply) = reply else {
+                    panic!("no reply to unsupported request {opcode:#04x} in {pdu:02x?}");
+                };
+                assert_eq!(
+                    reply.to_vec().unwrap(),
+                    build_unsupported_request_reply(opcode).to_vec().unwrap(),
+                    "bad reply to unsupported request in {pdu:02x?}"
+                );
+            }
+            _ => assert!(reply.is_none(), "replied to malformed PDU {pdu:02x?}"),
+        }
+        return;
+    };
+    let opcode = request.view().get_opcode();
+    match classify_opcode(opcode) {
+        OperationType::Request => {
+            let Some(reply) = reply else {
+                panic!("no reply to {opcode:?} in {pdu:02x?}");
+            };
+            match &reply._child_ {
+                AttChild::AttErrorResponse(error) => assert_eq!(error.opcode_in_error, opcode),
+                // responses immediately follow their request in the opcode space
+                _ => assert_eq!(u8::from(reply.opcode), u8::from(opcode) + 1),
+            }
+            let serialized = reply.to_vec().unwrap();
+            assert!(serialized.len() <= mtu, "{:?} exceeds the MTU of {mtu}", reply.opcode);
+        }
+        _ => assert!(reply.is_none(), "replied to {opcode:?} in {pdu:02x?}"),
+    }
+}
+
+/// Feed each PDU in the input into a fresh ATT server, checking the reply to
+/// each. Panics if the server misbehaves.
+pub fn fuzz_att_server(input: &[u8]) {
+    let rt = Builder::new_current_thread().build().unwrap();
+    rt.block_on(async {
+        let mut processor = AttPduProcessor::new(
+            make_db(),
+            SignatureVerifier::new(TransportIndex(1), Rc::new(MockSecurityManager::new())),
+            MAX_ATT_MTU,
+        );
+        let mut reader = PduReader { input };
+        while let Some(pdu) = reader.next_pdu() {
+            // the reply must fit in the MTU that was in use when the request arrived
+            let mtu = processor.mtu();
+            let reply = processor.process_pdu(&pdu).await;
+            check_reply(&pdu, reply, mtu);
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_empty_input() {
+        fuzz_att_server(&[]);
+    }
+
+    #[test]
+    fn test_arbitrary_pdus() {
+        fuzz_att_server(&[
+            // an empty PDU
+            0x00, 0x00, //
+            // an unknown opcode
+            0x00, 0x01, 0xFF, //
+            // a read request missing its handle
+            0x00, 0x01, 0x0A, //
+            // a response, which a client may not send
+            0x00, 0x03, 0x0B, 0x01, 0x02,
+        ]);
+    }
+
+    #[test]
+    fn test_structured_pdus() {
+        // every client opcode, with a variety of handles and parameters
+        let mut input = vec![];
+        for (i, _) in CLIENT_OPCODES.iter().enumerate() {
+            for (j, _) in HANDLES.iter().enumerate() {
+                input.extend_from_slice(&[(i as u8) << 1 | 1, 4, 3, j as u8, j as u8, 0, 0, 1, 2]);
+            }
+        }
+        fuzz_att_server(&input);
+    }
+
+    #[test]
+    fn test_truncated_structured_pdu() {
+        // a find information request, whose input ends partway through a handle
+        fuzz_att_server(&[0x03, 0x00, 0xFF, 0x01]);
+    }
+
+    #[test]
+    fn test_long_write_then_read() {
+        let mut input = vec![];
+        // exchange a small MTU
+        input.extend_from_slice(&[0x01, 0x02, 0x17, 0x00]);
+        // prepare a value running past the maximum attribute length
+        let prepare_write = 9 << 1 | 1;
+        input.extend_from_slice(&[prepare_write, 0x04, 0x03, 0xFF, 0x01, 0xAA, 0xBB]);
+        // execute it
+        let execute_write = 10 << 1 | 1;
+        input.extend_from_slice(&[execute_write, 0x01, 0x01]);
+        // read the long attribute at an offset
+        let read_blob = 5 << 1 | 1;
+        input.extend_from_slice(&[read_blob, 0x02, 0x03, 0x10, 0x00]);
+        fuzz_att_server(&input);
+    }
+}
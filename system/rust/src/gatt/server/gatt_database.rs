@@ -2,32 +2,61 @@
 //! by converting a registry of services into a list of attributes, and proxying
 //! ATT read/write requests into characteristic reads/writes
 
-use std::{cell::RefCell, collections::BTreeMap, ops::RangeInclusive, rc::Rc};
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    ops::RangeInclusive,
+    rc::Rc,
+};
 
 use anyhow::{bail, Result};
 use async_trait::async_trait;
+use bitflags::bitflags;
 use log::{error, warn};
 
 use crate::{
     core::{
+        address::AddressWithType,
         shared_box::{SharedBox, WeakBox, WeakBoxRef},
         uuid::Uuid,
     },
     gatt::{
         callbacks::{GattWriteRequestType, RawGattDatastore},
         ffi::AttributeBackingType,
-        ids::{AttHandle, TransportIndex},
+        ids::{AttHandle, Transport, TransportIndex},
+        security_manager::{SecurityLevel, SecurityManager},
     },
     packets::{
         AttErrorCode, GattCharacteristicDeclarationValueBuilder,
-        GattCharacteristicPropertiesBuilder, GattServiceDeclarationValueBuilder, Serializable,
-        UuidBuilder,
+        GattCharacteristicPropertiesBuilder, GattClientCharacteristicConfigurationBuilder,
+        GattClientCharacteristicConfigurationView, GattServiceDeclarationValueBuilder, Packet,
+        Serializable, Uuid128Builder, UuidBuilder,
     },
+    utils::aes_cmac::aes_cmac,
 };
 
 use super::{
-    att_database::{AttAttribute, AttDatabase},
+    access_policy::{AccessContext, AccessInterceptor},
+    att_database::{AttAttribute, AttAttributeValue, AttDatabase},
+    att_error::{AttError, ErrorLayer, UnmetRequirement},
     att_server_bearer::AttServerBearer,
+    authorization::{AttributeAccess, AuthorizationGrants, AuthorizationProvider},
+    client_configuration::{ClientConfiguration, ClientConfigurationStore},
+    config::GattServerConfig,
+    handle_assignments::{
+        HandleAllocationPolicy, HandleAssignmentStorage, HandleAssignments, ServiceKey,
+        ServiceLayout,
+    },
+    interceptors::{InterceptedOperation, Interceptor, InterceptorChain, OperationContext},
+    robust_caching::{ClientSupportedFeatures, DatabaseHash, RobustCachingStore},
+    schema::{
+        parse_permissions, parse_uuid, permission_names, CharacteristicSchema, DatabaseSchema,
+        DescriptorSchema, ServiceSchema, TransportSchema, ValueSchema,
+    },
+    user_descriptions::{UserDescriptionKey, UserDescriptionStorage, UserDescriptions},
+    write_locks::WriteLocks,
+    write_validation::WriteValidator,
 };
 
 pub use super::att_database::AttPermissions;
@@ -38,6 +67,56 @@ pub const PRIMARY_SERVICE_DECLARATION_UUID: Uuid = Uuid::new(0x2800);
 pub const SECONDARY_SERVICE_DECLARATION_UUID: Uuid = Uuid::new(0x2801);
 /// Characteristic Declaration from Bluetooth Assigned Numbers 3.5 Declarations
 pub const CHARACTERISTIC_UUID: Uuid = Uuid::new(0x2803);
+/// Characteristic Extended Properties from Bluetooth Assigned Numbers 3.7 Descriptors
+pub const CHARACTERISTIC_EXTENDED_PROPERTIES_UUID: Uuid = Uuid::new(0x2900);
+/// Characteristic User Description from Bluetooth Assigned Numbers 3.7 Descriptors
+pub const CHARACTERISTIC_USER_DESCRIPTION_UUID: Uuid = Uuid::new(0x2901);
+/// Client Characteristic Configuration from Bluetooth Assigned Numbers 3.7 Descriptors
+pub const CLIENT_CHARACTERISTIC_CONFIGURATION_UUID: Uuid = Uuid::new(0x2902);
+/// Characteristic Presentation Format from Bluetooth Assigned Numbers 3.7 Descriptors
+pub const CHARACTERISTIC_PRESENTATION_FORMAT_UUID: Uuid = Uuid::new(0x2904);
+/// Characteristic Aggregate Format from Bluetooth Assigned Numbers 3.7 Descriptors
+pub const CHARACTERISTIC_AGGREGATE_FORMAT_UUID: Uuid = Uuid::new(0x2905);
+
+bitflags! {
+    /// The Characteristic Extended Properties bits, from Core Spec 5.3 Vol 3G
+    /// 3.3.3.1 Characteristic Extended Properties
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+    pub struct ExtendedProperties : u16 {
+        /// The characteristic may be written using the Reliable Writes procedure
+        const RELIABLE_WRITE = 0x0001;
+        /// The Characteristic User Description descriptor may be written
+        const WRITABLE_AUXILIARIES = 0x0002;
+    }
+}
+
+/// The value of a Characteristic Presentation Format descriptor, from Core
+/// Spec 5.3 Vol 3G 3.3.3.5. The format, unit and description are assigned
+/// numbers (the description within the namespace).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PresentationFormat {
+    /// The format of the value (e.g. 0x06 for uint16)
+    pub format: u8,
+    /// The base 10 exponent by which to scale integer values
+    pub exponent: i8,
+    /// The unit of the value (e.g. 0x272F for degrees Celsius)
+    pub unit: u16,
+    /// The organization defining the description (0x01 for Bluetooth SIG)
+    pub namespace: u8,
+    /// Distinguishes characteristics of the same type (e.g. 0x0104 for "inside")
+    pub description: u16,
+}
+
+impl PresentationFormat {
+    /// The value of the descriptor
+    fn to_bytes(self) -> Vec<u8> {
+        let mut value = vec![self.format, self.exponent as u8];
+        value.extend(self.unit.to_le_bytes());
+        value.push(self.namespace);
+        value.extend(self.description.to_le_bytes());
+        value
+    }
+}
 
 /// A GattService (currently, only primary services are supported) has an
 /// identifying UUID and a list of contained characteristics, as well as a
@@ -56,6 +135,11 @@ pub struct GattServiceWithHandle {
 /// A GattCharacteristic consists of a handle (where the value attribute lives),
 /// a UUID identifying its type, and permissions indicating what operations can
 /// be performed
+///
+/// If the characteristic supports notifications or indications, but no Client
+/// Characteristic Configuration descriptor is supplied, the GattDatabase will
+/// manage one on its behalf, at the handle following the last descriptor (or
+/// the value, if there are no descriptors). That handle must be left free.
 #[derive(Debug, Clone)]
 pub struct GattCharacteristicWithHandle {
     /// The handle of the characteristic value attribute. The characteristic
@@ -83,30 +167,898 @@ pub struct GattDescriptorWithHandle {
     pub permissions: AttPermissions,
 }
 
+/// The static values of the characteristics and descriptors of a service, by
+/// handle
+type StaticValues = HashMap<AttHandle, Vec<u8>>;
+/// The managed user descriptions of a service, with their key and default
+/// value, by handle
+type ManagedUserDescriptions = HashMap<AttHandle, (UserDescriptionKey, Vec<u8>)>;
+/// The options of the characteristic values of a service, by handle
+type ValueOptionsByHandle = HashMap<AttHandle, ValueOptions>;
+
+/// Declaratively describes a primary service, so that it can be added with
+/// GattDatabase::add_service(), which validates it and provisions its handles
+#[derive(Debug, Clone)]
+pub struct ServiceBuilder {
+    type_: Uuid,
+    characteristics: Vec<CharacteristicBuilder>,
+    /// The only transport over which the service is visible, if restricted
+    transport: Option<Transport>,
+}
+
+/// Describes a characteristic of a ServiceBuilder. Its value is read from and
+/// written to the datastore backing the service, unless it is given a static
+/// value.
+#[derive(Debug, Clone)]
+pub struct CharacteristicBuilder {
+    type_: Uuid,
+    permissions: AttPermissions,
+    static_value: Option<Vec<u8>>,
+    descriptors: Vec<DescriptorBuilder>,
+    /// The extended properties advertised by the Characteristic Extended
+    /// Properties descriptor that we provision
+    extended_properties: ExtendedProperties,
+    /// The default value of the writable user description that we manage
+    user_description: Option<Vec<u8>>,
+    /// The presentation formats of the descriptors that we provision, along
+    /// with an aggregate format if there are several
+    presentation_formats: Vec<PresentationFormat>,
+    /// Checks the values written by clients
+    validator: Option<WriteValidator>,
+    /// Whether a queued notification takes the value of the next one
+    coalesce_notifications: bool,
+    /// The shortest key encrypting the link over which the value is accessible
+    min_encryption_key_size: Option<u8>,
+}
+
+/// Describes a descriptor of a CharacteristicBuilder. As above, its value is
+/// backed by the datastore unless it is given a static value.
+#[derive(Debug, Clone)]
+pub struct DescriptorBuilder {
+    type_: Uuid,
+    permissions: AttPermissions,
+    static_value: Option<Vec<u8>>,
+}
+
+/// Identifies a service added with GattDatabase::add_service(), so that it can
+/// later be removed
+#[derive(Debug, PartialEq, Eq)]
+pub struct ServiceToken {
+    handle: AttHandle,
+}
+
+impl ServiceBuilder {
+    /// Constructor, for a primary service of the given type
+    pub fn new(type_: Uuid) -> Self {
+        Self { type_, characteristics: vec![], transport: None }
+    }
+
+    /// Add a characteristic, following those already added
+    pub fn characteristic(mut self, characteristic: CharacteristicBuilder) -> Self {
+        self.characteristics.push(characteristic);
+        self
+    }
+
+    /// Make the service visible only to clients connected over the given
+    /// transport. To a client connected over the other, its handles appear to
+    /// be free: it is neither discovered nor accessible.
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// The number of handles occupied by the service, including those of the
+    /// CCCDs managed by the GattDatabase
+    fn handle_count(&self) -> usize {
+        1 + self.characteristics.iter().map(CharacteristicBuilder::handle_count).sum::<usize>()
+    }
+
+    fn validate(&self, config: &GattServerConfig) -> Result<()> {
+        self.characteristics.iter().try_for_each(|characteristic| characteristic.validate(config))
+    }
+
+    /// A digest of the types and permissions of the attributes of the service,
+    /// which determine its handles and declarations
+    fn layout(&self) -> ServiceLayout {
+        let mut message = Uuid128Builder::from(self.type_).data.to_vec();
+        for characteristic in &self.characteristics {
+            message.extend(Uuid128Builder::from(characteristic.type_).data.iter());
+            message.extend(characteristic.permissions.bits().to_le_bytes());
+            message.extend(characteristic.extended_properties.bits().to_le_bytes());
+            message.extend((characteristic.descriptors.len() as u16).to_le_bytes());
+            message.push(characteristic.user_description.is_some().into());
+            message.extend((characteristic.presentation_formats.len() as u16).to_le_bytes());
+            for descriptor in &characteristic.descriptors {
+                message.extend(Uuid128Builder::from(descriptor.type_).data.iter());
+                message.extend(descriptor.permissions.bits().to_le_bytes());
+            }
+        }
+        aes_cmac(&[0; 16], &message)
+    }
+
+    /// Assign consecutive handles to the attributes of the service, starting
+    /// with its declaration at the given handle. Also returns the static values
+    /// of the characteristics and descriptors, the managed user descriptions
+    /// (identified within the service with the given key, with their default
+    /// value), and the options of the characteristic values, by handle.
+    fn into_service_with_handles(
+        self,
+        handle: AttHandle,
+        key: ServiceKey,
+    ) -> (GattServiceWithHandle, StaticValues, ManagedUserDescriptions, ValueOptionsByHandle) {
+        let mut static_values = HashMap::new();
+        let mut user_descriptions = HashMap::new();
+        let mut value_options = HashMap::new();
+        // the service is known to fit, but the handle after it may not exist
+        let mut next_handle = u32::from(handle.0) + 1;
+        let mut characteristics = vec![];
+        for (index, characteristic) in self.characteristics.into_iter().enumerate() {
+            let managed_cccd = characteristic.has_managed_cccd();
+            // skip the characteristic declaration
+            let value_handle = AttHandle((next_handle + 1) as u16);
+            next_handle += 2;
+            if let Some(value) = characteristic.static_value {
+                static_values.insert(value_handle, value);
+            }
+            if characteristic.validator.is_some()
+                || characteristic.coalesce_notifications
+                || characteristic.min_encryption_key_size.is_some()
+            {
+                value_options.insert(
+                    value_handle,
+                    ValueOptions {
+                        validator: characteristic.validator,
+                        coalesce_notifications: characteristic.coalesce_notifications,
+                        min_encryption_key_size: characteristic.min_encryption_key_size,
+                    },
+                );
+            }
+            let mut descriptors = vec![];
+            // the extended properties we provision precede the other descriptors
+            if !characteristic.extended_properties.is_empty() {
+                let descriptor_handle = AttHandle(next_handle as u16);
+                next_handle += 1;
+                static_values.insert(
+                    descriptor_handle,
+                    characteristic.extended_properties.bits().to_le_bytes().to_vec(),
+                );
+                descriptors.push(GattDescriptorWithHandle {
+                    handle: descriptor_handle,
+                    type_: CHARACTERISTIC_EXTENDED_PROPERTIES_UUID,
+                    permissions: AttPermissions::READABLE,
+                });
+            }
+            for descriptor in characteristic.descriptors {
+                let descriptor_handle = AttHandle(next_handle as u16);
+                next_handle += 1;
+                if let Some(value) = descriptor.static_value {
+                    static_values.insert(descriptor_handle, value);
+                }
+                descriptors.push(GattDescriptorWithHandle {
+                    handle: descriptor_handle,
+                    type_: descriptor.type_,
+                    permissions: descriptor.permissions,
+                });
+            }
+            if let Some(default) = characteristic.user_description {
+                let descriptor_handle = AttHandle(next_handle as u16);
+                next_handle += 1;
+                let key = UserDescriptionKey {
+                    service: key,
+                    characteristic_type: characteristic.type_,
+                    characteristic: index as u16,
+                };
+                user_descriptions.insert(descriptor_handle, (key, default));
+                descriptors.push(GattDescriptorWithHandle {
+                    handle: descriptor_handle,
+                    type_: CHARACTERISTIC_USER_DESCRIPTION_UUID,
+                    permissions: AttPermissions::READABLE | AttPermissions::WRITABLE_WITH_RESPONSE,
+                });
+            }
+            let mut format_handles = vec![];
+            for format in &characteristic.presentation_formats {
+                let descriptor_handle = AttHandle(next_handle as u16);
+                next_handle += 1;
+                static_values.insert(descriptor_handle, format.to_bytes());
+                format_handles.push(descriptor_handle);
+                descriptors.push(GattDescriptorWithHandle {
+                    handle: descriptor_handle,
+                    type_: CHARACTERISTIC_PRESENTATION_FORMAT_UUID,
+                    permissions: AttPermissions::READABLE,
+                });
+            }
+            if format_handles.len() > 1 {
+                // the aggregate format lists the presentation formats, in order
+                let descriptor_handle = AttHandle(next_handle as u16);
+                next_handle += 1;
+                static_values.insert(
+                    descriptor_handle,
+                    format_handles.iter().flat_map(|handle| handle.0.to_le_bytes()).collect(),
+                );
+                descriptors.push(GattDescriptorWithHandle {
+                    handle: descriptor_handle,
+                    type_: CHARACTERISTIC_AGGREGATE_FORMAT_UUID,
+                    permissions: AttPermissions::READABLE,
+                });
+            }
+            if managed_cccd {
+                // the GattDatabase places its CCCD after the last descriptor
+                next_handle += 1;
+            }
+            characteristics.push(GattCharacteristicWithHandle {
+                handle: value_handle,
+                type_: characteristic.type_,
+                permissions: characteristic.permissions,
+                descriptors,
+            });
+        }
+        (
+            GattServiceWithHandle { handle, type_: self.type_, characteristics },
+            static_values,
+            user_descriptions,
+            value_options,
+        )
+    }
+}
+
+impl CharacteristicBuilder {
+    /// Constructor, for a characteristic of the given type. The permissions
+    /// determine both the properties in its declaration, and the security
+    /// required to access its value.
+    pub fn new(type_: Uuid, permissions: AttPermissions) -> Self {
+        Self {
+            type_,
+            permissions,
+            static_value: None,
+            descriptors: vec![],
+            extended_properties: ExtendedProperties::empty(),
+            user_description: None,
+            presentation_formats: vec![],
+            validator: None,
+            coalesce_notifications: false,
+            min_encryption_key_size: None,
+        }
+    }
+
+    /// Serve the given value for this characteristic, rather than reading it
+    /// from the datastore. The characteristic must not be writable.
+    pub fn static_value(mut self, value: Vec<u8>) -> Self {
+        self.static_value = Some(value);
+        self
+    }
+
+    /// Add a descriptor, following those already added. If the characteristic
+    /// supports notifications or indications, and no CCCD is added here, the
+    /// GattDatabase manages one on its behalf.
+    pub fn descriptor(mut self, descriptor: DescriptorBuilder) -> Self {
+        self.descriptors.push(descriptor);
+        self
+    }
+
+    /// Let clients write this characteristic using the Reliable Writes
+    /// procedure (Core Spec 5.3 Vol 3G 4.9.5). The characteristic must be
+    /// writable. A Characteristic Extended Properties descriptor advertising
+    /// it is provisioned before the other descriptors.
+    pub fn reliable_write(mut self) -> Self {
+        self.extended_properties |= ExtendedProperties::RELIABLE_WRITE;
+        self
+    }
+
+    /// Let clients write the Characteristic User Description descriptor, which
+    /// must then be added (and be writable). As above, a Characteristic
+    /// Extended Properties descriptor advertising it is provisioned.
+    pub fn writable_auxiliaries(mut self) -> Self {
+        self.extended_properties |= ExtendedProperties::WRITABLE_AUXILIARIES;
+        self
+    }
+
+    /// Add a Characteristic User Description descriptor that clients may
+    /// overwrite, holding the given description until they do. This also sets
+    /// writable auxiliaries. The GattDatabase manages the descriptor (after
+    /// those added explicitly), and persists what clients write to it.
+    pub fn writable_user_description(mut self, default: &str) -> Self {
+        self.user_description = Some(default.as_bytes().to_vec());
+        self.writable_auxiliaries()
+    }
+
+    /// Add a Characteristic Presentation Format descriptor, following the
+    /// presentation formats already added. These are provisioned after the
+    /// other descriptors and, if there are several, followed by a
+    /// Characteristic Aggregate Format descriptor listing them.
+    pub fn presentation_format(mut self, format: PresentationFormat) -> Self {
+        self.presentation_formats.push(format);
+        self
+    }
+
+    /// Run the given validator on every value written by a client, before it
+    /// reaches the datastore. The characteristic must be writable.
+    pub fn validator(mut self, validator: WriteValidator) -> Self {
+        self.validator = Some(validator);
+        self
+    }
+
+    /// Coalesce the notifications of the characteristic, for values (e.g. a
+    /// battery level or a sensor reading) of which only the latest matters.
+    /// While the connection is congested, a notification that is still queued
+    /// takes the value of the next one, which does not take a slot of its own.
+    /// The characteristic must support notifications.
+    pub fn coalesce_notifications(mut self) -> Self {
+        self.coalesce_notifications = true;
+        self
+    }
+
+    /// Only let clients access the value over a link encrypted with a key of
+    /// at least the given size, in octets (from 7 to 16), as some profiles
+    /// demand. Otherwise, the access fails with
+    /// INSUFFICIENT_ENCRYPTION_KEY_SIZE. The characteristic must require
+    /// encryption (or authentication).
+    pub fn min_encryption_key_size(mut self, size: u8) -> Self {
+        self.min_encryption_key_size = Some(size);
+        self
+    }
+
+    /// The extended properties of the characteristic, whether advertised by a
+    /// descriptor we provision or by one that was added explicitly
+    fn all_extended_properties(&self) -> ExtendedProperties {
+        self.descriptors
+            .iter()
+            .filter(|descriptor| descriptor.type_ == CHARACTERISTIC_EXTENDED_PROPERTIES_UUID)
+            .filter_map(|descriptor| descriptor.static_value.as_deref())
+            .map(|value| {
+                let bits = match *value {
+                    [b0] => u16::from(b0),
+                    [b0, b1, ..] => u16::from_le_bytes([b0, b1]),
+                    [] => 0,
+                };
+                ExtendedProperties::from_bits_truncate(bits)
+            })
+            .fold(self.extended_properties, |all, properties| all | properties)
+    }
+
+    fn has_managed_cccd(&self) -> bool {
+        (self.permissions.notify() || self.permissions.indicate())
+            && !self
+                .descriptors
+                .iter()
+                .any(|descriptor| descriptor.type_ == CLIENT_CHARACTERISTIC_CONFIGURATION_UUID)
+    }
+
+    fn handle_count(&self) -> usize {
+        2 + self.descriptors.len()
+            + usize::from(!self.extended_properties.is_empty())
+            + usize::from(self.user_description.is_some())
+            + self.presentation_formats.len()
+            + usize::from(self.presentation_formats.len() > 1)
+            + usize::from(self.has_managed_cccd())
+    }
+
+    fn validate(&self, config: &GattServerConfig) -> Result<()> {
+        validate_attribute(self.type_, self.permissions, self.static_value.as_deref(), config)?;
+        let cccd_count = self
+            .descriptors
+            .iter()
+            .filter(|descriptor| descriptor.type_ == CLIENT_CHARACTERISTIC_CONFIGURATION_UUID)
+            .count();
+        if cccd_count > 0 && !self.permissions.notify() && !self.permissions.indicate() {
+            bail!(
+                "characteristic {:?} has a CCCD but supports neither notifications nor indications",
+                self.type_
+            );
+        }
+        if cccd_count > 1 {
+            bail!("characteristic {:?} has {cccd_count} CCCDs", self.type_);
+        }
+        let extended_properties_count = self
+            .descriptors
+            .iter()
+            .filter(|descriptor| descriptor.type_ == CHARACTERISTIC_EXTENDED_PROPERTIES_UUID)
+            .count();
+        if extended_properties_count > 1
+            || (extended_properties_count > 0 && !self.extended_properties.is_empty())
+        {
+            bail!("characteristic {:?} has several extended properties descriptors", self.type_);
+        }
+        let extended_properties = self.all_extended_properties();
+        if extended_properties.contains(ExtendedProperties::RELIABLE_WRITE)
+            && !self.permissions.writable_with_response()
+        {
+            bail!("characteristic {:?} supports reliable writes but is not writable", self.type_);
+        }
+        // As per Core Spec 5.3 Vol 3G 3.3.3.2, the Characteristic User Description
+        // is writable exactly when the Writable Auxiliaries bit is set
+        let user_description_count = self
+            .descriptors
+            .iter()
+            .filter(|descriptor| descriptor.type_ == CHARACTERISTIC_USER_DESCRIPTION_UUID)
+            .count();
+        if user_description_count + usize::from(self.user_description.is_some()) > 1 {
+            bail!("characteristic {:?} has several user descriptions", self.type_);
+        }
+        if let Some(default) = &self.user_description {
+            if config.check_attribute_length(0, default.len()).is_err() {
+                bail!(
+                    "the user description of {:?} exceeds {} bytes",
+                    self.type_,
+                    config.max_attribute_length
+                );
+            }
+        }
+        let writable_user_description = self.user_description.is_some()
+            || self.descriptors.iter().any(|descriptor| {
+                descriptor.type_ == CHARACTERISTIC_USER_DESCRIPTION_UUID
+                    && (descriptor.permissions.writable_with_response()
+                        || descriptor.permissions.writable_without_response())
+            });
+        if extended_properties.contains(ExtendedProperties::WRITABLE_AUXILIARIES)
+            != writable_user_description
+        {
+            bail!(
+                "characteristic {:?} must both have writable auxiliaries and a writable user description, or neither",
+                self.type_
+            );
+        }
+        if self.validator.is_some()
+            && !self.permissions.writable_with_response()
+            && !self.permissions.writable_without_response()
+        {
+            bail!("characteristic {:?} has a validator but is not writable", self.type_);
+        }
+        if self.coalesce_notifications && !self.permissions.notify() {
+            bail!("characteristic {:?} coalesces notifications but has none", self.type_);
+        }
+        if let Some(size) = self.min_encryption_key_size {
+            // Core Spec 5.3 Vol 3H 2.3.4
+            if !(7..=16).contains(&size) {
+                bail!(
+                    "characteristic {:?} requires an encryption key of {size} octets, outside 7 to 16",
+                    self.type_
+                );
+            }
+            if !self.permissions.intersects(
+                AttPermissions::ENCRYPTION_REQUIRED | AttPermissions::AUTHENTICATION_REQUIRED,
+            ) {
+                bail!(
+                    "characteristic {:?} requires an encryption key size but not encryption",
+                    self.type_
+                );
+            }
+        }
+        // the aggregate format we provision could not list formats added explicitly
+        if !self.presentation_formats.is_empty()
+            && self.descriptors.iter().any(|descriptor| {
+                descriptor.type_ == CHARACTERISTIC_PRESENTATION_FORMAT_UUID
+                    || descriptor.type_ == CHARACTERISTIC_AGGREGATE_FORMAT_UUID
+            })
+        {
+            bail!(
+                "characteristic {:?} mixes provisioned and explicit presentation formats",
+                self.type_
+            );
+        }
+        for descriptor in &self.descriptors {
+            validate_attribute(
+                descriptor.type_,
+                descriptor.permissions,
+                descriptor.static_value.as_deref(),
+                config,
+            )?;
+            if descriptor.type_ == CLIENT_CHARACTERISTIC_CONFIGURATION_UUID
+                && !(descriptor.permissions.readable()
+                    && descriptor.permissions.writable_with_response())
+            {
+                bail!("the CCCD of characteristic {:?} must be readable and writable", self.type_);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DescriptorBuilder {
+    /// Constructor, for a descriptor of the given type
+    pub fn new(type_: Uuid, permissions: AttPermissions) -> Self {
+        Self { type_, permissions, static_value: None }
+    }
+
+    /// Serve the given value for this descriptor, rather than reading it from
+    /// the datastore. The descriptor must not be writable.
+    pub fn static_value(mut self, value: Vec<u8>) -> Self {
+        self.static_value = Some(value);
+        self
+    }
+}
+
+impl ServiceToken {
+    /// The handle of the service declaration
+    pub fn handle(&self) -> AttHandle {
+        self.handle
+    }
+}
+
+/// Check the constraints shared by characteristic values and descriptors
+fn validate_attribute(
+    type_: Uuid,
+    permissions: AttPermissions,
+    static_value: Option<&[u8]>,
+    config: &GattServerConfig,
+) -> Result<()> {
+    if [PRIMARY_SERVICE_DECLARATION_UUID, SECONDARY_SERVICE_DECLARATION_UUID, CHARACTERISTIC_UUID]
+        .contains(&type_)
+    {
+        bail!("{type_:?} is reserved for declarations");
+    }
+    if let Some(value) = static_value {
+        if permissions.writable_with_response() || permissions.writable_without_response() {
+            bail!("{type_:?} has a static value, so cannot be writable");
+        }
+        if config.check_attribute_length(0, value.len()).is_err() {
+            bail!("the static value of {type_:?} exceeds {} bytes", config.max_attribute_length);
+        }
+    }
+    Ok(())
+}
+
+/// The service described by a schema (identified with the given key across
+/// restarts), along with the static values, managed user descriptions and
+/// value options expected by GattDatabase::insert_service(), and the transport
+/// to which it is restricted. The managed CCCDs are left out, since the
+/// GattDatabase recreates them after the last descriptor.
+fn service_from_schema(
+    service: &ServiceSchema,
+    key: ServiceKey,
+    config: &GattServerConfig,
+) -> Result<(
+    GattServiceWithHandle,
+    StaticValues,
+    ManagedUserDescriptions,
+    ValueOptionsByHandle,
+    Option<Transport>,
+)> {
+    let mut static_values = HashMap::new();
+    let mut user_descriptions = HashMap::new();
+    let mut value_options = HashMap::new();
+    let mut characteristics = vec![];
+    for (index, characteristic) in service.characteristics.iter().enumerate() {
+        let type_ = parse_uuid(&characteristic.uuid)?;
+        let permissions = parse_permissions(&characteristic.permissions)?;
+        // the declaration must fit between the service declaration and the value
+        if characteristic.handle <= service.handle.saturating_add(1) {
+            bail!("characteristic {type_:?} has no room for its declaration");
+        }
+        let handle = AttHandle(characteristic.handle);
+        match &characteristic.value {
+            ValueSchema::Dynamic => {}
+            ValueSchema::Static(value) => {
+                static_values.insert(handle, value.clone());
+            }
+            ValueSchema::ClientConfiguration | ValueSchema::UserDescription(_) => {
+                bail!("characteristic {type_:?} has the value of a descriptor")
+            }
+        }
+        validate_attribute(
+            type_,
+            permissions,
+            static_values.get(&handle).map(Vec::as_slice),
+            config,
+        )?;
+        if let Some(size) = characteristic.min_encryption_key_size {
+            value_options.insert(
+                handle,
+                ValueOptions { min_encryption_key_size: Some(size), ..Default::default() },
+            );
+        }
+        let mut descriptors = vec![];
+        for (position, descriptor) in characteristic.descriptors.iter().enumerate() {
+            let descriptor_type = parse_uuid(&descriptor.uuid)?;
+            let descriptor_permissions = parse_permissions(&descriptor.permissions)?;
+            let descriptor_handle = AttHandle(descriptor.handle);
+            match &descriptor.value {
+                ValueSchema::Dynamic => {}
+                ValueSchema::Static(value) => {
+                    static_values.insert(descriptor_handle, value.clone());
+                }
+                ValueSchema::ClientConfiguration => {
+                    if position + 1 != characteristic.descriptors.len()
+                        || !(permissions.notify() || permissions.indicate())
+                    {
+                        bail!("the managed CCCD of {type_:?} must be its last descriptor");
+                    }
+                    continue;
+                }
+                ValueSchema::UserDescription(default) => {
+                    let key = UserDescriptionKey {
+                        service: key,
+                        characteristic_type: type_,
+                        characteristic: index as u16,
+                    };
+                    user_descriptions.insert(descriptor_handle, (key, default.clone()));
+                }
+            }
+            validate_attribute(
+                descriptor_type,
+                descriptor_permissions,
+                static_values.get(&descriptor_handle).map(Vec::as_slice),
+                config,
+            )?;
+            descriptors.push(GattDescriptorWithHandle {
+                handle: descriptor_handle,
+                type_: descriptor_type,
+                permissions: descriptor_permissions,
+            });
+        }
+        characteristics.push(GattCharacteristicWithHandle {
+            handle,
+            type_,
+            permissions,
+            descriptors,
+        });
+    }
+    let transport = service.transport.map(Transport::try_from).transpose()?;
+    Ok((
+        GattServiceWithHandle {
+            handle: AttHandle(service.handle),
+            type_: key.type_,
+            characteristics,
+        },
+        static_values,
+        user_descriptions,
+        value_options,
+        transport,
+    ))
+}
+
 /// The GattDatabase implements AttDatabase, and converts attribute reads/writes
 /// into GATT operations to be sent to the upper layers
 #[derive(Default)]
 pub struct GattDatabase {
     schema: RefCell<GattDatabaseSchema>,
     listeners: RefCell<Vec<Rc<dyn GattDatabaseCallbacks>>>,
+    client_configuration: RefCell<ClientConfigurationStore>,
+    client_configuration_bursts: RefCell<HashMap<TransportIndex, ClientConfigurationChanges>>,
+    robust_caching: Rc<RefCell<RobustCachingStore>>,
+    security_manager: Option<Rc<dyn SecurityManager>>,
+    authorization_provider: RefCell<Option<Rc<dyn AuthorizationProvider>>>,
+    authorization_grants: RefCell<AuthorizationGrants>,
+    access_interceptor: RefCell<Option<Rc<dyn AccessInterceptor>>>,
+    interceptors: RefCell<InterceptorChain>,
+    handle_assignments: Rc<RefCell<HandleAssignments>>,
+    user_descriptions: RefCell<UserDescriptions>,
+    write_locks: WriteLocks,
+    value_versions: RefCell<HashMap<AttHandle, u64>>,
+    config: GattServerConfig,
 }
 
+/// The writes of a client to managed CCCDs during a burst, of which only the
+/// last one to each CCCD is kept
+type ClientConfigurationChanges = Vec<(AttHandle, ClientConfiguration)>;
+
 #[derive(Default)]
 struct GattDatabaseSchema {
     attributes: BTreeMap<AttHandle, AttAttributeWithBackingValue>,
+    next_registration: u64,
+}
+
+impl GattDatabaseSchema {
+    /// The attribute with the given handle, if it is visible over the given
+    /// transport
+    fn visible_attribute(
+        &self,
+        handle: AttHandle,
+        transport: Transport,
+    ) -> Option<&AttAttributeWithBackingValue> {
+        self.attributes.get(&handle).filter(|attr| attr.is_visible_over(transport))
+    }
+
+    /// Allocate an identifier for the registration of a new service
+    fn alloc_registration(&mut self) -> u64 {
+        let registration = self.next_registration;
+        self.next_registration += 1;
+        registration
+    }
+
+    /// The datastore backing each registered service (that has dynamic
+    /// attributes), once each
+    fn datastores(&self) -> Vec<Rc<dyn RawGattDatastore>> {
+        let mut datastores = BTreeMap::new();
+        for attr in self.attributes.values() {
+            if let AttAttributeBackingValue::DynamicCharacteristic(datastore)
+            | AttAttributeBackingValue::DynamicDescriptor(datastore) = &attr.value
+            {
+                datastores.entry(attr.registration).or_insert_with(|| datastore.clone());
+            }
+        }
+        datastores.into_values().collect()
+    }
+
+    /// Whether a client may write the given attribute, as far as the extended
+    /// properties of its characteristic are concerned: a Characteristic User
+    /// Description may only be written if the Characteristic Extended
+    /// Properties descriptor preceding it sets writable auxiliaries (Core Spec
+    /// 5.3 Vol 3G 3.3.3.2)
+    fn allows_auxiliary_write(&self, attribute: &AttAttribute) -> bool {
+        if attribute.type_ != CHARACTERISTIC_USER_DESCRIPTION_UUID {
+            return true;
+        }
+        self.attributes
+            .range(..attribute.handle)
+            .rev()
+            .map(|(_, attr)| attr)
+            .take_while(|attr| attr.attribute.type_ != CHARACTERISTIC_UUID)
+            .find(|attr| attr.attribute.type_ == CHARACTERISTIC_EXTENDED_PROPERTIES_UUID)
+            .and_then(|attr| match &attr.value {
+                AttAttributeBackingValue::Static(value) => value.first().copied(),
+                _ => None,
+            })
+            .map(|properties| {
+                ExtendedProperties::from_bits_truncate(properties.into())
+                    .contains(ExtendedProperties::WRITABLE_AUXILIARIES)
+            })
+            .unwrap_or(false)
+    }
+
+    /// Whether the attribute at the given handle belongs to the specified
+    /// registration of a service
+    fn is_registered(&self, handle: AttHandle, registration: u64) -> bool {
+        self.attributes.get(&handle).map(|attr| attr.registration) == Some(registration)
+    }
+
+    /// The type of the service containing the attribute at the given handle
+    fn service_type(&self, handle: AttHandle) -> Option<Uuid> {
+        let (_, declaration) = self.attributes.range(..=handle).rev().find(|(_, attr)| {
+            [PRIMARY_SERVICE_DECLARATION_UUID, SECONDARY_SERVICE_DECLARATION_UUID]
+                .contains(&attr.attribute.type_)
+        })?;
+        let AttAttributeBackingValue::Static(value) = &declaration.value else {
+            return None;
+        };
+        Uuid::try_from_le_slice(value)
+    }
+
+    /// Compute the Database Hash, as per Core Spec 5.3 Vol 3G 7.3.1
+    fn database_hash(&self) -> DatabaseHash {
+        let mut message = vec![];
+        for AttAttributeWithBackingValue { attribute, value, .. } in self.attributes.values() {
+            let Some(type_) = attribute.type_.to_u16() else {
+                continue;
+            };
+            let include_value = match type_ {
+                // service, included service, and characteristic declarations
+                0x2800..=0x2803 => true,
+                // the Characteristic Extended Properties descriptor, and the other
+                // descriptors defined by GATT
+                0x2900 => true,
+                0x2901..=0x2905 => false,
+                _ => continue,
+            };
+            message.extend_from_slice(&attribute.handle.0.to_le_bytes());
+            message.extend_from_slice(&type_.to_le_bytes());
+            if include_value {
+                if let AttAttributeBackingValue::Static(value) = value {
+                    message.extend_from_slice(value);
+                }
+            }
+        }
+        // the hash is exposed in little-endian order, unlike the AES-CMAC output
+        let mut hash = aes_cmac(&[0; 16], &message);
+        hash.reverse();
+        hash
+    }
+
+    /// Find the first of `count` consecutive free handles, which must lie
+    /// between existing services (rather than within one), and outside of the
+    /// reserved ranges
+    fn find_free_handles(
+        &self,
+        count: usize,
+        reserved: &[RangeInclusive<AttHandle>],
+    ) -> Option<AttHandle> {
+        let mut from = 1;
+        loop {
+            let handle = self.find_free_handles_from(count, from)?;
+            let end = usize::from(handle.0) + count - 1;
+            let overlapping = reserved
+                .iter()
+                .filter(|range| usize::from(range.start().0) <= end && handle <= *range.end())
+                .map(|range| usize::from(range.end().0))
+                .max();
+            match overlapping {
+                Some(reserved_end) => from = reserved_end + 1,
+                None => return Some(handle),
+            }
+        }
+    }
+
+    fn find_free_handles_from(&self, count: usize, from: usize) -> Option<AttHandle> {
+        let mut next_free = from;
+        for AttAttributeWithBackingValue { attribute, .. } in self.attributes.values() {
+            let handle = usize::from(attribute.handle.0);
+            if handle < from {
+                continue;
+            }
+            if attribute.type_ == PRIMARY_SERVICE_DECLARATION_UUID && handle - next_free >= count {
+                return Some(AttHandle(next_free as u16));
+            }
+            next_free = handle + 1;
+        }
+        (usize::from(u16::MAX) + 1 - next_free >= count).then_some(AttHandle(next_free as u16))
+    }
+
+    /// Whether the `count` handles starting at the given one are free, and lie
+    /// between existing services
+    fn are_free_handles(&self, handle: AttHandle, count: usize) -> bool {
+        let end = usize::from(handle.0) + count - 1;
+        if handle.0 == 0 || end > usize::from(u16::MAX) {
+            return false;
+        }
+        match self.attributes.range(handle..).next() {
+            Some((next, attr)) => {
+                usize::from(next.0) > end
+                    && attr.attribute.type_ == PRIMARY_SERVICE_DECLARATION_UUID
+            }
+            None => true,
+        }
+    }
 }
 
 #[derive(Clone)]
 enum AttAttributeBackingValue {
-    Static(Vec<u8>),
+    Static(AttAttributeValue),
     DynamicCharacteristic(Rc<dyn RawGattDatastore>),
     DynamicDescriptor(Rc<dyn RawGattDatastore>),
+    /// A CCCD managed by the GattDatabase, for the characteristic with the
+    /// given value handle
+    ClientConfiguration(AttHandle),
+    /// A writable Characteristic User Description managed by the GattDatabase
+    UserDescription,
 }
 
 #[derive(Clone)]
 struct AttAttributeWithBackingValue {
     attribute: AttAttribute,
     value: AttAttributeBackingValue,
+    /// Identifies the registration of the service owning this attribute, so
+    /// that an operation that was in flight while the service was removed
+    /// can be detected (even if another service has since taken its place)
+    registration: u64,
+    /// Checks the values written by clients
+    validator: Option<WriteValidator>,
+    /// Whether the notifications of this characteristic value are coalesced
+    coalesce_notifications: bool,
+    /// The shortest key encrypting the link over which the attribute is
+    /// accessible, if it requires one
+    min_encryption_key_size: Option<u8>,
+    /// The only transport over which the attribute is visible, if its service
+    /// is restricted to one
+    transport: Option<Transport>,
+}
+
+/// The options of a characteristic value set by its CharacteristicBuilder
+#[derive(Default)]
+struct ValueOptions {
+    validator: Option<WriteValidator>,
+    coalesce_notifications: bool,
+    min_encryption_key_size: Option<u8>,
+}
+
+impl AttAttributeWithBackingValue {
+    /// Whether a client connected over the given transport can see the
+    /// attribute
+    fn is_visible_over(&self, transport: Transport) -> bool {
+        self.transport.map(|only| only == transport).unwrap_or(true)
+    }
+
+    /// Run the validator (if any) on a value written by a client at the given
+    /// offset. A validator needs the whole value, so it is only written at
+    /// offset 0.
+    fn validate_write(&self, offset: u32, data: &[u8]) -> Result<(), AttErrorCode> {
+        let Some(validator) = &self.validator else {
+            return Ok(());
+        };
+        if offset != 0 {
+            warn!("rejecting write to validated {:?} at offset {offset}", self.attribute.handle);
+            return Err(AttErrorCode::INVALID_OFFSET);
+        }
+        validator.validate(data)
+    }
 }
 
 /// Callbacks that can be registered on the GattDatabase to watch for
@@ -125,14 +1077,100 @@ pub trait GattDatabaseCallbacks {
     );
     /// A peer device has disconnected from this database
     fn on_le_disconnect(&self, tcb_idx: TransportIndex);
+    /// The peer device on the given bearer has been identified as bonded
+    fn on_le_bonded(&self, tcb_idx: TransportIndex, peer: AddressWithType);
     /// The attributes in the specified range have changed
     fn on_service_change(&self, range: RangeInclusive<AttHandle>);
+    /// The peer device on the given bearer wrote the managed CCCD of the
+    /// given characteristic
+    fn on_client_configuration_change(
+        &self,
+        tcb_idx: TransportIndex,
+        handle: AttHandle,
+        configuration: ClientConfiguration,
+    );
+    /// The peer device on the given bearer wrote the managed CCCDs of the
+    /// given characteristics in a burst (see
+    /// GattServerConfig::client_configuration_burst_window), which has now
+    /// ended. By default, each change is handled on its own.
+    fn on_client_configuration_changes(
+        &self,
+        tcb_idx: TransportIndex,
+        changes: &[(AttHandle, ClientConfiguration)],
+    ) {
+        for (handle, configuration) in changes {
+            self.on_client_configuration_change(tcb_idx, *handle, *configuration);
+        }
+    }
 }
 
 impl GattDatabase {
     /// Constructor, wrapping a GattDatastore
+    ///
+    /// Without a SecurityManager, every link is treated as unencrypted and
+    /// unauthorized, so attributes with security requirements are never
+    /// served.
     pub fn new() -> Self {
-        Default::default()
+        let this = Self::default();
+        this.update_database_hash();
+        this
+    }
+
+    /// Constructor, using the supplied SecurityManager to enforce the security
+    /// requirements of each attribute
+    pub fn new_with_security_manager(security_manager: Rc<dyn SecurityManager>) -> Self {
+        let this = Self { security_manager: Some(security_manager), ..Default::default() };
+        this.update_database_hash();
+        this
+    }
+
+    /// Enforce the given configuration, rather than the default one. Services
+    /// added beforehand are not revalidated against it, so it should be set
+    /// before adding any.
+    pub fn with_config(mut self, config: GattServerConfig) -> Self {
+        self.robust_caching.borrow_mut().set_robust_caching_enabled(config.robust_caching_enabled);
+        self.config = config;
+        self
+    }
+
+    /// The configuration enforced by this database
+    pub fn config(&self) -> GattServerConfig {
+        self.config
+    }
+
+    /// Set the AuthorizationProvider consulted when a client that is not
+    /// otherwise authorized accesses an attribute requiring authorization.
+    /// Without one, such accesses are rejected.
+    pub fn set_authorization_provider(&self, provider: Rc<dyn AuthorizationProvider>) {
+        self.authorization_provider.replace(Some(provider));
+    }
+
+    /// Set the AccessInterceptor consulted on every read and write of an
+    /// attribute (other than a declaration), before its permissions are
+    /// checked
+    pub fn set_access_interceptor(&self, interceptor: Rc<dyn AccessInterceptor>) {
+        self.access_interceptor.replace(Some(interceptor));
+    }
+
+    /// Pass the reads and writes of the attributes in the given range through
+    /// the interceptor, once they pass the checks of the database (see
+    /// interceptors for the order in which interceptors run)
+    pub fn add_interceptor(
+        &self,
+        range: RangeInclusive<AttHandle>,
+        order: i32,
+        interceptor: Rc<dyn Interceptor>,
+    ) {
+        self.interceptors.borrow_mut().add(range, order, interceptor);
+    }
+
+    /// Remove an interceptor added with add_interceptor(), from all of its
+    /// ranges. The operations already past it are unaffected.
+    pub fn remove_interceptor(&self, interceptor: &Rc<dyn Interceptor>) -> Result<()> {
+        if !self.interceptors.borrow_mut().remove(interceptor) {
+            bail!("the interceptor was never added");
+        }
+        Ok(())
     }
 
     /// Register an event listener
@@ -147,38 +1185,539 @@ impl GattDatabase {
         tcb_idx: TransportIndex,
         bearer: WeakBoxRef<AttServerBearer<AttDatabaseImpl>>,
     ) {
+        self.client_configuration.borrow_mut().on_le_connect(tcb_idx);
+        self.robust_caching.borrow_mut().on_le_connect(tcb_idx);
         for listener in self.listeners.borrow().iter() {
             listener.on_le_connect(tcb_idx, bearer.clone());
         }
     }
 
-    /// When the connection has dropped.
+    /// When the connection has dropped. The state kept for the client is
+    /// released (except that saved for a bonded peer), and then every service
+    /// is told that the peer disconnected.
     pub fn on_bearer_dropped(&self, tcb_idx: TransportIndex) {
+        // the configuration written during a burst is saved before the rest
+        // of the state of the client is released
+        self.end_client_configuration_burst(tcb_idx);
+        self.client_configuration.borrow_mut().on_le_disconnect(tcb_idx);
+        self.robust_caching.borrow_mut().on_le_disconnect(tcb_idx);
+        self.authorization_grants.borrow_mut().on_le_disconnect(tcb_idx);
+        self.write_locks.on_le_disconnect(tcb_idx);
         for listener in self.listeners.borrow().iter() {
             listener.on_le_disconnect(tcb_idx);
         }
+        // the schema is not borrowed while the datastores run, since they may
+        // modify the database
+        let datastores = self.schema.borrow().datastores();
+        for datastore in datastores {
+            datastore.on_peer_disconnected(tcb_idx);
+        }
     }
 
-    /// Add a service with pre-allocated handles (for co-existence with C++) backed by the supplied datastore
-    /// Assumes that the characteristic DECLARATION handles are one less than
-    /// the characteristic handles.
-    /// Returns failure if handles overlap with ones already allocated
-    pub fn add_service_with_handles(
-        &self,
-        service: GattServiceWithHandle,
-        datastore: Rc<dyn RawGattDatastore>,
-    ) -> Result<()> {
-        let mut attributes = BTreeMap::new();
-        let mut attribute_cnt = 0;
+    /// Whether any state is kept for the client on the given transport, i.e.
+    /// between on_bearer_ready() and on_bearer_dropped()
+    pub fn has_connection_state(&self, tcb_idx: TransportIndex) -> bool {
+        self.client_configuration.borrow().has_client(tcb_idx)
+            || self.client_configuration_bursts.borrow().contains_key(&tcb_idx)
+            || self.robust_caching.borrow().has_client(tcb_idx)
+            || self.authorization_grants.borrow().has_grants(tcb_idx)
+            || self.write_locks.has_client(tcb_idx)
+    }
 
-        let mut add_attribute = |attribute: AttAttribute, value: AttAttributeBackingValue| {
-            attribute_cnt += 1;
-            attributes.insert(attribute.handle, AttAttributeWithBackingValue { attribute, value })
+    /// When the peer on a connection has been identified as bonded (i.e. the link
+    /// is encrypted with a bonded key), so its client configuration and robust
+    /// caching state are retained across connections.
+    pub fn on_le_bonded(&self, tcb_idx: TransportIndex, peer: AddressWithType) {
+        self.client_configuration.borrow_mut().on_le_bonded(tcb_idx, peer);
+        self.robust_caching.borrow_mut().on_le_bonded(tcb_idx, peer);
+        for listener in self.listeners.borrow().iter() {
+            listener.on_le_bonded(tcb_idx, peer);
+        }
+    }
+
+    /// End the burst of writes of the client to managed CCCDs, if any, saving
+    /// its configuration and telling the listeners of the changes
+    fn end_client_configuration_burst(&self, tcb_idx: TransportIndex) {
+        let Some(changes) = self.client_configuration_bursts.borrow_mut().remove(&tcb_idx) else {
+            return;
         };
+        self.client_configuration.borrow_mut().save(tcb_idx);
+        for listener in self.listeners.borrow().iter() {
+            listener.on_client_configuration_changes(tcb_idx, &changes);
+        }
+    }
 
-        let mut characteristics = vec![];
+    /// Tell the database that the value of an attribute changed, e.g. when the
+    /// upper layer updates one that it serves, so that a client reading it
+    /// across the change is not served a value stitched from both versions
+    /// (see AttDatabase::value_version()). The writes of clients, and the
+    /// values notified with GattModule::notify_all(), are accounted for
+    /// already.
+    pub fn on_value_changed(&self, handle: AttHandle) {
+        *self.value_versions.borrow_mut().entry(handle).or_default() += 1;
+    }
 
-        // service definition
+    /// The robust caching state of all clients, including the current Database
+    /// Hash, shared with the GATT service that exposes it
+    pub fn robust_caching(&self) -> Rc<RefCell<RobustCachingStore>> {
+        self.robust_caching.clone()
+    }
+
+    /// Persist the handles of the services added with add_service() to the
+    /// given storage, restoring those assigned before a restart. This must be
+    /// set before adding any such services.
+    pub fn set_handle_assignment_storage(
+        &self,
+        storage: Rc<dyn HandleAssignmentStorage>,
+    ) -> Result<()> {
+        let mut handle_assignments = self.handle_assignments.borrow_mut();
+        if !handle_assignments.is_empty() {
+            bail!("handle assignment storage must be set before adding services");
+        }
+        handle_assignments.attach_storage(storage);
+        Ok(())
+    }
+
+    /// Set the policy deciding how many handles to set aside for the services
+    /// that add_service() places anew from now on (CompactAllocation by
+    /// default). Those already placed keep the handles set aside for them.
+    pub fn set_handle_allocation_policy(&self, policy: Rc<dyn HandleAllocationPolicy>) {
+        self.handle_assignments.borrow_mut().set_allocation_policy(policy);
+    }
+
+    /// The handles assigned to the services added with add_service(), shared
+    /// with the GATT service, which tells bonded clients about those that
+    /// changed since a restart
+    pub fn handle_assignments(&self) -> Rc<RefCell<HandleAssignments>> {
+        self.handle_assignments.clone()
+    }
+
+    /// Persist what clients write to the user descriptions managed by this
+    /// database to the given storage, restoring those written before a
+    /// restart. This must be set before adding any services with such
+    /// descriptions.
+    pub fn set_user_description_storage(
+        &self,
+        storage: Rc<dyn UserDescriptionStorage>,
+    ) -> Result<()> {
+        let mut user_descriptions = self.user_descriptions.borrow_mut();
+        if !user_descriptions.is_empty() {
+            bail!("user description storage must be set before adding services");
+        }
+        user_descriptions.attach_storage(storage);
+        Ok(())
+    }
+
+    /// The services in this database, with the range of handles that each one
+    /// spans and its type, in order of handle
+    pub fn services(&self) -> Vec<(RangeInclusive<AttHandle>, Uuid)> {
+        let mut services = vec![];
+        for AttAttributeWithBackingValue { attribute, value, .. } in
+            self.schema.borrow().attributes.values()
+        {
+            if attribute.type_ == PRIMARY_SERVICE_DECLARATION_UUID {
+                let AttAttributeBackingValue::Static(value) = value else {
+                    error!("service declaration at {:?} has no static value", attribute.handle);
+                    continue;
+                };
+                let Some(type_) = Uuid::try_from_le_slice(value) else {
+                    error!("service declaration at {:?} has an invalid type", attribute.handle);
+                    continue;
+                };
+                services.push((attribute.handle..=attribute.handle, type_));
+            } else if let Some((range, _)) = services.last_mut() {
+                *range = *range.start()..=attribute.handle;
+            }
+        }
+        services
+    }
+
+    /// The layout of this database, from which from_schema() can restore it
+    pub fn to_schema(&self) -> DatabaseSchema {
+        let schema = self.schema.borrow();
+        let user_descriptions = self.user_descriptions.borrow();
+        let mut services: Vec<ServiceSchema> = vec![];
+        // the properties declared for the characteristic whose value comes next
+        let mut declared_properties = None;
+        for AttAttributeWithBackingValue {
+            attribute,
+            value,
+            min_encryption_key_size,
+            transport,
+            ..
+        } in schema.attributes.values()
+        {
+            let value = match value {
+                AttAttributeBackingValue::Static(value) => ValueSchema::Static(value.to_vec()),
+                AttAttributeBackingValue::DynamicCharacteristic(_)
+                | AttAttributeBackingValue::DynamicDescriptor(_) => ValueSchema::Dynamic,
+                AttAttributeBackingValue::ClientConfiguration(_) => {
+                    ValueSchema::ClientConfiguration
+                }
+                AttAttributeBackingValue::UserDescription => ValueSchema::UserDescription(
+                    user_descriptions.get(attribute.handle).unwrap_or_default().to_vec(),
+                ),
+            };
+            if attribute.type_ == PRIMARY_SERVICE_DECLARATION_UUID {
+                let Some(type_) = (match &value {
+                    ValueSchema::Static(value) => Uuid::try_from_le_slice(value),
+                    _ => None,
+                }) else {
+                    error!("service declaration at {:?} has an invalid type", attribute.handle);
+                    continue;
+                };
+                services.push(ServiceSchema {
+                    handle: attribute.handle.0,
+                    uuid: type_.to_string(),
+                    transport: transport.map(TransportSchema::from),
+                    characteristics: vec![],
+                });
+                continue;
+            }
+            let Some(service) = services.last_mut() else {
+                error!("attribute at {:?} precedes every service", attribute.handle);
+                continue;
+            };
+            if attribute.type_ == CHARACTERISTIC_UUID {
+                declared_properties = Some(match &value {
+                    ValueSchema::Static(value) => value.first().copied().unwrap_or_default(),
+                    _ => 0,
+                });
+            } else if let Some(properties) = declared_properties.take() {
+                service.characteristics.push(CharacteristicSchema {
+                    handle: attribute.handle.0,
+                    uuid: attribute.type_.to_string(),
+                    properties,
+                    permissions: permission_names(attribute.permissions),
+                    value,
+                    min_encryption_key_size: *min_encryption_key_size,
+                    descriptors: vec![],
+                });
+            } else if let Some(characteristic) = service.characteristics.last_mut() {
+                characteristic.descriptors.push(DescriptorSchema {
+                    handle: attribute.handle.0,
+                    uuid: attribute.type_.to_string(),
+                    permissions: permission_names(attribute.permissions),
+                    value,
+                });
+            } else {
+                error!("descriptor at {:?} precedes every characteristic", attribute.handle);
+            }
+        }
+        DatabaseSchema { services }
+    }
+
+    /// Add the services of a schema exported by to_schema(), at the handles it
+    /// records, backed by the supplied datastore (for all attributes with a
+    /// dynamic value). The managed CCCDs are recreated without any
+    /// subscription, and the managed user descriptions take their value in
+    /// the schema as their default. Nothing is added if any service fails to
+    /// be.
+    pub fn from_schema(
+        &self,
+        schema: &DatabaseSchema,
+        datastore: Rc<dyn RawGattDatastore>,
+    ) -> Result<()> {
+        let mut instances = HashMap::<Uuid, u16>::new();
+        let mut added = vec![];
+        for service in &schema.services {
+            let result = parse_uuid(&service.uuid).and_then(|type_| {
+                let instance = instances.entry(type_).or_default();
+                let key = ServiceKey { type_, instance: *instance };
+                *instance += 1;
+                let (service, static_values, user_descriptions, value_options, transport) =
+                    service_from_schema(service, key, &self.config)?;
+                self.insert_service(
+                    service,
+                    static_values,
+                    user_descriptions,
+                    value_options,
+                    transport,
+                    datastore.clone(),
+                )
+            });
+            if let Err(err) = result {
+                if !added.is_empty() {
+                    self.remove_services_at_handles(&added)?;
+                }
+                return Err(err.context(format!("restoring the service at {}", service.handle)));
+            }
+            added.push(AttHandle(service.handle));
+        }
+        Ok(())
+    }
+
+    /// The characteristics (with a CCCD managed by this database) to which the
+    /// client on the given transport is subscribed
+    pub fn subscriptions(&self, tcb_idx: TransportIndex) -> Vec<(AttHandle, ClientConfiguration)> {
+        self.client_configuration.borrow().subscriptions(tcb_idx)
+    }
+
+    /// Check that the given handle was not removed since the client on the
+    /// specified transport was last told of a change covering it (in which
+    /// case it may still be using a stale cache). Otherwise, fails with
+    /// INVALID_HANDLE, even if the handle was assigned again.
+    fn check_not_removed(
+        &self,
+        tcb_idx: TransportIndex,
+        handle: AttHandle,
+    ) -> Result<(), AttError> {
+        if self.robust_caching.borrow().is_handle_removed(tcb_idx, handle) {
+            warn!("rejecting access to {handle:?}, removed before {tcb_idx:?} was told");
+            return Err(AttError::from(AttErrorCode::INVALID_HANDLE).for_handle(handle));
+        }
+        Ok(())
+    }
+
+    /// The interceptors of the operations on an attribute, in the order they
+    /// run
+    fn interceptors_for(&self, handle: AttHandle) -> Vec<Rc<dyn Interceptor>> {
+        let schema = self.schema.borrow();
+        let Some(attr) = schema.attributes.get(&handle) else {
+            return vec![];
+        };
+        if matches!(attr.attribute.type_.to_u16(), Some(0x2800..=0x2803)) {
+            // declarations are needed to discover the services
+            return vec![];
+        }
+        self.interceptors.borrow().interceptors_for(handle)
+    }
+
+    /// Check that the AccessInterceptor (if any) lets the client on the
+    /// specified transport access an attribute
+    fn check_access(
+        &self,
+        tcb_idx: TransportIndex,
+        attr: &AttAttributeWithBackingValue,
+        access: AttributeAccess,
+    ) -> Result<(), AttError> {
+        let Some(interceptor) = self.access_interceptor.borrow().clone() else {
+            return Ok(());
+        };
+        let handle = attr.attribute.handle;
+        if matches!(attr.attribute.type_.to_u16(), Some(0x2800..=0x2803)) {
+            // declarations are needed to discover the services
+            return Ok(());
+        }
+        let Some(service_type) = self.schema.borrow().service_type(handle) else {
+            error!("attribute {handle} is not in a service");
+            return Err(AttError::from(AttErrorCode::UNLIKELY_ERROR).for_handle(handle));
+        };
+        let bonded = self.client_configuration.borrow().is_bonded(tcb_idx);
+        interceptor
+            .check_access(&AccessContext { tcb_idx, bonded, service_type, handle, access })
+            .map_err(|code| AttError::new(code, ErrorLayer::AccessInterceptor).for_handle(handle))
+    }
+
+    /// Check that the specified transport may access an attribute (as decided
+    /// by the AccessInterceptor), and is secure enough to. If it is, but the
+    /// client still needs to be authorized, returns the AuthorizationProvider
+    /// to consult.
+    fn check_security(
+        &self,
+        tcb_idx: TransportIndex,
+        attr: &AttAttributeWithBackingValue,
+        access: AttributeAccess,
+    ) -> Result<Option<Rc<dyn AuthorizationProvider>>, AttError> {
+        self.check_access(tcb_idx, attr, access)?;
+        let handle = attr.attribute.handle;
+        let (security_level, authorized) = match &self.security_manager {
+            Some(security_manager) => (
+                security_manager.get_security_level(tcb_idx),
+                security_manager.is_authorized(tcb_idx),
+            ),
+            None => (SecurityLevel::NoSecurity, false),
+        };
+        let authorized = authorized
+            || self.authorization_grants.borrow().is_granted(
+                tcb_idx,
+                attr.attribute.handle,
+                attr.registration,
+            );
+        let result = match attr.attribute.permissions.check_security(security_level, authorized) {
+            // the link is encrypted, but the key may still be too short
+            result @ (Ok(()) | Err(AttErrorCode::INSUFFICIENT_AUTHORIZATION)) => {
+                self.check_encryption_key_size(tcb_idx, attr).and(result)
+            }
+            result => result,
+        };
+        let code = match result {
+            Ok(()) => return Ok(None),
+            Err(AttErrorCode::INSUFFICIENT_AUTHORIZATION)
+                if self.authorization_provider.borrow().is_some() =>
+            {
+                return Ok(self.authorization_provider.borrow().clone());
+            }
+            Err(code) => code,
+        };
+        let mut error = AttError::new(code, ErrorLayer::Security).for_handle(handle);
+        if let Some(requirement) = UnmetRequirement::from_security_error(code, security_level) {
+            error = error.with_unmet_requirement(requirement);
+        }
+        Err(error)
+    }
+
+    /// Check that the key encrypting the specified transport is at least as
+    /// long as the attribute requires
+    fn check_encryption_key_size(
+        &self,
+        tcb_idx: TransportIndex,
+        attr: &AttAttributeWithBackingValue,
+    ) -> Result<(), AttErrorCode> {
+        let Some(min_size) = attr.min_encryption_key_size else {
+            return Ok(());
+        };
+        let key_size = self
+            .security_manager
+            .as_ref()
+            .and_then(|security_manager| security_manager.get_encryption_key_size(tcb_idx));
+        match key_size {
+            Some(key_size) if key_size >= min_size => Ok(()),
+            _ => {
+                warn!(
+                    "rejecting access to {:?}, which requires a key of {min_size} octets, over a key of {key_size:?}",
+                    attr.attribute.handle
+                );
+                Err(AttErrorCode::INSUFFICIENT_ENCRYPTION_KEY_SIZE)
+            }
+        }
+    }
+
+    /// Recompute the Database Hash after the schema has changed
+    fn update_database_hash(&self) {
+        let database_hash = self.schema.borrow().database_hash();
+        self.robust_caching.borrow_mut().on_database_changed(database_hash);
+    }
+
+    /// Add a service with pre-allocated handles (for co-existence with C++) backed by the supplied datastore
+    /// Assumes that the characteristic DECLARATION handles are one less than
+    /// the characteristic handles.
+    /// Returns failure if handles overlap with ones already allocated
+    pub fn add_service_with_handles(
+        &self,
+        service: GattServiceWithHandle,
+        datastore: Rc<dyn RawGattDatastore>,
+    ) -> Result<()> {
+        self.insert_service(
+            service,
+            HashMap::new(),
+            HashMap::new(),
+            HashMap::new(),
+            None,
+            datastore,
+        )
+    }
+
+    /// Add a service described by a ServiceBuilder, backed by the supplied
+    /// datastore (for all attributes without a static value). It gets back the
+    /// handles it was assigned before a restart, if its layout is unchanged (or
+    /// it still fits in the handles set aside for it) and they are free.
+    /// Otherwise, it is placed at the first range of free handles between
+    /// existing services that can hold it, preferably along with the spare
+    /// handles the allocation policy sets aside for it, and clear of those set
+    /// aside for other services. Returns a token with which to remove the
+    /// service.
+    pub fn add_service(
+        &self,
+        service: ServiceBuilder,
+        datastore: Rc<dyn RawGattDatastore>,
+    ) -> Result<ServiceToken> {
+        service.validate(&self.config)?;
+        let handle_count = service.handle_count();
+        let layout = service.layout();
+        let (key, placement) = {
+            let handle_assignments = self.handle_assignments.borrow();
+            let schema = self.schema.borrow();
+            let key = handle_assignments.key_for(service.type_, layout);
+            let reserved = handle_assignments.reserved(key);
+            let reserved_count = handle_assignments.reserved_handle_count(handle_count);
+            let placement = handle_assignments
+                .assigned_reservation(key, layout, handle_count)
+                .filter(|(handle, _)| schema.are_free_handles(*handle, handle_count))
+                .or_else(|| {
+                    schema
+                        .find_free_handles(reserved_count, &reserved)
+                        .map(|handle| (handle, reserved_count))
+                })
+                .or_else(|| {
+                    schema
+                        .find_free_handles(handle_count, &reserved)
+                        .map(|handle| (handle, handle_count))
+                })
+                .or_else(|| {
+                    schema.find_free_handles(handle_count, &[]).map(|handle| (handle, handle_count))
+                });
+            (key, placement)
+        };
+        let Some((handle, reserved_count)) = placement else {
+            bail!("no range of {handle_count} free handles for service {:?}", service.type_);
+        };
+        let transport = service.transport;
+        let (service, static_values, user_descriptions, value_options) =
+            service.into_service_with_handles(handle, key);
+        self.insert_service(
+            service,
+            static_values,
+            user_descriptions,
+            value_options,
+            transport,
+            datastore,
+        )?;
+        self.handle_assignments.borrow_mut().on_service_added(
+            key,
+            handle,
+            handle_count,
+            reserved_count,
+            layout,
+        );
+        Ok(ServiceToken { handle })
+    }
+
+    /// Remove a service added with add_service()
+    pub fn remove_service(&self, token: ServiceToken) -> Result<()> {
+        self.remove_services(vec![token])
+    }
+
+    /// Add a service with pre-allocated handles, whose characteristics and
+    /// descriptors are backed by the supplied datastore, unless they have a
+    /// static value in static_values, or are user descriptions (with their
+    /// key and default value) in user_descriptions. The characteristics in
+    /// value_options have their values validated, or their notifications
+    /// coalesced. If a transport is given, the service is only visible over it.
+    fn insert_service(
+        &self,
+        service: GattServiceWithHandle,
+        mut static_values: StaticValues,
+        mut user_descriptions: ManagedUserDescriptions,
+        mut value_options: ValueOptionsByHandle,
+        transport: Option<Transport>,
+        datastore: Rc<dyn RawGattDatastore>,
+    ) -> Result<()> {
+        let mut attributes = BTreeMap::new();
+        let mut attribute_cnt = 0;
+        let registration = self.schema.borrow_mut().alloc_registration();
+
+        let mut add_attribute = |attribute: AttAttribute, value: AttAttributeBackingValue| {
+            attribute_cnt += 1;
+            let options = value_options.remove(&attribute.handle).unwrap_or_default();
+            attributes.insert(
+                attribute.handle,
+                AttAttributeWithBackingValue {
+                    attribute,
+                    value,
+                    registration,
+                    validator: options.validator,
+                    coalesce_notifications: options.coalesce_notifications,
+                    min_encryption_key_size: options.min_encryption_key_size,
+                    transport,
+                },
+            )
+        };
+
+        let mut characteristics = vec![];
+        let mut added_descriptions = vec![];
+
+        // service definition
         add_attribute(
             AttAttribute {
                 handle: service.handle,
@@ -190,7 +1729,8 @@ impl GattDatabase {
                     .to_vec()
                     .map_err(|e| {
                         anyhow::anyhow!("failed to encode primary service declaration: {e:?}")
-                    })?,
+                    })?
+                    .into(),
             ),
         );
 
@@ -202,6 +1742,12 @@ impl GattDatabase {
             // Recall that we assume the declaration handle is one less than the value
             // handle
             let declaration_handle = AttHandle(characteristic.handle.0 - 1);
+            // the properties bit signals the presence of the descriptor
+            // (Core Spec 5.3 Vol 3G 3.3.1.1)
+            let has_extended_properties = characteristic
+                .descriptors
+                .iter()
+                .any(|descriptor| descriptor.type_ == CHARACTERISTIC_EXTENDED_PROPERTIES_UUID);
 
             add_attribute(
                 AttAttribute {
@@ -219,10 +1765,10 @@ impl GattDatabase {
                                 .writable_without_response()
                                 .into(),
                             write: characteristic.permissions.writable_with_response().into(),
-                            notify: 0,
+                            notify: characteristic.permissions.notify().into(),
                             indicate: characteristic.permissions.indicate().into(),
                             authenticated_signed_writes: 0,
-                            extended_properties: 0,
+                            extended_properties: has_extended_properties.into(),
                         },
                         handle: characteristic.handle.into(),
                         uuid: characteristic.type_.into(),
@@ -230,7 +1776,8 @@ impl GattDatabase {
                     .to_vec()
                     .map_err(|e| {
                         anyhow::anyhow!("failed to encode characteristic declaration: {e:?}")
-                    })?,
+                    })?
+                    .into(),
                 ),
             );
 
@@ -241,18 +1788,57 @@ impl GattDatabase {
                     type_: characteristic.type_,
                     permissions: characteristic.permissions,
                 },
-                AttAttributeBackingValue::DynamicCharacteristic(datastore.clone()),
+                match static_values.remove(&characteristic.handle) {
+                    Some(value) => AttAttributeBackingValue::Static(value.into()),
+                    None => AttAttributeBackingValue::DynamicCharacteristic(datastore.clone()),
+                },
             );
 
             // descriptors
+            let has_cccd = characteristic
+                .descriptors
+                .iter()
+                .any(|descriptor| descriptor.type_ == CLIENT_CHARACTERISTIC_CONFIGURATION_UUID);
+            let last_handle = characteristic
+                .descriptors
+                .iter()
+                .map(|descriptor| descriptor.handle)
+                .max()
+                .unwrap_or(characteristic.handle);
             for descriptor in characteristic.descriptors {
+                let value = if let Some(value) = static_values.remove(&descriptor.handle) {
+                    AttAttributeBackingValue::Static(value.into())
+                } else if let Some(description) = user_descriptions.remove(&descriptor.handle) {
+                    added_descriptions.push((descriptor.handle, description));
+                    AttAttributeBackingValue::UserDescription
+                } else {
+                    AttAttributeBackingValue::DynamicDescriptor(datastore.clone())
+                };
                 add_attribute(
                     AttAttribute {
                         handle: descriptor.handle,
                         type_: descriptor.type_,
                         permissions: descriptor.permissions,
                     },
-                    AttAttributeBackingValue::DynamicDescriptor(datastore.clone()),
+                    value,
+                );
+            }
+
+            // if the upper layer did not supply a CCCD, we manage one ourselves
+            if (characteristic.permissions.notify() || characteristic.permissions.indicate())
+                && !has_cccd
+            {
+                let Some(cccd_handle) = last_handle.0.checked_add(1).map(AttHandle) else {
+                    bail!("no free handle for the CCCD of {:?}", characteristic.handle);
+                };
+                add_attribute(
+                    AttAttribute {
+                        handle: cccd_handle,
+                        type_: CLIENT_CHARACTERISTIC_CONFIGURATION_UUID,
+                        permissions: AttPermissions::READABLE
+                            | AttPermissions::WRITABLE_WITH_RESPONSE,
+                    },
+                    AttAttributeBackingValue::ClientConfiguration(characteristic.handle),
                 );
             }
         }
@@ -271,10 +1857,15 @@ impl GattDatabase {
 
         // if we made it here, we successfully loaded the new service
         static_data.attributes.extend(attributes.clone());
+        for (handle, (key, default)) in added_descriptions {
+            self.user_descriptions.borrow_mut().on_descriptor_added(handle, key, default);
+        }
 
         // re-entrancy via the listeners is possible, so we prevent it by dropping here
         drop(static_data);
 
+        self.update_database_hash();
+
         // notify listeners if any attribute changed
         let added_handles = attributes.into_iter().map(|attr| attr.0).collect::<Vec<_>>();
         if !added_handles.is_empty() {
@@ -290,37 +1881,84 @@ impl GattDatabase {
 
     /// Remove a previously-added service by service handle
     pub fn remove_service_at_handle(&self, service_handle: AttHandle) -> Result<()> {
-        let mut static_data = self.schema.borrow_mut();
+        self.remove_services_at_handles(&[service_handle])
+    }
 
-        // find next service
-        let next_service_handle = static_data
-            .attributes
-            .values()
-            .find(|attribute| {
-                attribute.attribute.handle > service_handle
-                    && attribute.attribute.type_ == PRIMARY_SERVICE_DECLARATION_UUID
-            })
-            .map(|service| service.attribute.handle);
+    /// Remove several services added with add_service() at once. Clients never
+    /// observe only some of them removed, and listeners are told of a single
+    /// change covering all of them. Nothing is removed if any of them was
+    /// already removed.
+    pub fn remove_services(&self, tokens: Vec<ServiceToken>) -> Result<()> {
+        {
+            let schema = self.schema.borrow();
+            for token in &tokens {
+                let is_service = schema
+                    .attributes
+                    .get(&token.handle)
+                    .map(|attr| attr.attribute.type_ == PRIMARY_SERVICE_DECLARATION_UUID)
+                    .unwrap_or(false);
+                if !is_service {
+                    bail!("service at {:?} was already removed", token.handle);
+                }
+            }
+        }
+        self.remove_services_at_handles(
+            &tokens.iter().map(|token| token.handle).collect::<Vec<_>>(),
+        )
+    }
 
-        // predicate matching all handles in our service
-        let in_service_pred = |handle: AttHandle| {
-            service_handle <= handle && next_service_handle.map(|x| handle < x).unwrap_or(true)
-        };
+    fn remove_services_at_handles(&self, service_handles: &[AttHandle]) -> Result<()> {
+        let mut static_data = self.schema.borrow_mut();
 
-        // record largest attribute matching predicate
-        let largest_service_handle =
-            static_data.attributes.keys().filter(|handle| in_service_pred(**handle)).max().cloned();
+        // find the range of every service before removing any of them, since
+        // each one extends up to the next service
+        let ranges = service_handles
+            .iter()
+            .filter_map(|&service_handle| {
+                let next_service_handle = static_data
+                    .attributes
+                    .values()
+                    .find(|attribute| {
+                        attribute.attribute.handle > service_handle
+                            && attribute.attribute.type_ == PRIMARY_SERVICE_DECLARATION_UUID
+                    })
+                    .map(|service| service.attribute.handle);
+                let largest_service_handle = static_data
+                    .attributes
+                    .range(service_handle..)
+                    .map(|(handle, _)| *handle)
+                    .take_while(|handle| next_service_handle.map(|x| *handle < x).unwrap_or(true))
+                    .last()?;
+                Some(service_handle..=largest_service_handle)
+            })
+            .collect::<Vec<_>>();
 
         // clear out attributes
-        static_data.attributes.retain(|curr_handle, _| !in_service_pred(*curr_handle));
+        static_data
+            .attributes
+            .retain(|curr_handle, _| !ranges.iter().any(|range| range.contains(curr_handle)));
 
         // re-entrancy via the listeners is possible, so we prevent it by dropping here
         drop(static_data);
 
-        // notify listeners if any attribute changed
-        if let Some(largest_service_handle) = largest_service_handle {
+        // and forget any subscriptions to the removed characteristics
+        for range in &ranges {
+            self.client_configuration.borrow_mut().on_handles_removed(range.clone());
+            self.robust_caching.borrow_mut().on_handles_removed(range.clone());
+            self.handle_assignments.borrow_mut().on_service_removed(*range.start());
+            self.user_descriptions.borrow_mut().on_handles_removed(range.clone());
+        }
+
+        self.update_database_hash();
+
+        // notify listeners if any attribute changed, with one range covering
+        // every removed service (which may also cover services in between them,
+        // as clients then just rediscover those)
+        let start = ranges.iter().map(|range| *range.start()).min();
+        let end = ranges.iter().map(|range| *range.end()).max();
+        if let (Some(start), Some(end)) = (start, end) {
             for listener in self.listeners.borrow().iter() {
-                listener.on_service_change(service_handle..=largest_service_handle);
+                listener.on_service_change(start..=end);
             }
         }
 
@@ -335,7 +1973,17 @@ impl SharedBox<GattDatabase> {
     /// Note: After the AttDatabaseImpl is constructed, we MUST call on_bearer_ready() with
     /// the resultant bearer, so that the listeners get the correct sequence of callbacks.
     pub fn get_att_database(&self, tcb_idx: TransportIndex) -> AttDatabaseImpl {
-        AttDatabaseImpl { gatt_db: self.downgrade(), tcb_idx }
+        self.get_att_database_over(tcb_idx, Transport::Le)
+    }
+
+    /// As get_att_database(), for a connection over the given transport, which
+    /// only sees the services visible over it
+    pub fn get_att_database_over(
+        &self,
+        tcb_idx: TransportIndex,
+        transport: Transport,
+    ) -> AttDatabaseImpl {
+        AttDatabaseImpl { gatt_db: self.downgrade(), tcb_idx, transport }
     }
 }
 
@@ -343,94 +1991,129 @@ impl SharedBox<GattDatabase> {
 pub struct AttDatabaseImpl {
     gatt_db: WeakBox<GattDatabase>,
     tcb_idx: TransportIndex,
+    transport: Transport,
 }
 
+/// The backing value of an attribute about to be written, its registration,
+/// and the AuthorizationProvider to consult first, if any
+type WriteTarget = (AttAttributeBackingValue, u64, Option<Rc<dyn AuthorizationProvider>>);
+
 #[async_trait(?Send)]
 impl AttDatabase for AttDatabaseImpl {
-    async fn read_attribute(&self, handle: AttHandle) -> Result<Vec<u8>, AttErrorCode> {
-        let value = self.gatt_db.with(|gatt_db| {
+    async fn read_attribute(&self, handle: AttHandle) -> Result<AttAttributeValue, AttError> {
+        self.read_attribute_at(handle, 0).await
+    }
+
+    async fn read_attribute_at(
+        &self,
+        handle: AttHandle,
+        offset: u32,
+    ) -> Result<AttAttributeValue, AttError> {
+        let (value, registration, authorization_provider) = self.gatt_db.with(|gatt_db| {
             let Some(gatt_db) = gatt_db else {
                 // db must have been closed
-                return Err(AttErrorCode::INVALID_HANDLE);
+                return Err(AttError::from(AttErrorCode::INVALID_HANDLE).for_handle(handle));
             };
+            gatt_db.check_not_removed(self.tcb_idx, handle)?;
             let services = gatt_db.schema.borrow();
-            let Some(attr) = services.attributes.get(&handle) else {
-                return Err(AttErrorCode::INVALID_HANDLE);
+            let Some(attr) = services.visible_attribute(handle, self.transport) else {
+                return Err(AttError::from(AttErrorCode::INVALID_HANDLE).for_handle(handle));
             };
             if !attr.attribute.permissions.readable() {
-                return Err(AttErrorCode::READ_NOT_PERMITTED);
+                return Err(AttError::from(AttErrorCode::READ_NOT_PERMITTED).for_handle(handle));
             }
-            Ok(attr.value.clone())
+            let authorization_provider =
+                gatt_db.check_security(self.tcb_idx, attr, AttributeAccess::Read)?;
+            Ok((attr.value.clone(), attr.registration, authorization_provider))
         })?;
+        self.authorize(handle, registration, authorization_provider, AttributeAccess::Read).await?;
+
+        let mut operation = InterceptedOperation::new(
+            self.interceptors_for(handle),
+            OperationContext {
+                tcb_idx: self.tcb_idx,
+                handle,
+                access: AttributeAccess::Read,
+                offset,
+                expects_response: true,
+            },
+        );
+        let result = match operation.before_read() {
+            Some(result) => result,
+            None => self.read_value(handle, offset, value, registration).await,
+        };
+        operation.after_read(result)
+    }
 
-        match value {
-            AttAttributeBackingValue::Static(val) => return Ok(val),
-            AttAttributeBackingValue::DynamicCharacteristic(datastore) => {
-                datastore
-                    .read(
-                        self.tcb_idx,
-                        handle,
-                        /* offset */ 0,
-                        AttributeBackingType::Characteristic,
-                    )
-                    .await
-            }
-            AttAttributeBackingValue::DynamicDescriptor(datastore) => {
-                datastore
-                    .read(
-                        self.tcb_idx,
-                        handle,
-                        /* offset */ 0,
-                        AttributeBackingType::Descriptor,
-                    )
-                    .await
-            }
+    async fn write_attribute(
+        &self,
+        handle: AttHandle,
+        offset: u32,
+        data: &[u8],
+    ) -> Result<(), AttError> {
+        // wait for the writes of this client to the attribute on its other
+        // bearers, so that the datastore only ever sees one at a time
+        let Some(write_lock) =
+            self.gatt_db.with(|gatt_db| Some(gatt_db?.write_locks.lock(self.tcb_idx, handle)))
+        else {
+            // db must have been closed
+            return Err(AttError::from(AttErrorCode::INVALID_HANDLE).for_handle(handle));
+        };
+        let _write_guard = write_lock.await;
+
+        let (value, registration, authorization_provider) =
+            self.checked_write_target(handle, offset, data)?;
+
+        self.authorize(handle, registration, authorization_provider, AttributeAccess::Write)
+            .await?;
+
+        let mut operation = InterceptedOperation::new(
+            self.interceptors_for(handle),
+            OperationContext {
+                tcb_idx: self.tcb_idx,
+                handle,
+                access: AttributeAccess::Write,
+                offset,
+                expects_response: true,
+            },
+        );
+        let mut data = Cow::Borrowed(data);
+        let result = match operation.before_write(&mut data) {
+            Some(result) => result,
+            None => self.write_value(handle, offset, &data, value, registration).await,
+        };
+        let result = operation.after_write(result);
+        if result.is_ok() {
+            self.gatt_db.with(|gatt_db| {
+                if let Some(gatt_db) = gatt_db {
+                    gatt_db.on_value_changed(handle);
+                }
+            });
         }
+        result
     }
 
-    async fn write_attribute(&self, handle: AttHandle, data: &[u8]) -> Result<(), AttErrorCode> {
-        let value = self.gatt_db.with(|gatt_db| {
-            let Some(gatt_db) = gatt_db else {
-                // db must have been closed
-                return Err(AttErrorCode::INVALID_HANDLE);
-            };
-            let services = gatt_db.schema.borrow();
-            let Some(attr) = services.attributes.get(&handle) else {
-                return Err(AttErrorCode::INVALID_HANDLE);
-            };
-            if !attr.attribute.permissions.writable_with_response() {
-                return Err(AttErrorCode::WRITE_NOT_PERMITTED);
-            }
-            Ok(attr.value.clone())
-        })?;
-
-        match value {
-            AttAttributeBackingValue::Static(val) => {
-                error!("A static attribute {val:?} is marked as writable - ignoring it and rejecting the write...");
-                return Err(AttErrorCode::WRITE_NOT_PERMITTED);
-            }
-            AttAttributeBackingValue::DynamicCharacteristic(datastore) => {
-                datastore
-                    .write(
-                        self.tcb_idx,
-                        handle,
-                        AttributeBackingType::Characteristic,
-                        GattWriteRequestType::Request,
-                        data,
-                    )
-                    .await
-            }
-            AttAttributeBackingValue::DynamicDescriptor(datastore) => {
-                datastore
-                    .write(
-                        self.tcb_idx,
-                        handle,
-                        AttributeBackingType::Descriptor,
-                        GattWriteRequestType::Request,
-                        data,
-                    )
-                    .await
+    fn check_write(&self, handle: AttHandle, offset: u32, data: &[u8]) -> Result<(), AttError> {
+        let (value, _, _) = self.checked_write_target(handle, offset, data)?;
+        // the offsets into the values managed by this database are checked
+        // here, while datastores check their own when written to
+        let error_code = match value {
+            AttAttributeBackingValue::ClientConfiguration(_) if offset != 0 => {
+                Some(AttErrorCode::ATTRIBUTE_NOT_LONG)
             }
+            AttAttributeBackingValue::UserDescription => self.gatt_db.with(|gatt_db| {
+                let len = gatt_db?.user_descriptions.borrow().get(handle).map(<[u8]>::len);
+                match len {
+                    None => Some(AttErrorCode::INVALID_HANDLE),
+                    Some(len) if offset as usize > len => Some(AttErrorCode::INVALID_OFFSET),
+                    Some(_) => None,
+                }
+            }),
+            _ => None,
+        };
+        match error_code {
+            Some(code) => Err(AttError::from(code).for_handle(handle).at_offset(offset)),
+            None => Ok(()),
         }
     }
 
@@ -440,8 +2123,11 @@ impl AttDatabase for AttDatabaseImpl {
                 // db must have been closed
                 return None;
             };
+            if gatt_db.check_not_removed(self.tcb_idx, handle).is_err() {
+                return None;
+            }
             let services = gatt_db.schema.borrow();
-            let Some(attr) = services.attributes.get(&handle) else {
+            let Some(attr) = services.visible_attribute(handle, self.transport) else {
                 warn!("cannot find handle {handle:?}");
                 return None;
             };
@@ -449,12 +2135,57 @@ impl AttDatabase for AttDatabaseImpl {
                 warn!("trying to write without response to {handle:?}, which doesn't support it");
                 return None;
             }
+            if !services.allows_auxiliary_write(&attr.attribute) {
+                warn!("dropping write to user description {handle:?} without writable auxiliaries");
+                return None;
+            }
+            if gatt_db.config.check_attribute_length(0, data.len()).is_err() {
+                warn!("dropping write without response to {handle:?} exceeding the maximum value length");
+                return None;
+            }
+            if let Err(error_code) = attr.validate_write(0, data) {
+                warn!("dropping invalid write without response to {handle:?}: {error_code:?}");
+                return None;
+            }
+            // there is no response in which to wait for authorization, so only
+            // clients that are already authorized may write
+            match gatt_db.check_security(self.tcb_idx, attr, AttributeAccess::Write) {
+                Ok(None) => (),
+                Ok(Some(_)) => {
+                    warn!(
+                        "dropping write without response to {handle:?} from an unauthorized client"
+                    );
+                    return None;
+                }
+                Err(error_code) => {
+                    warn!("dropping write without response to {handle:?} due to {error_code:?}");
+                    return None;
+                }
+            }
             Some(attr.value.clone())
         });
 
         let Some(value) = value else {
             return;
         };
+        let mut operation = InterceptedOperation::new(
+            self.interceptors_for(handle),
+            OperationContext {
+                tcb_idx: self.tcb_idx,
+                handle,
+                access: AttributeAccess::Write,
+                offset: 0,
+                expects_response: false,
+            },
+        );
+        let mut data = Cow::Borrowed(data);
+        if let Some(result) = operation.before_write(&mut data) {
+            if let Err(err) = operation.after_write(result) {
+                warn!("dropping write without response to {handle:?}: {err}");
+            }
+            return;
+        }
+        let data = &*data;
 
         match value {
             AttAttributeBackingValue::Static(val) => {
@@ -467,6 +2198,11 @@ impl AttDatabase for AttDatabaseImpl {
                     AttributeBackingType::Characteristic,
                     data,
                 );
+                self.gatt_db.with(|gatt_db| {
+                    if let Some(gatt_db) = gatt_db {
+                        gatt_db.on_value_changed(handle);
+                    }
+                });
             }
             AttAttributeBackingValue::DynamicDescriptor(datastore) => {
                 datastore.write_no_response(
@@ -475,35 +2211,448 @@ impl AttDatabase for AttDatabaseImpl {
                     AttributeBackingType::Descriptor,
                     data,
                 );
+                self.gatt_db.with(|gatt_db| {
+                    if let Some(gatt_db) = gatt_db {
+                        gatt_db.on_value_changed(handle);
+                    }
+                });
+            }
+            AttAttributeBackingValue::ClientConfiguration(_) => {
+                error!("A CCCD {handle:?} is marked as writable without response - ignoring the write...");
+            }
+            AttAttributeBackingValue::UserDescription => {
+                error!("A user description {handle:?} is marked as writable without response - ignoring the write...");
             }
         };
+        // there is no response in which to return the result
+        let _ = operation.after_write(Ok(()));
     }
 
     fn list_attributes(&self) -> Vec<AttAttribute> {
         self.gatt_db.with(|db| {
-            db.map(|db| db.schema.borrow().attributes.values().map(|attr| attr.attribute).collect())
-                .unwrap_or_default()
+            db.map(|db| {
+                db.schema
+                    .borrow()
+                    .attributes
+                    .values()
+                    .filter(|attr| attr.is_visible_over(self.transport))
+                    .map(|attr| attr.attribute)
+                    .collect()
+            })
+            .unwrap_or_default()
         })
     }
-}
-
-impl Clone for AttDatabaseImpl {
-    fn clone(&self) -> Self {
-        Self { gatt_db: self.gatt_db.clone(), tcb_idx: self.tcb_idx }
-    }
-}
 
-impl AttDatabaseImpl {
-    /// When the bearer owning this AttDatabase is invalidated,
-    /// we must notify the listeners tied to our GattDatabase.
-    ///
-    /// Note: AttDatabases referring to the backing GattDatabase
-    /// may still exist after bearer invalidation, but the bearer will
-    /// no longer exist (so packets can no longer be sent/received).
-    pub fn on_bearer_dropped(&self) {
+    fn attributes_in_range(
+        &self,
+        start: AttHandle,
+        end: AttHandle,
+        type_filter: Option<Uuid>,
+    ) -> Vec<AttAttribute> {
+        if start > end {
+            return vec![];
+        }
         self.gatt_db.with(|db| {
             db.map(|db| {
-                for listener in db.listeners.borrow().iter() {
+                db.schema
+                    .borrow()
+                    .attributes
+                    .range(start..=end)
+                    .map(|(_, attr)| attr)
+                    .filter(|attr| attr.is_visible_over(self.transport))
+                    .map(|attr| attr.attribute)
+                    .filter(|attr| type_filter.map(|type_| attr.type_ == type_).unwrap_or(true))
+                    .collect()
+            })
+            .unwrap_or_default()
+        })
+    }
+
+    fn coalesces_notifications(&self, handle: AttHandle) -> bool {
+        self.gatt_db.with(|db| {
+            db.and_then(|db| {
+                db.schema
+                    .borrow()
+                    .visible_attribute(handle, self.transport)
+                    .map(|attr| attr.coalesce_notifications)
+            })
+            .unwrap_or(false)
+        })
+    }
+
+    fn client_configuration(&self, handle: AttHandle) -> Option<ClientConfiguration> {
+        self.gatt_db.with(|db| {
+            let db = db?;
+            let is_managed = db.schema.borrow().attributes.values().any(|attr| {
+                matches!(
+                    attr.value,
+                    AttAttributeBackingValue::ClientConfiguration(characteristic_handle)
+                        if characteristic_handle == handle
+                )
+            });
+            is_managed.then(|| db.client_configuration.borrow().get(self.tcb_idx, handle))
+        })
+    }
+
+    fn is_change_aware(&self) -> bool {
+        self.gatt_db.with(|db| {
+            db.map(|db| db.robust_caching.borrow().is_change_aware(self.tcb_idx)).unwrap_or(true)
+        })
+    }
+
+    fn mark_change_aware(&self) {
+        self.gatt_db.with(|db| {
+            if let Some(db) = db {
+                db.robust_caching.borrow_mut().mark_change_aware(self.tcb_idx);
+            }
+        })
+    }
+
+    fn client_supported_features(&self) -> ClientSupportedFeatures {
+        self.gatt_db.with(|db| {
+            db.map(|db| db.robust_caching.borrow().client_supported_features(self.tcb_idx))
+                .unwrap_or_default()
+        })
+    }
+
+    fn server_config(&self) -> GattServerConfig {
+        self.gatt_db.with(|db| db.map(|db| db.config).unwrap_or_default())
+    }
+
+    fn value_version(&self, handle: AttHandle) -> Option<u64> {
+        self.gatt_db.with(|db| {
+            db.map(|db| db.value_versions.borrow().get(&handle).copied().unwrap_or_default())
+        })
+    }
+
+    fn begin_client_configuration_burst(&self) {
+        self.gatt_db.with(|db| {
+            if let Some(db) = db {
+                db.client_configuration_bursts.borrow_mut().entry(self.tcb_idx).or_default();
+            }
+        })
+    }
+
+    fn end_client_configuration_burst(&self) {
+        self.gatt_db.with(|db| {
+            if let Some(db) = db {
+                db.end_client_configuration_burst(self.tcb_idx);
+            }
+        })
+    }
+}
+
+impl Clone for AttDatabaseImpl {
+    fn clone(&self) -> Self {
+        Self { gatt_db: self.gatt_db.clone(), tcb_idx: self.tcb_idx, transport: self.transport }
+    }
+}
+
+impl AttDatabaseImpl {
+    /// The interceptors of the operations on an attribute, in the order they
+    /// run
+    fn interceptors_for(&self, handle: AttHandle) -> Vec<Rc<dyn Interceptor>> {
+        self.gatt_db.with(|gatt_db| {
+            gatt_db.map(|gatt_db| gatt_db.interceptors_for(handle)).unwrap_or_default()
+        })
+    }
+
+    /// Read the backing value of an attribute, from the given offset onwards
+    async fn read_value(
+        &self,
+        handle: AttHandle,
+        offset: u32,
+        value: AttAttributeBackingValue,
+        registration: u64,
+    ) -> Result<AttAttributeValue, AttError> {
+        let database_error =
+            |code: AttErrorCode| AttError::from(code).for_handle(handle).at_offset(offset);
+        match value {
+            AttAttributeBackingValue::Static(val) => {
+                val.skipped(offset as usize).map_err(database_error)
+            }
+            // the upper layer only produces the value from the offset onwards
+            AttAttributeBackingValue::DynamicCharacteristic(datastore) => {
+                let result = datastore
+                    .read(self.tcb_idx, handle, offset, AttributeBackingType::Characteristic)
+                    .await;
+                self.if_still_registered(handle, registration, result).map(Into::into)
+            }
+            AttAttributeBackingValue::DynamicDescriptor(datastore) => {
+                let result = datastore
+                    .read(self.tcb_idx, handle, offset, AttributeBackingType::Descriptor)
+                    .await;
+                self.if_still_registered(handle, registration, result).map(Into::into)
+            }
+            AttAttributeBackingValue::ClientConfiguration(characteristic_handle) => {
+                let configuration = self
+                    .client_configuration(characteristic_handle)
+                    .ok_or(database_error(AttErrorCode::INVALID_HANDLE))?;
+                GattClientCharacteristicConfigurationBuilder {
+                    notification: configuration.contains(ClientConfiguration::NOTIFICATION).into(),
+                    indication: configuration.contains(ClientConfiguration::INDICATION).into(),
+                }
+                .to_vec()
+                .map_err(|_| AttErrorCode::UNLIKELY_ERROR)
+                .and_then(|value| AttAttributeValue::from(value).skipped(offset as usize))
+                .map_err(database_error)
+            }
+            AttAttributeBackingValue::UserDescription => {
+                self.if_still_registered(handle, registration, Ok(()))?;
+                let value = self
+                    .gatt_db
+                    .with(|gatt_db| {
+                        gatt_db?.user_descriptions.borrow().get(handle).map(<[u8]>::to_vec)
+                    })
+                    .ok_or(database_error(AttErrorCode::INVALID_HANDLE))?;
+                AttAttributeValue::from(value).skipped(offset as usize).map_err(database_error)
+            }
+        }
+    }
+
+    /// Run the checks a write to an attribute must pass before its value
+    /// reaches its owner. If they pass, returns the backing value to write to,
+    /// with the AuthorizationProvider to consult first, if any.
+    fn checked_write_target(
+        &self,
+        handle: AttHandle,
+        offset: u32,
+        data: &[u8],
+    ) -> Result<WriteTarget, AttError> {
+        self.gatt_db.with(|gatt_db| {
+            let Some(gatt_db) = gatt_db else {
+                // db must have been closed
+                return Err(AttError::from(AttErrorCode::INVALID_HANDLE).for_handle(handle));
+            };
+            gatt_db.check_not_removed(self.tcb_idx, handle)?;
+            let services = gatt_db.schema.borrow();
+            let Some(attr) = services.visible_attribute(handle, self.transport) else {
+                return Err(AttError::from(AttErrorCode::INVALID_HANDLE).for_handle(handle));
+            };
+            if !attr.attribute.permissions.writable_with_response() {
+                return Err(AttError::from(AttErrorCode::WRITE_NOT_PERMITTED).for_handle(handle));
+            }
+            if !services.allows_auxiliary_write(&attr.attribute) {
+                warn!(
+                    "rejecting write to user description {handle:?} without writable auxiliaries"
+                );
+                return Err(AttError::from(AttErrorCode::WRITE_NOT_PERMITTED).for_handle(handle));
+            }
+            let authorization_provider =
+                gatt_db.check_security(self.tcb_idx, attr, AttributeAccess::Write)?;
+            if let Err(error_code) =
+                gatt_db.config.check_attribute_length(offset as usize, data.len())
+            {
+                warn!(
+                    "write to {handle:?} at offset {offset} would exceed the maximum value length"
+                );
+                return Err(AttError::from(error_code).for_handle(handle).at_offset(offset));
+            }
+            // don't bother the authorization provider with invalid values
+            attr.validate_write(offset, data).map_err(|code| {
+                AttError::new(code, ErrorLayer::WriteValidator).for_handle(handle).at_offset(offset)
+            })?;
+            Ok((attr.value.clone(), attr.registration, authorization_provider))
+        })
+    }
+
+    /// Write the backing value of an attribute, from the given offset onwards
+    async fn write_value(
+        &self,
+        handle: AttHandle,
+        offset: u32,
+        data: &[u8],
+        value: AttAttributeBackingValue,
+        registration: u64,
+    ) -> Result<(), AttError> {
+        match value {
+            AttAttributeBackingValue::Static(val) => {
+                error!("A static attribute {val:?} is marked as writable - ignoring it and rejecting the write...");
+                Err(AttError::from(AttErrorCode::WRITE_NOT_PERMITTED).for_handle(handle))
+            }
+            AttAttributeBackingValue::DynamicCharacteristic(datastore) => {
+                let result = datastore
+                    .write(
+                        self.tcb_idx,
+                        handle,
+                        AttributeBackingType::Characteristic,
+                        GattWriteRequestType::Request { offset },
+                        data,
+                    )
+                    .await;
+                self.if_still_registered(handle, registration, result)
+            }
+            AttAttributeBackingValue::DynamicDescriptor(datastore) => {
+                let result = datastore
+                    .write(
+                        self.tcb_idx,
+                        handle,
+                        AttributeBackingType::Descriptor,
+                        GattWriteRequestType::Request { offset },
+                        data,
+                    )
+                    .await;
+                self.if_still_registered(handle, registration, result)
+            }
+            AttAttributeBackingValue::ClientConfiguration(_) if offset != 0 => {
+                warn!("got write at non-zero offset to CCCD {handle:?}");
+                Err(AttError::from(AttErrorCode::ATTRIBUTE_NOT_LONG)
+                    .for_handle(handle)
+                    .at_offset(offset))
+            }
+            AttAttributeBackingValue::ClientConfiguration(characteristic_handle) => self
+                .write_client_configuration(characteristic_handle, data)
+                .map_err(|code| AttError::from(code).for_handle(handle)),
+            AttAttributeBackingValue::UserDescription => {
+                self.if_still_registered(handle, registration, Ok(()))?;
+                self.gatt_db
+                    .with(|gatt_db| {
+                        gatt_db
+                            .map(|gatt_db| {
+                                gatt_db.user_descriptions.borrow_mut().write(
+                                    handle,
+                                    offset as usize,
+                                    data,
+                                )
+                            })
+                            .unwrap_or(Err(AttErrorCode::INVALID_HANDLE))
+                    })
+                    .map_err(|code| AttError::from(code).for_handle(handle).at_offset(offset))
+            }
+        }
+    }
+
+    /// Consult the AuthorizationProvider, if the client still needs to be
+    /// authorized to access the attribute. A grant is cached for the rest of
+    /// the connection.
+    async fn authorize(
+        &self,
+        handle: AttHandle,
+        registration: u64,
+        authorization_provider: Option<Rc<dyn AuthorizationProvider>>,
+        access: AttributeAccess,
+    ) -> Result<(), AttError> {
+        let Some(authorization_provider) = authorization_provider else {
+            return Ok(());
+        };
+        if !authorization_provider.authorize(self.tcb_idx, handle, access).await {
+            warn!("client on {:?} was not authorized to access {handle:?}", self.tcb_idx);
+            return Err(AttError::new(
+                AttErrorCode::INSUFFICIENT_AUTHORIZATION,
+                ErrorLayer::Authorization,
+            )
+            .for_handle(handle));
+        }
+        self.if_still_registered(handle, registration, Ok(()))?;
+        self.gatt_db.with(|gatt_db| {
+            if let Some(gatt_db) = gatt_db {
+                gatt_db.authorization_grants.borrow_mut().grant(self.tcb_idx, handle, registration);
+            }
+        });
+        Ok(())
+    }
+
+    /// The service owning a dynamic attribute may be removed while its
+    /// datastore is handling an operation, in which case the operation fails
+    /// with INVALID_HANDLE rather than returning the result (whose errors are
+    /// attributed to the datastore)
+    fn if_still_registered<T>(
+        &self,
+        handle: AttHandle,
+        registration: u64,
+        result: Result<T, AttErrorCode>,
+    ) -> Result<T, AttError> {
+        let still_registered = self.gatt_db.with(|db| {
+            db.map(|db| db.schema.borrow().is_registered(handle, registration)).unwrap_or(false)
+        });
+        if !still_registered {
+            warn!("service owning {handle:?} was removed while it was being accessed");
+            return Err(AttError::from(AttErrorCode::INVALID_HANDLE).for_handle(handle));
+        }
+        result.map_err(|code| AttError::new(code, ErrorLayer::Datastore).for_handle(handle))
+    }
+
+    fn write_client_configuration(
+        &self,
+        characteristic_handle: AttHandle,
+        data: &[u8],
+    ) -> Result<(), AttErrorCode> {
+        // as per Core Spec 5.3 Vol 3G 3.3.3.3, the CCCD value is exactly two octets
+        if data.len() != 2 {
+            return Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH);
+        }
+        let ccc = GattClientCharacteristicConfigurationView::try_parse_from_buffer(data).map_err(
+            |err| {
+                warn!("failed to parse CCC descriptor, got: {err:?}");
+                AttErrorCode::UNLIKELY_ERROR
+            },
+        )?;
+        let mut configuration = ClientConfiguration::empty();
+        configuration.set(ClientConfiguration::NOTIFICATION, ccc.get_notification() != 0);
+        configuration.set(ClientConfiguration::INDICATION, ccc.get_indication() != 0);
+
+        self.gatt_db.with(|db| {
+            let Some(db) = db else {
+                // db must have been closed
+                return Err(AttErrorCode::INVALID_HANDLE);
+            };
+            let Some(permissions) = db
+                .schema
+                .borrow()
+                .attributes
+                .get(&characteristic_handle)
+                .map(|attr| attr.attribute.permissions)
+            else {
+                return Err(AttErrorCode::UNLIKELY_ERROR);
+            };
+            // the client may only subscribe to what the characteristic supports
+            if (configuration.contains(ClientConfiguration::NOTIFICATION) && !permissions.notify())
+                || (configuration.contains(ClientConfiguration::INDICATION)
+                    && !permissions.indicate())
+            {
+                return Err(
+                    AttErrorCode::CLIENT_CHARACTERISTIC_CONFIGURATION_DESCRIPTOR_IMPROPERLY_CONFIGURED,
+                );
+            }
+            if let Some(changes) =
+                db.client_configuration_bursts.borrow_mut().get_mut(&self.tcb_idx)
+            {
+                // saved, and told to the listeners, once the burst ends
+                db.client_configuration.borrow_mut().set_unsaved(
+                    self.tcb_idx,
+                    characteristic_handle,
+                    configuration,
+                );
+                changes.retain(|(handle, _)| *handle != characteristic_handle);
+                changes.push((characteristic_handle, configuration));
+                return Ok(());
+            }
+            db.client_configuration.borrow_mut().set(
+                self.tcb_idx,
+                characteristic_handle,
+                configuration,
+            );
+            for listener in db.listeners.borrow().iter() {
+                listener.on_client_configuration_change(
+                    self.tcb_idx,
+                    characteristic_handle,
+                    configuration,
+                );
+            }
+            Ok(())
+        })
+    }
+
+    /// When the bearer owning this AttDatabase is invalidated,
+    /// we must notify the listeners tied to our GattDatabase.
+    ///
+    /// Note: AttDatabases referring to the backing GattDatabase
+    /// may still exist after bearer invalidation, but the bearer will
+    /// no longer exist (so packets can no longer be sent/received).
+    pub fn on_bearer_dropped(&self) {
+        self.gatt_db.with(|db| {
+            db.map(|db| {
+                for listener in db.listeners.borrow().iter() {
                     listener.on_le_disconnect(self.tcb_idx)
                 }
             })
@@ -513,13 +2662,37 @@ impl AttDatabaseImpl {
 
 #[cfg(test)]
 mod test {
-    use tokio::{join, sync::mpsc::error::TryRecvError, task::spawn_local};
+    use std::cell::Cell;
+
+    use tokio::{
+        join,
+        sync::{
+            mpsc::{error::TryRecvError, UnboundedReceiver},
+            oneshot,
+        },
+        task::{spawn_local, yield_now},
+    };
 
     use crate::{
-        gatt::mocks::{
-            mock_database_callbacks::{MockCallbackEvents, MockCallbacks},
-            mock_datastore::{MockDatastore, MockDatastoreEvents},
-            mock_raw_datastore::{MockRawDatastore, MockRawDatastoreEvents},
+        gatt::{
+            callbacks::GattDatastore,
+            mocks::{
+                mock_database_callbacks::{MockCallbackEvents, MockCallbacks},
+                mock_datastore::{MockDatastore, MockDatastoreEvents},
+                mock_handle_assignment_storage::MockHandleAssignmentStorage,
+                mock_raw_datastore::{MockRawDatastore, MockRawDatastoreEvents},
+                mock_security_manager::MockSecurityManager,
+                mock_user_description_storage::MockUserDescriptionStorage,
+            },
+            mtu::MAX_ATT_MTU,
+            server::{
+                access_policy::BondedClientsOnly,
+                att_database::MAX_ATTRIBUTE_VALUE_LEN,
+                handle_assignments::{CompactAllocation, GapReservingAllocation},
+                interceptors::Interception,
+                security_elevation::SecurityElevation,
+                signature_verifier::SignatureVerifier,
+            },
         },
         packets::AttAttributeDataChild,
         utils::task::block_on_locally,
@@ -546,7 +2719,7 @@ mod test {
 
         let resp = tokio_test::block_on(att_db.read_attribute(AttHandle(1)));
 
-        assert_eq!(resp, Err(AttErrorCode::INVALID_HANDLE))
+        assert_eq!(resp, Err(AttErrorCode::INVALID_HANDLE.into()))
     }
 
     #[test]
@@ -582,7 +2755,8 @@ mod test {
                 GattServiceDeclarationValueBuilder { uuid: SERVICE_TYPE.into() }
             )
             .to_vec()
-            .map_err(|_| AttErrorCode::UNLIKELY_ERROR)
+            .map(Into::into)
+            .map_err(|_| AttError::from(AttErrorCode::UNLIKELY_ERROR))
         );
     }
 
@@ -667,6 +2841,57 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_attributes_in_range() {
+        // arrange two services, each with a single characteristic
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_datastore = Rc::new(gatt_datastore);
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        for handle in [1, 4] {
+            gatt_db
+                .add_service_with_handles(
+                    GattServiceWithHandle {
+                        handle: AttHandle(handle),
+                        type_: SERVICE_TYPE,
+                        characteristics: vec![GattCharacteristicWithHandle {
+                            handle: AttHandle(handle + 2),
+                            type_: CHARACTERISTIC_TYPE,
+                            permissions: AttPermissions::READABLE,
+                            descriptors: vec![],
+                        }],
+                    },
+                    gatt_datastore.clone(),
+                )
+                .unwrap();
+        }
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act: query a range straddling both services, by type and without
+        let attrs = att_db.attributes_in_range(AttHandle(2), AttHandle(5), None);
+        let declarations =
+            att_db.attributes_in_range(AttHandle(2), AttHandle(5), Some(CHARACTERISTIC_UUID));
+
+        // assert: only the attributes in range (of the right type) are returned
+        assert_eq!(
+            attrs.into_iter().map(|attr| attr.handle).collect::<Vec<_>>(),
+            vec![AttHandle(2), AttHandle(3), AttHandle(4), AttHandle(5)]
+        );
+        assert_eq!(
+            declarations.into_iter().map(|attr| attr.handle).collect::<Vec<_>>(),
+            vec![AttHandle(2), AttHandle(5)]
+        );
+    }
+
+    #[test]
+    fn test_attributes_in_empty_range() {
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        let attrs = att_db.attributes_in_range(AttHandle(5), AttHandle(4), None);
+
+        assert!(attrs.is_empty());
+    }
+
     #[test]
     fn test_single_characteristic_declaration() {
         let (gatt_datastore, _) = MockDatastore::new();
@@ -694,7 +2919,8 @@ mod test {
         let characteristic_decl =
             tokio_test::block_on(att_db.read_attribute(CHARACTERISTIC_DECLARATION_HANDLE));
 
-        assert_eq!(attrs.len(), 3, "{attrs:?}");
+        // the CCCD of the indicatable characteristic is also present
+        assert_eq!(attrs.len(), 4, "{attrs:?}");
         assert_eq!(attrs[0].type_, PRIMARY_SERVICE_DECLARATION_UUID);
         assert_eq!(
             attrs[1],
@@ -714,6 +2940,7 @@ mod test {
                     | AttPermissions::INDICATE
             }
         );
+        assert_eq!(attrs[3].type_, CLIENT_CHARACTERISTIC_CONFIGURATION_UUID);
 
         assert_eq!(
             characteristic_decl,
@@ -734,7 +2961,8 @@ mod test {
                 }
             )
             .to_vec()
-            .map_err(|_| AttErrorCode::UNLIKELY_ERROR)
+            .map(Into::into)
+            .map_err(|_| AttError::from(AttErrorCode::UNLIKELY_ERROR))
         );
     }
 
@@ -774,7 +3002,7 @@ mod test {
                         broadcast: 0,
                         write_without_response: 1,
                         write: 1,
-                        notify: 0,
+                        notify: 1,
                         indicate: 1,
                         authenticated_signed_writes: 0,
                         extended_properties: 0,
@@ -784,7 +3012,8 @@ mod test {
                 }
             )
             .to_vec()
-            .map_err(|_| AttErrorCode::UNLIKELY_ERROR)
+            .map(Into::into)
+            .map_err(|_| AttError::from(AttErrorCode::UNLIKELY_ERROR))
         );
     }
 
@@ -832,7 +3061,7 @@ mod test {
         });
 
         // assert: the supplied value matches what the att datastore returned
-        assert_eq!(characteristic_value, Ok(data.to_vec()));
+        assert_eq!(characteristic_value, Ok(data.to_vec().into()));
     }
 
     #[test]
@@ -859,7 +3088,7 @@ mod test {
             gatt_db.get_att_database(TCB_IDX).read_attribute(CHARACTERISTIC_VALUE_HANDLE),
         );
 
-        assert_eq!(characteristic_value, Err(AttErrorCode::READ_NOT_PERMITTED));
+        assert_eq!(characteristic_value, Err(AttErrorCode::READ_NOT_PERMITTED.into()));
     }
 
     #[test]
@@ -940,7 +3169,7 @@ mod test {
         let recv_data = block_on_locally(async {
             // start write task
             spawn_local(async move {
-                att_db.write_attribute(CHARACTERISTIC_VALUE_HANDLE, &data).await.unwrap();
+                att_db.write_attribute(CHARACTERISTIC_VALUE_HANDLE, 0, &data).await.unwrap();
             });
 
             let MockDatastoreEvents::Write(
@@ -994,13 +3223,66 @@ mod test {
                     };
                     reply.send(Err(AttErrorCode::UNLIKELY_ERROR)).unwrap();
                 },
-                att_db.write_attribute(CHARACTERISTIC_VALUE_HANDLE, &data)
+                att_db.write_attribute(CHARACTERISTIC_VALUE_HANDLE, 0, &data)
             )
             .1
         });
 
         // assert: the supplied value matches what the att datastore returned
-        assert_eq!(res, Err(AttErrorCode::UNLIKELY_ERROR));
+        assert_eq!(res, Err(AttErrorCode::UNLIKELY_ERROR.into()));
+    }
+
+    #[test]
+    fn test_concurrent_writes_to_same_characteristic_serialized() {
+        block_on_locally(async {
+            // arrange: a database with a single characteristic
+            let (gatt_datastore, mut data_evts) = MockDatastore::new();
+            let gatt_db = SharedBox::new(GattDatabase::new());
+            gatt_db
+                .add_service_with_handles(
+                    GattServiceWithHandle {
+                        handle: SERVICE_HANDLE,
+                        type_: SERVICE_TYPE,
+                        characteristics: vec![GattCharacteristicWithHandle {
+                            handle: CHARACTERISTIC_VALUE_HANDLE,
+                            type_: CHARACTERISTIC_TYPE,
+                            permissions: AttPermissions::WRITABLE_WITH_RESPONSE,
+                            descriptors: vec![],
+                        }],
+                    },
+                    Rc::new(gatt_datastore),
+                )
+                .unwrap();
+
+            // act: write the characteristic from two bearers of the same client
+            let writes = [[1], [2]].map(|data| {
+                let att_db = gatt_db.get_att_database(TCB_IDX);
+                spawn_local(async move {
+                    att_db.write_attribute(CHARACTERISTIC_VALUE_HANDLE, 0, &data).await
+                })
+            });
+            let Some(MockDatastoreEvents::Write(_, _, _, first, first_reply)) =
+                data_evts.recv().await
+            else {
+                unreachable!();
+            };
+            yield_now().await;
+
+            // assert: the second write is only delivered once the first completes
+            assert_eq!(first, vec![1]);
+            assert_eq!(data_evts.try_recv().unwrap_err(), TryRecvError::Empty);
+            first_reply.send(Ok(())).unwrap();
+            let Some(MockDatastoreEvents::Write(_, _, _, second, second_reply)) =
+                data_evts.recv().await
+            else {
+                unreachable!();
+            };
+            assert_eq!(second, vec![2]);
+            second_reply.send(Ok(())).unwrap();
+            for write in writes {
+                assert_eq!(write.await.unwrap(), Ok(()));
+            }
+        });
     }
 
     #[test]
@@ -1024,11 +3306,14 @@ mod test {
             .unwrap();
         let data = [1, 2];
 
-        let characteristic_value = tokio_test::block_on(
-            gatt_db.get_att_database(TCB_IDX).write_attribute(CHARACTERISTIC_VALUE_HANDLE, &data),
-        );
+        let characteristic_value =
+            tokio_test::block_on(gatt_db.get_att_database(TCB_IDX).write_attribute(
+                CHARACTERISTIC_VALUE_HANDLE,
+                0,
+                &data,
+            ));
 
-        assert_eq!(characteristic_value, Err(AttErrorCode::WRITE_NOT_PERMITTED));
+        assert_eq!(characteristic_value, Err(AttErrorCode::WRITE_NOT_PERMITTED.into()));
     }
 
     #[test]
@@ -1077,7 +3362,7 @@ mod test {
             pending_read.await.unwrap()
         });
 
-        assert_eq!(descriptor_value, data);
+        assert_eq!(*descriptor_value, data);
     }
 
     #[test]
@@ -1110,9 +3395,9 @@ mod test {
         // act: write, and wait for the callback to be invoked
         block_on_locally(async {
             // start write task
-            spawn_local(
-                async move { att_db.write_attribute(DESCRIPTOR_HANDLE, &data).await.unwrap() },
-            );
+            spawn_local(async move {
+                att_db.write_attribute(DESCRIPTOR_HANDLE, 0, &data).await.unwrap()
+            });
 
             let MockDatastoreEvents::Write(
                 TCB_IDX,
@@ -1263,7 +3548,7 @@ mod test {
         });
 
         // assert: the supplied value matches what the att datastore returned
-        assert_eq!(characteristic_value, Ok(data.to_vec()));
+        assert_eq!(characteristic_value, Ok(data.to_vec().into()));
         // the first datastore received no events
         assert_eq!(data_evts_1.try_recv().unwrap_err(), TryRecvError::Empty);
         // the second datastore has no remaining events
@@ -1273,9 +3558,15 @@ mod test {
     fn make_bearer(
         gatt_db: &SharedBox<GattDatabase>,
     ) -> SharedBox<AttServerBearer<AttDatabaseImpl>> {
-        SharedBox::new(AttServerBearer::new(gatt_db.get_att_database(TCB_IDX), |_| {
-            unreachable!();
-        }))
+        SharedBox::new(AttServerBearer::new(
+            gatt_db.get_att_database(TCB_IDX),
+            SignatureVerifier::new(TCB_IDX, Rc::new(MockSecurityManager::new())),
+            SecurityElevation::new(TCB_IDX, Rc::new(MockSecurityManager::new())),
+            MAX_ATT_MTU,
+            |_| {
+                unreachable!();
+            },
+        ))
     }
 
     #[test]
@@ -1309,6 +3600,92 @@ mod test {
         assert!(matches!(event, MockCallbackEvents::OnLeDisconnect(TCB_IDX)));
     }
 
+    /// Records the peers that disconnected
+    #[derive(Default)]
+    struct DisconnectionRecorder {
+        disconnected: RefCell<Vec<TransportIndex>>,
+    }
+
+    #[async_trait(?Send)]
+    impl GattDatastore for DisconnectionRecorder {
+        async fn read(
+            &self,
+            _: TransportIndex,
+            _: AttHandle,
+            _: AttributeBackingType,
+        ) -> Result<Vec<u8>, AttErrorCode> {
+            unreachable!()
+        }
+
+        async fn write(
+            &self,
+            _: TransportIndex,
+            _: AttHandle,
+            _: AttributeBackingType,
+            _: &[u8],
+        ) -> Result<(), AttErrorCode> {
+            unreachable!()
+        }
+
+        fn on_peer_disconnected(&self, tcb_idx: TransportIndex) {
+            self.disconnected.borrow_mut().push(tcb_idx);
+        }
+    }
+
+    #[test]
+    fn test_services_told_of_disconnection() {
+        // arrange: two services, one of them with several dynamic attributes
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        let datastore = Rc::new(DisconnectionRecorder::default());
+        let another_datastore = Rc::new(DisconnectionRecorder::default());
+        gatt_db
+            .add_service(
+                ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                    CharacteristicBuilder::new(CHARACTERISTIC_TYPE, AttPermissions::READABLE)
+                        .descriptor(DescriptorBuilder::new(
+                            DESCRIPTOR_TYPE,
+                            AttPermissions::READABLE,
+                        )),
+                ),
+                datastore.clone(),
+            )
+            .unwrap();
+        gatt_db
+            .add_service(
+                ServiceBuilder::new(SERVICE_TYPE).characteristic(CharacteristicBuilder::new(
+                    CHARACTERISTIC_TYPE,
+                    AttPermissions::READABLE,
+                )),
+                another_datastore.clone(),
+            )
+            .unwrap();
+        let bearer = make_bearer(&gatt_db);
+        gatt_db.on_bearer_ready(TCB_IDX, bearer.as_ref());
+
+        // act
+        gatt_db.on_bearer_dropped(TCB_IDX);
+
+        // assert: each service was told once, and nothing was kept for the client
+        assert_eq!(*datastore.disconnected.borrow(), vec![TCB_IDX]);
+        assert_eq!(*another_datastore.disconnected.borrow(), vec![TCB_IDX]);
+        assert!(!gatt_db.has_connection_state(TCB_IDX));
+    }
+
+    #[test]
+    fn test_bonded_listener() {
+        // arrange: db with a listener
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        let (callbacks, mut rx) = MockCallbacks::new();
+        gatt_db.register_listener(Rc::new(callbacks));
+
+        // act: identify the peer as bonded
+        gatt_db.on_le_bonded(TCB_IDX, AddressWithType::EMPTY);
+
+        // assert: we got the callback
+        let event = rx.blocking_recv().unwrap();
+        assert!(matches!(event, MockCallbackEvents::OnLeBonded(TCB_IDX, AddressWithType::EMPTY)));
+    }
+
     #[test]
     fn test_multiple_listeners() {
         // arrange: db with two listeners
@@ -1519,9 +3896,9 @@ mod test {
     }
 
     #[test]
-    fn test_unwriteable_without_response_characteristic() {
-        // arrange: db with a characteristic that is writable, but not writable-without-response
-        let (gatt_datastore, mut data_events) = MockRawDatastore::new();
+    fn test_value_version_changes_with_value() {
+        // arrange
+        let (gatt_datastore, _data_evts) = MockRawDatastore::new();
         let gatt_db = SharedBox::new(GattDatabase::new());
         gatt_db
             .add_service_with_handles(
@@ -1532,7 +3909,7 @@ mod test {
                         handle: CHARACTERISTIC_VALUE_HANDLE,
                         type_: CHARACTERISTIC_TYPE,
                         permissions: AttPermissions::READABLE
-                            | AttPermissions::WRITABLE_WITH_RESPONSE,
+                            | AttPermissions::WRITABLE_WITHOUT_RESPONSE,
                         descriptors: vec![],
                     }],
                 },
@@ -1540,7 +3917,43 @@ mod test {
             )
             .unwrap();
         let att_db = gatt_db.get_att_database(TCB_IDX);
-        let data = [1, 2];
+        let initial = att_db.value_version(CHARACTERISTIC_VALUE_HANDLE);
+
+        // act: the client writes the value, then the upper layer changes it
+        att_db.write_no_response_attribute(CHARACTERISTIC_VALUE_HANDLE, &[1, 2]);
+        let written = att_db.value_version(CHARACTERISTIC_VALUE_HANDLE);
+        gatt_db.on_value_changed(CHARACTERISTIC_VALUE_HANDLE);
+        let changed = att_db.value_version(CHARACTERISTIC_VALUE_HANDLE);
+
+        // assert
+        assert!(initial.is_some());
+        assert_ne!(written, initial);
+        assert_ne!(changed, written);
+    }
+
+    #[test]
+    fn test_unwriteable_without_response_characteristic() {
+        // arrange: db with a characteristic that is writable, but not writable-without-response
+        let (gatt_datastore, mut data_events) = MockRawDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        gatt_db
+            .add_service_with_handles(
+                GattServiceWithHandle {
+                    handle: SERVICE_HANDLE,
+                    type_: SERVICE_TYPE,
+                    characteristics: vec![GattCharacteristicWithHandle {
+                        handle: CHARACTERISTIC_VALUE_HANDLE,
+                        type_: CHARACTERISTIC_TYPE,
+                        permissions: AttPermissions::READABLE
+                            | AttPermissions::WRITABLE_WITH_RESPONSE,
+                        descriptors: vec![],
+                    }],
+                },
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+        let data = [1, 2];
 
         // act: try writing without response to this characteristic
         att_db.write_no_response_attribute(CHARACTERISTIC_VALUE_HANDLE, &data);
@@ -1548,4 +3961,2846 @@ mod test {
         // assert: no callback was sent
         assert_eq!(data_events.try_recv().unwrap_err(), TryRecvError::Empty);
     }
+
+    fn make_db_with_raw_writable_characteristic(
+    ) -> (SharedBox<GattDatabase>, UnboundedReceiver<MockRawDatastoreEvents>) {
+        let (gatt_datastore, data_evts) = MockRawDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        gatt_db
+            .add_service_with_handles(
+                GattServiceWithHandle {
+                    handle: SERVICE_HANDLE,
+                    type_: SERVICE_TYPE,
+                    characteristics: vec![GattCharacteristicWithHandle {
+                        handle: CHARACTERISTIC_VALUE_HANDLE,
+                        type_: CHARACTERISTIC_TYPE,
+                        permissions: AttPermissions::WRITABLE_WITH_RESPONSE,
+                        descriptors: vec![],
+                    }],
+                },
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+        (gatt_db, data_evts)
+    }
+
+    #[test]
+    fn test_write_at_offset_forwarded() {
+        // arrange
+        let (gatt_db, mut data_evts) = make_db_with_raw_writable_characteristic();
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+        let data = [1, 2];
+
+        // act: write to the characteristic at a non-zero offset
+        let res = block_on_locally(async {
+            let pending_write = spawn_local(async move {
+                att_db.write_attribute(CHARACTERISTIC_VALUE_HANDLE, 3, &data).await
+            });
+            let MockRawDatastoreEvents::Write(
+                TCB_IDX,
+                CHARACTERISTIC_VALUE_HANDLE,
+                AttributeBackingType::Characteristic,
+                GattWriteRequestType::Request { offset: 3 },
+                recv_data,
+                reply,
+            ) = data_evts.recv().await.unwrap()
+            else {
+                unreachable!();
+            };
+            assert_eq!(recv_data, data);
+            reply.send(Ok(())).unwrap();
+            pending_write.await.unwrap()
+        });
+
+        // assert: the offset was passed to the upper layer
+        assert_eq!(res, Ok(()));
+    }
+
+    #[test]
+    fn test_write_insufficient_authorization() {
+        // arrange
+        let (gatt_db, mut data_evts) = make_db_with_raw_writable_characteristic();
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act: the upper layer rejects the write
+        let res = tokio_test::block_on(async {
+            join!(
+                async {
+                    let MockRawDatastoreEvents::Write(_, _, _, _, _, reply) =
+                        data_evts.recv().await.unwrap()
+                    else {
+                        unreachable!();
+                    };
+                    reply.send(Err(AttErrorCode::INSUFFICIENT_AUTHORIZATION)).unwrap();
+                },
+                att_db.write_attribute(CHARACTERISTIC_VALUE_HANDLE, 0, &[1, 2])
+            )
+            .1
+        });
+
+        // assert: its error code was returned as-is
+        assert_eq!(res, Err(AttErrorCode::INSUFFICIENT_AUTHORIZATION.into()));
+    }
+
+    #[test]
+    fn test_write_exceeding_max_length() {
+        // arrange
+        let (gatt_db, mut data_evts) = make_db_with_raw_writable_characteristic();
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act: write a value that would end past the maximum attribute length
+        let res = tokio_test::block_on(att_db.write_attribute(
+            CHARACTERISTIC_VALUE_HANDLE,
+            MAX_ATTRIBUTE_VALUE_LEN as u32 - 1,
+            &[1, 2],
+        ));
+
+        // assert: it was rejected without reaching the upper layer
+        assert_eq!(res, Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH.into()));
+        assert_eq!(data_evts.try_recv().unwrap_err(), TryRecvError::Empty);
+    }
+
+    #[test]
+    fn test_check_write_does_not_reach_upper_layer() {
+        // arrange
+        let (gatt_db, mut data_evts) = make_db_with_raw_writable_characteristic();
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act
+        let valid = att_db.check_write(CHARACTERISTIC_VALUE_HANDLE, 0, &[1, 2]);
+        let too_long = att_db.check_write(
+            CHARACTERISTIC_VALUE_HANDLE,
+            MAX_ATTRIBUTE_VALUE_LEN as u32 - 1,
+            &[1, 2],
+        );
+
+        // assert: the checks ran, without writing anything
+        assert_eq!(valid, Ok(()));
+        assert_eq!(too_long, Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH.into()));
+        assert_eq!(data_evts.try_recv().unwrap_err(), TryRecvError::Empty);
+    }
+
+    #[test]
+    fn test_check_write_cccd_offset() {
+        let gatt_db = make_db_with_notify_characteristic();
+        let att_db = connect(&gatt_db);
+
+        assert_eq!(att_db.check_write(CCCD_HANDLE, 0, &[1, 0]), Ok(()));
+        assert_eq!(
+            att_db.check_write(CCCD_HANDLE, 1, &[0]),
+            Err(AttErrorCode::ATTRIBUTE_NOT_LONG.into())
+        );
+        assert_eq!(
+            att_db.client_configuration(CHARACTERISTIC_VALUE_HANDLE),
+            Some(ClientConfiguration::empty())
+        );
+    }
+
+    #[test]
+    fn test_write_exceeding_configured_max_length() {
+        // arrange: a database with a tighter limit than the spec
+        let (gatt_datastore, mut data_evts) = MockRawDatastore::new();
+        let gatt_db = SharedBox::new(
+            GattDatabase::new()
+                .with_config(GattServerConfig { max_attribute_length: 4, ..Default::default() }),
+        );
+        gatt_db
+            .add_service_with_handles(
+                GattServiceWithHandle {
+                    handle: SERVICE_HANDLE,
+                    type_: SERVICE_TYPE,
+                    characteristics: vec![GattCharacteristicWithHandle {
+                        handle: CHARACTERISTIC_VALUE_HANDLE,
+                        type_: CHARACTERISTIC_TYPE,
+                        permissions: AttPermissions::WRITABLE_WITH_RESPONSE
+                            | AttPermissions::WRITABLE_WITHOUT_RESPONSE,
+                        descriptors: vec![],
+                    }],
+                },
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act: write values one byte too long, with and without response
+        let res = tokio_test::block_on(att_db.write_attribute(
+            CHARACTERISTIC_VALUE_HANDLE,
+            1,
+            &[1, 2, 3, 4],
+        ));
+        att_db.write_no_response_attribute(CHARACTERISTIC_VALUE_HANDLE, &[1, 2, 3, 4, 5]);
+
+        // assert: both were rejected without reaching the upper layer
+        assert_eq!(res, Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH.into()));
+        assert_eq!(data_evts.try_recv().unwrap_err(), TryRecvError::Empty);
+        assert_eq!(att_db.server_config().max_attribute_length, 4);
+    }
+
+    #[test]
+    fn test_static_value_exceeding_configured_max_length() {
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(
+            GattDatabase::new()
+                .with_config(GattServerConfig { max_attribute_length: 4, ..Default::default() }),
+        );
+
+        let res = gatt_db.add_service(
+            ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                CharacteristicBuilder::new(CHARACTERISTIC_TYPE, AttPermissions::READABLE)
+                    .static_value(vec![1; 5]),
+            ),
+            Rc::new(gatt_datastore),
+        );
+
+        assert!(res.is_err());
+    }
+
+    fn make_db_with_secure_characteristic(
+        permissions: AttPermissions,
+        security_manager: Rc<MockSecurityManager>,
+    ) -> (SharedBox<GattDatabase>, UnboundedReceiver<MockRawDatastoreEvents>) {
+        let (gatt_datastore, data_evts) = MockRawDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new_with_security_manager(security_manager));
+        gatt_db
+            .add_service_with_handles(
+                GattServiceWithHandle {
+                    handle: SERVICE_HANDLE,
+                    type_: SERVICE_TYPE,
+                    characteristics: vec![GattCharacteristicWithHandle {
+                        handle: CHARACTERISTIC_VALUE_HANDLE,
+                        type_: CHARACTERISTIC_TYPE,
+                        permissions,
+                        descriptors: vec![],
+                    }],
+                },
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+        (gatt_db, data_evts)
+    }
+
+    #[test]
+    fn test_read_requires_encryption() {
+        // arrange: a characteristic requiring encryption, on an unencrypted link
+        let (gatt_db, mut data_evts) = make_db_with_secure_characteristic(
+            AttPermissions::READABLE | AttPermissions::ENCRYPTION_REQUIRED,
+            Rc::new(MockSecurityManager::new()),
+        );
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act
+        let res = tokio_test::block_on(att_db.read_attribute(CHARACTERISTIC_VALUE_HANDLE));
+
+        // assert: it was rejected without reaching the upper layer
+        assert_eq!(res, Err(AttErrorCode::INSUFFICIENT_ENCRYPTION.into()));
+        assert_eq!(data_evts.try_recv().unwrap_err(), TryRecvError::Empty);
+    }
+
+    #[test]
+    fn test_security_error_context() {
+        // arrange: a characteristic requiring encryption, on an unencrypted link
+        let (gatt_db, _data_evts) = make_db_with_secure_characteristic(
+            AttPermissions::READABLE | AttPermissions::ENCRYPTION_REQUIRED,
+            Rc::new(MockSecurityManager::new()),
+        );
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act
+        let error =
+            tokio_test::block_on(att_db.read_attribute(CHARACTERISTIC_VALUE_HANDLE)).unwrap_err();
+
+        // assert: the error says which requirement was not met
+        assert_eq!(error.layer, ErrorLayer::Security);
+        assert_eq!(error.handle, Some(CHARACTERISTIC_VALUE_HANDLE));
+        assert_eq!(
+            error.unmet_requirement,
+            Some(UnmetRequirement {
+                required: AttPermissions::ENCRYPTION_REQUIRED,
+                security_level: SecurityLevel::NoSecurity,
+            })
+        );
+    }
+
+    #[test]
+    fn test_read_on_encrypted_link() {
+        // arrange: a characteristic requiring encryption, on an encrypted link
+        let security_manager = Rc::new(MockSecurityManager::new());
+        let (gatt_db, mut data_evts) = make_db_with_secure_characteristic(
+            AttPermissions::READABLE | AttPermissions::ENCRYPTION_REQUIRED,
+            security_manager.clone(),
+        );
+        security_manager.set_security_level(TCB_IDX, SecurityLevel::Encrypted);
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act
+        let res = block_on_locally(async {
+            let pending_read =
+                spawn_local(
+                    async move { att_db.read_attribute(CHARACTERISTIC_VALUE_HANDLE).await },
+                );
+            let MockRawDatastoreEvents::Read(_, _, _, _, reply) = data_evts.recv().await.unwrap()
+            else {
+                unreachable!();
+            };
+            reply.send(Ok(vec![1, 2])).unwrap();
+            pending_read.await.unwrap()
+        });
+
+        // assert: the read was served
+        assert_eq!(res, Ok(vec![1, 2].into()));
+    }
+
+    fn make_db_requiring_key_size(
+        security_manager: Rc<MockSecurityManager>,
+    ) -> (SharedBox<GattDatabase>, UnboundedReceiver<MockDatastoreEvents>) {
+        let (gatt_datastore, data_evts) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new_with_security_manager(security_manager));
+        gatt_db
+            .add_service(
+                ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                    CharacteristicBuilder::new(
+                        CHARACTERISTIC_TYPE,
+                        AttPermissions::READABLE | AttPermissions::ENCRYPTION_REQUIRED,
+                    )
+                    .min_encryption_key_size(16),
+                ),
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+        (gatt_db, data_evts)
+    }
+
+    #[test]
+    fn test_read_requires_encryption_key_size() {
+        // arrange: a characteristic requiring a 16-octet key, on a link
+        // encrypted with a shorter one
+        let security_manager = Rc::new(MockSecurityManager::new());
+        let (gatt_db, mut data_evts) = make_db_requiring_key_size(security_manager.clone());
+        security_manager.set_security_level(TCB_IDX, SecurityLevel::Encrypted);
+        security_manager.set_encryption_key_size(TCB_IDX, 7);
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act
+        let res = tokio_test::block_on(att_db.read_attribute(AttHandle(3)));
+
+        // assert: it was rejected without reaching the upper layer
+        assert_eq!(res, Err(AttErrorCode::INSUFFICIENT_ENCRYPTION_KEY_SIZE.into()));
+        assert_eq!(data_evts.try_recv().unwrap_err(), TryRecvError::Empty);
+    }
+
+    #[test]
+    fn test_encryption_checked_before_key_size() {
+        // arrange: a characteristic requiring a 16-octet key, on an
+        // unencrypted link
+        let (gatt_db, _data_evts) = make_db_requiring_key_size(Rc::new(MockSecurityManager::new()));
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act
+        let res = tokio_test::block_on(att_db.read_attribute(AttHandle(3)));
+
+        // assert: the client is told to encrypt the link first
+        assert_eq!(res, Err(AttErrorCode::INSUFFICIENT_ENCRYPTION.into()));
+    }
+
+    #[test]
+    fn test_read_with_sufficient_encryption_key_size() {
+        // arrange: a characteristic requiring a 16-octet key, on a link
+        // encrypted with one
+        let security_manager = Rc::new(MockSecurityManager::new());
+        let (gatt_db, mut data_evts) = make_db_requiring_key_size(security_manager.clone());
+        security_manager.set_security_level(TCB_IDX, SecurityLevel::Encrypted);
+        security_manager.set_encryption_key_size(TCB_IDX, 16);
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act
+        let res = block_on_locally(async {
+            let pending_read =
+                spawn_local(async move { att_db.read_attribute(AttHandle(3)).await });
+            let MockDatastoreEvents::Read(_, _, _, reply) = data_evts.recv().await.unwrap() else {
+                unreachable!();
+            };
+            reply.send(Ok(vec![1, 2])).unwrap();
+            pending_read.await.unwrap()
+        });
+
+        // assert: the read was served
+        assert_eq!(res, Ok(vec![1, 2].into()));
+    }
+
+    #[test]
+    fn test_read_at_offset_forwarded() {
+        // arrange
+        let (gatt_db, mut data_evts) = make_db_with_secure_characteristic(
+            AttPermissions::READABLE,
+            Rc::new(MockSecurityManager::new()),
+        );
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act: read the characteristic from a non-zero offset
+        let (offset, res) = block_on_locally(async {
+            let pending_read = spawn_local(async move {
+                att_db.read_attribute_at(CHARACTERISTIC_VALUE_HANDLE, 2).await
+            });
+            let MockRawDatastoreEvents::Read(_, _, _, offset, reply) =
+                data_evts.recv().await.unwrap()
+            else {
+                unreachable!();
+            };
+            reply.send(Ok(vec![3, 4])).unwrap();
+            (offset, pending_read.await.unwrap())
+        });
+
+        // assert: the upper layer was asked for the part after the offset, and
+        // its reply was returned as-is
+        assert_eq!(offset, 2);
+        assert_eq!(res, Ok(vec![3, 4].into()));
+    }
+
+    #[test]
+    fn test_read_static_value_at_offset() {
+        // arrange
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        gatt_db
+            .add_service(
+                ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                    CharacteristicBuilder::new(CHARACTERISTIC_TYPE, AttPermissions::READABLE)
+                        .static_value(vec![1, 2, 3]),
+                ),
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act
+        let tail = tokio_test::block_on(att_db.read_attribute_at(AttHandle(3), 1));
+        let past_end = tokio_test::block_on(att_db.read_attribute_at(AttHandle(3), 4));
+
+        // assert
+        assert_eq!(tail, Ok(vec![2, 3].into()));
+        assert_eq!(past_end, Err(AttErrorCode::INVALID_OFFSET.into()));
+    }
+
+    #[test]
+    fn test_write_requires_authentication() {
+        // arrange: a characteristic requiring authentication, on a link
+        // encrypted with an unauthenticated key
+        let security_manager = Rc::new(MockSecurityManager::new());
+        let (gatt_db, mut data_evts) = make_db_with_secure_characteristic(
+            AttPermissions::WRITABLE_WITH_RESPONSE | AttPermissions::AUTHENTICATION_REQUIRED,
+            security_manager.clone(),
+        );
+        security_manager.set_security_level(TCB_IDX, SecurityLevel::Encrypted);
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act
+        let res =
+            tokio_test::block_on(att_db.write_attribute(CHARACTERISTIC_VALUE_HANDLE, 0, &[1]));
+
+        // assert
+        assert_eq!(res, Err(AttErrorCode::INSUFFICIENT_AUTHENTICATION.into()));
+        assert_eq!(data_evts.try_recv().unwrap_err(), TryRecvError::Empty);
+    }
+
+    #[test]
+    fn test_write_requires_authorization() {
+        let security_manager = Rc::new(MockSecurityManager::new());
+        let (gatt_db, _) = make_db_with_secure_characteristic(
+            AttPermissions::WRITABLE_WITH_RESPONSE | AttPermissions::AUTHORIZATION_REQUIRED,
+            security_manager.clone(),
+        );
+        security_manager.set_security_level(TCB_IDX, SecurityLevel::Authenticated);
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        let res =
+            tokio_test::block_on(att_db.write_attribute(CHARACTERISTIC_VALUE_HANDLE, 0, &[1]));
+
+        assert_eq!(res, Err(AttErrorCode::INSUFFICIENT_AUTHORIZATION.into()));
+    }
+
+    /// Answers each authorization request with the next queued decision,
+    /// recording the requests
+    #[derive(Default)]
+    struct TestAuthorizationProvider {
+        decisions: RefCell<Vec<oneshot::Receiver<bool>>>,
+        requests: RefCell<Vec<(TransportIndex, AttHandle, AttributeAccess)>>,
+    }
+
+    impl TestAuthorizationProvider {
+        fn answering(granted: bool) -> Rc<Self> {
+            let this = Rc::new(Self::default());
+            this.answer_next(granted);
+            this
+        }
+
+        fn answer_next(&self, granted: bool) {
+            let (tx, rx) = oneshot::channel();
+            tx.send(granted).unwrap();
+            self.decisions.borrow_mut().push(rx);
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl AuthorizationProvider for TestAuthorizationProvider {
+        async fn authorize(
+            &self,
+            tcb_idx: TransportIndex,
+            handle: AttHandle,
+            access: AttributeAccess,
+        ) -> bool {
+            self.requests.borrow_mut().push((tcb_idx, handle, access));
+            let decision = self.decisions.borrow_mut().remove(0);
+            decision.await.unwrap()
+        }
+    }
+
+    #[test]
+    fn test_read_authorized_by_provider() {
+        // arrange: a characteristic requiring authorization, which the provider grants
+        let (gatt_db, mut data_evts) = make_db_with_secure_characteristic(
+            AttPermissions::READABLE | AttPermissions::AUTHORIZATION_REQUIRED,
+            Rc::new(MockSecurityManager::new()),
+        );
+        let provider = TestAuthorizationProvider::answering(true);
+        gatt_db.set_authorization_provider(provider.clone());
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act
+        let res = block_on_locally(async {
+            let pending_read =
+                spawn_local(
+                    async move { att_db.read_attribute(CHARACTERISTIC_VALUE_HANDLE).await },
+                );
+            let MockRawDatastoreEvents::Read(_, _, _, _, reply) = data_evts.recv().await.unwrap()
+            else {
+                unreachable!();
+            };
+            reply.send(Ok(vec![1, 2])).unwrap();
+            pending_read.await.unwrap()
+        });
+
+        // assert: the provider was consulted, and the read was served
+        assert_eq!(
+            *provider.requests.borrow(),
+            vec![(TCB_IDX, CHARACTERISTIC_VALUE_HANDLE, AttributeAccess::Read)]
+        );
+        assert_eq!(res, Ok(vec![1, 2].into()));
+    }
+
+    #[test]
+    fn test_write_denied_by_provider() {
+        // arrange: a characteristic requiring authorization, which the provider denies
+        let (gatt_db, mut data_evts) = make_db_with_secure_characteristic(
+            AttPermissions::WRITABLE_WITH_RESPONSE | AttPermissions::AUTHORIZATION_REQUIRED,
+            Rc::new(MockSecurityManager::new()),
+        );
+        let provider = TestAuthorizationProvider::answering(false);
+        gatt_db.set_authorization_provider(provider.clone());
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act
+        let res = block_on_locally(att_db.write_attribute(CHARACTERISTIC_VALUE_HANDLE, 0, &[1]));
+
+        // assert: it was rejected without reaching the upper layer
+        assert_eq!(
+            *provider.requests.borrow(),
+            vec![(TCB_IDX, CHARACTERISTIC_VALUE_HANDLE, AttributeAccess::Write)]
+        );
+        assert_eq!(res, Err(AttErrorCode::INSUFFICIENT_AUTHORIZATION.into()));
+        assert_eq!(data_evts.try_recv().unwrap_err(), TryRecvError::Empty);
+    }
+
+    #[test]
+    fn test_provider_not_consulted_before_link_security() {
+        // arrange: a characteristic requiring both encryption and authorization,
+        // on an unencrypted link
+        let (gatt_db, _) = make_db_with_secure_characteristic(
+            AttPermissions::READABLE
+                | AttPermissions::ENCRYPTION_REQUIRED
+                | AttPermissions::AUTHORIZATION_REQUIRED,
+            Rc::new(MockSecurityManager::new()),
+        );
+        let provider = TestAuthorizationProvider::answering(true);
+        gatt_db.set_authorization_provider(provider.clone());
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act
+        let res = block_on_locally(att_db.read_attribute(CHARACTERISTIC_VALUE_HANDLE));
+
+        // assert: the link must be secured first
+        assert_eq!(res, Err(AttErrorCode::INSUFFICIENT_ENCRYPTION.into()));
+        assert!(provider.requests.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_read_waits_for_authorization() {
+        block_on_locally(async {
+            // arrange: a provider that has not yet decided (e.g. waiting for the user)
+            let (gatt_db, mut data_evts) = make_db_with_secure_characteristic(
+                AttPermissions::READABLE | AttPermissions::AUTHORIZATION_REQUIRED,
+                Rc::new(MockSecurityManager::new()),
+            );
+            let provider = Rc::new(TestAuthorizationProvider::default());
+            let (decision, rx) = oneshot::channel();
+            provider.decisions.borrow_mut().push(rx);
+            gatt_db.set_authorization_provider(provider.clone());
+            let att_db = gatt_db.get_att_database(TCB_IDX);
+            let pending_read =
+                spawn_local(
+                    async move { att_db.read_attribute(CHARACTERISTIC_VALUE_HANDLE).await },
+                );
+            yield_now().await;
+            assert_eq!(data_evts.try_recv().unwrap_err(), TryRecvError::Empty);
+
+            // act: the user denies access
+            decision.send(false).unwrap();
+
+            // assert: the read was rejected
+            assert_eq!(
+                pending_read.await.unwrap(),
+                Err(AttErrorCode::INSUFFICIENT_AUTHORIZATION.into())
+            );
+        });
+    }
+
+    #[test]
+    fn test_authorization_grant_cached() {
+        block_on_locally(async {
+            // arrange: a characteristic requiring authorization, writable both
+            // with and without response
+            let (gatt_db, mut data_evts) = make_db_with_secure_characteristic(
+                AttPermissions::WRITABLE_WITH_RESPONSE
+                    | AttPermissions::WRITABLE_WITHOUT_RESPONSE
+                    | AttPermissions::AUTHORIZATION_REQUIRED,
+                Rc::new(MockSecurityManager::new()),
+            );
+            let provider = TestAuthorizationProvider::answering(true);
+            gatt_db.set_authorization_provider(provider.clone());
+            let att_db = gatt_db.get_att_database(TCB_IDX);
+
+            // act: write without response before and after being authorized by a write
+            att_db.write_no_response_attribute(CHARACTERISTIC_VALUE_HANDLE, &[1]);
+            let pending_write = spawn_local({
+                let att_db = att_db.clone();
+                async move { att_db.write_attribute(CHARACTERISTIC_VALUE_HANDLE, 0, &[2]).await }
+            });
+            let MockRawDatastoreEvents::Write(_, _, _, _, _, reply) =
+                data_evts.recv().await.unwrap()
+            else {
+                unreachable!();
+            };
+            reply.send(Ok(())).unwrap();
+            pending_write.await.unwrap().unwrap();
+            att_db.write_no_response_attribute(CHARACTERISTIC_VALUE_HANDLE, &[3]);
+
+            // assert: the provider was only consulted once, and only the second
+            // write without response went through
+            assert_eq!(provider.requests.borrow().len(), 1);
+            let MockRawDatastoreEvents::WriteNoResponse(_, _, _, data) =
+                data_evts.try_recv().unwrap()
+            else {
+                unreachable!();
+            };
+            assert_eq!(data, vec![3]);
+            assert_eq!(data_evts.try_recv().unwrap_err(), TryRecvError::Empty);
+        });
+    }
+
+    #[test]
+    fn test_write_no_response_requires_encryption() {
+        // arrange: a characteristic requiring encryption, on an unencrypted link
+        let (gatt_db, mut data_evts) = make_db_with_secure_characteristic(
+            AttPermissions::WRITABLE_WITHOUT_RESPONSE | AttPermissions::ENCRYPTION_REQUIRED,
+            Rc::new(MockSecurityManager::new()),
+        );
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act
+        att_db.write_no_response_attribute(CHARACTERISTIC_VALUE_HANDLE, &[1]);
+
+        // assert: the write was dropped
+        assert_eq!(data_evts.try_recv().unwrap_err(), TryRecvError::Empty);
+    }
+
+    #[test]
+    fn test_security_required_without_security_manager() {
+        // arrange: a characteristic requiring encryption, in a database unable
+        // to determine the security of the link
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        gatt_db
+            .add_service_with_handles(
+                GattServiceWithHandle {
+                    handle: SERVICE_HANDLE,
+                    type_: SERVICE_TYPE,
+                    characteristics: vec![GattCharacteristicWithHandle {
+                        handle: CHARACTERISTIC_VALUE_HANDLE,
+                        type_: CHARACTERISTIC_TYPE,
+                        permissions: AttPermissions::READABLE | AttPermissions::ENCRYPTION_REQUIRED,
+                        descriptors: vec![],
+                    }],
+                },
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+
+        // act
+        let res = tokio_test::block_on(
+            gatt_db.get_att_database(TCB_IDX).read_attribute(CHARACTERISTIC_VALUE_HANDLE),
+        );
+
+        // assert: the link is treated as unencrypted
+        assert_eq!(res, Err(AttErrorCode::INSUFFICIENT_ENCRYPTION.into()));
+    }
+
+    const CCCD_HANDLE: AttHandle = AttHandle(5);
+
+    fn make_db_with_notify_characteristic() -> SharedBox<GattDatabase> {
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        gatt_db
+            .add_service_with_handles(
+                GattServiceWithHandle {
+                    handle: SERVICE_HANDLE,
+                    type_: SERVICE_TYPE,
+                    characteristics: vec![GattCharacteristicWithHandle {
+                        handle: CHARACTERISTIC_VALUE_HANDLE,
+                        type_: CHARACTERISTIC_TYPE,
+                        permissions: AttPermissions::READABLE | AttPermissions::NOTIFY,
+                        descriptors: vec![GattDescriptorWithHandle {
+                            handle: DESCRIPTOR_HANDLE,
+                            type_: DESCRIPTOR_TYPE,
+                            permissions: AttPermissions::READABLE,
+                        }],
+                    }],
+                },
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+        gatt_db
+    }
+
+    fn connect(gatt_db: &SharedBox<GattDatabase>) -> AttDatabaseImpl {
+        let bearer = make_bearer(gatt_db);
+        gatt_db.on_bearer_ready(TCB_IDX, bearer.as_ref());
+        gatt_db.get_att_database(TCB_IDX)
+    }
+
+    #[test]
+    fn test_cccd_materialized_after_descriptors() {
+        // arrange, act
+        let gatt_db = make_db_with_notify_characteristic();
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // assert: a CCCD was added after the last descriptor
+        let attrs = att_db.list_attributes();
+        assert_eq!(attrs.len(), 5, "{attrs:?}");
+        assert_eq!(
+            attrs[4],
+            AttAttribute {
+                handle: CCCD_HANDLE,
+                type_: CLIENT_CHARACTERISTIC_CONFIGURATION_UUID,
+                permissions: AttPermissions::READABLE | AttPermissions::WRITABLE_WITH_RESPONSE
+            }
+        );
+        // and that the client is initially unsubscribed
+        assert_eq!(tokio_test::block_on(att_db.read_attribute(CCCD_HANDLE)), Ok(vec![0, 0].into()));
+        assert_eq!(
+            att_db.client_configuration(CHARACTERISTIC_VALUE_HANDLE),
+            Some(ClientConfiguration::empty())
+        );
+    }
+
+    #[test]
+    fn test_cccd_not_materialized_if_supplied() {
+        // arrange
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act: add a characteristic with its own CCCD
+        gatt_db
+            .add_service_with_handles(
+                GattServiceWithHandle {
+                    handle: SERVICE_HANDLE,
+                    type_: SERVICE_TYPE,
+                    characteristics: vec![GattCharacteristicWithHandle {
+                        handle: CHARACTERISTIC_VALUE_HANDLE,
+                        type_: CHARACTERISTIC_TYPE,
+                        permissions: AttPermissions::INDICATE,
+                        descriptors: vec![GattDescriptorWithHandle {
+                            handle: DESCRIPTOR_HANDLE,
+                            type_: CLIENT_CHARACTERISTIC_CONFIGURATION_UUID,
+                            permissions: AttPermissions::READABLE
+                                | AttPermissions::WRITABLE_WITH_RESPONSE,
+                        }],
+                    }],
+                },
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+
+        // assert: no other CCCD was added, and the upper layer manages it
+        assert_eq!(att_db.list_attributes().len(), 4);
+        assert_eq!(att_db.client_configuration(CHARACTERISTIC_VALUE_HANDLE), None);
+    }
+
+    #[test]
+    fn test_cccd_write() {
+        // arrange
+        let gatt_db = make_db_with_notify_characteristic();
+        let att_db = connect(&gatt_db);
+
+        // act: subscribe to notifications
+        let res = tokio_test::block_on(att_db.write_attribute(CCCD_HANDLE, 0, &[1, 0]));
+
+        // assert: the subscription is visible to readers and to the bearer
+        assert_eq!(res, Ok(()));
+        assert_eq!(tokio_test::block_on(att_db.read_attribute(CCCD_HANDLE)), Ok(vec![1, 0].into()));
+        assert_eq!(
+            att_db.client_configuration(CHARACTERISTIC_VALUE_HANDLE),
+            Some(ClientConfiguration::NOTIFICATION)
+        );
+    }
+
+    #[test]
+    fn test_subscriptions_listed() {
+        // arrange
+        let gatt_db = make_db_with_notify_characteristic();
+        let att_db = connect(&gatt_db);
+
+        // act
+        tokio_test::block_on(att_db.write_attribute(CCCD_HANDLE, 0, &[1, 0])).unwrap();
+
+        // assert
+        assert_eq!(
+            gatt_db.subscriptions(TCB_IDX),
+            vec![(CHARACTERISTIC_VALUE_HANDLE, ClientConfiguration::NOTIFICATION)]
+        );
+    }
+
+    #[test]
+    fn test_services_listed_with_handle_ranges() {
+        // arrange
+        let gatt_db = make_db_with_notify_characteristic();
+
+        // act
+        let services = gatt_db.services();
+
+        // assert: the service spans up to its materialized CCCD
+        assert_eq!(services, vec![(SERVICE_HANDLE..=CCCD_HANDLE, SERVICE_TYPE)]);
+    }
+
+    #[test]
+    fn test_cccd_write_listener() {
+        // arrange: db with a listener, and a connected client
+        let gatt_db = make_db_with_notify_characteristic();
+        let (callbacks, mut rx) = MockCallbacks::new();
+        gatt_db.register_listener(Rc::new(callbacks));
+        let att_db = connect(&gatt_db);
+        assert!(matches!(rx.blocking_recv().unwrap(), MockCallbackEvents::OnLeConnect(..)));
+
+        // act: subscribe to notifications
+        tokio_test::block_on(att_db.write_attribute(CCCD_HANDLE, 0, &[1, 0])).unwrap();
+
+        // assert: we got the callback
+        let event = rx.blocking_recv().unwrap();
+        let MockCallbackEvents::OnClientConfigurationChange(
+            TCB_IDX,
+            CHARACTERISTIC_VALUE_HANDLE,
+            configuration,
+        ) = event
+        else {
+            unreachable!();
+        };
+        assert_eq!(configuration, ClientConfiguration::NOTIFICATION);
+    }
+
+    #[test]
+    fn test_cccd_write_unsupported_configuration() {
+        // arrange
+        let gatt_db = make_db_with_notify_characteristic();
+        let att_db = connect(&gatt_db);
+
+        // act: subscribe to indications, which the characteristic does not support
+        let res = tokio_test::block_on(att_db.write_attribute(CCCD_HANDLE, 0, &[2, 0]));
+
+        // assert
+        assert_eq!(
+            res,
+            Err(AttErrorCode::CLIENT_CHARACTERISTIC_CONFIGURATION_DESCRIPTOR_IMPROPERLY_CONFIGURED
+                .into())
+        );
+        assert_eq!(
+            att_db.client_configuration(CHARACTERISTIC_VALUE_HANDLE),
+            Some(ClientConfiguration::empty())
+        );
+    }
+
+    #[test]
+    fn test_cccd_write_invalid_length() {
+        let gatt_db = make_db_with_notify_characteristic();
+        let att_db = connect(&gatt_db);
+
+        let res = tokio_test::block_on(att_db.write_attribute(CCCD_HANDLE, 0, &[1]));
+
+        assert_eq!(res, Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH.into()));
+    }
+
+    #[test]
+    fn test_cccd_write_at_offset() {
+        let gatt_db = make_db_with_notify_characteristic();
+        let att_db = connect(&gatt_db);
+
+        let res = tokio_test::block_on(att_db.write_attribute(CCCD_HANDLE, 1, &[0]));
+
+        assert_eq!(res, Err(AttErrorCode::ATTRIBUTE_NOT_LONG.into()));
+    }
+
+    #[test]
+    fn test_cccd_bonded_subscription_restored() {
+        // arrange: a bonded client subscribes, then disconnects
+        let peer = AddressWithType { address: [1, 2, 3, 4, 5, 6], ..AddressWithType::EMPTY };
+        let gatt_db = make_db_with_notify_characteristic();
+        let att_db = connect(&gatt_db);
+        gatt_db.on_le_bonded(TCB_IDX, peer);
+        tokio_test::block_on(att_db.write_attribute(CCCD_HANDLE, 0, &[1, 0])).unwrap();
+        gatt_db.on_bearer_dropped(TCB_IDX);
+
+        // act: it reconnects, and is identified as bonded
+        let att_db = connect(&gatt_db);
+        assert_eq!(tokio_test::block_on(att_db.read_attribute(CCCD_HANDLE)), Ok(vec![0, 0].into()));
+        gatt_db.on_le_bonded(TCB_IDX, peer);
+
+        // assert: its subscription was restored
+        assert_eq!(tokio_test::block_on(att_db.read_attribute(CCCD_HANDLE)), Ok(vec![1, 0].into()));
+    }
+
+    #[test]
+    fn test_cccd_writes_in_burst_told_once_ended() {
+        // arrange
+        let gatt_db = make_db_with_notify_characteristic();
+        let (callbacks, mut rx) = MockCallbacks::new();
+        gatt_db.register_listener(Rc::new(callbacks));
+        let att_db = connect(&gatt_db);
+        assert!(matches!(rx.blocking_recv().unwrap(), MockCallbackEvents::OnLeConnect(..)));
+
+        // act: subscribe, unsubscribe and subscribe again within a burst
+        att_db.begin_client_configuration_burst();
+        tokio_test::block_on(att_db.write_attribute(CCCD_HANDLE, 0, &[1, 0])).unwrap();
+        let subscriptions = gatt_db.subscriptions(TCB_IDX);
+        tokio_test::block_on(att_db.write_attribute(CCCD_HANDLE, 0, &[0, 0])).unwrap();
+        tokio_test::block_on(att_db.write_attribute(CCCD_HANDLE, 0, &[1, 0])).unwrap();
+        let told_during_burst = rx.try_recv().is_ok();
+        att_db.end_client_configuration_burst();
+
+        // assert: the subscription took effect right away, but the listener
+        // was only told of the last write, once the burst ended
+        assert_eq!(
+            subscriptions,
+            vec![(CHARACTERISTIC_VALUE_HANDLE, ClientConfiguration::NOTIFICATION)]
+        );
+        assert!(!told_during_burst);
+        let MockCallbackEvents::OnClientConfigurationChange(
+            TCB_IDX,
+            CHARACTERISTIC_VALUE_HANDLE,
+            configuration,
+        ) = rx.try_recv().unwrap()
+        else {
+            unreachable!();
+        };
+        assert_eq!(configuration, ClientConfiguration::NOTIFICATION);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_cccd_burst_saved_on_disconnect() {
+        // arrange: a bonded client subscribes within a burst, then disconnects
+        // before it ends
+        let peer = AddressWithType { address: [1, 2, 3, 4, 5, 6], ..AddressWithType::EMPTY };
+        let gatt_db = make_db_with_notify_characteristic();
+        let att_db = connect(&gatt_db);
+        gatt_db.on_le_bonded(TCB_IDX, peer);
+        att_db.begin_client_configuration_burst();
+        tokio_test::block_on(att_db.write_attribute(CCCD_HANDLE, 0, &[1, 0])).unwrap();
+        gatt_db.on_bearer_dropped(TCB_IDX);
+
+        // act
+        let att_db = connect(&gatt_db);
+        gatt_db.on_le_bonded(TCB_IDX, peer);
+
+        // assert: its subscription was restored
+        assert_eq!(tokio_test::block_on(att_db.read_attribute(CCCD_HANDLE)), Ok(vec![1, 0].into()));
+        assert!(!gatt_db.client_configuration_bursts.borrow().contains_key(&TCB_IDX));
+    }
+
+    fn make_db_for_access_policy(interceptor: BondedClientsOnly) -> SharedBox<GattDatabase> {
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        gatt_db
+            .add_service(
+                ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                    CharacteristicBuilder::new(CHARACTERISTIC_TYPE, AttPermissions::READABLE)
+                        .static_value(vec![1, 2]),
+                ),
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+        gatt_db.set_access_interceptor(Rc::new(interceptor));
+        gatt_db
+    }
+
+    #[test]
+    fn test_access_interceptor_rejects_unbonded_client() {
+        // arrange
+        let gatt_db = make_db_for_access_policy(BondedClientsOnly::new());
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act
+        let value = tokio_test::block_on(att_db.read_attribute(CHARACTERISTIC_VALUE_HANDLE));
+        let declaration =
+            tokio_test::block_on(att_db.read_attribute(CHARACTERISTIC_DECLARATION_HANDLE));
+
+        // assert: the value is protected, but the service can still be discovered
+        assert_eq!(value, Err(AttErrorCode::INSUFFICIENT_AUTHENTICATION.into()));
+        assert!(declaration.is_ok());
+    }
+
+    #[test]
+    fn test_access_interceptor_allows_bonded_client() {
+        let gatt_db = make_db_for_access_policy(BondedClientsOnly::new());
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+        gatt_db.on_le_bonded(TCB_IDX, AddressWithType::EMPTY);
+
+        let value = tokio_test::block_on(att_db.read_attribute(CHARACTERISTIC_VALUE_HANDLE));
+
+        assert_eq!(value, Ok(vec![1, 2].into()));
+    }
+
+    #[test]
+    fn test_access_interceptor_allow_listed_service() {
+        let gatt_db =
+            make_db_for_access_policy(BondedClientsOnly::new().allow_service(SERVICE_TYPE));
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        let value = tokio_test::block_on(att_db.read_attribute(CHARACTERISTIC_VALUE_HANDLE));
+
+        assert_eq!(value, Ok(vec![1, 2].into()));
+    }
+
+    #[test]
+    fn test_access_interceptor_applies_until_bonded_again() {
+        // arrange: a bonded client disconnects, and reconnects
+        let gatt_db = make_db_for_access_policy(BondedClientsOnly::new());
+        connect(&gatt_db);
+        gatt_db.on_le_bonded(TCB_IDX, AddressWithType::EMPTY);
+        gatt_db.on_bearer_dropped(TCB_IDX);
+        let att_db = connect(&gatt_db);
+
+        // act: it reads before being identified as bonded on the new connection
+        let value = tokio_test::block_on(att_db.read_attribute(CHARACTERISTIC_VALUE_HANDLE));
+
+        // assert
+        assert_eq!(value, Err(AttErrorCode::INSUFFICIENT_AUTHENTICATION.into()));
+    }
+
+    /// Completes the reads and writes it intercepts with the given results (if
+    /// any), and doubles the values written otherwise
+    #[derive(Default)]
+    struct TestInterceptor {
+        read: Option<Result<Vec<u8>, AttErrorCode>>,
+        write: Option<Result<(), AttErrorCode>>,
+        calls: Cell<usize>,
+    }
+
+    impl Interceptor for TestInterceptor {
+        fn before_read(&self, _: &OperationContext) -> Interception<Vec<u8>> {
+            self.calls.set(self.calls.get() + 1);
+            match &self.read {
+                Some(result) => Interception::Complete(result.clone()),
+                None => Interception::Proceed,
+            }
+        }
+
+        fn before_write(&self, _: &OperationContext, value: &mut Vec<u8>) -> Interception<()> {
+            self.calls.set(self.calls.get() + 1);
+            value.iter_mut().for_each(|byte| *byte *= 2);
+            match self.write {
+                Some(result) => Interception::Complete(result),
+                None => Interception::Proceed,
+            }
+        }
+    }
+
+    fn make_db_with_interceptor(
+        permissions: AttPermissions,
+        interceptor: TestInterceptor,
+    ) -> (SharedBox<GattDatabase>, UnboundedReceiver<MockDatastoreEvents>, Rc<TestInterceptor>)
+    {
+        let (gatt_datastore, data_evts) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        gatt_db
+            .add_service_with_handles(
+                GattServiceWithHandle {
+                    handle: SERVICE_HANDLE,
+                    type_: SERVICE_TYPE,
+                    characteristics: vec![GattCharacteristicWithHandle {
+                        handle: CHARACTERISTIC_VALUE_HANDLE,
+                        type_: CHARACTERISTIC_TYPE,
+                        permissions,
+                        descriptors: vec![],
+                    }],
+                },
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+        let interceptor = Rc::new(interceptor);
+        gatt_db.add_interceptor(
+            SERVICE_HANDLE..=CHARACTERISTIC_VALUE_HANDLE,
+            0,
+            interceptor.clone(),
+        );
+        (gatt_db, data_evts, interceptor)
+    }
+
+    #[test]
+    fn test_interceptor_completes_read() {
+        // arrange
+        let (gatt_db, mut data_evts, interceptor) = make_db_with_interceptor(
+            AttPermissions::READABLE,
+            TestInterceptor { read: Some(Ok(vec![9])), ..Default::default() },
+        );
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act
+        let value = tokio_test::block_on(att_db.read_attribute(CHARACTERISTIC_VALUE_HANDLE));
+
+        // assert: the datastore was not involved
+        assert_eq!(value, Ok(vec![9].into()));
+        assert_eq!(interceptor.calls.get(), 1);
+        assert_eq!(data_evts.try_recv().unwrap_err(), TryRecvError::Empty);
+    }
+
+    #[test]
+    fn test_interceptor_not_consulted_without_permission() {
+        // arrange: the interceptor would complete reads of an unreadable value
+        let (gatt_db, _, interceptor) = make_db_with_interceptor(
+            AttPermissions::WRITABLE_WITH_RESPONSE,
+            TestInterceptor { read: Some(Ok(vec![9])), ..Default::default() },
+        );
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act
+        let value = tokio_test::block_on(att_db.read_attribute(CHARACTERISTIC_VALUE_HANDLE));
+
+        // assert: the permissions were checked first
+        assert_eq!(value, Err(AttErrorCode::READ_NOT_PERMITTED.into()));
+        assert_eq!(interceptor.calls.get(), 0);
+    }
+
+    #[test]
+    fn test_interceptor_runs_after_access_interceptor() {
+        // arrange
+        let (gatt_db, _, interceptor) = make_db_with_interceptor(
+            AttPermissions::READABLE,
+            TestInterceptor { read: Some(Ok(vec![9])), ..Default::default() },
+        );
+        gatt_db.set_access_interceptor(Rc::new(BondedClientsOnly::new()));
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act: the client reads before and after being identified as bonded
+        let unbonded = tokio_test::block_on(att_db.read_attribute(CHARACTERISTIC_VALUE_HANDLE));
+        let calls_while_unbonded = interceptor.calls.get();
+        gatt_db.on_le_bonded(TCB_IDX, AddressWithType::EMPTY);
+        let bonded = tokio_test::block_on(att_db.read_attribute(CHARACTERISTIC_VALUE_HANDLE));
+
+        // assert
+        assert_eq!(unbonded, Err(AttErrorCode::INSUFFICIENT_AUTHENTICATION.into()));
+        assert_eq!(calls_while_unbonded, 0);
+        assert_eq!(bonded, Ok(vec![9].into()));
+    }
+
+    #[test]
+    fn test_interceptor_rejects_write() {
+        // arrange
+        let (gatt_db, mut data_evts, _) = make_db_with_interceptor(
+            AttPermissions::WRITABLE_WITH_RESPONSE,
+            TestInterceptor {
+                write: Some(Err(AttErrorCode::WRITE_REQUEST_REJECTED)),
+                ..Default::default()
+            },
+        );
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act
+        let result =
+            tokio_test::block_on(att_db.write_attribute(CHARACTERISTIC_VALUE_HANDLE, 0, &[1, 2]));
+
+        // assert: the rejection is attributed to the interceptor
+        let err = result.unwrap_err();
+        assert_eq!(err.code, AttErrorCode::WRITE_REQUEST_REJECTED);
+        assert_eq!(err.layer, ErrorLayer::Interceptor);
+        assert_eq!(data_evts.try_recv().unwrap_err(), TryRecvError::Empty);
+    }
+
+    #[test]
+    fn test_interceptor_rewrites_written_value() {
+        // arrange
+        let (gatt_db, mut data_evts, _) = make_db_with_interceptor(
+            AttPermissions::WRITABLE_WITH_RESPONSE,
+            TestInterceptor::default(),
+        );
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act
+        let recv_data = block_on_locally(async {
+            spawn_local(async move {
+                att_db.write_attribute(CHARACTERISTIC_VALUE_HANDLE, 0, &[1, 2]).await.unwrap();
+            });
+            let Some(MockDatastoreEvents::Write(_, _, _, recv_data, reply)) =
+                data_evts.recv().await
+            else {
+                unreachable!();
+            };
+            reply.send(Ok(())).unwrap();
+            recv_data
+        });
+
+        // assert: the datastore got the rewritten value
+        assert_eq!(recv_data, vec![2, 4]);
+    }
+
+    #[test]
+    fn test_declarations_not_intercepted() {
+        let (gatt_db, _, interceptor) = make_db_with_interceptor(
+            AttPermissions::READABLE,
+            TestInterceptor {
+                read: Some(Err(AttErrorCode::READ_NOT_PERMITTED)),
+                ..Default::default()
+            },
+        );
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        let service = tokio_test::block_on(att_db.read_attribute(SERVICE_HANDLE));
+        let declaration =
+            tokio_test::block_on(att_db.read_attribute(CHARACTERISTIC_DECLARATION_HANDLE));
+
+        assert!(service.is_ok());
+        assert!(declaration.is_ok());
+        assert_eq!(interceptor.calls.get(), 0);
+    }
+
+    fn make_db_for_hashing() -> SharedBox<GattDatabase> {
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        gatt_db
+            .add_service_with_handles(
+                GattServiceWithHandle {
+                    handle: SERVICE_HANDLE,
+                    type_: SERVICE_TYPE,
+                    characteristics: vec![GattCharacteristicWithHandle {
+                        handle: CHARACTERISTIC_VALUE_HANDLE,
+                        type_: CHARACTERISTIC_TYPE,
+                        permissions: AttPermissions::READABLE,
+                        descriptors: vec![
+                            // the Characteristic User Description descriptor, which is hashed
+                            GattDescriptorWithHandle {
+                                handle: DESCRIPTOR_HANDLE,
+                                type_: Uuid::new(0x2901),
+                                permissions: AttPermissions::READABLE,
+                            },
+                            // a custom descriptor, which is not
+                            GattDescriptorWithHandle {
+                                handle: AttHandle(5),
+                                type_: DESCRIPTOR_TYPE,
+                                permissions: AttPermissions::READABLE,
+                            },
+                        ],
+                    }],
+                },
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+        gatt_db
+    }
+
+    #[test]
+    fn test_database_hash() {
+        let gatt_db = make_db_for_hashing();
+
+        let hash = gatt_db.robust_caching().borrow().database_hash();
+
+        // assert: the hash matches an independently computed AES-CMAC over the
+        // service declaration, characteristic declaration, and user description
+        assert_eq!(
+            hash,
+            [
+                0x90, 0x18, 0x49, 0xd5, 0x34, 0xd7, 0x86, 0xa4, 0xaf, 0xaa, 0x11, 0x67, 0x83, 0x13,
+                0x19, 0xab
+            ]
+        );
+    }
+
+    #[test]
+    fn test_database_hash_updated_on_change() {
+        // arrange
+        let gatt_db = make_db_for_hashing();
+        let hash = gatt_db.robust_caching().borrow().database_hash();
+
+        // act: add a service
+        let (gatt_datastore, _) = MockDatastore::new();
+        gatt_db
+            .add_service_with_handles(
+                GattServiceWithHandle {
+                    handle: AttHandle(10),
+                    type_: SERVICE_TYPE,
+                    characteristics: vec![],
+                },
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+
+        // assert: the hash changed
+        let new_hash = gatt_db.robust_caching().borrow().database_hash();
+        assert_ne!(hash, new_hash);
+
+        // act: remove the service
+        gatt_db.remove_service_at_handle(AttHandle(10)).unwrap();
+
+        // assert: the hash is restored
+        assert_eq!(gatt_db.robust_caching().borrow().database_hash(), hash);
+    }
+
+    fn add_service_with_single_characteristic(gatt_db: &GattDatabase, handle: AttHandle) {
+        let (gatt_datastore, _) = MockDatastore::new();
+        gatt_db
+            .add_service_with_handles(
+                GattServiceWithHandle {
+                    handle,
+                    type_: SERVICE_TYPE,
+                    characteristics: vec![GattCharacteristicWithHandle {
+                        handle: AttHandle(handle.0 + 2),
+                        type_: CHARACTERISTIC_TYPE,
+                        permissions: AttPermissions::READABLE,
+                        descriptors: vec![],
+                    }],
+                },
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_builder_provisions_handles() {
+        // arrange
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+
+        // act: add a service with a plain characteristic, and a notifying one with a
+        // descriptor
+        let token = gatt_db
+            .add_service(
+                ServiceBuilder::new(SERVICE_TYPE)
+                    .characteristic(CharacteristicBuilder::new(
+                        CHARACTERISTIC_TYPE,
+                        AttPermissions::READABLE,
+                    ))
+                    .characteristic(
+                        CharacteristicBuilder::new(
+                            CHARACTERISTIC_TYPE,
+                            AttPermissions::READABLE | AttPermissions::NOTIFY,
+                        )
+                        .descriptor(DescriptorBuilder::new(
+                            DESCRIPTOR_TYPE,
+                            AttPermissions::READABLE,
+                        )),
+                    ),
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+
+        // assert: the attributes are consecutive, with a managed CCCD after the descriptor
+        assert_eq!(token.handle(), AttHandle(1));
+        assert_eq!(
+            gatt_db
+                .get_att_database(TCB_IDX)
+                .list_attributes()
+                .into_iter()
+                .map(|attr| (attr.handle, attr.type_))
+                .collect::<Vec<_>>(),
+            vec![
+                (AttHandle(1), PRIMARY_SERVICE_DECLARATION_UUID),
+                (AttHandle(2), CHARACTERISTIC_UUID),
+                (AttHandle(3), CHARACTERISTIC_TYPE),
+                (AttHandle(4), CHARACTERISTIC_UUID),
+                (AttHandle(5), CHARACTERISTIC_TYPE),
+                (AttHandle(6), DESCRIPTOR_TYPE),
+                (AttHandle(7), CLIENT_CHARACTERISTIC_CONFIGURATION_UUID),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_builder_with_supplied_cccd() {
+        // arrange
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+
+        // act: add a notifying characteristic with its own CCCD
+        gatt_db
+            .add_service(
+                ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                    CharacteristicBuilder::new(CHARACTERISTIC_TYPE, AttPermissions::NOTIFY)
+                        .descriptor(DescriptorBuilder::new(
+                            CLIENT_CHARACTERISTIC_CONFIGURATION_UUID,
+                            AttPermissions::READABLE | AttPermissions::WRITABLE_WITH_RESPONSE,
+                        )),
+                ),
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+
+        // assert: no other CCCD was added, and the upper layer owns the supplied one
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+        assert_eq!(att_db.list_attributes().len(), 4);
+        assert_eq!(att_db.client_configuration(AttHandle(3)), None);
+    }
+
+    #[test]
+    fn test_builder_static_values() {
+        // arrange
+        let (gatt_datastore, mut data_rx) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        gatt_db
+            .add_service(
+                ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                    CharacteristicBuilder::new(CHARACTERISTIC_TYPE, AttPermissions::READABLE)
+                        .static_value(vec![1, 2])
+                        .descriptor(
+                            DescriptorBuilder::new(DESCRIPTOR_TYPE, AttPermissions::READABLE)
+                                .static_value(vec![3, 4]),
+                        ),
+                ),
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act
+        let characteristic_value = tokio_test::block_on(att_db.read_attribute(AttHandle(3)));
+        let descriptor_value = tokio_test::block_on(att_db.read_attribute(AttHandle(4)));
+
+        // assert: the static values were served without consulting the
+        // datastore, which an all-static service does not even keep
+        assert_eq!(characteristic_value, Ok(vec![1, 2].into()));
+        assert_eq!(descriptor_value, Ok(vec![3, 4].into()));
+        assert_eq!(data_rx.try_recv().unwrap_err(), TryRecvError::Disconnected);
+    }
+
+    #[test]
+    fn test_static_value_shared_between_reads() {
+        // arrange: a long static value
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        gatt_db
+            .add_service(
+                ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                    CharacteristicBuilder::new(CHARACTERISTIC_TYPE, AttPermissions::READABLE)
+                        .static_value(vec![1; MAX_ATTRIBUTE_VALUE_LEN]),
+                ),
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act: read it twice
+        let first = tokio_test::block_on(att_db.read_attribute(AttHandle(3))).unwrap();
+        let second = tokio_test::block_on(att_db.read_attribute(AttHandle(3))).unwrap();
+
+        // assert: both reads share the stored value, rather than copying it
+        assert_eq!(first, vec![1; MAX_ATTRIBUTE_VALUE_LEN]);
+        assert_eq!(first.as_ptr(), second.as_ptr());
+    }
+
+    #[test]
+    fn test_builder_dynamic_value() {
+        // arrange
+        let (gatt_datastore, mut data_rx) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        gatt_db
+            .add_service(
+                ServiceBuilder::new(SERVICE_TYPE).characteristic(CharacteristicBuilder::new(
+                    CHARACTERISTIC_TYPE,
+                    AttPermissions::READABLE,
+                )),
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act
+        let (_, value) = tokio_test::block_on(async {
+            join!(
+                async {
+                    let MockDatastoreEvents::Read(_, AttHandle(3), _, reply) =
+                        data_rx.recv().await.unwrap()
+                    else {
+                        unreachable!()
+                    };
+                    reply.send(Ok(vec![5, 6])).unwrap();
+                },
+                att_db.read_attribute(AttHandle(3))
+            )
+        });
+
+        // assert: the value was read from the datastore
+        assert_eq!(value, Ok(vec![5, 6].into()));
+    }
+
+    #[test]
+    fn test_builder_placed_between_services() {
+        // arrange: services at 1-3 and 10-12
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        add_service_with_single_characteristic(&gatt_db, AttHandle(1));
+        add_service_with_single_characteristic(&gatt_db, AttHandle(10));
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_datastore = Rc::new(gatt_datastore);
+
+        // act: add a service fitting in the gap, and one that does not
+        let small = gatt_db
+            .add_service(
+                ServiceBuilder::new(SERVICE_TYPE).characteristic(CharacteristicBuilder::new(
+                    CHARACTERISTIC_TYPE,
+                    AttPermissions::READABLE,
+                )),
+                gatt_datastore.clone(),
+            )
+            .unwrap();
+        let large = gatt_db
+            .add_service(
+                ServiceBuilder::new(SERVICE_TYPE)
+                    .characteristic(CharacteristicBuilder::new(
+                        CHARACTERISTIC_TYPE,
+                        AttPermissions::READABLE,
+                    ))
+                    .characteristic(CharacteristicBuilder::new(
+                        CHARACTERISTIC_TYPE,
+                        AttPermissions::READABLE,
+                    ))
+                    .characteristic(CharacteristicBuilder::new(
+                        CHARACTERISTIC_TYPE,
+                        AttPermissions::READABLE,
+                    )),
+                gatt_datastore,
+            )
+            .unwrap();
+
+        // assert
+        assert_eq!(small.handle(), AttHandle(4));
+        assert_eq!(large.handle(), AttHandle(13));
+    }
+
+    #[test]
+    fn test_builder_not_placed_within_service() {
+        // arrange: a service spanning the whole handle space, with a gap in the middle
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_datastore = Rc::new(gatt_datastore);
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        gatt_db
+            .add_service_with_handles(
+                GattServiceWithHandle {
+                    handle: AttHandle(1),
+                    type_: SERVICE_TYPE,
+                    characteristics: vec![GattCharacteristicWithHandle {
+                        handle: AttHandle(0xFFFF),
+                        type_: CHARACTERISTIC_TYPE,
+                        permissions: AttPermissions::READABLE,
+                        descriptors: vec![],
+                    }],
+                },
+                gatt_datastore.clone(),
+            )
+            .unwrap();
+
+        // act
+        let res = gatt_db.add_service(ServiceBuilder::new(SERVICE_TYPE), gatt_datastore);
+
+        // assert: no handles were available
+        assert!(res.is_err());
+    }
+
+    fn service_with_characteristics(count: usize) -> ServiceBuilder {
+        (0..count).fold(ServiceBuilder::new(SERVICE_TYPE), |service, _| {
+            service.characteristic(CharacteristicBuilder::new(
+                CHARACTERISTIC_TYPE,
+                AttPermissions::READABLE,
+            ))
+        })
+    }
+
+    /// Open a database persisting its handle assignments to the given storage,
+    /// as after a restart
+    fn restart_with_storage(storage: &Rc<MockHandleAssignmentStorage>) -> SharedBox<GattDatabase> {
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        gatt_db.set_handle_assignment_storage(storage.clone()).unwrap();
+        gatt_db
+    }
+
+    #[test]
+    fn test_builder_handles_persist_across_restart() {
+        // arrange: two services are added, and the first is removed (so the
+        // second would move if placed anew)
+        let storage = Rc::new(MockHandleAssignmentStorage::new());
+        let gatt_db = restart_with_storage(&storage);
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_datastore = Rc::new(gatt_datastore);
+        let first = gatt_db.add_service(service_with_characteristics(1), gatt_datastore.clone());
+        gatt_db.add_service(service_with_characteristics(2), gatt_datastore.clone()).unwrap();
+        gatt_db.add_service(service_with_characteristics(1), gatt_datastore.clone()).unwrap();
+        gatt_db.remove_service(first.unwrap()).unwrap();
+        let services = gatt_db.services();
+
+        // act: after a restart, the remaining services are added again
+        let gatt_db = restart_with_storage(&storage);
+        let (callbacks, mut callbacks_rx) = MockCallbacks::new();
+        gatt_db.register_listener(Rc::new(callbacks));
+        let second = gatt_db.add_service(service_with_characteristics(2), gatt_datastore.clone());
+        let third = gatt_db.add_service(service_with_characteristics(1), gatt_datastore);
+
+        // assert: they kept their handles, so nothing changed since the restart
+        assert_eq!(second.unwrap().handle(), AttHandle(4));
+        assert_eq!(third.unwrap().handle(), AttHandle(9));
+        assert_eq!(gatt_db.services(), services);
+        assert_eq!(gatt_db.handle_assignments().borrow().changed(), None);
+        // (listeners are still told of the addition, for connected clients)
+        assert!(matches!(callbacks_rx.try_recv(), Ok(MockCallbackEvents::OnServiceChange(_))));
+    }
+
+    #[test]
+    fn test_builder_new_service_avoids_assigned_handles() {
+        // arrange: a service is added, then removed
+        let storage = Rc::new(MockHandleAssignmentStorage::new());
+        let gatt_db = restart_with_storage(&storage);
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_datastore = Rc::new(gatt_datastore);
+        let token =
+            gatt_db.add_service(service_with_characteristics(1), gatt_datastore.clone()).unwrap();
+        gatt_db.remove_service(token).unwrap();
+
+        // act: after a restart, a service of another type is added
+        let gatt_db = restart_with_storage(&storage);
+        let other =
+            gatt_db.add_service(ServiceBuilder::new(Uuid::new(0x4321)), gatt_datastore).unwrap();
+
+        // assert: it was placed after the handles assigned to the first service
+        assert_eq!(other.handle(), AttHandle(4));
+    }
+
+    #[test]
+    fn test_builder_changed_layout_moves_service() {
+        // arrange: two services are added
+        let storage = Rc::new(MockHandleAssignmentStorage::new());
+        let gatt_db = restart_with_storage(&storage);
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_datastore = Rc::new(gatt_datastore);
+        gatt_db.add_service(service_with_characteristics(1), gatt_datastore.clone()).unwrap();
+        gatt_db.add_service(service_with_characteristics(1), gatt_datastore.clone()).unwrap();
+
+        // act: after a restart, the first one grew
+        let gatt_db = restart_with_storage(&storage);
+        let first = gatt_db.add_service(service_with_characteristics(2), gatt_datastore.clone());
+        let second = gatt_db.add_service(service_with_characteristics(1), gatt_datastore);
+
+        // assert: only the first moved (out of the way of the second), and both its
+        // old and new handles changed
+        assert_eq!(first.unwrap().handle(), AttHandle(7));
+        assert_eq!(second.unwrap().handle(), AttHandle(4));
+        assert_eq!(
+            gatt_db.handle_assignments().borrow().changed(),
+            Some(AttHandle(1)..=AttHandle(11))
+        );
+    }
+
+    #[test]
+    fn test_builder_service_grows_into_reserved_handles() {
+        // arrange: two services are added, with spare handles after each
+        let storage = Rc::new(MockHandleAssignmentStorage::new());
+        let gatt_db = restart_with_storage(&storage);
+        gatt_db.set_handle_allocation_policy(Rc::new(GapReservingAllocation { spare_handles: 2 }));
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_datastore = Rc::new(gatt_datastore);
+        gatt_db.add_service(service_with_characteristics(1), gatt_datastore.clone()).unwrap();
+        gatt_db.add_service(service_with_characteristics(1), gatt_datastore.clone()).unwrap();
+
+        // act: after a restart (without the policy), the first one grew
+        let gatt_db = restart_with_storage(&storage);
+        let first = gatt_db.add_service(service_with_characteristics(2), gatt_datastore.clone());
+        let second = gatt_db.add_service(service_with_characteristics(1), gatt_datastore);
+
+        // assert: neither moved, and only the handles of the first changed
+        assert_eq!(first.unwrap().handle(), AttHandle(1));
+        assert_eq!(second.unwrap().handle(), AttHandle(6));
+        assert_eq!(
+            gatt_db.handle_assignments().borrow().changed(),
+            Some(AttHandle(1)..=AttHandle(5))
+        );
+    }
+
+    #[test]
+    fn test_builder_new_service_avoids_spare_handles() {
+        // arrange
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_datastore = Rc::new(gatt_datastore);
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        gatt_db.set_handle_allocation_policy(Rc::new(GapReservingAllocation { spare_handles: 4 }));
+        gatt_db.add_service(service_with_characteristics(1), gatt_datastore.clone()).unwrap();
+
+        // act
+        gatt_db.set_handle_allocation_policy(Rc::new(CompactAllocation));
+        let second = gatt_db.add_service(service_with_characteristics(1), gatt_datastore.clone());
+        let third = gatt_db.add_service(service_with_characteristics(1), gatt_datastore);
+
+        // assert: the spare handles of the first service were left free
+        assert_eq!(second.unwrap().handle(), AttHandle(8));
+        assert_eq!(third.unwrap().handle(), AttHandle(11));
+    }
+
+    #[test]
+    fn test_handle_assignment_storage_set_after_services() {
+        let gatt_db = GattDatabase::new();
+        let (gatt_datastore, _) = MockDatastore::new();
+        gatt_db.add_service(service_with_characteristics(1), Rc::new(gatt_datastore)).unwrap();
+
+        let res =
+            gatt_db.set_handle_assignment_storage(Rc::new(MockHandleAssignmentStorage::new()));
+
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_builder_rejects_cccd_without_notify_or_indicate() {
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+
+        let res = gatt_db.add_service(
+            ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                CharacteristicBuilder::new(CHARACTERISTIC_TYPE, AttPermissions::READABLE)
+                    .descriptor(DescriptorBuilder::new(
+                        CLIENT_CHARACTERISTIC_CONFIGURATION_UUID,
+                        AttPermissions::READABLE | AttPermissions::WRITABLE_WITH_RESPONSE,
+                    )),
+            ),
+            Rc::new(gatt_datastore),
+        );
+
+        assert!(res.is_err());
+        assert!(gatt_db.get_att_database(TCB_IDX).list_attributes().is_empty());
+    }
+
+    #[test]
+    fn test_builder_rejects_duplicate_cccd() {
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        let cccd = DescriptorBuilder::new(
+            CLIENT_CHARACTERISTIC_CONFIGURATION_UUID,
+            AttPermissions::READABLE | AttPermissions::WRITABLE_WITH_RESPONSE,
+        );
+
+        let res = gatt_db.add_service(
+            ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                CharacteristicBuilder::new(CHARACTERISTIC_TYPE, AttPermissions::INDICATE)
+                    .descriptor(cccd.clone())
+                    .descriptor(cccd),
+            ),
+            Rc::new(gatt_datastore),
+        );
+
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_builder_reliable_write() {
+        // arrange
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+
+        // act
+        gatt_db
+            .add_service(
+                ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                    CharacteristicBuilder::new(
+                        CHARACTERISTIC_TYPE,
+                        AttPermissions::WRITABLE_WITH_RESPONSE,
+                    )
+                    .reliable_write(),
+                ),
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+
+        // assert: the declaration flags the extended properties, and the descriptor
+        // following the value advertises reliable writes
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+        let characteristic_decl =
+            tokio_test::block_on(att_db.read_attribute(CHARACTERISTIC_DECLARATION_HANDLE));
+        let extended_properties = tokio_test::block_on(att_db.read_attribute(DESCRIPTOR_HANDLE));
+
+        // assert
+        assert_eq!(
+            characteristic_decl,
+            AttAttributeDataChild::GattCharacteristicDeclarationValue(
+                GattCharacteristicDeclarationValueBuilder {
+                    properties: GattCharacteristicPropertiesBuilder {
+                        read: 0,
+                        broadcast: 0,
+                        write_without_response: 0,
+                        write: 1,
+                        notify: 0,
+                        indicate: 0,
+                        authenticated_signed_writes: 0,
+                        extended_properties: 1,
+                    },
+                    handle: CHARACTERISTIC_VALUE_HANDLE.into(),
+                    uuid: CHARACTERISTIC_TYPE.into()
+                }
+            )
+            .to_vec()
+            .map(Into::into)
+            .map_err(|_| AttError::from(AttErrorCode::UNLIKELY_ERROR))
+        );
+        assert_eq!(att_db.list_attributes()[3].type_, CHARACTERISTIC_EXTENDED_PROPERTIES_UUID);
+        assert_eq!(extended_properties, Ok(vec![0x01, 0x00].into()));
+    }
+
+    #[test]
+    fn test_builder_rejects_reliable_write_without_write() {
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+
+        let res = gatt_db.add_service(
+            ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                CharacteristicBuilder::new(CHARACTERISTIC_TYPE, AttPermissions::READABLE)
+                    .reliable_write(),
+            ),
+            Rc::new(gatt_datastore),
+        );
+
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_builder_rejects_duplicate_extended_properties() {
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+
+        let res = gatt_db.add_service(
+            ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                CharacteristicBuilder::new(
+                    CHARACTERISTIC_TYPE,
+                    AttPermissions::WRITABLE_WITH_RESPONSE,
+                )
+                .reliable_write()
+                .descriptor(
+                    DescriptorBuilder::new(
+                        CHARACTERISTIC_EXTENDED_PROPERTIES_UUID,
+                        AttPermissions::READABLE,
+                    )
+                    .static_value(vec![0x01, 0x00]),
+                ),
+            ),
+            Rc::new(gatt_datastore),
+        );
+
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_builder_rejects_explicit_reliable_write_without_write() {
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+
+        let res = gatt_db.add_service(
+            ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                CharacteristicBuilder::new(CHARACTERISTIC_TYPE, AttPermissions::READABLE)
+                    .descriptor(
+                        DescriptorBuilder::new(
+                            CHARACTERISTIC_EXTENDED_PROPERTIES_UUID,
+                            AttPermissions::READABLE,
+                        )
+                        .static_value(vec![0x01, 0x00]),
+                    ),
+            ),
+            Rc::new(gatt_datastore),
+        );
+
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_builder_extended_properties_precede_other_descriptors() {
+        // arrange
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+
+        // act
+        gatt_db
+            .add_service(
+                ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                    CharacteristicBuilder::new(
+                        CHARACTERISTIC_TYPE,
+                        AttPermissions::WRITABLE_WITH_RESPONSE,
+                    )
+                    .descriptor(DescriptorBuilder::new(
+                        CHARACTERISTIC_USER_DESCRIPTION_UUID,
+                        AttPermissions::READABLE | AttPermissions::WRITABLE_WITH_RESPONSE,
+                    ))
+                    .writable_auxiliaries()
+                    .reliable_write(),
+                ),
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+
+        // assert: a single descriptor advertises both bits, before the user description
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+        let extended_properties = tokio_test::block_on(att_db.read_attribute(DESCRIPTOR_HANDLE));
+        let attributes = att_db.list_attributes();
+        assert_eq!(attributes.len(), 5);
+        assert_eq!(attributes[3].type_, CHARACTERISTIC_EXTENDED_PROPERTIES_UUID);
+        assert_eq!(attributes[4].type_, CHARACTERISTIC_USER_DESCRIPTION_UUID);
+        assert_eq!(extended_properties, Ok(vec![0x03, 0x00].into()));
+    }
+
+    #[test]
+    fn test_builder_rejects_writable_auxiliaries_without_writable_user_description() {
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+
+        let res = gatt_db.add_service(
+            ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                CharacteristicBuilder::new(CHARACTERISTIC_TYPE, AttPermissions::READABLE)
+                    .descriptor(
+                        DescriptorBuilder::new(
+                            CHARACTERISTIC_USER_DESCRIPTION_UUID,
+                            AttPermissions::READABLE,
+                        )
+                        .static_value(b"name".to_vec()),
+                    )
+                    .writable_auxiliaries(),
+            ),
+            Rc::new(gatt_datastore),
+        );
+
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_builder_rejects_writable_user_description_without_writable_auxiliaries() {
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+
+        let res = gatt_db.add_service(
+            ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                CharacteristicBuilder::new(CHARACTERISTIC_TYPE, AttPermissions::READABLE)
+                    .descriptor(DescriptorBuilder::new(
+                        CHARACTERISTIC_USER_DESCRIPTION_UUID,
+                        AttPermissions::READABLE | AttPermissions::WRITABLE_WITH_RESPONSE,
+                    )),
+            ),
+            Rc::new(gatt_datastore),
+        );
+
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_builder_writable_user_description() {
+        // arrange
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        gatt_db
+            .add_service(
+                ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                    CharacteristicBuilder::new(CHARACTERISTIC_TYPE, AttPermissions::READABLE)
+                        .static_value(vec![1])
+                        .writable_user_description("kitchen"),
+                ),
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+        let default = tokio_test::block_on(att_db.read_attribute(AttHandle(5)));
+
+        // act
+        let write = tokio_test::block_on(att_db.write_attribute(AttHandle(5), 0, b"hall"));
+        let written = tokio_test::block_on(att_db.read_attribute(AttHandle(5)));
+
+        // assert: the description follows the extended properties, which set
+        // writable auxiliaries
+        let attributes = att_db.list_attributes();
+        assert_eq!(attributes[3].type_, CHARACTERISTIC_EXTENDED_PROPERTIES_UUID);
+        assert_eq!(
+            tokio_test::block_on(att_db.read_attribute(DESCRIPTOR_HANDLE)),
+            Ok(vec![0x02, 0x00].into())
+        );
+        assert_eq!(attributes[4].type_, CHARACTERISTIC_USER_DESCRIPTION_UUID);
+        assert_eq!(default, Ok(b"kitchen".to_vec().into()));
+        assert_eq!(write, Ok(()));
+        assert_eq!(written, Ok(b"hall".to_vec().into()));
+    }
+
+    #[test]
+    fn test_builder_user_description_persists_across_restart() {
+        // arrange: a client writes the description, then the stack restarts
+        let storage = Rc::new(MockUserDescriptionStorage::new());
+        let service = || {
+            ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                CharacteristicBuilder::new(CHARACTERISTIC_TYPE, AttPermissions::READABLE)
+                    .static_value(vec![1])
+                    .writable_user_description("kitchen"),
+            )
+        };
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        gatt_db.set_user_description_storage(storage.clone()).unwrap();
+        let (gatt_datastore, _) = MockDatastore::new();
+        gatt_db.add_service(service(), Rc::new(gatt_datastore)).unwrap();
+        tokio_test::block_on(gatt_db.get_att_database(TCB_IDX).write_attribute(
+            AttHandle(5),
+            0,
+            b"hall",
+        ))
+        .unwrap();
+
+        // act
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        gatt_db.set_user_description_storage(storage).unwrap();
+        let (gatt_datastore, _) = MockDatastore::new();
+        gatt_db.add_service(service(), Rc::new(gatt_datastore)).unwrap();
+        let description =
+            tokio_test::block_on(gatt_db.get_att_database(TCB_IDX).read_attribute(AttHandle(5)));
+
+        // assert
+        assert_eq!(description, Ok(b"hall".to_vec().into()));
+    }
+
+    #[test]
+    fn test_user_description_storage_must_precede_services() {
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        gatt_db
+            .add_service(
+                ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                    CharacteristicBuilder::new(CHARACTERISTIC_TYPE, AttPermissions::READABLE)
+                        .static_value(vec![1])
+                        .writable_user_description("kitchen"),
+                ),
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+
+        let res = gatt_db.set_user_description_storage(Rc::new(MockUserDescriptionStorage::new()));
+
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_builder_rejects_duplicate_user_description() {
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+
+        let res = gatt_db.add_service(
+            ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                CharacteristicBuilder::new(CHARACTERISTIC_TYPE, AttPermissions::READABLE)
+                    .descriptor(
+                        DescriptorBuilder::new(
+                            CHARACTERISTIC_USER_DESCRIPTION_UUID,
+                            AttPermissions::READABLE,
+                        )
+                        .static_value(b"name".to_vec()),
+                    )
+                    .writable_user_description("kitchen"),
+            ),
+            Rc::new(gatt_datastore),
+        );
+
+        assert!(res.is_err());
+    }
+
+    const TEMPERATURE_FORMAT: PresentationFormat = PresentationFormat {
+        format: 0x0E,
+        exponent: -2,
+        unit: 0x272F,
+        namespace: 0x01,
+        description: 0x0106,
+    };
+    const HUMIDITY_FORMAT: PresentationFormat = PresentationFormat {
+        format: 0x06,
+        exponent: 0,
+        unit: 0x27AD,
+        namespace: 0x01,
+        description: 0x0000,
+    };
+
+    #[test]
+    fn test_builder_single_presentation_format() {
+        // arrange
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+
+        // act
+        gatt_db
+            .add_service(
+                ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                    CharacteristicBuilder::new(CHARACTERISTIC_TYPE, AttPermissions::READABLE)
+                        .presentation_format(TEMPERATURE_FORMAT),
+                ),
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+
+        // assert: no aggregate format is needed
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+        let format = tokio_test::block_on(att_db.read_attribute(DESCRIPTOR_HANDLE));
+        let attributes = att_db.list_attributes();
+        assert_eq!(attributes.len(), 4);
+        assert_eq!(attributes[3].type_, CHARACTERISTIC_PRESENTATION_FORMAT_UUID);
+        assert_eq!(format, Ok(vec![0x0E, 0xFE, 0x2F, 0x27, 0x01, 0x06, 0x01].into()));
+    }
+
+    #[test]
+    fn test_builder_aggregate_format_lists_presentation_formats() {
+        // arrange
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+
+        // act
+        gatt_db
+            .add_service(
+                ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                    CharacteristicBuilder::new(CHARACTERISTIC_TYPE, AttPermissions::READABLE)
+                        .descriptor(
+                            DescriptorBuilder::new(
+                                CHARACTERISTIC_USER_DESCRIPTION_UUID,
+                                AttPermissions::READABLE,
+                            )
+                            .static_value(b"climate".to_vec()),
+                        )
+                        .presentation_format(TEMPERATURE_FORMAT)
+                        .presentation_format(HUMIDITY_FORMAT),
+                ),
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+
+        // assert: the formats follow the explicit descriptor, then the aggregate lists them
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+        let humidity = tokio_test::block_on(att_db.read_attribute(AttHandle(6)));
+        let aggregate = tokio_test::block_on(att_db.read_attribute(AttHandle(7)));
+        let attributes = att_db.list_attributes();
+        assert_eq!(attributes.len(), 7);
+        assert_eq!(attributes[3].type_, CHARACTERISTIC_USER_DESCRIPTION_UUID);
+        assert_eq!(attributes[4].type_, CHARACTERISTIC_PRESENTATION_FORMAT_UUID);
+        assert_eq!(attributes[5].type_, CHARACTERISTIC_PRESENTATION_FORMAT_UUID);
+        assert_eq!(attributes[6].type_, CHARACTERISTIC_AGGREGATE_FORMAT_UUID);
+        assert_eq!(humidity, Ok(vec![0x06, 0x00, 0xAD, 0x27, 0x01, 0x00, 0x00].into()));
+        assert_eq!(aggregate, Ok(vec![0x05, 0x00, 0x06, 0x00].into()));
+    }
+
+    #[test]
+    fn test_builder_presentation_formats_precede_managed_cccd() {
+        // arrange
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+
+        // act
+        gatt_db
+            .add_service(
+                ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                    CharacteristicBuilder::new(
+                        CHARACTERISTIC_TYPE,
+                        AttPermissions::READABLE | AttPermissions::NOTIFY,
+                    )
+                    .presentation_format(TEMPERATURE_FORMAT)
+                    .presentation_format(HUMIDITY_FORMAT),
+                ),
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+
+        // assert
+        let attributes = gatt_db.get_att_database(TCB_IDX).list_attributes();
+        assert_eq!(attributes.len(), 7);
+        assert_eq!(attributes[5].type_, CHARACTERISTIC_AGGREGATE_FORMAT_UUID);
+        assert_eq!(attributes[6].type_, CLIENT_CHARACTERISTIC_CONFIGURATION_UUID);
+    }
+
+    #[test]
+    fn test_builder_rejects_mixed_presentation_formats() {
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+
+        let res = gatt_db.add_service(
+            ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                CharacteristicBuilder::new(CHARACTERISTIC_TYPE, AttPermissions::READABLE)
+                    .descriptor(
+                        DescriptorBuilder::new(
+                            CHARACTERISTIC_PRESENTATION_FORMAT_UUID,
+                            AttPermissions::READABLE,
+                        )
+                        .static_value(vec![0x06, 0x00, 0xAD, 0x27, 0x01, 0x00, 0x00]),
+                    )
+                    .presentation_format(TEMPERATURE_FORMAT),
+            ),
+            Rc::new(gatt_datastore),
+        );
+
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_user_description_write_requires_writable_auxiliaries() {
+        // arrange: a writable user description without extended properties
+        let (gatt_datastore, mut data_evts) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        gatt_db
+            .add_service_with_handles(
+                GattServiceWithHandle {
+                    handle: SERVICE_HANDLE,
+                    type_: SERVICE_TYPE,
+                    characteristics: vec![GattCharacteristicWithHandle {
+                        handle: CHARACTERISTIC_VALUE_HANDLE,
+                        type_: CHARACTERISTIC_TYPE,
+                        permissions: AttPermissions::READABLE,
+                        descriptors: vec![GattDescriptorWithHandle {
+                            handle: DESCRIPTOR_HANDLE,
+                            type_: CHARACTERISTIC_USER_DESCRIPTION_UUID,
+                            permissions: AttPermissions::READABLE
+                                | AttPermissions::WRITABLE_WITH_RESPONSE,
+                        }],
+                    }],
+                },
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+
+        // act
+        let res = tokio_test::block_on(gatt_db.get_att_database(TCB_IDX).write_attribute(
+            DESCRIPTOR_HANDLE,
+            0,
+            b"hall",
+        ));
+
+        // assert: the write never reached the datastore
+        assert_eq!(res, Err(AttErrorCode::WRITE_NOT_PERMITTED.into()));
+        assert_eq!(data_evts.try_recv().unwrap_err(), TryRecvError::Empty);
+    }
+
+    #[test]
+    fn test_builder_rejects_writable_static_value() {
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+
+        let res = gatt_db.add_service(
+            ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                CharacteristicBuilder::new(
+                    CHARACTERISTIC_TYPE,
+                    AttPermissions::READABLE | AttPermissions::WRITABLE_WITH_RESPONSE,
+                )
+                .static_value(vec![1]),
+            ),
+            Rc::new(gatt_datastore),
+        );
+
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_builder_validator_rejects_invalid_write() {
+        // arrange
+        let (gatt_datastore, mut data_rx) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        gatt_db
+            .add_service(
+                ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                    CharacteristicBuilder::new(
+                        CHARACTERISTIC_TYPE,
+                        AttPermissions::WRITABLE_WITH_RESPONSE
+                            | AttPermissions::WRITABLE_WITHOUT_RESPONSE,
+                    )
+                    .validator(WriteValidator::length(1..=1)),
+                ),
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act
+        let res = tokio_test::block_on(att_db.write_attribute(AttHandle(3), 0, &[1, 2]));
+        att_db.write_no_response_attribute(AttHandle(3), &[1, 2]);
+
+        // assert: neither write reached the datastore
+        assert_eq!(res, Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH.into()));
+        assert_eq!(data_rx.try_recv().unwrap_err(), TryRecvError::Empty);
+    }
+
+    #[test]
+    fn test_builder_validator_passes_valid_write() {
+        // arrange
+        let (gatt_datastore, mut data_rx) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        gatt_db
+            .add_service(
+                ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                    CharacteristicBuilder::new(
+                        CHARACTERISTIC_TYPE,
+                        AttPermissions::WRITABLE_WITH_RESPONSE,
+                    )
+                    .validator(WriteValidator::one_of(vec![vec![1]])),
+                ),
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act
+        let (data, res) = tokio_test::block_on(async {
+            join!(
+                async {
+                    let MockDatastoreEvents::Write(_, AttHandle(3), _, data, reply) =
+                        data_rx.recv().await.unwrap()
+                    else {
+                        unreachable!();
+                    };
+                    reply.send(Ok(())).unwrap();
+                    data
+                },
+                att_db.write_attribute(AttHandle(3), 0, &[1])
+            )
+        });
+
+        // assert
+        assert_eq!(data, [1]);
+        assert_eq!(res, Ok(()));
+    }
+
+    #[test]
+    fn test_builder_validated_write_at_offset_rejected() {
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        gatt_db
+            .add_service(
+                ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                    CharacteristicBuilder::new(
+                        CHARACTERISTIC_TYPE,
+                        AttPermissions::WRITABLE_WITH_RESPONSE,
+                    )
+                    .validator(WriteValidator::length(0..=10)),
+                ),
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        let res = tokio_test::block_on(att_db.write_attribute(AttHandle(3), 1, &[1]));
+
+        assert_eq!(res, Err(AttErrorCode::INVALID_OFFSET.into()));
+    }
+
+    #[test]
+    fn test_builder_rejects_validator_without_write() {
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+
+        let res = gatt_db.add_service(
+            ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                CharacteristicBuilder::new(CHARACTERISTIC_TYPE, AttPermissions::READABLE)
+                    .validator(WriteValidator::length(1..=1)),
+            ),
+            Rc::new(gatt_datastore),
+        );
+
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_builder_coalesced_notifications() {
+        // arrange
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+
+        // act: add a characteristic whose notifications are coalesced, and one
+        // whose notifications are not
+        let token = gatt_db
+            .add_service(
+                ServiceBuilder::new(SERVICE_TYPE)
+                    .characteristic(
+                        CharacteristicBuilder::new(CHARACTERISTIC_TYPE, AttPermissions::NOTIFY)
+                            .coalesce_notifications(),
+                    )
+                    .characteristic(CharacteristicBuilder::new(
+                        CHARACTERISTIC_TYPE,
+                        AttPermissions::NOTIFY,
+                    )),
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+
+        // assert: (each is followed by its managed CCCD)
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+        assert!(att_db.coalesces_notifications(AttHandle(token.handle().0 + 2)));
+        assert!(!att_db.coalesces_notifications(AttHandle(token.handle().0 + 5)));
+    }
+
+    #[test]
+    fn test_builder_rejects_coalescing_without_notify() {
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+
+        let res = gatt_db.add_service(
+            ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                CharacteristicBuilder::new(CHARACTERISTIC_TYPE, AttPermissions::READABLE)
+                    .coalesce_notifications(),
+            ),
+            Rc::new(gatt_datastore),
+        );
+
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_builder_rejects_key_size_without_encryption() {
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+
+        let res = gatt_db.add_service(
+            ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                CharacteristicBuilder::new(CHARACTERISTIC_TYPE, AttPermissions::READABLE)
+                    .min_encryption_key_size(16),
+            ),
+            Rc::new(gatt_datastore),
+        );
+
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_builder_rejects_invalid_key_size() {
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+
+        let res = gatt_db.add_service(
+            ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                CharacteristicBuilder::new(
+                    CHARACTERISTIC_TYPE,
+                    AttPermissions::READABLE | AttPermissions::ENCRYPTION_REQUIRED,
+                )
+                .min_encryption_key_size(17),
+            ),
+            Rc::new(gatt_datastore),
+        );
+
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_builder_rejects_declaration_type() {
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+
+        let res = gatt_db.add_service(
+            ServiceBuilder::new(SERVICE_TYPE).characteristic(CharacteristicBuilder::new(
+                CHARACTERISTIC_UUID,
+                AttPermissions::READABLE,
+            )),
+            Rc::new(gatt_datastore),
+        );
+
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_remove_service_by_token() {
+        // arrange
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        let token = gatt_db
+            .add_service(
+                ServiceBuilder::new(SERVICE_TYPE).characteristic(CharacteristicBuilder::new(
+                    CHARACTERISTIC_TYPE,
+                    AttPermissions::READABLE,
+                )),
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+
+        // act
+        gatt_db.remove_service(token).unwrap();
+
+        // assert
+        assert!(gatt_db.get_att_database(TCB_IDX).list_attributes().is_empty());
+    }
+
+    #[test]
+    fn test_remove_service_by_stale_token() {
+        // arrange: a service that was removed by handle, after which another
+        // service was added
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_datastore = Rc::new(gatt_datastore);
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        let token =
+            gatt_db.add_service(ServiceBuilder::new(SERVICE_TYPE), gatt_datastore.clone()).unwrap();
+        gatt_db.remove_service_at_handle(token.handle()).unwrap();
+        add_service_with_single_characteristic(&gatt_db, AttHandle(2));
+
+        // act
+        let res = gatt_db.remove_service(token);
+
+        // assert: the other service was not affected
+        assert!(res.is_err());
+        assert_eq!(gatt_db.get_att_database(TCB_IDX).list_attributes().len(), 3);
+    }
+
+    fn add_service_with_writable_characteristic(
+        gatt_db: &GattDatabase,
+        datastore: MockDatastore,
+    ) -> ServiceToken {
+        gatt_db
+            .add_service(
+                ServiceBuilder::new(SERVICE_TYPE).characteristic(CharacteristicBuilder::new(
+                    CHARACTERISTIC_TYPE,
+                    AttPermissions::READABLE | AttPermissions::WRITABLE_WITH_RESPONSE,
+                )),
+                Rc::new(datastore),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn test_read_completing_after_service_removal() {
+        // arrange
+        let (gatt_datastore, mut data_rx) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        let token = add_service_with_writable_characteristic(&gatt_db, gatt_datastore);
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act: remove the service while a read is in flight, then complete the read
+        let (_, value) = tokio_test::block_on(async {
+            join!(
+                async {
+                    let MockDatastoreEvents::Read(_, _, _, reply) = data_rx.recv().await.unwrap()
+                    else {
+                        unreachable!()
+                    };
+                    gatt_db.remove_service(token).unwrap();
+                    reply.send(Ok(vec![1, 2])).unwrap();
+                },
+                att_db.read_attribute(CHARACTERISTIC_VALUE_HANDLE)
+            )
+        });
+
+        // assert: the read failed
+        assert_eq!(value, Err(AttErrorCode::INVALID_HANDLE.into()));
+    }
+
+    #[test]
+    fn test_read_completing_after_service_replaced() {
+        // arrange
+        let (gatt_datastore, mut data_rx) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        let token = add_service_with_writable_characteristic(&gatt_db, gatt_datastore);
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act: replace the service with an identical one while a read is in flight,
+        // then complete the read
+        let (_, value) = tokio_test::block_on(async {
+            join!(
+                async {
+                    let MockDatastoreEvents::Read(_, _, _, reply) = data_rx.recv().await.unwrap()
+                    else {
+                        unreachable!()
+                    };
+                    gatt_db.remove_service(token).unwrap();
+                    let (gatt_datastore, _) = MockDatastore::new();
+                    let token = add_service_with_writable_characteristic(&gatt_db, gatt_datastore);
+                    assert_eq!(token.handle(), SERVICE_HANDLE);
+                    reply.send(Ok(vec![1, 2])).unwrap();
+                },
+                att_db.read_attribute(CHARACTERISTIC_VALUE_HANDLE)
+            )
+        });
+
+        // assert: the read failed, since it was of the removed service
+        assert_eq!(value, Err(AttErrorCode::INVALID_HANDLE.into()));
+    }
+
+    #[test]
+    fn test_write_completing_after_service_removal() {
+        // arrange
+        let (gatt_datastore, mut data_rx) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        let token = add_service_with_writable_characteristic(&gatt_db, gatt_datastore);
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act: remove the service while a write is in flight, then complete the write
+        let (_, res) = tokio_test::block_on(async {
+            join!(
+                async {
+                    let MockDatastoreEvents::Write(_, _, _, _, reply) =
+                        data_rx.recv().await.unwrap()
+                    else {
+                        unreachable!()
+                    };
+                    gatt_db.remove_service(token).unwrap();
+                    reply.send(Ok(())).unwrap();
+                },
+                att_db.write_attribute(CHARACTERISTIC_VALUE_HANDLE, 0, &[1, 2])
+            )
+        });
+
+        // assert: the write failed
+        assert_eq!(res, Err(AttErrorCode::INVALID_HANDLE.into()));
+    }
+
+    #[test]
+    fn test_read_of_other_service_unaffected_by_removal() {
+        // arrange: two services
+        let (gatt_datastore, mut data_rx) = MockDatastore::new();
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        add_service_with_writable_characteristic(&gatt_db, gatt_datastore);
+        let (gatt_datastore, _) = MockDatastore::new();
+        let other = add_service_with_writable_characteristic(&gatt_db, gatt_datastore);
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act: remove the second service while a read of the first is in flight
+        let (_, value) = tokio_test::block_on(async {
+            join!(
+                async {
+                    let MockDatastoreEvents::Read(_, _, _, reply) = data_rx.recv().await.unwrap()
+                    else {
+                        unreachable!()
+                    };
+                    gatt_db.remove_service(other).unwrap();
+                    reply.send(Ok(vec![1, 2])).unwrap();
+                },
+                att_db.read_attribute(CHARACTERISTIC_VALUE_HANDLE)
+            )
+        });
+
+        // assert: the read succeeded
+        assert_eq!(value, Ok(vec![1, 2].into()));
+    }
+
+    #[cfg(not(feature = "le_only"))]
+    #[test]
+    fn test_service_restricted_to_transport() {
+        // arrange: an unrestricted service, followed by one visible only over LE
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_datastore = Rc::new(gatt_datastore);
+        gatt_db.add_service(service_with_characteristics(1), gatt_datastore.clone()).unwrap();
+        let restricted = gatt_db
+            .add_service(service_with_characteristics(1).transport(Transport::Le), gatt_datastore)
+            .unwrap()
+            .handle();
+        let le_db = gatt_db.get_att_database(TCB_IDX);
+        let br_edr_db = gatt_db.get_att_database_over(TransportIndex(2), Transport::BrEdr);
+
+        // act
+        let le_attributes = le_db.list_attributes();
+        let br_edr_attributes = br_edr_db.list_attributes();
+
+        // assert: only the LE client sees the restricted service
+        assert_eq!(le_attributes.len(), 6);
+        assert_eq!(br_edr_attributes.len(), 3);
+        assert!(br_edr_attributes.iter().all(|attr| attr.handle < restricted));
+        assert_eq!(br_edr_db.attributes_in_range(restricted, AttHandle(0xFFFF), None), vec![]);
+    }
+
+    #[cfg(not(feature = "le_only"))]
+    #[test]
+    fn test_service_restricted_to_transport_is_inaccessible() {
+        // arrange: a service visible only over LE, with a static characteristic
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        let (gatt_datastore, _) = MockDatastore::new();
+        let restricted = gatt_db
+            .add_service(
+                ServiceBuilder::new(SERVICE_TYPE)
+                    .characteristic(
+                        CharacteristicBuilder::new(CHARACTERISTIC_TYPE, AttPermissions::READABLE)
+                            .static_value(vec![1, 2]),
+                    )
+                    .transport(Transport::Le),
+                Rc::new(gatt_datastore),
+            )
+            .unwrap()
+            .handle();
+        let value_handle = AttHandle(restricted.0 + 2);
+        let le_db = gatt_db.get_att_database(TCB_IDX);
+        let br_edr_db = gatt_db.get_att_database_over(TransportIndex(2), Transport::BrEdr);
+
+        // act
+        let le_value = tokio_test::block_on(le_db.read_attribute(value_handle));
+        let br_edr_value = tokio_test::block_on(br_edr_db.read_attribute(value_handle));
+        let le_write = tokio_test::block_on(le_db.write_attribute(value_handle, 0, &[3]));
+        let br_edr_write = tokio_test::block_on(br_edr_db.write_attribute(value_handle, 0, &[3]));
+
+        // assert: over BR/EDR, the handle does not exist
+        assert_eq!(le_value, Ok(vec![1, 2].into()));
+        assert_eq!(le_write, Err(AttErrorCode::WRITE_NOT_PERMITTED.into()));
+        assert_eq!(br_edr_value, Err(AttErrorCode::INVALID_HANDLE.into()));
+        assert_eq!(br_edr_write, Err(AttErrorCode::INVALID_HANDLE.into()));
+    }
+
+    fn add_service_for_schema(gatt_db: &GattDatabase) {
+        let (gatt_datastore, _) = MockDatastore::new();
+        gatt_db
+            .add_service(
+                ServiceBuilder::new(SERVICE_TYPE)
+                    .characteristic(
+                        CharacteristicBuilder::new(CHARACTERISTIC_TYPE, AttPermissions::READABLE)
+                            .static_value(vec![1])
+                            .writable_user_description("kitchen"),
+                    )
+                    .characteristic(CharacteristicBuilder::new(
+                        DESCRIPTOR_TYPE,
+                        AttPermissions::READABLE | AttPermissions::NOTIFY,
+                    ))
+                    .transport(Transport::Le),
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_to_schema() {
+        // arrange
+        let gatt_db = GattDatabase::new();
+        add_service_for_schema(&gatt_db);
+
+        // act
+        let schema = gatt_db.to_schema();
+
+        // assert
+        let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        assert_eq!(
+            schema,
+            DatabaseSchema {
+                services: vec![ServiceSchema {
+                    handle: 1,
+                    uuid: "00001234-0000-1000-8000-00805f9b34fb".into(),
+                    transport: Some(TransportSchema::Le),
+                    characteristics: vec![
+                        CharacteristicSchema {
+                            handle: 3,
+                            uuid: "00005678-0000-1000-8000-00805f9b34fb".into(),
+                            // readable, with extended properties
+                            properties: 0x82,
+                            permissions: names(&["READABLE"]),
+                            value: ValueSchema::Static(vec![1]),
+                            min_encryption_key_size: None,
+                            descriptors: vec![
+                                DescriptorSchema {
+                                    handle: 4,
+                                    uuid: "00002900-0000-1000-8000-00805f9b34fb".into(),
+                                    permissions: names(&["READABLE"]),
+                                    value: ValueSchema::Static(vec![0x02, 0x00]),
+                                },
+                                DescriptorSchema {
+                                    handle: 5,
+                                    uuid: "00002901-0000-1000-8000-00805f9b34fb".into(),
+                                    permissions: names(&["READABLE", "WRITABLE_WITH_RESPONSE"]),
+                                    value: ValueSchema::UserDescription(b"kitchen".to_vec()),
+                                },
+                            ],
+                        },
+                        CharacteristicSchema {
+                            handle: 7,
+                            uuid: "00009abc-0000-1000-8000-00805f9b34fb".into(),
+                            // readable and notifiable
+                            properties: 0x12,
+                            permissions: names(&["READABLE", "NOTIFY"]),
+                            value: ValueSchema::Dynamic,
+                            min_encryption_key_size: None,
+                            descriptors: vec![DescriptorSchema {
+                                handle: 8,
+                                uuid: "00002902-0000-1000-8000-00805f9b34fb".into(),
+                                permissions: names(&["READABLE", "WRITABLE_WITH_RESPONSE"]),
+                                value: ValueSchema::ClientConfiguration,
+                            }],
+                        },
+                    ],
+                }],
+            }
+        );
+    }
+
+    #[test]
+    fn test_from_schema_restores_layout() {
+        // arrange: a database whose user description was written
+        let original = SharedBox::new(GattDatabase::new());
+        add_service_for_schema(&original);
+        let att_db = original.get_att_database(TCB_IDX);
+        tokio_test::block_on(att_db.write_attribute(AttHandle(5), 0, b"hall")).unwrap();
+        let schema = original.to_schema();
+        let (gatt_datastore, _) = MockDatastore::new();
+        let restored = SharedBox::new(GattDatabase::new());
+
+        // act
+        restored.from_schema(&schema, Rc::new(gatt_datastore)).unwrap();
+
+        // assert: the attributes are back at the same handles, with the same
+        // values and declarations
+        assert_eq!(restored.to_schema(), schema);
+        let restored_db = restored.get_att_database(TCB_IDX);
+        assert_eq!(restored_db.list_attributes(), att_db.list_attributes());
+        for handle in [2, 3, 4, 5, 6] {
+            assert_eq!(
+                tokio_test::block_on(restored_db.read_attribute(AttHandle(handle))),
+                tokio_test::block_on(att_db.read_attribute(AttHandle(handle)))
+            );
+        }
+        assert_eq!(
+            restored.robust_caching().borrow().database_hash(),
+            original.robust_caching().borrow().database_hash()
+        );
+    }
+
+    #[test]
+    fn test_from_schema_adds_nothing_on_failure() {
+        // arrange: a schema whose second service overlaps the first
+        let gatt_db = GattDatabase::new();
+        add_service_for_schema(&gatt_db);
+        let mut schema = gatt_db.to_schema();
+        let mut overlapping = schema.services[0].clone();
+        overlapping.handle = 6;
+        overlapping.characteristics.truncate(1);
+        overlapping.characteristics[0].handle = 8;
+        overlapping.characteristics[0].descriptors.clear();
+        schema.services.push(overlapping);
+        let (gatt_datastore, _) = MockDatastore::new();
+        let restored = GattDatabase::new();
+
+        // act
+        let result = restored.from_schema(&schema, Rc::new(gatt_datastore));
+
+        // assert
+        assert!(result.is_err());
+        assert!(restored.services().is_empty());
+    }
+
+    #[test]
+    fn test_from_schema_rejects_unknown_permission() {
+        // arrange
+        let gatt_db = GattDatabase::new();
+        add_service_for_schema(&gatt_db);
+        let mut schema = gatt_db.to_schema();
+        schema.services[0].characteristics[0].permissions.push("READ".into());
+        let (gatt_datastore, _) = MockDatastore::new();
+
+        // act
+        let result = GattDatabase::new().from_schema(&schema, Rc::new(gatt_datastore));
+
+        // assert
+        assert!(result.is_err());
+    }
 }
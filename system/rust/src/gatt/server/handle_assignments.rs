@@ -0,0 +1,605 @@
+//! This module remembers the handles assigned to the services added with
+//! GattDatabase::add_service(), so that the same services get the same handles
+//! after the stack restarts. Bonded clients cache the handles of the database,
+//! so if they moved, the clients would access the wrong attributes.
+//!
+//! Each service is identified by its type, and by its instance among the
+//! services of that type (preferably one previously assigned to a service with
+//! the same layout, that was still in the database before the restart, or else
+//! the first one not in use). A service keeps its
+//! previous handles only if its layout (i.e. its characteristics and
+//! descriptors) is unchanged and those handles are still free. Otherwise it is placed anew,
+//! and the affected handles are reported as changed since the restart, so
+//! that bonded clients can be told through Service Changed.
+//!
+//! A HandleAllocationPolicy may set aside spare handles after each service
+//! placed anew, which other services stay clear of. A service whose layout
+//! changed then keeps its first handle as long as it still fits in the handles
+//! set aside for it, so that only its own handles change, rather than those of
+//! every service placed after it. The handles set aside are persisted with the
+//! assignment, so they outlive a change in the policy.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ops::RangeInclusive,
+    rc::Rc,
+};
+
+use log::{info, warn};
+
+use crate::{core::uuid::Uuid, gatt::ids::AttHandle, packets::Uuid128Builder};
+
+/// The version of the serialized format, to be bumped on incompatible changes
+const SERIALIZATION_VERSION: u8 = 3;
+
+/// The version of the serialized format before the services in the database
+/// were told apart from those removed, which is still restored
+const SERIALIZATION_VERSION_WITHOUT_REMOVALS: u8 = 2;
+
+/// The version of the serialized format before the handles set aside for each
+/// service were persisted, which is still restored
+const SERIALIZATION_VERSION_WITHOUT_RESERVATIONS: u8 = 1;
+
+/// Persists the serialized handle assignments of a single GATT server. An
+/// instance of this trait must be attached to the HandleAssignments of the
+/// database before any services are added.
+pub trait HandleAssignmentStorage {
+    /// Load the handle assignments, if any were stored
+    fn load(&self) -> Option<Vec<u8>>;
+
+    /// Store the handle assignments, replacing any previous ones
+    fn store(&self, data: Vec<u8>);
+}
+
+/// Decides how many handles to set aside for a service placed anew
+pub trait HandleAllocationPolicy {
+    /// The number of handles to set aside for a service that needs
+    /// handle_count of them (at least handle_count). If that many free
+    /// handles cannot be found, the service only gets handle_count.
+    fn reserved_handle_count(&self, handle_count: usize) -> usize;
+}
+
+/// Packs services next to each other, for the smallest database. This is the
+/// default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CompactAllocation;
+
+impl HandleAllocationPolicy for CompactAllocation {
+    fn reserved_handle_count(&self, handle_count: usize) -> usize {
+        handle_count
+    }
+}
+
+/// Sets aside spare handles after each service, so that it can grow by that
+/// many handles without moving
+#[derive(Clone, Copy, Debug)]
+pub struct GapReservingAllocation {
+    /// The number of spare handles after each service
+    pub spare_handles: usize,
+}
+
+impl HandleAllocationPolicy for GapReservingAllocation {
+    fn reserved_handle_count(&self, handle_count: usize) -> usize {
+        handle_count + self.spare_handles
+    }
+}
+
+/// A digest of the attributes of a service, which changes whenever clients
+/// would have to rediscover it
+pub type ServiceLayout = [u8; 16];
+
+/// Identifies a service across restarts
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ServiceKey {
+    /// The type of the service
+    pub type_: Uuid,
+    /// The index of the service among those of the same type
+    pub instance: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Assignment {
+    handle: AttHandle,
+    handle_count: u16,
+    /// The number of handles set aside for the service, from its first one
+    /// (at least handle_count)
+    reserved_count: u16,
+    layout: ServiceLayout,
+}
+
+impl Assignment {
+    fn range(&self) -> RangeInclusive<AttHandle> {
+        self.handle..=AttHandle(self.handle.0 + (self.handle_count - 1))
+    }
+
+    fn reserved_range(&self) -> RangeInclusive<AttHandle> {
+        self.handle..=AttHandle(self.handle.0 + (self.reserved_count - 1))
+    }
+
+    /// The spare handles set aside after the service, if any
+    fn spare_range(&self) -> Option<RangeInclusive<AttHandle>> {
+        (self.reserved_count > self.handle_count).then(|| {
+            AttHandle(self.handle.0 + self.handle_count)
+                ..=AttHandle(self.handle.0 + (self.reserved_count - 1))
+        })
+    }
+}
+
+/// The handles assigned to the services of a GattDatabase, as persisted
+/// across restarts
+#[derive(Default)]
+pub struct HandleAssignments {
+    storage: Option<Rc<dyn HandleAssignmentStorage>>,
+    /// The policy for services placed anew (CompactAllocation if unset)
+    policy: Option<Rc<dyn HandleAllocationPolicy>>,
+    assignments: HashMap<ServiceKey, Assignment>,
+    /// The services currently in the database, by handle
+    in_use: HashMap<AttHandle, ServiceKey>,
+    /// The services that were in the database when the assignments were last
+    /// stored before the restart, rather than removed
+    in_use_before_restart: HashSet<ServiceKey>,
+    changed: Option<RangeInclusive<AttHandle>>,
+}
+
+impl HandleAssignments {
+    /// Restore the assignments from the given storage, to which they are then
+    /// persisted whenever a service is added. Stored assignments that cannot be
+    /// parsed are discarded.
+    pub fn attach_storage(&mut self, storage: Rc<dyn HandleAssignmentStorage>) {
+        (self.assignments, self.in_use_before_restart) = storage
+            .load()
+            .and_then(|data| {
+                let assignments = deserialize(&data);
+                if assignments.is_none() {
+                    warn!("discarding malformed handle assignments");
+                }
+                assignments
+            })
+            .unwrap_or_default();
+        info!("restored the handles of {} service(s)", self.assignments.len());
+        self.storage = Some(storage);
+    }
+
+    /// Set the policy deciding how many handles to set aside for the services
+    /// placed anew from now on
+    pub fn set_allocation_policy(&mut self, policy: Rc<dyn HandleAllocationPolicy>) {
+        self.policy = Some(policy);
+    }
+
+    /// The number of handles to set aside for a service placed anew, which
+    /// needs handle_count of them
+    pub fn reserved_handle_count(&self, handle_count: usize) -> usize {
+        self.policy
+            .as_ref()
+            .map(|policy| policy.reserved_handle_count(handle_count).max(handle_count))
+            .unwrap_or(handle_count)
+    }
+
+    /// Whether no services have been added since the storage was attached
+    pub fn is_empty(&self) -> bool {
+        self.in_use.is_empty()
+    }
+
+    /// The key of a new service of the given type and layout: the first
+    /// instance not currently in the database that was assigned to a service
+    /// with the same layout (preferably one that had not been removed before
+    /// the restart), if any, or else the first one not in use
+    pub fn key_for(&self, type_: Uuid, layout: ServiceLayout) -> ServiceKey {
+        let in_use = self
+            .in_use
+            .values()
+            .filter(|key| key.type_ == type_)
+            .map(|key| key.instance)
+            .collect::<HashSet<_>>();
+        let same_layout = self
+            .assignments
+            .iter()
+            .filter(|(key, assignment)| {
+                key.type_ == type_ && assignment.layout == layout && !in_use.contains(&key.instance)
+            })
+            .map(|(key, _)| (!self.in_use_before_restart.contains(key), key.instance))
+            .min()
+            .map(|(_, instance)| instance);
+        let instance = same_layout
+            .unwrap_or_else(|| (0..).find(|instance| !in_use.contains(instance)).unwrap());
+        ServiceKey { type_, instance }
+    }
+
+    /// The handle previously assigned to the service, if its layout is
+    /// unchanged
+    pub fn assigned_handle(&self, key: ServiceKey, layout: ServiceLayout) -> Option<AttHandle> {
+        self.assignments
+            .get(&key)
+            .filter(|assignment| assignment.layout == layout)
+            .map(|assignment| assignment.handle)
+    }
+
+    /// The handle previously assigned to the service, and the number of
+    /// handles set aside for it from there, if its layout is unchanged or it
+    /// now needs handle_count handles that still fit in those set aside
+    pub fn assigned_reservation(
+        &self,
+        key: ServiceKey,
+        layout: ServiceLayout,
+        handle_count: usize,
+    ) -> Option<(AttHandle, usize)> {
+        self.assignments
+            .get(&key)
+            .filter(|assignment| {
+                assignment.layout == layout
+                    || handle_count <= usize::from(assignment.reserved_count)
+            })
+            .map(|assignment| (assignment.handle, usize::from(assignment.reserved_count)))
+    }
+
+    /// The handles set aside for services that are not currently in the
+    /// database (other than the given one), and the spare handles set aside
+    /// after those that are, which new services should stay clear of so that
+    /// those services can later get them back (or grow into them)
+    pub fn reserved(&self, except: ServiceKey) -> Vec<RangeInclusive<AttHandle>> {
+        let in_use = self.in_use.values().collect::<HashSet<_>>();
+        self.assignments
+            .iter()
+            .filter(|(key, _)| **key != except)
+            .filter_map(|(key, assignment)| {
+                if in_use.contains(key) {
+                    assignment.spare_range()
+                } else {
+                    Some(assignment.reserved_range())
+                }
+            })
+            .collect()
+    }
+
+    /// A service was added at the given handle, with reserved_count handles
+    /// set aside for it from there. If it previously had other handles (or
+    /// none), both its old and new handles are marked as changed.
+    pub fn on_service_added(
+        &mut self,
+        key: ServiceKey,
+        handle: AttHandle,
+        handle_count: usize,
+        reserved_count: usize,
+        layout: ServiceLayout,
+    ) {
+        let assignment = Assignment {
+            handle,
+            handle_count: handle_count as u16,
+            reserved_count: reserved_count.max(handle_count) as u16,
+            layout,
+        };
+        self.in_use.insert(handle, key);
+        let previous = self.assignments.insert(key, assignment);
+        if previous != Some(assignment) {
+            if let Some(previous) = previous {
+                info!("{key:?} moved from {:?} to {:?}", previous.range(), assignment.range());
+                self.mark_changed(previous.range());
+            }
+            self.mark_changed(assignment.range());
+        }
+        self.store();
+    }
+
+    /// The service at the given handle was removed. Its handles remain
+    /// assigned, in case it is added again.
+    pub fn on_service_removed(&mut self, handle: AttHandle) {
+        if self.in_use.remove(&handle).is_some() {
+            self.store();
+        }
+    }
+
+    /// The range covering every service that could not keep its handles, or
+    /// that was first added, since the storage was attached (if any). Bonded
+    /// clients that have not connected since then may have cached other
+    /// attributes there.
+    pub fn changed(&self) -> Option<RangeInclusive<AttHandle>> {
+        self.storage.as_ref()?;
+        self.changed.clone()
+    }
+
+    fn store(&self) {
+        if let Some(storage) = &self.storage {
+            let in_use = self.in_use.values().collect::<HashSet<_>>();
+            storage.store(serialize(&self.assignments, &in_use));
+        }
+    }
+
+    fn mark_changed(&mut self, range: RangeInclusive<AttHandle>) {
+        self.changed = Some(match self.changed.take() {
+            Some(changed) => *changed.start().min(range.start())..=*changed.end().max(range.end()),
+            None => range,
+        });
+    }
+}
+
+fn serialize(
+    assignments: &HashMap<ServiceKey, Assignment>,
+    in_use: &HashSet<&ServiceKey>,
+) -> Vec<u8> {
+    let mut out = vec![SERIALIZATION_VERSION];
+    out.extend((assignments.len() as u16).to_le_bytes());
+    for (key, assignment) in assignments {
+        out.extend(Uuid128Builder::from(key.type_).data.iter());
+        out.extend(key.instance.to_le_bytes());
+        out.extend(assignment.handle.0.to_le_bytes());
+        out.extend(assignment.handle_count.to_le_bytes());
+        out.extend(assignment.reserved_count.to_le_bytes());
+        out.extend(assignment.layout);
+        out.push(in_use.contains(key).into());
+    }
+    out
+}
+
+/// Restore the assignments, along with the services that were in the database
+/// (which are all of them in the formats without removals)
+fn deserialize(data: &[u8]) -> Option<(HashMap<ServiceKey, Assignment>, HashSet<ServiceKey>)> {
+    let mut reader = Reader(data);
+    let (with_reservations, with_removals) = match reader.u8()? {
+        SERIALIZATION_VERSION => (true, true),
+        SERIALIZATION_VERSION_WITHOUT_REMOVALS => (true, false),
+        SERIALIZATION_VERSION_WITHOUT_RESERVATIONS => (false, false),
+        _ => return None,
+    };
+    let mut assignments = HashMap::new();
+    let mut in_use = HashSet::new();
+    for _ in 0..reader.u16()? {
+        let key = ServiceKey {
+            type_: Uuid::try_from_le_slice(reader.bytes(16)?)?,
+            instance: reader.u16()?,
+        };
+        let handle = AttHandle(reader.u16()?);
+        let handle_count = reader.u16()?;
+        let reserved_count = if with_reservations { reader.u16()? } else { handle_count };
+        let assignment = Assignment {
+            handle,
+            handle_count,
+            reserved_count,
+            layout: reader.bytes(16)?.try_into().ok()?,
+        };
+        let was_in_use = if with_removals {
+            match reader.u8()? {
+                0 => false,
+                1 => true,
+                _ => return None,
+            }
+        } else {
+            true
+        };
+        if assignment.handle.0 == 0
+            || assignment.handle_count == 0
+            || assignment.reserved_count < assignment.handle_count
+            || assignment.handle.0.checked_add(assignment.reserved_count - 1).is_none()
+        {
+            return None;
+        }
+        assignments.insert(key, assignment);
+        if was_in_use {
+            in_use.insert(key);
+        }
+    }
+    if !reader.0.is_empty() {
+        return None;
+    }
+    Some((assignments, in_use))
+}
+
+/// Reads little-endian fields from the front of a buffer
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::gatt::mocks::mock_handle_assignment_storage::MockHandleAssignmentStorage;
+
+    use super::*;
+
+    const SERVICE_TYPE: Uuid = Uuid::new(0x1234);
+    const LAYOUT: ServiceLayout = [1; 16];
+    const ANOTHER_LAYOUT: ServiceLayout = [2; 16];
+
+    fn attached(storage: &Rc<MockHandleAssignmentStorage>) -> HandleAssignments {
+        let mut assignments = HandleAssignments::default();
+        assignments.attach_storage(storage.clone());
+        assignments
+    }
+
+    #[test]
+    fn test_assignment_restored() {
+        // arrange: a service is added, then the stack restarts
+        let storage = Rc::new(MockHandleAssignmentStorage::new());
+        let mut assignments = attached(&storage);
+        let key = assignments.key_for(SERVICE_TYPE, LAYOUT);
+        assignments.on_service_added(key, AttHandle(20), 3, 3, LAYOUT);
+
+        // act
+        let assignments = attached(&storage);
+
+        // assert: the service gets its handle back, unless its layout changed
+        assert_eq!(assignments.assigned_handle(key, LAYOUT), Some(AttHandle(20)));
+        assert_eq!(assignments.assigned_handle(key, ANOTHER_LAYOUT), None);
+        assert!(assignments.reserved(key).is_empty());
+        assert_eq!(
+            assignments.reserved(ServiceKey { type_: SERVICE_TYPE, instance: 1 }),
+            vec![AttHandle(20)..=AttHandle(22)]
+        );
+    }
+
+    #[test]
+    fn test_instances() {
+        let mut assignments = HandleAssignments::default();
+        let first = assignments.key_for(SERVICE_TYPE, LAYOUT);
+        assignments.on_service_added(first, AttHandle(20), 1, 1, LAYOUT);
+        let second = assignments.key_for(SERVICE_TYPE, LAYOUT);
+        assignments.on_service_added(second, AttHandle(30), 1, 1, LAYOUT);
+        assignments.on_service_removed(AttHandle(20));
+
+        assert_eq!(first.instance, 0);
+        assert_eq!(second.instance, 1);
+        assert_eq!(assignments.key_for(SERVICE_TYPE, LAYOUT), first);
+        assert_eq!(assignments.key_for(Uuid::new(0x5678), LAYOUT).instance, 0);
+    }
+
+    #[test]
+    fn test_instance_with_same_layout_preferred() {
+        // arrange: two services of the same type, with different layouts
+        let storage = Rc::new(MockHandleAssignmentStorage::new());
+        let mut assignments = attached(&storage);
+        let first = assignments.key_for(SERVICE_TYPE, LAYOUT);
+        assignments.on_service_added(first, AttHandle(20), 1, 1, LAYOUT);
+        let second = assignments.key_for(SERVICE_TYPE, ANOTHER_LAYOUT);
+        assignments.on_service_added(second, AttHandle(30), 1, 1, ANOTHER_LAYOUT);
+
+        // act: after a restart, they are added in the reverse order
+        let assignments = attached(&storage);
+
+        // assert
+        assert_eq!(assignments.key_for(SERVICE_TYPE, ANOTHER_LAYOUT), second);
+        assert_eq!(assignments.key_for(SERVICE_TYPE, LAYOUT), first);
+    }
+
+    #[test]
+    fn test_instance_removed_before_restart_not_preferred() {
+        // arrange: two services with the same layout, the first of which is
+        // removed before the restart
+        let storage = Rc::new(MockHandleAssignmentStorage::new());
+        let mut assignments = attached(&storage);
+        let first = assignments.key_for(SERVICE_TYPE, LAYOUT);
+        assignments.on_service_added(first, AttHandle(20), 1, 1, LAYOUT);
+        let second = assignments.key_for(SERVICE_TYPE, LAYOUT);
+        assignments.on_service_added(second, AttHandle(30), 1, 1, LAYOUT);
+        assignments.on_service_removed(AttHandle(20));
+
+        // act
+        let assignments = attached(&storage);
+
+        // assert: the remaining service gets its own instance back
+        assert_eq!(assignments.key_for(SERVICE_TYPE, LAYOUT), second);
+    }
+
+    #[test]
+    fn test_unchanged_after_restart() {
+        // arrange
+        let storage = Rc::new(MockHandleAssignmentStorage::new());
+        let mut assignments = attached(&storage);
+        let key = assignments.key_for(SERVICE_TYPE, LAYOUT);
+        assignments.on_service_added(key, AttHandle(20), 3, 3, LAYOUT);
+        assert_eq!(assignments.changed(), Some(AttHandle(20)..=AttHandle(22)));
+
+        // act: the service is added at the same handle after a restart
+        let mut assignments = attached(&storage);
+        assignments.on_service_added(key, AttHandle(20), 3, 3, LAYOUT);
+
+        // assert
+        assert_eq!(assignments.changed(), None);
+    }
+
+    #[test]
+    fn test_moved_after_restart() {
+        // arrange
+        let storage = Rc::new(MockHandleAssignmentStorage::new());
+        let mut assignments = attached(&storage);
+        let key = assignments.key_for(SERVICE_TYPE, LAYOUT);
+        assignments.on_service_added(key, AttHandle(20), 3, 3, LAYOUT);
+
+        // act: the service is added with another layout after a restart
+        let mut assignments = attached(&storage);
+        assignments.on_service_added(key, AttHandle(30), 4, 4, ANOTHER_LAYOUT);
+
+        // assert: both its old and new handles changed, and the new ones persist
+        assert_eq!(assignments.changed(), Some(AttHandle(20)..=AttHandle(33)));
+        assert_eq!(attached(&storage).assigned_handle(key, ANOTHER_LAYOUT), Some(AttHandle(30)));
+    }
+
+    #[test]
+    fn test_reservation_restored() {
+        // arrange: a service is added with spare handles, then the stack restarts
+        let storage = Rc::new(MockHandleAssignmentStorage::new());
+        let mut assignments = attached(&storage);
+        let key = assignments.key_for(SERVICE_TYPE, LAYOUT);
+        assignments.on_service_added(key, AttHandle(20), 3, 5, LAYOUT);
+
+        // act
+        let mut assignments = attached(&storage);
+
+        // assert: the service can grow into its spare handles, which other
+        // services stay clear of (even once it is back)
+        assert_eq!(
+            assignments.assigned_reservation(key, ANOTHER_LAYOUT, 5),
+            Some((AttHandle(20), 5))
+        );
+        assert_eq!(assignments.assigned_reservation(key, ANOTHER_LAYOUT, 6), None);
+        let other = ServiceKey { type_: SERVICE_TYPE, instance: 1 };
+        assert_eq!(assignments.reserved(other), vec![AttHandle(20)..=AttHandle(24)]);
+        assignments.on_service_added(key, AttHandle(20), 3, 5, LAYOUT);
+        assert_eq!(assignments.reserved(other), vec![AttHandle(23)..=AttHandle(24)]);
+    }
+
+    #[test]
+    fn test_assignments_without_reservations_restored() {
+        // arrange: assignments stored before reservations were persisted
+        let storage = Rc::new(MockHandleAssignmentStorage::new());
+        let mut data = vec![SERIALIZATION_VERSION_WITHOUT_RESERVATIONS];
+        data.extend(1u16.to_le_bytes());
+        data.extend(Uuid128Builder::from(SERVICE_TYPE).data.iter());
+        data.extend(0u16.to_le_bytes());
+        data.extend(20u16.to_le_bytes());
+        data.extend(3u16.to_le_bytes());
+        data.extend(LAYOUT);
+        storage.set(data);
+
+        // act
+        let assignments = attached(&storage);
+
+        // assert: no spare handles were set aside
+        let key = ServiceKey { type_: SERVICE_TYPE, instance: 0 };
+        assert_eq!(assignments.assigned_reservation(key, LAYOUT, 3), Some((AttHandle(20), 3)));
+    }
+
+    #[test]
+    fn test_allocation_policy() {
+        let mut assignments = HandleAssignments::default();
+        assert_eq!(assignments.reserved_handle_count(3), 3);
+
+        assignments.set_allocation_policy(Rc::new(GapReservingAllocation { spare_handles: 4 }));
+
+        assert_eq!(assignments.reserved_handle_count(3), 7);
+    }
+
+    #[test]
+    fn test_malformed_storage_discarded() {
+        let storage = Rc::new(MockHandleAssignmentStorage::new());
+        storage.set(vec![SERIALIZATION_VERSION, 1]);
+
+        let assignments = attached(&storage);
+
+        assert!(assignments.reserved(ServiceKey { type_: SERVICE_TYPE, instance: 0 }).is_empty());
+    }
+
+    #[test]
+    fn test_nothing_changed_without_storage() {
+        let mut assignments = HandleAssignments::default();
+        let key = assignments.key_for(SERVICE_TYPE, LAYOUT);
+
+        assignments.on_service_added(key, AttHandle(20), 3, 3, LAYOUT);
+
+        assert_eq!(assignments.changed(), None);
+    }
+}
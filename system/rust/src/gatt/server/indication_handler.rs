@@ -1,20 +1,21 @@
 use std::time::Duration;
 
 use log::{trace, warn};
-use tokio::{
-    sync::mpsc::{self, error::TrySendError},
-    time::timeout,
-};
+use tokio::sync::mpsc::{self, error::TrySendError};
 
 use crate::{
     gatt::ids::AttHandle,
     packets::{AttAttributeDataChild, AttChild, AttHandleValueIndicationBuilder, Serializable},
-    utils::packet::build_att_data,
+    utils::{
+        clock::{timeout, Clock},
+        packet::build_att_data,
+    },
 };
 
 use super::{
     att_database::{AttDatabase, StableAttDatabase},
     att_server_bearer::SendError,
+    client_configuration::ClientConfiguration,
 };
 
 #[derive(Debug)]
@@ -30,10 +31,15 @@ pub enum IndicationError {
     AttributeNotFound,
     /// The indicated attribute does not support indications
     IndicationsNotSupported,
+    /// The client has not subscribed to indications of this attribute
+    ClientNotSubscribed,
     /// Failed to send the outgoing indication packet
     SendError(SendError),
     /// Did not receive a confirmation in the given time (30s)
     ConfirmationTimeout,
+    /// A previous indication on this bearer timed out, so no further
+    /// indications may be sent (Core Spec 5.3 Vol 3F 3.3.3)
+    BearerTimedOut,
     /// The connection was dropped while waiting for a confirmation
     ConnectionDroppedWhileWaitingForConfirmation,
 }
@@ -41,12 +47,13 @@ pub enum IndicationError {
 pub struct IndicationHandler<T> {
     db: T,
     pending_confirmation: mpsc::Receiver<()>,
+    timed_out: bool,
 }
 
 impl<T: AttDatabase> IndicationHandler<T> {
     pub fn new(db: T) -> (Self, ConfirmationWatcher) {
         let (tx, rx) = mpsc::channel(1);
-        (Self { db, pending_confirmation: rx }, ConfirmationWatcher(tx))
+        (Self { db, pending_confirmation: rx, timed_out: false }, ConfirmationWatcher(tx))
     }
 
     pub async fn send(
@@ -54,8 +61,14 @@ impl<T: AttDatabase> IndicationHandler<T> {
         handle: AttHandle,
         data: AttAttributeDataChild,
         mtu: usize,
+        clock: &dyn Clock,
         send_packet: impl FnOnce(AttChild) -> Result<(), SendError>,
     ) -> Result<(), IndicationError> {
+        if self.timed_out {
+            warn!("cannot send indication for {handle:?} since a previous indication timed out");
+            return Err(IndicationError::BearerTimedOut);
+        }
+
         let data_size = data
             .size_in_bits()
             .map_err(SendError::SerializeError)
@@ -78,6 +91,18 @@ impl<T: AttDatabase> IndicationHandler<T> {
             return Err(IndicationError::IndicationsNotSupported);
         }
 
+        if !self
+            .db
+            .client_configuration(handle)
+            .map(|configuration| configuration.contains(ClientConfiguration::INDICATION))
+            .unwrap_or(true)
+        {
+            warn!(
+                "cannot send indication for {handle:?} since the client has not subscribed to it"
+            );
+            return Err(IndicationError::ClientNotSubscribed);
+        }
+
         // flushing any confirmations that arrived before we sent the next indication
         let _ = self.pending_confirmation.try_recv();
 
@@ -87,7 +112,7 @@ impl<T: AttDatabase> IndicationHandler<T> {
         )
         .map_err(IndicationError::SendError)?;
 
-        match timeout(Duration::from_secs(30), self.pending_confirmation.recv()).await {
+        match timeout(clock, Duration::from_secs(30), self.pending_confirmation.recv()).await {
             Ok(Some(())) => Ok(()),
             Ok(None) => {
                 warn!("connection dropped while waiting for indication confirmation");
@@ -95,6 +120,7 @@ impl<T: AttDatabase> IndicationHandler<T> {
             }
             Err(_) => {
                 warn!("Sent indication but received no response for 30s");
+                self.timed_out = true;
                 Err(IndicationError::ConfirmationTimeout)
             }
         }
@@ -129,7 +155,7 @@ mod test {
             att_database::AttAttribute, gatt_database::AttPermissions,
             test::test_att_db::TestAttDatabase,
         },
-        utils::task::block_on_locally,
+        utils::{clock::TokioClock, task::block_on_locally},
     };
 
     use super::*;
@@ -175,7 +201,7 @@ mod test {
             // act: send an indication
             spawn_local(async move {
                 indication_handler
-                    .send(HANDLE, get_data(), MTU, move |packet| {
+                    .send(HANDLE, get_data(), MTU, &TokioClock, move |packet| {
                         tx.send(packet).unwrap();
                         Ok(())
                     })
@@ -205,7 +231,7 @@ mod test {
 
             // act: send an indication on a nonexistent handle
             let ret = indication_handler
-                .send(NONEXISTENT_HANDLE, get_data(), MTU, move |_| unreachable!())
+                .send(NONEXISTENT_HANDLE, get_data(), MTU, &TokioClock, move |_| unreachable!())
                 .await;
 
             // assert: that we failed with IndicationError::AttributeNotFound
@@ -222,7 +248,7 @@ mod test {
 
             // act: send an indication on an attribute that does not support indications
             let ret = indication_handler
-                .send(NON_INDICATE_HANDLE, get_data(), MTU, move |_| unreachable!())
+                .send(NON_INDICATE_HANDLE, get_data(), MTU, &TokioClock, move |_| unreachable!())
                 .await;
 
             // assert: that we failed with IndicationError::IndicationsNotSupported
@@ -241,7 +267,7 @@ mod test {
             // act: send an indication
             let pending_result = spawn_local(async move {
                 indication_handler
-                    .send(HANDLE, get_data(), MTU, move |packet| {
+                    .send(HANDLE, get_data(), MTU, &TokioClock, move |packet| {
                         tx.send(packet).unwrap();
                         Ok(())
                     })
@@ -267,7 +293,7 @@ mod test {
             // act: send an indication
             let pending_result = spawn_local(async move {
                 indication_handler
-                    .send(HANDLE, get_data(), MTU, move |packet| {
+                    .send(HANDLE, get_data(), MTU, &TokioClock, move |packet| {
                         tx.send(packet).unwrap();
                         Ok(())
                     })
@@ -299,7 +325,7 @@ mod test {
             // act: send an indication
             let pending_result = spawn_local(async move {
                 indication_handler
-                    .send(HANDLE, get_data(), MTU, move |packet| {
+                    .send(HANDLE, get_data(), MTU, &TokioClock, move |packet| {
                         tx.send(packet).unwrap();
                         Ok(())
                     })
@@ -334,7 +360,7 @@ mod test {
             let time_sent = Instant::now();
             let pending_result = spawn_local(async move {
                 indication_handler
-                    .send(HANDLE, get_data(), MTU, move |packet| {
+                    .send(HANDLE, get_data(), MTU, &TokioClock, move |packet| {
                         tx.send(packet).unwrap();
                         Ok(())
                     })
@@ -357,6 +383,27 @@ mod test {
         });
     }
 
+    #[test]
+    fn test_no_indications_after_timeout() {
+        block_on_locally(async move {
+            // arrange: an indication that timed out
+            let (mut indication_handler, confirmation_watcher) =
+                IndicationHandler::new(get_att_database());
+            let res =
+                indication_handler.send(HANDLE, get_data(), MTU, &TokioClock, |_| Ok(())).await;
+            assert!(matches!(res, Err(IndicationError::ConfirmationTimeout)));
+
+            // act: send another indication, even after a late confirmation arrives
+            confirmation_watcher.on_confirmation();
+            let res = indication_handler
+                .send(HANDLE, get_data(), MTU, &TokioClock, move |_| unreachable!())
+                .await;
+
+            // assert: the bearer refuses to send it
+            assert!(matches!(res, Err(IndicationError::BearerTimedOut)));
+        });
+    }
+
     #[test]
     fn test_mtu_exceeds() {
         block_on_locally(async move {
@@ -370,6 +417,7 @@ mod test {
                     HANDLE,
                     AttAttributeDataChild::RawData([1, 2, 3].into()),
                     4,
+                    &TokioClock,
                     move |_| unreachable!(),
                 )
                 .await;
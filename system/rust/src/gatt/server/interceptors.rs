@@ -0,0 +1,452 @@
+//! This module holds the middleware of a GattDatabase: a chain of Interceptors,
+//! each registered against a range of handles, through which the reads and
+//! writes of the attributes in its range pass. An interceptor may observe an
+//! operation (e.g. to log it), modify the value written or the result returned,
+//! or complete the operation itself (e.g. to enforce a policy, or to work
+//! around the quirks of a peer), without involving the datastore.
+//!
+//! The ordering is deterministic:
+//! - Interceptors run in increasing order of the order they were registered
+//!   with, and those with the same order in the order they were registered.
+//! - Their before_*() hooks run in that order, until one completes the
+//!   operation. The after_*() hooks of those that ran then run in the reverse
+//!   order, so that each wraps all the ones after it.
+//!
+//! Interceptors only see the operations that pass the checks of the database:
+//! the permissions and security requirements of the attribute, the
+//! AccessInterceptor, the AuthorizationProvider and the WriteValidator. They
+//! cannot grant an access the database would reject, and the values they
+//! write instead are not validated again. Declarations are never intercepted,
+//! so that clients can still discover all the services.
+
+use std::{borrow::Cow, ops::RangeInclusive, rc::Rc};
+
+use log::info;
+
+use crate::{
+    gatt::ids::{AttHandle, TransportIndex},
+    packets::AttErrorCode,
+};
+
+use super::{
+    att_database::AttAttributeValue,
+    att_error::{AttError, ErrorLayer},
+    authorization::AttributeAccess,
+};
+
+/// An operation on an attribute, as presented to an Interceptor
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OperationContext {
+    /// The transport of the client
+    pub tcb_idx: TransportIndex,
+    /// The attribute being accessed
+    pub handle: AttHandle,
+    /// Whether the attribute is being read or written
+    pub access: AttributeAccess,
+    /// The offset from which the value is read or written
+    pub offset: u32,
+    /// Whether the client awaits a response (i.e. this is not an
+    /// ATT_WRITE_CMD, whose result is dropped)
+    pub expects_response: bool,
+}
+
+/// What an interceptor decides before an operation
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Interception<T> {
+    /// Pass the operation on to the next interceptor (or to the datastore)
+    Proceed,
+    /// Complete the operation with this result, without involving the next
+    /// interceptors or the datastore
+    Complete(Result<T, AttErrorCode>),
+}
+
+/// Observes, modifies or completes the operations on a range of attributes.
+/// Each hook does nothing by default.
+pub trait Interceptor {
+    /// Called before a read (the value is that read from the offset onwards)
+    fn before_read(&self, _context: &OperationContext) -> Interception<Vec<u8>> {
+        Interception::Proceed
+    }
+
+    /// Called once a read has completed, with the result about to be returned
+    fn after_read(&self, _context: &OperationContext, _result: &mut Result<Vec<u8>, AttErrorCode>) {
+    }
+
+    /// Called before a write, with the value about to be written
+    fn before_write(&self, _context: &OperationContext, _value: &mut Vec<u8>) -> Interception<()> {
+        Interception::Proceed
+    }
+
+    /// Called once a write has completed, with the result about to be
+    /// returned. The result of a write command is Ok once it is handed to the
+    /// datastore.
+    fn after_write(&self, _context: &OperationContext, _result: &mut Result<(), AttErrorCode>) {}
+}
+
+struct Entry {
+    range: RangeInclusive<AttHandle>,
+    order: i32,
+    interceptor: Rc<dyn Interceptor>,
+}
+
+/// The interceptors of a GattDatabase, in the order they run
+#[derive(Default)]
+pub struct InterceptorChain {
+    entries: Vec<Entry>,
+}
+
+impl InterceptorChain {
+    /// Constructor, without any interceptor
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pass the operations on the attributes in the given range through the
+    /// interceptor, after those with a lower or equal order
+    pub fn add(
+        &mut self,
+        range: RangeInclusive<AttHandle>,
+        order: i32,
+        interceptor: Rc<dyn Interceptor>,
+    ) {
+        let position = self.entries.partition_point(|entry| entry.order <= order);
+        self.entries.insert(position, Entry { range, order, interceptor });
+    }
+
+    /// Stop passing operations through the interceptor, in all the ranges it
+    /// was added for. Returns whether it was found.
+    pub fn remove(&mut self, interceptor: &Rc<dyn Interceptor>) -> bool {
+        let len = self.entries.len();
+        // only the data pointers are compared, since vtables may be duplicated
+        let target = Rc::as_ptr(interceptor).cast::<()>();
+        self.entries.retain(|entry| Rc::as_ptr(&entry.interceptor).cast::<()>() != target);
+        self.entries.len() != len
+    }
+
+    /// The interceptors of the operations on the given attribute, in the
+    /// order they run
+    pub fn interceptors_for(&self, handle: AttHandle) -> Vec<Rc<dyn Interceptor>> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.range.contains(&handle))
+            .map(|entry| entry.interceptor.clone())
+            .collect()
+    }
+}
+
+/// An operation passing through its interceptors. The before_*() hooks are
+/// run first, then, unless one of them completed it, the operation itself, and
+/// finally the after_*() hooks of the interceptors whose before_*() hook ran.
+pub struct InterceptedOperation {
+    interceptors: Vec<Rc<dyn Interceptor>>,
+    context: OperationContext,
+    ran: usize,
+}
+
+impl InterceptedOperation {
+    /// Constructor, for the given interceptors (in the order they run)
+    pub fn new(interceptors: Vec<Rc<dyn Interceptor>>, context: OperationContext) -> Self {
+        Self { interceptors, context, ran: 0 }
+    }
+
+    /// Whether no interceptor applies to the operation
+    pub fn is_empty(&self) -> bool {
+        self.interceptors.is_empty()
+    }
+
+    /// Run the before_read() hooks. Returns the result of the read, if an
+    /// interceptor completed it.
+    pub fn before_read(&mut self) -> Option<Result<AttAttributeValue, AttError>> {
+        for interceptor in &self.interceptors {
+            self.ran += 1;
+            if let Interception::Complete(result) = interceptor.before_read(&self.context) {
+                return Some(result.map(Into::into).map_err(|code| self.error(code)));
+            }
+        }
+        None
+    }
+
+    /// Run the after_read() hooks on the result of the read
+    pub fn after_read(
+        &self,
+        result: Result<AttAttributeValue, AttError>,
+    ) -> Result<AttAttributeValue, AttError> {
+        if self.ran == 0 {
+            return result;
+        }
+        let mut intercepted = result.as_ref().map(|value| value.to_vec()).map_err(|err| err.code);
+        for interceptor in self.interceptors[..self.ran].iter().rev() {
+            interceptor.after_read(&self.context, &mut intercepted);
+        }
+        match (result, intercepted) {
+            // keep the context of the original result if it is unchanged
+            (Ok(value), Ok(intercepted)) if *value == *intercepted => Ok(value),
+            (Err(err), Err(code)) if err.code == code => Err(err),
+            (_, intercepted) => intercepted.map(Into::into).map_err(|code| self.error(code)),
+        }
+    }
+
+    /// Run the before_write() hooks on the value about to be written, which
+    /// they may replace. Returns the result of the write, if an interceptor
+    /// completed it.
+    pub fn before_write(&mut self, value: &mut Cow<'_, [u8]>) -> Option<Result<(), AttError>> {
+        if self.interceptors.is_empty() {
+            return None;
+        }
+        let mut intercepted = value.to_vec();
+        let mut completed = None;
+        for interceptor in &self.interceptors {
+            self.ran += 1;
+            if let Interception::Complete(result) =
+                interceptor.before_write(&self.context, &mut intercepted)
+            {
+                completed = Some(result.map_err(|code| self.error(code)));
+                break;
+            }
+        }
+        if *intercepted != **value {
+            *value = Cow::Owned(intercepted);
+        }
+        completed
+    }
+
+    /// Run the after_write() hooks on the result of the write
+    pub fn after_write(&self, result: Result<(), AttError>) -> Result<(), AttError> {
+        if self.ran == 0 {
+            return result;
+        }
+        let mut intercepted = result.map_err(|err| err.code);
+        for interceptor in self.interceptors[..self.ran].iter().rev() {
+            interceptor.after_write(&self.context, &mut intercepted);
+        }
+        match (result, intercepted) {
+            (Err(err), Err(code)) if err.code == code => Err(err),
+            (_, intercepted) => intercepted.map_err(|code| self.error(code)),
+        }
+    }
+
+    fn error(&self, code: AttErrorCode) -> AttError {
+        let error = AttError::new(code, ErrorLayer::Interceptor).for_handle(self.context.handle);
+        if self.context.offset != 0 {
+            error.at_offset(self.context.offset)
+        } else {
+            error
+        }
+    }
+}
+
+/// Logs every operation on the attributes it intercepts, with its outcome
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LoggingInterceptor;
+
+impl Interceptor for LoggingInterceptor {
+    fn after_read(&self, context: &OperationContext, result: &mut Result<Vec<u8>, AttErrorCode>) {
+        match result {
+            Ok(value) => info!(
+                "read of {} at offset {} from {} returned {} bytes",
+                context.handle,
+                context.offset,
+                context.tcb_idx,
+                value.len()
+            ),
+            Err(code) => info!(
+                "read of {} at offset {} from {} failed with {code:?}",
+                context.handle, context.offset, context.tcb_idx
+            ),
+        }
+    }
+
+    fn before_write(&self, context: &OperationContext, value: &mut Vec<u8>) -> Interception<()> {
+        info!(
+            "{} of {} bytes to {} at offset {} from {}",
+            if context.expects_response { "write" } else { "write command" },
+            value.len(),
+            context.handle,
+            context.offset,
+            context.tcb_idx
+        );
+        Interception::Proceed
+    }
+
+    fn after_write(&self, context: &OperationContext, result: &mut Result<(), AttErrorCode>) {
+        if let Err(code) = result {
+            info!("write to {} from {} failed with {code:?}", context.handle, context.tcb_idx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    const TCB_IDX: TransportIndex = TransportIndex(1);
+    const HANDLE: AttHandle = AttHandle(3);
+
+    const CONTEXT: OperationContext = OperationContext {
+        tcb_idx: TCB_IDX,
+        handle: HANDLE,
+        access: AttributeAccess::Read,
+        offset: 0,
+        expects_response: true,
+    };
+
+    /// Records its hooks in a shared log, and completes reads if told to
+    struct RecordingInterceptor {
+        name: &'static str,
+        log: Rc<RefCell<Vec<String>>>,
+        completes: Option<Result<Vec<u8>, AttErrorCode>>,
+    }
+
+    impl Interceptor for RecordingInterceptor {
+        fn before_read(&self, _: &OperationContext) -> Interception<Vec<u8>> {
+            self.log.borrow_mut().push(format!("before {}", self.name));
+            match &self.completes {
+                Some(result) => Interception::Complete(result.clone()),
+                None => Interception::Proceed,
+            }
+        }
+
+        fn after_read(&self, _: &OperationContext, _: &mut Result<Vec<u8>, AttErrorCode>) {
+            self.log.borrow_mut().push(format!("after {}", self.name));
+        }
+    }
+
+    fn recording(
+        name: &'static str,
+        log: &Rc<RefCell<Vec<String>>>,
+        completes: Option<Result<Vec<u8>, AttErrorCode>>,
+    ) -> Rc<dyn Interceptor> {
+        Rc::new(RecordingInterceptor { name, log: log.clone(), completes })
+    }
+
+    /// Doubles each value written, and appends to each value read
+    struct Rewriter;
+
+    impl Interceptor for Rewriter {
+        fn after_read(&self, _: &OperationContext, result: &mut Result<Vec<u8>, AttErrorCode>) {
+            if let Ok(value) = result {
+                value.push(0xff);
+            }
+        }
+
+        fn before_write(&self, _: &OperationContext, value: &mut Vec<u8>) -> Interception<()> {
+            value.iter_mut().for_each(|byte| *byte *= 2);
+            Interception::Proceed
+        }
+    }
+
+    #[test]
+    fn test_chain_ordering() {
+        // arrange
+        let log = Rc::new(RefCell::new(vec![]));
+        let mut chain = InterceptorChain::new();
+        chain.add(HANDLE..=HANDLE, 1, recording("c", &log, None));
+        chain.add(HANDLE..=HANDLE, 0, recording("a", &log, None));
+        chain.add(HANDLE..=HANDLE, 0, recording("b", &log, None));
+
+        // act
+        let mut operation = InterceptedOperation::new(chain.interceptors_for(HANDLE), CONTEXT);
+        assert!(operation.before_read().is_none());
+        operation.after_read(Ok(vec![1].into())).unwrap();
+
+        // assert: by order, then in order of registration, and unwound in reverse
+        assert_eq!(
+            *log.borrow(),
+            vec!["before a", "before b", "before c", "after c", "after b", "after a"]
+        );
+    }
+
+    #[test]
+    fn test_only_interceptors_in_range() {
+        let mut chain = InterceptorChain::new();
+        chain.add(AttHandle(1)..=AttHandle(2), 0, Rc::new(LoggingInterceptor));
+        chain.add(AttHandle(2)..=AttHandle(4), 0, Rc::new(LoggingInterceptor));
+
+        assert_eq!(chain.interceptors_for(AttHandle(1)).len(), 1);
+        assert_eq!(chain.interceptors_for(AttHandle(2)).len(), 2);
+        assert!(chain.interceptors_for(AttHandle(5)).is_empty());
+    }
+
+    #[test]
+    fn test_removed_interceptor() {
+        let interceptor: Rc<dyn Interceptor> = Rc::new(LoggingInterceptor);
+        let mut chain = InterceptorChain::new();
+        chain.add(HANDLE..=HANDLE, 0, interceptor.clone());
+
+        assert!(chain.remove(&interceptor));
+        assert!(chain.interceptors_for(HANDLE).is_empty());
+        assert!(!chain.remove(&interceptor));
+    }
+
+    #[test]
+    fn test_completed_read_skips_later_interceptors() {
+        // arrange
+        let log = Rc::new(RefCell::new(vec![]));
+        let interceptors = vec![
+            recording("a", &log, None),
+            recording("b", &log, Some(Err(AttErrorCode::READ_NOT_PERMITTED))),
+            recording("c", &log, None),
+        ];
+
+        // act
+        let mut operation = InterceptedOperation::new(interceptors, CONTEXT);
+        let completed = operation.before_read().unwrap();
+        let result = operation.after_read(completed);
+
+        // assert: the error is attributed to the interceptors
+        let err = result.unwrap_err();
+        assert_eq!(err.code, AttErrorCode::READ_NOT_PERMITTED);
+        assert_eq!(err.layer, ErrorLayer::Interceptor);
+        assert_eq!(*log.borrow(), vec!["before a", "before b", "after b", "after a"]);
+    }
+
+    #[test]
+    fn test_unchanged_result_keeps_context() {
+        // arrange
+        let log = Rc::new(RefCell::new(vec![]));
+        let mut operation = InterceptedOperation::new(vec![recording("a", &log, None)], CONTEXT);
+        operation.before_read();
+
+        // act
+        let result = operation
+            .after_read(Err(AttError::new(AttErrorCode::UNLIKELY_ERROR, ErrorLayer::Datastore)));
+
+        // assert
+        assert_eq!(result.unwrap_err().layer, ErrorLayer::Datastore);
+    }
+
+    #[test]
+    fn test_values_rewritten() {
+        // arrange
+        let rewriter: Rc<dyn Interceptor> = Rc::new(Rewriter);
+        let mut read = InterceptedOperation::new(vec![rewriter.clone()], CONTEXT);
+        let mut write = InterceptedOperation::new(
+            vec![rewriter],
+            OperationContext { access: AttributeAccess::Write, ..CONTEXT },
+        );
+        let mut value = Cow::Borrowed(&[1, 2][..]);
+
+        // act
+        read.before_read();
+        let read_value = read.after_read(Ok(vec![1].into()));
+        let completed = write.before_write(&mut value);
+
+        // assert
+        assert_eq!(read_value, Ok(vec![1, 0xff].into()));
+        assert!(completed.is_none());
+        assert_eq!(*value, [2, 4]);
+    }
+
+    #[test]
+    fn test_no_interceptors() {
+        let mut operation = InterceptedOperation::new(vec![], CONTEXT);
+        let mut value = Cow::Borrowed(&[1, 2][..]);
+
+        assert!(operation.is_empty());
+        assert!(operation.before_write(&mut value).is_none());
+        assert!(matches!(value, Cow::Borrowed(_)));
+        assert_eq!(operation.after_write(Ok(())), Ok(()));
+    }
+}
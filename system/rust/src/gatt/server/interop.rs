@@ -0,0 +1,213 @@
+//! This module holds the workarounds for peers known to mishandle parts of
+//! ATT (e.g. a car kit that cannot reassemble a large MTU, or a watch that
+//! gives up on long reads). Each Quirk is registered in the InteropRegistry
+//! against a pattern matching the peers affected, by address or by name, and
+//! the quirks of a peer are looked up once, when it connects. They then apply
+//! to every bearer of its connection:
+//! - MaxMtu caps the MTU offered when the client exchanges the MTU (and the
+//!   one used on its EATT bearers).
+//! - LenientReadBlob answers the ATT_READ_BLOB_REQs that would otherwise fail
+//!   with ATTRIBUTE_NOT_LONG with the rest of the value.
+//! - MaxNotificationPayload caps the values notified and indicated.
+//!
+//! Peers without quirks are unaffected.
+
+use crate::{core::address::AddressWithType, gatt::ids::TransportIndex};
+
+/// A workaround for the misbehavior of a peer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quirk {
+    /// The peer mishandles larger MTUs, so the ATT_MTU is capped at this
+    MaxMtu(usize),
+    /// The peer reads every value with an ATT_READ_BLOB_REQ after its
+    /// ATT_READ_REQ, and drops it (rather than ending the long read) when the
+    /// request fails with ATTRIBUTE_NOT_LONG, so it is served instead
+    LenientReadBlob,
+    /// The peer drops notifications and indications whose value is longer
+    /// than this, whatever the MTU, so they are rejected with DataExceedsMtu
+    /// (and notify_all() truncates them)
+    MaxNotificationPayload(usize),
+}
+
+/// Matches the peers affected by a quirk
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PeerPattern {
+    /// The peer with this address (of either type), stored in little-endian
+    /// order like AddressWithType
+    Address([u8; 6]),
+    /// The peers whose address starts with these bytes, in the order they are
+    /// written (e.g. the OUI of a vendor, most significant byte first)
+    AddressPrefix(Vec<u8>),
+    /// The peers whose name starts with this
+    NamePrefix(String),
+}
+
+impl PeerPattern {
+    /// Whether the pattern matches the peer
+    pub fn matches(&self, peer: &PeerInfo) -> bool {
+        match self {
+            PeerPattern::Address(address) => {
+                peer.address.map(|peer| peer.address == *address).unwrap_or(false)
+            }
+            PeerPattern::AddressPrefix(prefix) => peer
+                .address
+                .map(|peer| peer.address.iter().rev().take(prefix.len()).eq(prefix.iter()))
+                .unwrap_or(false),
+            PeerPattern::NamePrefix(prefix) => {
+                peer.name.as_ref().map(|name| name.starts_with(prefix.as_str())).unwrap_or(false)
+            }
+        }
+    }
+}
+
+/// What is known of a peer when it connects
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerInfo {
+    /// The address of the peer (its identity address, if it is bonded)
+    pub address: Option<AddressWithType>,
+    /// The name of the peer, if it was discovered
+    pub name: Option<String>,
+}
+
+/// Tells the server what is known of the peer behind each connection, so
+/// that its quirks can be looked up
+pub trait PeerInfoProvider {
+    /// What is known of the peer on the specified transport
+    fn peer_info(&self, tcb_idx: TransportIndex) -> PeerInfo;
+}
+
+/// The quirks that apply to a peer, combined
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quirks {
+    /// The largest ATT_MTU to use with the peer
+    pub max_mtu: Option<usize>,
+    /// Whether ATT_READ_BLOB_REQs are served even when the value is not long
+    pub lenient_read_blob: bool,
+    /// The longest value to notify or indicate to the peer
+    pub max_notification_payload: Option<usize>,
+}
+
+impl Quirks {
+    /// Apply another quirk. If several caps apply, the lowest one wins.
+    pub fn add(&mut self, quirk: Quirk) {
+        fn min_cap(cap: Option<usize>, other: usize) -> Option<usize> {
+            Some(cap.map(|cap| cap.min(other)).unwrap_or(other))
+        }
+        match quirk {
+            Quirk::MaxMtu(mtu) => self.max_mtu = min_cap(self.max_mtu, mtu),
+            Quirk::LenientReadBlob => self.lenient_read_blob = true,
+            Quirk::MaxNotificationPayload(len) => {
+                self.max_notification_payload = min_cap(self.max_notification_payload, len)
+            }
+        }
+    }
+
+    /// The given MTU, capped for the peer
+    pub fn cap_mtu(&self, mtu: usize) -> usize {
+        self.max_mtu.map(|max| mtu.min(max)).unwrap_or(mtu)
+    }
+
+    /// The MTU within which notifications and indications to the peer must
+    /// fit, given the MTU of the bearer
+    pub fn cap_notification_mtu(&self, mtu: usize) -> usize {
+        // the payload follows the opcode and handle
+        self.max_notification_payload.map(|max| mtu.min(max + 3)).unwrap_or(mtu)
+    }
+
+    /// The given length of a notified value, capped for the peer
+    pub fn cap_notification_payload(&self, len: usize) -> usize {
+        self.max_notification_payload.map(|max| len.min(max)).unwrap_or(len)
+    }
+}
+
+/// The quirks registered for each pattern of peers
+#[derive(Clone, Debug, Default)]
+pub struct InteropRegistry {
+    entries: Vec<(PeerPattern, Quirk)>,
+}
+
+impl InteropRegistry {
+    /// Constructor, without any quirk
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Apply the quirk to the peers matching the pattern
+    pub fn register(&mut self, pattern: PeerPattern, quirk: Quirk) {
+        self.entries.push((pattern, quirk));
+    }
+
+    /// The quirks that apply to the peer
+    pub fn quirks_for(&self, peer: &PeerInfo) -> Quirks {
+        let mut quirks = Quirks::default();
+        for (_, quirk) in self.entries.iter().filter(|(pattern, _)| pattern.matches(peer)) {
+            quirks.add(*quirk);
+        }
+        quirks
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::core::address::AddressType;
+
+    use super::*;
+
+    const PEER: AddressWithType = AddressWithType {
+        address: [0x06, 0x05, 0x04, 0x03, 0x02, 0x01],
+        address_type: AddressType::Public,
+    };
+
+    fn peer(address: Option<AddressWithType>, name: Option<&str>) -> PeerInfo {
+        PeerInfo { address, name: name.map(String::from) }
+    }
+
+    #[test]
+    fn test_patterns() {
+        let peer = peer(Some(PEER), Some("Car Kit 2000"));
+
+        assert!(PeerPattern::Address(PEER.address).matches(&peer));
+        assert!(PeerPattern::AddressPrefix(vec![0x01, 0x02, 0x03]).matches(&peer));
+        assert!(!PeerPattern::AddressPrefix(vec![0x06, 0x05]).matches(&peer));
+        assert!(PeerPattern::NamePrefix("Car Kit".into()).matches(&peer));
+        assert!(!PeerPattern::NamePrefix("Watch".into()).matches(&peer));
+    }
+
+    #[test]
+    fn test_unknown_peer_matches_nothing() {
+        let peer = PeerInfo::default();
+
+        assert!(!PeerPattern::Address(PEER.address).matches(&peer));
+        assert!(!PeerPattern::AddressPrefix(vec![0x01]).matches(&peer));
+        assert!(!PeerPattern::NamePrefix("".into()).matches(&peer));
+    }
+
+    #[test]
+    fn test_quirks_combined() {
+        // arrange
+        let mut registry = InteropRegistry::new();
+        registry.register(PeerPattern::NamePrefix("Car".into()), Quirk::MaxMtu(185));
+        registry.register(PeerPattern::Address(PEER.address), Quirk::MaxMtu(100));
+        registry.register(PeerPattern::Address(PEER.address), Quirk::LenientReadBlob);
+        registry.register(PeerPattern::NamePrefix("Watch".into()), Quirk::MaxMtu(23));
+
+        // act
+        let quirks = registry.quirks_for(&peer(Some(PEER), Some("Car Kit")));
+
+        // assert: the lowest cap wins
+        assert_eq!(
+            quirks,
+            Quirks { max_mtu: Some(100), lenient_read_blob: true, max_notification_payload: None }
+        );
+    }
+
+    #[test]
+    fn test_caps() {
+        let quirks = Quirks { max_notification_payload: Some(20), ..Default::default() };
+
+        assert_eq!(quirks.cap_mtu(517), 517);
+        assert_eq!(quirks.cap_notification_mtu(517), 23);
+        assert_eq!(quirks.cap_notification_mtu(23), 23);
+        assert_eq!(quirks.cap_notification_payload(100), 20);
+    }
+}
@@ -13,6 +13,7 @@ use crate::gatt::ids::{AdvertiserId, ServerId, TransportIndex};
 pub struct IsolationManager {
     advertiser_to_server: HashMap<AdvertiserId, ServerId>,
     transport_to_server: HashMap<TransportIndex, ServerId>,
+    br_edr_server: Option<ServerId>,
 }
 
 impl IsolationManager {
@@ -21,6 +22,7 @@ impl IsolationManager {
         IsolationManager {
             advertiser_to_server: HashMap::new(),
             transport_to_server: HashMap::new(),
+            br_edr_server: None,
         }
     }
 
@@ -45,6 +47,29 @@ impl IsolationManager {
         self.advertiser_to_server.remove(&advertiser_id);
     }
 
+    /// Link a given GATT server to BR/EDR, so incoming connections over BR/EDR
+    /// will be visible only by the linked server
+    pub fn associate_server_with_br_edr(&mut self, server_id: ServerId) {
+        info!("associating server {server_id:?} with BR/EDR");
+        let old = self.br_edr_server.replace(server_id);
+        if let Some(old) = old {
+            error!(
+                "new server {server_id:?} associated with BR/EDR, displacing old server {old:?}"
+            );
+        }
+    }
+
+    /// Clear the server associated with BR/EDR, if one exists
+    pub fn clear_br_edr_server(&mut self) {
+        info!("removing server (if any) associated with BR/EDR");
+        self.br_edr_server = None;
+    }
+
+    /// Look up the server tied to BR/EDR, if one exists
+    pub fn br_edr_server(&self) -> Option<ServerId> {
+        self.br_edr_server
+    }
+
     /// Check if this transport is currently owned by the Rust stack
     pub fn is_connection_isolated(&self, tcb_idx: TransportIndex) -> bool {
         self.transport_to_server.contains_key(&tcb_idx)
@@ -66,6 +91,9 @@ impl IsolationManager {
     pub fn clear_server(&mut self, server_id: ServerId) {
         info!("clearing advertisers associated with {server_id:?}");
         self.advertiser_to_server.retain(|_, server| *server != server_id);
+        if self.br_edr_server == Some(server_id) {
+            self.br_edr_server = None;
+        }
     }
 
     /// Handles an incoming connection
@@ -90,7 +118,22 @@ impl IsolationManager {
         }
     }
 
-    /// Handle a disconnection
+    /// Handles a connection over BR/EDR. These are not made to an advertising
+    /// set, so they are exposed to the server associated with BR/EDR instead.
+    ///
+    /// This event should be supplied from the enclosing module, not directly from the upper layer.
+    #[cfg(not(feature = "le_only"))]
+    pub fn on_br_edr_connect(&mut self, tcb_idx: TransportIndex, server_id: ServerId) {
+        info!(
+            "connection over BR/EDR on transport {tcb_idx:?} is isolated to server {server_id:?}"
+        );
+        let old = self.transport_to_server.insert(tcb_idx, server_id);
+        if let Some(old) = old {
+            error!("new server {server_id:?} on transport {tcb_idx:?} displacing existing server {old:?}")
+        }
+    }
+
+    /// Handle a disconnection, over either transport
     ///
     /// This event should be supplied from the enclosing module, not directly from the upper layer.
     pub fn on_le_disconnect(&mut self, tcb_idx: TransportIndex) {
@@ -222,4 +265,59 @@ mod test {
         assert!(server_id.is_none());
         assert!(!isolation_manager.is_connection_isolated(TCB_IDX));
     }
+
+    #[test]
+    #[cfg(not(feature = "le_only"))]
+    fn test_br_edr_connect() {
+        let mut isolation_manager = IsolationManager::new();
+
+        isolation_manager.on_br_edr_connect(TCB_IDX, SERVER_ID);
+        let server_id = isolation_manager.get_server_id(TCB_IDX);
+
+        assert_eq!(server_id, Some(SERVER_ID));
+    }
+
+    #[test]
+    #[cfg(not(feature = "le_only"))]
+    fn test_not_isolated_after_br_edr_disconnection() {
+        let mut isolation_manager = IsolationManager::new();
+        isolation_manager.on_br_edr_connect(TCB_IDX, SERVER_ID);
+
+        isolation_manager.on_le_disconnect(TCB_IDX);
+        let is_isolated = isolation_manager.is_connection_isolated(TCB_IDX);
+
+        assert!(!is_isolated);
+    }
+
+    #[test]
+    fn test_br_edr_server() {
+        let mut isolation_manager = IsolationManager::new();
+
+        isolation_manager.associate_server_with_br_edr(SERVER_ID);
+        let server_id = isolation_manager.br_edr_server();
+
+        assert_eq!(server_id, Some(SERVER_ID));
+    }
+
+    #[test]
+    fn test_br_edr_server_cleared_with_server() {
+        let mut isolation_manager = IsolationManager::new();
+        isolation_manager.associate_server_with_br_edr(SERVER_ID);
+
+        isolation_manager.clear_server(SERVER_ID);
+        let server_id = isolation_manager.br_edr_server();
+
+        assert!(server_id.is_none());
+    }
+
+    #[test]
+    fn test_clear_br_edr_server() {
+        let mut isolation_manager = IsolationManager::new();
+        isolation_manager.associate_server_with_br_edr(SERVER_ID);
+
+        isolation_manager.clear_br_edr_server();
+        let server_id = isolation_manager.br_edr_server();
+
+        assert!(server_id.is_none());
+    }
 }
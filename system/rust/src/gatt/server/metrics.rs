@@ -0,0 +1,379 @@
+//! Counters for the ATT traffic on each connection, so that the state of the
+//! GATT server can be dumped (e.g. into an Android bugreport) when diagnosing
+//! misbehaving clients or applications.
+
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    gatt::ids::{ConnectionId, Transport},
+    packets::{AttErrorCode, AttOpcode},
+};
+
+/// The counters of a single bearer, or (once merged) of every bearer of a
+/// connection
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BearerMetrics {
+    /// The number of requests received, by opcode
+    pub requests: BTreeMap<u8, u64>,
+    /// The number of error responses sent, by error code
+    pub error_responses: BTreeMap<u8, u64>,
+    /// The number of transactions (a request and its response) completed,
+    /// other than MTU exchanges (which are answered immediately)
+    pub transactions: u64,
+    /// The total time between receiving a request and sending its response,
+    /// over all completed transactions
+    pub total_transaction_latency: Duration,
+    /// The number of notifications sent
+    pub notifications_sent: u64,
+    /// The total size of the values of the notifications sent, in bytes
+    pub notification_bytes_sent: u64,
+    /// The number of notification values replaced by a newer one before they
+    /// were sent (for characteristics whose notifications are coalesced)
+    pub notifications_coalesced: u64,
+    /// The total size of the PDUs sent, in bytes
+    pub bytes_sent: u64,
+    /// The total size of the PDUs received, in bytes
+    pub bytes_received: u64,
+    /// The number of writes currently buffered in the prepared write queue(s)
+    pub prepared_writes: usize,
+    /// The largest number of writes ever buffered in a prepared write queue
+    pub max_prepared_writes: usize,
+    /// How long the bearer has been open
+    pub uptime: Duration,
+}
+
+impl BearerMetrics {
+    /// Record a request received by the server
+    pub fn on_request(&mut self, opcode: AttOpcode) {
+        *self.requests.entry(opcode.into()).or_default() += 1;
+    }
+
+    /// Record an error response sent by the server
+    pub fn on_error_response(&mut self, error_code: AttErrorCode) {
+        *self.error_responses.entry(error_code.into()).or_default() += 1;
+    }
+
+    /// Record the completion of a transaction, which took the given time
+    pub fn on_transaction_complete(&mut self, latency: Duration, prepared_writes: usize) {
+        self.transactions += 1;
+        self.total_transaction_latency += latency;
+        self.prepared_writes = prepared_writes;
+        self.max_prepared_writes = self.max_prepared_writes.max(prepared_writes);
+    }
+
+    /// Record a notification sent by the server, with a value of the given
+    /// size
+    pub fn on_notification_sent(&mut self, len: usize) {
+        self.notifications_sent += 1;
+        self.notification_bytes_sent += len as u64;
+    }
+
+    /// Record a notification value that was replaced by a newer one before it
+    /// was sent
+    pub fn on_notification_coalesced(&mut self) {
+        self.notifications_coalesced += 1;
+    }
+
+    /// Record a PDU of the given size sent by the server
+    pub fn on_pdu_sent(&mut self, len: usize) {
+        self.bytes_sent += len as u64;
+    }
+
+    /// Record a PDU of the given size received from the client
+    pub fn on_pdu_received(&mut self, len: usize) {
+        self.bytes_received += len as u64;
+    }
+
+    /// The average time between receiving a request and sending its response,
+    /// if any transaction has completed
+    pub fn average_transaction_latency(&self) -> Option<Duration> {
+        if self.transactions == 0 {
+            return None;
+        }
+        Some(self.total_transaction_latency / self.transactions as u32)
+    }
+
+    /// The rate at which notification values were sent over the uptime of the
+    /// bearer, in bytes per second
+    pub fn notification_throughput(&self) -> f64 {
+        if self.uptime.is_zero() {
+            return 0.0;
+        }
+        self.notification_bytes_sent as f64 / self.uptime.as_secs_f64()
+    }
+
+    /// Accumulate the counters of another bearer of the same connection. The
+    /// uptime is that of the longest-lived bearer.
+    pub fn merge(&mut self, other: &BearerMetrics) {
+        for (opcode, count) in &other.requests {
+            *self.requests.entry(*opcode).or_default() += count;
+        }
+        for (error_code, count) in &other.error_responses {
+            *self.error_responses.entry(*error_code).or_default() += count;
+        }
+        self.transactions += other.transactions;
+        self.total_transaction_latency += other.total_transaction_latency;
+        self.notifications_sent += other.notifications_sent;
+        self.notification_bytes_sent += other.notification_bytes_sent;
+        self.notifications_coalesced += other.notifications_coalesced;
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+        self.prepared_writes += other.prepared_writes;
+        self.max_prepared_writes = self.max_prepared_writes.max(other.max_prepared_writes);
+        self.uptime = self.uptime.max(other.uptime);
+    }
+}
+
+/// The metrics of a single connection
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionMetrics {
+    /// The connection
+    pub conn_id: ConnectionId,
+    /// The transport the client connected over
+    pub transport: Transport,
+    /// The MTU of each bearer of the connection, starting with the unenhanced
+    /// one
+    pub mtus: Vec<usize>,
+    /// The counters of all bearers of the connection
+    pub counters: BearerMetrics,
+}
+
+/// The current state of a single connection, across all of its bearers, as
+/// sampled by connection-quality monitoring
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// The MTU of the unenhanced bearer
+    pub mtu: usize,
+    /// The number of bearers, including the unenhanced one
+    pub bearers: usize,
+    /// The number of indications sent or queued, and not yet confirmed
+    pub outstanding_indications: usize,
+    /// The number of notifications queued, and not yet sent
+    pub queued_notifications: usize,
+    /// The total size of the PDUs sent, in bytes
+    pub bytes_sent: u64,
+    /// The total size of the PDUs received, in bytes
+    pub bytes_received: u64,
+    /// When a PDU was last sent or received on any bearer (or the connection
+    /// was opened, if none has been)
+    pub last_activity: Instant,
+}
+
+/// A snapshot of the metrics of every connection to the GATT server
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// The connections, in order of ConnectionId
+    pub connections: Vec<ConnectionMetrics>,
+}
+
+impl MetricsSnapshot {
+    /// The number of bearers using each MTU, across all connections
+    pub fn mtu_distribution(&self) -> BTreeMap<usize, usize> {
+        let mut distribution = BTreeMap::new();
+        for mtu in self.connections.iter().flat_map(|connection| &connection.mtus) {
+            *distribution.entry(*mtu).or_default() += 1;
+        }
+        distribution
+    }
+}
+
+/// Formats the snapshot as human-readable text, for inclusion in a bugreport
+impl Display for MetricsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "GATT server: {} connection(s)", self.connections.len())?;
+        for connection in &self.connections {
+            let counters = &connection.counters;
+            writeln!(
+                f,
+                "  {:?} over {:?}, up for {:?}, MTUs {:?}",
+                connection.conn_id, connection.transport, counters.uptime, connection.mtus
+            )?;
+            for (opcode, count) in &counters.requests {
+                writeln!(f, "    requests {}: {count}", format_opcode(*opcode))?;
+            }
+            for (error_code, count) in &counters.error_responses {
+                writeln!(f, "    error responses {}: {count}", format_error_code(*error_code))?;
+            }
+            match counters.average_transaction_latency() {
+                Some(latency) => writeln!(
+                    f,
+                    "    transactions: {}, average latency {latency:?}",
+                    counters.transactions
+                )?,
+                None => writeln!(f, "    transactions: 0")?,
+            }
+            writeln!(
+                f,
+                "    notifications: {} ({} bytes, {:.1} bytes/s), {} coalesced",
+                counters.notifications_sent,
+                counters.notification_bytes_sent,
+                counters.notification_throughput(),
+                counters.notifications_coalesced
+            )?;
+            writeln!(
+                f,
+                "    bytes: {} sent, {} received",
+                counters.bytes_sent, counters.bytes_received
+            )?;
+            writeln!(
+                f,
+                "    prepared writes: {} queued, at most {}",
+                counters.prepared_writes, counters.max_prepared_writes
+            )?;
+        }
+        for (mtu, count) in self.mtu_distribution() {
+            writeln!(f, "  MTU {mtu}: {count} bearer(s)")?;
+        }
+        Ok(())
+    }
+}
+
+fn format_opcode(opcode: u8) -> String {
+    match AttOpcode::try_from(opcode) {
+        Ok(opcode) => format!("{opcode:?}"),
+        Err(_) => format!("{opcode:#04x}"),
+    }
+}
+
+fn format_error_code(error_code: u8) -> String {
+    match AttErrorCode::try_from(error_code) {
+        Ok(error_code) => format!("{error_code:?}"),
+        Err(_) => format!("{error_code:#04x}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::gatt::ids::{ServerId, TransportIndex};
+
+    use super::*;
+
+    const CONN_ID: ConnectionId = ConnectionId::new(TransportIndex(1), ServerId(2));
+    const ANOTHER_CONN_ID: ConnectionId = ConnectionId::new(TransportIndex(2), ServerId(2));
+
+    #[test]
+    fn test_average_latency() {
+        let mut metrics = BearerMetrics::default();
+
+        metrics.on_transaction_complete(Duration::from_millis(10), 0);
+        metrics.on_transaction_complete(Duration::from_millis(30), 0);
+
+        assert_eq!(metrics.average_transaction_latency(), Some(Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn test_no_average_latency_without_transactions() {
+        let metrics = BearerMetrics::default();
+
+        assert_eq!(metrics.average_transaction_latency(), None);
+    }
+
+    #[test]
+    fn test_prepared_write_depth() {
+        let mut metrics = BearerMetrics::default();
+
+        metrics.on_transaction_complete(Duration::ZERO, 1);
+        metrics.on_transaction_complete(Duration::ZERO, 2);
+        metrics.on_transaction_complete(Duration::ZERO, 0);
+
+        assert_eq!(metrics.prepared_writes, 0);
+        assert_eq!(metrics.max_prepared_writes, 2);
+    }
+
+    #[test]
+    fn test_notification_throughput() {
+        let mut metrics = BearerMetrics { uptime: Duration::from_secs(2), ..Default::default() };
+
+        metrics.on_notification_sent(10);
+        metrics.on_notification_sent(30);
+
+        assert_eq!(metrics.notifications_sent, 2);
+        assert_eq!(metrics.notification_throughput(), 20.0);
+    }
+
+    #[test]
+    fn test_merge() {
+        // arrange
+        let mut metrics = BearerMetrics { uptime: Duration::from_secs(1), ..Default::default() };
+        metrics.on_request(AttOpcode::READ_REQUEST);
+        metrics.on_error_response(AttErrorCode::INVALID_HANDLE);
+        let mut other = BearerMetrics { uptime: Duration::from_secs(2), ..Default::default() };
+        other.on_request(AttOpcode::READ_REQUEST);
+        other.on_request(AttOpcode::WRITE_REQUEST);
+        other.on_transaction_complete(Duration::from_millis(5), 3);
+        metrics.on_pdu_received(2);
+        other.on_pdu_received(3);
+        other.on_pdu_sent(4);
+
+        // act
+        metrics.merge(&other);
+
+        // assert
+        assert_eq!(
+            metrics.requests,
+            BTreeMap::from([
+                (u8::from(AttOpcode::READ_REQUEST), 2),
+                (u8::from(AttOpcode::WRITE_REQUEST), 1)
+            ])
+        );
+        assert_eq!(
+            metrics.error_responses,
+            BTreeMap::from([(u8::from(AttErrorCode::INVALID_HANDLE), 1)])
+        );
+        assert_eq!(metrics.transactions, 1);
+        assert_eq!((metrics.bytes_sent, metrics.bytes_received), (4, 5));
+        assert_eq!(metrics.max_prepared_writes, 3);
+        assert_eq!(metrics.uptime, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_mtu_distribution() {
+        let snapshot = MetricsSnapshot {
+            connections: vec![
+                ConnectionMetrics {
+                    conn_id: CONN_ID,
+                    transport: Transport::Le,
+                    mtus: vec![23, 64],
+                    counters: BearerMetrics::default(),
+                },
+                ConnectionMetrics {
+                    conn_id: ANOTHER_CONN_ID,
+                    transport: Transport::Le,
+                    mtus: vec![23],
+                    counters: BearerMetrics::default(),
+                },
+            ],
+        };
+
+        assert_eq!(snapshot.mtu_distribution(), BTreeMap::from([(23, 2), (64, 1)]));
+    }
+
+    #[test]
+    fn test_dump_includes_counters() {
+        // arrange
+        let mut counters = BearerMetrics::default();
+        counters.on_request(AttOpcode::READ_REQUEST);
+        counters.on_error_response(AttErrorCode::INVALID_HANDLE);
+        let snapshot = MetricsSnapshot {
+            connections: vec![ConnectionMetrics {
+                conn_id: CONN_ID,
+                transport: Transport::Le,
+                mtus: vec![23],
+                counters,
+            }],
+        };
+
+        // act
+        let dump = snapshot.to_string();
+
+        // assert
+        assert!(dump.contains("1 connection(s)"));
+        assert!(dump.contains(&format!("requests {:?}: 1", AttOpcode::READ_REQUEST)));
+        assert!(dump.contains(&format!("error responses {:?}: 1", AttErrorCode::INVALID_HANDLE)));
+        assert!(dump.contains("MTU 23: 1 bearer(s)"));
+    }
+}
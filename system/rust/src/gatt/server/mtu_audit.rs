@@ -0,0 +1,62 @@
+//! Every handler that builds a PDU is responsible for truncating it to the
+//! MTU of its bearer, and a handler that gets this wrong produces PDUs that a
+//! client may reject or drop. In tests and fuzzing builds, every outgoing PDU
+//! is checked against the MTU just before it is transmitted, so that such a
+//! regression in any handler fails loudly, naming the offending PDU. In other
+//! builds, the check compiles to nothing.
+
+use crate::packets::AttBuilder;
+#[cfg(any(test, feature = "fuzzing"))]
+use crate::packets::Serializable;
+
+/// Check that an outgoing PDU fits in the MTU of the bearer it is about to
+/// be sent on, panicking with the PDU otherwise
+#[cfg(any(test, feature = "fuzzing"))]
+pub fn audit_outgoing_pdu(packet: &AttBuilder, mtu: usize) {
+    let Ok(bits) = packet.size_in_bits() else {
+        // the serializer failure is reported by the caller
+        return;
+    };
+    let len = bits / 8;
+    assert!(
+        len <= mtu,
+        "outgoing {:?} of {len} bytes exceeds the MTU of {mtu}: {packet:?}",
+        packet.opcode
+    );
+}
+
+/// Check that an outgoing PDU fits in the MTU of the bearer it is about to
+/// be sent on (only in tests and fuzzing builds)
+#[cfg(not(any(test, feature = "fuzzing")))]
+pub fn audit_outgoing_pdu(_packet: &AttBuilder, _mtu: usize) {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::{
+        gatt::mtu::DEFAULT_ATT_MTU,
+        packets::{AttAttributeDataChild, AttChild, AttOpcode, AttReadResponseBuilder},
+        utils::packet::build_att_data,
+    };
+
+    fn read_response(len: usize) -> AttBuilder {
+        AttBuilder {
+            opcode: AttOpcode::READ_RESPONSE,
+            _child_: AttChild::AttReadResponse(AttReadResponseBuilder {
+                value: build_att_data(AttAttributeDataChild::RawData(vec![0; len].into())),
+            }),
+        }
+    }
+
+    #[test]
+    fn test_pdu_within_mtu() {
+        audit_outgoing_pdu(&read_response(22), DEFAULT_ATT_MTU);
+    }
+
+    #[test]
+    #[should_panic(expected = "READ_RESPONSE of 24 bytes exceeds the MTU of 23")]
+    fn test_pdu_exceeding_mtu() {
+        audit_outgoing_pdu(&read_response(23), DEFAULT_ATT_MTU);
+    }
+}
@@ -0,0 +1,957 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+};
+
+use log::warn;
+use tokio::sync::Notify;
+
+use crate::{
+    gatt::ids::AttHandle,
+    packets::{
+        AttAttributeDataChild, AttChild, AttHandleValueNotificationBuilder,
+        AttMultipleHandleValueNotificationBuilder, Serializable,
+    },
+    utils::packet::build_att_data,
+};
+
+use super::{
+    att_database::{AttDatabase, StableAttDatabase},
+    att_server_bearer::SendError,
+    client_configuration::ClientConfiguration,
+    gatt_database::{PRIMARY_SERVICE_DECLARATION_UUID, SECONDARY_SERVICE_DECLARATION_UUID},
+    robust_caching::ClientSupportedFeatures,
+};
+
+/// The maximum number of notifications that may be queued on a single
+/// connection (e.g. while waiting for an MTU exchange to complete) before
+/// further notifications are rejected, unless configured otherwise (see
+/// GattServerConfig::max_queued_notifications).
+pub const MAX_QUEUED_NOTIFICATIONS: usize = 16;
+
+/// The maximum number of notifications that a single service may queue on a
+/// connection, so that one service sending bulk data cannot take up the
+/// whole queue.
+pub const MAX_QUEUED_NOTIFICATIONS_PER_SERVICE: usize = MAX_QUEUED_NOTIFICATIONS / 2;
+
+/// The connection is reported as congested once this many notifications are
+/// queued...
+pub const CONGESTION_HIGH_WATERMARK: usize = MAX_QUEUED_NOTIFICATIONS * 3 / 4;
+
+/// ...and as no longer congested once the queue drains down to this many.
+pub const CONGESTION_LOW_WATERMARK: usize = MAX_QUEUED_NOTIFICATIONS / 4;
+
+/// The limits of a notification queue, scaled from its size in the same
+/// proportions as the defaults above
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct QueueLimits {
+    max_queued: usize,
+    max_queued_per_service: usize,
+    high_watermark: usize,
+    low_watermark: usize,
+}
+
+impl QueueLimits {
+    fn new(max_queued: usize) -> Self {
+        Self {
+            max_queued,
+            max_queued_per_service: (max_queued / 2).max(1),
+            high_watermark: (max_queued * 3 / 4).max(1),
+            low_watermark: max_queued / 4,
+        }
+    }
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        Self::new(MAX_QUEUED_NOTIFICATIONS)
+    }
+}
+
+/// The priority of a notification. Queued notifications are sent in order of
+/// priority, so that e.g. HID reports are not held up behind bulk data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Bulk data, which may be delayed
+    Low = 0,
+    /// The default
+    Normal = 1,
+    /// Low-latency traffic, e.g. input reports
+    High = 2,
+}
+
+#[derive(Debug)]
+/// Errors that can occur while sending a notification
+pub enum NotificationError {
+    /// The provided data exceeds the MTU limitations
+    DataExceedsMtu {
+        /// The actual max payload size permitted
+        /// (ATT_MTU - 3, since 3 bytes are needed for the header)
+        mtu: usize,
+    },
+    /// The notified attribute handle does not exist
+    AttributeNotFound,
+    /// The notified attribute does not support notifications
+    NotificationsNotSupported,
+    /// The client has not subscribed to notifications of this attribute
+    ClientNotSubscribed,
+    /// Too many notifications are already queued on this connection
+    Congested,
+    /// Too many notifications from the service containing the notified
+    /// attribute are already queued on this connection
+    ServiceQuotaExceeded,
+    /// Failed to send the outgoing notification packet
+    SendError(SendError),
+}
+
+/// Tracks the notifications queued on a single connection, so that a
+/// misbehaving upper layer cannot queue an unbounded number of them.
+pub struct NotificationHandler<T> {
+    db: T,
+    queue: Rc<NotificationQueue>,
+}
+
+type CongestionHandler = Box<dyn Fn(bool)>;
+
+/// The occupancy of the notification queue, shared with each permit
+#[derive(Default)]
+struct NotificationQueue {
+    limits: QueueLimits,
+    queued: Cell<usize>,
+    /// Indexed by Priority
+    queued_by_priority: [Cell<usize>; 3],
+    /// Keyed by the handle of the service declaration
+    queued_by_service: RefCell<HashMap<AttHandle, usize>>,
+    /// The values held by the queued notifications of coalesced attributes,
+    /// by handle, which later notifications replace
+    coalesced_values: RefCell<HashMap<AttHandle, HeldValue>>,
+    /// Signalled whenever a permit is released
+    released: Notify,
+    /// Whether the queue filled up past the high watermark, and has not yet
+    /// drained to the low watermark
+    queue_congested: Cell<bool>,
+    /// Whether the transport is withholding the credits needed to send
+    transport_congested: Cell<bool>,
+    /// Whether congestion (from either source) was last reported
+    congested: Cell<bool>,
+    on_congestion: RefCell<Option<CongestionHandler>>,
+}
+
+impl NotificationQueue {
+    fn set_congested(&self, congested: bool) {
+        self.queue_congested.set(congested);
+        self.report_congestion();
+    }
+
+    fn report_congestion(&self) {
+        let congested = self.queue_congested.get() || self.transport_congested.get();
+        if self.congested.replace(congested) != congested {
+            if let Some(handler) = self.on_congestion.borrow().as_ref() {
+                handler(congested);
+            }
+        }
+    }
+
+    fn has_queued_above(&self, priority: Priority) -> bool {
+        self.queued_by_priority[priority as usize + 1..].iter().any(|queued| queued.get() > 0)
+    }
+
+    /// Stop coalescing the notifications of the given attribute into the
+    /// given value, if they are
+    fn release_held_value(&self, handle: AttHandle, held: &HeldValue) {
+        let mut coalesced_values = self.coalesced_values.borrow_mut();
+        if coalesced_values.get(&handle).map(|value| Rc::ptr_eq(value, held)).unwrap_or(false) {
+            coalesced_values.remove(&handle);
+        }
+    }
+}
+
+impl<T: AttDatabase + Clone> NotificationHandler<T> {
+    /// Constructor, with a queue of the size configured for the database
+    pub fn new(db: T) -> Self {
+        let limits = QueueLimits::new(db.server_config().max_queued_notifications);
+        Self { db, queue: Rc::new(NotificationQueue { limits, ..Default::default() }) }
+    }
+
+    /// The number of notifications queued, and not yet sent
+    pub fn queued(&self) -> usize {
+        self.queue.queued.get()
+    }
+
+    /// Set the handler invoked when the queue fills up past the high
+    /// watermark, and when it drains back down to the low watermark
+    pub fn set_on_congestion(&self, handler: impl Fn(bool) + 'static) {
+        self.queue.on_congestion.replace(Some(Box::new(handler)));
+    }
+
+    /// Record whether the transport is withholding the credits needed to send
+    /// on this connection. The connection is reported as congested while
+    /// either it is, or the queue is past its high watermark.
+    pub fn set_transport_congested(&self, congested: bool) {
+        self.queue.transport_congested.set(congested);
+        self.queue.report_congestion();
+    }
+
+    /// Whether the client accepts ATT_MULTIPLE_HANDLE_VALUE_NTF PDUs
+    pub fn client_supports_multiple_notifications(&self) -> bool {
+        self.db
+            .client_supported_features()
+            .contains(ClientSupportedFeatures::MULTIPLE_HANDLE_VALUE_NOTIFICATIONS)
+    }
+
+    /// Reserve a slot in the notification queue for a notification of the
+    /// given attribute. The slot is released once the returned permit is
+    /// dropped.
+    pub fn try_reserve(
+        &self,
+        handle: AttHandle,
+        priority: Priority,
+    ) -> Result<NotificationPermit<T>, NotificationError> {
+        let queued = self.queue.queued.get();
+        if queued >= self.queue.limits.max_queued {
+            warn!("too many notifications are queued, dropping notification");
+            self.queue.set_congested(true);
+            return Err(NotificationError::Congested);
+        }
+
+        // attributes outside of any service are only limited by the size of the queue
+        let service = self.find_service(handle);
+        if let Some(service) = service {
+            let mut queued_by_service = self.queue.queued_by_service.borrow_mut();
+            let service_queued = queued_by_service.get(&service).copied().unwrap_or_default();
+            if service_queued >= self.queue.limits.max_queued_per_service {
+                warn!("too many notifications are queued by service {service:?}, dropping notification");
+                return Err(NotificationError::ServiceQuotaExceeded);
+            }
+            queued_by_service.insert(service, service_queued + 1);
+        }
+
+        self.queue.queued.set(queued + 1);
+        let priority_queued = &self.queue.queued_by_priority[priority as usize];
+        priority_queued.set(priority_queued.get() + 1);
+        if queued + 1 >= self.queue.limits.high_watermark {
+            self.queue.set_congested(true);
+        }
+        Ok(NotificationPermit {
+            db: self.db.clone(),
+            queue: self.queue.clone(),
+            priority,
+            service,
+            held: None,
+        })
+    }
+
+    /// If a notification of the given attribute is queued and holds its value
+    /// for coalescing (see NotificationPermit::hold_value()), replace that
+    /// value with the given one, so that the value it held is never sent, and
+    /// return None. Otherwise, returns the value, to be sent with a permit of
+    /// its own.
+    pub fn try_coalesce(
+        &self,
+        handle: AttHandle,
+        data: AttAttributeDataChild,
+    ) -> Option<AttAttributeDataChild> {
+        match self.queue.coalesced_values.borrow().get(&handle) {
+            Some(held) => {
+                held.replace(Some(data));
+                None
+            }
+            None => Some(data),
+        }
+    }
+
+    /// The handle of the declaration of the service containing the given
+    /// attribute, if any
+    fn find_service(&self, handle: AttHandle) -> Option<AttHandle> {
+        self.db
+            .attributes_in_range(AttHandle(1), handle, None)
+            .into_iter()
+            .rev()
+            .find(|attr| {
+                attr.type_ == PRIMARY_SERVICE_DECLARATION_UUID
+                    || attr.type_ == SECONDARY_SERVICE_DECLARATION_UUID
+            })
+            .map(|attr| attr.handle)
+    }
+}
+
+/// The value of a queued notification, shared with the queue if the
+/// notifications of its attribute are coalesced
+type HeldValue = Rc<RefCell<Option<AttAttributeDataChild>>>;
+
+/// A reserved slot in the notification queue of a connection
+pub struct NotificationPermit<T> {
+    db: T,
+    queue: Rc<NotificationQueue>,
+    priority: Priority,
+    service: Option<AttHandle>,
+    held: Option<(AttHandle, HeldValue)>,
+}
+
+impl<T: AttDatabase> NotificationPermit<T> {
+    /// Hold the value of the notification of the given attribute until it is
+    /// sent. If the notifications of the attribute are coalesced, the later
+    /// ones replace the value meanwhile, rather than being queued.
+    pub fn hold_value(mut self, handle: AttHandle, data: AttAttributeDataChild) -> Self {
+        let held = Rc::new(RefCell::new(Some(data)));
+        if self.db.coalesces_notifications(handle) {
+            self.queue.coalesced_values.borrow_mut().entry(handle).or_insert_with(|| held.clone());
+        }
+        self.held = Some((handle, held));
+        self
+    }
+
+    /// Take the latest value held by this permit, once it is time to send it.
+    /// Later notifications of the attribute are queued as usual.
+    pub fn take_value(&mut self) -> Option<AttAttributeDataChild> {
+        let (handle, held) = self.held.take()?;
+        self.queue.release_held_value(handle, &held);
+        held.take()
+    }
+
+    /// Wait until no notifications of a higher priority are queued on this
+    /// connection
+    pub async fn wait_for_turn(&self) {
+        loop {
+            // created before checking, so that no release can be missed
+            let released = self.queue.released.notified();
+            if !self.queue.has_queued_above(self.priority) {
+                return;
+            }
+            released.await;
+        }
+    }
+
+    /// Validate and send a notification, consuming this permit
+    pub fn send(
+        self,
+        handle: AttHandle,
+        data: AttAttributeDataChild,
+        mtu: usize,
+        send_packet: impl FnOnce(AttChild) -> Result<(), SendError>,
+    ) -> Result<(), NotificationError> {
+        let data_size = data
+            .size_in_bits()
+            .map_err(SendError::SerializeError)
+            .map_err(NotificationError::SendError)?;
+        // As per Core Spec 5.3 Vol 3F 3.4.7.1, the notified value must be at most
+        // ATT_MTU-3
+        if data_size > (mtu - 3) * 8 {
+            return Err(NotificationError::DataExceedsMtu { mtu: mtu - 3 });
+        }
+        self.check_attribute(handle)?;
+
+        send_packet(
+            AttHandleValueNotificationBuilder {
+                handle: handle.into(),
+                value: build_att_data(data),
+            }
+            .into(),
+        )
+        .map_err(NotificationError::SendError)
+    }
+
+    /// Check that the attribute supports notifications, and that the client
+    /// has subscribed to them (if its CCCD is managed by the database)
+    fn check_attribute(&self, handle: AttHandle) -> Result<(), NotificationError> {
+        if !self
+            .db
+            .snapshot()
+            .find_attribute(handle)
+            .ok_or(NotificationError::AttributeNotFound)?
+            .permissions
+            .notify()
+        {
+            warn!(
+                "cannot send notification for {handle:?} since it does not support notifications"
+            );
+            return Err(NotificationError::NotificationsNotSupported);
+        }
+
+        if !self
+            .db
+            .client_configuration(handle)
+            .map(|configuration| configuration.contains(ClientConfiguration::NOTIFICATION))
+            .unwrap_or(true)
+        {
+            warn!(
+                "cannot send notification for {handle:?} since the client has not subscribed to it"
+            );
+            return Err(NotificationError::ClientNotSubscribed);
+        }
+        Ok(())
+    }
+}
+
+/// Validate a batch of notifications (each with the permit reserved for it),
+/// and send them in order. If packing, consecutive values are packed into
+/// ATT_MULTIPLE_HANDLE_VALUE_NTF PDUs (Core Spec 5.3 Vol 3F 3.4.7.4) as long
+/// as they fit in the MTU, and a value that cannot be paired with its
+/// neighbours is sent as an ATT_HANDLE_VALUE_NTF. Nothing is sent unless the
+/// whole batch is valid.
+pub fn send_batch<T: AttDatabase>(
+    batch: Vec<(NotificationPermit<T>, AttHandle, Vec<u8>)>,
+    mtu: usize,
+    pack: bool,
+    mut send_packet: impl FnMut(AttChild) -> Result<(), SendError>,
+) -> Result<(), NotificationError> {
+    for (permit, handle, value) in &batch {
+        // each value must fit, should it end up in an individual notification
+        if value.len() > mtu - 3 {
+            return Err(NotificationError::DataExceedsMtu { mtu: mtu - 3 });
+        }
+        permit.check_attribute(*handle)?;
+    }
+
+    // the tuples of the PDU being packed, each with a 2-octet handle and length
+    let mut tuples: Vec<(AttHandle, Vec<u8>)> = vec![];
+    let mut flush = |tuples: &mut Vec<(AttHandle, Vec<u8>)>| -> Result<(), NotificationError> {
+        let packet = match tuples.len() {
+            0 => return Ok(()),
+            1 => {
+                let (handle, value) = tuples.pop().unwrap();
+                AttHandleValueNotificationBuilder {
+                    handle: handle.into(),
+                    value: build_att_data(AttAttributeDataChild::RawData(value.into())),
+                }
+                .into()
+            }
+            _ => {
+                let mut data = vec![];
+                for (handle, value) in tuples.drain(..) {
+                    data.extend_from_slice(&handle.0.to_le_bytes());
+                    data.extend_from_slice(&(value.len() as u16).to_le_bytes());
+                    data.extend_from_slice(&value);
+                }
+                AttMultipleHandleValueNotificationBuilder {
+                    value: build_att_data(AttAttributeDataChild::RawData(data.into())),
+                }
+                .into()
+            }
+        };
+        send_packet(packet).map_err(NotificationError::SendError)
+    };
+    // the permits are held until the whole batch is sent
+    let (_permits, notifications): (Vec<_>, Vec<_>) =
+        batch.into_iter().map(|(permit, handle, value)| (permit, (handle, value))).unzip();
+    let mut packed_len = 0;
+    for (handle, value) in notifications {
+        let tuple_len = 4 + value.len();
+        if !pack || packed_len + tuple_len > mtu - 1 {
+            flush(&mut tuples)?;
+            packed_len = 0;
+        }
+        packed_len += tuple_len;
+        tuples.push((handle, value));
+    }
+    flush(&mut tuples)
+}
+
+impl<T> Drop for NotificationPermit<T> {
+    fn drop(&mut self) {
+        if let Some((handle, held)) = &self.held {
+            self.queue.release_held_value(*handle, held);
+        }
+        let queued = self.queue.queued.get() - 1;
+        self.queue.queued.set(queued);
+        let priority_queued = &self.queue.queued_by_priority[self.priority as usize];
+        priority_queued.set(priority_queued.get() - 1);
+        if let Some(service) = self.service {
+            let mut queued_by_service = self.queue.queued_by_service.borrow_mut();
+            if let Some(service_queued) = queued_by_service.get_mut(&service) {
+                *service_queued -= 1;
+                if *service_queued == 0 {
+                    queued_by_service.remove(&service);
+                }
+            }
+        }
+        if queued <= self.queue.limits.low_watermark {
+            self.queue.set_congested(false);
+        }
+        self.queue.released.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::task::{spawn_local, yield_now};
+
+    use crate::{
+        core::uuid::Uuid,
+        gatt::server::{
+            att_database::AttAttribute, config::GattServerConfig, gatt_database::AttPermissions,
+            test::test_att_db::TestAttDatabase,
+        },
+        utils::task::block_on_locally,
+    };
+
+    use super::*;
+
+    const HANDLE: AttHandle = AttHandle(1);
+    const NON_NOTIFY_HANDLE: AttHandle = AttHandle(2);
+    const NONEXISTENT_HANDLE: AttHandle = AttHandle(3);
+    const DATA: [u8; 3] = [1, 2, 3];
+    const MTU: usize = 23;
+
+    fn get_att_database() -> TestAttDatabase {
+        TestAttDatabase::new(vec![
+            (
+                AttAttribute {
+                    handle: HANDLE,
+                    type_: Uuid::new(123),
+                    permissions: AttPermissions::NOTIFY,
+                },
+                vec![],
+            ),
+            (
+                AttAttribute {
+                    handle: NON_NOTIFY_HANDLE,
+                    type_: Uuid::new(123),
+                    permissions: AttPermissions::READABLE,
+                },
+                vec![],
+            ),
+        ])
+    }
+
+    fn get_data() -> AttAttributeDataChild {
+        AttAttributeDataChild::RawData(DATA.into())
+    }
+
+    #[test]
+    fn test_notification_sent() {
+        // arrange
+        let handler = NotificationHandler::new(get_att_database());
+        let mut sent = None;
+
+        // act
+        handler
+            .try_reserve(HANDLE, Priority::Normal)
+            .unwrap()
+            .send(HANDLE, get_data(), MTU, |packet| {
+                sent = Some(packet);
+                Ok(())
+            })
+            .unwrap();
+
+        // assert: that an AttHandleValueNotification was sent
+        assert_eq!(
+            sent.unwrap(),
+            AttHandleValueNotificationBuilder {
+                handle: HANDLE.into(),
+                value: build_att_data(get_data())
+            }
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_unsupported_permission() {
+        let handler = NotificationHandler::new(get_att_database());
+
+        let res = handler.try_reserve(NON_NOTIFY_HANDLE, Priority::Normal).unwrap().send(
+            NON_NOTIFY_HANDLE,
+            get_data(),
+            MTU,
+            |_| unreachable!(),
+        );
+
+        assert!(matches!(res, Err(NotificationError::NotificationsNotSupported)));
+    }
+
+    #[test]
+    fn test_nonexistent_handle() {
+        let handler = NotificationHandler::new(get_att_database());
+
+        let res = handler.try_reserve(NONEXISTENT_HANDLE, Priority::Normal).unwrap().send(
+            NONEXISTENT_HANDLE,
+            get_data(),
+            MTU,
+            |_| unreachable!(),
+        );
+
+        assert!(matches!(res, Err(NotificationError::AttributeNotFound)));
+    }
+
+    #[test]
+    fn test_data_exceeds_mtu() {
+        let handler = NotificationHandler::new(get_att_database());
+
+        let res = handler.try_reserve(HANDLE, Priority::Normal).unwrap().send(
+            HANDLE,
+            AttAttributeDataChild::RawData([0; MTU - 2].into()),
+            MTU,
+            |_| unreachable!(),
+        );
+
+        assert!(matches!(res, Err(NotificationError::DataExceedsMtu { mtu: 20 })));
+    }
+
+    #[test]
+    fn test_batch_packed_within_mtu() {
+        // arrange: three tuples of 10 octets, of which only two fit in MTU-1
+        let handler = NotificationHandler::new(get_att_database());
+        let batch = (0..3)
+            .map(|i| (handler.try_reserve(HANDLE, Priority::Normal).unwrap(), HANDLE, vec![i; 6]))
+            .collect();
+        let mut sent = vec![];
+
+        // act
+        send_batch(batch, MTU, true, |packet| {
+            sent.push(packet);
+            Ok(())
+        })
+        .unwrap();
+
+        // assert: the first two are packed, and the last is sent on its own
+        let mut packed = vec![];
+        for i in 0..2 {
+            packed.extend([0x01, 0x00, 0x06, 0x00]);
+            packed.extend([i; 6]);
+        }
+        assert_eq!(
+            sent,
+            vec![
+                AttMultipleHandleValueNotificationBuilder {
+                    value: build_att_data(AttAttributeDataChild::RawData(packed.into())),
+                }
+                .into(),
+                AttHandleValueNotificationBuilder {
+                    handle: HANDLE.into(),
+                    value: build_att_data(AttAttributeDataChild::RawData([2; 6].into())),
+                }
+                .into(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_batch_not_sent() {
+        let handler = NotificationHandler::new(get_att_database());
+        let batch = vec![
+            (handler.try_reserve(HANDLE, Priority::Normal).unwrap(), HANDLE, vec![1]),
+            (
+                handler.try_reserve(NON_NOTIFY_HANDLE, Priority::Normal).unwrap(),
+                NON_NOTIFY_HANDLE,
+                vec![2],
+            ),
+        ];
+
+        let res = send_batch(batch, MTU, true, |_| unreachable!());
+
+        assert!(matches!(res, Err(NotificationError::NotificationsNotSupported)));
+    }
+
+    #[test]
+    fn test_too_many_queued_notifications() {
+        // arrange: fill up the queue
+        let handler = NotificationHandler::new(get_att_database());
+        let permits = (0..MAX_QUEUED_NOTIFICATIONS)
+            .map(|_| handler.try_reserve(HANDLE, Priority::Normal).unwrap())
+            .collect::<Vec<_>>();
+
+        // act: try to queue another notification
+        let res = handler.try_reserve(HANDLE, Priority::Normal);
+
+        // assert: it was rejected
+        assert!(matches!(res, Err(NotificationError::Congested)));
+        drop(permits);
+    }
+
+    #[test]
+    fn test_configured_queue_size() {
+        // arrange: fill up a queue smaller than the default
+        let db = get_att_database();
+        db.set_config(GattServerConfig { max_queued_notifications: 4, ..Default::default() });
+        let handler = NotificationHandler::new(db);
+        let permits = (0..4)
+            .map(|_| handler.try_reserve(HANDLE, Priority::Normal).unwrap())
+            .collect::<Vec<_>>();
+
+        // act: try to queue another notification
+        let res = handler.try_reserve(HANDLE, Priority::Normal);
+
+        // assert: it was rejected
+        assert!(matches!(res, Err(NotificationError::Congested)));
+        drop(permits);
+    }
+
+    #[test]
+    fn test_queue_slot_released_after_send() {
+        // arrange: fill up the queue
+        let handler = NotificationHandler::new(get_att_database());
+        let mut permits = (0..MAX_QUEUED_NOTIFICATIONS)
+            .map(|_| handler.try_reserve(HANDLE, Priority::Normal).unwrap())
+            .collect::<Vec<_>>();
+
+        // act: send one of the queued notifications
+        permits.pop().unwrap().send(HANDLE, get_data(), MTU, |_| Ok(())).unwrap();
+
+        // assert: another notification can now be queued
+        assert_eq!(handler.queued(), MAX_QUEUED_NOTIFICATIONS - 1);
+        assert!(handler.try_reserve(HANDLE, Priority::Normal).is_ok());
+    }
+
+    #[test]
+    fn test_congestion_reported_once_until_drained() {
+        // arrange: fill up the queue
+        let handler = NotificationHandler::new(get_att_database());
+        let congestion = Rc::new(RefCell::new(vec![]));
+        handler.set_on_congestion({
+            let congestion = congestion.clone();
+            move |congested| congestion.borrow_mut().push(congested)
+        });
+        let mut permits = (0..MAX_QUEUED_NOTIFICATIONS)
+            .map(|_| handler.try_reserve(HANDLE, Priority::Normal).unwrap())
+            .collect::<Vec<_>>();
+
+        // act: overflow the queue twice, then drain it
+        assert!(handler.try_reserve(HANDLE, Priority::Normal).is_err());
+        assert!(handler.try_reserve(HANDLE, Priority::Normal).is_err());
+        permits.clear();
+
+        // assert: congestion was reported once, and then cleared
+        assert_eq!(*congestion.borrow(), vec![true, false]);
+    }
+
+    #[test]
+    fn test_congestion_reported_while_either_source_congested() {
+        // arrange
+        let handler = NotificationHandler::new(get_att_database());
+        let congestion = Rc::new(RefCell::new(vec![]));
+        handler.set_on_congestion({
+            let congestion = congestion.clone();
+            move |congested| congestion.borrow_mut().push(congested)
+        });
+
+        // act: the transport withholds credits while the queue fills up, then
+        // catches up before the queue drains
+        handler.set_transport_congested(true);
+        let mut permits = (0..CONGESTION_HIGH_WATERMARK)
+            .map(|_| handler.try_reserve(HANDLE, Priority::Normal).unwrap())
+            .collect::<Vec<_>>();
+        handler.set_transport_congested(false);
+        let still_congested = congestion.borrow().clone();
+        permits.clear();
+
+        // assert: congestion was reported once, and only cleared once both
+        // sources cleared
+        assert_eq!(still_congested, vec![true]);
+        assert_eq!(*congestion.borrow(), vec![true, false]);
+    }
+
+    #[test]
+    fn test_congestion_follows_watermarks() {
+        // arrange
+        let handler = NotificationHandler::new(get_att_database());
+        let congestion = Rc::new(RefCell::new(vec![]));
+        handler.set_on_congestion({
+            let congestion = congestion.clone();
+            move |congested| congestion.borrow_mut().push(congested)
+        });
+        let mut permits = (0..CONGESTION_HIGH_WATERMARK - 1)
+            .map(|_| handler.try_reserve(HANDLE, Priority::Normal).unwrap())
+            .collect::<Vec<_>>();
+        assert!(congestion.borrow().is_empty());
+
+        // act: reach the high watermark, then drain down to just above the low watermark
+        permits.push(handler.try_reserve(HANDLE, Priority::Normal).unwrap());
+        permits.truncate(CONGESTION_LOW_WATERMARK + 1);
+
+        // assert: congestion was reported, but not yet cleared
+        assert_eq!(*congestion.borrow(), vec![true]);
+
+        // act: reach the low watermark
+        permits.pop();
+
+        // assert: congestion was cleared
+        assert_eq!(*congestion.borrow(), vec![true, false]);
+    }
+
+    fn get_att_database_with_services() -> TestAttDatabase {
+        let attr = |handle, type_, permissions| {
+            (AttAttribute { handle: AttHandle(handle), type_, permissions }, vec![])
+        };
+        TestAttDatabase::new(vec![
+            attr(1, PRIMARY_SERVICE_DECLARATION_UUID, AttPermissions::READABLE),
+            attr(2, Uuid::new(123), AttPermissions::NOTIFY),
+            attr(3, SECONDARY_SERVICE_DECLARATION_UUID, AttPermissions::READABLE),
+            attr(4, Uuid::new(123), AttPermissions::NOTIFY),
+        ])
+    }
+
+    #[test]
+    fn test_service_quota() {
+        // arrange: fill up the quota of the first service
+        let handler = NotificationHandler::new(get_att_database_with_services());
+        let permits = (0..MAX_QUEUED_NOTIFICATIONS_PER_SERVICE)
+            .map(|_| handler.try_reserve(AttHandle(2), Priority::Low).unwrap())
+            .collect::<Vec<_>>();
+
+        // act: try to queue another notification from each service
+        let first = handler.try_reserve(AttHandle(2), Priority::High);
+        let second = handler.try_reserve(AttHandle(4), Priority::High);
+
+        // assert: only the service within its quota could queue a notification
+        assert!(matches!(first, Err(NotificationError::ServiceQuotaExceeded)));
+        assert!(second.is_ok());
+        drop(permits);
+    }
+
+    #[test]
+    fn test_service_quota_released_with_permit() {
+        // arrange: fill up the quota of a service
+        let handler = NotificationHandler::new(get_att_database_with_services());
+        let mut permits = (0..MAX_QUEUED_NOTIFICATIONS_PER_SERVICE)
+            .map(|_| handler.try_reserve(AttHandle(2), Priority::Normal).unwrap())
+            .collect::<Vec<_>>();
+
+        // act: release one of its notifications
+        permits.pop();
+
+        // assert: the service can queue another notification
+        assert!(handler.try_reserve(AttHandle(2), Priority::Normal).is_ok());
+    }
+
+    #[test]
+    fn test_higher_priority_sent_first() {
+        block_on_locally(async {
+            // arrange: a low priority notification queued behind a high priority one
+            let handler = NotificationHandler::new(get_att_database());
+            let high = handler.try_reserve(HANDLE, Priority::High).unwrap();
+            let low = handler.try_reserve(HANDLE, Priority::Low).unwrap();
+            let sent = Rc::new(RefCell::new(vec![]));
+            let pending_low = spawn_local({
+                let sent = sent.clone();
+                async move {
+                    low.wait_for_turn().await;
+                    sent.borrow_mut().push(Priority::Low);
+                }
+            });
+            yield_now().await;
+            assert!(sent.borrow().is_empty());
+
+            // act: the high priority notification is sent
+            high.wait_for_turn().await;
+            sent.borrow_mut().push(Priority::High);
+            drop(high);
+            pending_low.await.unwrap();
+
+            // assert: the low priority notification went out afterwards
+            assert_eq!(*sent.borrow(), vec![Priority::High, Priority::Low]);
+        });
+    }
+
+    #[test]
+    fn test_equal_priority_not_blocked() {
+        block_on_locally(async {
+            // arrange
+            let handler = NotificationHandler::new(get_att_database());
+            let first = handler.try_reserve(HANDLE, Priority::Normal).unwrap();
+            let second = handler.try_reserve(HANDLE, Priority::Normal).unwrap();
+
+            // act + assert: neither waits behind the other
+            second.wait_for_turn().await;
+            drop(second);
+            first.wait_for_turn().await;
+        });
+    }
+
+    #[test]
+    fn test_no_congestion_reported_below_limit() {
+        // arrange
+        let handler = NotificationHandler::new(get_att_database());
+        let congestion = Rc::new(RefCell::new(vec![]));
+        handler.set_on_congestion({
+            let congestion = congestion.clone();
+            move |congested| congestion.borrow_mut().push(congested)
+        });
+
+        // act: queue and release a notification
+        drop(handler.try_reserve(HANDLE, Priority::Normal).unwrap());
+
+        // assert: nothing was reported
+        assert!(congestion.borrow().is_empty());
+    }
+
+    fn raw_data(value: &[u8]) -> AttAttributeDataChild {
+        AttAttributeDataChild::RawData(value.into())
+    }
+
+    #[test]
+    fn test_coalesced_value_replaced_while_queued() {
+        // arrange: a queued notification of a coalesced attribute
+        let db = get_att_database();
+        db.coalesce_notifications(HANDLE);
+        let handler = NotificationHandler::new(db);
+        let mut permit = handler
+            .try_reserve(HANDLE, Priority::Normal)
+            .unwrap()
+            .hold_value(HANDLE, raw_data(&[1]));
+
+        // act: two more notifications are sent before it
+        let second = handler.try_coalesce(HANDLE, raw_data(&[2]));
+        let third = handler.try_coalesce(HANDLE, raw_data(&[3]));
+
+        // assert: they took no slot, and the queued notification holds the latest value
+        assert!(second.is_none());
+        assert!(third.is_none());
+        assert_eq!(handler.queued(), 1);
+        assert_eq!(permit.take_value(), Some(raw_data(&[3])));
+    }
+
+    #[test]
+    fn test_coalescing_stops_once_value_taken() {
+        // arrange: a queued notification of a coalesced attribute, about to be sent
+        let db = get_att_database();
+        db.coalesce_notifications(HANDLE);
+        let handler = NotificationHandler::new(db);
+        let mut permit = handler
+            .try_reserve(HANDLE, Priority::Normal)
+            .unwrap()
+            .hold_value(HANDLE, raw_data(&[1]));
+        permit.take_value();
+
+        // act
+        let next = handler.try_coalesce(HANDLE, raw_data(&[2]));
+
+        // assert: the next notification needs a slot of its own
+        assert_eq!(next, Some(raw_data(&[2])));
+    }
+
+    #[test]
+    fn test_coalescing_stops_once_permit_dropped() {
+        // arrange
+        let db = get_att_database();
+        db.coalesce_notifications(HANDLE);
+        let handler = NotificationHandler::new(db);
+        let permit = handler
+            .try_reserve(HANDLE, Priority::Normal)
+            .unwrap()
+            .hold_value(HANDLE, raw_data(&[1]));
+
+        // act: the queued notification is cancelled
+        drop(permit);
+        let next = handler.try_coalesce(HANDLE, raw_data(&[2]));
+
+        // assert
+        assert_eq!(next, Some(raw_data(&[2])));
+    }
+
+    #[test]
+    fn test_not_coalesced_by_default() {
+        // arrange: a queued notification of an attribute that is not coalesced
+        let handler = NotificationHandler::new(get_att_database());
+        let _permit = handler
+            .try_reserve(HANDLE, Priority::Normal)
+            .unwrap()
+            .hold_value(HANDLE, raw_data(&[1]));
+
+        // act
+        let next = handler.try_coalesce(HANDLE, raw_data(&[2]));
+
+        // assert: both are sent
+        assert_eq!(next, Some(raw_data(&[2])));
+    }
+}
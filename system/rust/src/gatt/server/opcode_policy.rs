@@ -0,0 +1,183 @@
+//! A GattModule may have an OpcodePolicy, which decides which ATT operations
+//! each connection may perform at all, before they are dispatched. Rejected
+//! requests are answered with the error chosen by the policy, and rejected
+//! commands are dropped. MTU exchanges and confirmations are never blocked,
+//! since they are needed to keep the bearer itself working.
+//!
+//! The policy sees only the opcode, so restrictions on the attributes that may
+//! be read are left to an AccessInterceptor (see the access_policy module).
+//! RestrictedPeers implements both, so carrier or enterprise policies can be
+//! enforced in one place.
+
+use std::{cell::RefCell, collections::HashSet};
+
+use log::warn;
+
+use crate::{
+    gatt::ids::TransportIndex,
+    packets::{AttErrorCode, AttOpcode},
+};
+
+use super::{
+    access_policy::{AccessContext, AccessInterceptor},
+    authorization::AttributeAccess,
+    services::gap::GAP_SERVICE_UUID,
+};
+
+/// Decides which ATT operations a connection may perform
+pub trait OpcodePolicy {
+    /// Check whether the client on the given transport may perform an
+    /// operation with the given opcode. Otherwise, returns the error with
+    /// which to reject it.
+    fn check_opcode(&self, tcb_idx: TransportIndex, opcode: AttOpcode) -> Result<(), AttErrorCode>;
+}
+
+/// Whether the opcode is exempt from any OpcodePolicy
+pub fn is_exempt(opcode: AttOpcode) -> bool {
+    matches!(opcode, AttOpcode::EXCHANGE_MTU_REQUEST | AttOpcode::HANDLE_VALUE_CONFIRMATION)
+}
+
+/// Only lets restricted peers discover the database and read the GAP service.
+/// Other read requests are rejected with READ_NOT_PERMITTED, other requests
+/// with REQUEST_NOT_SUPPORTED, and commands are dropped. Peers that are not
+/// restricted are unaffected.
+///
+/// To restrict reads to the GAP service, this must be set as the
+/// AccessInterceptor of each server, besides the OpcodePolicy of the module.
+#[derive(Debug, Default)]
+pub struct RestrictedPeers {
+    restricted: RefCell<HashSet<TransportIndex>>,
+}
+
+impl RestrictedPeers {
+    /// Constructor, with no restricted peers
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Restrict the peer on the given transport, until it disconnects (or is
+    /// unrestricted)
+    pub fn restrict(&self, tcb_idx: TransportIndex) {
+        self.restricted.borrow_mut().insert(tcb_idx);
+    }
+
+    /// Lift the restrictions on the peer on the given transport. This must
+    /// also be called once it disconnects, since the transport may be reused.
+    pub fn unrestrict(&self, tcb_idx: TransportIndex) {
+        self.restricted.borrow_mut().remove(&tcb_idx);
+    }
+
+    /// Whether the peer on the given transport is restricted
+    pub fn is_restricted(&self, tcb_idx: TransportIndex) -> bool {
+        self.restricted.borrow().contains(&tcb_idx)
+    }
+}
+
+impl OpcodePolicy for RestrictedPeers {
+    fn check_opcode(&self, tcb_idx: TransportIndex, opcode: AttOpcode) -> Result<(), AttErrorCode> {
+        if !self.is_restricted(tcb_idx) {
+            return Ok(());
+        }
+        match opcode {
+            AttOpcode::FIND_INFORMATION_REQUEST
+            | AttOpcode::FIND_BY_TYPE_VALUE_REQUEST
+            | AttOpcode::READ_BY_GROUP_TYPE_REQUEST
+            | AttOpcode::READ_BY_TYPE_REQUEST
+            | AttOpcode::READ_REQUEST
+            | AttOpcode::READ_BLOB_REQUEST => Ok(()),
+            AttOpcode::READ_MULTIPLE_REQUEST | AttOpcode::READ_MULTIPLE_VARIABLE_REQUEST => {
+                warn!("rejecting {opcode:?} from restricted peer on {tcb_idx}");
+                Err(AttErrorCode::READ_NOT_PERMITTED)
+            }
+            _ => {
+                warn!("rejecting {opcode:?} from restricted peer on {tcb_idx}");
+                Err(AttErrorCode::REQUEST_NOT_SUPPORTED)
+            }
+        }
+    }
+}
+
+impl AccessInterceptor for RestrictedPeers {
+    fn check_access(&self, context: &AccessContext) -> Result<(), AttErrorCode> {
+        if !self.is_restricted(context.tcb_idx) || context.service_type == GAP_SERVICE_UUID {
+            return Ok(());
+        }
+        warn!(
+            "rejecting {:?} of {} in service {} from restricted peer on {}",
+            context.access, context.handle, context.service_type, context.tcb_idx
+        );
+        match context.access {
+            AttributeAccess::Read => Err(AttErrorCode::READ_NOT_PERMITTED),
+            AttributeAccess::Write => Err(AttErrorCode::WRITE_NOT_PERMITTED),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{core::uuid::Uuid, gatt::ids::AttHandle};
+
+    use super::*;
+
+    const TCB_IDX: TransportIndex = TransportIndex(1);
+    const ANOTHER_TCB_IDX: TransportIndex = TransportIndex(2);
+
+    fn restricted() -> RestrictedPeers {
+        let policy = RestrictedPeers::new();
+        policy.restrict(TCB_IDX);
+        policy
+    }
+
+    fn read_of(service_type: Uuid) -> AccessContext {
+        AccessContext {
+            tcb_idx: TCB_IDX,
+            bonded: false,
+            service_type,
+            handle: AttHandle(3),
+            access: AttributeAccess::Read,
+        }
+    }
+
+    #[test]
+    fn test_restricted_peer_may_discover() {
+        let policy = restricted();
+
+        assert_eq!(policy.check_opcode(TCB_IDX, AttOpcode::READ_BY_GROUP_TYPE_REQUEST), Ok(()));
+        assert_eq!(policy.check_opcode(TCB_IDX, AttOpcode::FIND_INFORMATION_REQUEST), Ok(()));
+        assert_eq!(policy.check_opcode(TCB_IDX, AttOpcode::READ_REQUEST), Ok(()));
+    }
+
+    #[test]
+    fn test_restricted_peer_may_not_write() {
+        let policy = restricted();
+
+        assert_eq!(
+            policy.check_opcode(TCB_IDX, AttOpcode::WRITE_REQUEST),
+            Err(AttErrorCode::REQUEST_NOT_SUPPORTED)
+        );
+        assert_eq!(
+            policy.check_opcode(TCB_IDX, AttOpcode::READ_MULTIPLE_REQUEST),
+            Err(AttErrorCode::READ_NOT_PERMITTED)
+        );
+    }
+
+    #[test]
+    fn test_unrestricted_peer_unaffected() {
+        let policy = restricted();
+
+        assert_eq!(policy.check_opcode(ANOTHER_TCB_IDX, AttOpcode::WRITE_REQUEST), Ok(()));
+        policy.unrestrict(TCB_IDX);
+        assert_eq!(policy.check_opcode(TCB_IDX, AttOpcode::WRITE_REQUEST), Ok(()));
+    }
+
+    #[test]
+    fn test_restricted_peer_may_only_read_gap() {
+        let policy = restricted();
+
+        assert_eq!(policy.check_access(&read_of(GAP_SERVICE_UUID)), Ok(()));
+        assert_eq!(
+            policy.check_access(&read_of(Uuid::new(0x1234))),
+            Err(AttErrorCode::READ_NOT_PERMITTED)
+        );
+    }
+}
@@ -0,0 +1,124 @@
+//! This module classifies the raw PDUs received from a client before they
+//! reach the transaction handlers, so that malformed PDUs are handled
+//! consistently (Core Spec 5.3 Vol 3F 3.3 and 3.4.1.1):
+//!
+//! - a request with an opcode the server does not know is rejected with
+//!   REQUEST_NOT_SUPPORTED
+//! - a request with a known opcode but a malformed body is rejected with
+//!   INVALID_PDU, by the request handler once it parses the body
+//! - a command with an unknown opcode or a malformed body, and any PDU that a
+//!   client should never send to a server (e.g. a response or notification),
+//!   is dropped silently
+//!
+//! Every request gets exactly one reply, and no PDU can cause a panic.
+
+use log::warn;
+
+use crate::packets::{AttBuilder, AttChild, AttErrorCode, AttOpcode, OwnedAttView, OwnedPacket};
+
+/// The Command Flag of an ATT opcode, set on all commands (Core Spec 5.3 Vol
+/// 3F 3.3.1)
+const COMMAND_FLAG: u8 = 0x40;
+
+/// The outcome of decoding a raw PDU received from a client
+pub enum DecodedPdu {
+    /// The PDU has a known opcode, and should be processed (though its body
+    /// may still turn out to be malformed)
+    Parsed(OwnedAttView),
+    /// The PDU is a request with the given opcode, which the server does not
+    /// know, so it must be rejected with REQUEST_NOT_SUPPORTED
+    UnsupportedRequest(u8),
+    /// The PDU should be dropped silently
+    Ignored,
+}
+
+/// Classify a raw PDU received from a client
+pub fn decode_pdu(pdu: &[u8]) -> DecodedPdu {
+    let Some(&opcode) = pdu.first() else {
+        warn!("dropping empty ATT PDU");
+        return DecodedPdu::Ignored;
+    };
+    match OwnedAttView::try_parse(pdu.into()) {
+        Ok(packet) => DecodedPdu::Parsed(packet),
+        Err(err) if AttOpcode::try_from(opcode).is_ok() => {
+            warn!("dropping malformed ATT PDU with opcode {opcode:#04x}: {err:?}");
+            DecodedPdu::Ignored
+        }
+        Err(_) if opcode & COMMAND_FLAG != 0 => {
+            warn!("dropping unsupported command {opcode:#04x}");
+            DecodedPdu::Ignored
+        }
+        Err(_) => {
+            warn!("rejecting unsupported request {opcode:#04x}");
+            DecodedPdu::UnsupportedRequest(opcode)
+        }
+    }
+}
+
+/// Build the reply to a request with an unknown opcode. It is assembled by
+/// hand, since the opcode in error cannot be represented as an AttOpcode.
+pub fn build_unsupported_request_reply(opcode: u8) -> AttBuilder {
+    // the opcode in error, the handle in error (always 0), and the error code
+    let [handle_lo, handle_hi] = 0u16.to_le_bytes();
+    let payload = vec![opcode, handle_lo, handle_hi, AttErrorCode::REQUEST_NOT_SUPPORTED.into()];
+    AttBuilder { opcode: AttOpcode::ERROR_RESPONSE, _child_: AttChild::RawData(payload.into()) }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::gatt::ids::AttHandle;
+    use crate::packets::{AttReadRequestBuilder, Serializable};
+
+    use super::*;
+
+    #[test]
+    fn test_known_request_parsed() {
+        let pdu = AttBuilder {
+            opcode: AttOpcode::READ_REQUEST,
+            _child_: AttReadRequestBuilder { attribute_handle: AttHandle(3).into() }.into(),
+        }
+        .to_vec()
+        .unwrap();
+
+        let decoded = decode_pdu(&pdu);
+
+        assert!(matches!(decoded, DecodedPdu::Parsed(_)));
+    }
+
+    #[test]
+    fn test_unknown_request_unsupported() {
+        // 0x14 is reserved, and does not have the Command Flag set
+        let decoded = decode_pdu(&[0x14, 0x01, 0x02]);
+
+        assert!(matches!(decoded, DecodedPdu::UnsupportedRequest(0x14)));
+    }
+
+    #[test]
+    fn test_unknown_command_ignored() {
+        // 0x54 is reserved, but has the Command Flag set
+        let decoded = decode_pdu(&[0x54, 0x01, 0x02]);
+
+        assert!(matches!(decoded, DecodedPdu::Ignored));
+    }
+
+    #[test]
+    fn test_empty_pdu_ignored() {
+        let decoded = decode_pdu(&[]);
+
+        assert!(matches!(decoded, DecodedPdu::Ignored));
+    }
+
+    #[test]
+    fn test_every_opcode_decoded_without_panicking() {
+        for opcode in 0..=u8::MAX {
+            decode_pdu(&[opcode]);
+        }
+    }
+
+    #[test]
+    fn test_unsupported_request_reply() {
+        let reply = build_unsupported_request_reply(0x14);
+
+        assert_eq!(reply.to_vec().unwrap(), vec![0x01, 0x14, 0x00, 0x00, 0x06]);
+    }
+}
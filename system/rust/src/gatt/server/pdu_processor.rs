@@ -0,0 +1,76 @@
+//! This module provides a sans-IO entry point into the ATT server, which
+//! processes raw PDUs received from a client and returns the reply to each,
+//! rather than sending it over a bearer. It drives the same AttServerCore as
+//! the AttServerBearer, but awaits each transaction in turn rather than
+//! scheduling it on the runtime, so it is primarily intended for fuzzing and
+//! for replaying captured PDUs.
+
+use std::{collections::VecDeque, time::Instant};
+
+use crate::{gatt::mtu::AttMtu, packets::AttBuilder};
+
+use super::{
+    att_database::AttDatabase,
+    att_server_core::{Action, AttServerCore},
+    command_handler::process_command,
+    mtu_audit::audit_outgoing_pdu,
+    request_handler::AttRequestHandler,
+    signature_verifier::SignatureVerifier,
+};
+
+/// Processes the PDUs received on a single (unenhanced) bearer, one at a time
+pub struct AttPduProcessor<Db: AttDatabase> {
+    db: Db,
+    core: AttServerCore,
+    request_handler: AttRequestHandler<Db>,
+    signature_verifier: SignatureVerifier,
+}
+
+impl<Db: AttDatabase + Clone + 'static> AttPduProcessor<Db> {
+    /// Constructor. The server_rx_mtu is offered to the client if it exchanges
+    /// the MTU.
+    pub fn new(db: Db, signature_verifier: SignatureVerifier, server_rx_mtu: usize) -> Self {
+        Self {
+            request_handler: AttRequestHandler::new(db.clone()),
+            db,
+            core: AttServerCore::new(AttMtu::new(), server_rx_mtu),
+            signature_verifier,
+        }
+    }
+
+    /// The MTU currently in use
+    pub fn mtu(&self) -> usize {
+        self.core.mtu().snapshot_or_default()
+    }
+
+    /// Process a PDU received from the client to completion, and return the
+    /// reply to send (if any). Malformed PDUs are handled as described in the
+    /// pdu_decoder module: commands never get a reply, and neither do PDUs
+    /// that a client should never send to a server.
+    pub async fn process_pdu(&mut self, pdu: &[u8]) -> Option<AttBuilder> {
+        let mut actions = VecDeque::from(self.core.handle_rx_pdu(pdu, Instant::now()));
+        let mut reply = None;
+        while let Some(action) = actions.pop_front() {
+            match action {
+                Action::Send(packet) => {
+                    audit_outgoing_pdu(&packet, self.mtu());
+                    reply = Some(packet)
+                }
+                Action::StartTransaction { request, mtu, .. } => {
+                    let response = self.request_handler.process_packet(request.view(), mtu).await;
+                    actions.extend(self.core.complete_transaction(
+                        response,
+                        self.request_handler.prepared_write_queue_depth(),
+                        Instant::now(),
+                    ));
+                }
+                Action::ProcessCommand(command) => {
+                    process_command(&self.db, &self.signature_verifier, command.view())
+                }
+                // there are no indications, upper layers, or channel to involve
+                Action::ConfirmIndication | Action::Event(_) | Action::Close(_) => {}
+            }
+        }
+        reply
+    }
+}
@@ -0,0 +1,349 @@
+//! This module replays the PDUs that clients sent in a btsnoop capture (e.g.
+//! from a field log) into the sans-IO ATT server, backed by a database
+//! described by the user, and compares the replies it produces with those
+//! captured. This is meant for triaging interop issues: a mismatch either
+//! points at a difference between the described database and that of the
+//! device, or at a behavior change of the server.
+//!
+//! The database is described in text, with one attribute per line:
+//!
+//! ```text
+//! # handle  type  permissions             value (hex, optional)
+//! 0x0001    2800  READABLE                0018
+//! 0x0003    2a00  READABLE|NOTIFY         4269726f
+//! ```
+//!
+//! The handle is in decimal or hex, the type in any form accepted by Uuid,
+//! and the permissions are a `|`-separated list of AttPermissions flags.
+//! Declarations are not generated, so they have to be listed with their
+//! values, as they appear in the database of the device.
+
+pub mod btsnoop;
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fmt,
+    rc::Rc,
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::warn;
+use tokio::runtime::Builder;
+
+use crate::{
+    core::uuid::Uuid,
+    gatt::{
+        ids::{AttHandle, TransportIndex},
+        mocks::mock_security_manager::MockSecurityManager,
+        opcode_types::{classify_opcode, OperationType},
+    },
+    packets::{AttOpcode, Serializable},
+};
+
+use self::btsnoop::{parse_att_frames, AttFrame};
+
+use super::{
+    att_database::{AttAttribute, AttPermissions},
+    pdu_processor::AttPduProcessor,
+    signature_verifier::SignatureVerifier,
+    test::test_att_db::TestAttDatabase,
+    trace::Direction,
+};
+
+/// A request whose replayed reply differs from the captured one
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplayMismatch {
+    /// The ACL connection handle
+    pub connection: u16,
+    /// The request sent by the client
+    pub request: Vec<u8>,
+    /// The reply in the capture, if any was captured before the next request
+    pub captured: Option<Vec<u8>>,
+    /// The reply produced by the replayed server
+    pub replayed: Vec<u8>,
+}
+
+impl fmt::Display for ReplayMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "connection {:#05x}: request {:02x?} replayed {:02x?}, captured {:02x?}",
+            self.connection, self.request, self.replayed, self.captured
+        )
+    }
+}
+
+/// The outcome of replaying a capture
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+    /// The number of PDUs to which the replayed server replied
+    pub requests: usize,
+    /// The replies that differ from the capture, in capture order
+    pub mismatches: Vec<ReplayMismatch>,
+}
+
+/// The attributes of the database against which a capture is replayed
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DatabaseDescription {
+    attributes: Vec<(AttAttribute, Vec<u8>)>,
+}
+
+/// Parses the textual description (see the module documentation)
+impl FromStr for DatabaseDescription {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut attributes: Vec<(AttAttribute, Vec<u8>)> = vec![];
+        for (i, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let attribute = parse_attribute(line).with_context(|| format!("line {}", i + 1))?;
+            if let Some((previous, _)) = attributes.last() {
+                if previous.handle >= attribute.0.handle {
+                    bail!("line {}: handles must be increasing", i + 1);
+                }
+            }
+            attributes.push(attribute);
+        }
+        Ok(Self { attributes })
+    }
+}
+
+fn parse_attribute(line: &str) -> Result<(AttAttribute, Vec<u8>)> {
+    let fields = line.split_whitespace().collect::<Vec<_>>();
+    let (handle, type_, permissions, value) = match fields.as_slice() {
+        [handle, type_, permissions] => (handle, type_, permissions, ""),
+        [handle, type_, permissions, value] => (handle, type_, permissions, *value),
+        _ => bail!("expected a handle, a type, permissions, and optionally a value"),
+    };
+    let handle = match handle.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => handle.parse(),
+    }
+    .map_err(|_| anyhow!("invalid handle {handle:?}"))?;
+    if handle == 0 {
+        bail!("handle 0 is reserved");
+    }
+    let type_ = type_.parse::<Uuid>()?;
+    let permissions = bitflags::parser::from_str::<AttPermissions>(permissions)
+        .map_err(|err| anyhow!("invalid permissions {permissions:?}: {err}"))?;
+    if value.len() % 2 != 0 {
+        bail!("invalid value {value:?}");
+    }
+    let value = (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&value[i..i + 2], 16))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| anyhow!("invalid value {value:?}"))?;
+    Ok((AttAttribute { handle: AttHandle(handle), type_, permissions }, value))
+}
+
+/// The state of the replay on a single connection
+struct ConnectionReplay {
+    processor: AttPduProcessor<TestAttDatabase>,
+    /// The last request the replayed server replied to, with its reply, until
+    /// the captured reply is found
+    pending: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Replay the PDUs sent by clients in the given btsnoop capture into an ATT
+/// server (offering the given MTU), backed by the described database, and
+/// compare the replies with those in the capture. All the
+/// connections in the capture share the database, as they would on the
+/// device.
+pub fn replay_capture(
+    capture: &[u8],
+    database: &DatabaseDescription,
+    server_rx_mtu: usize,
+) -> Result<ReplayReport> {
+    let frames = parse_att_frames(capture)?;
+    let db = TestAttDatabase::new(database.attributes.clone());
+    let security_manager = Rc::new(MockSecurityManager::new());
+    let rt = Builder::new_current_thread().build()?;
+    Ok(rt.block_on(async move {
+        let mut report = ReplayReport::default();
+        let mut connections = HashMap::new();
+        for AttFrame { connection, direction, pdu } in frames {
+            let replay = match connections.entry(connection) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(ConnectionReplay {
+                    processor: AttPduProcessor::new(
+                        db.clone(),
+                        SignatureVerifier::new(
+                            TransportIndex(connection as u8),
+                            security_manager.clone(),
+                        ),
+                        server_rx_mtu,
+                    ),
+                    pending: None,
+                }),
+            };
+            match direction {
+                Direction::Rx => {
+                    let Some(reply) = replay.processor.process_pdu(&pdu).await else {
+                        continue;
+                    };
+                    report.requests += 1;
+                    let reply = reply.to_vec().unwrap_or_default();
+                    if let Some((request, replayed)) = replay.pending.replace((pdu, reply)) {
+                        // the capture has no reply to the previous request
+                        report.mismatches.push(ReplayMismatch {
+                            connection,
+                            request,
+                            captured: None,
+                            replayed,
+                        });
+                    }
+                }
+                Direction::Tx => {
+                    let is_reply = pdu
+                        .first()
+                        .and_then(|opcode| AttOpcode::try_from(*opcode).ok())
+                        .map(|opcode| matches!(classify_opcode(opcode), OperationType::Response))
+                        .unwrap_or(false);
+                    if !is_reply {
+                        continue;
+                    }
+                    let Some((request, replayed)) = replay.pending.take() else {
+                        warn!("captured reply {pdu:02x?} on {connection:#05x} was not replayed");
+                        continue;
+                    };
+                    if replayed != pdu {
+                        report.mismatches.push(ReplayMismatch {
+                            connection,
+                            request,
+                            captured: Some(pdu),
+                            replayed,
+                        });
+                    }
+                }
+            }
+        }
+        let mut unanswered = connections
+            .into_iter()
+            .filter_map(|(connection, replay)| Some((connection, replay.pending?)))
+            .collect::<Vec<_>>();
+        unanswered.sort_by_key(|(connection, _)| *connection);
+        for (connection, (request, replayed)) in unanswered {
+            report.mismatches.push(ReplayMismatch {
+                connection,
+                request,
+                captured: None,
+                replayed,
+            });
+        }
+        report
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::gatt::mtu::MAX_ATT_MTU;
+
+    use super::{btsnoop::test_capture::CaptureBuilder, *};
+
+    const CONNECTION: u16 = 0x0040;
+
+    const DESCRIPTION: &str = "
+        # a service with a single readable characteristic
+        0x0001 2800 READABLE 0f18
+        0x0002 2803 READABLE 020300192a
+        3      2a19 READABLE|NOTIFY 64
+    ";
+
+    #[test]
+    fn test_parse_database_description() {
+        let attributes = DESCRIPTION.parse::<DatabaseDescription>().unwrap().attributes;
+
+        assert_eq!(attributes.len(), 3);
+        assert_eq!(
+            attributes[2],
+            (
+                AttAttribute {
+                    handle: AttHandle(3),
+                    type_: Uuid::new(0x2a19),
+                    permissions: AttPermissions::READABLE | AttPermissions::NOTIFY
+                },
+                vec![0x64]
+            )
+        );
+    }
+
+    #[test]
+    fn test_reject_malformed_description() {
+        for description in [
+            "0x0001 2800",
+            "0x0001 2800 READABLE 0",
+            "0x0001 2800 SHINY",
+            "2 2800 READABLE\n1 2800 READABLE",
+        ] {
+            assert!(description.parse::<DatabaseDescription>().is_err(), "{description:?}");
+        }
+    }
+
+    #[test]
+    fn test_matching_replies() {
+        // arrange: the client reads the characteristic, and gets its value
+        let capture = CaptureBuilder::new()
+            .att(CONNECTION, Direction::Rx, &[0x0A, 0x03, 0x00])
+            .att(CONNECTION, Direction::Tx, &[0x0B, 0x64])
+            .build();
+
+        // act
+        let report = replay_capture(&capture, &DESCRIPTION.parse().unwrap(), MAX_ATT_MTU).unwrap();
+
+        // assert
+        assert_eq!(report, ReplayReport { requests: 1, mismatches: vec![] });
+    }
+
+    #[test]
+    fn test_mismatching_reply_reported() {
+        // arrange: the device replied with another value, then did not reply at all
+        let capture = CaptureBuilder::new()
+            .att(CONNECTION, Direction::Rx, &[0x0A, 0x03, 0x00])
+            .att(CONNECTION, Direction::Tx, &[0x0B, 0x32])
+            .att(CONNECTION, Direction::Rx, &[0x0A, 0x03, 0x00])
+            .build();
+
+        // act
+        let report = replay_capture(&capture, &DESCRIPTION.parse().unwrap(), MAX_ATT_MTU).unwrap();
+
+        // assert
+        assert_eq!(
+            report.mismatches,
+            vec![
+                ReplayMismatch {
+                    connection: CONNECTION,
+                    request: vec![0x0A, 0x03, 0x00],
+                    captured: Some(vec![0x0B, 0x32]),
+                    replayed: vec![0x0B, 0x64],
+                },
+                ReplayMismatch {
+                    connection: CONNECTION,
+                    request: vec![0x0A, 0x03, 0x00],
+                    captured: None,
+                    replayed: vec![0x0B, 0x64],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_notifications_not_compared() {
+        // arrange: a notification from the device precedes its reply
+        let capture = CaptureBuilder::new()
+            .att(CONNECTION, Direction::Rx, &[0x0A, 0x03, 0x00])
+            .att(CONNECTION, Direction::Tx, &[0x1B, 0x03, 0x00, 0x63])
+            .att(CONNECTION, Direction::Tx, &[0x0B, 0x64])
+            .build();
+
+        // act
+        let report = replay_capture(&capture, &DESCRIPTION.parse().unwrap(), MAX_ATT_MTU).unwrap();
+
+        // assert
+        assert!(report.mismatches.is_empty());
+    }
+}
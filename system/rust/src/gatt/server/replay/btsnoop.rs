@@ -0,0 +1,303 @@
+//! This module extracts the ATT PDUs exchanged on the fixed ATT channel from a
+//! btsnoop capture (as written by the Android snoop log), reassembling the
+//! L2CAP frames that span several ACL packets. The local device is assumed to
+//! be the server, so what the controller hands to the host came from a client.
+
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use log::warn;
+
+use crate::gatt::server::trace::Direction;
+
+/// The identification pattern starting every btsnoop capture
+const BTSNOOP_MAGIC: &[u8; 8] = b"btsnoop\0";
+
+/// The datalink type of captures of HCI packets without their H4 indicator
+const DATALINK_HCI_UNENCAPSULATED: u32 = 1001;
+/// The datalink type of captures of HCI packets with their H4 indicator
+const DATALINK_HCI_UART: u32 = 1002;
+
+/// The H4 indicator of ACL data packets
+const H4_ACL: u8 = 0x02;
+
+/// The flag of a record set if the packet went from the controller to the host
+const FLAG_RECEIVED: u32 = 0x01;
+/// The flag of a record set if the packet was a command or an event, rather
+/// than data
+const FLAG_COMMAND_OR_EVENT: u32 = 0x02;
+
+/// The packet boundary flag of ACL packets continuing an L2CAP frame
+const PB_CONTINUATION: u16 = 0b01;
+
+/// The CID of the fixed ATT channel over LE (Core Spec 5.3 Vol 3A 2.1)
+const ATT_CID: u16 = 0x0004;
+
+/// A PDU on the fixed ATT channel of a connection
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AttFrame {
+    /// The ACL connection handle
+    pub connection: u16,
+    /// Whether the PDU came from the client, or was sent by the server
+    pub direction: Direction,
+    /// The ATT PDU
+    pub pdu: Vec<u8>,
+}
+
+/// Parse the ATT frames out of a btsnoop capture, in capture order. Other
+/// packets are skipped, as are L2CAP frames that cannot be reassembled (e.g.
+/// since they started before the capture did).
+pub fn parse_att_frames(capture: &[u8]) -> Result<Vec<AttFrame>> {
+    let mut reader = Reader(capture);
+    if reader.bytes(8) != Some(&BTSNOOP_MAGIC[..]) {
+        bail!("not a btsnoop capture");
+    }
+    let (Some(version), Some(datalink)) = (reader.u32(), reader.u32()) else {
+        bail!("truncated btsnoop header");
+    };
+    if version != 1 {
+        bail!("unsupported btsnoop version {version}");
+    }
+    let has_indicator = match datalink {
+        DATALINK_HCI_UNENCAPSULATED => false,
+        DATALINK_HCI_UART => true,
+        _ => bail!("unsupported btsnoop datalink {datalink}"),
+    };
+
+    let mut frames = vec![];
+    let mut reassembler = Reassembler::default();
+    while !reader.0.is_empty() {
+        // original length, included length, flags, cumulative drops, timestamp
+        let (Some(_), Some(len), Some(flags), Some(_), Some(_)) =
+            (reader.u32(), reader.u32(), reader.u32(), reader.u32(), reader.bytes(8))
+        else {
+            bail!("truncated btsnoop record header");
+        };
+        let Some(mut packet) = reader.bytes(len as usize) else {
+            bail!("truncated btsnoop record");
+        };
+        if flags & FLAG_COMMAND_OR_EVENT != 0 {
+            continue;
+        }
+        if has_indicator {
+            match packet.split_first() {
+                Some((&H4_ACL, rest)) => packet = rest,
+                _ => continue,
+            }
+        }
+        let direction = if flags & FLAG_RECEIVED != 0 { Direction::Rx } else { Direction::Tx };
+        frames.extend(reassembler.on_acl_packet(direction, packet));
+    }
+    Ok(frames)
+}
+
+/// Reassembles the L2CAP frames of each connection, in each direction
+#[derive(Default)]
+struct Reassembler {
+    /// The L2CAP frames being reassembled, with their expected length
+    partial: HashMap<(u16, Direction), (usize, Vec<u8>)>,
+}
+
+impl Reassembler {
+    fn on_acl_packet(&mut self, direction: Direction, packet: &[u8]) -> Option<AttFrame> {
+        let mut reader = Reader(packet);
+        let (Some(header), Some(len)) = (reader.u16(), reader.u16()) else {
+            warn!("skipping truncated ACL packet");
+            return None;
+        };
+        let Some(data) = reader.bytes(len.into()) else {
+            warn!("skipping truncated ACL packet");
+            return None;
+        };
+        let connection = header & 0x0FFF;
+        let key = (connection, direction);
+        if (header >> 12) & 0b11 == PB_CONTINUATION {
+            let Some((_, frame)) = self.partial.get_mut(&key) else {
+                // the start of the frame was not captured
+                return None;
+            };
+            frame.extend_from_slice(data);
+        } else {
+            let Some(frame_len) = Reader(data).u16() else {
+                warn!("skipping ACL packet without a complete L2CAP header");
+                return None;
+            };
+            if self.partial.contains_key(&key) {
+                warn!("discarding incomplete L2CAP frame on connection {connection:#05x}");
+            }
+            // the length excludes the basic L2CAP header
+            self.partial.insert(key, (usize::from(frame_len) + 4, data.to_vec()));
+        }
+
+        let (expected_len, frame) = &self.partial[&key];
+        if frame.len() < *expected_len {
+            return None;
+        }
+        let (expected_len, frame) = self.partial.remove(&key).unwrap();
+        if frame.len() > expected_len {
+            warn!("discarding oversized L2CAP frame on connection {connection:#05x}");
+            return None;
+        }
+        let mut reader = Reader(&frame[2..]);
+        if reader.u16() != Some(ATT_CID) {
+            return None;
+        }
+        Some(AttFrame { connection, direction, pdu: reader.0.to_vec() })
+    }
+}
+
+/// Reads fields from the front of a buffer. The fields of btsnoop records are
+/// big-endian, while those of HCI and L2CAP are little-endian.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+}
+
+/// Builds btsnoop captures, for tests
+#[cfg(test)]
+pub mod test_capture {
+    use super::*;
+
+    /// A btsnoop capture being built, with the H4 datalink
+    pub struct CaptureBuilder(Vec<u8>);
+
+    impl Default for CaptureBuilder {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl CaptureBuilder {
+        /// Constructor, with just the header
+        pub fn new() -> Self {
+            let mut capture = BTSNOOP_MAGIC.to_vec();
+            capture.extend(1u32.to_be_bytes());
+            capture.extend(DATALINK_HCI_UART.to_be_bytes());
+            Self(capture)
+        }
+
+        /// Append a record with the given flags and packet, including its H4
+        /// indicator
+        pub fn record(mut self, flags: u32, packet: &[u8]) -> Self {
+            let len = packet.len() as u32;
+            for field in [len, len, flags, 0] {
+                self.0.extend(field.to_be_bytes());
+            }
+            self.0.extend([0; 8]);
+            self.0.extend_from_slice(packet);
+            self
+        }
+
+        /// Append the ACL packet carrying the given ATT PDU in a single
+        /// fragment, received from the client if direction is Rx
+        pub fn att(self, connection: u16, direction: Direction, pdu: &[u8]) -> Self {
+            let mut packet = vec![H4_ACL];
+            packet.extend((connection | 0x2000).to_le_bytes());
+            packet.extend((pdu.len() as u16 + 4).to_le_bytes());
+            packet.extend((pdu.len() as u16).to_le_bytes());
+            packet.extend(ATT_CID.to_le_bytes());
+            packet.extend_from_slice(pdu);
+            let flags = if direction == Direction::Rx { FLAG_RECEIVED } else { 0 };
+            self.record(flags, &packet)
+        }
+
+        /// The capture
+        pub fn build(self) -> Vec<u8> {
+            self.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{test_capture::CaptureBuilder, *};
+
+    const CONNECTION: u16 = 0x0040;
+
+    #[test]
+    fn test_reject_non_btsnoop() {
+        assert!(parse_att_frames(b"not a capture").is_err());
+    }
+
+    #[test]
+    fn test_att_frames_in_order() {
+        // arrange
+        let capture = CaptureBuilder::new()
+            .att(CONNECTION, Direction::Rx, &[0x0A, 0x03, 0x00])
+            .att(CONNECTION, Direction::Tx, &[0x0B, 0x01])
+            .build();
+
+        // act
+        let frames = parse_att_frames(&capture).unwrap();
+
+        // assert
+        assert_eq!(
+            frames,
+            vec![
+                AttFrame {
+                    connection: CONNECTION,
+                    direction: Direction::Rx,
+                    pdu: vec![0x0A, 0x03, 0x00]
+                },
+                AttFrame {
+                    connection: CONNECTION,
+                    direction: Direction::Tx,
+                    pdu: vec![0x0B, 0x01]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fragmented_frame_reassembled() {
+        // arrange: a read request split across a start and a continuation packet
+        let capture = CaptureBuilder::new()
+            .record(FLAG_RECEIVED, &[H4_ACL, 0x40, 0x20, 0x05, 0x00, 0x03, 0x00, 0x04, 0x00, 0x0A])
+            .record(FLAG_RECEIVED, &[H4_ACL, 0x40, 0x10, 0x02, 0x00, 0x03, 0x00])
+            .build();
+
+        // act
+        let frames = parse_att_frames(&capture).unwrap();
+
+        // assert
+        assert_eq!(
+            frames,
+            vec![AttFrame {
+                connection: CONNECTION,
+                direction: Direction::Rx,
+                pdu: vec![0x0A, 0x03, 0x00]
+            }]
+        );
+    }
+
+    #[test]
+    fn test_other_packets_skipped() {
+        // arrange: an HCI event, and a frame on a channel other than ATT
+        let capture = CaptureBuilder::new()
+            .record(FLAG_RECEIVED | FLAG_COMMAND_OR_EVENT, &[0x04, 0x0E, 0x00])
+            .record(FLAG_RECEIVED, &[H4_ACL, 0x40, 0x20, 0x05, 0x00, 0x01, 0x00, 0x05, 0x00, 0x01])
+            .build();
+
+        // act
+        let frames = parse_att_frames(&capture).unwrap();
+
+        // assert
+        assert!(frames.is_empty());
+    }
+}
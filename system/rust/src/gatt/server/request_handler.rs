@@ -1,22 +1,36 @@
 use log::warn;
 
 use crate::{
+    core::uuid::Uuid,
     gatt::ids::AttHandle,
     packets::{
-        AttChild, AttErrorCode, AttErrorResponseBuilder, AttFindByTypeValueRequestView,
-        AttFindInformationRequestView, AttOpcode, AttReadByGroupTypeRequestView,
-        AttReadByTypeRequestView, AttReadRequestView, AttView, AttWriteRequestView, Packet,
-        ParseError,
+        AttBuilder, AttChild, AttErrorCode, AttErrorResponseBuilder, AttExecuteWriteRequestView,
+        AttFindByTypeValueRequestView, AttFindInformationRequestView, AttOpcode,
+        AttPrepareWriteRequestView, AttReadBlobRequestView, AttReadByGroupTypeRequestView,
+        AttReadByTypeRequestView, AttReadMultipleRequestView, AttReadMultipleVariableRequestView,
+        AttReadRequestView, AttView, AttWriteRequestView, Packet, ParseError,
     },
+    utils::packet::HACK_child_to_opcode,
 };
 
 use super::{
     att_database::AttDatabase,
+    gatt_database::CLIENT_CHARACTERISTIC_CONFIGURATION_UUID,
+    interop::Quirks,
+    robust_caching::DATABASE_HASH_UUID,
     transactions::{
         find_by_type_value::handle_find_by_type_value_request,
         find_information_request::handle_find_information_request,
+        prepare_write_request::{
+            handle_execute_write_request, handle_prepare_write_request, PreparedWriteQueue,
+        },
+        read_blob_request::{handle_read_blob_request, LongReadTracker},
         read_by_group_type_request::handle_read_by_group_type_request,
-        read_by_type_request::handle_read_by_type_request, read_request::handle_read_request,
+        read_by_type_request::handle_read_by_type_request,
+        read_multiple_request::{
+            handle_read_multiple_request, handle_read_multiple_variable_request,
+        },
+        read_request::handle_read_request,
         write_request::handle_write_request,
     },
 };
@@ -25,98 +39,286 @@ use super::{
 /// bearer per database, to ensure serialization.
 pub struct AttRequestHandler<Db: AttDatabase> {
     db: Db,
+    prepared_writes: PreparedWriteQueue,
+    long_reads: LongReadTracker,
+    quirks: Quirks,
+    in_client_configuration_burst: bool,
 }
 
 impl<Db: AttDatabase> AttRequestHandler<Db> {
     pub fn new(db: Db) -> Self {
-        Self { db }
+        Self {
+            db,
+            prepared_writes: PreparedWriteQueue::new(),
+            long_reads: LongReadTracker::new(),
+            quirks: Quirks::default(),
+            in_client_configuration_burst: false,
+        }
+    }
+
+    /// Work around the quirks of the peer in the requests processed from now on
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    /// Whether the requests processed so far ended with a burst of writes to
+    /// managed CCCDs that is still in progress
+    pub fn in_client_configuration_burst(&self) -> bool {
+        self.in_client_configuration_burst
+    }
+
+    /// End the burst of writes to managed CCCDs in progress, if any (e.g. once
+    /// the client has made no request for the burst window)
+    pub fn end_client_configuration_burst(&mut self) {
+        if self.in_client_configuration_burst {
+            self.in_client_configuration_burst = false;
+            self.db.end_client_configuration_burst();
+        }
+    }
+
+    /// The number of writes buffered in the prepared write queue of this
+    /// bearer, awaiting an ATT_EXECUTE_WRITE_REQ
+    pub fn prepared_write_queue_depth(&self) -> usize {
+        self.prepared_writes.len()
     }
 
     // Runs a task to process an incoming packet. Takes an exclusive reference to
     // ensure that only one request is outstanding at a time (notifications +
     // commands should take a different path)
-    pub async fn process_packet(&mut self, packet: AttView<'_>, mtu: usize) -> AttChild {
+    pub async fn process_packet(&mut self, packet: AttView<'_>, mtu: usize) -> AttBuilder {
+        // As per Core Spec 5.3 Vol 3G 2.5.2.1, a change-unaware client is told that
+        // its cache is out of sync, and is considered change-aware once it sends
+        // its next request
+        if !self.db.is_change_aware() && !self.is_allowed_while_change_unaware(packet) {
+            self.db.mark_change_aware();
+            return build_reply(AttErrorResponseBuilder {
+                opcode_in_error: packet.get_opcode(),
+                handle_in_error: AttHandle(0).into(),
+                error_code: AttErrorCode::DATABASE_OUT_OF_SYNC,
+            });
+        }
+
+        self.track_client_configuration_burst(packet);
+
         match self.try_parse_and_process_packet(packet, mtu).await {
             Ok(result) => result,
-            Err(_) => {
-                // parse error, assume it's an unsupported request
-                // TODO(aryarahul): distinguish between REQUEST_NOT_SUPPORTED and INVALID_PDU
-                AttErrorResponseBuilder {
+            Err(err) => {
+                // the opcode is supported (or we would not have tried to parse it), so the
+                // rest of the request is malformed
+                warn!("rejecting malformed {:?}: {err:?}", packet.get_opcode());
+                build_reply(AttErrorResponseBuilder {
                     opcode_in_error: packet.get_opcode(),
                     handle_in_error: AttHandle(0).into(),
-                    error_code: AttErrorCode::REQUEST_NOT_SUPPORTED,
-                }
-                .into()
+                    error_code: AttErrorCode::INVALID_PDU,
+                })
             }
         }
     }
 
+    /// A write to a managed CCCD begins a burst of them (if the server config
+    /// enables them), which any other request ends
+    fn track_client_configuration_burst(&mut self, packet: AttView<'_>) {
+        if self.db.server_config().client_configuration_burst_window.is_none() {
+            return;
+        }
+        let is_client_configuration_write = packet.get_opcode() == AttOpcode::WRITE_REQUEST
+            && AttWriteRequestView::try_parse(packet)
+                .ok()
+                .map(|request| {
+                    let handle = request.get_handle().into();
+                    !self
+                        .db
+                        .attributes_in_range(
+                            handle,
+                            handle,
+                            Some(CLIENT_CHARACTERISTIC_CONFIGURATION_UUID),
+                        )
+                        .is_empty()
+                })
+                .unwrap_or(false);
+        if !is_client_configuration_write {
+            self.end_client_configuration_burst();
+        } else if !self.in_client_configuration_burst {
+            self.in_client_configuration_burst = true;
+            self.db.begin_client_configuration_burst();
+        }
+    }
+
+    /// A change-unaware client may still read the Database Hash (by handle or
+    /// by type) to learn of the change, and may complete a queued write.
+    fn is_allowed_while_change_unaware(&self, packet: AttView<'_>) -> bool {
+        match packet.get_opcode() {
+            AttOpcode::READ_REQUEST => AttReadRequestView::try_parse(packet)
+                .ok()
+                .and_then(|request| {
+                    let handle = request.get_attribute_handle().into();
+                    self.db.attributes_in_range(handle, handle, None).into_iter().next()
+                })
+                .map(|attribute| attribute.type_ == DATABASE_HASH_UUID)
+                .unwrap_or(false),
+            AttOpcode::READ_BY_TYPE_REQUEST => AttReadByTypeRequestView::try_parse(packet)
+                .ok()
+                .and_then(|request| Uuid::try_from(request.get_attribute_type()).ok())
+                .map(|type_| type_ == DATABASE_HASH_UUID)
+                .unwrap_or(false),
+            AttOpcode::EXECUTE_WRITE_REQUEST => true,
+            _ => false,
+        }
+    }
+
     async fn try_parse_and_process_packet(
         &mut self,
         packet: AttView<'_>,
         mtu: usize,
-    ) -> Result<AttChild, ParseError> {
-        let snapshotted_db = self.db.snapshot();
-        match packet.get_opcode() {
-            AttOpcode::READ_REQUEST => {
-                Ok(handle_read_request(AttReadRequestView::try_parse(packet)?, mtu, &self.db).await)
-            }
+    ) -> Result<AttBuilder, ParseError> {
+        let reply = match packet.get_opcode() {
+            AttOpcode::READ_REQUEST => Ok(handle_read_request(
+                AttReadRequestView::try_parse(packet)?,
+                mtu,
+                &mut self.long_reads,
+                &self.db,
+            )
+            .await),
+            AttOpcode::READ_BLOB_REQUEST => Ok(handle_read_blob_request(
+                AttReadBlobRequestView::try_parse(packet)?,
+                mtu,
+                self.quirks.lenient_read_blob,
+                &mut self.long_reads,
+                &self.db,
+            )
+            .await),
+            AttOpcode::READ_MULTIPLE_REQUEST => Ok(handle_read_multiple_request(
+                AttReadMultipleRequestView::try_parse(packet)?,
+                mtu,
+                &self.db,
+            )
+            .await),
+            AttOpcode::READ_MULTIPLE_VARIABLE_REQUEST => Ok(handle_read_multiple_variable_request(
+                AttReadMultipleVariableRequestView::try_parse(packet)?,
+                mtu,
+                &self.db,
+            )
+            .await),
             AttOpcode::READ_BY_GROUP_TYPE_REQUEST => {
                 handle_read_by_group_type_request(
                     AttReadByGroupTypeRequestView::try_parse(packet)?,
                     mtu,
-                    &snapshotted_db,
+                    &self.db,
                 )
                 .await
             }
             AttOpcode::READ_BY_TYPE_REQUEST => {
-                handle_read_by_type_request(
+                // this is serialized as it is built, so is already a complete PDU
+                return handle_read_by_type_request(
                     AttReadByTypeRequestView::try_parse(packet)?,
                     mtu,
-                    &snapshotted_db,
+                    &self.db,
                 )
-                .await
+                .await;
             }
             AttOpcode::FIND_INFORMATION_REQUEST => Ok(handle_find_information_request(
                 AttFindInformationRequestView::try_parse(packet)?,
                 mtu,
-                &snapshotted_db,
+                &self.db,
             )),
             AttOpcode::FIND_BY_TYPE_VALUE_REQUEST => Ok(handle_find_by_type_value_request(
                 AttFindByTypeValueRequestView::try_parse(packet)?,
                 mtu,
-                &snapshotted_db,
+                &self.db,
             )
             .await),
             AttOpcode::WRITE_REQUEST => {
                 Ok(handle_write_request(AttWriteRequestView::try_parse(packet)?, &self.db).await)
             }
+            AttOpcode::PREPARE_WRITE_REQUEST => Ok(handle_prepare_write_request(
+                AttPrepareWriteRequestView::try_parse(packet)?,
+                &mut self.prepared_writes,
+                &self.db,
+            )),
+            AttOpcode::EXECUTE_WRITE_REQUEST => Ok(handle_execute_write_request(
+                AttExecuteWriteRequestView::try_parse(packet)?,
+                &mut self.prepared_writes,
+                &self.db,
+            )
+            .await),
             _ => {
-                warn!("Dropping unsupported opcode {:?}", packet.get_opcode());
-                Err(ParseError::InvalidEnumValue)
+                warn!("rejecting unsupported opcode {:?}", packet.get_opcode());
+                Ok(AttErrorResponseBuilder {
+                    opcode_in_error: packet.get_opcode(),
+                    handle_in_error: AttHandle(0).into(),
+                    error_code: AttErrorCode::REQUEST_NOT_SUPPORTED,
+                }
+                .into())
             }
-        }
+        }?;
+        Ok(build_reply(reply))
     }
 }
 
+/// Wrap the reply from a transaction into a complete PDU
+fn build_reply(reply: impl Into<AttChild>) -> AttBuilder {
+    let reply = reply.into();
+    AttBuilder { opcode: HACK_child_to_opcode(&reply), _child_: reply }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    use std::rc::Rc;
+
     use crate::{
-        core::uuid::Uuid,
-        gatt::server::{
-            att_database::{AttAttribute, AttPermissions},
-            request_handler::AttRequestHandler,
-            test::test_att_db::TestAttDatabase,
+        core::{shared_box::SharedBox, uuid::Uuid},
+        gatt::{
+            ids::TransportIndex,
+            mocks::mock_datastore::MockDatastore,
+            server::{
+                att_database::{AttAttribute, AttPermissions},
+                gatt_database::{
+                    AttDatabaseImpl, CharacteristicBuilder, GattDatabase, ServiceBuilder,
+                },
+                request_handler::AttRequestHandler,
+                robust_caching::DATABASE_HASH_UUID,
+                test::test_att_db::TestAttDatabase,
+            },
         },
         packets::{
-            AttAttributeDataChild, AttReadRequestBuilder, AttReadResponseBuilder,
-            AttWriteResponseBuilder,
+            AttAttributeDataChild, AttReadByTypeRequestBuilder, AttReadRequestBuilder,
+            AttReadResponseBuilder, AttWriteResponseBuilder, OwnedAttView, OwnedPacket,
+            Serializable, Uuid128Builder,
+        },
+        utils::{
+            packet::{build_att_data, build_att_view_or_crash},
+            task::block_on_locally,
         },
-        utils::packet::{build_att_data, build_att_view_or_crash},
     };
 
+    const HANDLE: AttHandle = AttHandle(3);
+    const DATABASE_HASH_HANDLE: AttHandle = AttHandle(5);
+
+    fn make_change_unaware_db() -> TestAttDatabase {
+        let db = TestAttDatabase::new(vec![
+            (
+                AttAttribute {
+                    handle: HANDLE,
+                    type_: Uuid::new(0x1234),
+                    permissions: AttPermissions::READABLE,
+                },
+                vec![1, 2, 3],
+            ),
+            (
+                AttAttribute {
+                    handle: DATABASE_HASH_HANDLE,
+                    type_: DATABASE_HASH_UUID,
+                    permissions: AttPermissions::READABLE,
+                },
+                vec![4; 16],
+            ),
+        ]);
+        db.set_change_aware(false);
+        db
+    }
+
     #[test]
     fn test_read_request() {
         // arrange
@@ -128,7 +330,7 @@ mod test {
             },
             vec![1, 2, 3],
         )]);
-        let mut handler = AttRequestHandler { db };
+        let mut handler = AttRequestHandler::new(db);
         let att_view = build_att_view_or_crash(AttReadRequestBuilder {
             attribute_handle: AttHandle(3).into(),
         });
@@ -138,7 +340,7 @@ mod test {
 
         // assert
         assert_eq!(
-            response,
+            response._child_,
             AttChild::AttReadResponse(AttReadResponseBuilder {
                 value: build_att_data(AttAttributeDataChild::RawData([1, 2, 3].into()))
             })
@@ -156,7 +358,7 @@ mod test {
             },
             vec![1, 2, 3],
         )]);
-        let mut handler = AttRequestHandler { db };
+        let mut handler = AttRequestHandler::new(db);
         let att_view = build_att_view_or_crash(AttWriteResponseBuilder {});
 
         // act
@@ -164,7 +366,7 @@ mod test {
 
         // assert
         assert_eq!(
-            response,
+            response._child_,
             AttChild::AttErrorResponse(AttErrorResponseBuilder {
                 opcode_in_error: AttOpcode::WRITE_RESPONSE,
                 handle_in_error: AttHandle(0).into(),
@@ -172,4 +374,261 @@ mod test {
             })
         );
     }
+
+    #[test]
+    fn test_malformed_request() {
+        // arrange
+        let db = TestAttDatabase::new(vec![]);
+        let mut handler = AttRequestHandler::new(db);
+        // an ATT_READ_REQ with a truncated handle
+        let att_view = OwnedAttView::try_parse([0x0a, 0x03].into()).unwrap();
+
+        // act
+        let response = tokio_test::block_on(handler.process_packet(att_view.view(), 31));
+
+        // assert
+        assert_eq!(
+            response._child_,
+            AttChild::AttErrorResponse(AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::READ_REQUEST,
+                handle_in_error: AttHandle(0).into(),
+                error_code: AttErrorCode::INVALID_PDU
+            })
+        );
+    }
+
+    #[test]
+    fn test_change_unaware_client_out_of_sync() {
+        // arrange
+        let db = make_change_unaware_db();
+        let mut handler = AttRequestHandler::new(db.clone());
+        let att_view =
+            build_att_view_or_crash(AttReadRequestBuilder { attribute_handle: HANDLE.into() });
+
+        // act
+        let response = tokio_test::block_on(handler.process_packet(att_view.view(), 31));
+
+        // assert: the request was rejected, but the client is now change-aware
+        assert_eq!(
+            response._child_,
+            AttChild::AttErrorResponse(AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::READ_REQUEST,
+                handle_in_error: AttHandle(0).into(),
+                error_code: AttErrorCode::DATABASE_OUT_OF_SYNC
+            })
+        );
+        assert!(db.is_change_aware());
+    }
+
+    #[test]
+    fn test_change_unaware_client_next_request_succeeds() {
+        // arrange: a change-unaware client has been told it is out of sync
+        let db = make_change_unaware_db();
+        let mut handler = AttRequestHandler::new(db);
+        let att_view =
+            build_att_view_or_crash(AttReadRequestBuilder { attribute_handle: HANDLE.into() });
+        tokio_test::block_on(handler.process_packet(att_view.view(), 31));
+
+        // act: retry the request
+        let response = tokio_test::block_on(handler.process_packet(att_view.view(), 31));
+
+        // assert: it succeeds
+        assert!(matches!(response._child_, AttChild::AttReadResponse(_)));
+    }
+
+    #[test]
+    fn test_change_unaware_client_reads_database_hash() {
+        // arrange
+        let db = make_change_unaware_db();
+        let mut handler = AttRequestHandler::new(db);
+        let att_view = build_att_view_or_crash(AttReadRequestBuilder {
+            attribute_handle: DATABASE_HASH_HANDLE.into(),
+        });
+
+        // act
+        let response = tokio_test::block_on(handler.process_packet(att_view.view(), 31));
+
+        // assert: the hash was read
+        assert_eq!(
+            response._child_,
+            AttChild::AttReadResponse(AttReadResponseBuilder {
+                value: build_att_data(AttAttributeDataChild::RawData([4; 16].into()))
+            })
+        );
+    }
+
+    #[test]
+    fn test_change_unaware_client_reads_database_hash_by_type() {
+        // arrange
+        let db = make_change_unaware_db();
+        let mut handler = AttRequestHandler::new(db);
+        let att_view = build_att_view_or_crash(AttReadByTypeRequestBuilder {
+            starting_handle: AttHandle(1).into(),
+            ending_handle: AttHandle(0xFFFF).into(),
+            attribute_type: DATABASE_HASH_UUID.into(),
+        });
+
+        // act
+        let response = tokio_test::block_on(handler.process_packet(att_view.view(), 31));
+
+        // assert: the hash was read
+        assert_eq!(response.opcode, AttOpcode::READ_BY_TYPE_RESPONSE);
+    }
+
+    const SERVICE_TYPE_32: Uuid = Uuid::new(0x01020304);
+    #[test]
+    fn test_requests_do_not_list_the_whole_database() {
+        // arrange
+        let db = TestAttDatabase::new(vec![
+            (
+                AttAttribute {
+                    handle: AttHandle(1),
+                    type_: Uuid::new(0x2800),
+                    permissions: AttPermissions::READABLE,
+                },
+                vec![0x11, 0x18],
+            ),
+            (
+                AttAttribute {
+                    handle: HANDLE,
+                    type_: Uuid::new(0x1234),
+                    permissions: AttPermissions::READABLE | AttPermissions::WRITABLE_WITH_RESPONSE,
+                },
+                vec![1, 2, 3],
+            ),
+        ]);
+        let mut handler = AttRequestHandler::new(db.clone());
+
+        // act: discover the database, then read and queue a write
+        for pdu in [
+            vec![0x10, 1, 0, 0xFF, 0xFF, 0x00, 0x28],
+            vec![0x08, 1, 0, 0xFF, 0xFF, 0x34, 0x12],
+            vec![0x04, 1, 0, 0xFF, 0xFF],
+            vec![0x06, 1, 0, 0xFF, 0xFF, 0x00, 0x28, 0x11, 0x18],
+            vec![0x0A, 3, 0],
+            vec![0x16, 3, 0, 0, 0, 4],
+        ] {
+            let att_view = OwnedAttView::try_parse(pdu.into()).unwrap();
+            tokio_test::block_on(handler.process_packet(att_view.view(), 31));
+        }
+
+        // assert: each request only looked up the handles it covers
+        assert_eq!(db.list_attributes_calls(), 0);
+    }
+
+    const SERVICE_TYPE_16: Uuid = Uuid::new(0x1811);
+    const CHARACTERISTIC_TYPE_16: Uuid = Uuid::new(0x1234);
+    const CHARACTERISTIC_TYPE_32: Uuid = Uuid::new(0x05060708);
+
+    /// The 128-bit form of a UUID, in the little-endian order of ATT PDUs
+    fn long_form(uuid: Uuid) -> Vec<u8> {
+        Uuid128Builder::from(uuid).data.to_vec()
+    }
+
+    /// A database with a service of a 32-bit type, holding characteristics of
+    /// a 16-bit (3), a 32-bit (5), and a 128-bit (7) type (with the values 1, 2
+    /// and 3), followed by a service of a 16-bit type (8)
+    fn make_db_with_mixed_uuid_sizes(characteristic_type_128: Uuid) -> SharedBox<GattDatabase> {
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        let (gatt_datastore, _) = MockDatastore::new();
+        let gatt_datastore = Rc::new(gatt_datastore);
+        let service = [CHARACTERISTIC_TYPE_16, CHARACTERISTIC_TYPE_32, characteristic_type_128]
+            .into_iter()
+            .zip(1..)
+            .fold(ServiceBuilder::new(SERVICE_TYPE_32), |service, (type_, value)| {
+                service.characteristic(
+                    CharacteristicBuilder::new(type_, AttPermissions::READABLE)
+                        .static_value(vec![value]),
+                )
+            });
+        gatt_db.add_service(service, gatt_datastore.clone()).unwrap();
+        gatt_db.add_service(ServiceBuilder::new(SERVICE_TYPE_16), gatt_datastore).unwrap();
+        gatt_db
+    }
+
+    async fn request(handler: &mut AttRequestHandler<AttDatabaseImpl>, pdu: Vec<u8>) -> Vec<u8> {
+        let att_view = OwnedAttView::try_parse(pdu.into()).unwrap();
+        handler.process_packet(att_view.view(), 64).await.to_vec().unwrap()
+    }
+
+    #[test]
+    fn test_discovery_with_mixed_uuid_sizes() {
+        block_on_locally(async {
+            // arrange
+            let characteristic_type_128 = Uuid::try_from_le_slice(&[1; 16]).unwrap();
+            let gatt_db = make_db_with_mixed_uuid_sizes(characteristic_type_128);
+            let mut handler = AttRequestHandler::new(gatt_db.get_att_database(TransportIndex(1)));
+
+            // act: discover the services, characteristics and descriptors, each
+            // response holding a single UUID size
+            let services = request(&mut handler, vec![0x10, 1, 0, 0xFF, 0xFF, 0x00, 0x28]).await;
+            let next_services =
+                request(&mut handler, vec![0x10, 8, 0, 0xFF, 0xFF, 0x00, 0x28]).await;
+            let characteristics = request(&mut handler, vec![0x08, 1, 0, 7, 0, 0x03, 0x28]).await;
+            let next_characteristics =
+                request(&mut handler, vec![0x08, 3, 0, 7, 0, 0x03, 0x28]).await;
+            let information = request(&mut handler, vec![0x04, 1, 0, 7, 0]).await;
+            let next_information = request(&mut handler, vec![0x04, 5, 0, 7, 0]).await;
+            let last_information = request(&mut handler, vec![0x04, 7, 0, 7, 0]).await;
+            let found_service = request(
+                &mut handler,
+                [vec![0x06, 1, 0, 0xFF, 0xFF, 0x00, 0x28], long_form(SERVICE_TYPE_32)].concat(),
+            )
+            .await;
+
+            // assert: the 32-bit UUIDs were expanded to 128 bits
+            assert_eq!(services, [vec![0x11, 20, 1, 0, 7, 0], long_form(SERVICE_TYPE_32)].concat());
+            assert_eq!(next_services, vec![0x11, 6, 8, 0, 8, 0, 0x11, 0x18]);
+            assert_eq!(characteristics, vec![0x09, 7, 2, 0, 0x02, 3, 0, 0x34, 0x12]);
+            assert_eq!(
+                next_characteristics,
+                [
+                    vec![0x09, 21, 4, 0, 0x02, 5, 0],
+                    long_form(CHARACTERISTIC_TYPE_32),
+                    vec![6, 0, 0x02, 7, 0],
+                    long_form(characteristic_type_128),
+                ]
+                .concat()
+            );
+            assert_eq!(
+                information,
+                vec![
+                    0x05, 0x01, 1, 0, 0x00, 0x28, 2, 0, 0x03, 0x28, 3, 0, 0x34, 0x12, 4, 0, 0x03,
+                    0x28
+                ]
+            );
+            assert_eq!(
+                next_information,
+                [vec![0x05, 0x02, 5, 0], long_form(CHARACTERISTIC_TYPE_32)].concat()
+            );
+            assert_eq!(
+                last_information,
+                [vec![0x05, 0x02, 7, 0], long_form(characteristic_type_128)].concat()
+            );
+            assert_eq!(found_service, vec![0x07, 1, 0, 7, 0]);
+        });
+    }
+
+    #[test]
+    fn test_read_by_type_with_32_bit_uuid() {
+        block_on_locally(async {
+            // arrange
+            let gatt_db = make_db_with_mixed_uuid_sizes(Uuid::try_from_le_slice(&[1; 16]).unwrap());
+            let mut handler = AttRequestHandler::new(gatt_db.get_att_database(TransportIndex(1)));
+
+            // act: read the characteristic of a 32-bit type by its type, in both the
+            // 128-bit form, and the 32-bit form (which clients should not send, but
+            // is unambiguous)
+            let short = request(&mut handler, vec![0x08, 1, 0, 7, 0, 8, 7, 6, 5]).await;
+            let long = request(
+                &mut handler,
+                [vec![0x08, 1, 0, 7, 0], long_form(CHARACTERISTIC_TYPE_32)].concat(),
+            )
+            .await;
+
+            // assert: both forms identify the same characteristic
+            assert_eq!(short, vec![0x09, 3, 5, 0, 2]);
+            assert_eq!(long, short);
+        });
+    }
 }
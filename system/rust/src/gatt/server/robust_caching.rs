@@ -0,0 +1,598 @@
+//! This module tracks the robust caching state of every connected client, as
+//! defined in Core Spec 5.3 Vol 3G 2.5.2.1 Robust Caching.
+//!
+//! A client that enables robust caching (via the Client Supported Features
+//! characteristic) becomes change-unaware whenever the database changes, and
+//! must not access the database until it has learned of the change (by
+//! reading the Database Hash, or confirming a Service Changed indication).
+//!
+//! The state of a bonded client is retained across connections, so that it is
+//! change-unaware on reconnection if the database changed in the meantime.
+//!
+//! Independently of robust caching, the handles of removed services stay
+//! invalid for every client that may have cached them, until it confirms a
+//! Service Changed indication covering them. Accesses to them fail with
+//! INVALID_HANDLE even if they were reassigned to another service in the
+//! meantime, rather than reaching an attribute the client did not discover.
+
+use std::{collections::HashMap, ops::RangeInclusive};
+
+use bitflags::bitflags;
+use log::info;
+
+use crate::{
+    core::{address::AddressWithType, uuid::Uuid},
+    gatt::ids::{AttHandle, TransportIndex},
+    packets::AttErrorCode,
+};
+
+/// The UUID used for the Client Supported Features characteristic (Assigned
+/// Numbers 3.8.1 Characteristics by Name)
+pub const CLIENT_SUPPORTED_FEATURES_UUID: Uuid = Uuid::new(0x2B29);
+/// The UUID used for the Database Hash characteristic (Assigned Numbers 3.8.1
+/// Characteristics by Name)
+pub const DATABASE_HASH_UUID: Uuid = Uuid::new(0x2B2A);
+
+/// The Database Hash, as defined in Core Spec 5.3 Vol 3G 7.3, in the
+/// little-endian order in which it is exposed to clients
+pub type DatabaseHash = [u8; 16];
+
+bitflags! {
+    /// The Client Supported Features bits, from Core Spec 5.3 Vol 3G 7.2 Client
+    /// Supported Features
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+    pub struct ClientSupportedFeatures : u8 {
+        /// The client supports robust caching
+        const ROBUST_CACHING = 0x01;
+        /// The client supports Enhanced ATT bearers
+        const ENHANCED_ATT_BEARER = 0x02;
+        /// The client supports receiving ATT_MULTIPLE_HANDLE_VALUE_NTF PDUs
+        const MULTIPLE_HANDLE_VALUE_NOTIFICATIONS = 0x04;
+    }
+}
+
+/// Identifies a removal of handles, so that the confirmation of a Service
+/// Changed indication only covers the removals made before it was sent
+pub type RemovalGeneration = u64;
+
+/// The ranges of removed handles that a client has not yet been told of, with
+/// the latest removal of each
+type RemovedRanges = Vec<(RangeInclusive<AttHandle>, RemovalGeneration)>;
+
+struct ClientState {
+    peer: Option<AddressWithType>,
+    features: ClientSupportedFeatures,
+    change_aware: bool,
+    removed: RemovedRanges,
+}
+
+impl Default for ClientState {
+    fn default() -> Self {
+        // a client that has not enabled robust caching is always change-aware
+        Self {
+            peer: None,
+            features: ClientSupportedFeatures::empty(),
+            change_aware: true,
+            removed: vec![],
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct BondedState {
+    features: ClientSupportedFeatures,
+    /// The hash of the database when the client was last change-aware
+    database_hash: Option<DatabaseHash>,
+    removed: RemovedRanges,
+}
+
+/// Add a range to those a client has not been told of (or, if already there,
+/// record the later of the two removals)
+fn add_removed_range(
+    removed: &mut RemovedRanges,
+    range: &RangeInclusive<AttHandle>,
+    generation: RemovalGeneration,
+) {
+    match removed.iter_mut().find(|(existing, _)| existing == range) {
+        Some((_, existing)) => *existing = (*existing).max(generation),
+        None => removed.push((range.clone(), generation)),
+    }
+}
+
+/// Stores the robust caching state of every connected client, as well as that
+/// of bonded clients that are not currently connected
+#[derive(Default)]
+pub struct RobustCachingStore {
+    database_hash: DatabaseHash,
+    clients: HashMap<TransportIndex, ClientState>,
+    bonded: HashMap<AddressWithType, BondedState>,
+    robust_caching_disabled: bool,
+    removal_generation: RemovalGeneration,
+}
+
+impl RobustCachingStore {
+    /// Set whether clients may enable robust caching. If not, the bit is
+    /// ignored when written, or restored for a bonded client, so every client
+    /// stays change-aware.
+    pub fn set_robust_caching_enabled(&mut self, enabled: bool) {
+        self.robust_caching_disabled = !enabled;
+    }
+
+    /// The given features, without those the server does not support
+    fn supported(&self, features: ClientSupportedFeatures) -> ClientSupportedFeatures {
+        if self.robust_caching_disabled {
+            features - ClientSupportedFeatures::ROBUST_CACHING
+        } else {
+            features
+        }
+    }
+
+    /// The current Database Hash
+    pub fn database_hash(&self) -> DatabaseHash {
+        self.database_hash
+    }
+
+    /// The database has changed, so every client that enabled robust caching
+    /// becomes change-unaware
+    pub fn on_database_changed(&mut self, database_hash: DatabaseHash) {
+        if database_hash == self.database_hash {
+            return;
+        }
+        self.database_hash = database_hash;
+        for client in self.clients.values_mut() {
+            if client.features.contains(ClientSupportedFeatures::ROBUST_CACHING) {
+                client.change_aware = false;
+            }
+        }
+    }
+
+    /// Whether the client may access the database (i.e. it has not enabled
+    /// robust caching, or has learned of the latest change)
+    pub fn is_change_aware(&self, tcb_idx: TransportIndex) -> bool {
+        self.clients
+            .get(&tcb_idx)
+            .map(|client| {
+                !client.features.contains(ClientSupportedFeatures::ROBUST_CACHING)
+                    || client.change_aware
+            })
+            .unwrap_or(true)
+    }
+
+    /// The client has learned of the latest change to the database
+    pub fn mark_change_aware(&mut self, tcb_idx: TransportIndex) {
+        let client = self.clients.entry(tcb_idx).or_default();
+        if !client.change_aware {
+            info!("client {tcb_idx:?} is now change-aware");
+        }
+        client.change_aware = true;
+        self.save(tcb_idx);
+    }
+
+    /// The handles in the given range were removed from the database, so they
+    /// are invalid for every connected or bonded client until it confirms a
+    /// Service Changed indication covering them
+    pub fn on_handles_removed(&mut self, range: RangeInclusive<AttHandle>) {
+        self.removal_generation += 1;
+        for client in self.clients.values_mut() {
+            add_removed_range(&mut client.removed, &range, self.removal_generation);
+        }
+        for saved in self.bonded.values_mut() {
+            add_removed_range(&mut saved.removed, &range, self.removal_generation);
+        }
+    }
+
+    /// The latest removal of handles, which a Service Changed indication sent
+    /// now covers once confirmed
+    pub fn removal_generation(&self) -> RemovalGeneration {
+        self.removal_generation
+    }
+
+    /// Whether the given handle was removed from the database since the client
+    /// last confirmed a Service Changed indication covering it (and so it must
+    /// be treated as invalid, even if it was assigned again)
+    pub fn is_handle_removed(&self, tcb_idx: TransportIndex, handle: AttHandle) -> bool {
+        self.clients
+            .get(&tcb_idx)
+            .map(|client| client.removed.iter().any(|(range, _)| range.contains(&handle)))
+            .unwrap_or(false)
+    }
+
+    /// The client confirmed a Service Changed indication for the given range,
+    /// sent at the given removal generation, so it has learned of the latest
+    /// change to the database, including the removal of the handles within that
+    /// range up to then. Handles removed after the indication was sent stay
+    /// invalid, even if they were assigned again.
+    pub fn on_service_changed_confirmed(
+        &mut self,
+        tcb_idx: TransportIndex,
+        range: &RangeInclusive<AttHandle>,
+        sent_at: RemovalGeneration,
+    ) {
+        if let Some(client) = self.clients.get_mut(&tcb_idx) {
+            client.removed.retain(|(removed, generation)| {
+                *generation > sent_at
+                    || !(range.contains(removed.start()) && range.contains(removed.end()))
+            });
+        }
+        self.mark_change_aware(tcb_idx);
+    }
+
+    /// Get the features enabled by the client
+    pub fn client_supported_features(&self, tcb_idx: TransportIndex) -> ClientSupportedFeatures {
+        self.clients.get(&tcb_idx).map(|client| client.features).unwrap_or_default()
+    }
+
+    /// Handle a write to the Client Supported Features characteristic. As per
+    /// Core Spec 5.3 Vol 3G 7.2, a client may not disable a feature it has
+    /// previously enabled.
+    pub fn set_client_supported_features(
+        &mut self,
+        tcb_idx: TransportIndex,
+        value: &[u8],
+    ) -> Result<(), AttErrorCode> {
+        // trailing zero octets carry no features, and we only know the first octet
+        let len = value.iter().rposition(|octet| *octet != 0).map(|pos| pos + 1).unwrap_or(0);
+        let Some(first) = value[..len].first() else {
+            return Err(AttErrorCode::VALUE_NOT_ALLOWED);
+        };
+        let features = self.supported(ClientSupportedFeatures::from_bits_truncate(*first));
+
+        let client = self.clients.entry(tcb_idx).or_default();
+        if !features.contains(client.features) {
+            info!("client {tcb_idx:?} tried to disable supported features, rejecting");
+            return Err(AttErrorCode::VALUE_NOT_ALLOWED);
+        }
+        client.features = features;
+        self.save(tcb_idx);
+        Ok(())
+    }
+
+    /// Whether any state is kept for the client on the given transport (i.e.
+    /// it is connected)
+    pub fn has_client(&self, tcb_idx: TransportIndex) -> bool {
+        self.clients.contains_key(&tcb_idx)
+    }
+
+    /// A client has connected. Until it is known to be bonded, it has not
+    /// enabled any features.
+    pub fn on_le_connect(&mut self, tcb_idx: TransportIndex) {
+        self.clients.insert(tcb_idx, ClientState::default());
+    }
+
+    /// A client has disconnected, so its state is discarded (unless it is
+    /// bonded, in which case it has already been saved)
+    pub fn on_le_disconnect(&mut self, tcb_idx: TransportIndex) {
+        self.clients.remove(&tcb_idx);
+    }
+
+    /// A client has been identified as a bonded peer. Its saved features are
+    /// restored, and it is change-unaware if the database changed since it was
+    /// last change-aware. The handles removed while it was disconnected stay
+    /// invalid until it is told of them.
+    pub fn on_le_bonded(&mut self, tcb_idx: TransportIndex, peer: AddressWithType) {
+        let saved = self.bonded.get(&peer).cloned().unwrap_or_default();
+        let saved_features = self.supported(saved.features);
+        let client = self.clients.entry(tcb_idx).or_default();
+        client.peer = Some(peer);
+        client.features |= saved_features;
+        for (range, generation) in &saved.removed {
+            add_removed_range(&mut client.removed, range, *generation);
+        }
+        if saved_features.contains(ClientSupportedFeatures::ROBUST_CACHING)
+            && saved.database_hash != Some(self.database_hash)
+        {
+            client.change_aware = false;
+        }
+        self.save(tcb_idx);
+    }
+
+    /// Save the state of a bonded client for future connections
+    fn save(&mut self, tcb_idx: TransportIndex) {
+        let Some(client) = self.clients.get(&tcb_idx) else {
+            return;
+        };
+        let Some(peer) = client.peer else {
+            return;
+        };
+        let saved = self.bonded.entry(peer).or_default();
+        saved.features = client.features;
+        saved.removed = client.removed.clone();
+        if client.change_aware {
+            saved.database_hash = Some(self.database_hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::core::address::AddressType;
+
+    use super::*;
+
+    const TCB_IDX: TransportIndex = TransportIndex(1);
+    const ANOTHER_TCB_IDX: TransportIndex = TransportIndex(2);
+    const PEER: AddressWithType =
+        AddressWithType { address: [1, 2, 3, 4, 5, 6], address_type: AddressType::Public };
+    const HASH: DatabaseHash = [1; 16];
+    const ANOTHER_HASH: DatabaseHash = [2; 16];
+
+    fn enable_robust_caching(store: &mut RobustCachingStore, tcb_idx: TransportIndex) {
+        store
+            .set_client_supported_features(
+                tcb_idx,
+                &[ClientSupportedFeatures::ROBUST_CACHING.bits()],
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_robust_caching_client_becomes_unaware() {
+        // arrange: a client enables robust caching
+        let mut store = RobustCachingStore::default();
+        store.on_le_connect(TCB_IDX);
+        enable_robust_caching(&mut store, TCB_IDX);
+        assert!(store.is_change_aware(TCB_IDX));
+
+        // act: the database changes
+        store.on_database_changed(HASH);
+
+        // assert: the client is change-unaware until it learns of the change
+        assert!(!store.is_change_aware(TCB_IDX));
+        store.mark_change_aware(TCB_IDX);
+        assert!(store.is_change_aware(TCB_IDX));
+    }
+
+    #[test]
+    fn test_other_clients_stay_aware() {
+        // arrange: only one of two clients enables robust caching
+        let mut store = RobustCachingStore::default();
+        store.on_le_connect(TCB_IDX);
+        store.on_le_connect(ANOTHER_TCB_IDX);
+        enable_robust_caching(&mut store, TCB_IDX);
+
+        // act: the database changes
+        store.on_database_changed(HASH);
+
+        // assert: the other client is unaffected
+        assert!(!store.is_change_aware(TCB_IDX));
+        assert!(store.is_change_aware(ANOTHER_TCB_IDX));
+    }
+
+    #[test]
+    fn test_unchanged_hash() {
+        let mut store = RobustCachingStore::default();
+        store.on_database_changed(HASH);
+        store.on_le_connect(TCB_IDX);
+        enable_robust_caching(&mut store, TCB_IDX);
+
+        store.on_database_changed(HASH);
+
+        assert!(store.is_change_aware(TCB_IDX));
+    }
+
+    #[test]
+    fn test_features_cannot_be_disabled() {
+        // arrange
+        let mut store = RobustCachingStore::default();
+        store.on_le_connect(TCB_IDX);
+        enable_robust_caching(&mut store, TCB_IDX);
+
+        // act: try to disable robust caching, while enabling another feature
+        let res = store.set_client_supported_features(
+            TCB_IDX,
+            &[ClientSupportedFeatures::ENHANCED_ATT_BEARER.bits()],
+        );
+
+        // assert: the write was rejected
+        assert_eq!(res, Err(AttErrorCode::VALUE_NOT_ALLOWED));
+        assert_eq!(
+            store.client_supported_features(TCB_IDX),
+            ClientSupportedFeatures::ROBUST_CACHING
+        );
+    }
+
+    #[test]
+    fn test_robust_caching_ignored_when_disabled() {
+        // arrange
+        let mut store = RobustCachingStore::default();
+        store.set_robust_caching_enabled(false);
+        store.on_le_connect(TCB_IDX);
+
+        // act: enable robust caching alongside another feature, then change the
+        // database
+        store
+            .set_client_supported_features(
+                TCB_IDX,
+                &[(ClientSupportedFeatures::ROBUST_CACHING
+                    | ClientSupportedFeatures::ENHANCED_ATT_BEARER)
+                    .bits()],
+            )
+            .unwrap();
+        store.on_database_changed(HASH);
+
+        // assert: only the other feature was enabled, so the client stays aware
+        assert_eq!(
+            store.client_supported_features(TCB_IDX),
+            ClientSupportedFeatures::ENHANCED_ATT_BEARER
+        );
+        assert!(store.is_change_aware(TCB_IDX));
+    }
+
+    #[test]
+    fn test_empty_features_rejected() {
+        let mut store = RobustCachingStore::default();
+        store.on_le_connect(TCB_IDX);
+
+        assert_eq!(
+            store.set_client_supported_features(TCB_IDX, &[]),
+            Err(AttErrorCode::VALUE_NOT_ALLOWED)
+        );
+        assert_eq!(
+            store.set_client_supported_features(TCB_IDX, &[0, 0]),
+            Err(AttErrorCode::VALUE_NOT_ALLOWED)
+        );
+    }
+
+    #[test]
+    fn test_trailing_octets_ignored() {
+        let mut store = RobustCachingStore::default();
+        store.on_le_connect(TCB_IDX);
+
+        store.set_client_supported_features(TCB_IDX, &[0x05, 0x00]).unwrap();
+
+        assert_eq!(
+            store.client_supported_features(TCB_IDX),
+            ClientSupportedFeatures::ROBUST_CACHING
+                | ClientSupportedFeatures::MULTIPLE_HANDLE_VALUE_NOTIFICATIONS
+        );
+    }
+
+    #[test]
+    fn test_unbonded_client_aware_on_reconnect() {
+        // arrange: an unbonded client enables robust caching and disconnects
+        let mut store = RobustCachingStore::default();
+        store.on_le_connect(TCB_IDX);
+        enable_robust_caching(&mut store, TCB_IDX);
+        store.on_le_disconnect(TCB_IDX);
+
+        // act: the database changes, and the client reconnects
+        store.on_database_changed(HASH);
+        store.on_le_connect(TCB_IDX);
+
+        // assert: it starts afresh
+        assert!(store.is_change_aware(TCB_IDX));
+        assert_eq!(store.client_supported_features(TCB_IDX), ClientSupportedFeatures::empty());
+    }
+
+    #[test]
+    fn test_bonded_client_unaware_on_reconnect_after_change() {
+        // arrange: a bonded client enables robust caching and disconnects
+        let mut store = RobustCachingStore::default();
+        store.on_database_changed(HASH);
+        store.on_le_connect(TCB_IDX);
+        store.on_le_bonded(TCB_IDX, PEER);
+        enable_robust_caching(&mut store, TCB_IDX);
+        store.on_le_disconnect(TCB_IDX);
+
+        // act: the database changes, and the client reconnects
+        store.on_database_changed(ANOTHER_HASH);
+        store.on_le_connect(ANOTHER_TCB_IDX);
+        store.on_le_bonded(ANOTHER_TCB_IDX, PEER);
+
+        // assert: its features were restored, and it is change-unaware
+        assert_eq!(
+            store.client_supported_features(ANOTHER_TCB_IDX),
+            ClientSupportedFeatures::ROBUST_CACHING
+        );
+        assert!(!store.is_change_aware(ANOTHER_TCB_IDX));
+    }
+
+    #[test]
+    fn test_bonded_client_aware_on_reconnect_without_change() {
+        // arrange: a bonded client enables robust caching and disconnects
+        let mut store = RobustCachingStore::default();
+        store.on_database_changed(HASH);
+        store.on_le_connect(TCB_IDX);
+        store.on_le_bonded(TCB_IDX, PEER);
+        enable_robust_caching(&mut store, TCB_IDX);
+        store.on_le_disconnect(TCB_IDX);
+
+        // act: the client reconnects
+        store.on_le_connect(TCB_IDX);
+        store.on_le_bonded(TCB_IDX, PEER);
+
+        // assert: it is still change-aware
+        assert!(store.is_change_aware(TCB_IDX));
+    }
+
+    #[test]
+    fn test_removed_handles_invalid_until_confirmed() {
+        // arrange
+        let mut store = RobustCachingStore::default();
+        store.on_le_connect(TCB_IDX);
+
+        // act: a service is removed
+        store.on_handles_removed(AttHandle(10)..=AttHandle(12));
+
+        // assert: its handles are invalid until the client confirms a Service Changed
+        // indication covering them
+        assert!(store.is_handle_removed(TCB_IDX, AttHandle(11)));
+        assert!(!store.is_handle_removed(TCB_IDX, AttHandle(13)));
+        store.on_service_changed_confirmed(TCB_IDX, &(AttHandle(11)..=AttHandle(20)), 1);
+        assert!(store.is_handle_removed(TCB_IDX, AttHandle(11)));
+        store.on_service_changed_confirmed(TCB_IDX, &(AttHandle(10)..=AttHandle(12)), 1);
+        assert!(!store.is_handle_removed(TCB_IDX, AttHandle(11)));
+    }
+
+    #[test]
+    fn test_removed_handles_invalid_until_later_indication_confirmed() {
+        // arrange: an indication is sent before a service is removed
+        let mut store = RobustCachingStore::default();
+        store.on_le_connect(TCB_IDX);
+        let sent_at = store.removal_generation();
+
+        // act: the service is removed, then the earlier indication is confirmed
+        store.on_handles_removed(AttHandle(10)..=AttHandle(12));
+        store.on_service_changed_confirmed(TCB_IDX, &(AttHandle(10)..=AttHandle(12)), sent_at);
+
+        // assert: its handles stay invalid until an indication sent after the removal
+        // is confirmed
+        assert!(store.is_handle_removed(TCB_IDX, AttHandle(11)));
+        let sent_at = store.removal_generation();
+        store.on_service_changed_confirmed(TCB_IDX, &(AttHandle(10)..=AttHandle(12)), sent_at);
+        assert!(!store.is_handle_removed(TCB_IDX, AttHandle(11)));
+    }
+
+    #[test]
+    fn test_new_client_unaffected_by_removed_handles() {
+        let mut store = RobustCachingStore::default();
+        store.on_le_connect(TCB_IDX);
+        store.on_handles_removed(AttHandle(10)..=AttHandle(12));
+
+        store.on_le_connect(ANOTHER_TCB_IDX);
+
+        assert!(!store.is_handle_removed(ANOTHER_TCB_IDX, AttHandle(11)));
+    }
+
+    #[test]
+    fn test_removed_handles_retained_for_bonded_client() {
+        // arrange: a bonded client disconnects
+        let mut store = RobustCachingStore::default();
+        store.on_le_connect(TCB_IDX);
+        store.on_le_bonded(TCB_IDX, PEER);
+        store.on_le_disconnect(TCB_IDX);
+
+        // act: a service is removed, and the client reconnects
+        store.on_handles_removed(AttHandle(10)..=AttHandle(12));
+        store.on_le_connect(ANOTHER_TCB_IDX);
+        let before_bonding = store.is_handle_removed(ANOTHER_TCB_IDX, AttHandle(11));
+        store.on_le_bonded(ANOTHER_TCB_IDX, PEER);
+
+        // assert: the removed handles are invalid once it is identified, until confirmed
+        assert!(!before_bonding);
+        assert!(store.is_handle_removed(ANOTHER_TCB_IDX, AttHandle(11)));
+        store.on_service_changed_confirmed(ANOTHER_TCB_IDX, &(AttHandle(10)..=AttHandle(12)), 1);
+        store.on_le_disconnect(ANOTHER_TCB_IDX);
+        store.on_le_connect(TCB_IDX);
+        store.on_le_bonded(TCB_IDX, PEER);
+        assert!(!store.is_handle_removed(TCB_IDX, AttHandle(11)));
+    }
+
+    #[test]
+    fn test_bonded_client_unaware_when_disconnected_while_unaware() {
+        // arrange: a bonded client is change-unaware when it disconnects
+        let mut store = RobustCachingStore::default();
+        store.on_le_connect(TCB_IDX);
+        store.on_le_bonded(TCB_IDX, PEER);
+        enable_robust_caching(&mut store, TCB_IDX);
+        store.on_database_changed(HASH);
+        store.on_le_disconnect(TCB_IDX);
+
+        // act: the client reconnects, with no further changes
+        store.on_le_connect(TCB_IDX);
+        store.on_le_bonded(TCB_IDX, PEER);
+
+        // assert: it is still change-unaware
+        assert!(!store.is_change_aware(TCB_IDX));
+    }
+}
@@ -0,0 +1,185 @@
+//! This module describes the layout of a GattDatabase declaratively: its
+//! services, characteristics and descriptors, at their handles, with their
+//! properties and permissions. A schema is exported with
+//! GattDatabase::to_schema() (e.g. to compare against a golden schema in a
+//! test, or to inspect the database of a device offline), and the same layout
+//! is restored with GattDatabase::from_schema(), so that clients find every
+//! attribute at the handle they cached.
+//!
+//! The schema only holds primitive types (UUIDs in their 128-bit string form,
+//! and permissions by name), so that it reads well once serialized. With the
+//! "serde" feature, it implements Serialize and Deserialize.
+//!
+//! What is not part of the layout is not captured: the datastores backing the
+//! dynamic values, write validators, and whether notifications are coalesced.
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+
+use crate::{core::uuid::Uuid, gatt::ids::Transport};
+
+use super::att_database::AttPermissions;
+
+/// The services of a GattDatabase, in order of handle
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DatabaseSchema {
+    /// The primary services
+    pub services: Vec<ServiceSchema>,
+}
+
+/// A primary service, and the characteristics it contains
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServiceSchema {
+    /// The handle of the service declaration
+    pub handle: u16,
+    /// The type of the service
+    pub uuid: String,
+    /// The only transport over which the service is visible, if restricted
+    pub transport: Option<TransportSchema>,
+    /// The characteristics, in order of handle
+    pub characteristics: Vec<CharacteristicSchema>,
+}
+
+/// A characteristic, whose declaration is at the handle before its value
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CharacteristicSchema {
+    /// The handle of the characteristic value
+    pub handle: u16,
+    /// The type of the characteristic value
+    pub uuid: String,
+    /// The properties in the characteristic declaration (Core Spec 5.3 Vol 3G
+    /// 3.3.1.1). They follow from the permissions and descriptors, so they are
+    /// not read back by GattDatabase::from_schema().
+    pub properties: u8,
+    /// The names of the permissions of the value (e.g. "READABLE")
+    pub permissions: Vec<String>,
+    /// Where the value comes from
+    pub value: ValueSchema,
+    /// The shortest key encrypting the link over which the value is
+    /// accessible, if it requires one
+    pub min_encryption_key_size: Option<u8>,
+    /// The descriptors, in order of handle
+    pub descriptors: Vec<DescriptorSchema>,
+}
+
+/// A descriptor of a characteristic
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DescriptorSchema {
+    /// The handle of the descriptor
+    pub handle: u16,
+    /// The type of the descriptor
+    pub uuid: String,
+    /// The names of the permissions of the descriptor
+    pub permissions: Vec<String>,
+    /// Where the value comes from
+    pub value: ValueSchema,
+}
+
+/// Where the value of an attribute comes from
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ValueSchema {
+    /// Read from and written to the datastore backing the service
+    Dynamic,
+    /// Served by the database, without involving the datastore
+    Static(Vec<u8>),
+    /// A CCCD managed by the database
+    ClientConfiguration,
+    /// A writable user description managed by the database, with its current
+    /// value (which becomes its default once restored)
+    UserDescription(Vec<u8>),
+}
+
+/// The transport to which a service is restricted
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransportSchema {
+    /// LE
+    Le,
+    /// BR/EDR
+    BrEdr,
+}
+
+impl From<Transport> for TransportSchema {
+    fn from(transport: Transport) -> Self {
+        match transport {
+            Transport::Le => TransportSchema::Le,
+            #[cfg(not(feature = "le_only"))]
+            Transport::BrEdr => TransportSchema::BrEdr,
+        }
+    }
+}
+
+impl TryFrom<TransportSchema> for Transport {
+    type Error = anyhow::Error;
+
+    fn try_from(transport: TransportSchema) -> Result<Self> {
+        match transport {
+            TransportSchema::Le => Ok(Transport::Le),
+            #[cfg(not(feature = "le_only"))]
+            TransportSchema::BrEdr => Ok(Transport::BrEdr),
+            #[cfg(feature = "le_only")]
+            TransportSchema::BrEdr => Err(anyhow!("BR/EDR is not supported by this build")),
+        }
+    }
+}
+
+/// The names of the given permissions, in order of bit
+pub fn permission_names(permissions: AttPermissions) -> Vec<String> {
+    permissions.iter_names().map(|(name, _)| name.to_string()).collect()
+}
+
+/// The permissions with the given names
+pub fn parse_permissions(names: &[String]) -> Result<AttPermissions> {
+    names.iter().try_fold(AttPermissions::empty(), |permissions, name| {
+        AttPermissions::from_name(name)
+            .map(|permission| permissions | permission)
+            .ok_or_else(|| anyhow!("unknown permission {name:?}"))
+    })
+}
+
+/// The UUID in the given string (in any form accepted by Uuid::from_str())
+pub fn parse_uuid(uuid: &str) -> Result<Uuid> {
+    Uuid::from_str(uuid)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_permissions_round_trip() {
+        let permissions =
+            AttPermissions::READABLE | AttPermissions::NOTIFY | AttPermissions::ENCRYPTION_REQUIRED;
+
+        let names = permission_names(permissions);
+
+        assert_eq!(names, vec!["READABLE", "NOTIFY", "ENCRYPTION_REQUIRED"]);
+        assert_eq!(parse_permissions(&names).unwrap(), permissions);
+    }
+
+    #[test]
+    fn test_unknown_permission() {
+        assert!(parse_permissions(&["READABLE".into(), "READ".into()]).is_err());
+    }
+
+    #[test]
+    fn test_uuid_forms() {
+        assert_eq!(parse_uuid("180a").unwrap(), Uuid::new(0x180a));
+        assert_eq!(parse_uuid(&Uuid::new(0x180a).to_string()).unwrap(), Uuid::new(0x180a));
+        assert!(parse_uuid("not a uuid").is_err());
+    }
+
+    #[test]
+    fn test_transport_round_trip() {
+        assert_eq!(
+            Transport::try_from(TransportSchema::from(Transport::Le)).unwrap(),
+            Transport::Le
+        );
+    }
+}
@@ -0,0 +1,341 @@
+//! This module handles requests that fail because the link is not secure
+//! enough. Rather than immediately returning the error, the request is parked
+//! while the link security is upgraded, and then replayed. If the upgrade does
+//! not complete in time, the original error is returned.
+
+use std::{rc::Rc, time::Duration};
+
+use log::{info, trace, warn};
+use tokio::sync::Notify;
+
+use crate::{
+    gatt::{
+        ids::TransportIndex,
+        security_manager::{SecurityLevel, SecurityManager},
+    },
+    packets::{AttChild, AttErrorCode, AttErrorResponseBuilder, AttOpcode},
+    utils::clock::{timeout, Clock},
+};
+
+/// How long a request may stay parked while the link security is upgraded.
+/// This leaves a margin before the client's own ATT transaction timeout of 30s
+/// (Core Spec 5.3 Vol 3F 3.3.3) expires.
+pub const SECURITY_ELEVATION_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Parks the requests on a single bearer while the link security is upgraded
+pub struct SecurityElevation {
+    tcb_idx: TransportIndex,
+    security_manager: Rc<dyn SecurityManager>,
+    security_changed: Notify,
+}
+
+impl SecurityElevation {
+    /// Constructor
+    pub fn new(tcb_idx: TransportIndex, security_manager: Rc<dyn SecurityManager>) -> Self {
+        Self { tcb_idx, security_manager, security_changed: Notify::new() }
+    }
+
+    /// Invoked when the security level of the link has changed, or when an
+    /// attempt to change it has completed (successfully or not).
+    pub fn on_security_level_changed(&self) {
+        self.security_changed.notify_waiters();
+    }
+
+    /// If the reply to a request is an error caused by insufficient link
+    /// security, request that the link security be upgraded, and wait until it
+    /// is (as measured by the given clock). Returns true if the request should
+    /// be replayed.
+    pub async fn try_elevate(&self, reply: &AttChild, clock: &dyn Clock) -> bool {
+        let Some(required) = Self::required_security_level(reply) else {
+            return false;
+        };
+        let current = self.security_manager.get_security_level(self.tcb_idx);
+        if current >= required {
+            // the upper layer rejected the request itself, so elevation won't help
+            return false;
+        }
+
+        // subscribe before requesting, in case the upgrade completes immediately
+        let security_changed = self.security_changed.notified();
+        if !self.security_manager.request_security_elevation(self.tcb_idx, required) {
+            trace!("security elevation to {required:?} is not available on {:?}", self.tcb_idx);
+            return false;
+        }
+        info!("parking request on {:?} until security is elevated to {required:?}", self.tcb_idx);
+
+        if timeout(clock, SECURITY_ELEVATION_TIMEOUT, security_changed).await.is_err() {
+            warn!("security elevation on {:?} timed out", self.tcb_idx);
+            return false;
+        }
+        let current = self.security_manager.get_security_level(self.tcb_idx);
+        if current < required {
+            warn!("security elevation on {:?} failed, still at {current:?}", self.tcb_idx);
+            return false;
+        }
+        true
+    }
+
+    /// The security level that would have allowed the request to succeed, if
+    /// it failed due to insufficient link security
+    fn required_security_level(reply: &AttChild) -> Option<SecurityLevel> {
+        let AttChild::AttErrorResponse(AttErrorResponseBuilder {
+            opcode_in_error, error_code, ..
+        }) = reply
+        else {
+            return None;
+        };
+        // the prepared writes are discarded once executed, even if this fails,
+        // so there would be nothing left to replay
+        if *opcode_in_error == AttOpcode::EXECUTE_WRITE_REQUEST {
+            return None;
+        }
+        match error_code {
+            AttErrorCode::INSUFFICIENT_ENCRYPTION => Some(SecurityLevel::Encrypted),
+            AttErrorCode::INSUFFICIENT_AUTHENTICATION => Some(SecurityLevel::Authenticated),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::{
+        task::{spawn_local, yield_now},
+        time::Instant,
+    };
+
+    use crate::{
+        gatt::{ids::AttHandle, mocks::mock_security_manager::MockSecurityManager},
+        packets::{AttAttributeDataChild, AttReadResponseBuilder},
+        utils::{
+            clock::{TokioClock, VirtualClock},
+            packet::build_att_data,
+            task::block_on_locally,
+        },
+    };
+
+    use super::*;
+
+    const TCB_IDX: TransportIndex = TransportIndex(1);
+
+    fn make_security_elevation() -> (Rc<SecurityElevation>, Rc<MockSecurityManager>) {
+        let security_manager = Rc::new(MockSecurityManager::new());
+        security_manager.set_security_elevation_supported(true);
+        (Rc::new(SecurityElevation::new(TCB_IDX, security_manager.clone())), security_manager)
+    }
+
+    fn make_error(opcode_in_error: AttOpcode, error_code: AttErrorCode) -> AttChild {
+        AttErrorResponseBuilder {
+            opcode_in_error,
+            handle_in_error: AttHandle(3).into(),
+            error_code,
+        }
+        .into()
+    }
+
+    #[test]
+    fn test_elevation_succeeds() {
+        block_on_locally(async {
+            // arrange
+            let (security_elevation, security_manager) = make_security_elevation();
+
+            // act: park a request that failed due to insufficient encryption
+            let pending = spawn_local({
+                let security_elevation = security_elevation.clone();
+                async move {
+                    security_elevation
+                        .try_elevate(
+                            &make_error(
+                                AttOpcode::READ_REQUEST,
+                                AttErrorCode::INSUFFICIENT_ENCRYPTION,
+                            ),
+                            &TokioClock,
+                        )
+                        .await
+                }
+            });
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            security_manager.set_security_level(TCB_IDX, SecurityLevel::Encrypted);
+            security_elevation.on_security_level_changed();
+
+            // assert: encryption was requested, and the request should be replayed
+            assert_eq!(
+                security_manager.take_security_elevation_requests(),
+                vec![(TCB_IDX, SecurityLevel::Encrypted)]
+            );
+            assert!(pending.await.unwrap());
+        });
+    }
+
+    #[test]
+    fn test_elevation_times_out() {
+        block_on_locally(async {
+            // arrange
+            let (security_elevation, _) = make_security_elevation();
+            let time_parked = Instant::now();
+
+            // act: park a request, and never upgrade the link
+            let res = security_elevation
+                .try_elevate(
+                    &make_error(AttOpcode::READ_REQUEST, AttErrorCode::INSUFFICIENT_AUTHENTICATION),
+                    &TokioClock,
+                )
+                .await;
+
+            // assert: the request is not replayed, after the timeout
+            assert!(!res);
+            assert!(Instant::now().duration_since(time_parked) >= SECURITY_ELEVATION_TIMEOUT);
+        });
+    }
+
+    #[test]
+    fn test_elevation_times_out_on_virtual_clock() {
+        block_on_locally(async {
+            // arrange: a request parked on a virtual clock
+            let (security_elevation, _) = make_security_elevation();
+            let clock = VirtualClock::new();
+            let pending = spawn_local({
+                let clock = clock.clone();
+                async move {
+                    security_elevation
+                        .try_elevate(
+                            &make_error(
+                                AttOpcode::READ_REQUEST,
+                                AttErrorCode::INSUFFICIENT_AUTHENTICATION,
+                            ),
+                            &clock,
+                        )
+                        .await
+                }
+            });
+            yield_now().await;
+
+            // act: advance the clock up to the timeout, and then past it
+            clock.advance(SECURITY_ELEVATION_TIMEOUT - Duration::from_millis(1)).await;
+            let parked_before_timeout = !pending.is_finished();
+            clock.advance(Duration::from_millis(1)).await;
+
+            // assert: the request was only released once the timeout elapsed
+            assert!(parked_before_timeout);
+            assert!(!pending.await.unwrap());
+        });
+    }
+
+    #[test]
+    fn test_elevation_fails() {
+        block_on_locally(async {
+            // arrange
+            let (security_elevation, security_manager) = make_security_elevation();
+
+            // act: park a request requiring authentication, and only encrypt the link
+            let pending = spawn_local({
+                let security_elevation = security_elevation.clone();
+                async move {
+                    security_elevation
+                        .try_elevate(
+                            &make_error(
+                                AttOpcode::READ_REQUEST,
+                                AttErrorCode::INSUFFICIENT_AUTHENTICATION,
+                            ),
+                            &TokioClock,
+                        )
+                        .await
+                }
+            });
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            security_manager.set_security_level(TCB_IDX, SecurityLevel::Encrypted);
+            security_elevation.on_security_level_changed();
+
+            // assert: the request is not replayed
+            assert!(!pending.await.unwrap());
+        });
+    }
+
+    #[test]
+    fn test_elevation_not_supported() {
+        block_on_locally(async {
+            let (security_elevation, security_manager) = make_security_elevation();
+            security_manager.set_security_elevation_supported(false);
+
+            let res = security_elevation
+                .try_elevate(
+                    &make_error(AttOpcode::READ_REQUEST, AttErrorCode::INSUFFICIENT_ENCRYPTION),
+                    &TokioClock,
+                )
+                .await;
+
+            assert!(!res);
+        });
+    }
+
+    #[test]
+    fn test_no_elevation_for_other_replies() {
+        block_on_locally(async {
+            // arrange
+            let (security_elevation, security_manager) = make_security_elevation();
+
+            // act: try to elevate after a successful reply and an unrelated error
+            let success = security_elevation
+                .try_elevate(
+                    &AttReadResponseBuilder {
+                        value: build_att_data(AttAttributeDataChild::RawData([1, 2].into())),
+                    }
+                    .into(),
+                    &TokioClock,
+                )
+                .await;
+            let error = security_elevation
+                .try_elevate(
+                    &make_error(AttOpcode::READ_REQUEST, AttErrorCode::INVALID_HANDLE),
+                    &TokioClock,
+                )
+                .await;
+
+            // assert: nothing was requested
+            assert!(!success);
+            assert!(!error);
+            assert_eq!(security_manager.take_security_elevation_requests(), vec![]);
+        });
+    }
+
+    #[test]
+    fn test_no_elevation_when_already_secure() {
+        block_on_locally(async {
+            // arrange: the link is already encrypted
+            let (security_elevation, security_manager) = make_security_elevation();
+            security_manager.set_security_level(TCB_IDX, SecurityLevel::Encrypted);
+
+            // act: the upper layer rejects a request due to insufficient encryption
+            let res = security_elevation
+                .try_elevate(
+                    &make_error(AttOpcode::READ_REQUEST, AttErrorCode::INSUFFICIENT_ENCRYPTION),
+                    &TokioClock,
+                )
+                .await;
+
+            // assert: nothing was requested
+            assert!(!res);
+            assert_eq!(security_manager.take_security_elevation_requests(), vec![]);
+        });
+    }
+
+    #[test]
+    fn test_no_elevation_for_execute_write() {
+        block_on_locally(async {
+            let (security_elevation, security_manager) = make_security_elevation();
+
+            let res = security_elevation
+                .try_elevate(
+                    &make_error(
+                        AttOpcode::EXECUTE_WRITE_REQUEST,
+                        AttErrorCode::INSUFFICIENT_ENCRYPTION,
+                    ),
+                    &TokioClock,
+                )
+                .await;
+
+            assert!(!res);
+            assert_eq!(security_manager.take_security_elevation_requests(), vec![]);
+        });
+    }
+}
@@ -1,18 +1,34 @@
 //! This module initializes the built-in services included in every
 //! GATT server.
 
+pub mod dis;
 pub mod gap;
 pub mod gatt;
 
+use std::rc::Rc;
+
 use anyhow::Result;
 
-use self::{gap::register_gap_service, gatt::register_gatt_service};
+use self::{
+    dis::{register_dis_service, DeviceInformationProvider},
+    gap::{register_gap_service, GapConfiguration},
+    gatt::{register_gatt_service, ServerSupportedFeatures},
+};
 
 use super::gatt_database::GattDatabase;
 
-/// Register all built-in services with the provided database
-pub fn register_builtin_services(database: &mut GattDatabase) -> Result<()> {
-    register_gap_service(database)?;
-    register_gatt_service(database)?;
+/// Register all built-in services with the provided database. The Device
+/// Information Service is only included if a provider is supplied.
+pub fn register_builtin_services(
+    database: &mut GattDatabase,
+    gap_configuration: Rc<dyn GapConfiguration>,
+    server_supported_features: ServerSupportedFeatures,
+    device_information: Option<&dyn DeviceInformationProvider>,
+) -> Result<()> {
+    register_gap_service(database, gap_configuration)?;
+    register_gatt_service(database, server_supported_features)?;
+    if let Some(device_information) = device_information {
+        register_dis_service(database, device_information)?;
+    }
     Ok(())
 }
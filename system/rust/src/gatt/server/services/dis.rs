@@ -0,0 +1,273 @@
+//! The Device Information Service, as defined in the Device Information
+//! Service Specification 1.1. It is only included in servers when the upper
+//! layers supply the information to expose, as the legacy stack does.
+
+use std::{collections::HashMap, rc::Rc};
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::{
+    core::uuid::Uuid,
+    gatt::{
+        callbacks::GattDatastore,
+        ffi::AttributeBackingType,
+        ids::{AttHandle, TransportIndex},
+        server::gatt_database::{
+            AttPermissions, GattCharacteristicWithHandle, GattDatabase, GattServiceWithHandle,
+        },
+    },
+    packets::AttErrorCode,
+};
+
+/// The values exposed by the Device Information Service, supplied by the
+/// upper layers. They are read once, when the service is registered, and a
+/// characteristic is only included if its value is supplied.
+pub trait DeviceInformationProvider {
+    /// The Manufacturer Name String
+    fn manufacturer_name(&self) -> Option<String>;
+    /// The Model Number String
+    fn model_number(&self) -> Option<String>;
+    /// The Firmware Revision String
+    fn firmware_revision(&self) -> Option<String>;
+    /// The Software Revision String
+    fn software_revision(&self) -> Option<String>;
+    /// The PnP ID
+    fn pnp_id(&self) -> Option<PnpId>;
+}
+
+/// The value of the PnP ID characteristic (DIS 1.1 3.9)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PnpId {
+    /// The namespace of the Vendor ID: 0x01 for the Bluetooth SIG, 0x02 for
+    /// the USB Implementer's Forum
+    pub vendor_id_source: u8,
+    /// The Vendor ID, in the namespace given by vendor_id_source
+    pub vendor_id: u16,
+    /// Assigned by the vendor
+    pub product_id: u16,
+    /// Assigned by the vendor
+    pub product_version: u16,
+}
+
+impl PnpId {
+    fn to_le_bytes(self) -> Vec<u8> {
+        [self.vendor_id_source]
+            .into_iter()
+            .chain(
+                [self.vendor_id, self.product_id, self.product_version]
+                    .into_iter()
+                    .flat_map(u16::to_le_bytes),
+            )
+            .collect()
+    }
+}
+
+/// The UUID used for the Device Information Service (Assigned Numbers 3.4.2
+/// Services by Name)
+pub const DEVICE_INFORMATION_SERVICE_UUID: Uuid = Uuid::new(0x180A);
+/// The UUID used for the Manufacturer Name String characteristic (Assigned Numbers 3.8.1 Characteristics by Name)
+pub const MANUFACTURER_NAME_UUID: Uuid = Uuid::new(0x2A29);
+/// The UUID used for the Model Number String characteristic (Assigned Numbers 3.8.1 Characteristics by Name)
+pub const MODEL_NUMBER_UUID: Uuid = Uuid::new(0x2A24);
+/// The UUID used for the Firmware Revision String characteristic (Assigned Numbers 3.8.1 Characteristics by Name)
+pub const FIRMWARE_REVISION_UUID: Uuid = Uuid::new(0x2A26);
+/// The UUID used for the Software Revision String characteristic (Assigned Numbers 3.8.1 Characteristics by Name)
+pub const SOFTWARE_REVISION_UUID: Uuid = Uuid::new(0x2A28);
+/// The UUID used for the PnP ID characteristic (Assigned Numbers 3.8.1 Characteristics by Name)
+pub const PNP_ID_UUID: Uuid = Uuid::new(0x2A50);
+
+// Must lie in the range specified by GATT_GAP_START_HANDLE from legacy stack,
+// after the GAP service, so that it never collides with the handles the legacy
+// stack assigns to other services
+const DEVICE_INFORMATION_SERVICE_HANDLE: AttHandle = AttHandle(28);
+
+/// Serves the values read from the provider when the service was registered,
+/// by value handle
+struct DeviceInformationService {
+    values: HashMap<AttHandle, Vec<u8>>,
+}
+
+#[async_trait(?Send)]
+impl GattDatastore for DeviceInformationService {
+    async fn read(
+        &self,
+        _: TransportIndex,
+        handle: AttHandle,
+        _: AttributeBackingType,
+    ) -> Result<Vec<u8>, AttErrorCode> {
+        Ok(self.values.get(&handle).expect("unexpected handle read").clone())
+    }
+
+    async fn write(
+        &self,
+        _: TransportIndex,
+        _: AttHandle,
+        _: AttributeBackingType,
+        _: &[u8],
+    ) -> Result<(), AttErrorCode> {
+        unreachable!("the device information is not writable")
+    }
+}
+
+/// Register the Device Information Service in the provided GATT database,
+/// exposing the values currently supplied by the given provider.
+pub fn register_dis_service(
+    database: &mut GattDatabase,
+    provider: &dyn DeviceInformationProvider,
+) -> Result<()> {
+    let characteristics = [
+        (MANUFACTURER_NAME_UUID, provider.manufacturer_name().map(String::into_bytes)),
+        (MODEL_NUMBER_UUID, provider.model_number().map(String::into_bytes)),
+        (FIRMWARE_REVISION_UUID, provider.firmware_revision().map(String::into_bytes)),
+        (SOFTWARE_REVISION_UUID, provider.software_revision().map(String::into_bytes)),
+        (PNP_ID_UUID, provider.pnp_id().map(PnpId::to_le_bytes)),
+    ]
+    .into_iter()
+    .filter_map(|(type_, value)| Some((type_, value?)))
+    .enumerate()
+    // each characteristic is a declaration followed by its value
+    .map(|(index, (type_, value))| {
+        (AttHandle(DEVICE_INFORMATION_SERVICE_HANDLE.0 + 2 * (index as u16 + 1)), type_, value)
+    })
+    .collect::<Vec<_>>();
+    database.add_service_with_handles(
+        GattServiceWithHandle {
+            handle: DEVICE_INFORMATION_SERVICE_HANDLE,
+            type_: DEVICE_INFORMATION_SERVICE_UUID,
+            characteristics: characteristics
+                .iter()
+                .map(|(handle, type_, _)| GattCharacteristicWithHandle {
+                    handle: *handle,
+                    type_: *type_,
+                    permissions: AttPermissions::READABLE,
+                    descriptors: vec![],
+                })
+                .collect(),
+        },
+        Rc::new(DeviceInformationService {
+            values: characteristics.into_iter().map(|(handle, _, value)| (handle, value)).collect(),
+        }),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::{
+        core::shared_box::SharedBox,
+        gatt::server::{
+            att_database::{AttAttribute, AttDatabase},
+            gatt_database::{CHARACTERISTIC_UUID, PRIMARY_SERVICE_DECLARATION_UUID},
+        },
+        utils::task::block_on_locally,
+    };
+
+    const TCB_IDX: TransportIndex = TransportIndex(1);
+
+    const PNP_ID: PnpId = PnpId {
+        vendor_id_source: 0x01,
+        vendor_id: 0x00E0,
+        product_id: 0x1234,
+        product_version: 0x0102,
+    };
+
+    struct TestDeviceInformation {
+        software_revision: Option<String>,
+    }
+
+    impl DeviceInformationProvider for TestDeviceInformation {
+        fn manufacturer_name(&self) -> Option<String> {
+            Some("Manufacturer".into())
+        }
+
+        fn model_number(&self) -> Option<String> {
+            Some("Model".into())
+        }
+
+        fn firmware_revision(&self) -> Option<String> {
+            Some("1.0".into())
+        }
+
+        fn software_revision(&self) -> Option<String> {
+            self.software_revision.clone()
+        }
+
+        fn pnp_id(&self) -> Option<PnpId> {
+            Some(PNP_ID)
+        }
+    }
+
+    fn init_dis_db(software_revision: Option<&str>) -> SharedBox<GattDatabase> {
+        let mut gatt_database = GattDatabase::new();
+        register_dis_service(
+            &mut gatt_database,
+            &TestDeviceInformation { software_revision: software_revision.map(Into::into) },
+        )
+        .unwrap();
+        SharedBox::new(gatt_database)
+    }
+
+    fn read_value(gatt_db: &SharedBox<GattDatabase>, type_: Uuid) -> Vec<u8> {
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+        let attrs = att_db.list_attributes();
+        let handle = attrs.iter().find(|attr| attr.type_ == type_).unwrap().handle;
+        block_on_locally(att_db.read_attribute(handle)).unwrap().to_vec()
+    }
+
+    #[test]
+    fn test_dis_service_discovery() {
+        // arrange
+        let gatt_db = init_dis_db(Some("2.0"));
+        let att_db = gatt_db.get_att_database(TCB_IDX);
+
+        // act: look at the service and characteristic declarations
+        let attrs = att_db.list_attributes();
+        let service = attrs.first().unwrap();
+        let declarations =
+            attrs.iter().filter(|attr| attr.type_ == CHARACTERISTIC_UUID).collect::<Vec<_>>();
+
+        // assert
+        assert_eq!(service.handle, DEVICE_INFORMATION_SERVICE_HANDLE);
+        assert_eq!(service.type_, PRIMARY_SERVICE_DECLARATION_UUID);
+        assert_eq!(
+            &*block_on_locally(att_db.read_attribute(service.handle)).unwrap(),
+            [0x0A, 0x18]
+        );
+        assert_eq!(declarations.len(), 5);
+        assert!(declarations
+            .iter()
+            .all(|AttAttribute { permissions, .. }| *permissions == AttPermissions::READABLE));
+    }
+
+    #[test]
+    fn test_read_strings() {
+        let gatt_db = init_dis_db(Some("2.0"));
+
+        assert_eq!(read_value(&gatt_db, MANUFACTURER_NAME_UUID), b"Manufacturer");
+        assert_eq!(read_value(&gatt_db, MODEL_NUMBER_UUID), b"Model");
+        assert_eq!(read_value(&gatt_db, FIRMWARE_REVISION_UUID), b"1.0");
+        assert_eq!(read_value(&gatt_db, SOFTWARE_REVISION_UUID), b"2.0");
+    }
+
+    #[test]
+    fn test_read_pnp_id() {
+        let gatt_db = init_dis_db(None);
+
+        assert_eq!(read_value(&gatt_db, PNP_ID_UUID), [0x01, 0xE0, 0x00, 0x34, 0x12, 0x02, 0x01]);
+    }
+
+    #[test]
+    fn test_missing_value_not_exposed() {
+        // arrange
+        let gatt_db = init_dis_db(None);
+
+        // act
+        let attrs = gatt_db.get_att_database(TCB_IDX).list_attributes();
+
+        // assert
+        assert!(!attrs.iter().any(|attr| attr.type_ == SOFTWARE_REVISION_UUID));
+        assert_eq!(attrs.iter().filter(|attr| attr.type_ == CHARACTERISTIC_UUID).count(), 4);
+    }
+}
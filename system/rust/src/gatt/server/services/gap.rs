@@ -18,12 +18,89 @@ use crate::{
     packets::AttErrorCode,
 };
 
-struct GapService;
+/// The values exposed by the GAP service, supplied by the upper layers
+pub trait GapConfiguration {
+    /// The Device Name, or None if it must not be exposed to peers
+    fn device_name(&self) -> Option<Vec<u8>>;
+    /// The Appearance (Assigned Numbers 2.6 Appearance Values)
+    fn appearance(&self) -> u16;
+    /// The Peripheral Preferred Connection Parameters, or None if the
+    /// characteristic should not be included
+    fn preferred_connection_parameters(&self) -> Option<PreferredConnectionParameters>;
+    /// Whether peers may write the Device Name (Core Spec 5.3 Vol 3C 12.1)
+    fn is_device_name_writable(&self) -> bool;
+    /// Store a Device Name written by a peer
+    fn set_device_name(&self, name: Vec<u8>);
+}
+
+/// The value of the Peripheral Preferred Connection Parameters characteristic
+/// (Core Spec 5.3 Vol 3C 12.3)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PreferredConnectionParameters {
+    /// In units of 1.25ms
+    pub min_connection_interval: u16,
+    /// In units of 1.25ms
+    pub max_connection_interval: u16,
+    /// In connection events
+    pub peripheral_latency: u16,
+    /// In units of 10ms
+    pub supervision_timeout: u16,
+}
+
+impl PreferredConnectionParameters {
+    fn to_le_bytes(self) -> Vec<u8> {
+        [
+            self.min_connection_interval,
+            self.max_connection_interval,
+            self.peripheral_latency,
+            self.supervision_timeout,
+        ]
+        .into_iter()
+        .flat_map(u16::to_le_bytes)
+        .collect()
+    }
+}
+
+/// The configuration used unless the upper layers supply one: the Device Name
+/// is hidden, and the Appearance is "Unknown"
+pub struct DefaultGapConfiguration;
+
+impl GapConfiguration for DefaultGapConfiguration {
+    fn device_name(&self) -> Option<Vec<u8>> {
+        // TODO(aryarahul): support discoverability, when we make this the main GATT server
+        None
+    }
+
+    fn appearance(&self) -> u16 {
+        // 0x0000 from AssignedNumbers => "Unknown"
+        0x0000
+    }
+
+    fn preferred_connection_parameters(&self) -> Option<PreferredConnectionParameters> {
+        None
+    }
+
+    fn is_device_name_writable(&self) -> bool {
+        false
+    }
+
+    fn set_device_name(&self, _: Vec<u8>) {
+        unreachable!("the Device Name is not writable")
+    }
+}
+
+struct GapService {
+    configuration: Rc<dyn GapConfiguration>,
+}
 
 // Must lie in the range specified by GATT_GAP_START_HANDLE from legacy stack
 const GAP_SERVICE_HANDLE: AttHandle = AttHandle(20);
 const DEVICE_NAME_HANDLE: AttHandle = AttHandle(22);
 const DEVICE_APPEARANCE_HANDLE: AttHandle = AttHandle(24);
+const PREFERRED_CONNECTION_PARAMETERS_HANDLE: AttHandle = AttHandle(26);
+
+/// As per Core Spec 5.3 Vol 3C 12.1
+const MAX_DEVICE_NAME_LEN: usize = 248;
 
 /// The UUID used for the GAP service (Assigned Numbers 3.4.1 Services by Name)
 pub const GAP_SERVICE_UUID: Uuid = Uuid::new(0x1800);
@@ -31,6 +108,8 @@ pub const GAP_SERVICE_UUID: Uuid = Uuid::new(0x1800);
 pub const DEVICE_NAME_UUID: Uuid = Uuid::new(0x2A00);
 /// The UUID used for the Device Appearance characteristic (Assigned Numbers 3.8.1 Characteristics by Name)
 pub const DEVICE_APPEARANCE_UUID: Uuid = Uuid::new(0x2A01);
+/// The UUID used for the Peripheral Preferred Connection Parameters characteristic (Assigned Numbers 3.8.1 Characteristics by Name)
+pub const PREFERRED_CONNECTION_PARAMETERS_UUID: Uuid = Uuid::new(0x2A04);
 
 #[async_trait(?Send)]
 impl GattDatastore for GapService {
@@ -41,13 +120,16 @@ impl GattDatastore for GapService {
         _: AttributeBackingType,
     ) -> Result<Vec<u8>, AttErrorCode> {
         match handle {
+            // if the name is hidden, don't let peers read it
             DEVICE_NAME_HANDLE => {
-                // for non-bonded peers, don't let them read the device name
-                // TODO(aryarahul): support discoverability, when we make this the main GATT server
-                Err(AttErrorCode::INSUFFICIENT_AUTHENTICATION)
+                self.configuration.device_name().ok_or(AttErrorCode::INSUFFICIENT_AUTHENTICATION)
             }
-            // 0x0000 from AssignedNumbers => "Unknown"
-            DEVICE_APPEARANCE_HANDLE => Ok(vec![0x00, 0x00]),
+            DEVICE_APPEARANCE_HANDLE => Ok(self.configuration.appearance().to_le_bytes().to_vec()),
+            PREFERRED_CONNECTION_PARAMETERS_HANDLE => self
+                .configuration
+                .preferred_connection_parameters()
+                .map(PreferredConnectionParameters::to_le_bytes)
+                .ok_or(AttErrorCode::UNLIKELY_ERROR),
             _ => unreachable!("unexpected handle read"),
         }
     }
@@ -55,44 +137,73 @@ impl GattDatastore for GapService {
     async fn write(
         &self,
         _: TransportIndex,
-        _: AttHandle,
+        handle: AttHandle,
         _: AttributeBackingType,
-        _: &[u8],
+        data: &[u8],
     ) -> Result<(), AttErrorCode> {
-        unreachable!("no GAP data should be writable")
+        if handle != DEVICE_NAME_HANDLE {
+            unreachable!("only the Device Name should be writable")
+        }
+        if data.len() > MAX_DEVICE_NAME_LEN {
+            return Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH);
+        }
+        self.configuration.set_device_name(data.to_vec());
+        Ok(())
     }
 }
 
-/// Register the GAP service in the provided GATT database.
-pub fn register_gap_service(database: &mut GattDatabase) -> Result<()> {
+/// Register the GAP service in the provided GATT database, exposing the
+/// values from the given configuration.
+pub fn register_gap_service(
+    database: &mut GattDatabase,
+    configuration: Rc<dyn GapConfiguration>,
+) -> Result<()> {
+    let mut device_name_permissions = AttPermissions::READABLE;
+    if configuration.is_device_name_writable() {
+        device_name_permissions |= AttPermissions::WRITABLE_WITH_RESPONSE;
+    }
+    let has_preferred_connection_parameters =
+        configuration.preferred_connection_parameters().is_some();
     database.add_service_with_handles(
         // GAP Service
         GattServiceWithHandle {
             handle: GAP_SERVICE_HANDLE,
             type_: GAP_SERVICE_UUID,
             // Device Name
-            characteristics: vec![
-                GattCharacteristicWithHandle {
+            characteristics: [
+                Some(GattCharacteristicWithHandle {
                     handle: DEVICE_NAME_HANDLE,
                     type_: DEVICE_NAME_UUID,
-                    permissions: AttPermissions::READABLE,
+                    permissions: device_name_permissions,
                     descriptors: vec![],
-                },
+                }),
                 // Appearance
-                GattCharacteristicWithHandle {
+                Some(GattCharacteristicWithHandle {
                     handle: DEVICE_APPEARANCE_HANDLE,
                     type_: DEVICE_APPEARANCE_UUID,
                     permissions: AttPermissions::READABLE,
                     descriptors: vec![],
-                },
-            ],
+                }),
+                // Peripheral Preferred Connection Parameters
+                has_preferred_connection_parameters.then_some(GattCharacteristicWithHandle {
+                    handle: PREFERRED_CONNECTION_PARAMETERS_HANDLE,
+                    type_: PREFERRED_CONNECTION_PARAMETERS_UUID,
+                    permissions: AttPermissions::READABLE,
+                    descriptors: vec![],
+                }),
+            ]
+            .into_iter()
+            .flatten()
+            .collect(),
         },
-        Rc::new(GapService),
+        Rc::new(GapService { configuration }),
     )
 }
 
 #[cfg(test)]
 mod test {
+    use std::cell::RefCell;
+
     use super::*;
 
     use crate::{
@@ -106,9 +217,51 @@ mod test {
 
     const TCB_IDX: TransportIndex = TransportIndex(1);
 
+    const PARAMETERS: PreferredConnectionParameters = PreferredConnectionParameters {
+        min_connection_interval: 0x0006,
+        max_connection_interval: 0x0C80,
+        peripheral_latency: 0x0001,
+        supervision_timeout: 0x0100,
+    };
+
+    #[derive(Default)]
+    struct TestGapConfiguration {
+        device_name: RefCell<Option<Vec<u8>>>,
+        writable: bool,
+        parameters: Option<PreferredConnectionParameters>,
+    }
+
+    impl GapConfiguration for TestGapConfiguration {
+        fn device_name(&self) -> Option<Vec<u8>> {
+            self.device_name.borrow().clone()
+        }
+
+        fn appearance(&self) -> u16 {
+            0x0341
+        }
+
+        fn preferred_connection_parameters(&self) -> Option<PreferredConnectionParameters> {
+            self.parameters
+        }
+
+        fn is_device_name_writable(&self) -> bool {
+            self.writable
+        }
+
+        fn set_device_name(&self, name: Vec<u8>) {
+            self.device_name.replace(Some(name));
+        }
+    }
+
     fn init_dbs() -> (SharedBox<GattDatabase>, impl AttDatabase) {
+        init_dbs_with_configuration(Rc::new(DefaultGapConfiguration))
+    }
+
+    fn init_dbs_with_configuration(
+        configuration: Rc<dyn GapConfiguration>,
+    ) -> (SharedBox<GattDatabase>, impl AttDatabase) {
         let mut gatt_database = GattDatabase::new();
-        register_gap_service(&mut gatt_database).unwrap();
+        register_gap_service(&mut gatt_database, configuration).unwrap();
         let gatt_database = SharedBox::new(gatt_database);
         let att_database = gatt_database.get_att_database(TCB_IDX);
         (gatt_database, att_database)
@@ -148,7 +301,7 @@ mod test {
         let name = block_on_locally(att_db.read_attribute(DEVICE_NAME_HANDLE));
 
         // assert: the name is not readable
-        assert_eq!(name, Err(AttErrorCode::INSUFFICIENT_AUTHENTICATION));
+        assert_eq!(name, Err(AttErrorCode::INSUFFICIENT_AUTHENTICATION.into()));
     }
 
     #[test]
@@ -160,6 +313,86 @@ mod test {
         let name = block_on_locally(att_db.read_attribute(DEVICE_APPEARANCE_HANDLE));
 
         // assert: the name is not readable
-        assert_eq!(name, Ok(vec![0x00, 0x00]));
+        assert_eq!(name, Ok(vec![0x00, 0x00].into()));
+    }
+
+    #[test]
+    fn test_read_configured_values() {
+        // arrange
+        let configuration = TestGapConfiguration {
+            device_name: RefCell::new(Some(b"name".to_vec())),
+            ..Default::default()
+        };
+        let (_gatt_db, att_db) = init_dbs_with_configuration(Rc::new(configuration));
+
+        // act
+        let name = block_on_locally(att_db.read_attribute(DEVICE_NAME_HANDLE));
+        let appearance = block_on_locally(att_db.read_attribute(DEVICE_APPEARANCE_HANDLE));
+
+        // assert
+        assert_eq!(name, Ok(b"name".to_vec().into()));
+        assert_eq!(appearance, Ok(vec![0x41, 0x03].into()));
+    }
+
+    #[test]
+    fn test_preferred_connection_parameters() {
+        // arrange
+        let configuration =
+            TestGapConfiguration { parameters: Some(PARAMETERS), ..Default::default() };
+        let (_gatt_db, att_db) = init_dbs_with_configuration(Rc::new(configuration));
+
+        // act
+        let attrs = att_db.list_attributes();
+        let value = block_on_locally(att_db.read_attribute(PREFERRED_CONNECTION_PARAMETERS_HANDLE));
+
+        // assert
+        assert_eq!(attrs.len(), 7);
+        assert_eq!(attrs[6].handle, PREFERRED_CONNECTION_PARAMETERS_HANDLE);
+        assert_eq!(attrs[6].type_, PREFERRED_CONNECTION_PARAMETERS_UUID);
+        assert_eq!(value, Ok(vec![0x06, 0x00, 0x80, 0x0C, 0x01, 0x00, 0x00, 0x01].into()));
+    }
+
+    #[test]
+    fn test_write_device_name() {
+        // arrange
+        let configuration = Rc::new(TestGapConfiguration { writable: true, ..Default::default() });
+        let (_gatt_db, att_db) = init_dbs_with_configuration(configuration.clone());
+
+        // act
+        let res = block_on_locally(att_db.write_attribute(DEVICE_NAME_HANDLE, 0, b"new name"));
+
+        // assert: the configuration was updated
+        assert_eq!(res, Ok(()));
+        assert_eq!(configuration.device_name(), Some(b"new name".to_vec()));
+    }
+
+    #[test]
+    fn test_write_device_name_too_long() {
+        // arrange
+        let configuration = Rc::new(TestGapConfiguration { writable: true, ..Default::default() });
+        let (_gatt_db, att_db) = init_dbs_with_configuration(configuration.clone());
+
+        // act
+        let res = block_on_locally(att_db.write_attribute(
+            DEVICE_NAME_HANDLE,
+            0,
+            &[b'a'; MAX_DEVICE_NAME_LEN + 1],
+        ));
+
+        // assert
+        assert_eq!(res, Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH.into()));
+        assert_eq!(configuration.device_name(), None);
+    }
+
+    #[test]
+    fn test_write_device_name_not_permitted() {
+        // arrange
+        let (_gatt_db, att_db) = init_dbs();
+
+        // act
+        let res = block_on_locally(att_db.write_attribute(DEVICE_NAME_HANDLE, 0, b"new name"));
+
+        // assert
+        assert_eq!(res, Err(AttErrorCode::WRITE_NOT_PERMITTED.into()));
     }
 }
@@ -4,11 +4,12 @@ use std::{cell::RefCell, collections::HashMap, ops::RangeInclusive, rc::Rc};
 
 use anyhow::Result;
 use async_trait::async_trait;
+use bitflags::bitflags;
 use log::{error, warn};
-use tokio::task::spawn_local;
 
 use crate::{
     core::{
+        address::AddressWithType,
         shared_box::{WeakBox, WeakBoxRef},
         uuid::Uuid,
     },
@@ -18,64 +19,90 @@ use crate::{
         ids::{AttHandle, TransportIndex},
         server::{
             att_server_bearer::AttServerBearer,
+            client_configuration::ClientConfiguration,
             gatt_database::{
                 AttDatabaseImpl, AttPermissions, GattCharacteristicWithHandle, GattDatabase,
-                GattDatabaseCallbacks, GattDescriptorWithHandle, GattServiceWithHandle,
+                GattDatabaseCallbacks, GattServiceWithHandle,
+            },
+            handle_assignments::HandleAssignments,
+            robust_caching::{
+                RobustCachingStore, CLIENT_SUPPORTED_FEATURES_UUID, DATABASE_HASH_UUID,
             },
         },
     },
-    packets::{
-        AttErrorCode, GattClientCharacteristicConfigurationBuilder,
-        GattClientCharacteristicConfigurationView, GattServiceChangedBuilder, Packet, Serializable,
-    },
+    packets::{AttErrorCode, GattServiceChangedBuilder},
 };
 
-#[derive(Default)]
+pub use crate::gatt::server::gatt_database::CLIENT_CHARACTERISTIC_CONFIGURATION_UUID;
+
 struct GattService {
     clients: RefCell<HashMap<TransportIndex, ClientState>>,
+    /// Bonded peers that are not currently connected, along with the range of
+    /// handles that changed since they disconnected (if any)
+    disconnected_bonded_peers: RefCell<HashMap<AddressWithType, Option<RangeInclusive<AttHandle>>>>,
+    /// Shared with the GattDatabase, which keeps the Database Hash up to date
+    robust_caching: Rc<RefCell<RobustCachingStore>>,
+    /// Shared with the GattDatabase, which tracks the handles that changed
+    /// since a restart
+    handle_assignments: Rc<RefCell<HandleAssignments>>,
+    server_supported_features: ServerSupportedFeatures,
+}
+
+bitflags! {
+    /// The Server Supported Features bits, from Core Spec 5.3 Vol 3G 7.4 Server
+    /// Supported Features
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+    pub struct ServerSupportedFeatures : u8 {
+        /// The server supports Enhanced ATT bearers
+        const EATT_SUPPORTED = 0x01;
+    }
 }
 
 #[derive(Clone)]
 struct ClientState {
     bearer: WeakBox<AttServerBearer<AttDatabaseImpl>>,
-    registered_for_service_change: bool,
+    peer: Option<AddressWithType>,
 }
 
 // Must lie in the range specified by GATT_GATT_START_HANDLE from legacy stack
 const GATT_SERVICE_HANDLE: AttHandle = AttHandle(1);
 const SERVICE_CHANGE_HANDLE: AttHandle = AttHandle(3);
-const SERVICE_CHANGE_CCC_DESCRIPTOR_HANDLE: AttHandle = AttHandle(4);
+// Handle 4 is the CCCD of the Service Changed characteristic
+const CLIENT_SUPPORTED_FEATURES_HANDLE: AttHandle = AttHandle(6);
+const DATABASE_HASH_HANDLE: AttHandle = AttHandle(8);
+const SERVER_SUPPORTED_FEATURES_HANDLE: AttHandle = AttHandle(10);
 
 /// The UUID used for the GATT service (Assigned Numbers 3.4.1 Services by Name)
 pub const GATT_SERVICE_UUID: Uuid = Uuid::new(0x1801);
 /// The UUID used for the Service Changed characteristic (Assigned Numbers 3.8.1 Characteristics by Name)
 pub const SERVICE_CHANGE_UUID: Uuid = Uuid::new(0x2A05);
-/// The UUID used for the Client Characteristic Configuration descriptor (Assigned Numbers 3.7 Descriptors)
-pub const CLIENT_CHARACTERISTIC_CONFIGURATION_UUID: Uuid = Uuid::new(0x2902);
+/// The UUID used for the Server Supported Features characteristic (Assigned Numbers 3.8.1 Characteristics by Name)
+pub const SERVER_SUPPORTED_FEATURES_UUID: Uuid = Uuid::new(0x2B3A);
 
 #[async_trait(?Send)]
 impl GattDatastore for GattService {
+    // The Service Changed characteristic is neither readable nor writable, and
+    // its CCCD is managed by the GattDatabase, so only the robust caching and
+    // feature characteristics reach us
     async fn read(
         &self,
         tcb_idx: TransportIndex,
         handle: AttHandle,
         _: AttributeBackingType,
     ) -> Result<Vec<u8>, AttErrorCode> {
-        if handle == SERVICE_CHANGE_CCC_DESCRIPTOR_HANDLE {
-            GattClientCharacteristicConfigurationBuilder {
-                notification: 0,
-                indication: self
-                    .clients
-                    .borrow()
-                    .get(&tcb_idx)
-                    .map(|state| state.registered_for_service_change)
-                    .unwrap_or(false)
-                    .into(),
+        match handle {
+            CLIENT_SUPPORTED_FEATURES_HANDLE => {
+                Ok(vec![self.robust_caching.borrow().client_supported_features(tcb_idx).bits()])
             }
-            .to_vec()
-            .map_err(|_| AttErrorCode::UNLIKELY_ERROR)
-        } else {
-            unreachable!()
+            DATABASE_HASH_HANDLE => {
+                // As per Core Spec 5.3 Vol 3G 2.5.2.1, a client that reads the
+                // Database Hash becomes change-aware
+                let mut robust_caching = self.robust_caching.borrow_mut();
+                robust_caching.mark_change_aware(tcb_idx);
+                Ok(robust_caching.database_hash().to_vec())
+            }
+            SERVER_SUPPORTED_FEATURES_HANDLE => Ok(vec![self.server_supported_features.bits()]),
+            _ => unreachable!("unexpected read from {handle:?}"),
         }
     }
 
@@ -86,93 +113,176 @@ impl GattDatastore for GattService {
         _: AttributeBackingType,
         data: &[u8],
     ) -> Result<(), AttErrorCode> {
-        if handle == SERVICE_CHANGE_CCC_DESCRIPTOR_HANDLE {
-            let ccc = GattClientCharacteristicConfigurationView::try_parse_from_buffer(data)
-                .map_err(|err| {
-                    warn!("failed to parse CCC descriptor, got: {err:?}");
-                    AttErrorCode::APPLICATION_ERROR
-                })?;
-            let mut clients = self.clients.borrow_mut();
-            let state = clients.get_mut(&tcb_idx);
-            let Some(state) = state else {
-                error!("Received write request from disconnected client...");
-                return Err(AttErrorCode::UNLIKELY_ERROR);
-            };
-            state.registered_for_service_change = ccc.get_indication() != 0;
-            Ok(())
-        } else {
-            unreachable!()
+        match handle {
+            CLIENT_SUPPORTED_FEATURES_HANDLE => {
+                self.robust_caching.borrow_mut().set_client_supported_features(tcb_idx, data)
+            }
+            _ => unreachable!("unexpected write to {handle:?}"),
         }
     }
 }
 
+impl GattService {
+    /// Send a Service Changed indication for the given range. The bearer drops
+    /// it if the client has not subscribed. Once confirmed, the client is
+    /// change-aware, and the handles removed within the range may be accessed
+    /// again (if they were reassigned).
+    fn send_service_changed_indication(
+        &self,
+        tcb_idx: TransportIndex,
+        bearer: &WeakBox<AttServerBearer<AttDatabaseImpl>>,
+        range: &RangeInclusive<AttHandle>,
+    ) {
+        bearer.with(|bearer| match bearer {
+            Some(bearer) => {
+                let indication = bearer.send_indication(
+                    SERVICE_CHANGE_HANDLE,
+                    GattServiceChangedBuilder {
+                        start_handle: (*range.start()).into(),
+                        end_handle: (*range.end()).into(),
+                    }
+                    .into(),
+                );
+                let robust_caching = self.robust_caching.clone();
+                let sent_at = robust_caching.borrow().removal_generation();
+                let range = range.clone();
+                bearer
+                    .executor()
+                    .spawn(Box::pin(async move {
+                        if indication.await.is_ok() {
+                            robust_caching
+                                .borrow_mut()
+                                .on_service_changed_confirmed(tcb_idx, &range, sent_at);
+                        }
+                    }))
+                    .detach();
+            }
+            None => {
+                error!("Registered client's bearer has been destructed ({tcb_idx:?})")
+            }
+        });
+    }
+}
+
 impl GattDatabaseCallbacks for GattService {
     fn on_le_connect(
         &self,
         tcb_idx: TransportIndex,
         bearer: WeakBoxRef<AttServerBearer<AttDatabaseImpl>>,
     ) {
-        // TODO(aryarahul): registered_for_service_change may not be false for bonded devices
-        self.clients.borrow_mut().insert(
-            tcb_idx,
-            ClientState { bearer: bearer.downgrade(), registered_for_service_change: false },
-        );
+        self.clients
+            .borrow_mut()
+            .insert(tcb_idx, ClientState { bearer: bearer.downgrade(), peer: None });
     }
 
     fn on_le_disconnect(&self, tcb_idx: TransportIndex) {
-        self.clients.borrow_mut().remove(&tcb_idx);
+        let client = self.clients.borrow_mut().remove(&tcb_idx);
+        if let Some(ClientState { peer: Some(peer), .. }) = client {
+            self.disconnected_bonded_peers.borrow_mut().insert(peer, None);
+        }
+    }
+
+    fn on_le_bonded(&self, tcb_idx: TransportIndex, peer: AddressWithType) {
+        let Some(client) = self.clients.borrow_mut().get_mut(&tcb_idx).map(|client| {
+            client.peer = Some(peer);
+            client.clone()
+        }) else {
+            warn!("got bonding identity for unknown client {tcb_idx:?}");
+            return;
+        };
+        // As per Core Spec 5.3 Vol 3G 7.1, a bonded client must be informed of any
+        // changes made while it was disconnected. If it has not connected since the
+        // stack restarted, we only know which services did not keep their handles
+        // (so a newly bonded client may be informed needlessly).
+        let pending = match self.disconnected_bonded_peers.borrow_mut().remove(&peer) {
+            Some(pending) => pending,
+            None => self.handle_assignments.borrow().changed(),
+        };
+        if let Some(range) = pending {
+            self.send_service_changed_indication(tcb_idx, &client.bearer, &range);
+        }
     }
 
     fn on_service_change(&self, range: RangeInclusive<AttHandle>) {
-        for (conn_id, client) in self.clients.borrow().clone() {
-            if client.registered_for_service_change {
-                client.bearer.with(|bearer| match bearer {
-                    Some(bearer) => {
-                        spawn_local(
-                            bearer.send_indication(
-                                SERVICE_CHANGE_HANDLE,
-                                GattServiceChangedBuilder {
-                                    start_handle: (*range.start()).into(),
-                                    end_handle: (*range.end()).into(),
-                                }
-                                .into(),
-                            ),
-                        );
-                    }
-                    None => {
-                        error!("Registered client's bearer has been destructed ({conn_id:?})")
-                    }
-                });
-            }
+        for (tcb_idx, client) in self.clients.borrow().clone() {
+            self.send_service_changed_indication(tcb_idx, &client.bearer, &range);
+        }
+        for pending in self.disconnected_bonded_peers.borrow_mut().values_mut() {
+            *pending = Some(match pending.take() {
+                Some(pending) => {
+                    *pending.start().min(range.start())..=*pending.end().max(range.end())
+                }
+                None => range.clone(),
+            });
         }
     }
+
+    fn on_client_configuration_change(
+        &self,
+        _tcb_idx: TransportIndex,
+        _handle: AttHandle,
+        _configuration: ClientConfiguration,
+    ) {
+        // Service Changed is indicated to every client, so we don't need to
+        // track its CCCD
+    }
 }
 
-/// Register the GATT service in the provided GATT database.
-pub fn register_gatt_service(database: &mut GattDatabase) -> Result<()> {
-    let this = Rc::new(GattService::default());
+/// Register the GATT service in the provided GATT database, advertising the
+/// given features of the server.
+pub fn register_gatt_service(
+    database: &mut GattDatabase,
+    server_supported_features: ServerSupportedFeatures,
+) -> Result<()> {
+    let this = Rc::new(GattService {
+        clients: Default::default(),
+        disconnected_bonded_peers: Default::default(),
+        robust_caching: database.robust_caching(),
+        handle_assignments: database.handle_assignments(),
+        server_supported_features,
+    });
     database.add_service_with_handles(
         // GATT Service
         GattServiceWithHandle {
             handle: GATT_SERVICE_HANDLE,
             type_: GATT_SERVICE_UUID,
-            // Service Changed Characteristic
-            characteristics: vec![GattCharacteristicWithHandle {
-                handle: SERVICE_CHANGE_HANDLE,
-                type_: SERVICE_CHANGE_UUID,
-                permissions: AttPermissions::INDICATE,
-                descriptors: vec![GattDescriptorWithHandle {
-                    handle: SERVICE_CHANGE_CCC_DESCRIPTOR_HANDLE,
-                    type_: CLIENT_CHARACTERISTIC_CONFIGURATION_UUID,
+            characteristics: vec![
+                // Service Changed Characteristic, whose CCCD is managed by the database
+                GattCharacteristicWithHandle {
+                    handle: SERVICE_CHANGE_HANDLE,
+                    type_: SERVICE_CHANGE_UUID,
+                    permissions: AttPermissions::INDICATE,
+                    descriptors: vec![],
+                },
+                // Client Supported Features Characteristic
+                GattCharacteristicWithHandle {
+                    handle: CLIENT_SUPPORTED_FEATURES_HANDLE,
+                    type_: CLIENT_SUPPORTED_FEATURES_UUID,
                     permissions: AttPermissions::READABLE | AttPermissions::WRITABLE_WITH_RESPONSE,
-                }],
-            }],
+                    descriptors: vec![],
+                },
+                // Database Hash Characteristic
+                GattCharacteristicWithHandle {
+                    handle: DATABASE_HASH_HANDLE,
+                    type_: DATABASE_HASH_UUID,
+                    permissions: AttPermissions::READABLE,
+                    descriptors: vec![],
+                },
+                // Server Supported Features Characteristic
+                GattCharacteristicWithHandle {
+                    handle: SERVER_SUPPORTED_FEATURES_HANDLE,
+                    type_: SERVER_SUPPORTED_FEATURES_UUID,
+                    permissions: AttPermissions::READABLE,
+                    descriptors: vec![],
+                },
+            ],
         },
         this.clone(),
     )?;
     database.register_listener(this);
     Ok(())
 }
+
 #[cfg(test)]
 mod test {
     use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
@@ -182,26 +292,51 @@ mod test {
     use crate::{
         core::shared_box::SharedBox,
         gatt::{
-            mocks::mock_datastore::MockDatastore,
+            mocks::{
+                mock_datastore::MockDatastore,
+                mock_handle_assignment_storage::MockHandleAssignmentStorage,
+                mock_security_manager::MockSecurityManager,
+            },
+            mtu::MAX_ATT_MTU,
             server::{
                 att_database::AttDatabase,
+                att_error::AttError,
                 gatt_database::{
-                    GattDatabase, CHARACTERISTIC_UUID, PRIMARY_SERVICE_DECLARATION_UUID,
+                    CharacteristicBuilder, GattDatabase, ServiceBuilder, CHARACTERISTIC_UUID,
+                    PRIMARY_SERVICE_DECLARATION_UUID,
                 },
+                robust_caching::ClientSupportedFeatures,
+                security_elevation::SecurityElevation,
+                signature_verifier::SignatureVerifier,
             },
         },
-        packets::{AttAttributeDataChild, AttBuilder, AttChild},
-        utils::task::{block_on_locally, try_await},
+        packets::{
+            AttAttributeDataChild, AttBuilder, AttChild, AttHandleValueConfirmationBuilder,
+            GattClientCharacteristicConfigurationBuilder, Serializable,
+        },
+        utils::{
+            packet::build_att_view_or_crash,
+            task::{block_on_locally, try_await},
+        },
     };
 
+    // the CCCD following the Service Changed characteristic, managed by the GattDatabase
+    const SERVICE_CHANGE_CCC_DESCRIPTOR_HANDLE: AttHandle = AttHandle(4);
+
     const TCB_IDX: TransportIndex = TransportIndex(1);
     const ANOTHER_TCB_IDX: TransportIndex = TransportIndex(2);
+    const PEER: AddressWithType =
+        AddressWithType { address: [1, 2, 3, 4, 5, 6], ..AddressWithType::EMPTY };
     const SERVICE_TYPE: Uuid = Uuid::new(0x1234);
     const CHARACTERISTIC_TYPE: Uuid = Uuid::new(0x5678);
 
     fn init_gatt_db() -> SharedBox<GattDatabase> {
+        init_gatt_db_with_features(ServerSupportedFeatures::empty())
+    }
+
+    fn init_gatt_db_with_features(features: ServerSupportedFeatures) -> SharedBox<GattDatabase> {
         let mut gatt_database = GattDatabase::new();
-        register_gatt_service(&mut gatt_database).unwrap();
+        register_gatt_service(&mut gatt_database, features).unwrap();
         SharedBox::new(gatt_database)
     }
 
@@ -212,10 +347,16 @@ mod test {
     {
         let att_database = gatt_database.get_att_database(tcb_idx);
         let (tx, rx) = unbounded_channel();
-        let bearer = SharedBox::new(AttServerBearer::new(att_database.clone(), move |packet| {
-            tx.send(packet).unwrap();
-            Ok(())
-        }));
+        let bearer = SharedBox::new(AttServerBearer::new(
+            att_database.clone(),
+            SignatureVerifier::new(tcb_idx, Rc::new(MockSecurityManager::new())),
+            SecurityElevation::new(tcb_idx, Rc::new(MockSecurityManager::new())),
+            MAX_ATT_MTU,
+            move |packet| {
+                tx.send(packet).unwrap();
+                Ok(())
+            },
+        ));
         gatt_database.on_bearer_ready(tcb_idx, bearer.as_ref());
         (att_database, bearer, rx)
     }
@@ -229,22 +370,34 @@ mod test {
         // act: discover all services
         let attrs = att_db.list_attributes();
 
-        // assert: 1 service + 1 char decl + 1 char value + 1 char descriptor = 4 attrs
-        assert_eq!(attrs.len(), 4);
+        // assert: 1 service + 4 char decls + 4 char values + 1 char descriptor = 10 attrs
+        assert_eq!(attrs.len(), 10);
         // assert: value handles are correct
         assert_eq!(attrs[0].handle, GATT_SERVICE_HANDLE);
         assert_eq!(attrs[2].handle, SERVICE_CHANGE_HANDLE);
+        assert_eq!(attrs[5].handle, CLIENT_SUPPORTED_FEATURES_HANDLE);
+        assert_eq!(attrs[7].handle, DATABASE_HASH_HANDLE);
+        assert_eq!(attrs[9].handle, SERVER_SUPPORTED_FEATURES_HANDLE);
         // assert: types are correct
         assert_eq!(attrs[0].type_, PRIMARY_SERVICE_DECLARATION_UUID);
         assert_eq!(attrs[1].type_, CHARACTERISTIC_UUID);
         assert_eq!(attrs[2].type_, SERVICE_CHANGE_UUID);
         assert_eq!(attrs[3].type_, CLIENT_CHARACTERISTIC_CONFIGURATION_UUID);
+        assert_eq!(attrs[5].type_, CLIENT_SUPPORTED_FEATURES_UUID);
+        assert_eq!(attrs[7].type_, DATABASE_HASH_UUID);
+        assert_eq!(attrs[9].type_, SERVER_SUPPORTED_FEATURES_UUID);
         // assert: permissions of value attrs are correct
         assert_eq!(attrs[2].permissions, AttPermissions::INDICATE);
         assert_eq!(
             attrs[3].permissions,
             AttPermissions::READABLE | AttPermissions::WRITABLE_WITH_RESPONSE
         );
+        assert_eq!(
+            attrs[5].permissions,
+            AttPermissions::READABLE | AttPermissions::WRITABLE_WITH_RESPONSE
+        );
+        assert_eq!(attrs[7].permissions, AttPermissions::READABLE);
+        assert_eq!(attrs[9].permissions, AttPermissions::READABLE);
     }
 
     #[test]
@@ -258,19 +411,21 @@ mod test {
             block_on_locally(att_db.read_attribute(SERVICE_CHANGE_CCC_DESCRIPTOR_HANDLE)).unwrap();
 
         assert_eq!(
-            Ok(resp),
+            resp,
             GattClientCharacteristicConfigurationBuilder { notification: 0, indication: 0 }
                 .to_vec()
+                .unwrap()
         );
     }
 
     async fn register_for_indication(
         att_db: &impl AttDatabase,
         handle: AttHandle,
-    ) -> Result<(), AttErrorCode> {
+    ) -> Result<(), AttError> {
         att_db
             .write_attribute(
                 handle,
+                0,
                 &GattClientCharacteristicConfigurationBuilder { notification: 0, indication: 1 }
                     .to_vec()
                     .unwrap(),
@@ -293,9 +448,10 @@ mod test {
 
         // assert: we are registered for indications
         assert_eq!(
-            Ok(resp),
+            resp,
             GattClientCharacteristicConfigurationBuilder { notification: 0, indication: 1 }
                 .to_vec()
+                .unwrap()
         );
     }
 
@@ -309,6 +465,7 @@ mod test {
         block_on_locally(
             att_db.write_attribute(
                 SERVICE_CHANGE_CCC_DESCRIPTOR_HANDLE,
+                0,
                 &GattClientCharacteristicConfigurationBuilder { notification: 0, indication: 1 }
                     .to_vec()
                     .unwrap(),
@@ -319,6 +476,7 @@ mod test {
         block_on_locally(
             att_db.write_attribute(
                 SERVICE_CHANGE_CCC_DESCRIPTOR_HANDLE,
+                0,
                 &GattClientCharacteristicConfigurationBuilder { notification: 0, indication: 0 }
                     .to_vec()
                     .unwrap(),
@@ -331,9 +489,10 @@ mod test {
 
         // assert: we are not registered for indications
         assert_eq!(
-            Ok(resp),
+            resp,
             GattClientCharacteristicConfigurationBuilder { notification: 0, indication: 0 }
                 .to_vec()
+                .unwrap()
         );
     }
 
@@ -495,4 +654,317 @@ mod test {
             assert!(rx2.recv().await.is_none());
         });
     }
+
+    fn add_service_at(gatt_db: &SharedBox<GattDatabase>, handle: AttHandle) {
+        let (gatt_datastore, _) = MockDatastore::new();
+        gatt_db
+            .add_service_with_handles(
+                GattServiceWithHandle { handle, type_: SERVICE_TYPE, characteristics: vec![] },
+                Rc::new(gatt_datastore),
+            )
+            .unwrap();
+    }
+
+    fn disconnect(
+        gatt_db: &SharedBox<GattDatabase>,
+        tcb_idx: TransportIndex,
+        bearer: SharedBox<AttServerBearer<AttDatabaseImpl>>,
+    ) {
+        drop(bearer);
+        gatt_db.on_bearer_dropped(tcb_idx);
+    }
+
+    #[test]
+    fn test_bonded_client_indicated_of_changes_on_reconnect() {
+        block_on_locally(async {
+            // arrange: a bonded client registers, then disconnects
+            let gatt_db = init_gatt_db();
+            let (att_db, bearer, _) = add_connection(&gatt_db, TCB_IDX);
+            gatt_db.on_le_bonded(TCB_IDX, PEER);
+            register_for_indication(&att_db, SERVICE_CHANGE_CCC_DESCRIPTOR_HANDLE).await.unwrap();
+            disconnect(&gatt_db, TCB_IDX, bearer);
+
+            // act: add two services while it is disconnected, then reconnect
+            add_service_at(&gatt_db, AttHandle(30));
+            add_service_at(&gatt_db, AttHandle(20));
+            let (_, _bearer, mut rx) = add_connection(&gatt_db, ANOTHER_TCB_IDX);
+            gatt_db.on_le_bonded(ANOTHER_TCB_IDX, PEER);
+
+            // assert: we received a single indication covering both changes
+            let resp = rx.recv().await.unwrap();
+            let AttChild::AttHandleValueIndication(resp) = resp._child_ else {
+                unreachable!();
+            };
+            let AttAttributeDataChild::GattServiceChanged(resp) = resp.value._child_ else {
+                unreachable!();
+            };
+            assert_eq!(resp.start_handle.handle, 20);
+            assert_eq!(resp.end_handle.handle, 30);
+        });
+    }
+
+    #[test]
+    fn test_bonded_client_not_indicated_without_changes() {
+        block_on_locally(async {
+            // arrange: a bonded client registers, then disconnects
+            let gatt_db = init_gatt_db();
+            let (att_db, bearer, _) = add_connection(&gatt_db, TCB_IDX);
+            gatt_db.on_le_bonded(TCB_IDX, PEER);
+            register_for_indication(&att_db, SERVICE_CHANGE_CCC_DESCRIPTOR_HANDLE).await.unwrap();
+            disconnect(&gatt_db, TCB_IDX, bearer);
+
+            // act: reconnect without any changes
+            let (_, _bearer, mut rx) = add_connection(&gatt_db, TCB_IDX);
+            gatt_db.on_le_bonded(TCB_IDX, PEER);
+
+            // assert: nothing was sent
+            assert!(try_await(async move { rx.recv().await }).await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_unbonded_client_not_indicated_on_reconnect() {
+        block_on_locally(async {
+            // arrange: an unbonded client registers, then disconnects
+            let gatt_db = init_gatt_db();
+            let (att_db, bearer, _) = add_connection(&gatt_db, TCB_IDX);
+            register_for_indication(&att_db, SERVICE_CHANGE_CCC_DESCRIPTOR_HANDLE).await.unwrap();
+            disconnect(&gatt_db, TCB_IDX, bearer);
+
+            // act: add a service while it is disconnected, then reconnect
+            add_service_at(&gatt_db, AttHandle(30));
+            let (_, _bearer, mut rx) = add_connection(&gatt_db, TCB_IDX);
+
+            // assert: nothing was sent, since it is no longer registered
+            assert!(try_await(async move { rx.recv().await }).await.is_err());
+        });
+    }
+
+    /// Open a database persisting its handle assignments to the given storage
+    /// (as after a restart), with a service of the given number of
+    /// characteristics
+    fn restart_with_service(
+        storage: &Rc<MockHandleAssignmentStorage>,
+        characteristic_count: usize,
+    ) -> SharedBox<GattDatabase> {
+        let gatt_db = init_gatt_db();
+        gatt_db.set_handle_assignment_storage(storage.clone()).unwrap();
+        let service =
+            (0..characteristic_count).fold(ServiceBuilder::new(SERVICE_TYPE), |service, _| {
+                service.characteristic(CharacteristicBuilder::new(
+                    CHARACTERISTIC_TYPE,
+                    AttPermissions::READABLE,
+                ))
+            });
+        let (gatt_datastore, _) = MockDatastore::new();
+        gatt_db.add_service(service, Rc::new(gatt_datastore)).unwrap();
+        gatt_db
+    }
+
+    #[test]
+    fn test_bonded_client_indicated_of_moved_service_after_restart() {
+        block_on_locally(async {
+            // arrange: after a restart, a service grew, so it could not keep its handles
+            let storage = Rc::new(MockHandleAssignmentStorage::new());
+            restart_with_service(&storage, 1);
+            let gatt_db = restart_with_service(&storage, 2);
+
+            // act: a bonded client (whose subscription was restored) connects
+            let (att_db, _bearer, mut rx) = add_connection(&gatt_db, TCB_IDX);
+            register_for_indication(&att_db, SERVICE_CHANGE_CCC_DESCRIPTOR_HANDLE).await.unwrap();
+            gatt_db.on_le_bonded(TCB_IDX, PEER);
+
+            // assert: it was told of the handles of the service, old and new
+            let resp = rx.recv().await.unwrap();
+            let AttChild::AttHandleValueIndication(resp) = resp._child_ else {
+                unreachable!();
+            };
+            let AttAttributeDataChild::GattServiceChanged(resp) = resp.value._child_ else {
+                unreachable!();
+            };
+            assert_eq!(resp.start_handle.handle, 11);
+            assert_eq!(resp.end_handle.handle, 15);
+        });
+    }
+
+    #[test]
+    fn test_bonded_client_not_indicated_of_unchanged_services_after_restart() {
+        block_on_locally(async {
+            // arrange: after a restart, a service kept its handles
+            let storage = Rc::new(MockHandleAssignmentStorage::new());
+            restart_with_service(&storage, 1);
+            let gatt_db = restart_with_service(&storage, 1);
+
+            // act: a bonded client connects
+            let (att_db, _bearer, mut rx) = add_connection(&gatt_db, TCB_IDX);
+            register_for_indication(&att_db, SERVICE_CHANGE_CCC_DESCRIPTOR_HANDLE).await.unwrap();
+            gatt_db.on_le_bonded(TCB_IDX, PEER);
+
+            // assert: nothing was sent
+            assert!(try_await(async move { rx.recv().await }).await.is_err());
+        });
+    }
+
+    async fn enable_robust_caching(att_db: &impl AttDatabase) {
+        att_db
+            .write_attribute(
+                CLIENT_SUPPORTED_FEATURES_HANDLE,
+                0,
+                &[ClientSupportedFeatures::ROBUST_CACHING.bits()],
+            )
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_read_database_hash() {
+        // arrange
+        let gatt_db = init_gatt_db();
+        let (att_db, _, _) = add_connection(&gatt_db, TCB_IDX);
+
+        // act
+        let resp = block_on_locally(att_db.read_attribute(DATABASE_HASH_HANDLE)).unwrap();
+
+        // assert: we read the hash computed by the database
+        assert_eq!(*resp, gatt_db.robust_caching().borrow().database_hash());
+    }
+
+    #[test]
+    fn test_server_supported_features() {
+        // arrange
+        let gatt_db = init_gatt_db_with_features(ServerSupportedFeatures::EATT_SUPPORTED);
+        let (att_db, _, _) = add_connection(&gatt_db, TCB_IDX);
+
+        // act
+        let resp = block_on_locally(att_db.read_attribute(SERVER_SUPPORTED_FEATURES_HANDLE));
+
+        // assert
+        assert_eq!(resp, Ok(vec![ServerSupportedFeatures::EATT_SUPPORTED.bits()].into()));
+    }
+
+    #[test]
+    fn test_no_server_supported_features() {
+        // arrange
+        let gatt_db = init_gatt_db();
+        let (att_db, _, _) = add_connection(&gatt_db, TCB_IDX);
+
+        // act
+        let resp = block_on_locally(att_db.read_attribute(SERVER_SUPPORTED_FEATURES_HANDLE));
+
+        // assert
+        assert_eq!(resp, Ok(vec![0].into()));
+    }
+
+    #[test]
+    fn test_client_supported_features() {
+        // arrange
+        let gatt_db = init_gatt_db();
+        let (att_db, _, _) = add_connection(&gatt_db, TCB_IDX);
+        let (another_att_db, _, _) = add_connection(&gatt_db, ANOTHER_TCB_IDX);
+
+        // act: enable robust caching on one connection
+        block_on_locally(enable_robust_caching(&att_db));
+
+        // assert: only that connection has it enabled
+        assert_eq!(
+            block_on_locally(att_db.read_attribute(CLIENT_SUPPORTED_FEATURES_HANDLE)),
+            Ok(vec![ClientSupportedFeatures::ROBUST_CACHING.bits()].into())
+        );
+        assert_eq!(
+            block_on_locally(another_att_db.read_attribute(CLIENT_SUPPORTED_FEATURES_HANDLE)),
+            Ok(vec![0].into())
+        );
+    }
+
+    #[test]
+    fn test_client_supported_features_cannot_be_disabled() {
+        // arrange
+        let gatt_db = init_gatt_db();
+        let (att_db, _, _) = add_connection(&gatt_db, TCB_IDX);
+        block_on_locally(enable_robust_caching(&att_db));
+
+        // act
+        let res =
+            block_on_locally(att_db.write_attribute(CLIENT_SUPPORTED_FEATURES_HANDLE, 0, &[0]));
+
+        // assert
+        assert_eq!(res, Err(AttErrorCode::VALUE_NOT_ALLOWED.into()));
+    }
+
+    #[test]
+    fn test_database_hash_changed_on_service_change() {
+        // arrange
+        let gatt_db = init_gatt_db();
+        let (att_db, _, _) = add_connection(&gatt_db, TCB_IDX);
+        let hash = block_on_locally(att_db.read_attribute(DATABASE_HASH_HANDLE)).unwrap();
+
+        // act
+        add_service_at(&gatt_db, AttHandle(30));
+
+        // assert
+        let new_hash = block_on_locally(att_db.read_attribute(DATABASE_HASH_HANDLE)).unwrap();
+        assert_ne!(hash, new_hash);
+    }
+
+    #[test]
+    fn test_robust_caching_client_unaware_until_hash_read() {
+        block_on_locally(async {
+            // arrange: a client enables robust caching
+            let gatt_db = init_gatt_db();
+            let (att_db, _bearer, _) = add_connection(&gatt_db, TCB_IDX);
+            enable_robust_caching(&att_db).await;
+
+            // act: the database changes
+            add_service_at(&gatt_db, AttHandle(30));
+
+            // assert: the client is change-unaware until it reads the hash
+            assert!(!att_db.is_change_aware());
+            att_db.read_attribute(DATABASE_HASH_HANDLE).await.unwrap();
+            assert!(att_db.is_change_aware());
+        });
+    }
+
+    #[test]
+    fn test_robust_caching_client_aware_after_confirming_service_change() {
+        block_on_locally(async {
+            // arrange: a client enables robust caching and subscribes to service changes
+            let gatt_db = init_gatt_db();
+            let (att_db, bearer, mut rx) = add_connection(&gatt_db, TCB_IDX);
+            enable_robust_caching(&att_db).await;
+            register_for_indication(&att_db, SERVICE_CHANGE_CCC_DESCRIPTOR_HANDLE).await.unwrap();
+
+            // act: the database changes, and the client confirms the indication
+            add_service_at(&gatt_db, AttHandle(30));
+            rx.recv().await.unwrap();
+            assert!(!att_db.is_change_aware());
+            bearer.as_ref().handle_packet(
+                build_att_view_or_crash(AttHandleValueConfirmationBuilder {}).view(),
+            );
+            // let the indication task observe the confirmation
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+
+            // assert: the client is now change-aware
+            assert!(att_db.is_change_aware());
+        });
+    }
+
+    #[test]
+    fn test_bonded_robust_caching_client_unaware_on_reconnect() {
+        block_on_locally(async {
+            // arrange: a bonded client enables robust caching, then disconnects
+            let gatt_db = init_gatt_db();
+            let (att_db, bearer, _) = add_connection(&gatt_db, TCB_IDX);
+            gatt_db.on_le_bonded(TCB_IDX, PEER);
+            enable_robust_caching(&att_db).await;
+            disconnect(&gatt_db, TCB_IDX, bearer);
+
+            // act: the database changes while it is disconnected, then it reconnects
+            add_service_at(&gatt_db, AttHandle(30));
+            let (att_db, _bearer, _) = add_connection(&gatt_db, TCB_IDX);
+            gatt_db.on_le_bonded(TCB_IDX, PEER);
+
+            // assert: it is change-unaware
+            assert!(!att_db.is_change_aware());
+        });
+    }
 }
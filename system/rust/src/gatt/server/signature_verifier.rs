@@ -0,0 +1,204 @@
+//! This module verifies the Authentication Signature of signed ATT PDUs, as
+//! per Core Spec 5.3 Vol 3C 10.4.2 and Vol 3H 2.4.5.
+
+use std::rc::Rc;
+
+use crate::{
+    gatt::{ids::TransportIndex, security_manager::SecurityManager},
+    utils::aes_cmac::aes_cmac,
+};
+
+/// The length of the Authentication Signature (the sign counter followed by
+/// the MAC) at the end of a signed PDU
+pub const SIGNATURE_LEN: usize = 12;
+
+/// The length of the MAC at the end of the Authentication Signature
+const MAC_LEN: usize = 8;
+
+/// The reasons a signed PDU may be rejected
+#[derive(Debug, PartialEq, Eq)]
+pub enum SignatureError {
+    /// The PDU is too short to contain an Authentication Signature
+    MissingSignature,
+    /// The peer has not distributed a CSRK
+    NoSigningKey,
+    /// The sign counter has already been used (i.e. the PDU may be a replay)
+    StaleSignCounter {
+        /// The counter in the PDU
+        received: u32,
+        /// The lowest counter that would have been accepted
+        expected: u32,
+    },
+    /// The MAC does not match the contents of the PDU
+    InvalidMac,
+}
+
+/// Verifies the signed PDUs received on a single transport
+pub struct SignatureVerifier {
+    tcb_idx: TransportIndex,
+    security_manager: Rc<dyn SecurityManager>,
+}
+
+impl SignatureVerifier {
+    /// Constructor
+    pub fn new(tcb_idx: TransportIndex, security_manager: Rc<dyn SecurityManager>) -> Self {
+        Self { tcb_idx, security_manager }
+    }
+
+    /// Verify a signed PDU (from the opcode up to and including the
+    /// Authentication Signature). If it is valid, its sign counter is consumed
+    /// so that the PDU cannot be replayed.
+    pub fn verify(&self, pdu: &[u8]) -> Result<(), SignatureError> {
+        if pdu.len() < SIGNATURE_LEN + 1 {
+            return Err(SignatureError::MissingSignature);
+        }
+        let key = self
+            .security_manager
+            .get_peer_signing_key(self.tcb_idx)
+            .ok_or(SignatureError::NoSigningKey)?;
+
+        let (signed_data, mac) = pdu.split_at(pdu.len() - MAC_LEN);
+        let counter = u32::from_le_bytes(
+            signed_data[signed_data.len() - 4..].try_into().expect("length was checked above"),
+        );
+        if counter < key.sign_counter {
+            return Err(SignatureError::StaleSignCounter {
+                received: counter,
+                expected: key.sign_counter,
+            });
+        }
+
+        // The key, message, and MAC are all little-endian, while AES-CMAC is
+        // specified most-significant octet first. The MAC is the
+        // most-significant 64 bits of the CMAC output.
+        let mut csrk = key.csrk;
+        csrk.reverse();
+        let message = signed_data.iter().rev().copied().collect::<Vec<_>>();
+        let mut expected_mac: [u8; MAC_LEN] =
+            aes_cmac(&csrk, &message)[..MAC_LEN].try_into().expect("the CMAC is 16 octets long");
+        expected_mac.reverse();
+        if !constant_time_eq(&expected_mac, mac) {
+            return Err(SignatureError::InvalidMac);
+        }
+
+        self.security_manager.set_peer_sign_counter(self.tcb_idx, counter.saturating_add(1));
+        Ok(())
+    }
+}
+
+/// Compare two MACs in a time independent of their contents, so that a peer
+/// timing the rejections of its forgeries cannot tell how many of their
+/// octets were correct
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let difference = a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b));
+    // keep the compiler from turning the fold back into an early exit
+    std::hint::black_box(difference) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use crate::gatt::{
+        mocks::mock_security_manager::MockSecurityManager, security_manager::PeerSigningKey,
+    };
+
+    use super::*;
+
+    const TCB_IDX: TransportIndex = TransportIndex(1);
+    const ANOTHER_TCB_IDX: TransportIndex = TransportIndex(2);
+    const CSRK: [u8; 16] = [
+        0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e,
+        0x1f,
+    ];
+    // a Signed Write Command to handle 3 with value [1, 2, 3] and sign counter 5
+    const SIGNED_PDU: [u8; 18] = [
+        0xd2, 0x03, 0x00, 0x01, 0x02, 0x03, 0x05, 0x00, 0x00, 0x00, 0xac, 0x2a, 0x98, 0xd6, 0xa8,
+        0x7f, 0xad, 0xd1,
+    ];
+
+    fn make_verifier(sign_counter: u32) -> (SignatureVerifier, Rc<MockSecurityManager>) {
+        let security_manager = Rc::new(MockSecurityManager::new());
+        security_manager.set_peer_signing_key(TCB_IDX, PeerSigningKey { csrk: CSRK, sign_counter });
+        (SignatureVerifier::new(TCB_IDX, security_manager.clone()), security_manager)
+    }
+
+    #[test]
+    fn test_valid_signature() {
+        let (verifier, security_manager) = make_verifier(0);
+
+        let res = verifier.verify(&SIGNED_PDU);
+
+        assert_eq!(res, Ok(()));
+        assert_eq!(security_manager.get_peer_signing_key(TCB_IDX).unwrap().sign_counter, 6);
+    }
+
+    #[test]
+    fn test_signature_at_expected_counter() {
+        let (verifier, _) = make_verifier(5);
+
+        assert_eq!(verifier.verify(&SIGNED_PDU), Ok(()));
+    }
+
+    #[test]
+    fn test_replayed_signature() {
+        // arrange: the PDU is accepted once
+        let (verifier, _) = make_verifier(0);
+        verifier.verify(&SIGNED_PDU).unwrap();
+
+        // act: it is received again
+        let res = verifier.verify(&SIGNED_PDU);
+
+        // assert: it is rejected
+        assert_eq!(res, Err(SignatureError::StaleSignCounter { received: 5, expected: 6 }));
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(&[1, 2, 3], &[1, 2, 3]));
+        assert!(!constant_time_eq(&[1, 2, 3], &[0, 2, 3]));
+        assert!(!constant_time_eq(&[1, 2, 3], &[1, 2, 4]));
+        assert!(!constant_time_eq(&[1, 2, 3], &[1, 2]));
+    }
+
+    #[test]
+    fn test_invalid_mac() {
+        let (verifier, security_manager) = make_verifier(0);
+        let mut pdu = SIGNED_PDU;
+        pdu[4] ^= 1;
+
+        let res = verifier.verify(&pdu);
+
+        assert_eq!(res, Err(SignatureError::InvalidMac));
+        // the sign counter is only consumed by valid PDUs
+        assert_eq!(security_manager.get_peer_signing_key(TCB_IDX).unwrap().sign_counter, 0);
+    }
+
+    #[test]
+    fn test_wrong_key() {
+        let (verifier, security_manager) = make_verifier(0);
+        security_manager
+            .set_peer_signing_key(TCB_IDX, PeerSigningKey { csrk: [0; 16], sign_counter: 0 });
+
+        assert_eq!(verifier.verify(&SIGNED_PDU), Err(SignatureError::InvalidMac));
+    }
+
+    #[test]
+    fn test_no_signing_key() {
+        let (_, security_manager) = make_verifier(0);
+        let verifier = SignatureVerifier::new(ANOTHER_TCB_IDX, security_manager);
+
+        assert_eq!(verifier.verify(&SIGNED_PDU), Err(SignatureError::NoSigningKey));
+    }
+
+    #[test]
+    fn test_missing_signature() {
+        let (verifier, _) = make_verifier(0);
+
+        assert_eq!(
+            verifier.verify(&SIGNED_PDU[..SIGNATURE_LEN]),
+            Err(SignatureError::MissingSignature)
+        );
+    }
+}
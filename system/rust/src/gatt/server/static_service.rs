@@ -0,0 +1,367 @@
+//! This module serves services whose characteristics all hold constant values
+//! (e.g. DIS- or GAP-style services), without the GattDatabase and the
+//! datastore it would consult. A StaticService owns its attributes outright,
+//! so every access completes without yielding, and cannot change once it is
+//! built. It is meant to be added as a backend of a CompositeAttDatabase.
+
+use std::{collections::BTreeMap, ops::RangeInclusive, rc::Rc};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use log::warn;
+
+use crate::{
+    core::uuid::Uuid,
+    gatt::ids::AttHandle,
+    packets::{
+        AttErrorCode, GattCharacteristicDeclarationValueBuilder,
+        GattCharacteristicPropertiesBuilder, GattServiceDeclarationValueBuilder, Serializable,
+        UuidBuilder,
+    },
+};
+
+use super::{
+    att_database::{
+        AttAttribute, AttAttributeValue, AttDatabase, AttPermissions, StableAttDatabase,
+        MAX_ATTRIBUTE_VALUE_LEN,
+    },
+    att_error::AttError,
+    composite_att_database::CompositeAttDatabase,
+    gatt_database::{
+        CHARACTERISTIC_UUID, PRIMARY_SERVICE_DECLARATION_UUID, SECONDARY_SERVICE_DECLARATION_UUID,
+    },
+};
+
+/// Describes a primary service whose characteristics are all readable, and
+/// hold the given values
+#[derive(Debug, Clone)]
+pub struct StaticServiceBuilder {
+    type_: Uuid,
+    characteristics: Vec<(Uuid, Vec<u8>)>,
+}
+
+impl StaticServiceBuilder {
+    /// Constructor, for a primary service of the given type
+    pub fn new(type_: Uuid) -> Self {
+        Self { type_, characteristics: vec![] }
+    }
+
+    /// Add a characteristic holding the given value, following those already
+    /// added
+    pub fn characteristic(mut self, type_: Uuid, value: Vec<u8>) -> Self {
+        self.characteristics.push((type_, value));
+        self
+    }
+
+    /// The number of handles occupied by the service: its declaration, then a
+    /// declaration and a value for each characteristic
+    pub fn handle_count(&self) -> usize {
+        1 + 2 * self.characteristics.len()
+    }
+
+    /// Assign consecutive handles to the attributes of the service, starting
+    /// with its declaration at the given handle
+    pub fn build(self, handle: AttHandle) -> Result<StaticService> {
+        if handle == AttHandle::RESERVED {
+            bail!("a service cannot start at {handle:?}");
+        }
+        let last = u32::from(handle.0) + self.handle_count() as u32 - 1;
+        if last > u32::from(AttHandle::MAX.0) {
+            bail!(
+                "the {} handles of {:?} do not fit after {handle:?}",
+                self.handle_count(),
+                self.type_
+            );
+        }
+
+        let mut attributes = BTreeMap::new();
+        let mut add_attribute = |handle: AttHandle, type_: Uuid, value: Vec<u8>| {
+            attributes.insert(
+                handle,
+                (
+                    AttAttribute { handle, type_, permissions: AttPermissions::READABLE },
+                    AttAttributeValue::from(value),
+                ),
+            );
+        };
+        add_attribute(
+            handle,
+            PRIMARY_SERVICE_DECLARATION_UUID,
+            GattServiceDeclarationValueBuilder { uuid: UuidBuilder::from(self.type_) }
+                .to_vec()
+                .map_err(|e| anyhow!("failed to encode primary service declaration: {e:?}"))?,
+        );
+        for (i, (type_, value)) in self.characteristics.into_iter().enumerate() {
+            if [
+                PRIMARY_SERVICE_DECLARATION_UUID,
+                SECONDARY_SERVICE_DECLARATION_UUID,
+                CHARACTERISTIC_UUID,
+            ]
+            .contains(&type_)
+            {
+                bail!("{type_:?} is reserved for declarations");
+            }
+            if value.len() > MAX_ATTRIBUTE_VALUE_LEN {
+                bail!("the value of {type_:?} exceeds {MAX_ATTRIBUTE_VALUE_LEN} bytes");
+            }
+            let declaration_handle = AttHandle(handle.0 + 1 + 2 * i as u16);
+            let value_handle = AttHandle(declaration_handle.0 + 1);
+            add_attribute(
+                declaration_handle,
+                CHARACTERISTIC_UUID,
+                GattCharacteristicDeclarationValueBuilder {
+                    properties: GattCharacteristicPropertiesBuilder {
+                        broadcast: 0,
+                        read: 1,
+                        write_without_response: 0,
+                        write: 0,
+                        notify: 0,
+                        indicate: 0,
+                        authenticated_signed_writes: 0,
+                        extended_properties: 0,
+                    },
+                    handle: value_handle.into(),
+                    uuid: type_.into(),
+                }
+                .to_vec()
+                .map_err(|e| anyhow!("failed to encode characteristic declaration: {e:?}"))?,
+            );
+            add_attribute(value_handle, type_, value);
+        }
+        Ok(StaticService { attributes: Rc::new(attributes) })
+    }
+
+    /// Reserve a free range of handles in the given database, and add the
+    /// service there as a backend. Returns the service, which can also be
+    /// added at the same handles to the databases of other connections.
+    pub fn add_to(self, db: &mut CompositeAttDatabase) -> Result<StaticService> {
+        let count = u16::try_from(self.handle_count())
+            .map_err(|_| anyhow!("{:?} has too many characteristics", self.type_))?;
+        let Some(range) = db.allocate_range(count) else {
+            bail!("no room for the {count} handles of {:?}", self.type_);
+        };
+        let service = self.build(*range.start())?;
+        db.add_backend(range, Rc::new(service.clone()))?;
+        Ok(service)
+    }
+}
+
+/// A service built by a StaticServiceBuilder. Clones share the same
+/// attributes, so a single service can back the databases of every
+/// connection.
+#[derive(Debug, Clone)]
+pub struct StaticService {
+    attributes: Rc<BTreeMap<AttHandle, (AttAttribute, AttAttributeValue)>>,
+}
+
+impl StaticService {
+    /// The handles occupied by the service, from its declaration to the value
+    /// of its last characteristic
+    pub fn handles(&self) -> RangeInclusive<AttHandle> {
+        // a service always has a declaration
+        let first = *self.attributes.keys().next().unwrap();
+        let last = *self.attributes.keys().next_back().unwrap();
+        first..=last
+    }
+
+    /// The value of the attribute at the given handle. Unlike
+    /// AttDatabase::read_attribute(), this is synchronous.
+    pub fn value(&self, handle: AttHandle) -> Result<AttAttributeValue, AttError> {
+        self.attributes
+            .get(&handle)
+            .map(|(_, value)| value.clone())
+            .ok_or_else(|| AttError::from(AttErrorCode::INVALID_HANDLE).for_handle(handle))
+    }
+}
+
+#[async_trait(?Send)]
+impl AttDatabase for StaticService {
+    async fn read_attribute(&self, handle: AttHandle) -> Result<AttAttributeValue, AttError> {
+        self.value(handle)
+    }
+
+    async fn write_attribute(
+        &self,
+        handle: AttHandle,
+        offset: u32,
+        data: &[u8],
+    ) -> Result<(), AttError> {
+        self.check_write(handle, offset, data)
+    }
+
+    fn check_write(&self, handle: AttHandle, _offset: u32, _data: &[u8]) -> Result<(), AttError> {
+        let code = if self.attributes.contains_key(&handle) {
+            AttErrorCode::WRITE_NOT_PERMITTED
+        } else {
+            AttErrorCode::INVALID_HANDLE
+        };
+        Err(AttError::from(code).for_handle(handle))
+    }
+
+    fn write_no_response_attribute(&self, handle: AttHandle, _data: &[u8]) {
+        warn!("dropping write command to static attribute {handle:?}");
+    }
+
+    fn list_attributes(&self) -> Vec<AttAttribute> {
+        self.attributes.values().map(|(attribute, _)| *attribute).collect()
+    }
+
+    fn attributes_in_range(
+        &self,
+        start: AttHandle,
+        end: AttHandle,
+        type_filter: Option<Uuid>,
+    ) -> Vec<AttAttribute> {
+        if start > end {
+            return vec![];
+        }
+        self.attributes
+            .range(start..=end)
+            .map(|(_, (attribute, _))| *attribute)
+            .filter(|attr| type_filter.map(|type_| attr.type_ == type_).unwrap_or(true))
+            .collect()
+    }
+}
+
+impl StableAttDatabase for StaticService {}
+
+#[cfg(test)]
+mod test {
+    use crate::gatt::server::test::test_att_db::TestAttDatabase;
+
+    use super::*;
+
+    const SERVICE_TYPE: Uuid = Uuid::new(0x180A);
+    const FIRST_CHARACTERISTIC_TYPE: Uuid = Uuid::new(0x2A29);
+    const SECOND_CHARACTERISTIC_TYPE: Uuid = Uuid::new(0x2A24);
+
+    fn make_builder() -> StaticServiceBuilder {
+        StaticServiceBuilder::new(SERVICE_TYPE)
+            .characteristic(FIRST_CHARACTERISTIC_TYPE, b"Manufacturer".to_vec())
+            .characteristic(SECOND_CHARACTERISTIC_TYPE, b"Model".to_vec())
+    }
+
+    #[test]
+    fn test_handles_assigned_consecutively() {
+        // arrange
+        let service = make_builder().build(AttHandle(5)).unwrap();
+
+        // act
+        let attributes = service.list_attributes();
+
+        // assert
+        assert_eq!(service.handles(), AttHandle(5)..=AttHandle(9));
+        assert_eq!(
+            attributes.iter().map(|attr| (attr.handle.0, attr.type_)).collect::<Vec<_>>(),
+            vec![
+                (5, PRIMARY_SERVICE_DECLARATION_UUID),
+                (6, CHARACTERISTIC_UUID),
+                (7, FIRST_CHARACTERISTIC_TYPE),
+                (8, CHARACTERISTIC_UUID),
+                (9, SECOND_CHARACTERISTIC_TYPE),
+            ]
+        );
+        assert!(attributes.iter().all(|attr| attr.permissions == AttPermissions::READABLE));
+    }
+
+    #[test]
+    fn test_declarations_encoded() {
+        let service = make_builder().build(AttHandle(5)).unwrap();
+
+        // the service type, then the read property, value handle and type
+        assert_eq!(&*service.value(AttHandle(5)).unwrap(), [0x0A, 0x18]);
+        assert_eq!(&*service.value(AttHandle(8)).unwrap(), [0x02, 0x09, 0x00, 0x24, 0x2A]);
+    }
+
+    #[test]
+    fn test_read_value() {
+        let service = make_builder().build(AttHandle(5)).unwrap();
+
+        assert_eq!(
+            tokio_test::block_on(service.read_attribute(AttHandle(7))),
+            Ok(b"Manufacturer".to_vec().into())
+        );
+        assert_eq!(
+            tokio_test::block_on(service.read_attribute_at(AttHandle(9), 2)),
+            Ok(b"del".to_vec().into())
+        );
+        assert_eq!(
+            tokio_test::block_on(service.read_attribute(AttHandle(10))),
+            Err(AttErrorCode::INVALID_HANDLE.into())
+        );
+    }
+
+    #[test]
+    fn test_write_rejected() {
+        let service = make_builder().build(AttHandle(5)).unwrap();
+
+        let res = tokio_test::block_on(service.write_attribute(AttHandle(7), 0, &[1]));
+
+        assert_eq!(res, Err(AttErrorCode::WRITE_NOT_PERMITTED.into()));
+        assert_eq!(&*service.value(AttHandle(7)).unwrap(), b"Manufacturer");
+    }
+
+    #[test]
+    fn test_attributes_in_range_with_type_filter() {
+        let service = make_builder().build(AttHandle(5)).unwrap();
+
+        let declarations =
+            service.attributes_in_range(AttHandle(6), AttHandle(0xFFFF), Some(CHARACTERISTIC_UUID));
+
+        assert_eq!(declarations.iter().map(|attr| attr.handle.0).collect::<Vec<_>>(), vec![6, 8]);
+    }
+
+    #[test]
+    fn test_added_to_composite_database() {
+        // arrange: a database whose first handles are already taken
+        let mut db = CompositeAttDatabase::new();
+        let other = db.allocate_range(4).unwrap();
+        db.add_backend(
+            other,
+            Rc::new(TestAttDatabase::new(vec![(
+                AttAttribute {
+                    handle: AttHandle(1),
+                    type_: Uuid::new(0x1234),
+                    permissions: AttPermissions::READABLE,
+                },
+                vec![1],
+            )])),
+        )
+        .unwrap();
+
+        // act
+        let service = make_builder().add_to(&mut db).unwrap();
+
+        // assert: the service went after the other backend, and is served
+        assert_eq!(service.handles(), AttHandle(5)..=AttHandle(9));
+        assert_eq!(db.list_attributes().len(), 6);
+        assert_eq!(
+            tokio_test::block_on(db.read_attribute(AttHandle(9))),
+            Ok(b"Model".to_vec().into())
+        );
+    }
+
+    #[test]
+    fn test_service_past_last_handle_rejected() {
+        assert!(make_builder().build(AttHandle(0xFFFC)).is_err());
+        assert!(make_builder().build(AttHandle(0xFFFB)).is_ok());
+    }
+
+    #[test]
+    fn test_reserved_type_rejected() {
+        let res = StaticServiceBuilder::new(SERVICE_TYPE)
+            .characteristic(CHARACTERISTIC_UUID, vec![1])
+            .build(AttHandle(1));
+
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_value_too_long_rejected() {
+        let res = StaticServiceBuilder::new(SERVICE_TYPE)
+            .characteristic(FIRST_CHARACTERISTIC_TYPE, vec![0; MAX_ATTRIBUTE_VALUE_LEN + 1])
+            .build(AttHandle(1));
+
+        assert!(res.is_err());
+    }
+}
@@ -1 +1,5 @@
+// the harnesses built outside of tests only use part of the test database
+#[cfg_attr(not(test), allow(dead_code))]
 pub mod test_att_db;
+#[cfg(test)]
+pub mod test_bearer;
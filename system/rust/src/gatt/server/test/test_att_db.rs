@@ -1,24 +1,67 @@
 use crate::{
+    core::uuid::Uuid,
     gatt::{
         ids::AttHandle,
-        server::att_database::{AttAttribute, AttDatabase, StableAttDatabase},
+        server::{
+            att_database::{
+                AttAttribute, AttAttributeValue, AttDatabase, StableAttDatabase,
+                MAX_ATTRIBUTE_VALUE_LEN,
+            },
+            att_error::AttError,
+            config::GattServerConfig,
+            robust_caching::ClientSupportedFeatures,
+        },
     },
     packets::AttErrorCode,
 };
 
 use async_trait::async_trait;
 use log::{info, warn};
-use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, HashMap, VecDeque},
+    rc::Rc,
+    time::Duration,
+};
 
 #[derive(Clone, Debug)]
 pub struct TestAttDatabase {
     attributes: Rc<BTreeMap<AttHandle, TestAttributeWithData>>,
+    change_aware: Rc<Cell<bool>>,
+    config: Rc<Cell<GattServerConfig>>,
+    client_supported_features: Rc<Cell<ClientSupportedFeatures>>,
+    scripts: Rc<RefCell<HashMap<AttHandle, ReadScript>>>,
+    coalesced: Rc<RefCell<Vec<AttHandle>>>,
+    write_failures: Rc<RefCell<HashMap<AttHandle, AttErrorCode>>>,
+    list_calls: Rc<Cell<usize>>,
+}
+
+/// How the reads of an attribute behave, beyond returning its value
+#[derive(Debug, Default)]
+struct ReadScript {
+    /// The number of reads that succeeded so far
+    reads: usize,
+    /// Fail the reads made after this many succeeded, with the given error
+    fail_after: Option<(usize, AttErrorCode)>,
+    /// Resolve each read only after this (virtual) time
+    delay: Option<Duration>,
+    /// The values the attribute successively takes after each read
+    next_values: VecDeque<Vec<u8>>,
 }
 
 #[derive(Debug)]
 struct TestAttributeWithData {
     attribute: AttAttribute,
     data: RefCell<Vec<u8>>,
+    /// Bumped whenever the data changes
+    version: Cell<u64>,
+}
+
+impl TestAttributeWithData {
+    fn replace_data(&self, data: Vec<u8>) {
+        self.data.replace(data);
+        self.version.set(self.version.get() + 1);
+    }
 }
 
 impl TestAttDatabase {
@@ -28,49 +71,168 @@ impl TestAttDatabase {
                 attributes
                     .into_iter()
                     .map(|(attribute, data)| {
-                        (attribute.handle, TestAttributeWithData { attribute, data: data.into() })
+                        (
+                            attribute.handle,
+                            TestAttributeWithData {
+                                attribute,
+                                data: data.into(),
+                                version: Cell::new(0),
+                            },
+                        )
                     })
                     .collect(),
             ),
+            change_aware: Rc::new(Cell::new(true)),
+            config: Rc::new(Cell::new(GattServerConfig::default())),
+            client_supported_features: Rc::new(Cell::new(ClientSupportedFeatures::empty())),
+            scripts: Rc::default(),
+            coalesced: Rc::default(),
+            write_failures: Rc::default(),
+            list_calls: Rc::default(),
+        }
+    }
+
+    /// Set whether the client is change-aware (see AttDatabase::is_change_aware())
+    pub fn set_change_aware(&self, change_aware: bool) {
+        self.change_aware.set(change_aware);
+    }
+
+    /// Set the configuration reported by AttDatabase::server_config()
+    pub fn set_config(&self, config: GattServerConfig) {
+        self.config.set(config);
+    }
+
+    /// Set the features reported by AttDatabase::client_supported_features()
+    pub fn set_client_supported_features(&self, features: ClientSupportedFeatures) {
+        self.client_supported_features.set(features);
+    }
+
+    /// Coalesce the notifications of the given attribute (see
+    /// AttDatabase::coalesces_notifications())
+    pub fn coalesce_notifications(&self, handle: AttHandle) {
+        self.coalesced.borrow_mut().push(handle);
+    }
+
+    /// Fail every read of the given attribute with the given error, once
+    /// `reads` of them have succeeded
+    pub fn fail_reads_after(&self, handle: AttHandle, reads: usize, error: AttErrorCode) {
+        self.scripts.borrow_mut().entry(handle).or_default().fail_after = Some((reads, error));
+    }
+
+    /// Fail the next write to the given attribute with the given error, once
+    /// it has passed the checks of check_write(), as its owner would
+    pub fn fail_next_write(&self, handle: AttHandle, error: AttErrorCode) {
+        self.write_failures.borrow_mut().insert(handle, error);
+    }
+
+    /// The number of times list_attributes() was called, so tests can check
+    /// that lookups only visit the handles they need
+    pub fn list_attributes_calls(&self) -> usize {
+        self.list_calls.get()
+    }
+
+    /// Resolve each read of the given attribute only after the given delay.
+    /// Tests run with paused time (see utils::task::block_on_locally), so
+    /// this lets them interleave other events with a pending read.
+    pub fn delay_reads(&self, handle: AttHandle, delay: Duration) {
+        self.scripts.borrow_mut().entry(handle).or_default().delay = Some(delay);
+    }
+
+    /// Allow the values of attributes to change between reads. Since the
+    /// database then no longer stays stable across async points, the result
+    /// does not implement StableAttDatabase, and goes through the paths
+    /// (e.g. snapshot()) taken by databases that may change under a request.
+    pub fn into_mutable(self) -> MutableTestAttDatabase {
+        MutableTestAttDatabase(self)
+    }
+
+    /// Run the script of a read, returning the error it fails with, if any.
+    /// The attribute takes its next value (if one is queued) once the read
+    /// returns its current one.
+    async fn run_read_script(&self, handle: AttHandle) -> Result<(), AttErrorCode> {
+        let delay = self.scripts.borrow().get(&handle).and_then(|script| script.delay);
+        if let Some(delay) = delay {
+            tokio::time::sleep(delay).await;
         }
+        let mut scripts = self.scripts.borrow_mut();
+        let Some(script) = scripts.get_mut(&handle) else {
+            return Ok(());
+        };
+        if let Some((reads, error)) = script.fail_after {
+            if script.reads >= reads {
+                return Err(error);
+            }
+        }
+        script.reads += 1;
+        Ok(())
+    }
+
+    fn take_write_failure(&self, handle: AttHandle) -> Option<AttErrorCode> {
+        self.write_failures.borrow_mut().remove(&handle)
+    }
+
+    fn take_next_value(&self, handle: AttHandle) -> Option<Vec<u8>> {
+        self.scripts.borrow_mut().get_mut(&handle)?.next_values.pop_front()
     }
 }
 
 #[async_trait(?Send)]
 impl AttDatabase for TestAttDatabase {
-    async fn read_attribute(&self, handle: AttHandle) -> Result<Vec<u8>, AttErrorCode> {
+    async fn read_attribute(&self, handle: AttHandle) -> Result<AttAttributeValue, AttError> {
         info!("reading {handle:?}");
         match self.attributes.get(&handle) {
             Some(TestAttributeWithData { attribute: AttAttribute { permissions, .. }, .. })
                 if !permissions.readable() =>
             {
-                Err(AttErrorCode::READ_NOT_PERMITTED)
+                Err(AttErrorCode::READ_NOT_PERMITTED.into())
             }
-            Some(TestAttributeWithData { data, .. }) => Ok(data.borrow().clone()),
-            None => Err(AttErrorCode::INVALID_HANDLE),
+            Some(attribute) => {
+                self.run_read_script(handle).await?;
+                let value = attribute.data.borrow().as_slice().into();
+                if let Some(next_value) = self.take_next_value(handle) {
+                    attribute.replace_data(next_value);
+                }
+                Ok(value)
+            }
+            None => Err(AttErrorCode::INVALID_HANDLE.into()),
         }
     }
-    async fn write_attribute(&self, handle: AttHandle, data: &[u8]) -> Result<(), AttErrorCode> {
-        match self.attributes.get(&handle) {
-            Some(TestAttributeWithData { attribute: AttAttribute { permissions, .. }, .. })
-                if !permissions.writable_with_response() =>
-            {
-                Err(AttErrorCode::WRITE_NOT_PERMITTED)
-            }
-            Some(TestAttributeWithData { data: data_cell, .. }) => {
-                data_cell.replace(data.to_vec());
-                Ok(())
-            }
-            None => Err(AttErrorCode::INVALID_HANDLE),
+    async fn write_attribute(
+        &self,
+        handle: AttHandle,
+        offset: u32,
+        data: &[u8],
+    ) -> Result<(), AttError> {
+        self.check_write(handle, offset, data)?;
+        if let Some(error) = self.take_write_failure(handle) {
+            return Err(error.into());
+        }
+        let attribute = &self.attributes[&handle];
+        let mut value = attribute.data.borrow().clone();
+        value.truncate(offset as usize);
+        value.extend_from_slice(data);
+        attribute.replace_data(value);
+        Ok(())
+    }
+    fn check_write(&self, handle: AttHandle, offset: u32, data: &[u8]) -> Result<(), AttError> {
+        let Some(attribute) = self.attributes.get(&handle) else {
+            return Err(AttErrorCode::INVALID_HANDLE.into());
+        };
+        let offset = offset as usize;
+        if !attribute.attribute.permissions.writable_with_response() {
+            Err(AttErrorCode::WRITE_NOT_PERMITTED.into())
+        } else if offset > attribute.data.borrow().len() {
+            Err(AttErrorCode::INVALID_OFFSET.into())
+        } else if offset + data.len() > MAX_ATTRIBUTE_VALUE_LEN {
+            Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH.into())
+        } else {
+            Ok(())
         }
     }
     fn write_no_response_attribute(&self, handle: AttHandle, data: &[u8]) {
         match self.attributes.get(&handle) {
-            Some(TestAttributeWithData {
-                attribute: AttAttribute { permissions, .. },
-                data: data_cell,
-            }) if !permissions.writable_with_response() => {
-                data_cell.replace(data.to_vec());
+            Some(attribute) if attribute.attribute.permissions.writable_without_response() => {
+                attribute.replace_data(data.to_vec());
             }
             _ => {
                 warn!("rejecting write command to {handle:?}")
@@ -78,9 +240,206 @@ impl AttDatabase for TestAttDatabase {
         }
     }
     fn list_attributes(&self) -> Vec<AttAttribute> {
+        self.list_calls.set(self.list_calls.get() + 1);
         self.attributes.values().map(|attr| attr.attribute).collect()
     }
+    fn attributes_in_range(
+        &self,
+        start: AttHandle,
+        end: AttHandle,
+        type_filter: Option<Uuid>,
+    ) -> Vec<AttAttribute> {
+        if end < start {
+            return vec![];
+        }
+        self.attributes
+            .range(start..=end)
+            .map(|(_, attr)| attr.attribute)
+            .filter(|attr| type_filter.map(|type_| attr.type_ == type_).unwrap_or(true))
+            .collect()
+    }
+    fn is_change_aware(&self) -> bool {
+        self.change_aware.get()
+    }
+    fn mark_change_aware(&self) {
+        self.change_aware.set(true);
+    }
+    fn server_config(&self) -> GattServerConfig {
+        self.config.get()
+    }
+    fn client_supported_features(&self) -> ClientSupportedFeatures {
+        self.client_supported_features.get()
+    }
+    fn coalesces_notifications(&self, handle: AttHandle) -> bool {
+        self.coalesced.borrow().contains(&handle)
+    }
 }
 
 // We guarantee that the contents of a TestAttDatabase will remain stable
 impl StableAttDatabase for TestAttDatabase {}
+
+/// A TestAttDatabase whose values may change between reads, so that does not
+/// implement StableAttDatabase, but reports the version of each value (see
+/// AttDatabase::value_version())
+#[derive(Clone, Debug)]
+pub struct MutableTestAttDatabase(TestAttDatabase);
+
+impl MutableTestAttDatabase {
+    /// Replace the value of an attribute
+    pub fn set_value(&self, handle: AttHandle, value: Vec<u8>) {
+        if let Some(attribute) = self.0.attributes.get(&handle) {
+            attribute.replace_data(value);
+        }
+    }
+
+    /// Make the attribute take each of the given values in turn, after each
+    /// of its next reads
+    pub fn change_value_after_reads(&self, handle: AttHandle, values: Vec<Vec<u8>>) {
+        self.0.scripts.borrow_mut().entry(handle).or_default().next_values.extend(values);
+    }
+}
+
+#[async_trait(?Send)]
+impl AttDatabase for MutableTestAttDatabase {
+    async fn read_attribute(&self, handle: AttHandle) -> Result<AttAttributeValue, AttError> {
+        self.0.read_attribute(handle).await
+    }
+    async fn write_attribute(
+        &self,
+        handle: AttHandle,
+        offset: u32,
+        data: &[u8],
+    ) -> Result<(), AttError> {
+        self.0.write_attribute(handle, offset, data).await
+    }
+    fn check_write(&self, handle: AttHandle, offset: u32, data: &[u8]) -> Result<(), AttError> {
+        self.0.check_write(handle, offset, data)
+    }
+    fn write_no_response_attribute(&self, handle: AttHandle, data: &[u8]) {
+        self.0.write_no_response_attribute(handle, data)
+    }
+    fn list_attributes(&self) -> Vec<AttAttribute> {
+        self.0.list_attributes()
+    }
+    fn is_change_aware(&self) -> bool {
+        self.0.is_change_aware()
+    }
+    fn mark_change_aware(&self) {
+        self.0.mark_change_aware()
+    }
+    fn server_config(&self) -> GattServerConfig {
+        self.0.server_config()
+    }
+    fn value_version(&self, handle: AttHandle) -> Option<u64> {
+        self.0.attributes.get(&handle).map(|attribute| attribute.version.get())
+    }
+    fn client_supported_features(&self) -> ClientSupportedFeatures {
+        self.0.client_supported_features()
+    }
+    fn coalesces_notifications(&self, handle: AttHandle) -> bool {
+        self.0.coalesces_notifications(handle)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::{
+        core::uuid::Uuid,
+        gatt::server::{att_database::AttPermissions, request_handler::AttRequestHandler},
+        packets::{AttAttributeDataChild, AttChild, AttReadRequestBuilder, AttReadResponseBuilder},
+        utils::{
+            packet::{build_att_data, build_att_view_or_crash},
+            task::block_on_locally,
+        },
+    };
+
+    const HANDLE: AttHandle = AttHandle(3);
+
+    fn make_db() -> TestAttDatabase {
+        TestAttDatabase::new(vec![(
+            AttAttribute {
+                handle: HANDLE,
+                type_: Uuid::new(0x1234),
+                permissions: AttPermissions::READABLE,
+            },
+            vec![1, 2, 3],
+        )])
+    }
+
+    fn read(db: &impl AttDatabase) -> Result<Vec<u8>, AttErrorCode> {
+        tokio_test::block_on(db.read_attribute(HANDLE))
+            .map(|value| value.to_vec())
+            .map_err(AttErrorCode::from)
+    }
+
+    #[test]
+    fn test_fail_reads_after() {
+        // arrange
+        let db = make_db();
+
+        // act
+        db.fail_reads_after(HANDLE, 2, AttErrorCode::UNLIKELY_ERROR);
+
+        // assert
+        assert_eq!(read(&db), Ok(vec![1, 2, 3]));
+        assert_eq!(read(&db), Ok(vec![1, 2, 3]));
+        assert_eq!(read(&db), Err(AttErrorCode::UNLIKELY_ERROR));
+        assert_eq!(read(&db), Err(AttErrorCode::UNLIKELY_ERROR));
+    }
+
+    #[test]
+    fn test_delay_reads() {
+        block_on_locally(async {
+            // arrange
+            let db = make_db();
+            db.delay_reads(HANDLE, Duration::from_secs(5));
+            let start = tokio::time::Instant::now();
+
+            // act
+            let value = db.read_attribute(HANDLE).await;
+
+            // assert
+            assert_eq!(value.unwrap().to_vec(), vec![1, 2, 3]);
+            assert!(start.elapsed() >= Duration::from_secs(5));
+        });
+    }
+
+    #[test]
+    fn test_change_value_after_reads() {
+        // arrange
+        let db = make_db().into_mutable();
+
+        // act
+        db.change_value_after_reads(HANDLE, vec![vec![4], vec![5, 6]]);
+
+        // assert
+        assert_eq!(read(&db), Ok(vec![1, 2, 3]));
+        assert_eq!(read(&db), Ok(vec![4]));
+        assert_eq!(read(&db), Ok(vec![5, 6]));
+        assert_eq!(read(&db), Ok(vec![5, 6]));
+    }
+
+    #[test]
+    fn test_mutable_db_served_by_request_handler() {
+        // arrange
+        let db = make_db().into_mutable();
+        let mut handler = AttRequestHandler::new(db.clone());
+        let att_view =
+            build_att_view_or_crash(AttReadRequestBuilder { attribute_handle: HANDLE.into() });
+
+        // act: change the value between two reads
+        tokio_test::block_on(handler.process_packet(att_view.view(), 31));
+        db.set_value(HANDLE, vec![7]);
+        let response = tokio_test::block_on(handler.process_packet(att_view.view(), 31));
+
+        // assert
+        assert_eq!(
+            response._child_,
+            AttChild::AttReadResponse(AttReadResponseBuilder {
+                value: build_att_data(AttAttributeDataChild::RawData([7].into()))
+            })
+        );
+    }
+}
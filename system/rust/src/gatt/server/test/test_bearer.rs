@@ -0,0 +1,252 @@
+//! A harness wrapping an AttServerBearer, so that tests can script the
+//! exchange of PDUs with a client ("the client sends X, so expect the server
+//! to send Y within N polls"), and compare everything sent in both directions
+//! against a golden transcript.
+//!
+//! It MUST be used from within block_on_locally(), whose single-threaded
+//! executor (with mocked time) makes the scheduling of the tasks spawned by
+//! the bearer deterministic.
+
+use std::rc::Rc;
+
+use tokio::{
+    sync::mpsc::{error::TryRecvError, unbounded_channel, UnboundedReceiver},
+    task::yield_now,
+};
+
+use crate::{
+    core::shared_box::SharedBox,
+    gatt::{
+        ids::TransportIndex,
+        mocks::mock_security_manager::MockSecurityManager,
+        mtu::MAX_ATT_MTU,
+        server::{
+            att_database::AttDatabase, att_server_bearer::AttServerBearer,
+            security_elevation::SecurityElevation, signature_verifier::SignatureVerifier,
+        },
+    },
+    packets::{AttBuilder, AttChild, OwnedAttView, OwnedPacket, Serializable},
+    utils::packet::HACK_child_to_opcode,
+};
+
+const TCB_IDX: TransportIndex = TransportIndex(1);
+
+/// The number of polls within which a reply is usually expected, which is
+/// enough for the bearer to run a transaction against a TestAttDatabase
+pub const DEFAULT_POLLS: usize = 10;
+
+pub struct TestBearer<T: AttDatabase + Clone + 'static> {
+    bearer: SharedBox<AttServerBearer<T>>,
+    rx: UnboundedReceiver<AttBuilder>,
+    transcript: Vec<String>,
+}
+
+impl<T: AttDatabase + Clone + 'static> TestBearer<T> {
+    /// Open an (unenhanced) bearer to the given database
+    pub fn new(db: T) -> Self {
+        let security_manager = Rc::new(MockSecurityManager::new());
+        let (tx, rx) = unbounded_channel();
+        let bearer = AttServerBearer::new(
+            db,
+            SignatureVerifier::new(TCB_IDX, security_manager.clone()),
+            SecurityElevation::new(TCB_IDX, security_manager),
+            MAX_ATT_MTU,
+            move |packet| {
+                tx.send(packet).unwrap();
+                Ok(())
+            },
+        )
+        .into();
+        Self { bearer, rx, transcript: vec![] }
+    }
+
+    /// The client sends the given PDU
+    pub fn send(&mut self, pdu: impl Into<AttChild>) {
+        let child = pdu.into();
+        let pdu = AttBuilder { opcode: HACK_child_to_opcode(&child), _child_: child };
+        self.send_raw(&pdu.to_vec().unwrap());
+    }
+
+    /// The client sends the given bytes, which must form a valid ATT PDU
+    pub fn send_raw(&mut self, pdu: &[u8]) {
+        self.transcript.push(format!("> {}", to_hex(pdu)));
+        let pdu = OwnedAttView::try_parse(pdu.into()).unwrap();
+        self.bearer.as_ref().handle_packet(pdu.view());
+    }
+
+    /// Expect the server to send the given PDU within the given number of
+    /// polls of the executor, having sent nothing else beforehand
+    pub async fn expect(&mut self, expected: impl Into<AttChild>, polls: usize) {
+        let child = expected.into();
+        let expected = AttBuilder { opcode: HACK_child_to_opcode(&child), _child_: child };
+        self.expect_raw(&expected.to_vec().unwrap(), polls).await;
+    }
+
+    /// As expect(), but for the serialized PDU
+    pub async fn expect_raw(&mut self, expected: &[u8], polls: usize) {
+        let Some(pdu) = self.poll_for_pdu(polls).await else {
+            panic!("expected {} within {polls} polls, but nothing was sent", to_hex(expected));
+        };
+        assert_eq!(to_hex(&pdu), to_hex(expected), "unexpected PDU from the server");
+    }
+
+    /// Expect the server to send nothing within the given number of polls
+    pub async fn expect_nothing(&mut self, polls: usize) {
+        if let Some(pdu) = self.poll_for_pdu(polls).await {
+            panic!("expected nothing within {polls} polls, but got {}", to_hex(&pdu));
+        }
+    }
+
+    /// Everything sent so far, one PDU per line, in hex. Lines starting with
+    /// '>' were sent by the client, and those starting with '<' by the server.
+    pub fn transcript(&self) -> String {
+        self.transcript.iter().map(|line| format!("{line}\n")).collect()
+    }
+
+    /// Compare the transcript against a golden one (e.g. loaded from a file
+    /// with include_str!()). Blank lines and comments starting with '#' in the
+    /// golden transcript are ignored.
+    pub fn assert_transcript_matches(&self, golden: &str) {
+        let golden = golden
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            self.transcript.iter().map(String::as_str).collect::<Vec<_>>(),
+            golden,
+            "transcript does not match, got:\n{}",
+            self.transcript()
+        );
+    }
+
+    /// Poll the executor until the server sends a PDU (which is recorded), or
+    /// the given number of polls elapses
+    async fn poll_for_pdu(&mut self, polls: usize) -> Option<Vec<u8>> {
+        for _ in 0..polls {
+            match self.rx.try_recv() {
+                Ok(pdu) => {
+                    let pdu = pdu.to_vec().unwrap();
+                    self.transcript.push(format!("< {}", to_hex(&pdu)));
+                    return Some(pdu);
+                }
+                Err(TryRecvError::Empty) => yield_now().await,
+                Err(TryRecvError::Disconnected) => panic!("bearer dropped"),
+            }
+        }
+        None
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::{
+        core::uuid::Uuid,
+        gatt::{
+            ids::AttHandle,
+            server::{
+                att_database::{AttAttribute, AttPermissions},
+                test::test_att_db::TestAttDatabase,
+            },
+        },
+        packets::{
+            AttAttributeDataChild, AttErrorCode, AttErrorResponseBuilder,
+            AttExchangeMtuRequestBuilder, AttExchangeMtuResponseBuilder, AttOpcode,
+            AttReadRequestBuilder, AttReadResponseBuilder, AttWriteCommandBuilder,
+        },
+        utils::{packet::build_att_data, task::block_on_locally},
+    };
+
+    const HANDLE: AttHandle = AttHandle(3);
+
+    fn make_db() -> TestAttDatabase {
+        TestAttDatabase::new(vec![(
+            AttAttribute {
+                handle: HANDLE,
+                type_: Uuid::new(0x1234),
+                permissions: AttPermissions::READABLE | AttPermissions::WRITABLE_WITHOUT_RESPONSE,
+            },
+            vec![1, 2, 3],
+        )])
+    }
+
+    #[test]
+    fn test_scripted_exchange() {
+        block_on_locally(async {
+            let mut bearer = TestBearer::new(make_db());
+
+            bearer.send(AttReadRequestBuilder { attribute_handle: HANDLE.into() });
+            bearer
+                .expect(
+                    AttReadResponseBuilder {
+                        value: build_att_data(AttAttributeDataChild::RawData([1, 2, 3].into())),
+                    },
+                    DEFAULT_POLLS,
+                )
+                .await;
+            bearer.send(AttReadRequestBuilder { attribute_handle: AttHandle(4).into() });
+            bearer
+                .expect(
+                    AttErrorResponseBuilder {
+                        opcode_in_error: AttOpcode::READ_REQUEST,
+                        handle_in_error: AttHandle(4).into(),
+                        error_code: AttErrorCode::INVALID_HANDLE,
+                    },
+                    DEFAULT_POLLS,
+                )
+                .await;
+        });
+    }
+
+    #[test]
+    fn test_command_gets_no_reply() {
+        block_on_locally(async {
+            let mut bearer = TestBearer::new(make_db());
+
+            bearer.send(AttWriteCommandBuilder {
+                handle: HANDLE.into(),
+                value: build_att_data(AttAttributeDataChild::RawData([4, 5].into())),
+            });
+
+            bearer.expect_nothing(DEFAULT_POLLS).await;
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "nothing was sent")]
+    fn test_missing_reply_detected() {
+        block_on_locally(async {
+            let mut bearer = TestBearer::new(make_db());
+
+            bearer.expect(AttExchangeMtuResponseBuilder { mtu: 517 }, DEFAULT_POLLS).await;
+        });
+    }
+
+    #[test]
+    fn test_golden_transcript() {
+        block_on_locally(async {
+            let mut bearer = TestBearer::new(make_db());
+
+            bearer.send(AttExchangeMtuRequestBuilder { mtu: 64 });
+            bearer.expect_raw(&[0x03, 0x05, 0x02], DEFAULT_POLLS).await;
+            bearer.send_raw(&[0x0a, 0x03, 0x00]);
+            bearer.expect_raw(&[0x0b, 0x01, 0x02, 0x03], DEFAULT_POLLS).await;
+            bearer.send(AttWriteCommandBuilder {
+                handle: HANDLE.into(),
+                value: build_att_data(AttAttributeDataChild::RawData([4, 5].into())),
+            });
+            bearer.expect_nothing(DEFAULT_POLLS).await;
+            bearer.send_raw(&[0x0a, 0x03, 0x00]);
+            bearer.expect_raw(&[0x0b, 0x04, 0x05], DEFAULT_POLLS).await;
+
+            bearer
+                .assert_transcript_matches(include_str!("golden/mtu_exchange_read_and_write.txt"));
+        });
+    }
+}
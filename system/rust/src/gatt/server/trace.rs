@@ -0,0 +1,289 @@
+//! This module lets integrators observe every ATT PDU received or sent by the
+//! server (e.g. to feed them into btsnoop or Perfetto), and provides a tracer
+//! retaining the most recent PDUs for the debug dump. It also lets them follow
+//! each ATT transaction through the server as a span, to tell whether a slow
+//! transaction spent its time being parsed, in the database, or being sent.
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    fmt,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    gatt::ids::{BearerId, ConnectionId, EattCid},
+    packets::{AttOpcode, AttView},
+};
+
+/// The number of PDUs retained by a RingBufferTracer by default
+pub const DEFAULT_TRACE_CAPACITY: usize = 64;
+
+/// Whether a PDU was received from or sent to the client
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// The PDU was received from the client
+    Rx,
+    /// The PDU was sent to the client
+    Tx,
+}
+
+/// A single ATT PDU received or sent by the server
+pub struct AttTraceEvent<'a> {
+    /// The connection on which the PDU was exchanged
+    pub conn_id: ConnectionId,
+    /// The EATT bearer on which the PDU was exchanged, or None for the
+    /// unenhanced bearer
+    pub cid: Option<EattCid>,
+    /// Whether the PDU was received or sent
+    pub direction: Direction,
+    /// When the PDU was received, or handed to the transport
+    pub timestamp: SystemTime,
+    /// The PDU
+    pub pdu: AttView<'a>,
+}
+
+/// Observes the ATT PDUs received and sent by the server
+pub trait AttTracer {
+    /// Invoked on every PDU, in the order in which they were processed
+    fn on_pdu(&self, event: &AttTraceEvent<'_>);
+
+    /// Print the state of the tracer (e.g. the PDUs it retains) as part of
+    /// the debug dump of the server
+    fn debug_dump(&self, _out: &mut dyn fmt::Write) -> fmt::Result {
+        Ok(())
+    }
+}
+
+/// Identifies the span of a single ATT transaction
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SpanId {
+    /// The connection on which the transaction took place
+    pub conn_id: ConnectionId,
+    /// The EATT bearer on which the transaction took place, or None for the
+    /// unenhanced bearer
+    pub cid: Option<EattCid>,
+    /// The number of transactions started on the bearer before this one
+    pub seq: u64,
+}
+
+/// A point reached by an ATT transaction. A span goes through them in the
+/// order listed (ending at either ResponseSent or TimedOut), so the time
+/// between two consecutive events is spent in a single stage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SpanPhase {
+    /// The PDU of the request was received
+    RequestReceived,
+    /// The request was parsed, and handed to the database (after holding it
+    /// back, if the client is throttled)
+    Dispatched,
+    /// The database produced the response (which may be an error response)
+    DatabaseResolved,
+    /// The response was handed to the transport
+    ResponseSent,
+    /// The database did not produce the response before the ATT transaction
+    /// timeout, so none was sent
+    TimedOut,
+}
+
+/// An ATT transaction reaching a SpanPhase
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpanEvent {
+    /// The transaction
+    pub span: SpanId,
+    /// The opcode of its request
+    pub opcode: AttOpcode,
+    /// The point it reached
+    pub phase: SpanPhase,
+    /// When it reached it, as measured by the Clock of the server
+    pub timestamp: Instant,
+}
+
+/// Follows the ATT transactions processed by the server
+pub trait TransactionTracer {
+    /// Invoked as each transaction reaches each SpanPhase
+    fn on_span_event(&self, event: &SpanEvent);
+}
+
+/// A PDU retained by a RingBufferTracer
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// The connection on which the PDU was exchanged
+    pub conn_id: ConnectionId,
+    /// The EATT bearer on which the PDU was exchanged, or None for the
+    /// unenhanced bearer
+    pub cid: Option<EattCid>,
+    /// Whether the PDU was received or sent
+    pub direction: Direction,
+    /// When the PDU was received, or handed to the transport
+    pub timestamp: SystemTime,
+    /// The opcode of the PDU
+    pub opcode: AttOpcode,
+    /// The serialized PDU, including its opcode
+    pub bytes: Vec<u8>,
+}
+
+/// A tracer retaining the most recent PDUs, up to a fixed capacity
+pub struct RingBufferTracer {
+    capacity: usize,
+    records: RefCell<VecDeque<TraceRecord>>,
+}
+
+impl RingBufferTracer {
+    /// Constructor, retaining at most the given number of PDUs
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, records: VecDeque::with_capacity(capacity).into() }
+    }
+
+    /// The retained PDUs, oldest first
+    pub fn records(&self) -> Vec<TraceRecord> {
+        self.records.borrow().iter().cloned().collect()
+    }
+}
+
+impl Default for RingBufferTracer {
+    fn default() -> Self {
+        Self::new(DEFAULT_TRACE_CAPACITY)
+    }
+}
+
+impl AttTracer for RingBufferTracer {
+    fn on_pdu(&self, event: &AttTraceEvent<'_>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut records = self.records.borrow_mut();
+        if records.len() == self.capacity {
+            records.pop_front();
+        }
+        let opcode = event.pdu.get_opcode();
+        records.push_back(TraceRecord {
+            conn_id: event.conn_id,
+            cid: event.cid,
+            direction: event.direction,
+            timestamp: event.timestamp,
+            opcode,
+            bytes: std::iter::once(u8::from(opcode)).chain(event.pdu.get_raw_payload()).collect(),
+        });
+    }
+
+    fn debug_dump(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        let records = self.records.borrow();
+        writeln!(out, "last {} ATT PDU(s):", records.len())?;
+        for record in records.iter() {
+            let timestamp = record.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+            let bearer = BearerId { tcb_idx: record.conn_id.get_tcb_idx(), cid: record.cid };
+            let bytes = record.bytes.iter().map(|byte| format!("{byte:02x}")).collect::<String>();
+            writeln!(
+                out,
+                "  {}.{:03} {:?} {} ({bearer}) {:?} {bytes}",
+                timestamp.as_secs(),
+                timestamp.subsec_millis(),
+                record.direction,
+                record.conn_id,
+                record.opcode
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        gatt::ids::{AttHandle, ServerId, TransportIndex},
+        packets::{AttReadRequestBuilder, AttWriteResponseBuilder},
+        utils::packet::build_att_view_or_crash,
+    };
+
+    use super::*;
+
+    const CONN_ID: ConnectionId = ConnectionId::new(TransportIndex(1), ServerId(2));
+
+    fn trace(tracer: &RingBufferTracer, direction: Direction, pdu: AttView<'_>) {
+        tracer.on_pdu(&AttTraceEvent {
+            conn_id: CONN_ID,
+            cid: None,
+            direction,
+            timestamp: UNIX_EPOCH,
+            pdu,
+        });
+    }
+
+    #[test]
+    fn test_records_pdu() {
+        // arrange
+        let tracer = RingBufferTracer::default();
+        let pdu = build_att_view_or_crash(AttReadRequestBuilder {
+            attribute_handle: AttHandle(3).into(),
+        });
+
+        // act
+        trace(&tracer, Direction::Rx, pdu.view());
+
+        // assert
+        assert_eq!(
+            tracer.records(),
+            vec![TraceRecord {
+                conn_id: CONN_ID,
+                cid: None,
+                direction: Direction::Rx,
+                timestamp: UNIX_EPOCH,
+                opcode: AttOpcode::READ_REQUEST,
+                bytes: vec![0x0a, 0x03, 0x00],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_oldest_pdu_evicted_at_capacity() {
+        // arrange
+        let tracer = RingBufferTracer::new(1);
+        let request = build_att_view_or_crash(AttReadRequestBuilder {
+            attribute_handle: AttHandle(3).into(),
+        });
+        let response = build_att_view_or_crash(AttWriteResponseBuilder {});
+
+        // act
+        trace(&tracer, Direction::Rx, request.view());
+        trace(&tracer, Direction::Tx, response.view());
+
+        // assert
+        let records = tracer.records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].direction, Direction::Tx);
+        assert_eq!(records[0].opcode, AttOpcode::WRITE_RESPONSE);
+    }
+
+    #[test]
+    fn test_zero_capacity_retains_nothing() {
+        let tracer = RingBufferTracer::new(0);
+        let pdu = build_att_view_or_crash(AttWriteResponseBuilder {});
+
+        trace(&tracer, Direction::Tx, pdu.view());
+
+        assert_eq!(tracer.records(), vec![]);
+    }
+
+    #[test]
+    fn test_debug_dump() {
+        // arrange
+        let tracer = RingBufferTracer::default();
+        let pdu = build_att_view_or_crash(AttWriteResponseBuilder {});
+        trace(&tracer, Direction::Tx, pdu.view());
+
+        // act
+        let mut dump = String::new();
+        tracer.debug_dump(&mut dump).unwrap();
+
+        // assert
+        assert!(dump.contains("last 1 ATT PDU(s)"), "{dump}");
+        assert!(
+            dump.contains(&format!(
+                "0.000 Tx tcb1/server2 (tcb1/unenhanced) {:?} 13",
+                AttOpcode::WRITE_RESPONSE
+            )),
+            "{dump}"
+        );
+    }
+}
@@ -0,0 +1,112 @@
+use log::{info, warn};
+
+use crate::{
+    gatt::{ids::AttHandle, mtu::AttMtu},
+    packets::{
+        AttChild, AttErrorCode, AttErrorResponseBuilder, AttExchangeMtuRequestView,
+        AttExchangeMtuResponseBuilder, AttOpcode, AttView, Packet,
+    },
+};
+
+/// Handle an ATT_EXCHANGE_MTU_REQ from the client, updating the MTU of the
+/// bearer if it is accepted. The packet is parsed here (rather than by the
+/// caller) so that a malformed request is rejected with INVALID_PDU.
+pub fn handle_exchange_mtu_request(
+    packet: AttView<'_>,
+    mtu: &AttMtu,
+    server_rx_mtu: usize,
+) -> AttChild {
+    match AttExchangeMtuRequestView::try_parse(packet) {
+        Ok(request) => match mtu.on_exchange_request(request.get_mtu().into(), server_rx_mtu) {
+            Ok(mtu) => {
+                info!("MTU exchanged, now using {mtu}");
+                AttExchangeMtuResponseBuilder { mtu: server_rx_mtu as u16 }.into()
+            }
+            Err(err) => {
+                warn!("rejecting ATT_EXCHANGE_MTU_REQ: {err}");
+                AttErrorResponseBuilder {
+                    opcode_in_error: AttOpcode::EXCHANGE_MTU_REQUEST,
+                    handle_in_error: AttHandle(0).into(),
+                    error_code: AttErrorCode::REQUEST_NOT_SUPPORTED,
+                }
+                .into()
+            }
+        },
+        Err(_) => AttErrorResponseBuilder {
+            opcode_in_error: AttOpcode::EXCHANGE_MTU_REQUEST,
+            handle_in_error: AttHandle(0).into(),
+            error_code: AttErrorCode::INVALID_PDU,
+        }
+        .into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::{
+        packets::{AttExchangeMtuRequestBuilder, OwnedAttView, OwnedPacket},
+        utils::packet::build_att_view_or_crash,
+    };
+
+    const SERVER_RX_MTU: usize = 100;
+
+    #[test]
+    fn test_exchange_uses_smaller_mtu() {
+        // arrange
+        let mtu = AttMtu::new();
+        let request = build_att_view_or_crash(AttExchangeMtuRequestBuilder { mtu: 200 });
+
+        // act
+        let response = handle_exchange_mtu_request(request.view(), &mtu, SERVER_RX_MTU);
+
+        // assert
+        assert_eq!(response, AttExchangeMtuResponseBuilder { mtu: SERVER_RX_MTU as u16 }.into());
+        assert_eq!(mtu.snapshot_or_default(), SERVER_RX_MTU);
+    }
+
+    #[test]
+    fn test_second_exchange_rejected() {
+        // arrange
+        let mtu = AttMtu::new();
+        let request = build_att_view_or_crash(AttExchangeMtuRequestBuilder { mtu: 50 });
+        handle_exchange_mtu_request(request.view(), &mtu, SERVER_RX_MTU);
+
+        // act
+        let response = handle_exchange_mtu_request(request.view(), &mtu, SERVER_RX_MTU);
+
+        // assert
+        assert_eq!(
+            response,
+            AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::EXCHANGE_MTU_REQUEST,
+                handle_in_error: AttHandle(0).into(),
+                error_code: AttErrorCode::REQUEST_NOT_SUPPORTED,
+            }
+            .into()
+        );
+        assert_eq!(mtu.snapshot_or_default(), 50);
+    }
+
+    #[test]
+    fn test_truncated_request() {
+        // arrange: a request missing its MTU field
+        let mtu = AttMtu::new();
+        let request = OwnedAttView::try_parse(vec![0x02].into_boxed_slice()).unwrap();
+
+        // act
+        let response = handle_exchange_mtu_request(request.view(), &mtu, SERVER_RX_MTU);
+
+        // assert
+        assert_eq!(
+            response,
+            AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::EXCHANGE_MTU_REQUEST,
+                handle_in_error: AttHandle(0).into(),
+                error_code: AttErrorCode::INVALID_PDU,
+            }
+            .into()
+        );
+    }
+}
@@ -4,7 +4,10 @@ use crate::{
     core::uuid::Uuid,
     gatt::{
         ids::AttHandle,
-        server::att_database::{AttAttribute, StableAttDatabase},
+        server::{
+            att_database::{AttAttribute, AttDatabase},
+            gatt_database::PRIMARY_SERVICE_DECLARATION_UUID,
+        },
     },
     packets::{
         AttChild, AttErrorCode, AttErrorResponseBuilder, AttFindByTypeValueRequestView,
@@ -20,12 +23,13 @@ use super::helpers::{
 pub async fn handle_find_by_type_value_request(
     request: AttFindByTypeValueRequestView<'_>,
     mtu: usize,
-    db: &impl StableAttDatabase,
+    db: &impl AttDatabase,
 ) -> AttChild {
     let Some(attrs) = filter_to_range(
+        db,
         request.get_starting_handle().into(),
         request.get_ending_handle().into(),
-        db.list_attributes().into_iter(),
+        Some(request.get_attribute_type().into()),
     ) else {
         return AttErrorResponseBuilder {
             opcode_in_error: AttOpcode::FIND_BY_TYPE_VALUE_REQUEST,
@@ -37,13 +41,11 @@ pub async fn handle_find_by_type_value_request(
 
     // ATT_MTU-1 limit comes from Spec 5.3 Vol 3F Sec 3.4.3.4
     let mut matches = PayloadAccumulator::new(mtu - 1);
+    let requested_value = request.get_attribute_value().get_raw_payload().collect::<Vec<_>>();
 
     for attr @ AttAttribute { handle, type_, .. } in attrs {
-        if Uuid::from(request.get_attribute_type()) != type_ {
-            continue;
-        }
         if let Ok(value) = db.read_attribute(handle).await {
-            if value == request.get_attribute_value().get_raw_payload().collect::<Vec<_>>() {
+            if values_match(type_, &value, &requested_value) {
                 // match found
                 if !matches.push(AttributeHandleRangeBuilder {
                     found_attribute_handle: handle.into(),
@@ -72,19 +74,44 @@ pub async fn handle_find_by_type_value_request(
     }
 }
 
+/// Service UUIDs may be equivalently expressed as either a 16-bit or a 128-bit
+/// UUID (5.3 Vol 3G 4.4.2), so these are compared as UUIDs rather than
+/// bytewise. All other values must match exactly.
+fn values_match(type_: Uuid, value: &[u8], requested_value: &[u8]) -> bool {
+    if type_ == PRIMARY_SERVICE_DECLARATION_UUID {
+        if let (Some(uuid), Some(requested_uuid)) =
+            (Uuid::try_from_le_slice(value), Uuid::try_from_le_slice(requested_value))
+        {
+            return uuid == requested_uuid;
+        }
+    }
+    value == requested_value
+}
+
 #[cfg(test)]
 mod test {
+    use std::rc::Rc;
+
     use crate::{
+        core::shared_box::SharedBox,
         gatt::{
             ffi::Uuid,
+            ids::TransportIndex,
+            mocks::mock_datastore::MockDatastore,
             server::{
+                att_database::AttDatabase,
                 gatt_database::{
-                    AttPermissions, CHARACTERISTIC_UUID, PRIMARY_SERVICE_DECLARATION_UUID,
+                    AttPermissions, GattCharacteristicWithHandle, GattDatabase,
+                    GattDescriptorWithHandle, GattServiceWithHandle, CHARACTERISTIC_UUID,
+                    PRIMARY_SERVICE_DECLARATION_UUID,
                 },
                 test::test_att_db::TestAttDatabase,
             },
         },
-        packets::{AttAttributeDataChild, AttFindByTypeValueRequestBuilder},
+        packets::{
+            AttAttributeDataChild, AttFindByTypeValueRequestBuilder, Serializable,
+            UuidAsAttDataBuilder,
+        },
         utils::packet::{build_att_data, build_view_or_crash},
     };
 
@@ -384,4 +411,126 @@ mod test {
             }
         );
     }
+
+    const SERVICE_TYPE: Uuid = Uuid::new(0x1800);
+    const ANOTHER_SERVICE_TYPE: Uuid = Uuid::new(0x1801);
+
+    fn find_primary_service(
+        db: &impl AttDatabase,
+        attribute_value: AttAttributeDataChild,
+    ) -> Vec<AttributeHandleRangeBuilder> {
+        let att_view = build_view_or_crash(AttFindByTypeValueRequestBuilder {
+            starting_handle: AttHandle::MIN.into(),
+            ending_handle: AttHandle::MAX.into(),
+            attribute_type: PRIMARY_SERVICE_DECLARATION_UUID.try_into().unwrap(),
+            attribute_value: build_att_data(attribute_value),
+        });
+        let response =
+            tokio_test::block_on(handle_find_by_type_value_request(att_view.view(), 128, db));
+        let AttChild::AttFindByTypeValueResponse(response) = response else {
+            unreachable!("{response:?}")
+        };
+        response.handles_info.into_vec()
+    }
+
+    #[test]
+    fn test_primary_service_by_16_bit_uuid() {
+        // arrange: services whose declarations hold 128-bit UUIDs
+        let db = TestAttDatabase::new(vec![
+            (
+                AttAttribute {
+                    handle: AttHandle(1),
+                    type_: PRIMARY_SERVICE_DECLARATION_UUID,
+                    permissions: AttPermissions::READABLE,
+                },
+                UuidAsAttDataBuilder { uuid: SERVICE_TYPE.into() }.to_vec().unwrap(),
+            ),
+            (
+                AttAttribute {
+                    handle: AttHandle(2),
+                    type_: PRIMARY_SERVICE_DECLARATION_UUID,
+                    permissions: AttPermissions::READABLE,
+                },
+                UuidAsAttDataBuilder { uuid: ANOTHER_SERVICE_TYPE.into() }.to_vec().unwrap(),
+            ),
+        ]);
+
+        // act: look for a service by its 16-bit UUID
+        let handles_info =
+            find_primary_service(&db, AttAttributeDataChild::RawData([0x00, 0x18].into()));
+
+        // assert: the service was found
+        assert_eq!(
+            handles_info,
+            vec![AttributeHandleRangeBuilder {
+                found_attribute_handle: AttHandle(1).into(),
+                group_end_handle: AttHandle(1).into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_primary_service_group_end_from_gatt_database() {
+        // arrange: two services, the first ending with a descriptor
+        let gatt_db = SharedBox::new(GattDatabase::new());
+        gatt_db
+            .add_service_with_handles(
+                GattServiceWithHandle {
+                    handle: AttHandle(1),
+                    type_: SERVICE_TYPE,
+                    characteristics: vec![GattCharacteristicWithHandle {
+                        handle: AttHandle(3),
+                        type_: UUID,
+                        permissions: AttPermissions::READABLE,
+                        descriptors: vec![GattDescriptorWithHandle {
+                            handle: AttHandle(4),
+                            type_: ANOTHER_UUID,
+                            permissions: AttPermissions::READABLE,
+                        }],
+                    }],
+                },
+                Rc::new(MockDatastore::new().0),
+            )
+            .unwrap();
+        gatt_db
+            .add_service_with_handles(
+                GattServiceWithHandle {
+                    handle: AttHandle(10),
+                    type_: ANOTHER_SERVICE_TYPE,
+                    characteristics: vec![GattCharacteristicWithHandle {
+                        handle: AttHandle(12),
+                        type_: UUID,
+                        permissions: AttPermissions::READABLE,
+                        descriptors: vec![],
+                    }],
+                },
+                Rc::new(MockDatastore::new().0),
+            )
+            .unwrap();
+        let db = gatt_db.get_att_database(TransportIndex(1));
+
+        // act: look for each service
+        let first =
+            find_primary_service(&db, UuidAsAttDataBuilder { uuid: SERVICE_TYPE.into() }.into());
+        let second = find_primary_service(
+            &db,
+            UuidAsAttDataBuilder { uuid: ANOTHER_SERVICE_TYPE.into() }.into(),
+        );
+
+        // assert: each group ends at the last attribute of its service
+        assert_eq!(
+            first,
+            vec![AttributeHandleRangeBuilder {
+                found_attribute_handle: AttHandle(1).into(),
+                group_end_handle: AttHandle(4).into(),
+            }]
+        );
+        assert_eq!(
+            second,
+            vec![AttributeHandleRangeBuilder {
+                found_attribute_handle: AttHandle(10).into(),
+                group_end_handle: AttHandle(12).into(),
+            }]
+        );
+    }
 }
@@ -20,9 +20,10 @@ pub fn handle_find_information_request<T: AttDatabase>(
     db: &T,
 ) -> AttChild {
     let Some(attrs) = filter_to_range(
+        db,
         request.get_starting_handle().into(),
         request.get_ending_handle().into(),
-        db.list_attributes().into_iter(),
+        None,
     ) else {
         return AttErrorResponseBuilder {
             opcode_in_error: AttOpcode::FIND_INFORMATION_REQUEST,
@@ -81,6 +82,9 @@ fn handle_find_information_request_short(
     }
 }
 
+/// Returns a builder IF we can return at least one attribute, otherwise returns
+/// None. Stops at the first 16-bit UUID, so that it is returned in the short
+/// format by the following request.
 fn handle_find_information_request_long(
     attributes: impl Iterator<Item = AttAttribute>,
     mtu: usize,
@@ -89,6 +93,9 @@ fn handle_find_information_request_long(
     let mut out = PayloadAccumulator::new(mtu - 2);
 
     for AttAttribute { handle, type_: uuid, .. } in attributes {
+        if uuid.to_u16().is_some() {
+            break;
+        }
         if !out.push(AttFindInformationResponseLongEntryBuilder {
             handle: handle.into(),
             uuid: uuid.into(),
@@ -348,4 +355,222 @@ mod test {
             }
         );
     }
+
+    #[test]
+    fn test_long_uuids_stop_at_short_uuid() {
+        // arrange: a 128-bit UUID followed by a 16-bit UUID
+        let db = TestAttDatabase::new(vec![
+            (
+                AttAttribute {
+                    handle: AttHandle(3),
+                    type_: Uuid::new(0x01020304),
+                    permissions: AttPermissions::READABLE,
+                },
+                vec![4, 5],
+            ),
+            (
+                AttAttribute {
+                    handle: AttHandle(4),
+                    type_: Uuid::new(0x0102),
+                    permissions: AttPermissions::READABLE,
+                },
+                vec![4, 5],
+            ),
+        ]);
+
+        // act
+        let att_view = build_view_or_crash(AttFindInformationRequestBuilder {
+            starting_handle: AttHandle(3).into(),
+            ending_handle: AttHandle(4).into(),
+        });
+        let response = handle_find_information_request(att_view.view(), 128, &db);
+
+        // assert: only the 128-bit UUID is returned, in the long format
+        let AttChild::AttFindInformationResponse(response) = response else {
+            unreachable!("{response:?}");
+        };
+        assert_eq!(
+            response,
+            AttFindInformationResponseBuilder {
+                format: AttFindInformationResponseFormat::LONG,
+                _child_: AttFindInformationLongResponseBuilder {
+                    data: [AttFindInformationResponseLongEntryBuilder {
+                        handle: AttHandle(3).into(),
+                        uuid: Uuid::new(0x01020304).into(),
+                    }]
+                    .into()
+                }
+                .into()
+            }
+        );
+    }
+
+    fn make_db_at_boundary_handles() -> TestAttDatabase {
+        TestAttDatabase::new(vec![
+            (
+                AttAttribute {
+                    handle: AttHandle(0x0001),
+                    type_: Uuid::new(0x0102),
+                    permissions: AttPermissions::READABLE,
+                },
+                vec![4, 5],
+            ),
+            (
+                AttAttribute {
+                    handle: AttHandle(0xFFFF),
+                    type_: Uuid::new(0x0103),
+                    permissions: AttPermissions::READABLE,
+                },
+                vec![4, 5],
+            ),
+        ])
+    }
+
+    #[test]
+    fn test_full_handle_range() {
+        // arrange: attributes at the first and last handles
+        let db = make_db_at_boundary_handles();
+
+        // act: request the full handle range
+        let att_view = build_view_or_crash(AttFindInformationRequestBuilder {
+            starting_handle: AttHandle(0x0001).into(),
+            ending_handle: AttHandle(0xFFFF).into(),
+        });
+        let response = handle_find_information_request(att_view.view(), 128, &db);
+
+        // assert: both attributes are returned
+        let AttChild::AttFindInformationResponse(response) = response else {
+            unreachable!("{response:?}");
+        };
+        assert_eq!(
+            response,
+            AttFindInformationResponseBuilder {
+                format: AttFindInformationResponseFormat::SHORT,
+                _child_: AttFindInformationShortResponseBuilder {
+                    data: [
+                        AttFindInformationResponseShortEntryBuilder {
+                            handle: AttHandle(0x0001).into(),
+                            uuid: Uuid::new(0x0102).try_into().unwrap(),
+                        },
+                        AttFindInformationResponseShortEntryBuilder {
+                            handle: AttHandle(0xFFFF).into(),
+                            uuid: Uuid::new(0x0103).try_into().unwrap(),
+                        }
+                    ]
+                    .into()
+                }
+                .into()
+            }
+        );
+    }
+
+    #[test]
+    fn test_first_handle_only() {
+        let db = make_db_at_boundary_handles();
+
+        let att_view = build_view_or_crash(AttFindInformationRequestBuilder {
+            starting_handle: AttHandle(0x0001).into(),
+            ending_handle: AttHandle(0x0001).into(),
+        });
+        let response = handle_find_information_request(att_view.view(), 128, &db);
+
+        let AttChild::AttFindInformationResponse(response) = response else {
+            unreachable!("{response:?}");
+        };
+        assert_eq!(
+            response,
+            AttFindInformationResponseBuilder {
+                format: AttFindInformationResponseFormat::SHORT,
+                _child_: AttFindInformationShortResponseBuilder {
+                    data: [AttFindInformationResponseShortEntryBuilder {
+                        handle: AttHandle(0x0001).into(),
+                        uuid: Uuid::new(0x0102).try_into().unwrap(),
+                    }]
+                    .into()
+                }
+                .into()
+            }
+        );
+    }
+
+    #[test]
+    fn test_last_handle_only() {
+        let db = make_db_at_boundary_handles();
+
+        let att_view = build_view_or_crash(AttFindInformationRequestBuilder {
+            starting_handle: AttHandle(0xFFFF).into(),
+            ending_handle: AttHandle(0xFFFF).into(),
+        });
+        let response = handle_find_information_request(att_view.view(), 128, &db);
+
+        let AttChild::AttFindInformationResponse(response) = response else {
+            unreachable!("{response:?}");
+        };
+        assert_eq!(
+            response,
+            AttFindInformationResponseBuilder {
+                format: AttFindInformationResponseFormat::SHORT,
+                _child_: AttFindInformationShortResponseBuilder {
+                    data: [AttFindInformationResponseShortEntryBuilder {
+                        handle: AttHandle(0xFFFF).into(),
+                        uuid: Uuid::new(0x0103).try_into().unwrap(),
+                    }]
+                    .into()
+                }
+                .into()
+            }
+        );
+    }
+
+    #[test]
+    fn test_start_handle_zero() {
+        // arrange
+        let db = make_db_at_boundary_handles();
+
+        // act: start the range at the reserved handle 0x0000
+        let att_view = build_view_or_crash(AttFindInformationRequestBuilder {
+            starting_handle: AttHandle(0x0000).into(),
+            ending_handle: AttHandle(0xFFFF).into(),
+        });
+        let response = handle_find_information_request(att_view.view(), 128, &db);
+
+        // assert: got INVALID_HANDLE
+        let AttChild::AttErrorResponse(response) = response else {
+            unreachable!("{response:?}");
+        };
+        assert_eq!(
+            response,
+            AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::FIND_INFORMATION_REQUEST,
+                handle_in_error: AttHandle(0x0000).into(),
+                error_code: AttErrorCode::INVALID_HANDLE,
+            }
+        );
+    }
+
+    #[test]
+    fn test_empty_range_between_boundaries() {
+        // arrange
+        let db = make_db_at_boundary_handles();
+
+        // act: request every handle between the two attributes
+        let att_view = build_view_or_crash(AttFindInformationRequestBuilder {
+            starting_handle: AttHandle(0x0002).into(),
+            ending_handle: AttHandle(0xFFFE).into(),
+        });
+        let response = handle_find_information_request(att_view.view(), 128, &db);
+
+        // assert: got ATTRIBUTE_NOT_FOUND
+        let AttChild::AttErrorResponse(response) = response else {
+            unreachable!("{response:?}");
+        };
+        assert_eq!(
+            response,
+            AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::FIND_INFORMATION_REQUEST,
+                handle_in_error: AttHandle(0x0002).into(),
+                error_code: AttErrorCode::ATTRIBUTE_NOT_FOUND,
+            }
+        );
+    }
 }
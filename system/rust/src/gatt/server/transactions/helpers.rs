@@ -2,3 +2,4 @@ pub mod att_filter_by_size_type;
 pub mod att_grouping;
 pub mod att_range_filter;
 pub mod payload_accumulator;
+pub mod pdu_writer;
@@ -3,8 +3,10 @@
 
 use crate::{
     core::uuid::Uuid,
-    gatt::server::att_database::{AttAttribute, StableAttDatabase},
-    packets::{AttAttributeDataChild, AttErrorCode},
+    gatt::server::{
+        att_database::{AttAttribute, AttAttributeValue, AttDatabase},
+        att_error::AttError,
+    },
 };
 
 /// An attribute and the value
@@ -12,10 +14,11 @@ use crate::{
 pub struct AttributeWithValue {
     /// The attribute
     pub attr: AttAttribute,
-    pub value: AttAttributeDataChild,
+    /// The value, truncated to the size limit
+    pub value: AttAttributeValue,
 }
 
-/// Takes a StableAttDatabase, a range of handles, a target type, and a
+/// Takes an AttDatabase, a range of handles, a target type, and a
 /// size limit.
 ///
 /// Returns an iterator of attributes in the range and matching the type,
@@ -25,11 +28,11 @@ pub struct AttributeWithValue {
 /// Attributes are truncated to the attr_size limit before size comparison.
 /// If an error occurs while reading, do not output further attributes.
 pub async fn filter_read_attributes_by_size_type(
-    db: &impl StableAttDatabase,
+    db: &impl AttDatabase,
     attrs: impl Iterator<Item = AttAttribute>,
     target: Uuid,
     size_limit: usize,
-) -> Result<impl Iterator<Item = AttributeWithValue>, AttErrorCode> {
+) -> Result<impl Iterator<Item = AttributeWithValue>, AttError> {
     let target_attrs = attrs.filter(|attr| attr.type_ == target);
 
     let mut out = vec![];
@@ -37,8 +40,8 @@ pub async fn filter_read_attributes_by_size_type(
 
     for attr @ AttAttribute { handle, .. } in target_attrs {
         match db.read_attribute(handle).await {
-            Ok(mut value) => {
-                value.truncate(size_limit);
+            Ok(value) => {
+                let value = value.truncated(size_limit);
                 let value_size = value.len();
                 if let Some(curr_elem_size) = curr_elem_size {
                     if curr_elem_size != value_size {
@@ -49,10 +52,7 @@ pub async fn filter_read_attributes_by_size_type(
                     curr_elem_size = Some(value_size)
                 }
 
-                out.push(AttributeWithValue {
-                    attr,
-                    value: AttAttributeDataChild::RawData(value.into_boxed_slice()),
-                });
+                out.push(AttributeWithValue { attr, value });
             }
             Err(err) => {
                 if out.is_empty() {
@@ -80,7 +80,7 @@ mod test {
                 test::test_att_db::TestAttDatabase,
             },
         },
-        packets::AttAttributeDataChild,
+        packets::AttErrorCode,
     };
 
     const UUID: Uuid = Uuid::new(1234);
@@ -112,7 +112,7 @@ mod test {
             response.collect::<Vec<_>>(),
             vec![AttributeWithValue {
                 attr: db.find_attribute(AttHandle(3)).unwrap(),
-                value: AttAttributeDataChild::RawData([4, 5].into())
+                value: vec![4, 5].into()
             }]
         )
     }
@@ -162,11 +162,11 @@ mod test {
             vec![
                 AttributeWithValue {
                     attr: db.find_attribute(AttHandle(3)).unwrap(),
-                    value: AttAttributeDataChild::RawData([4, 5].into())
+                    value: vec![4, 5].into()
                 },
                 AttributeWithValue {
                     attr: db.find_attribute(AttHandle(6)).unwrap(),
-                    value: AttAttributeDataChild::RawData([6, 7].into())
+                    value: vec![6, 7].into()
                 }
             ]
         );
@@ -216,7 +216,7 @@ mod test {
             response.collect::<Vec<_>>(),
             vec![AttributeWithValue {
                 attr: db.find_attribute(AttHandle(3)).unwrap(),
-                value: AttAttributeDataChild::RawData([4, 5].into())
+                value: vec![4, 5].into()
             },]
         );
     }
@@ -247,7 +247,7 @@ mod test {
             response.collect::<Vec<_>>(),
             vec![AttributeWithValue {
                 attr: db.find_attribute(AttHandle(3)).unwrap(),
-                value: AttAttributeDataChild::RawData([4, 5].into())
+                value: vec![4, 5].into()
             },]
         );
     }
@@ -291,7 +291,7 @@ mod test {
         ));
 
         // assert: got READ_NOT_PERMITTED
-        assert!(matches!(response, Err(AttErrorCode::READ_NOT_PERMITTED)));
+        assert!(matches!(response, Err(err) if err == AttErrorCode::READ_NOT_PERMITTED));
     }
 
     #[test]
@@ -340,7 +340,7 @@ mod test {
             response.collect::<Vec<_>>(),
             vec![AttributeWithValue {
                 attr: db.find_attribute(AttHandle(3)).unwrap(),
-                value: AttAttributeDataChild::RawData([4, 5, 6].into())
+                value: vec![4, 5, 6].into()
             },]
         );
     }
@@ -391,11 +391,11 @@ mod test {
             vec![
                 AttributeWithValue {
                     attr: db.find_attribute(AttHandle(3)).unwrap(),
-                    value: AttAttributeDataChild::RawData([4, 5, 6].into())
+                    value: vec![4, 5, 6].into()
                 },
                 AttributeWithValue {
                     attr: db.find_attribute(AttHandle(5)).unwrap(),
-                    value: AttAttributeDataChild::RawData([6, 7, 8].into())
+                    value: vec![6, 7, 8].into()
                 }
             ]
         );
@@ -4,7 +4,8 @@
 
 use crate::core::uuid::Uuid;
 
-use crate::gatt::server::att_database::{AttAttribute, StableAttDatabase};
+use crate::gatt::ids::AttHandle;
+use crate::gatt::server::att_database::{AttAttribute, AttDatabase};
 use crate::gatt::server::gatt_database::{
     CHARACTERISTIC_UUID, PRIMARY_SERVICE_DECLARATION_UUID, SECONDARY_SERVICE_DECLARATION_UUID,
 };
@@ -29,19 +30,19 @@ fn get_grouping_level(uuid: Uuid) -> usize {
 /// Expects `attrs` to be in sorted order by attribute handle.
 ///
 /// Attribute grouping is defined in 5.3 Vol 3G Sec 2.5.3 Attribute Grouping
-pub fn find_group_end(
-    db: &impl StableAttDatabase,
-    group_start: AttAttribute,
-) -> Option<AttAttribute> {
+pub fn find_group_end(db: &impl AttDatabase, group_start: AttAttribute) -> Option<AttAttribute> {
     if !GROUPING_ATTRIBUTES.contains(&group_start.type_) {
         return None; // invalid / unsupported grouping attribute
     }
 
+    if group_start.handle == AttHandle::MAX {
+        return Some(group_start);
+    }
+
     Some(
-        db.list_attributes()
+        // ignore attributes at or before the current position
+        db.attributes_in_range(AttHandle(group_start.handle.0 + 1), AttHandle::MAX, None)
             .into_iter()
-            // ignore attributes at or before the current position
-            .skip_while(|attr| attr.handle <= group_start.handle)
             // consider only attributes strictly within the current group
             .take_while(|attr| {
                 get_grouping_level(attr.type_) > get_grouping_level(group_start.type_)
@@ -54,10 +55,7 @@ pub fn find_group_end(
 
 #[cfg(test)]
 mod test {
-    use crate::gatt::{
-        ids::AttHandle,
-        server::{gatt_database::AttPermissions, test::test_att_db::TestAttDatabase},
-    };
+    use crate::gatt::server::{gatt_database::AttPermissions, test::test_att_db::TestAttDatabase};
 
     use super::*;
 
@@ -1,30 +1,36 @@
 //! This module encapsulates the attribute range filtration logic used
 //! in many ATT commands, such as ATT_FIND_INFORMATION_REQ and
-//! ATT_FIND_BY_TYPE_VALUE REQ
+//! ATT_FIND_BY_TYPE_VALUE REQ, on top of the range query of the database
 
-use crate::gatt::{ids::AttHandle, server::att_database::AttAttribute};
+use crate::{
+    core::uuid::Uuid,
+    gatt::{
+        ids::AttHandle,
+        server::att_database::{AttAttribute, AttDatabase},
+    },
+};
 
-/// Filter a (sorted) iterator of attributes to those that lie within
-/// the specified range. If the range is invalid (start = 0, or start > end),
-/// return None.
+/// Get the attributes of the database that lie within the specified range
+/// (and are of the given type, if any), in sorted order. If the range is
+/// invalid (start = 0, or start > end), return None.
 pub fn filter_to_range(
+    db: &impl AttDatabase,
     start_handle: AttHandle,
     end_handle: AttHandle,
-    attrs: impl Iterator<Item = AttAttribute> + Clone,
+    type_filter: Option<Uuid>,
 ) -> Option<impl Iterator<Item = AttAttribute> + Clone> {
     if start_handle.0 == 0 || end_handle < start_handle {
         return None;
     }
-    Some(
-        attrs
-            .skip_while(move |attr| attr.handle < start_handle)
-            .take_while(move |attr| attr.handle <= end_handle),
-    )
+    Some(db.attributes_in_range(start_handle, end_handle, type_filter).into_iter())
 }
 
 #[cfg(test)]
 mod test {
-    use crate::gatt::server::{gatt_database::AttPermissions, gatt_database::CHARACTERISTIC_UUID};
+    use crate::gatt::server::{
+        gatt_database::{AttPermissions, CHARACTERISTIC_UUID, PRIMARY_SERVICE_DECLARATION_UUID},
+        test::test_att_db::TestAttDatabase,
+    };
 
     use super::*;
 
@@ -36,9 +42,13 @@ mod test {
         }
     }
 
+    fn db_from_attrs(attrs: impl IntoIterator<Item = AttAttribute>) -> TestAttDatabase {
+        TestAttDatabase::new(attrs.into_iter().map(|attr| (attr, vec![])).collect())
+    }
+
     #[test]
     fn test_invalid_start_handle() {
-        let res = filter_to_range(AttHandle(0), AttHandle(2), [].into_iter());
+        let res = filter_to_range(&db_from_attrs([]), AttHandle(0), AttHandle(2), None);
 
         assert!(res.is_none())
     }
@@ -46,7 +56,7 @@ mod test {
     #[test]
     fn test_invalid_range() {
         // call with a range where end < start
-        let res = filter_to_range(AttHandle(3), AttHandle(1), [].into_iter());
+        let res = filter_to_range(&db_from_attrs([]), AttHandle(3), AttHandle(1), None);
 
         assert!(res.is_none())
     }
@@ -55,22 +65,30 @@ mod test {
     fn test_trivial_range() {
         // call with a range where start == end, make sure it gets the relevant
         // attribute
-        let res =
-            filter_to_range(AttHandle(3), AttHandle(3), [attr(2), attr(3), attr(4)].into_iter())
-                .unwrap();
+        let db = db_from_attrs([attr(2), attr(3), attr(4)]);
+
+        let res = filter_to_range(&db, AttHandle(3), AttHandle(3), None).unwrap();
 
         assert_eq!(res.collect::<Vec<_>>(), vec![attr(3)])
     }
 
     #[test]
     fn test_nontrivial_range() {
-        let res = filter_to_range(
-            AttHandle(3),
-            AttHandle(4),
-            [attr(2), attr(3), attr(4), attr(5)].into_iter(),
-        )
-        .unwrap();
+        let db = db_from_attrs([attr(2), attr(3), attr(4), attr(5)]);
+
+        let res = filter_to_range(&db, AttHandle(3), AttHandle(4), None).unwrap();
 
         assert_eq!(res.collect::<Vec<_>>(), vec![attr(3), attr(4)])
     }
+
+    #[test]
+    fn test_type_filter() {
+        let service = AttAttribute { type_: PRIMARY_SERVICE_DECLARATION_UUID, ..attr(3) };
+        let db = db_from_attrs([attr(2), service, attr(4)]);
+
+        let res =
+            filter_to_range(&db, AttHandle(2), AttHandle(4), Some(CHARACTERISTIC_UUID)).unwrap();
+
+        assert_eq!(res.collect::<Vec<_>>(), vec![attr(2), attr(4)])
+    }
 }
@@ -0,0 +1,108 @@
+//! A writer that serializes the body of a response PDU directly into a single
+//! buffer, allocated up front at the size of the MTU, rather than first
+//! assembling a builder per element and serializing them all at the end.
+
+use crate::packets::{AttBuilder, AttChild, AttOpcode};
+
+pub struct PduWriter {
+    opcode: AttOpcode,
+    buf: Vec<u8>,
+    lim: usize,
+}
+
+impl PduWriter {
+    /// Constructor. The size is that of the PDU body (i.e. excluding the
+    /// opcode), which is never exceeded.
+    pub fn new(opcode: AttOpcode, size: usize) -> Self {
+        Self { opcode, buf: Vec::with_capacity(size), lim: size }
+    }
+
+    /// Append the given fields, provided that they all fit. Either all of them
+    /// are written, or none are.
+    #[must_use]
+    pub fn push(&mut self, fields: &[&[u8]]) -> bool {
+        let elem_size = fields.iter().map(|field| field.len()).sum::<usize>();
+        if self.buf.len() + elem_size > self.lim {
+            return false;
+        }
+        for field in fields {
+            self.buf.extend_from_slice(field);
+        }
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Wrap the serialized body into a PDU, without copying it
+    pub fn into_builder(self) -> AttBuilder {
+        AttBuilder { opcode: self.opcode, _child_: AttChild::RawData(self.buf.into_boxed_slice()) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::packets::{AttBuilder, AttChild, AttOpcode};
+
+    use super::PduWriter;
+
+    #[test]
+    fn test_empty() {
+        let writer = PduWriter::new(AttOpcode::READ_BY_TYPE_RESPONSE, 0);
+        assert!(writer.is_empty())
+    }
+
+    #[test]
+    fn test_nonempty() {
+        let mut writer = PduWriter::new(AttOpcode::READ_BY_TYPE_RESPONSE, 4);
+
+        let ok = writer.push(&[&[1], &[2, 3]]);
+
+        assert!(ok);
+        assert!(!writer.is_empty());
+        assert_eq!(
+            writer.into_builder(),
+            AttBuilder {
+                opcode: AttOpcode::READ_BY_TYPE_RESPONSE,
+                _child_: AttChild::RawData([1, 2, 3].into())
+            }
+        );
+    }
+
+    #[test]
+    fn test_push_exactly_full() {
+        let mut writer = PduWriter::new(AttOpcode::READ_BY_TYPE_RESPONSE, 3);
+
+        let ok = writer.push(&[&[1, 2, 3]]);
+
+        assert!(ok);
+    }
+
+    #[test]
+    fn test_push_overflow_writes_nothing() {
+        let mut writer = PduWriter::new(AttOpcode::READ_BY_TYPE_RESPONSE, 4);
+        assert!(writer.push(&[&[1, 2]]));
+
+        let ok = writer.push(&[&[3], &[4, 5]]);
+
+        assert!(!ok);
+        assert_eq!(
+            writer.into_builder(),
+            AttBuilder {
+                opcode: AttOpcode::READ_BY_TYPE_RESPONSE,
+                _child_: AttChild::RawData([1, 2].into())
+            }
+        );
+    }
+
+    #[test]
+    fn test_never_reallocates() {
+        let mut writer = PduWriter::new(AttOpcode::READ_BY_TYPE_RESPONSE, 8);
+        let buf = writer.buf.as_ptr();
+
+        while writer.push(&[&[1, 2, 3]]) {}
+
+        assert_eq!(writer.buf.as_ptr(), buf);
+    }
+}
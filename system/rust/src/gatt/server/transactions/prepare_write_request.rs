@@ -0,0 +1,660 @@
+//! This module handles the queued write procedure (ATT_PREPARE_WRITE_REQ +
+//! ATT_EXECUTE_WRITE_REQ), used to write attribute values that do not fit in a
+//! single ATT_WRITE_REQ. See Core Spec 5.3 Vol 3F 3.4.6.
+
+use log::{trace, warn};
+
+use crate::{
+    gatt::{
+        ids::AttHandle,
+        server::{att_database::AttDatabase, config::GattServerConfig},
+    },
+    packets::{
+        AttAttributeDataBuilder, AttAttributeDataChild, AttChild, AttErrorCode,
+        AttErrorResponseBuilder, AttExecuteWriteFlags, AttExecuteWriteRequestView,
+        AttExecuteWriteResponseBuilder, AttOpcode, AttPrepareWriteRequestView,
+        AttPrepareWriteResponseBuilder,
+    },
+};
+
+/// A single buffered ATT_PREPARE_WRITE_REQ
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PreparedWrite {
+    handle: AttHandle,
+    offset: usize,
+    value: Vec<u8>,
+}
+
+/// The value assembled for an attribute, as its handle, the offset of its
+/// first write, and the value from that offset onwards
+type AssembledWrite = (AttHandle, usize, Vec<u8>);
+
+/// The prepared writes buffered on a single bearer. These are only committed
+/// to the database once an ATT_EXECUTE_WRITE_REQ is received.
+#[derive(Debug, Default)]
+pub struct PreparedWriteQueue {
+    writes: Vec<PreparedWrite>,
+}
+
+impl PreparedWriteQueue {
+    /// Constructor
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The number of writes currently buffered
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    /// The total size of the values of the writes currently buffered
+    fn buffered_bytes(&self) -> usize {
+        self.writes.iter().map(|write| write.value.len()).sum()
+    }
+
+    /// Assemble the buffered writes into the final value of each attribute
+    /// (from the offset of its first write onwards), in the order in which
+    /// each attribute was first prepared.
+    ///
+    /// The writes for a given attribute must be contiguous (as produced by the
+    /// Write Long Characteristic Values procedure, Core Spec 5.3 Vol 3G
+    /// 4.9.4), since the offset of each write is validated against the range
+    /// assembled so far. Whether the first write lies within the current value
+    /// is left to the database. The assembled values must also fit within the
+    /// maximum attribute length (Core Spec 5.3 Vol 3F 3.4.6.3).
+    fn assemble(
+        &self,
+        config: &GattServerConfig,
+    ) -> Result<Vec<AssembledWrite>, (AttHandle, AttErrorCode)> {
+        let mut out: Vec<AssembledWrite> = vec![];
+        for PreparedWrite { handle, offset, value } in &self.writes {
+            let idx = match out.iter().position(|(curr, _, _)| curr == handle) {
+                Some(idx) => idx,
+                None => {
+                    out.push((*handle, *offset, vec![]));
+                    out.len() - 1
+                }
+            };
+            let (_, start, assembled) = &mut out[idx];
+            if *offset < *start || *offset - *start > assembled.len() {
+                warn!(
+                    "prepared write to {handle:?} at offset {offset} is outside the assembled range {start}..{}",
+                    *start + assembled.len()
+                );
+                return Err((*handle, AttErrorCode::INVALID_OFFSET));
+            }
+            assembled.truncate(*offset - *start);
+            assembled.extend_from_slice(value);
+            if let Err(error_code) = config.check_attribute_length(*start, assembled.len()) {
+                warn!("prepared writes to {handle:?} exceed the maximum value length");
+                return Err((*handle, error_code));
+            }
+        }
+        Ok(out)
+    }
+}
+
+pub fn handle_prepare_write_request(
+    request: AttPrepareWriteRequestView<'_>,
+    queue: &mut PreparedWriteQueue,
+    db: &impl AttDatabase,
+) -> AttChild {
+    let handle: AttHandle = request.get_handle().into();
+    let offset = request.get_offset();
+    let value = request.get_value().get_raw_payload().collect::<Vec<_>>();
+
+    let error_code = match db.attributes_in_range(handle, handle, None).into_iter().next() {
+        None => Some(AttErrorCode::INVALID_HANDLE),
+        Some(attr) if !attr.permissions.writable_with_response() => {
+            Some(AttErrorCode::WRITE_NOT_PERMITTED)
+        }
+        // bound the queue, so a client cannot make us buffer arbitrarily many writes
+        Some(_) => db
+            .server_config()
+            .check_prepared_write(queue.len(), queue.buffered_bytes(), value.len())
+            .err(),
+    };
+    if error_code == Some(AttErrorCode::PREPARE_QUEUE_FULL) {
+        warn!("rejecting prepared write to {handle:?}, since the queue is full");
+    }
+    if let Some(error_code) = error_code {
+        return AttErrorResponseBuilder {
+            opcode_in_error: AttOpcode::PREPARE_WRITE_REQUEST,
+            handle_in_error: handle.into(),
+            error_code,
+        }
+        .into();
+    }
+
+    trace!("queueing prepared write to {handle:?} at offset {offset}");
+    queue.writes.push(PreparedWrite { handle, offset: offset.into(), value });
+
+    // As per Core Spec 5.3 Vol 3F 3.4.6.2, the response echoes the request. We
+    // echo what was actually queued, so a client verifying a reliable write
+    // (Core Spec 5.3 Vol 3G 4.9.5) checks the bytes that will be committed.
+    let queued = queue.writes.last().expect("a write was just queued");
+    AttPrepareWriteResponseBuilder {
+        handle: queued.handle.into(),
+        offset,
+        value: AttAttributeDataBuilder {
+            _child_: AttAttributeDataChild::RawData(queued.value.clone().into_boxed_slice()),
+        },
+    }
+    .into()
+}
+
+pub async fn handle_execute_write_request(
+    request: AttExecuteWriteRequestView<'_>,
+    queue: &mut PreparedWriteQueue,
+    db: &impl AttDatabase,
+) -> AttChild {
+    // the queue is cleared whether or not the execution succeeds
+    // (Core Spec 5.3 Vol 3F 3.4.6.3)
+    let pending = std::mem::take(queue);
+
+    if request.get_flags() == AttExecuteWriteFlags::CANCEL {
+        trace!("cancelling {} prepared writes", pending.writes.len());
+        return AttExecuteWriteResponseBuilder {}.into();
+    }
+
+    // validate every write before committing any of them, so that a write
+    // rejected by the server (e.g. for its offset, length or permissions)
+    // leaves every attribute unchanged
+    let assembled = pending.assemble(&db.server_config()).and_then(|assembled| {
+        for (handle, offset, value) in &assembled {
+            db.check_write(*handle, *offset as u32, value)
+                .map_err(|error| (*handle, error.report(AttOpcode::EXECUTE_WRITE_REQUEST)))?;
+        }
+        Ok(assembled)
+    });
+    let assembled = match assembled {
+        Ok(assembled) => assembled,
+        Err((handle, error_code)) => {
+            return AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::EXECUTE_WRITE_REQUEST,
+                handle_in_error: handle.into(),
+                error_code,
+            }
+            .into()
+        }
+    };
+
+    // The owners of the values (or the AuthorizationProvider) may still reject
+    // a write once it is committed. Since each owner commits its writes
+    // independently, those made before it then stay applied, and the rest are
+    // not attempted.
+    for (handle, offset, value) in assembled {
+        if let Err(error) = db.write_attribute(handle, offset as u32, &value).await {
+            warn!("execute write failed on {handle:?}, after committing the writes before it");
+            return AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::EXECUTE_WRITE_REQUEST,
+                handle_in_error: handle.into(),
+                error_code: error.report(AttOpcode::EXECUTE_WRITE_REQUEST),
+            }
+            .into();
+        }
+    }
+
+    AttExecuteWriteResponseBuilder {}.into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio_test::block_on;
+
+    use crate::{
+        core::uuid::Uuid,
+        gatt::server::{
+            att_database::{AttAttribute, AttPermissions, MAX_ATTRIBUTE_VALUE_LEN},
+            config::DEFAULT_MAX_PREPARED_WRITES,
+            test::test_att_db::TestAttDatabase,
+        },
+        packets::{AttExecuteWriteRequestBuilder, AttPrepareWriteRequestBuilder},
+        utils::packet::{build_att_data, build_view_or_crash},
+    };
+
+    const HANDLE: AttHandle = AttHandle(1);
+    const ANOTHER_HANDLE: AttHandle = AttHandle(2);
+    const READ_ONLY_HANDLE: AttHandle = AttHandle(3);
+    const INVALID_HANDLE: AttHandle = AttHandle(4);
+
+    fn make_db() -> TestAttDatabase {
+        TestAttDatabase::new(vec![
+            (
+                AttAttribute {
+                    handle: HANDLE,
+                    type_: Uuid::new(0x1234),
+                    permissions: AttPermissions::READABLE | AttPermissions::WRITABLE_WITH_RESPONSE,
+                },
+                vec![9, 9, 9],
+            ),
+            (
+                AttAttribute {
+                    handle: ANOTHER_HANDLE,
+                    type_: Uuid::new(0x1234),
+                    permissions: AttPermissions::READABLE | AttPermissions::WRITABLE_WITH_RESPONSE,
+                },
+                vec![],
+            ),
+            (
+                AttAttribute {
+                    handle: READ_ONLY_HANDLE,
+                    type_: Uuid::new(0x1234),
+                    permissions: AttPermissions::READABLE,
+                },
+                vec![],
+            ),
+        ])
+    }
+
+    fn prepare(
+        queue: &mut PreparedWriteQueue,
+        db: &TestAttDatabase,
+        handle: AttHandle,
+        offset: u16,
+        value: &[u8],
+    ) -> AttChild {
+        let att_view = build_view_or_crash(AttPrepareWriteRequestBuilder {
+            handle: handle.into(),
+            offset,
+            value: build_att_data(AttAttributeDataChild::RawData(value.into())),
+        });
+        handle_prepare_write_request(att_view.view(), queue, db)
+    }
+
+    fn execute(
+        queue: &mut PreparedWriteQueue,
+        db: &TestAttDatabase,
+        flags: AttExecuteWriteFlags,
+    ) -> AttChild {
+        let att_view = build_view_or_crash(AttExecuteWriteRequestBuilder { flags });
+        block_on(handle_execute_write_request(att_view.view(), queue, db))
+    }
+
+    #[test]
+    fn test_prepare_write_echoes_request() {
+        // arrange
+        let db = make_db();
+        let mut queue = PreparedWriteQueue::new();
+
+        // act
+        let resp = prepare(&mut queue, &db, HANDLE, 0, &[1, 2]);
+
+        // assert: the request was echoed, and nothing was written yet
+        assert_eq!(
+            resp,
+            AttPrepareWriteResponseBuilder {
+                handle: HANDLE.into(),
+                offset: 0,
+                value: build_att_data(AttAttributeDataChild::RawData([1, 2].into())),
+            }
+            .into()
+        );
+        assert_ne!(queue.len(), 0);
+        assert_eq!(block_on(db.read_attribute(HANDLE)).unwrap(), vec![9, 9, 9]);
+    }
+
+    #[test]
+    fn test_prepare_queue_full_with_many_fragments() {
+        // arrange
+        let db = make_db();
+        let mut queue = PreparedWriteQueue::new();
+
+        // act: a hostile client prepares thousands of one-byte fragments
+        let responses = (0..5000u16)
+            .map(|offset| prepare(&mut queue, &db, HANDLE, offset, &[1]))
+            .collect::<Vec<_>>();
+
+        // assert: only the first fragments were queued, and the rest were rejected
+        assert_eq!(queue.len(), DEFAULT_MAX_PREPARED_WRITES);
+        assert!(responses[..DEFAULT_MAX_PREPARED_WRITES]
+            .iter()
+            .all(|resp| matches!(resp, AttChild::AttPrepareWriteResponse(_))));
+        assert!(responses[DEFAULT_MAX_PREPARED_WRITES..].iter().all(|resp| *resp
+            == AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::PREPARE_WRITE_REQUEST,
+                handle_in_error: HANDLE.into(),
+                error_code: AttErrorCode::PREPARE_QUEUE_FULL,
+            }
+            .into()));
+    }
+
+    #[test]
+    fn test_prepare_queue_full_by_size() {
+        // arrange: a queue that can hold 4 bytes
+        let db = make_db();
+        db.set_config(GattServerConfig { max_prepared_write_bytes: 4, ..Default::default() });
+        let mut queue = PreparedWriteQueue::new();
+        prepare(&mut queue, &db, HANDLE, 0, &[1, 2, 3]);
+
+        // act: prepare a write that would overflow it
+        let resp = prepare(&mut queue, &db, HANDLE, 3, &[4, 5]);
+
+        // assert: it was rejected, and the queue was left as-is
+        assert_eq!(
+            resp,
+            AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::PREPARE_WRITE_REQUEST,
+                handle_in_error: HANDLE.into(),
+                error_code: AttErrorCode::PREPARE_QUEUE_FULL,
+            }
+            .into()
+        );
+        assert_eq!(queue.len(), 1);
+        assert_eq!(
+            execute(&mut queue, &db, AttExecuteWriteFlags::EXECUTE),
+            AttExecuteWriteResponseBuilder {}.into()
+        );
+        assert_eq!(block_on(db.read_attribute(HANDLE)).unwrap(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_prepare_queue_reusable_after_execute() {
+        // arrange: a full queue
+        let db = make_db();
+        db.set_config(GattServerConfig { max_prepared_writes: 1, ..Default::default() });
+        let mut queue = PreparedWriteQueue::new();
+        prepare(&mut queue, &db, HANDLE, 0, &[1]);
+        execute(&mut queue, &db, AttExecuteWriteFlags::CANCEL);
+
+        // act
+        let resp = prepare(&mut queue, &db, HANDLE, 0, &[2]);
+
+        // assert: the queue accepted writes again once drained
+        assert!(matches!(resp, AttChild::AttPrepareWriteResponse(_)));
+    }
+
+    #[test]
+    fn test_prepare_write_invalid_handle() {
+        let db = make_db();
+        let mut queue = PreparedWriteQueue::new();
+
+        let resp = prepare(&mut queue, &db, INVALID_HANDLE, 0, &[1, 2]);
+
+        assert_eq!(
+            resp,
+            AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::PREPARE_WRITE_REQUEST,
+                handle_in_error: INVALID_HANDLE.into(),
+                error_code: AttErrorCode::INVALID_HANDLE,
+            }
+            .into()
+        );
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn test_prepare_write_not_writable() {
+        let db = make_db();
+        let mut queue = PreparedWriteQueue::new();
+
+        let resp = prepare(&mut queue, &db, READ_ONLY_HANDLE, 0, &[1, 2]);
+
+        assert_eq!(
+            resp,
+            AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::PREPARE_WRITE_REQUEST,
+                handle_in_error: READ_ONLY_HANDLE.into(),
+                error_code: AttErrorCode::WRITE_NOT_PERMITTED,
+            }
+            .into()
+        );
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn test_execute_long_write() {
+        // arrange: a long write split across three prepared writes
+        let db = make_db();
+        let mut queue = PreparedWriteQueue::new();
+        prepare(&mut queue, &db, HANDLE, 0, &[1, 2]);
+        prepare(&mut queue, &db, HANDLE, 2, &[3, 4]);
+        prepare(&mut queue, &db, HANDLE, 4, &[5]);
+
+        // act
+        let resp = execute(&mut queue, &db, AttExecuteWriteFlags::EXECUTE);
+
+        // assert: the assembled value was written
+        assert_eq!(resp, AttExecuteWriteResponseBuilder {}.into());
+        assert_eq!(block_on(db.read_attribute(HANDLE)).unwrap(), vec![1, 2, 3, 4, 5]);
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn test_execute_overlapping_writes() {
+        let db = make_db();
+        let mut queue = PreparedWriteQueue::new();
+        prepare(&mut queue, &db, HANDLE, 0, &[1, 2, 3]);
+        prepare(&mut queue, &db, HANDLE, 1, &[4]);
+
+        execute(&mut queue, &db, AttExecuteWriteFlags::EXECUTE);
+
+        assert_eq!(block_on(db.read_attribute(HANDLE)).unwrap(), vec![1, 4]);
+    }
+
+    #[test]
+    fn test_execute_multiple_handles() {
+        let db = make_db();
+        let mut queue = PreparedWriteQueue::new();
+        prepare(&mut queue, &db, HANDLE, 0, &[1]);
+        prepare(&mut queue, &db, ANOTHER_HANDLE, 0, &[2]);
+        prepare(&mut queue, &db, HANDLE, 1, &[3]);
+
+        let resp = execute(&mut queue, &db, AttExecuteWriteFlags::EXECUTE);
+
+        assert_eq!(resp, AttExecuteWriteResponseBuilder {}.into());
+        assert_eq!(block_on(db.read_attribute(HANDLE)).unwrap(), vec![1, 3]);
+        assert_eq!(block_on(db.read_attribute(ANOTHER_HANDLE)).unwrap(), vec![2]);
+    }
+
+    #[test]
+    fn test_execute_invalid_offset_is_atomic() {
+        // arrange: a valid write to one handle, and a gap in the writes to another
+        let db = make_db();
+        let mut queue = PreparedWriteQueue::new();
+        prepare(&mut queue, &db, ANOTHER_HANDLE, 0, &[1]);
+        prepare(&mut queue, &db, HANDLE, 0, &[1, 2]);
+        prepare(&mut queue, &db, HANDLE, 3, &[3]);
+
+        // act
+        let resp = execute(&mut queue, &db, AttExecuteWriteFlags::EXECUTE);
+
+        // assert: we got INVALID_OFFSET on the offending handle, and nothing was written
+        assert_eq!(
+            resp,
+            AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::EXECUTE_WRITE_REQUEST,
+                handle_in_error: HANDLE.into(),
+                error_code: AttErrorCode::INVALID_OFFSET,
+            }
+            .into()
+        );
+        assert_eq!(block_on(db.read_attribute(HANDLE)).unwrap(), vec![9, 9, 9]);
+        assert_eq!(block_on(db.read_attribute(ANOTHER_HANDLE)).unwrap(), vec![]);
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn test_execute_write_at_offset() {
+        // arrange: a write to the tail of an existing value
+        let db = make_db();
+        let mut queue = PreparedWriteQueue::new();
+        prepare(&mut queue, &db, HANDLE, 2, &[1, 2]);
+        prepare(&mut queue, &db, HANDLE, 4, &[3]);
+
+        // act
+        let resp = execute(&mut queue, &db, AttExecuteWriteFlags::EXECUTE);
+
+        // assert: the value was replaced from the first offset onwards
+        assert_eq!(resp, AttExecuteWriteResponseBuilder {}.into());
+        assert_eq!(block_on(db.read_attribute(HANDLE)).unwrap(), vec![9, 9, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_execute_invalid_offset_in_middle_of_queue_commits_nothing() {
+        // arrange: valid writes around one starting after the end of its value
+        let db = make_db();
+        let mut queue = PreparedWriteQueue::new();
+        prepare(&mut queue, &db, HANDLE, 0, &[1, 2]);
+        prepare(&mut queue, &db, ANOTHER_HANDLE, 1, &[3]);
+        prepare(&mut queue, &db, HANDLE, 2, &[4]);
+
+        // act
+        let resp = execute(&mut queue, &db, AttExecuteWriteFlags::EXECUTE);
+
+        // assert: the offset was checked before committing the first write
+        assert_eq!(
+            resp,
+            AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::EXECUTE_WRITE_REQUEST,
+                handle_in_error: ANOTHER_HANDLE.into(),
+                error_code: AttErrorCode::INVALID_OFFSET,
+            }
+            .into()
+        );
+        assert_eq!(block_on(db.read_attribute(HANDLE)).unwrap(), vec![9, 9, 9]);
+        assert_eq!(block_on(db.read_attribute(ANOTHER_HANDLE)).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_execute_rejected_by_owner_in_middle_of_queue() {
+        // arrange: the owner of the second value rejects its write
+        let db = make_db();
+        let mut queue = PreparedWriteQueue::new();
+        prepare(&mut queue, &db, ANOTHER_HANDLE, 0, &[1]);
+        prepare(&mut queue, &db, HANDLE, 0, &[2]);
+        db.fail_next_write(HANDLE, AttErrorCode::VALUE_NOT_ALLOWED);
+
+        // act
+        let resp = execute(&mut queue, &db, AttExecuteWriteFlags::EXECUTE);
+
+        // assert: the error is reported, and only the writes before it stay applied
+        assert_eq!(
+            resp,
+            AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::EXECUTE_WRITE_REQUEST,
+                handle_in_error: HANDLE.into(),
+                error_code: AttErrorCode::VALUE_NOT_ALLOWED,
+            }
+            .into()
+        );
+        assert_eq!(block_on(db.read_attribute(ANOTHER_HANDLE)).unwrap(), vec![1]);
+        assert_eq!(block_on(db.read_attribute(HANDLE)).unwrap(), vec![9, 9, 9]);
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn test_execute_write_before_first_offset() {
+        let db = make_db();
+        let mut queue = PreparedWriteQueue::new();
+        prepare(&mut queue, &db, HANDLE, 2, &[1, 2]);
+        prepare(&mut queue, &db, HANDLE, 1, &[3]);
+
+        let resp = execute(&mut queue, &db, AttExecuteWriteFlags::EXECUTE);
+
+        assert_eq!(
+            resp,
+            AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::EXECUTE_WRITE_REQUEST,
+                handle_in_error: HANDLE.into(),
+                error_code: AttErrorCode::INVALID_OFFSET,
+            }
+            .into()
+        );
+        assert_eq!(block_on(db.read_attribute(HANDLE)).unwrap(), vec![9, 9, 9]);
+    }
+
+    #[test]
+    fn test_execute_write_past_end_of_value() {
+        // arrange: a write starting after the end of the current value
+        let db = make_db();
+        let mut queue = PreparedWriteQueue::new();
+        prepare(&mut queue, &db, HANDLE, 4, &[1]);
+
+        // act
+        let resp = execute(&mut queue, &db, AttExecuteWriteFlags::EXECUTE);
+
+        // assert: the database rejected the offset
+        assert_eq!(
+            resp,
+            AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::EXECUTE_WRITE_REQUEST,
+                handle_in_error: HANDLE.into(),
+                error_code: AttErrorCode::INVALID_OFFSET,
+            }
+            .into()
+        );
+        assert_eq!(block_on(db.read_attribute(HANDLE)).unwrap(), vec![9, 9, 9]);
+    }
+
+    #[test]
+    fn test_execute_write_too_long() {
+        let db = make_db();
+        let mut queue = PreparedWriteQueue::new();
+        prepare(&mut queue, &db, ANOTHER_HANDLE, 0, &[0; MAX_ATTRIBUTE_VALUE_LEN]);
+        prepare(&mut queue, &db, ANOTHER_HANDLE, MAX_ATTRIBUTE_VALUE_LEN as u16, &[0]);
+
+        let resp = execute(&mut queue, &db, AttExecuteWriteFlags::EXECUTE);
+
+        assert_eq!(
+            resp,
+            AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::EXECUTE_WRITE_REQUEST,
+                handle_in_error: ANOTHER_HANDLE.into(),
+                error_code: AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH,
+            }
+            .into()
+        );
+        assert_eq!(block_on(db.read_attribute(ANOTHER_HANDLE)).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_execute_write_too_long_commits_nothing() {
+        // arrange: a valid write, followed by one that is too long
+        let db = make_db();
+        let mut queue = PreparedWriteQueue::new();
+        prepare(&mut queue, &db, HANDLE, 0, &[1, 2]);
+        prepare(&mut queue, &db, ANOTHER_HANDLE, 0, &[0; MAX_ATTRIBUTE_VALUE_LEN]);
+        prepare(&mut queue, &db, ANOTHER_HANDLE, MAX_ATTRIBUTE_VALUE_LEN as u16, &[0]);
+
+        // act
+        let resp = execute(&mut queue, &db, AttExecuteWriteFlags::EXECUTE);
+
+        // assert: the length was checked before committing the first write
+        assert_eq!(
+            resp,
+            AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::EXECUTE_WRITE_REQUEST,
+                handle_in_error: ANOTHER_HANDLE.into(),
+                error_code: AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH,
+            }
+            .into()
+        );
+        assert_eq!(block_on(db.read_attribute(HANDLE)).unwrap(), vec![9, 9, 9]);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let db = make_db();
+        let mut queue = PreparedWriteQueue::new();
+        prepare(&mut queue, &db, HANDLE, 0, &[1, 2]);
+
+        let resp = execute(&mut queue, &db, AttExecuteWriteFlags::CANCEL);
+
+        assert_eq!(resp, AttExecuteWriteResponseBuilder {}.into());
+        assert_eq!(block_on(db.read_attribute(HANDLE)).unwrap(), vec![9, 9, 9]);
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn test_execute_empty_queue() {
+        let db = make_db();
+        let mut queue = PreparedWriteQueue::new();
+
+        let resp = execute(&mut queue, &db, AttExecuteWriteFlags::EXECUTE);
+
+        assert_eq!(resp, AttExecuteWriteResponseBuilder {}.into());
+    }
+}
@@ -0,0 +1,410 @@
+use log::warn;
+
+use crate::{
+    gatt::{
+        ids::AttHandle,
+        server::{att_database::AttDatabase, config::TornReadPolicy},
+    },
+    packets::{
+        AttAttributeDataBuilder, AttAttributeDataChild, AttChild, AttErrorCode,
+        AttErrorResponseBuilder, AttOpcode, AttReadBlobRequestView, AttReadBlobResponseBuilder,
+    },
+};
+
+/// Remembers the value last read on a bearer (by an ATT_READ_REQ or an
+/// ATT_READ_BLOB_REQ), so that the following segments of a long read can be
+/// checked against it
+#[derive(Debug, Default)]
+pub struct LongReadTracker {
+    last_read: Option<LastRead>,
+}
+
+#[derive(Debug)]
+struct LastRead {
+    handle: AttHandle,
+    /// The version of the value, sampled before it was read
+    version: Option<u64>,
+    /// The length of the entire value
+    len: usize,
+}
+
+impl LongReadTracker {
+    /// Constructor
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Whether a read of the attribute from the given offset, which returned
+    /// the given number of bytes of the given version of its value, reads the
+    /// same value as the previous segments of the long read it continues (if
+    /// any). A read from offset 0 starts a new long read.
+    pub fn continues_last_read(
+        &self,
+        handle: AttHandle,
+        offset: usize,
+        version: Option<u64>,
+        len: usize,
+    ) -> bool {
+        offset == 0
+            || self
+                .last_read
+                .as_ref()
+                .filter(|last| last.handle == handle)
+                .map(|last| last.version == version && last.len == offset + len)
+                .unwrap_or(true)
+    }
+
+    /// Record a read of the attribute, as in continues_last_read()
+    pub fn on_read(&mut self, handle: AttHandle, offset: usize, version: Option<u64>, len: usize) {
+        self.last_read = Some(LastRead { handle, version, len: offset + len });
+    }
+}
+
+/// If lenient, the request is served even when the value could have been read
+/// in its entirety with an ATT_READ_REQ (see interop::Quirk::LenientReadBlob)
+pub async fn handle_read_blob_request<T: AttDatabase>(
+    request: AttReadBlobRequestView<'_>,
+    mtu: usize,
+    lenient: bool,
+    long_reads: &mut LongReadTracker,
+    db: &T,
+) -> AttChild {
+    let handle = request.get_attribute_handle().into();
+    let offset = request.get_offset() as usize;
+
+    // the version is sampled first, so a change made while the value is read
+    // is noticed by the next segment
+    let version = db.value_version(handle);
+    // the database validates the offset, since it may hold only part of the value
+    let error_code = match db.read_attribute_at(handle, offset as u32).await {
+        // As per 5.3 3F 3.4.4.5 ATT_READ_BLOB_REQ, if the value could have been read
+        // in its entirety using an ATT_READ_REQ (i.e. it is at most MTU - 1 bytes
+        // long), we may reject the request
+        Ok(data) if !lenient && offset != 0 && offset + data.len() < mtu => {
+            AttErrorCode::ATTRIBUTE_NOT_LONG
+        }
+        Ok(data)
+            if !long_reads.continues_last_read(handle, offset, version, data.len())
+                && db.server_config().torn_reads == TornReadPolicy::Fail =>
+        {
+            // the segments read so far are kept as the baseline, so the rest of
+            // this long read fails too, until the client restarts it
+            warn!("{handle:?} changed in the middle of a long read, at offset {offset}");
+            AttErrorCode::UNLIKELY_ERROR
+        }
+        Ok(data) => {
+            long_reads.on_read(handle, offset, version, data.len());
+            // as per 5.3 3F 3.4.4.6 ATT_READ_BLOB_RSP, we truncate to MTU - 1, so only
+            // this part of the (shared) value is copied
+            let data = &data[..data.len().min(mtu - 1)];
+            return AttReadBlobResponseBuilder {
+                value: AttAttributeDataBuilder {
+                    _child_: AttAttributeDataChild::RawData(data.into()),
+                },
+            }
+            .into();
+        }
+        Err(error) => error.report(AttOpcode::READ_BLOB_REQUEST),
+    };
+
+    AttErrorResponseBuilder {
+        opcode_in_error: AttOpcode::READ_BLOB_REQUEST,
+        handle_in_error: handle.into(),
+        error_code,
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::{
+        core::uuid::Uuid,
+        gatt::{
+            ids::AttHandle,
+            server::{
+                att_database::{AttAttribute, AttPermissions},
+                config::GattServerConfig,
+                test::test_att_db::TestAttDatabase,
+            },
+        },
+        packets::{AttReadBlobRequestBuilder, Serializable},
+        utils::packet::{build_att_data, build_view_or_crash},
+    };
+
+    const HANDLE: AttHandle = AttHandle(3);
+
+    fn make_db_with_value(value: Vec<u8>) -> TestAttDatabase {
+        TestAttDatabase::new(vec![(
+            AttAttribute {
+                handle: HANDLE,
+                type_: Uuid::new(0x1234),
+                permissions: AttPermissions::READABLE,
+            },
+            value,
+        )])
+    }
+
+    fn do_read_blob_request(
+        handle: AttHandle,
+        offset: u16,
+        mtu: usize,
+        db: &TestAttDatabase,
+    ) -> AttChild {
+        let att_view = build_view_or_crash(AttReadBlobRequestBuilder {
+            attribute_handle: handle.into(),
+            offset,
+        });
+        tokio_test::block_on(handle_read_blob_request(
+            att_view.view(),
+            mtu,
+            false,
+            &mut LongReadTracker::new(),
+            db,
+        ))
+    }
+
+    fn make_error(handle: AttHandle, error_code: AttErrorCode) -> AttChild {
+        AttErrorResponseBuilder {
+            opcode_in_error: AttOpcode::READ_BLOB_REQUEST,
+            handle_in_error: handle.into(),
+            error_code,
+        }
+        .into()
+    }
+
+    #[test]
+    fn test_read_blob_from_offset() {
+        let db = make_db_with_value((0..30).collect());
+
+        let response = do_read_blob_request(HANDLE, 22, 23, &db);
+
+        response.to_vec().unwrap(); // check it serializes
+        assert_eq!(
+            response,
+            AttChild::AttReadBlobResponse(AttReadBlobResponseBuilder {
+                value: build_att_data(AttAttributeDataChild::RawData((22..30).collect()))
+            })
+        );
+    }
+
+    #[test]
+    fn test_multi_blob_read() {
+        // arrange: a value spanning three MTU-sized chunks
+        let value = (0..50).collect::<Vec<u8>>();
+        let db = make_db_with_value(value.clone());
+        let mtu = 23;
+
+        // act: read blobs until we get a short response, as a client would
+        let mut read = vec![];
+        loop {
+            let response = do_read_blob_request(HANDLE, read.len() as u16, mtu, &db);
+            let response = response.to_vec().unwrap();
+            read.extend_from_slice(&response);
+            if response.len() < mtu - 1 {
+                break;
+            }
+        }
+
+        // assert: we reassembled the entire value
+        assert_eq!(read, value);
+    }
+
+    #[test]
+    fn test_read_blob_at_end_of_value() {
+        // a client reading a long value whose last segment is exactly MTU - 1 bytes
+        // will issue one more read blob at the end of the value, which should
+        // return an empty response
+        let db = make_db_with_value((0..44).collect());
+
+        let response = do_read_blob_request(HANDLE, 44, 23, &db);
+
+        assert_eq!(
+            response,
+            AttChild::AttReadBlobResponse(AttReadBlobResponseBuilder {
+                value: build_att_data(AttAttributeDataChild::RawData([].into()))
+            })
+        );
+    }
+
+    #[test]
+    fn test_value_of_mtu_minus_one_is_not_long() {
+        // a value of exactly MTU - 1 bytes fits in a single ATT_READ_RSP, so the
+        // read blob a client issues after it is rejected
+        let db = make_db_with_value((0..22).collect());
+
+        let at_end = do_read_blob_request(HANDLE, 22, 23, &db);
+        let within = do_read_blob_request(HANDLE, 1, 23, &db);
+
+        assert_eq!(at_end, make_error(HANDLE, AttErrorCode::ATTRIBUTE_NOT_LONG));
+        assert_eq!(within, make_error(HANDLE, AttErrorCode::ATTRIBUTE_NOT_LONG));
+    }
+
+    #[test]
+    fn test_value_of_mtu_bytes_is_long() {
+        let db = make_db_with_value((0..23).collect());
+
+        let response = do_read_blob_request(HANDLE, 22, 23, &db);
+
+        assert_eq!(
+            response,
+            AttChild::AttReadBlobResponse(AttReadBlobResponseBuilder {
+                value: build_att_data(AttAttributeDataChild::RawData([22].into()))
+            })
+        );
+    }
+
+    #[test]
+    fn test_invalid_offset() {
+        let db = make_db_with_value((0..30).collect());
+
+        let response = do_read_blob_request(HANDLE, 31, 23, &db);
+
+        assert_eq!(response, make_error(HANDLE, AttErrorCode::INVALID_OFFSET));
+    }
+
+    #[test]
+    fn test_attribute_not_long() {
+        let db = make_db_with_value(vec![1, 2, 3]);
+
+        let response = do_read_blob_request(HANDLE, 1, 23, &db);
+
+        assert_eq!(response, make_error(HANDLE, AttErrorCode::ATTRIBUTE_NOT_LONG));
+    }
+
+    #[test]
+    fn test_lenient_short_attribute() {
+        let db = make_db_with_value(vec![1, 2, 3]);
+        let att_view = build_view_or_crash(AttReadBlobRequestBuilder {
+            attribute_handle: HANDLE.into(),
+            offset: 1,
+        });
+
+        let response = tokio_test::block_on(handle_read_blob_request(
+            att_view.view(),
+            23,
+            true,
+            &mut LongReadTracker::new(),
+            &db,
+        ));
+
+        assert_eq!(
+            response,
+            AttChild::AttReadBlobResponse(AttReadBlobResponseBuilder {
+                value: build_att_data(AttAttributeDataChild::RawData([2, 3].into()))
+            })
+        );
+    }
+
+    #[test]
+    fn test_short_attribute_at_zero_offset() {
+        let db = make_db_with_value(vec![1, 2, 3]);
+
+        let response = do_read_blob_request(HANDLE, 0, 23, &db);
+
+        assert_eq!(
+            response,
+            AttChild::AttReadBlobResponse(AttReadBlobResponseBuilder {
+                value: build_att_data(AttAttributeDataChild::RawData([1, 2, 3].into()))
+            })
+        );
+    }
+
+    #[test]
+    fn test_value_changed_between_segments() {
+        // arrange: a client has read the first segment of a long value
+        let db = make_db_with_value((0..50).collect()).into_mutable();
+        let mut long_reads = LongReadTracker::new();
+        let mut read_blob = |offset| {
+            let att_view = build_view_or_crash(AttReadBlobRequestBuilder {
+                attribute_handle: HANDLE.into(),
+                offset,
+            });
+            tokio_test::block_on(handle_read_blob_request(
+                att_view.view(),
+                23,
+                false,
+                &mut long_reads,
+                &db,
+            ))
+        };
+        read_blob(0);
+
+        // act: the value changes (but keeps its length) before the next segment
+        db.set_value(HANDLE, (100..150).collect());
+        let torn = read_blob(22);
+        let still_torn = read_blob(44);
+        let restarted = read_blob(0);
+        let continued = read_blob(22);
+
+        // assert: the long read fails until the client restarts it
+        assert_eq!(torn, make_error(HANDLE, AttErrorCode::UNLIKELY_ERROR));
+        assert_eq!(still_torn, make_error(HANDLE, AttErrorCode::UNLIKELY_ERROR));
+        assert_eq!(
+            restarted,
+            AttChild::AttReadBlobResponse(AttReadBlobResponseBuilder {
+                value: build_att_data(AttAttributeDataChild::RawData((100..122).collect()))
+            })
+        );
+        assert_eq!(
+            continued,
+            AttChild::AttReadBlobResponse(AttReadBlobResponseBuilder {
+                value: build_att_data(AttAttributeDataChild::RawData((122..144).collect()))
+            })
+        );
+    }
+
+    #[test]
+    fn test_torn_read_served_by_policy() {
+        // arrange
+        let db = make_db_with_value((0..50).collect());
+        db.set_config(GattServerConfig { torn_reads: TornReadPolicy::Serve, ..Default::default() });
+        let db = db.into_mutable();
+        let mut long_reads = LongReadTracker::new();
+        long_reads.on_read(HANDLE, 0, db.value_version(HANDLE), 50);
+        db.set_value(HANDLE, (100..150).collect());
+        let att_view = build_view_or_crash(AttReadBlobRequestBuilder {
+            attribute_handle: HANDLE.into(),
+            offset: 22,
+        });
+
+        // act
+        let response = tokio_test::block_on(handle_read_blob_request(
+            att_view.view(),
+            23,
+            false,
+            &mut long_reads,
+            &db,
+        ));
+
+        // assert: the segment comes from the new value
+        assert_eq!(
+            response,
+            AttChild::AttReadBlobResponse(AttReadBlobResponseBuilder {
+                value: build_att_data(AttAttributeDataChild::RawData((122..144).collect()))
+            })
+        );
+    }
+
+    #[test]
+    fn test_length_change_noticed_without_version() {
+        let mut long_reads = LongReadTracker::new();
+        long_reads.on_read(HANDLE, 0, None, 50);
+
+        assert!(long_reads.continues_last_read(HANDLE, 22, None, 28));
+        assert!(!long_reads.continues_last_read(HANDLE, 22, None, 30));
+        // a read from offset 0, or of another attribute, starts anew
+        assert!(long_reads.continues_last_read(HANDLE, 0, None, 30));
+        assert!(long_reads.continues_last_read(AttHandle(4), 22, None, 30));
+    }
+
+    #[test]
+    fn test_read_blob_invalid_handle() {
+        let db = make_db_with_value(vec![1, 2, 3]);
+
+        let response = do_read_blob_request(AttHandle(4), 0, 23, &db);
+
+        assert_eq!(response, make_error(AttHandle(4), AttErrorCode::INVALID_HANDLE));
+    }
+}
@@ -3,14 +3,14 @@ use crate::{
     gatt::{
         ids::AttHandle,
         server::{
-            att_database::StableAttDatabase,
+            att_database::AttDatabase,
             gatt_database::{PRIMARY_SERVICE_DECLARATION_UUID, SECONDARY_SERVICE_DECLARATION_UUID},
         },
     },
     packets::{
-        AttAttributeDataBuilder, AttChild, AttErrorCode, AttErrorResponseBuilder, AttOpcode,
-        AttReadByGroupTypeDataElementBuilder, AttReadByGroupTypeRequestView,
-        AttReadByGroupTypeResponseBuilder, ParseError,
+        AttAttributeDataBuilder, AttAttributeDataChild, AttChild, AttErrorCode,
+        AttErrorResponseBuilder, AttOpcode, AttReadByGroupTypeDataElementBuilder,
+        AttReadByGroupTypeRequestView, AttReadByGroupTypeResponseBuilder, ParseError,
     },
 };
 
@@ -21,10 +21,14 @@ use super::helpers::{
     payload_accumulator::PayloadAccumulator,
 };
 
+/// The longest value that can be returned in a single attribute data element
+/// (Core Spec 5.3 Vol 3F 3.4.4.10)
+const MAX_VALUE_LEN: usize = 251;
+
 pub async fn handle_read_by_group_type_request(
     request: AttReadByGroupTypeRequestView<'_>,
     mtu: usize,
-    db: &impl StableAttDatabase,
+    db: &impl AttDatabase,
 ) -> Result<AttChild, ParseError> {
     let group_type: Uuid = request.get_attribute_group_type().try_into()?;
 
@@ -44,9 +48,10 @@ pub async fn handle_read_by_group_type_request(
     };
 
     let Some(attrs) = filter_to_range(
+        db,
         request.get_starting_handle().into(),
         request.get_ending_handle().into(),
-        db.list_attributes().into_iter(),
+        Some(group_type),
     ) else {
         failure_response.error_code = AttErrorCode::INVALID_HANDLE;
         return Ok(failure_response.into());
@@ -64,8 +69,10 @@ pub async fn handle_read_by_group_type_request(
     // MTU-2 limit comes from Core Spec 5.3 Vol 3F 3.4.4.9
     let mut matches = PayloadAccumulator::new(mtu - 2);
 
-    // MTU-6 limit comes from Core Spec 5.3 Vol 3F 3.4.4.9
-    match filter_read_attributes_by_size_type(db, attrs, group_type, mtu - 6).await {
+    // MTU-6 limit comes from Core Spec 5.3 Vol 3F 3.4.4.9, and the cap from the
+    // one-octet Length field, which includes the two handles
+    let value_size_limit = (mtu - 6).min(MAX_VALUE_LEN);
+    match filter_read_attributes_by_size_type(db, attrs, group_type, value_size_limit).await {
         Ok(attrs) => {
             for AttributeWithValue { attr, value } in attrs {
                 if !matches.push(AttReadByGroupTypeDataElementBuilder {
@@ -74,14 +81,16 @@ pub async fn handle_read_by_group_type_request(
                         .expect("should never be None, since grouping UUID was validated earlier")
                         .handle
                         .into(),
-                    value: AttAttributeDataBuilder { _child_: value },
+                    value: AttAttributeDataBuilder {
+                        _child_: AttAttributeDataChild::RawData((&*value).into()),
+                    },
                 }) {
                     break;
                 }
             }
         }
         Err(err) => {
-            failure_response.error_code = err;
+            failure_response.error_code = err.report(AttOpcode::READ_BY_GROUP_TYPE_REQUEST);
             return Ok(failure_response.into());
         }
     }
@@ -410,4 +419,197 @@ mod test {
             }
         )
     }
+
+    const SHORT_UUID_VALUE: [u8; 2] = [0x00, 0x18];
+    const ANOTHER_SHORT_UUID_VALUE: [u8; 2] = [0x01, 0x18];
+    const LONG_UUID_VALUE: [u8; 16] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+
+    fn attr(handle: u16, type_: Uuid, value: &[u8]) -> (AttAttribute, Vec<u8>) {
+        (
+            AttAttribute {
+                handle: AttHandle(handle),
+                type_,
+                permissions: AttPermissions::READABLE,
+            },
+            value.to_vec(),
+        )
+    }
+
+    fn read_by_group_type(
+        db: &impl AttDatabase,
+        starting_handle: u16,
+        group_type: Uuid,
+        mtu: usize,
+    ) -> AttChild {
+        let att_view = build_view_or_crash(AttReadByGroupTypeRequestBuilder {
+            starting_handle: AttHandle(starting_handle).into(),
+            ending_handle: AttHandle::MAX.into(),
+            attribute_group_type: group_type.into(),
+        });
+        tokio_test::block_on(handle_read_by_group_type_request(att_view.view(), mtu, db)).unwrap()
+    }
+
+    fn element(
+        handle: u16,
+        end_group_handle: u16,
+        value: &[u8],
+    ) -> AttReadByGroupTypeDataElementBuilder {
+        AttReadByGroupTypeDataElementBuilder {
+            handle: AttHandle(handle).into(),
+            end_group_handle: AttHandle(end_group_handle).into(),
+            value: build_att_data(AttAttributeDataChild::RawData(value.into())),
+        }
+    }
+
+    fn make_db_with_mixed_uuid_services() -> TestAttDatabase {
+        TestAttDatabase::new(vec![
+            attr(1, PRIMARY_SERVICE_DECLARATION_UUID, &SHORT_UUID_VALUE),
+            attr(2, CHARACTERISTIC_UUID, &[1, 2, 3]),
+            attr(4, PRIMARY_SERVICE_DECLARATION_UUID, &ANOTHER_SHORT_UUID_VALUE),
+            attr(6, PRIMARY_SERVICE_DECLARATION_UUID, &LONG_UUID_VALUE),
+            attr(7, CHARACTERISTIC_UUID, &[1, 2, 3]),
+            attr(9, PRIMARY_SERVICE_DECLARATION_UUID, &LONG_UUID_VALUE),
+            attr(10, PRIMARY_SERVICE_DECLARATION_UUID, &SHORT_UUID_VALUE),
+            attr(11, CHARACTERISTIC_UUID, &[1, 2, 3]),
+        ])
+    }
+
+    #[test]
+    fn test_mixed_uuids_stop_at_first_long_uuid() {
+        // arrange
+        let db = make_db_with_mixed_uuid_services();
+
+        // act: discover all primary services
+        let response = read_by_group_type(&db, 1, PRIMARY_SERVICE_DECLARATION_UUID, 100);
+
+        // assert: only the leading services with 16-bit UUIDs are returned
+        assert_eq!(
+            response,
+            AttReadByGroupTypeResponseBuilder {
+                data: [element(1, 2, &SHORT_UUID_VALUE), element(4, 4, &ANOTHER_SHORT_UUID_VALUE)]
+                    .into()
+            }
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_mixed_uuids_stop_at_first_short_uuid() {
+        // arrange
+        let db = make_db_with_mixed_uuid_services();
+
+        // act: continue discovery after the services with 16-bit UUIDs
+        let response = read_by_group_type(&db, 5, PRIMARY_SERVICE_DECLARATION_UUID, 100);
+
+        // assert: only the services with 128-bit UUIDs are returned
+        assert_eq!(
+            response,
+            AttReadByGroupTypeResponseBuilder {
+                data: [element(6, 7, &LONG_UUID_VALUE), element(9, 9, &LONG_UUID_VALUE)].into()
+            }
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_mixed_uuids_last_service_group_end() {
+        // arrange
+        let db = make_db_with_mixed_uuid_services();
+
+        // act: continue discovery after the services with 128-bit UUIDs
+        let response = read_by_group_type(&db, 10, PRIMARY_SERVICE_DECLARATION_UUID, 100);
+
+        // assert: the last service extends to its last attribute
+        assert_eq!(
+            response,
+            AttReadByGroupTypeResponseBuilder { data: [element(10, 11, &SHORT_UUID_VALUE)].into() }
+                .into()
+        );
+    }
+
+    #[test]
+    fn test_long_uuids_limited_by_mtu() {
+        // arrange
+        let db = make_db_with_mixed_uuid_services();
+
+        // act: use the minimum MTU, so a single 128-bit entry (20 bytes) fits
+        let response = read_by_group_type(&db, 5, PRIMARY_SERVICE_DECLARATION_UUID, 23);
+
+        // assert
+        assert_eq!(
+            response,
+            AttReadByGroupTypeResponseBuilder { data: [element(6, 7, &LONG_UUID_VALUE)].into() }
+                .into()
+        );
+    }
+
+    #[test]
+    fn test_secondary_services() {
+        // arrange: secondary services interleaved with primary services
+        let db = TestAttDatabase::new(vec![
+            attr(1, PRIMARY_SERVICE_DECLARATION_UUID, &SHORT_UUID_VALUE),
+            attr(2, SECONDARY_SERVICE_DECLARATION_UUID, &SHORT_UUID_VALUE),
+            attr(3, CHARACTERISTIC_UUID, &[1, 2, 3]),
+            attr(5, PRIMARY_SERVICE_DECLARATION_UUID, &ANOTHER_SHORT_UUID_VALUE),
+            attr(6, SECONDARY_SERVICE_DECLARATION_UUID, &ANOTHER_SHORT_UUID_VALUE),
+        ]);
+
+        // act: discover the primary and the secondary services
+        let primary = read_by_group_type(&db, 1, PRIMARY_SERVICE_DECLARATION_UUID, 100);
+        let secondary = read_by_group_type(&db, 1, SECONDARY_SERVICE_DECLARATION_UUID, 100);
+
+        // assert: each service group ends before the next service, of either kind
+        assert_eq!(
+            primary,
+            AttReadByGroupTypeResponseBuilder {
+                data: [element(1, 1, &SHORT_UUID_VALUE), element(5, 5, &ANOTHER_SHORT_UUID_VALUE)]
+                    .into()
+            }
+            .into()
+        );
+        assert_eq!(
+            secondary,
+            AttReadByGroupTypeResponseBuilder {
+                data: [element(2, 3, &SHORT_UUID_VALUE), element(6, 6, &ANOTHER_SHORT_UUID_VALUE)]
+                    .into()
+            }
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_characteristic_group_type_unsupported() {
+        // arrange
+        let db = make_db_with_mixed_uuid_services();
+
+        // act: try to group by characteristic, which is a grouping UUID but not a
+        // service
+        let response = read_by_group_type(&db, 1, CHARACTERISTIC_UUID, 100);
+
+        // assert
+        assert_eq!(
+            response,
+            AttErrorResponseBuilder {
+                handle_in_error: AttHandle(1).into(),
+                opcode_in_error: AttOpcode::READ_BY_GROUP_TYPE_REQUEST,
+                error_code: AttErrorCode::UNSUPPORTED_GROUP_TYPE,
+            }
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_value_limited_by_length_field() {
+        // arrange: a service with a value too long to be described by the Length field
+        let db = TestAttDatabase::new(vec![attr(1, PRIMARY_SERVICE_DECLARATION_UUID, &[1; 300])]);
+
+        // act: use an MTU large enough to hold the full value
+        let response = read_by_group_type(&db, 1, PRIMARY_SERVICE_DECLARATION_UUID, 512);
+
+        // assert: the value was truncated so the entry length fits in one octet
+        assert_eq!(
+            response,
+            AttReadByGroupTypeResponseBuilder { data: [element(1, 1, &[1; 251])].into() }.into()
+        );
+    }
 }
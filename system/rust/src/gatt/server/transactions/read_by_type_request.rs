@@ -1,24 +1,32 @@
 use crate::{
     core::uuid::Uuid,
-    gatt::{ids::AttHandle, server::att_database::StableAttDatabase},
+    gatt::{ids::AttHandle, server::att_database::AttDatabase},
     packets::{
-        AttAttributeDataBuilder, AttChild, AttErrorCode, AttErrorResponseBuilder, AttOpcode,
-        AttReadByTypeDataElementBuilder, AttReadByTypeRequestView, AttReadByTypeResponseBuilder,
+        AttBuilder, AttErrorCode, AttErrorResponseBuilder, AttOpcode, AttReadByTypeRequestView,
         ParseError,
     },
+    utils::packet::HACK_child_to_opcode,
 };
 
 use super::helpers::{
     att_filter_by_size_type::{filter_read_attributes_by_size_type, AttributeWithValue},
     att_range_filter::filter_to_range,
-    payload_accumulator::PayloadAccumulator,
+    pdu_writer::PduWriter,
 };
 
+/// The longest value that can be returned in a single attribute data element,
+/// since the Length field is one octet and also covers the handle (Core Spec
+/// 5.3 Vol 3F 3.4.4.2)
+const MAX_VALUE_LEN: usize = 253;
+
+/// Handle a READ_BY_TYPE_REQ. The response is serialized directly into a
+/// single buffer of at most MTU bytes as the attributes are read, since the
+/// response to a discovery of all characteristics is both large and frequent.
 pub async fn handle_read_by_type_request(
     request: AttReadByTypeRequestView<'_>,
     mtu: usize,
-    db: &impl StableAttDatabase,
-) -> Result<AttChild, ParseError> {
+    db: &impl AttDatabase,
+) -> Result<AttBuilder, ParseError> {
     let request_type: Uuid = request.get_attribute_type().try_into()?;
 
     // As per spec (5.3 Vol 3F 3.4.4.1)
@@ -35,42 +43,118 @@ pub async fn handle_read_by_type_request(
         // the default error code if we just fail to find anything
         error_code: AttErrorCode::ATTRIBUTE_NOT_FOUND,
     };
+    let failure = |failure_response: AttErrorResponseBuilder| {
+        let child = failure_response.into();
+        AttBuilder { opcode: HACK_child_to_opcode(&child), _child_: child }
+    };
 
     let Some(attrs) = filter_to_range(
+        db,
         request.get_starting_handle().into(),
         request.get_ending_handle().into(),
-        db.list_attributes().into_iter(),
+        Some(request_type),
     ) else {
         failure_response.error_code = AttErrorCode::INVALID_HANDLE;
-        return Ok(failure_response.into());
+        return Ok(failure(failure_response));
     };
 
-    // MTU-2 limit comes from Core Spec 5.3 Vol 3F 3.4.4.1
-    let mut out = PayloadAccumulator::new(mtu - 2);
+    // The attribute data list is limited to MTU-2 (Core Spec 5.3 Vol 3F
+    // 3.4.4.2), so together with the Length field the body is at most MTU-1
+    let mut out = PduWriter::new(AttOpcode::READ_BY_TYPE_RESPONSE, mtu - 1);
 
     // MTU-4 limit comes from Core Spec 5.3 Vol 3F 3.4.4.1
-    match filter_read_attributes_by_size_type(db, attrs, request_type, mtu - 4).await {
+    match filter_read_attributes_by_size_type(db, attrs, request_type, (mtu - 4).min(MAX_VALUE_LEN))
+        .await
+    {
+        Ok(attrs) => {
+            for AttributeWithValue { attr, value } in attrs {
+                let handle = attr.handle.0.to_le_bytes();
+                // every element has the same length, so the first one sets it
+                let length = [(handle.len() + value.len()) as u8];
+                let pushed = if out.is_empty() {
+                    out.push(&[&length, &handle, &value])
+                } else {
+                    out.push(&[&handle, &value])
+                };
+                if !pushed {
+                    break;
+                }
+            }
+        }
+        Err(err) => {
+            failure_response.error_code = err.report(AttOpcode::READ_BY_TYPE_REQUEST);
+            return Ok(failure(failure_response));
+        }
+    }
+
+    Ok(if out.is_empty() { failure(failure_response) } else { out.into_builder() })
+}
+
+/// The handler of READ_BY_TYPE_REQ that handle_read_by_type_request()
+/// replaced, which assembles the response out of the PDL builders one element
+/// at a time. It is only kept so that benches/read_by_type.rs can compare the
+/// two on the same database and request.
+#[cfg(feature = "benchmarking")]
+pub async fn handle_read_by_type_request_with_builders(
+    request: AttReadByTypeRequestView<'_>,
+    mtu: usize,
+    db: &impl AttDatabase,
+) -> Result<AttBuilder, ParseError> {
+    use crate::packets::{
+        AttAttributeDataBuilder, AttAttributeDataChild, AttChild, AttReadByTypeDataElementBuilder,
+        AttReadByTypeResponseBuilder,
+    };
+
+    use super::helpers::payload_accumulator::PayloadAccumulator;
+
+    let request_type: Uuid = request.get_attribute_type().try_into()?;
+
+    let mut failure_response = AttErrorResponseBuilder {
+        opcode_in_error: AttOpcode::READ_BY_TYPE_REQUEST,
+        handle_in_error: AttHandle::from(request.get_starting_handle()).into(),
+        error_code: AttErrorCode::ATTRIBUTE_NOT_FOUND,
+    };
+    let response =
+        |child: AttChild| AttBuilder { opcode: HACK_child_to_opcode(&child), _child_: child };
+
+    let Some(attrs) = filter_to_range(
+        db,
+        request.get_starting_handle().into(),
+        request.get_ending_handle().into(),
+        Some(request_type),
+    ) else {
+        failure_response.error_code = AttErrorCode::INVALID_HANDLE;
+        return Ok(response(failure_response.into()));
+    };
+
+    let mut out = PayloadAccumulator::new(mtu - 2);
+
+    match filter_read_attributes_by_size_type(db, attrs, request_type, (mtu - 4).min(MAX_VALUE_LEN))
+        .await
+    {
         Ok(attrs) => {
             for AttributeWithValue { attr, value } in attrs {
                 if !out.push(AttReadByTypeDataElementBuilder {
                     handle: attr.handle.into(),
-                    value: AttAttributeDataBuilder { _child_: value },
+                    value: AttAttributeDataBuilder {
+                        _child_: AttAttributeDataChild::RawData((&*value).into()),
+                    },
                 }) {
                     break;
                 }
             }
         }
         Err(err) => {
-            failure_response.error_code = err;
-            return Ok(failure_response.into());
+            failure_response.error_code = err.report(AttOpcode::READ_BY_TYPE_REQUEST);
+            return Ok(response(failure_response.into()));
         }
     }
 
-    Ok(if out.is_empty() {
+    Ok(response(if out.is_empty() {
         failure_response.into()
     } else {
         AttReadByTypeResponseBuilder { data: out.into_boxed_slice() }.into()
-    })
+    }))
 }
 
 #[cfg(test)]
@@ -86,13 +170,21 @@ mod test {
                 test::test_att_db::TestAttDatabase,
             },
         },
-        packets::{AttAttributeDataChild, AttReadByTypeRequestBuilder},
+        packets::{
+            AttAttributeDataChild, AttChild, AttReadByTypeDataElementBuilder,
+            AttReadByTypeRequestBuilder, AttReadByTypeResponseBuilder, Serializable,
+        },
         utils::packet::{build_att_data, build_view_or_crash},
     };
 
     const UUID: Uuid = Uuid::new(1234);
     const ANOTHER_UUID: Uuid = Uuid::new(2345);
 
+    fn serialize(child: impl Into<AttChild>) -> Vec<u8> {
+        let child = child.into();
+        AttBuilder { opcode: HACK_child_to_opcode(&child), _child_: child }.to_vec().unwrap()
+    }
+
     #[test]
     fn test_single_matching_attr() {
         // arrange
@@ -115,18 +207,15 @@ mod test {
             tokio_test::block_on(handle_read_by_type_request(att_view.view(), 31, &db)).unwrap();
 
         // assert
-        let AttChild::AttReadByTypeResponse(response) = response else {
-            unreachable!("{:?}", response)
-        };
         assert_eq!(
-            response,
-            AttReadByTypeResponseBuilder {
+            response.to_vec().unwrap(),
+            serialize(AttReadByTypeResponseBuilder {
                 data: [AttReadByTypeDataElementBuilder {
                     handle: AttHandle(3).into(),
                     value: build_att_data(AttAttributeDataChild::RawData([4, 5].into()))
                 }]
                 .into()
-            }
+            })
         )
     }
 
@@ -171,12 +260,9 @@ mod test {
 
         // assert: we correctly filtered by type (so we are using the filter_by_type
         // utility)
-        let AttChild::AttReadByTypeResponse(response) = response else {
-            unreachable!("{:?}", response)
-        };
         assert_eq!(
-            response,
-            AttReadByTypeResponseBuilder {
+            response.to_vec().unwrap(),
+            serialize(AttReadByTypeResponseBuilder {
                 data: [
                     AttReadByTypeDataElementBuilder {
                         handle: AttHandle(3).into(),
@@ -188,7 +274,7 @@ mod test {
                     }
                 ]
                 .into()
-            }
+            })
         )
     }
 
@@ -224,18 +310,15 @@ mod test {
             tokio_test::block_on(handle_read_by_type_request(att_view.view(), 8, &db)).unwrap();
 
         // assert: we return only the first attribute
-        let AttChild::AttReadByTypeResponse(response) = response else {
-            unreachable!("{:?}", response)
-        };
         assert_eq!(
-            response,
-            AttReadByTypeResponseBuilder {
+            response.to_vec().unwrap(),
+            serialize(AttReadByTypeResponseBuilder {
                 data: [AttReadByTypeDataElementBuilder {
                     handle: AttHandle(3).into(),
                     value: build_att_data(AttAttributeDataChild::RawData([4, 5, 6].into()))
                 },]
                 .into()
-            }
+            })
         )
     }
 
@@ -271,7 +354,7 @@ mod test {
             tokio_test::block_on(handle_read_by_type_request(att_view.view(), 31, &db)).unwrap();
 
         // assert: we return ATTRIBUTE_NOT_FOUND
-        let AttChild::AttErrorResponse(response) = response else { unreachable!("{:?}", response) };
+        let AttChild::AttErrorResponse(response) = response._child_ else { unreachable!() };
         assert_eq!(
             response,
             AttErrorResponseBuilder {
@@ -297,7 +380,7 @@ mod test {
             tokio_test::block_on(handle_read_by_type_request(att_view.view(), 31, &db)).unwrap();
 
         // assert: we return an INVALID_HANDLE error
-        let AttChild::AttErrorResponse(response) = response else { unreachable!("{:?}", response) };
+        let AttChild::AttErrorResponse(response) = response._child_ else { unreachable!() };
         assert_eq!(
             response,
             AttErrorResponseBuilder {
@@ -307,4 +390,38 @@ mod test {
             }
         )
     }
+
+    #[test]
+    fn test_value_length_fits_in_length_field() {
+        // arrange: an attribute longer than any Length field can describe
+        let db = TestAttDatabase::new(vec![(
+            AttAttribute {
+                handle: AttHandle(3),
+                type_: UUID,
+                permissions: AttPermissions::READABLE,
+            },
+            vec![1; 300],
+        )]);
+
+        // act: read with the largest MTU
+        let att_view = build_view_or_crash(AttReadByTypeRequestBuilder {
+            starting_handle: AttHandle(3).into(),
+            ending_handle: AttHandle(6).into(),
+            attribute_type: UUID.into(),
+        });
+        let response =
+            tokio_test::block_on(handle_read_by_type_request(att_view.view(), 517, &db)).unwrap();
+
+        // assert: the value was truncated so that the Length field does not overflow
+        assert_eq!(
+            response.to_vec().unwrap(),
+            serialize(AttReadByTypeResponseBuilder {
+                data: [AttReadByTypeDataElementBuilder {
+                    handle: AttHandle(3).into(),
+                    value: build_att_data(AttAttributeDataChild::RawData([1; 253].into()))
+                },]
+                .into()
+            })
+        )
+    }
 }
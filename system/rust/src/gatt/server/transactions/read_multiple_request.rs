@@ -0,0 +1,266 @@
+use crate::{
+    gatt::{
+        ids::AttHandle,
+        server::att_database::{AttAttributeValue, AttDatabase},
+    },
+    packets::{
+        AttAttributeDataBuilder, AttAttributeDataChild, AttChild, AttErrorCode,
+        AttErrorResponseBuilder, AttHandleView, AttOpcode, AttReadMultipleRequestView,
+        AttReadMultipleResponseBuilder, AttReadMultipleVariableRequestView,
+        AttReadMultipleVariableResponseBuilder,
+    },
+};
+
+/// Read each of the supplied handles in order, failing on the first handle
+/// that cannot be read. As per Core Spec 5.3 Vol 3F 3.4.4.7 and 3.4.4.11,
+/// the set of handles must contain at least two handles.
+async fn read_all<'a>(
+    handles: impl Iterator<Item = AttHandleView<'a>>,
+    opcode: AttOpcode,
+    db: &impl AttDatabase,
+) -> Result<Vec<AttAttributeValue>, AttChild> {
+    let handles = handles.map(AttHandle::from).collect::<Vec<_>>();
+    if handles.len() < 2 {
+        return Err(AttErrorResponseBuilder {
+            opcode_in_error: opcode,
+            handle_in_error: AttHandle(0).into(),
+            error_code: AttErrorCode::INVALID_PDU,
+        }
+        .into());
+    }
+
+    let mut values = vec![];
+    for handle in handles {
+        match db.read_attribute(handle).await {
+            Ok(value) => values.push(value),
+            Err(error) => {
+                return Err(AttErrorResponseBuilder {
+                    opcode_in_error: opcode,
+                    handle_in_error: handle.into(),
+                    error_code: error.report(opcode),
+                }
+                .into())
+            }
+        }
+    }
+    Ok(values)
+}
+
+pub async fn handle_read_multiple_request(
+    request: AttReadMultipleRequestView<'_>,
+    mtu: usize,
+    db: &impl AttDatabase,
+) -> AttChild {
+    let values =
+        match read_all(request.get_children_iter(), AttOpcode::READ_MULTIPLE_REQUEST, db).await {
+            Ok(values) => values,
+            Err(error_response) => return error_response,
+        };
+
+    // as per 5.3 3F 3.4.4.8 ATT_READ_MULTIPLE_RSP, the concatenated values are
+    // truncated to MTU - 1
+    let mut data = vec![];
+    for value in values {
+        if data.len() >= mtu - 1 {
+            break;
+        }
+        data.extend_from_slice(&value);
+    }
+    data.truncate(mtu - 1);
+
+    AttReadMultipleResponseBuilder {
+        value: AttAttributeDataBuilder {
+            _child_: AttAttributeDataChild::RawData(data.into_boxed_slice()),
+        },
+    }
+    .into()
+}
+
+pub async fn handle_read_multiple_variable_request(
+    request: AttReadMultipleVariableRequestView<'_>,
+    mtu: usize,
+    db: &impl AttDatabase,
+) -> AttChild {
+    let values =
+        match read_all(request.get_children_iter(), AttOpcode::READ_MULTIPLE_VARIABLE_REQUEST, db)
+            .await
+        {
+            Ok(values) => values,
+            Err(error_response) => return error_response,
+        };
+
+    // as per 5.3 3F 3.4.4.12 ATT_READ_MULTIPLE_VARIABLE_RSP, each value is
+    // prefixed with its length, and the tuple list is truncated to MTU - 1
+    let mut data = vec![];
+    for value in values {
+        if data.len() + 2 > mtu - 1 {
+            break;
+        }
+        data.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        data.extend_from_slice(&value);
+    }
+    data.truncate(mtu - 1);
+
+    AttReadMultipleVariableResponseBuilder {
+        value: AttAttributeDataBuilder {
+            _child_: AttAttributeDataChild::RawData(data.into_boxed_slice()),
+        },
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::{
+        core::uuid::Uuid,
+        gatt::server::{
+            att_database::{AttAttribute, AttPermissions},
+            test::test_att_db::TestAttDatabase,
+        },
+        packets::{
+            AttHandleBuilder, AttReadMultipleRequestBuilder, AttReadMultipleVariableRequestBuilder,
+            Serializable,
+        },
+        utils::packet::build_view_or_crash,
+    };
+
+    const HANDLE: AttHandle = AttHandle(1);
+    const ANOTHER_HANDLE: AttHandle = AttHandle(2);
+    const UNREADABLE_HANDLE: AttHandle = AttHandle(3);
+
+    fn make_db() -> TestAttDatabase {
+        TestAttDatabase::new(vec![
+            (
+                AttAttribute {
+                    handle: HANDLE,
+                    type_: Uuid::new(0x1234),
+                    permissions: AttPermissions::READABLE,
+                },
+                vec![1, 2, 3],
+            ),
+            (
+                AttAttribute {
+                    handle: ANOTHER_HANDLE,
+                    type_: Uuid::new(0x1234),
+                    permissions: AttPermissions::READABLE,
+                },
+                vec![4, 5],
+            ),
+            (
+                AttAttribute {
+                    handle: UNREADABLE_HANDLE,
+                    type_: Uuid::new(0x1234),
+                    permissions: AttPermissions::empty(),
+                },
+                vec![],
+            ),
+        ])
+    }
+
+    fn handles(handles: &[AttHandle]) -> Box<[AttHandleBuilder]> {
+        handles.iter().map(|handle| (*handle).into()).collect()
+    }
+
+    fn do_read_multiple(handles_: &[AttHandle], mtu: usize) -> AttChild {
+        let att_view =
+            build_view_or_crash(AttReadMultipleRequestBuilder { children: handles(handles_) });
+        tokio_test::block_on(handle_read_multiple_request(att_view.view(), mtu, &make_db()))
+    }
+
+    fn do_read_multiple_variable(handles_: &[AttHandle], mtu: usize) -> AttChild {
+        let att_view = build_view_or_crash(AttReadMultipleVariableRequestBuilder {
+            children: handles(handles_),
+        });
+        tokio_test::block_on(handle_read_multiple_variable_request(
+            att_view.view(),
+            mtu,
+            &make_db(),
+        ))
+    }
+
+    #[test]
+    fn test_read_multiple() {
+        let response = do_read_multiple(&[ANOTHER_HANDLE, HANDLE], 23);
+
+        assert!(matches!(response, AttChild::AttReadMultipleResponse(_)));
+        assert_eq!(response.to_vec().unwrap(), vec![4, 5, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_read_multiple_truncated() {
+        let response = do_read_multiple(&[HANDLE, ANOTHER_HANDLE], 5);
+
+        assert_eq!(response.to_vec().unwrap(), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_read_multiple_failure() {
+        let response = do_read_multiple(&[HANDLE, UNREADABLE_HANDLE, AttHandle(10)], 23);
+
+        assert_eq!(
+            response,
+            AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::READ_MULTIPLE_REQUEST,
+                handle_in_error: UNREADABLE_HANDLE.into(),
+                error_code: AttErrorCode::READ_NOT_PERMITTED,
+            }
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_read_multiple_single_handle() {
+        let response = do_read_multiple(&[HANDLE], 23);
+
+        assert_eq!(
+            response,
+            AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::READ_MULTIPLE_REQUEST,
+                handle_in_error: AttHandle(0).into(),
+                error_code: AttErrorCode::INVALID_PDU,
+            }
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_read_multiple_variable() {
+        let response = do_read_multiple_variable(&[HANDLE, ANOTHER_HANDLE], 23);
+
+        assert!(matches!(response, AttChild::AttReadMultipleVariableResponse(_)));
+        assert_eq!(response.to_vec().unwrap(), vec![3, 0, 1, 2, 3, 2, 0, 4, 5]);
+    }
+
+    #[test]
+    fn test_read_multiple_variable_truncated() {
+        // the second value is truncated, but retains its full length
+        let response = do_read_multiple_variable(&[HANDLE, ANOTHER_HANDLE], 9);
+
+        assert_eq!(response.to_vec().unwrap(), vec![3, 0, 1, 2, 3, 2, 0, 4]);
+    }
+
+    #[test]
+    fn test_read_multiple_variable_no_room_for_length() {
+        // there is no room for the length of the second value, so it is omitted
+        let response = do_read_multiple_variable(&[HANDLE, ANOTHER_HANDLE], 7);
+
+        assert_eq!(response.to_vec().unwrap(), vec![3, 0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_read_multiple_variable_failure() {
+        let response = do_read_multiple_variable(&[UNREADABLE_HANDLE, HANDLE], 23);
+
+        assert_eq!(
+            response,
+            AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::READ_MULTIPLE_VARIABLE_REQUEST,
+                handle_in_error: UNREADABLE_HANDLE.into(),
+                error_code: AttErrorCode::READ_NOT_PERMITTED,
+            }
+            .into()
+        );
+    }
+}
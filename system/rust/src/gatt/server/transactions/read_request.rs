@@ -1,33 +1,38 @@
 use crate::{
-    gatt::server::att_database::AttDatabase,
+    gatt::server::{att_database::AttDatabase, transactions::read_blob_request::LongReadTracker},
     packets::{
         AttAttributeDataBuilder, AttAttributeDataChild, AttChild, AttErrorResponseBuilder,
         AttOpcode, AttReadRequestView, AttReadResponseBuilder,
     },
 };
 
+/// The value read starts a long read, whose next segments are checked against
+/// it
 pub async fn handle_read_request<T: AttDatabase>(
     request: AttReadRequestView<'_>,
     mtu: usize,
+    long_reads: &mut LongReadTracker,
     db: &T,
 ) -> AttChild {
     let handle = request.get_attribute_handle().into();
 
+    let version = db.value_version(handle);
     match db.read_attribute(handle).await {
-        Ok(mut data) => {
+        Ok(data) => {
+            long_reads.on_read(handle, 0, version, data.len());
             // as per 5.3 3F 3.4.4.4 ATT_READ_RSP, we truncate to MTU - 1
-            data.truncate(mtu - 1);
+            let data = &data[..data.len().min(mtu - 1)];
             AttReadResponseBuilder {
                 value: AttAttributeDataBuilder {
-                    _child_: AttAttributeDataChild::RawData(data.into_boxed_slice()),
+                    _child_: AttAttributeDataChild::RawData(data.into()),
                 },
             }
             .into()
         }
-        Err(error_code) => AttErrorResponseBuilder {
+        Err(error) => AttErrorResponseBuilder {
             opcode_in_error: AttOpcode::READ_REQUEST,
             handle_in_error: handle.into(),
-            error_code,
+            error_code: error.report(AttOpcode::READ_REQUEST),
         }
         .into(),
     }
@@ -69,7 +74,12 @@ mod test {
         let att_view = build_view_or_crash(AttReadRequestBuilder {
             attribute_handle: AttHandle(handle).into(),
         });
-        tokio_test::block_on(handle_read_request(att_view.view(), mtu, db))
+        tokio_test::block_on(handle_read_request(
+            att_view.view(),
+            mtu,
+            &mut LongReadTracker::new(),
+            db,
+        ))
     }
 
     #[test]
@@ -11,12 +11,12 @@ pub async fn handle_write_request<T: AttDatabase>(
 ) -> AttChild {
     let handle = request.get_handle().into();
     let value = request.get_value().get_raw_payload().collect::<Vec<_>>();
-    match db.write_attribute(handle, &value).await {
+    match db.write_attribute(handle, 0, &value).await {
         Ok(()) => AttWriteResponseBuilder {}.into(),
-        Err(error_code) => AttErrorResponseBuilder {
+        Err(error) => AttErrorResponseBuilder {
             opcode_in_error: AttOpcode::WRITE_REQUEST,
             handle_in_error: handle.into(),
-            error_code,
+            error_code: error.report(AttOpcode::WRITE_REQUEST),
         }
         .into(),
     }
@@ -33,7 +33,7 @@ mod test {
         gatt::{
             ids::AttHandle,
             server::{
-                att_database::{AttAttribute, AttDatabase},
+                att_database::{AttAttribute, AttDatabase, MAX_ATTRIBUTE_VALUE_LEN},
                 gatt_database::AttPermissions,
                 test::test_att_db::TestAttDatabase,
             },
@@ -100,4 +100,37 @@ mod test {
             })
         );
     }
+
+    #[test]
+    fn test_write_too_long() {
+        // arrange: db with one writable attribute
+        let db = TestAttDatabase::new(vec![(
+            AttAttribute {
+                handle: AttHandle(1),
+                type_: Uuid::new(0x1234),
+                permissions: AttPermissions::READABLE | AttPermissions::WRITABLE_WITH_RESPONSE,
+            },
+            vec![1],
+        )]);
+
+        // act: write a value longer than any attribute may be
+        let att_view = build_view_or_crash(AttWriteRequestBuilder {
+            handle: AttHandle(1).into(),
+            value: build_att_data(AttAttributeDataChild::RawData(
+                [0; MAX_ATTRIBUTE_VALUE_LEN + 1].into(),
+            )),
+        });
+        let resp = block_on(handle_write_request(att_view.view(), &db));
+
+        // assert: that the write failed, and the value is unchanged
+        assert_eq!(
+            resp,
+            AttChild::from(AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::WRITE_REQUEST,
+                handle_in_error: AttHandle(1).into(),
+                error_code: AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH
+            })
+        );
+        assert_eq!(block_on(db.read_attribute(AttHandle(1))).unwrap(), vec![1]);
+    }
 }
@@ -0,0 +1,264 @@
+//! This module holds the values of the writable Characteristic User
+//! Description descriptors managed by the GattDatabase (Core Spec 5.3 Vol 3G
+//! 3.3.3.2). Each description starts out with the default supplied with its
+//! service, until a client overwrites it. What clients wrote is persisted, so
+//! that it survives both the removal of the service and stack restarts.
+//!
+//! A description is identified by its service (as for handle assignments) and
+//! by the type and index of its characteristic within that service, so a value
+//! written for one characteristic never resurfaces on another.
+
+use std::{collections::HashMap, ops::RangeInclusive, rc::Rc};
+
+use log::{info, warn};
+
+use crate::{
+    core::uuid::Uuid,
+    gatt::ids::AttHandle,
+    packets::{AttErrorCode, Uuid128Builder},
+};
+
+use super::handle_assignments::ServiceKey;
+
+/// The version of the serialized format, to be bumped on incompatible changes
+const SERIALIZATION_VERSION: u8 = 1;
+
+/// Persists the serialized user descriptions written by the clients of a
+/// single GATT server
+pub trait UserDescriptionStorage {
+    /// Load the user descriptions, if any were stored
+    fn load(&self) -> Option<Vec<u8>>;
+
+    /// Store the user descriptions, replacing any previous ones
+    fn store(&self, data: Vec<u8>);
+}
+
+/// Identifies the user description of a characteristic across restarts
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct UserDescriptionKey {
+    /// The service containing the characteristic
+    pub service: ServiceKey,
+    /// The type of the characteristic
+    pub characteristic_type: Uuid,
+    /// The index of the characteristic within its service
+    pub characteristic: u16,
+}
+
+/// The user descriptions managed by a GattDatabase
+#[derive(Default)]
+pub struct UserDescriptions {
+    storage: Option<Rc<dyn UserDescriptionStorage>>,
+    /// The descriptions written by clients
+    written: HashMap<UserDescriptionKey, Vec<u8>>,
+    /// The descriptors currently in the database, by handle, with their
+    /// default values
+    in_use: HashMap<AttHandle, (UserDescriptionKey, Vec<u8>)>,
+}
+
+impl UserDescriptions {
+    /// Restore the descriptions written by clients from the given storage, to
+    /// which they are then persisted on every write. Stored descriptions that
+    /// cannot be parsed are discarded.
+    pub fn attach_storage(&mut self, storage: Rc<dyn UserDescriptionStorage>) {
+        self.written = storage
+            .load()
+            .and_then(|data| {
+                let written = deserialize(&data);
+                if written.is_none() {
+                    warn!("discarding malformed user descriptions");
+                }
+                written
+            })
+            .unwrap_or_default();
+        info!("restored {} user description(s)", self.written.len());
+        self.storage = Some(storage);
+    }
+
+    /// Whether no descriptors have been added since the storage was attached
+    pub fn is_empty(&self) -> bool {
+        self.in_use.is_empty()
+    }
+
+    /// A managed descriptor was added at the given handle
+    pub fn on_descriptor_added(
+        &mut self,
+        handle: AttHandle,
+        key: UserDescriptionKey,
+        default: Vec<u8>,
+    ) {
+        self.in_use.insert(handle, (key, default));
+    }
+
+    /// The attributes in the given range were removed. What clients wrote to
+    /// the descriptors among them is kept, in case their service is added
+    /// again.
+    pub fn on_handles_removed(&mut self, range: RangeInclusive<AttHandle>) {
+        self.in_use.retain(|handle, _| !range.contains(handle));
+    }
+
+    /// The current description held by the descriptor at the given handle
+    pub fn get(&self, handle: AttHandle) -> Option<&[u8]> {
+        let (key, default) = self.in_use.get(&handle)?;
+        Some(self.written.get(key).unwrap_or(default))
+    }
+
+    /// Write the description held by the descriptor at the given handle,
+    /// replacing whatever followed the offset
+    pub fn write(
+        &mut self,
+        handle: AttHandle,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<(), AttErrorCode> {
+        let Some(current) = self.get(handle) else {
+            return Err(AttErrorCode::INVALID_HANDLE);
+        };
+        if offset > current.len() {
+            return Err(AttErrorCode::INVALID_OFFSET);
+        }
+        let mut value = current[..offset].to_vec();
+        value.extend_from_slice(data);
+        let (key, _) = self.in_use[&handle];
+        self.written.insert(key, value);
+        if let Some(storage) = &self.storage {
+            storage.store(serialize(&self.written));
+        }
+        Ok(())
+    }
+}
+
+fn serialize(written: &HashMap<UserDescriptionKey, Vec<u8>>) -> Vec<u8> {
+    let mut out = vec![SERIALIZATION_VERSION];
+    out.extend((written.len() as u16).to_le_bytes());
+    for (key, value) in written {
+        out.extend(Uuid128Builder::from(key.service.type_).data.iter());
+        out.extend(key.service.instance.to_le_bytes());
+        out.extend(Uuid128Builder::from(key.characteristic_type).data.iter());
+        out.extend(key.characteristic.to_le_bytes());
+        out.extend((value.len() as u16).to_le_bytes());
+        out.extend(value);
+    }
+    out
+}
+
+fn deserialize(data: &[u8]) -> Option<HashMap<UserDescriptionKey, Vec<u8>>> {
+    let mut reader = Reader(data);
+    if reader.u8()? != SERIALIZATION_VERSION {
+        return None;
+    }
+    let mut written = HashMap::new();
+    for _ in 0..reader.u16()? {
+        let key = UserDescriptionKey {
+            service: ServiceKey {
+                type_: Uuid::try_from_le_slice(reader.bytes(16)?)?,
+                instance: reader.u16()?,
+            },
+            characteristic_type: Uuid::try_from_le_slice(reader.bytes(16)?)?,
+            characteristic: reader.u16()?,
+        };
+        let len = reader.u16()?;
+        written.insert(key, reader.bytes(len.into())?.to_vec());
+    }
+    if !reader.0.is_empty() {
+        return None;
+    }
+    Some(written)
+}
+
+/// Reads little-endian fields from the front of a buffer
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::gatt::mocks::mock_user_description_storage::MockUserDescriptionStorage;
+
+    use super::*;
+
+    const HANDLE: AttHandle = AttHandle(5);
+    const KEY: UserDescriptionKey = UserDescriptionKey {
+        service: ServiceKey { type_: Uuid::new(0x1234), instance: 0 },
+        characteristic_type: Uuid::new(0x5678),
+        characteristic: 1,
+    };
+
+    fn attached(storage: &Rc<MockUserDescriptionStorage>) -> UserDescriptions {
+        let mut descriptions = UserDescriptions::default();
+        descriptions.attach_storage(storage.clone());
+        descriptions.on_descriptor_added(HANDLE, KEY, b"default".to_vec());
+        descriptions
+    }
+
+    #[test]
+    fn test_default_until_written() {
+        let mut descriptions = attached(&Rc::new(MockUserDescriptionStorage::new()));
+
+        assert_eq!(descriptions.get(HANDLE), Some(&b"default"[..]));
+        descriptions.write(HANDLE, 0, b"kitchen").unwrap();
+        assert_eq!(descriptions.get(HANDLE), Some(&b"kitchen"[..]));
+    }
+
+    #[test]
+    fn test_write_at_offset() {
+        let mut descriptions = attached(&Rc::new(MockUserDescriptionStorage::new()));
+
+        descriptions.write(HANDLE, 3, b"ice").unwrap();
+
+        assert_eq!(descriptions.get(HANDLE), Some(&b"defice"[..]));
+        assert_eq!(descriptions.write(HANDLE, 7, b"x"), Err(AttErrorCode::INVALID_OFFSET));
+    }
+
+    #[test]
+    fn test_written_description_restored() {
+        // arrange: a client writes the description, then the stack restarts
+        let storage = Rc::new(MockUserDescriptionStorage::new());
+        attached(&storage).write(HANDLE, 0, b"kitchen").unwrap();
+
+        // act
+        let descriptions = attached(&storage);
+
+        // assert
+        assert_eq!(descriptions.get(HANDLE), Some(&b"kitchen"[..]));
+    }
+
+    #[test]
+    fn test_written_description_kept_after_removal() {
+        let mut descriptions = attached(&Rc::new(MockUserDescriptionStorage::new()));
+        descriptions.write(HANDLE, 0, b"kitchen").unwrap();
+
+        descriptions.on_handles_removed(HANDLE..=HANDLE);
+        let removed = descriptions.get(HANDLE).map(<[u8]>::to_vec);
+        descriptions.on_descriptor_added(AttHandle(10), KEY, b"default".to_vec());
+
+        assert_eq!(removed, None);
+        assert_eq!(descriptions.get(AttHandle(10)), Some(&b"kitchen"[..]));
+    }
+
+    #[test]
+    fn test_malformed_storage_discarded() {
+        let storage = Rc::new(MockUserDescriptionStorage::new());
+        storage.set(vec![SERIALIZATION_VERSION, 1, 0, 1, 2, 3]);
+
+        let descriptions = attached(&storage);
+
+        assert_eq!(descriptions.get(HANDLE), Some(&b"default"[..]));
+    }
+}
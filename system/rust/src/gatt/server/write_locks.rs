@@ -0,0 +1,179 @@
+//! This module serializes the writes of a client to each attribute. A client
+//! with several EATT bearers may issue writes to the same attribute on each of
+//! them at once, and while the upper layer handles one (e.g. asynchronously,
+//! or by deferring its response), it must not be handed the next.
+//!
+//! The guarantees are as follows:
+//! - The writes of a client to a given attribute reach the datastore one at a
+//!   time, in the order in which the server received them (across all of its
+//!   bearers). Each only starts once the previous one has completed.
+//! - The writes to other attributes, or from other clients, are not held up.
+//! - An ATT_EXECUTE_WRITE_REQ commits the value assembled for each attribute
+//!   as a single write, so it is ordered like any other write. It is not
+//!   atomic across attributes: the write of another bearer may land between
+//!   those of two attributes it commits.
+//! - ATT_WRITE_CMDs are delivered as soon as they are received, since the
+//!   client expects no ordering beyond that of its bearer for them.
+
+use std::{cell::RefCell, collections::HashMap, future::Future, sync::Arc};
+
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+use crate::gatt::ids::{AttHandle, TransportIndex};
+
+/// Held while a write is in progress, until it is dropped
+pub type WriteGuard = OwnedMutexGuard<()>;
+
+type Lock = Arc<Mutex<()>>;
+
+/// A lock for each attribute being written by each client
+#[derive(Debug, Default)]
+pub struct WriteLocks {
+    locks: RefCell<HashMap<(TransportIndex, AttHandle), Lock>>,
+}
+
+impl WriteLocks {
+    /// Constructor
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wait for the writes of the client on the specified transport to the
+    /// given attribute that started waiting before this one to complete. The
+    /// write may then proceed until the guard is dropped.
+    pub fn lock(
+        &self,
+        tcb_idx: TransportIndex,
+        handle: AttHandle,
+    ) -> impl Future<Output = WriteGuard> {
+        let mut locks = self.locks.borrow_mut();
+        // drop the locks that are no longer held or awaited by anyone
+        locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+        locks.entry((tcb_idx, handle)).or_default().clone().lock_owned()
+    }
+
+    /// Whether a write of the client on the specified transport is in
+    /// progress (or waiting) on the given attribute
+    pub fn is_locked(&self, tcb_idx: TransportIndex, handle: AttHandle) -> bool {
+        self.locks
+            .borrow()
+            .get(&(tcb_idx, handle))
+            .map(|lock| lock.try_lock().is_err())
+            .unwrap_or(false)
+    }
+
+    /// The client on the specified transport has disconnected, so its locks
+    /// are released once the writes holding them are dropped
+    pub fn on_le_disconnect(&self, tcb_idx: TransportIndex) {
+        self.locks
+            .borrow_mut()
+            .retain(|(tcb, _), lock| *tcb != tcb_idx || Arc::strong_count(lock) > 1);
+    }
+
+    /// Whether any lock is kept for the client on the specified transport
+    pub fn has_client(&self, tcb_idx: TransportIndex) -> bool {
+        self.locks
+            .borrow()
+            .iter()
+            .any(|((tcb, _), lock)| *tcb == tcb_idx && Arc::strong_count(lock) > 1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use tokio::task::spawn_local;
+
+    use crate::utils::task::{block_on_locally, try_await};
+
+    use super::*;
+
+    const TCB_IDX: TransportIndex = TransportIndex(1);
+    const ANOTHER_TCB_IDX: TransportIndex = TransportIndex(2);
+    const HANDLE: AttHandle = AttHandle(3);
+    const ANOTHER_HANDLE: AttHandle = AttHandle(4);
+
+    #[test]
+    fn test_second_write_waits_for_first() {
+        block_on_locally(async {
+            // arrange
+            let locks = WriteLocks::new();
+            let first = locks.lock(TCB_IDX, HANDLE).await;
+
+            // act
+            let Err(pending) = try_await(locks.lock(TCB_IDX, HANDLE)).await else {
+                unreachable!("the second write did not wait for the first");
+            };
+            drop(first);
+
+            // assert: the second write proceeds once the first completes
+            let _second = pending.await;
+            assert!(locks.is_locked(TCB_IDX, HANDLE));
+        });
+    }
+
+    #[test]
+    fn test_writes_proceed_in_order() {
+        block_on_locally(async {
+            // arrange: a write in progress, and two more queued behind it
+            let locks = Rc::new(WriteLocks::new());
+            let order = Rc::new(RefCell::new(vec![]));
+            let first = locks.lock(TCB_IDX, HANDLE).await;
+            let tasks = [1, 2].map(|i| {
+                let lock = locks.lock(TCB_IDX, HANDLE);
+                let order = order.clone();
+                spawn_local(async move {
+                    let _guard = lock.await;
+                    order.borrow_mut().push(i);
+                })
+            });
+
+            // act: let both queue up, then release the first write
+            tokio::task::yield_now().await;
+            drop(first);
+            for task in tasks {
+                task.await.unwrap();
+            }
+
+            // assert
+            assert_eq!(*order.borrow(), vec![1, 2]);
+        });
+    }
+
+    #[test]
+    fn test_other_handles_and_clients_not_held_up() {
+        block_on_locally(async {
+            // arrange
+            let locks = WriteLocks::new();
+            let _first = locks.lock(TCB_IDX, HANDLE).await;
+
+            // act
+            let another_handle = try_await(locks.lock(TCB_IDX, ANOTHER_HANDLE)).await;
+            let another_client = try_await(locks.lock(ANOTHER_TCB_IDX, HANDLE)).await;
+
+            // assert
+            assert!(another_handle.is_ok());
+            assert!(another_client.is_ok());
+        });
+    }
+
+    #[test]
+    fn test_released_on_disconnect() {
+        block_on_locally(async {
+            // arrange
+            let locks = WriteLocks::new();
+            let guard = locks.lock(TCB_IDX, HANDLE).await;
+            locks.on_le_disconnect(TCB_IDX);
+            assert!(locks.has_client(TCB_IDX));
+
+            // act: the write holding the lock is dropped
+            drop(guard);
+            locks.on_le_disconnect(TCB_IDX);
+
+            // assert
+            assert!(!locks.has_client(TCB_IDX));
+            assert!(!locks.is_locked(TCB_IDX, HANDLE));
+        });
+    }
+}
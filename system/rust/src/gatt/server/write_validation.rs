@@ -0,0 +1,182 @@
+//! Services added with a ServiceBuilder may attach a WriteValidator to their
+//! writable characteristics. The GattDatabase runs it on every value written
+//! by a client, before the write is authorized or reaches the datastore, and
+//! rejects the write with the error it returns. This spares each profile from
+//! checking the length and format of the values it accepts.
+
+use std::{fmt, ops::RangeInclusive, rc::Rc};
+
+use crate::packets::AttErrorCode;
+
+/// A field of a fixed-layout value, as described to WriteValidator::schema().
+/// Integers are little-endian.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FieldSchema {
+    /// An 8-bit unsigned integer, within the given range
+    U8(RangeInclusive<u8>),
+    /// A 16-bit unsigned integer, within the given range
+    U16(RangeInclusive<u16>),
+    /// A 32-bit unsigned integer, within the given range
+    U32(RangeInclusive<u32>),
+    /// The given number of bytes, with any value
+    Bytes(usize),
+}
+
+impl FieldSchema {
+    fn len(&self) -> usize {
+        match self {
+            FieldSchema::U8(_) => 1,
+            FieldSchema::U16(_) => 2,
+            FieldSchema::U32(_) => 4,
+            FieldSchema::Bytes(len) => *len,
+        }
+    }
+
+    /// Whether the field (of the right length) is within range
+    fn accepts(&self, field: &[u8]) -> bool {
+        match self {
+            FieldSchema::U8(range) => range.contains(&field[0]),
+            FieldSchema::U16(range) => range.contains(&u16::from_le_bytes([field[0], field[1]])),
+            FieldSchema::U32(range) => {
+                range.contains(&u32::from_le_bytes([field[0], field[1], field[2], field[3]]))
+            }
+            FieldSchema::Bytes(_) => true,
+        }
+    }
+}
+
+type Validate = dyn Fn(&[u8]) -> Result<(), AttErrorCode>;
+
+/// Checks the values written to a characteristic. Since it sees the whole
+/// value, writes at a non-zero offset to a validated characteristic fail with
+/// INVALID_OFFSET.
+#[derive(Clone)]
+pub struct WriteValidator(Rc<Validate>);
+
+impl fmt::Debug for WriteValidator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WriteValidator(..)")
+    }
+}
+
+impl WriteValidator {
+    /// Constructor, from a closure returning the error with which an invalid
+    /// value is rejected
+    pub fn new(validator: impl Fn(&[u8]) -> Result<(), AttErrorCode> + 'static) -> Self {
+        Self(Rc::new(validator))
+    }
+
+    /// Accept values whose length is within the given range. Others fail with
+    /// INVALID_ATTRIBUTE_VALUE_LENGTH.
+    pub fn length(range: RangeInclusive<usize>) -> Self {
+        Self::new(move |value| {
+            if !range.contains(&value.len()) {
+                return Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH);
+            }
+            Ok(())
+        })
+    }
+
+    /// Accept only the given values. Others fail with VALUE_NOT_ALLOWED.
+    pub fn one_of(values: Vec<Vec<u8>>) -> Self {
+        Self::new(move |value| {
+            if !values.iter().any(|allowed| allowed == value) {
+                return Err(AttErrorCode::VALUE_NOT_ALLOWED);
+            }
+            Ok(())
+        })
+    }
+
+    /// Accept values made of the given fields, in order. Values of another
+    /// length fail with INVALID_ATTRIBUTE_VALUE_LENGTH, and those with a field
+    /// out of its range with OUT_OF_RANGE.
+    pub fn schema(fields: Vec<FieldSchema>) -> Self {
+        let len = fields.iter().map(FieldSchema::len).sum::<usize>();
+        Self::new(move |value| {
+            if value.len() != len {
+                return Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH);
+            }
+            let mut rest = value;
+            for field in &fields {
+                let (value, next) = rest.split_at(field.len());
+                if !field.accepts(value) {
+                    return Err(AttErrorCode::OUT_OF_RANGE);
+                }
+                rest = next;
+            }
+            Ok(())
+        })
+    }
+
+    /// Accept only values that both validators accept, checking this one
+    /// first
+    pub fn and(self, other: WriteValidator) -> Self {
+        Self::new(move |value| {
+            self.validate(value)?;
+            other.validate(value)
+        })
+    }
+
+    /// Check a value written at offset 0
+    pub fn validate(&self, value: &[u8]) -> Result<(), AttErrorCode> {
+        (self.0)(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_length() {
+        let validator = WriteValidator::length(1..=2);
+
+        assert_eq!(validator.validate(&[]), Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH));
+        assert_eq!(validator.validate(&[1]), Ok(()));
+        assert_eq!(validator.validate(&[1, 2]), Ok(()));
+        assert_eq!(
+            validator.validate(&[1, 2, 3]),
+            Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH)
+        );
+    }
+
+    #[test]
+    fn test_one_of() {
+        let validator = WriteValidator::one_of(vec![vec![0], vec![1]]);
+
+        assert_eq!(validator.validate(&[1]), Ok(()));
+        assert_eq!(validator.validate(&[2]), Err(AttErrorCode::VALUE_NOT_ALLOWED));
+        assert_eq!(validator.validate(&[0, 1]), Err(AttErrorCode::VALUE_NOT_ALLOWED));
+    }
+
+    #[test]
+    fn test_schema() {
+        let validator = WriteValidator::schema(vec![
+            FieldSchema::U8(0..=1),
+            FieldSchema::U16(0x0100..=0x01FF),
+            FieldSchema::Bytes(1),
+        ]);
+
+        assert_eq!(validator.validate(&[1, 0x23, 0x01, 0xFF]), Ok(()));
+        assert_eq!(validator.validate(&[2, 0x23, 0x01, 0xFF]), Err(AttErrorCode::OUT_OF_RANGE));
+        assert_eq!(validator.validate(&[1, 0x23, 0x02, 0xFF]), Err(AttErrorCode::OUT_OF_RANGE));
+        assert_eq!(
+            validator.validate(&[1, 0x23, 0x01]),
+            Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH)
+        );
+    }
+
+    #[test]
+    fn test_and() {
+        let validator = WriteValidator::length(1..=1).and(WriteValidator::new(|value| {
+            if value[0] % 2 != 0 {
+                return Err(AttErrorCode::APPLICATION_ERROR);
+            }
+            Ok(())
+        }));
+
+        assert_eq!(validator.validate(&[2]), Ok(()));
+        assert_eq!(validator.validate(&[3]), Err(AttErrorCode::APPLICATION_ERROR));
+        assert_eq!(validator.validate(&[]), Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH));
+    }
+}
@@ -16,7 +16,7 @@
 //! dependency order.
 
 use connection::le_manager::InactiveLeAclManager;
-use gatt::{channel::AttTransport, GattCallbacks};
+use gatt::{channel::AttTransport, security_manager::SecurityManager, GattCallbacks};
 use log::{info, warn};
 use tokio::task::LocalSet;
 
@@ -37,6 +37,7 @@ pub mod connection;
 pub mod core;
 pub mod gatt;
 pub mod packets;
+pub mod profiles;
 pub mod utils;
 
 /// The owner of the main Rust thread on which all Rust modules run
@@ -68,6 +69,7 @@ impl GlobalModuleRegistry {
     pub fn start(
         gatt_callbacks: Rc<dyn GattCallbacks>,
         att_transport: Rc<dyn AttTransport>,
+        security_manager: Rc<dyn SecurityManager>,
         le_acl_manager: impl InactiveLeAclManager,
         on_started: impl FnOnce(),
     ) {
@@ -93,7 +95,11 @@ impl GlobalModuleRegistry {
             // Then follow the pure-Rust modules
             let gatt_incoming_callbacks =
                 Rc::new(gatt::callbacks::CallbackTransactionManager::new(gatt_callbacks.clone()));
-            let gatt_module = &mut gatt::server::GattModule::new(att_transport.clone(), arbiter);
+            let gatt_module = &mut gatt::server::GattModule::new(
+                att_transport.clone(),
+                security_manager,
+                arbiter,
+            );
 
             let connection_manager = connection::ConnectionManager::new(le_acl_manager);
 
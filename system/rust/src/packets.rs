@@ -13,3 +13,6 @@ impl std::cmp::PartialEq for SerializeError {
         std::mem::discriminant(self) == std::mem::discriminant(rhs)
     }
 }
+
+#[cfg(test)]
+mod conformance_test;
@@ -0,0 +1,616 @@
+//! Golden wire-format vectors for every ATT opcode, laid out as in the PDU
+//! definitions of Core Spec 5.3 Vol 3F 3.4. Each vector is checked both ways:
+//! the builder must produce exactly the expected bytes, and parsing the bytes
+//! and rebuilding the PDU from the parsed view must yield the same builder.
+
+use crate::{
+    core::uuid::Uuid,
+    gatt::ids::AttHandle,
+    utils::packet::{build_att_data, HACK_child_to_opcode},
+};
+
+use super::*;
+
+struct Vector {
+    name: &'static str,
+    bytes: &'static [u8],
+    pdu: AttChild,
+}
+
+fn handle(handle: u16) -> AttHandleBuilder {
+    AttHandle(handle).into()
+}
+
+fn raw(value: &[u8]) -> AttAttributeDataBuilder {
+    build_att_data(AttAttributeDataChild::RawData(value.into()))
+}
+
+fn vectors() -> Vec<Vector> {
+    vec![
+        Vector {
+            name: "ATT_ERROR_RSP (3.4.1.1)",
+            bytes: &[0x01, 0x0a, 0x03, 0x00, 0x0a],
+            pdu: AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::READ_REQUEST,
+                handle_in_error: handle(0x0003),
+                error_code: AttErrorCode::ATTRIBUTE_NOT_FOUND,
+            }
+            .into(),
+        },
+        Vector {
+            name: "ATT_EXCHANGE_MTU_REQ (3.4.2.1)",
+            bytes: &[0x02, 0x05, 0x02],
+            pdu: AttExchangeMtuRequestBuilder { mtu: 517 }.into(),
+        },
+        Vector {
+            name: "ATT_EXCHANGE_MTU_RSP (3.4.2.2)",
+            bytes: &[0x03, 0x17, 0x00],
+            pdu: AttExchangeMtuResponseBuilder { mtu: 23 }.into(),
+        },
+        Vector {
+            name: "ATT_FIND_INFORMATION_REQ (3.4.3.1)",
+            bytes: &[0x04, 0x01, 0x00, 0xff, 0xff],
+            pdu: AttFindInformationRequestBuilder {
+                starting_handle: handle(0x0001),
+                ending_handle: handle(0xffff),
+            }
+            .into(),
+        },
+        Vector {
+            name: "ATT_FIND_INFORMATION_RSP, 16-bit UUIDs (3.4.3.2)",
+            bytes: &[0x05, 0x01, 0x03, 0x00, 0x02, 0x29],
+            pdu: AttFindInformationResponseBuilder {
+                format: AttFindInformationResponseFormat::SHORT,
+                _child_: AttFindInformationShortResponseBuilder {
+                    data: [AttFindInformationResponseShortEntryBuilder {
+                        handle: handle(0x0003),
+                        uuid: Uuid::new(0x2902).try_into().unwrap(),
+                    }]
+                    .into(),
+                }
+                .into(),
+            }
+            .into(),
+        },
+        Vector {
+            name: "ATT_FIND_INFORMATION_RSP, 128-bit UUIDs (3.4.3.2)",
+            bytes: &[
+                0x05, 0x02, 0x04, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09,
+                0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+            ],
+            pdu: AttFindInformationResponseBuilder {
+                format: AttFindInformationResponseFormat::LONG,
+                _child_: AttFindInformationLongResponseBuilder {
+                    data: [AttFindInformationResponseLongEntryBuilder {
+                        handle: handle(0x0004),
+                        uuid: Uuid::try_from_le_slice(&[
+                            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b,
+                            0x0c, 0x0d, 0x0e, 0x0f,
+                        ])
+                        .unwrap()
+                        .into(),
+                    }]
+                    .into(),
+                }
+                .into(),
+            }
+            .into(),
+        },
+        Vector {
+            name: "ATT_FIND_BY_TYPE_VALUE_REQ (3.4.3.3)",
+            bytes: &[0x06, 0x01, 0x00, 0xff, 0xff, 0x00, 0x28, 0x0f, 0x18],
+            pdu: AttFindByTypeValueRequestBuilder {
+                starting_handle: handle(0x0001),
+                ending_handle: handle(0xffff),
+                attribute_type: Uuid::new(0x2800).try_into().unwrap(),
+                attribute_value: raw(&[0x0f, 0x18]),
+            }
+            .into(),
+        },
+        Vector {
+            name: "ATT_FIND_BY_TYPE_VALUE_RSP (3.4.3.4)",
+            bytes: &[0x07, 0x10, 0x00, 0x15, 0x00],
+            pdu: AttFindByTypeValueResponseBuilder {
+                handles_info: [AttributeHandleRangeBuilder {
+                    found_attribute_handle: handle(0x0010),
+                    group_end_handle: handle(0x0015),
+                }]
+                .into(),
+            }
+            .into(),
+        },
+        Vector {
+            name: "ATT_READ_BY_TYPE_REQ (3.4.4.1)",
+            bytes: &[0x08, 0x01, 0x00, 0x0f, 0x00, 0x03, 0x28],
+            pdu: AttReadByTypeRequestBuilder {
+                starting_handle: handle(0x0001),
+                ending_handle: handle(0x000f),
+                attribute_type: Uuid::new(0x2803).into(),
+            }
+            .into(),
+        },
+        Vector {
+            name: "ATT_READ_BY_TYPE_RSP (3.4.4.2)",
+            bytes: &[0x09, 0x07, 0x02, 0x00, 0x02, 0x03, 0x00, 0x00, 0x2a],
+            pdu: AttReadByTypeResponseBuilder {
+                data: [AttReadByTypeDataElementBuilder {
+                    handle: handle(0x0002),
+                    value: raw(&[0x02, 0x03, 0x00, 0x00, 0x2a]),
+                }]
+                .into(),
+            }
+            .into(),
+        },
+        Vector {
+            name: "ATT_READ_REQ (3.4.4.3)",
+            bytes: &[0x0a, 0x03, 0x00],
+            pdu: AttReadRequestBuilder { attribute_handle: handle(0x0003) }.into(),
+        },
+        Vector {
+            name: "ATT_READ_RSP (3.4.4.4)",
+            bytes: &[0x0b, 0x61, 0x62, 0x63],
+            pdu: AttReadResponseBuilder { value: raw(b"abc") }.into(),
+        },
+        Vector {
+            name: "ATT_READ_BLOB_REQ (3.4.4.5)",
+            bytes: &[0x0c, 0x03, 0x00, 0x16, 0x00],
+            pdu: AttReadBlobRequestBuilder { attribute_handle: handle(0x0003), offset: 22 }.into(),
+        },
+        Vector {
+            name: "ATT_READ_BLOB_RSP (3.4.4.6)",
+            bytes: &[0x0d, 0x01, 0x02],
+            pdu: AttReadBlobResponseBuilder { value: raw(&[0x01, 0x02]) }.into(),
+        },
+        Vector {
+            name: "ATT_READ_MULTIPLE_REQ (3.4.4.7)",
+            bytes: &[0x0e, 0x03, 0x00, 0x05, 0x00],
+            pdu: AttReadMultipleRequestBuilder {
+                children: [handle(0x0003), handle(0x0005)].into(),
+            }
+            .into(),
+        },
+        Vector {
+            name: "ATT_READ_MULTIPLE_RSP (3.4.4.8)",
+            bytes: &[0x0f, 0x01, 0x02, 0x03],
+            pdu: AttReadMultipleResponseBuilder { value: raw(&[0x01, 0x02, 0x03]) }.into(),
+        },
+        Vector {
+            name: "ATT_READ_BY_GROUP_TYPE_REQ (3.4.4.9)",
+            bytes: &[0x10, 0x01, 0x00, 0xff, 0xff, 0x00, 0x28],
+            pdu: AttReadByGroupTypeRequestBuilder {
+                starting_handle: handle(0x0001),
+                ending_handle: handle(0xffff),
+                attribute_group_type: Uuid::new(0x2800).into(),
+            }
+            .into(),
+        },
+        Vector {
+            name: "ATT_READ_BY_GROUP_TYPE_RSP (3.4.4.10)",
+            bytes: &[0x11, 0x06, 0x01, 0x00, 0x05, 0x00, 0x00, 0x18],
+            pdu: AttReadByGroupTypeResponseBuilder {
+                data: [AttReadByGroupTypeDataElementBuilder {
+                    handle: handle(0x0001),
+                    end_group_handle: handle(0x0005),
+                    value: raw(&[0x00, 0x18]),
+                }]
+                .into(),
+            }
+            .into(),
+        },
+        Vector {
+            name: "ATT_READ_MULTIPLE_VARIABLE_REQ (3.4.4.11)",
+            bytes: &[0x20, 0x03, 0x00, 0x05, 0x00],
+            pdu: AttReadMultipleVariableRequestBuilder {
+                children: [handle(0x0003), handle(0x0005)].into(),
+            }
+            .into(),
+        },
+        Vector {
+            name: "ATT_READ_MULTIPLE_VARIABLE_RSP (3.4.4.12)",
+            bytes: &[0x21, 0x01, 0x00, 0xaa, 0x02, 0x00, 0xbb, 0xcc],
+            pdu: AttReadMultipleVariableResponseBuilder {
+                value: raw(&[0x01, 0x00, 0xaa, 0x02, 0x00, 0xbb, 0xcc]),
+            }
+            .into(),
+        },
+        Vector {
+            name: "ATT_WRITE_REQ (3.4.5.1)",
+            bytes: &[0x12, 0x0f, 0x00, 0x01, 0x00],
+            pdu: AttWriteRequestBuilder { handle: handle(0x000f), value: raw(&[0x01, 0x00]) }
+                .into(),
+        },
+        Vector {
+            name: "ATT_WRITE_RSP (3.4.5.2)",
+            bytes: &[0x13],
+            pdu: AttWriteResponseBuilder {}.into(),
+        },
+        Vector {
+            name: "ATT_WRITE_CMD (3.4.5.3)",
+            bytes: &[0x52, 0x03, 0x00, 0xaa],
+            pdu: AttWriteCommandBuilder { handle: handle(0x0003), value: raw(&[0xaa]) }.into(),
+        },
+        Vector {
+            name: "ATT_SIGNED_WRITE_CMD (3.4.5.4)",
+            bytes: &[
+                0xd2, 0x03, 0x00, 0xaa, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a,
+                0x0b, 0x0c,
+            ],
+            pdu: AttSignedWriteCommandBuilder {
+                handle: handle(0x0003),
+                value: raw(&[
+                    0xaa, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c,
+                ]),
+            }
+            .into(),
+        },
+        Vector {
+            name: "ATT_PREPARE_WRITE_REQ (3.4.6.1)",
+            bytes: &[0x16, 0x03, 0x00, 0x12, 0x00, 0x01, 0x02],
+            pdu: AttPrepareWriteRequestBuilder {
+                handle: handle(0x0003),
+                offset: 0x12,
+                value: raw(&[0x01, 0x02]),
+            }
+            .into(),
+        },
+        Vector {
+            name: "ATT_PREPARE_WRITE_RSP (3.4.6.2)",
+            bytes: &[0x17, 0x03, 0x00, 0x12, 0x00, 0x01, 0x02],
+            pdu: AttPrepareWriteResponseBuilder {
+                handle: handle(0x0003),
+                offset: 0x12,
+                value: raw(&[0x01, 0x02]),
+            }
+            .into(),
+        },
+        Vector {
+            name: "ATT_EXECUTE_WRITE_REQ, cancel (3.4.6.3)",
+            bytes: &[0x18, 0x00],
+            pdu: AttExecuteWriteRequestBuilder { flags: AttExecuteWriteFlags::CANCEL }.into(),
+        },
+        Vector {
+            name: "ATT_EXECUTE_WRITE_REQ, execute (3.4.6.3)",
+            bytes: &[0x18, 0x01],
+            pdu: AttExecuteWriteRequestBuilder { flags: AttExecuteWriteFlags::EXECUTE }.into(),
+        },
+        Vector {
+            name: "ATT_EXECUTE_WRITE_RSP (3.4.6.4)",
+            bytes: &[0x19],
+            pdu: AttExecuteWriteResponseBuilder {}.into(),
+        },
+        Vector {
+            name: "ATT_HANDLE_VALUE_NTF (3.4.7.1)",
+            bytes: &[0x1b, 0x03, 0x00, 0x01, 0x02],
+            pdu: AttHandleValueNotificationBuilder {
+                handle: handle(0x0003),
+                value: raw(&[0x01, 0x02]),
+            }
+            .into(),
+        },
+        Vector {
+            name: "ATT_MULTIPLE_HANDLE_VALUE_NTF (3.4.7.4)",
+            bytes: &[0x23, 0x03, 0x00, 0x01, 0x00, 0x01, 0x05, 0x00, 0x02, 0x00, 0x02, 0x03],
+            pdu: AttMultipleHandleValueNotificationBuilder {
+                value: raw(&[0x03, 0x00, 0x01, 0x00, 0x01, 0x05, 0x00, 0x02, 0x00, 0x02, 0x03]),
+            }
+            .into(),
+        },
+        Vector {
+            name: "ATT_HANDLE_VALUE_IND (3.4.7.2)",
+            bytes: &[0x1d, 0x03, 0x00, 0x01, 0x02],
+            pdu: AttHandleValueIndicationBuilder {
+                handle: handle(0x0003),
+                value: raw(&[0x01, 0x02]),
+            }
+            .into(),
+        },
+        Vector {
+            name: "ATT_HANDLE_VALUE_CFM (3.4.7.3)",
+            bytes: &[0x1e],
+            pdu: AttHandleValueConfirmationBuilder {}.into(),
+        },
+    ]
+}
+
+fn parsed_handle(view: AttHandleView<'_>) -> AttHandleBuilder {
+    AttHandle::from(view).into()
+}
+
+fn parsed_data(view: AttAttributeDataView<'_>) -> AttAttributeDataBuilder {
+    raw(&view.get_raw_payload().collect::<Vec<_>>())
+}
+
+fn parsed_uuid(view: UuidView<'_>) -> UuidBuilder {
+    Uuid::try_from(view).unwrap().into()
+}
+
+/// Rebuild a PDU from its parsed view, field by field
+fn rebuild(view: AttView<'_>) -> AttChild {
+    match view.get_opcode() {
+        AttOpcode::ERROR_RESPONSE => {
+            let view = AttErrorResponseView::try_parse(view).unwrap();
+            AttErrorResponseBuilder {
+                opcode_in_error: view.get_opcode_in_error(),
+                handle_in_error: parsed_handle(view.get_handle_in_error()),
+                error_code: view.get_error_code(),
+            }
+            .into()
+        }
+        AttOpcode::EXCHANGE_MTU_REQUEST => {
+            let view = AttExchangeMtuRequestView::try_parse(view).unwrap();
+            AttExchangeMtuRequestBuilder { mtu: view.get_mtu() }.into()
+        }
+        AttOpcode::EXCHANGE_MTU_RESPONSE => {
+            let view = AttExchangeMtuResponseView::try_parse(view).unwrap();
+            AttExchangeMtuResponseBuilder { mtu: view.get_mtu() }.into()
+        }
+        AttOpcode::FIND_INFORMATION_REQUEST => {
+            let view = AttFindInformationRequestView::try_parse(view).unwrap();
+            AttFindInformationRequestBuilder {
+                starting_handle: parsed_handle(view.get_starting_handle()),
+                ending_handle: parsed_handle(view.get_ending_handle()),
+            }
+            .into()
+        }
+        AttOpcode::FIND_INFORMATION_RESPONSE => {
+            let view = AttFindInformationResponseView::try_parse(view).unwrap();
+            let format = view.get_format();
+            let child = match format {
+                AttFindInformationResponseFormat::SHORT => {
+                    let view = AttFindInformationShortResponseView::try_parse(view).unwrap();
+                    AttFindInformationShortResponseBuilder {
+                        data: view
+                            .get_data_iter()
+                            .map(|entry| AttFindInformationResponseShortEntryBuilder {
+                                handle: parsed_handle(entry.get_handle()),
+                                uuid: Uuid::from(entry.get_uuid()).try_into().unwrap(),
+                            })
+                            .collect(),
+                    }
+                    .into()
+                }
+                AttFindInformationResponseFormat::LONG => {
+                    let view = AttFindInformationLongResponseView::try_parse(view).unwrap();
+                    AttFindInformationLongResponseBuilder {
+                        data: view
+                            .get_data_iter()
+                            .map(|entry| AttFindInformationResponseLongEntryBuilder {
+                                handle: parsed_handle(entry.get_handle()),
+                                uuid: Uuid::from(entry.get_uuid()).into(),
+                            })
+                            .collect(),
+                    }
+                    .into()
+                }
+            };
+            AttFindInformationResponseBuilder { format, _child_: child }.into()
+        }
+        AttOpcode::FIND_BY_TYPE_VALUE_REQUEST => {
+            let view = AttFindByTypeValueRequestView::try_parse(view).unwrap();
+            AttFindByTypeValueRequestBuilder {
+                starting_handle: parsed_handle(view.get_starting_handle()),
+                ending_handle: parsed_handle(view.get_ending_handle()),
+                attribute_type: Uuid::from(view.get_attribute_type()).try_into().unwrap(),
+                attribute_value: parsed_data(view.get_attribute_value()),
+            }
+            .into()
+        }
+        AttOpcode::FIND_BY_TYPE_VALUE_RESPONSE => {
+            let view = AttFindByTypeValueResponseView::try_parse(view).unwrap();
+            AttFindByTypeValueResponseBuilder {
+                handles_info: view
+                    .get_handles_info_iter()
+                    .map(|range| AttributeHandleRangeBuilder {
+                        found_attribute_handle: parsed_handle(range.get_found_attribute_handle()),
+                        group_end_handle: parsed_handle(range.get_group_end_handle()),
+                    })
+                    .collect(),
+            }
+            .into()
+        }
+        AttOpcode::READ_BY_TYPE_REQUEST => {
+            let view = AttReadByTypeRequestView::try_parse(view).unwrap();
+            AttReadByTypeRequestBuilder {
+                starting_handle: parsed_handle(view.get_starting_handle()),
+                ending_handle: parsed_handle(view.get_ending_handle()),
+                attribute_type: parsed_uuid(view.get_attribute_type()),
+            }
+            .into()
+        }
+        AttOpcode::READ_BY_TYPE_RESPONSE => {
+            let view = AttReadByTypeResponseView::try_parse(view).unwrap();
+            AttReadByTypeResponseBuilder {
+                data: view
+                    .get_data_iter()
+                    .map(|element| AttReadByTypeDataElementBuilder {
+                        handle: parsed_handle(element.get_handle()),
+                        value: parsed_data(element.get_value()),
+                    })
+                    .collect(),
+            }
+            .into()
+        }
+        AttOpcode::READ_REQUEST => {
+            let view = AttReadRequestView::try_parse(view).unwrap();
+            AttReadRequestBuilder { attribute_handle: parsed_handle(view.get_attribute_handle()) }
+                .into()
+        }
+        AttOpcode::READ_RESPONSE => {
+            let view = AttReadResponseView::try_parse(view).unwrap();
+            AttReadResponseBuilder { value: parsed_data(view.get_value()) }.into()
+        }
+        AttOpcode::READ_BLOB_REQUEST => {
+            let view = AttReadBlobRequestView::try_parse(view).unwrap();
+            AttReadBlobRequestBuilder {
+                attribute_handle: parsed_handle(view.get_attribute_handle()),
+                offset: view.get_offset(),
+            }
+            .into()
+        }
+        AttOpcode::READ_BLOB_RESPONSE => {
+            let view = AttReadBlobResponseView::try_parse(view).unwrap();
+            AttReadBlobResponseBuilder { value: parsed_data(view.get_value()) }.into()
+        }
+        AttOpcode::READ_MULTIPLE_REQUEST => {
+            let view = AttReadMultipleRequestView::try_parse(view).unwrap();
+            AttReadMultipleRequestBuilder {
+                children: view.get_children_iter().map(parsed_handle).collect(),
+            }
+            .into()
+        }
+        AttOpcode::READ_MULTIPLE_RESPONSE => {
+            let view = AttReadMultipleResponseView::try_parse(view).unwrap();
+            AttReadMultipleResponseBuilder { value: parsed_data(view.get_value()) }.into()
+        }
+        AttOpcode::READ_BY_GROUP_TYPE_REQUEST => {
+            let view = AttReadByGroupTypeRequestView::try_parse(view).unwrap();
+            AttReadByGroupTypeRequestBuilder {
+                starting_handle: parsed_handle(view.get_starting_handle()),
+                ending_handle: parsed_handle(view.get_ending_handle()),
+                attribute_group_type: parsed_uuid(view.get_attribute_group_type()),
+            }
+            .into()
+        }
+        AttOpcode::READ_BY_GROUP_TYPE_RESPONSE => {
+            let view = AttReadByGroupTypeResponseView::try_parse(view).unwrap();
+            AttReadByGroupTypeResponseBuilder {
+                data: view
+                    .get_data_iter()
+                    .map(|element| AttReadByGroupTypeDataElementBuilder {
+                        handle: parsed_handle(element.get_handle()),
+                        end_group_handle: parsed_handle(element.get_end_group_handle()),
+                        value: parsed_data(element.get_value()),
+                    })
+                    .collect(),
+            }
+            .into()
+        }
+        AttOpcode::READ_MULTIPLE_VARIABLE_REQUEST => {
+            let view = AttReadMultipleVariableRequestView::try_parse(view).unwrap();
+            AttReadMultipleVariableRequestBuilder {
+                children: view.get_children_iter().map(parsed_handle).collect(),
+            }
+            .into()
+        }
+        AttOpcode::READ_MULTIPLE_VARIABLE_RESPONSE => {
+            let view = AttReadMultipleVariableResponseView::try_parse(view).unwrap();
+            AttReadMultipleVariableResponseBuilder { value: parsed_data(view.get_value()) }.into()
+        }
+        AttOpcode::WRITE_REQUEST => {
+            let view = AttWriteRequestView::try_parse(view).unwrap();
+            AttWriteRequestBuilder {
+                handle: parsed_handle(view.get_handle()),
+                value: parsed_data(view.get_value()),
+            }
+            .into()
+        }
+        AttOpcode::WRITE_RESPONSE => {
+            AttWriteResponseView::try_parse(view).unwrap();
+            AttWriteResponseBuilder {}.into()
+        }
+        AttOpcode::WRITE_COMMAND => {
+            let view = AttWriteCommandView::try_parse(view).unwrap();
+            AttWriteCommandBuilder {
+                handle: parsed_handle(view.get_handle()),
+                value: parsed_data(view.get_value()),
+            }
+            .into()
+        }
+        AttOpcode::SIGNED_WRITE_COMMAND => {
+            let view = AttSignedWriteCommandView::try_parse(view).unwrap();
+            AttSignedWriteCommandBuilder {
+                handle: parsed_handle(view.get_handle()),
+                value: parsed_data(view.get_value()),
+            }
+            .into()
+        }
+        AttOpcode::PREPARE_WRITE_REQUEST => {
+            let view = AttPrepareWriteRequestView::try_parse(view).unwrap();
+            AttPrepareWriteRequestBuilder {
+                handle: parsed_handle(view.get_handle()),
+                offset: view.get_offset(),
+                value: parsed_data(view.get_value()),
+            }
+            .into()
+        }
+        AttOpcode::PREPARE_WRITE_RESPONSE => {
+            let view = AttPrepareWriteResponseView::try_parse(view).unwrap();
+            AttPrepareWriteResponseBuilder {
+                handle: parsed_handle(view.get_handle()),
+                offset: view.get_offset(),
+                value: parsed_data(view.get_value()),
+            }
+            .into()
+        }
+        AttOpcode::EXECUTE_WRITE_REQUEST => {
+            let view = AttExecuteWriteRequestView::try_parse(view).unwrap();
+            AttExecuteWriteRequestBuilder { flags: view.get_flags() }.into()
+        }
+        AttOpcode::EXECUTE_WRITE_RESPONSE => {
+            AttExecuteWriteResponseView::try_parse(view).unwrap();
+            AttExecuteWriteResponseBuilder {}.into()
+        }
+        AttOpcode::HANDLE_VALUE_NOTIFICATION => {
+            let view = AttHandleValueNotificationView::try_parse(view).unwrap();
+            AttHandleValueNotificationBuilder {
+                handle: parsed_handle(view.get_handle()),
+                value: parsed_data(view.get_value()),
+            }
+            .into()
+        }
+        AttOpcode::MULTIPLE_HANDLE_VALUE_NOTIFICATION => {
+            let view = AttMultipleHandleValueNotificationView::try_parse(view).unwrap();
+            AttMultipleHandleValueNotificationBuilder { value: parsed_data(view.get_value()) }
+                .into()
+        }
+        AttOpcode::HANDLE_VALUE_INDICATION => {
+            let view = AttHandleValueIndicationView::try_parse(view).unwrap();
+            AttHandleValueIndicationBuilder {
+                handle: parsed_handle(view.get_handle()),
+                value: parsed_data(view.get_value()),
+            }
+            .into()
+        }
+        AttOpcode::HANDLE_VALUE_CONFIRMATION => {
+            AttHandleValueConfirmationView::try_parse(view).unwrap();
+            AttHandleValueConfirmationBuilder {}.into()
+        }
+    }
+}
+
+#[test]
+fn test_builders_produce_golden_bytes() {
+    for Vector { name, bytes, pdu } in vectors() {
+        let serialized = AttBuilder { opcode: HACK_child_to_opcode(&pdu), _child_: pdu }.to_vec();
+
+        assert_eq!(serialized.as_deref(), Ok(bytes), "{name}");
+    }
+}
+
+#[test]
+fn test_golden_bytes_round_trip() {
+    for Vector { name, bytes, pdu } in vectors() {
+        let parsed = OwnedAttView::try_parse(bytes.into()).unwrap();
+
+        let rebuilt = rebuild(parsed.view());
+
+        assert_eq!(rebuilt, pdu, "{name}");
+        assert_eq!(
+            AttBuilder { opcode: parsed.view().get_opcode(), _child_: rebuilt }.to_vec().as_deref(),
+            Ok(bytes),
+            "{name}"
+        );
+    }
+}
+
+#[test]
+fn test_every_opcode_has_a_vector() {
+    let covered = vectors()
+        .iter()
+        .map(|vector| AttOpcode::try_from(vector.bytes[0]).unwrap())
+        .collect::<Vec<_>>();
+
+    for opcode in (0..=u8::MAX).filter_map(|opcode| AttOpcode::try_from(opcode).ok()) {
+        assert!(covered.contains(&opcode), "no vector for {opcode:?}");
+    }
+}
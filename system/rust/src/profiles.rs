@@ -0,0 +1,7 @@
+//! This module contains the profiles implemented natively on top of the
+//! GattModule. Unlike the built-in GAP and GATT services, they are optional:
+//! the upper layers add them to the servers that should expose them.
+
+pub mod bas;
+pub mod cts;
+pub mod hogp;
@@ -0,0 +1,343 @@
+//! The Battery Service, as defined in the Battery Service Specification 1.0.
+//! Each battery of the device is exposed by its own instance of the service,
+//! whose Battery Level characteristic may be read and notified.
+//!
+//! The levels are supplied by the platform, which reports every change so
+//! that subscribed clients are notified. As per BAS 1.0 3.1.2.1, when the
+//! device exposes several batteries, each Battery Level has a Characteristic
+//! Presentation Format descriptor telling the instances apart.
+
+use std::{cell::Cell, collections::HashMap, future::Future, rc::Rc};
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+
+use crate::{
+    core::uuid::Uuid,
+    gatt::{
+        callbacks::GattDatastore,
+        ffi::AttributeBackingType,
+        ids::{AttHandle, ConnectionId, ServerId, TransportIndex},
+        server::{
+            gatt_database::{
+                AttPermissions, CharacteristicBuilder, PresentationFormat, ServiceBuilder,
+                ServiceToken,
+            },
+            GattModule, NotificationError,
+        },
+    },
+    packets::AttErrorCode,
+};
+
+/// The UUID used for the Battery Service (Assigned Numbers 3.4.2 Services by
+/// Name)
+pub const BATTERY_SERVICE_UUID: Uuid = Uuid::new(0x180F);
+/// The UUID used for the Battery Level characteristic (Assigned Numbers 3.8.1
+/// Characteristics by Name)
+pub const BATTERY_LEVEL_UUID: Uuid = Uuid::new(0x2A19);
+
+/// The highest Battery Level, in percent
+pub const MAX_BATTERY_LEVEL: u8 = 100;
+
+/// The presentation of a Battery Level: an unsigned 8-bit percentage
+/// (Assigned Numbers 2.4 Formats and 3.5 Units), distinguished by its
+/// description in the Bluetooth SIG namespace ("first", "second", ...)
+fn presentation_format(battery: usize) -> PresentationFormat {
+    PresentationFormat {
+        format: 0x04,
+        exponent: 0,
+        unit: 0x27AD,
+        namespace: 0x01,
+        description: battery as u16 + 1,
+    }
+}
+
+/// Serves the Battery Level of a single battery
+struct BatteryLevel {
+    level: Rc<Cell<u8>>,
+}
+
+#[async_trait(?Send)]
+impl GattDatastore for BatteryLevel {
+    async fn read(
+        &self,
+        _: TransportIndex,
+        _: AttHandle,
+        _: AttributeBackingType,
+    ) -> Result<Vec<u8>, AttErrorCode> {
+        Ok(vec![self.level.get()])
+    }
+
+    async fn write(
+        &self,
+        _: TransportIndex,
+        _: AttHandle,
+        _: AttributeBackingType,
+        _: &[u8],
+    ) -> Result<(), AttErrorCode> {
+        unreachable!("the Battery Level is not writable")
+    }
+}
+
+/// A battery exposed by an instance of the Battery Service
+struct Battery {
+    token: ServiceToken,
+    value_handle: AttHandle,
+    level: Rc<Cell<u8>>,
+}
+
+/// The instances of the Battery Service on a server, one per battery
+pub struct BatteryService {
+    server_id: ServerId,
+    batteries: Vec<Battery>,
+}
+
+impl BatteryService {
+    /// Add an instance of the Battery Service to the given server for each
+    /// battery, with the given initial levels (in percent)
+    pub fn register(gatt: &mut GattModule, server_id: ServerId, levels: &[u8]) -> Result<Self> {
+        if levels.is_empty() {
+            bail!("the Battery Service needs at least one battery");
+        }
+        if let Some(level) = levels.iter().find(|level| **level > MAX_BATTERY_LEVEL) {
+            bail!("invalid battery level {level}");
+        }
+        let mut service = Self { server_id, batteries: vec![] };
+        for (battery, level) in levels.iter().enumerate() {
+            let mut characteristic = CharacteristicBuilder::new(
+                BATTERY_LEVEL_UUID,
+                AttPermissions::READABLE | AttPermissions::NOTIFY,
+            );
+            if levels.len() > 1 {
+                characteristic = characteristic.presentation_format(presentation_format(battery));
+            }
+            let level = Rc::new(Cell::new(*level));
+            let token = match gatt.add_gatt_service(
+                server_id,
+                ServiceBuilder::new(BATTERY_SERVICE_UUID).characteristic(characteristic),
+                BatteryLevel { level: level.clone() },
+            ) {
+                Ok(token) => token,
+                Err(err) => {
+                    // don't leave the instances added so far behind
+                    service.unregister(gatt)?;
+                    return Err(err);
+                }
+            };
+            // the value follows the service and characteristic declarations
+            let value_handle = AttHandle(token.handle().0 + 2);
+            service.batteries.push(Battery { token, value_handle, level });
+        }
+        Ok(service)
+    }
+
+    /// Remove every instance of the service from its server
+    pub fn unregister(self, gatt: &mut GattModule) -> Result<()> {
+        for battery in self.batteries {
+            gatt.remove_gatt_service(self.server_id, battery.token)?;
+        }
+        Ok(())
+    }
+
+    /// The current level of the given battery
+    pub fn battery_level(&self, battery: usize) -> Option<u8> {
+        self.batteries.get(battery).map(|battery| battery.level.get())
+    }
+
+    /// The platform reports that the level of the given battery changed, so it
+    /// is served from now on, and is notified to every subscribed client. The
+    /// returned future resolves to the outcome on each of their connections.
+    pub fn on_battery_level_changed(
+        &self,
+        gatt: &GattModule,
+        battery: usize,
+        level: u8,
+    ) -> Result<impl Future<Output = HashMap<ConnectionId, Result<(), NotificationError>>>> {
+        let Some(Battery { value_handle, level: current, .. }) = self.batteries.get(battery) else {
+            bail!("no battery {battery}");
+        };
+        if level > MAX_BATTERY_LEVEL {
+            bail!("invalid battery level {level}");
+        }
+        current.set(level);
+        gatt.notify_all(self.server_id, *value_handle, &[level])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    use crate::{
+        gatt::{
+            ids::AdvertiserId,
+            mocks::{mock_security_manager::MockSecurityManager, mock_transport::MockAttTransport},
+            server::isolation_manager::IsolationManager,
+        },
+        packets::{
+            AttAttributeDataChild, AttBuilder, AttChild, AttReadRequestBuilder,
+            AttReadResponseBuilder, AttWriteRequestBuilder,
+            GattClientCharacteristicConfigurationBuilder, Serializable,
+        },
+        utils::{
+            packet::{build_att_data, build_att_view_or_crash},
+            task::block_on_locally,
+        },
+    };
+
+    use super::*;
+
+    const TCB_IDX: TransportIndex = TransportIndex(1);
+    const SERVER_ID: ServerId = ServerId(2);
+    const ADVERTISER_ID: AdvertiserId = AdvertiserId(3);
+
+    fn start_gatt_module() -> (GattModule, UnboundedReceiver<(TransportIndex, AttBuilder)>) {
+        let (transport, transport_rx, _) = MockAttTransport::new();
+        let mut gatt = GattModule::new(
+            Rc::new(transport),
+            Rc::new(MockSecurityManager::new()),
+            Arc::new(Mutex::new(IsolationManager::new())),
+        );
+        gatt.open_gatt_server(SERVER_ID).unwrap();
+        gatt.get_isolation_manager().associate_server_with_advertiser(SERVER_ID, ADVERTISER_ID);
+        (gatt, transport_rx)
+    }
+
+    async fn read(
+        gatt: &GattModule,
+        transport_rx: &mut UnboundedReceiver<(TransportIndex, AttBuilder)>,
+        handle: AttHandle,
+    ) -> AttChild {
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttReadRequestBuilder { attribute_handle: handle.into() })
+                .view(),
+        );
+        transport_rx.recv().await.unwrap().1._child_
+    }
+
+    fn read_response(value: &[u8]) -> AttChild {
+        AttReadResponseBuilder {
+            value: build_att_data(AttAttributeDataChild::RawData(value.into())),
+        }
+        .into()
+    }
+
+    #[test]
+    fn test_battery_level_read() {
+        block_on_locally(async {
+            // arrange
+            let (mut gatt, mut transport_rx) = start_gatt_module();
+            let bas = BatteryService::register(&mut gatt, SERVER_ID, &[42]).unwrap();
+            gatt.on_le_connect(TCB_IDX, Some(ADVERTISER_ID)).unwrap();
+
+            // act
+            let resp = read(&gatt, &mut transport_rx, bas.batteries[0].value_handle).await;
+
+            // assert
+            assert_eq!(resp, read_response(&[42]));
+        });
+    }
+
+    #[test]
+    fn test_single_battery_has_no_presentation_format() {
+        block_on_locally(async {
+            // arrange
+            let (mut gatt, mut transport_rx) = start_gatt_module();
+            let bas = BatteryService::register(&mut gatt, SERVER_ID, &[42]).unwrap();
+            gatt.on_le_connect(TCB_IDX, Some(ADVERTISER_ID)).unwrap();
+
+            // act: read the attribute following the value
+            let value_handle = bas.batteries[0].value_handle;
+            let resp = read(&gatt, &mut transport_rx, AttHandle(value_handle.0 + 1)).await;
+
+            // assert: it is the (unsubscribed) CCCD
+            assert_eq!(resp, read_response(&[0x00, 0x00]));
+        });
+    }
+
+    #[test]
+    fn test_batteries_distinguished_by_presentation_format() {
+        block_on_locally(async {
+            // arrange
+            let (mut gatt, mut transport_rx) = start_gatt_module();
+            let bas = BatteryService::register(&mut gatt, SERVER_ID, &[42, 84]).unwrap();
+            gatt.on_le_connect(TCB_IDX, Some(ADVERTISER_ID)).unwrap();
+
+            // act: read the value and presentation format of the second battery
+            let value_handle = bas.batteries[1].value_handle;
+            let level = read(&gatt, &mut transport_rx, value_handle).await;
+            let format = read(&gatt, &mut transport_rx, AttHandle(value_handle.0 + 1)).await;
+
+            // assert
+            assert_eq!(level, read_response(&[84]));
+            assert_eq!(format, read_response(&[0x04, 0x00, 0xAD, 0x27, 0x01, 0x02, 0x00]));
+        });
+    }
+
+    #[test]
+    fn test_level_change_notified() {
+        block_on_locally(async {
+            // arrange: a client subscribed to the Battery Level
+            let (mut gatt, mut transport_rx) = start_gatt_module();
+            let bas = BatteryService::register(&mut gatt, SERVER_ID, &[42]).unwrap();
+            gatt.on_le_connect(TCB_IDX, Some(ADVERTISER_ID)).unwrap();
+            let value_handle = bas.batteries[0].value_handle;
+            gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+                build_att_view_or_crash(AttWriteRequestBuilder {
+                    handle: AttHandle(value_handle.0 + 1).into(),
+                    value: build_att_data(GattClientCharacteristicConfigurationBuilder {
+                        notification: 1,
+                        indication: 0,
+                    }),
+                })
+                .view(),
+            );
+            transport_rx.recv().await.unwrap();
+
+            // act
+            let results = bas.on_battery_level_changed(&gatt, 0, 41).unwrap().await;
+
+            // assert: the new level was notified, and is served from now on
+            let (_, notification) = transport_rx.recv().await.unwrap();
+            assert_eq!(notification.to_vec().unwrap(), [0x1B, value_handle.0 as u8, 0x00, 41]);
+            assert!(matches!(results[&ConnectionId::new(TCB_IDX, SERVER_ID)], Ok(())));
+            assert_eq!(bas.battery_level(0), Some(41));
+            assert_eq!(read(&gatt, &mut transport_rx, value_handle).await, read_response(&[41]));
+        });
+    }
+
+    #[test]
+    fn test_invalid_levels_rejected() {
+        let (mut gatt, _) = start_gatt_module();
+
+        assert!(BatteryService::register(&mut gatt, SERVER_ID, &[]).is_err());
+        assert!(BatteryService::register(&mut gatt, SERVER_ID, &[101]).is_err());
+        let bas = BatteryService::register(&mut gatt, SERVER_ID, &[42]).unwrap();
+        assert!(bas.on_battery_level_changed(&gatt, 0, 101).is_err());
+        assert!(bas.on_battery_level_changed(&gatt, 1, 42).is_err());
+    }
+
+    #[test]
+    fn test_unregister_removes_instances() {
+        block_on_locally(async {
+            // arrange
+            let (mut gatt, mut transport_rx) = start_gatt_module();
+            let bas = BatteryService::register(&mut gatt, SERVER_ID, &[42, 84]).unwrap();
+            let value_handle = bas.batteries[0].value_handle;
+
+            // act
+            bas.unregister(&mut gatt).unwrap();
+            gatt.on_le_connect(TCB_IDX, Some(ADVERTISER_ID)).unwrap();
+
+            // assert
+            let AttChild::AttErrorResponse(resp) =
+                read(&gatt, &mut transport_rx, value_handle).await
+            else {
+                unreachable!()
+            };
+            assert_eq!(resp.error_code, AttErrorCode::INVALID_HANDLE);
+        });
+    }
+}
@@ -0,0 +1,618 @@
+//! The server role of the Current Time Service, as defined in the Current
+//! Time Service Specification 1.1. Only the mandatory Current Time
+//! characteristic is exposed.
+//!
+//! The time is sourced from the platform, which reports every adjustment so
+//! that subscribed clients are notified, subject to the rate limits of CTS 1.1
+//! 3.1.2.1 for adjustments to an external reference time. Clients may
+//! optionally write the time back, once the platform authorizes them.
+
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    future::Future,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bitflags::bitflags;
+
+use crate::{
+    core::uuid::Uuid,
+    gatt::{
+        callbacks::GattDatastore,
+        ffi::AttributeBackingType,
+        ids::{AttHandle, ConnectionId, ServerId, TransportIndex},
+        server::{
+            authorization::{AttributeAccess, AuthorizationProvider},
+            gatt_database::{AttPermissions, CharacteristicBuilder, ServiceBuilder, ServiceToken},
+            GattModule, NotificationError,
+        },
+    },
+    packets::AttErrorCode,
+    utils::clock::Clock,
+};
+
+/// The UUID used for the Current Time Service (Assigned Numbers 3.4.2
+/// Services by Name)
+pub const CURRENT_TIME_SERVICE_UUID: Uuid = Uuid::new(0x1805);
+/// The UUID used for the Current Time characteristic (Assigned Numbers 3.8.1
+/// Characteristics by Name)
+pub const CURRENT_TIME_UUID: Uuid = Uuid::new(0x2A2B);
+
+/// Returned when a written time is accepted, but some of its fields are
+/// ignored (CTS 1.1 1.6)
+pub const DATA_FIELD_IGNORED: AttErrorCode = AttErrorCode::APPLICATION_ERROR;
+
+/// Adjustments to an external reference time smaller than this are not
+/// notified (CTS 1.1 3.1.2.1)
+pub const MIN_NOTIFIED_EXTERNAL_ADJUSTMENT: Duration = Duration::from_secs(60);
+/// Adjustments to an external reference time are not notified more often
+/// than this (CTS 1.1 3.1.2.1)
+pub const MIN_EXTERNAL_NOTIFICATION_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+bitflags! {
+    /// The reasons for an adjustment of the time (CTS 1.1 3.1.1)
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+    pub struct AdjustReason : u8 {
+        /// The time was set manually
+        const MANUAL_TIME_UPDATE = 0x01;
+        /// The time was synchronized to an external reference
+        const EXTERNAL_REFERENCE_TIME_UPDATE = 0x02;
+        /// The time zone changed
+        const CHANGE_OF_TIME_ZONE = 0x04;
+        /// Daylight saving time started or ended
+        const CHANGE_OF_DST = 0x08;
+    }
+}
+
+/// The local date and time, with 1/256 second resolution (the Exact Time 256
+/// characteristic). Fields that are not known are 0.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExactTime256 {
+    /// Between 1582 and 9999
+    pub year: u16,
+    /// Between 1 (January) and 12
+    pub month: u8,
+    /// Between 1 and 31
+    pub day: u8,
+    /// Between 0 and 23
+    pub hours: u8,
+    /// Between 0 and 59
+    pub minutes: u8,
+    /// Between 0 and 59
+    pub seconds: u8,
+    /// Between 1 (Monday) and 7
+    pub day_of_week: u8,
+    /// In units of 1/256 second
+    pub fractions256: u8,
+}
+
+impl ExactTime256 {
+    /// The length of the value of the Current Time characteristic, which
+    /// appends the Adjust Reason
+    const CURRENT_TIME_LEN: usize = 10;
+
+    fn to_current_time(self, adjust_reason: AdjustReason) -> Vec<u8> {
+        let [year_lo, year_hi] = self.year.to_le_bytes();
+        vec![
+            year_lo,
+            year_hi,
+            self.month,
+            self.day,
+            self.hours,
+            self.minutes,
+            self.seconds,
+            self.day_of_week,
+            self.fractions256,
+            adjust_reason.bits(),
+        ]
+    }
+
+    /// Parse a written Current Time value. Fails with OUT_OF_RANGE if a field
+    /// is invalid.
+    fn from_current_time(value: &[u8]) -> Result<(Self, AdjustReason), AttErrorCode> {
+        if value.len() != Self::CURRENT_TIME_LEN {
+            return Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH);
+        }
+        let time = Self {
+            year: u16::from_le_bytes([value[0], value[1]]),
+            month: value[2],
+            day: value[3],
+            hours: value[4],
+            minutes: value[5],
+            seconds: value[6],
+            day_of_week: value[7],
+            fractions256: value[8],
+        };
+        if !(time.year == 0 || (1582..=9999).contains(&time.year))
+            || time.month > 12
+            || time.day > 31
+            || time.hours > 23
+            || time.minutes > 59
+            || time.seconds > 59
+            || time.day_of_week > 7
+        {
+            return Err(AttErrorCode::OUT_OF_RANGE);
+        }
+        Ok((time, AdjustReason::from_bits_truncate(value[9])))
+    }
+}
+
+/// The source of the local time, supplied by the platform
+pub trait TimeSource {
+    /// The current local time
+    fn current_time(&self) -> ExactTime256;
+    /// A client on the given transport wrote the time (only if the service
+    /// was registered as writable, and once the client was authorized). Fails
+    /// with DATA_FIELD_IGNORED if only part of the time was applied.
+    fn set_current_time(
+        &self,
+        tcb_idx: TransportIndex,
+        time: ExactTime256,
+        adjust_reason: AdjustReason,
+    ) -> Result<(), AttErrorCode>;
+}
+
+/// Serves the Current Time characteristic
+struct CurrentTimeDatastore {
+    time_source: Rc<dyn TimeSource>,
+    write_authorization: Option<Rc<dyn AuthorizationProvider>>,
+    adjust_reason: Rc<Cell<AdjustReason>>,
+}
+
+#[async_trait(?Send)]
+impl GattDatastore for CurrentTimeDatastore {
+    async fn read(
+        &self,
+        _: TransportIndex,
+        _: AttHandle,
+        _: AttributeBackingType,
+    ) -> Result<Vec<u8>, AttErrorCode> {
+        Ok(self.time_source.current_time().to_current_time(self.adjust_reason.get()))
+    }
+
+    async fn write(
+        &self,
+        tcb_idx: TransportIndex,
+        handle: AttHandle,
+        _: AttributeBackingType,
+        data: &[u8],
+    ) -> Result<(), AttErrorCode> {
+        let Some(authorization) = &self.write_authorization else {
+            unreachable!("the Current Time is not writable")
+        };
+        if !authorization.authorize(tcb_idx, handle, AttributeAccess::Write).await {
+            return Err(AttErrorCode::INSUFFICIENT_AUTHORIZATION);
+        }
+        let (time, adjust_reason) = ExactTime256::from_current_time(data)?;
+        self.time_source.set_current_time(tcb_idx, time, adjust_reason)
+    }
+}
+
+/// The Current Time Service registered on a server
+pub struct CurrentTimeService {
+    server_id: ServerId,
+    token: ServiceToken,
+    value_handle: AttHandle,
+    time_source: Rc<dyn TimeSource>,
+    clock: Rc<dyn Clock>,
+    adjust_reason: Rc<Cell<AdjustReason>>,
+    /// When an adjustment to an external reference time was last notified
+    last_external_notification: Cell<Option<Instant>>,
+}
+
+impl CurrentTimeService {
+    /// Add the Current Time Service to the given server, serving the time from
+    /// the given source. If an AuthorizationProvider is supplied, clients may
+    /// also write the time, once it authorizes them. The clock is used to rate
+    /// limit notifications.
+    pub fn register(
+        gatt: &mut GattModule,
+        server_id: ServerId,
+        time_source: Rc<dyn TimeSource>,
+        write_authorization: Option<Rc<dyn AuthorizationProvider>>,
+        clock: Rc<dyn Clock>,
+    ) -> Result<Self> {
+        let mut permissions = AttPermissions::READABLE | AttPermissions::NOTIFY;
+        if write_authorization.is_some() {
+            permissions |= AttPermissions::WRITABLE_WITH_RESPONSE;
+        }
+        let adjust_reason = Rc::new(Cell::new(AdjustReason::empty()));
+        let token = gatt.add_gatt_service(
+            server_id,
+            ServiceBuilder::new(CURRENT_TIME_SERVICE_UUID)
+                .characteristic(CharacteristicBuilder::new(CURRENT_TIME_UUID, permissions)),
+            CurrentTimeDatastore {
+                time_source: time_source.clone(),
+                write_authorization,
+                adjust_reason: adjust_reason.clone(),
+            },
+        )?;
+        // the value follows the service and characteristic declarations
+        let value_handle = AttHandle(token.handle().0 + 2);
+        Ok(Self {
+            server_id,
+            token,
+            value_handle,
+            time_source,
+            clock,
+            adjust_reason,
+            last_external_notification: Cell::new(None),
+        })
+    }
+
+    /// Remove the service from its server
+    pub fn unregister(self, gatt: &mut GattModule) -> Result<()> {
+        gatt.remove_gatt_service(self.server_id, self.token)
+    }
+
+    /// The platform reports that the time was adjusted by the given amount
+    /// (in either direction) for the given reasons, so the new time is
+    /// notified to every subscribed client. Returns None if the adjustment is
+    /// not notified, as it is a small or frequent adjustment to an external
+    /// reference time. Otherwise, the future resolves to the outcome on each
+    /// subscribed connection.
+    pub fn on_time_adjusted(
+        &self,
+        gatt: &GattModule,
+        adjust_reason: AdjustReason,
+        adjustment: Duration,
+    ) -> Result<Option<impl Future<Output = HashMap<ConnectionId, Result<(), NotificationError>>>>>
+    {
+        self.adjust_reason.set(adjust_reason);
+        let now = self.clock.now();
+        // the limits only apply if the external reference is the sole reason
+        if adjust_reason == AdjustReason::EXTERNAL_REFERENCE_TIME_UPDATE {
+            if adjustment < MIN_NOTIFIED_EXTERNAL_ADJUSTMENT {
+                return Ok(None);
+            }
+            if let Some(last) = self.last_external_notification.get() {
+                if now < last + MIN_EXTERNAL_NOTIFICATION_INTERVAL {
+                    return Ok(None);
+                }
+            }
+            self.last_external_notification.set(Some(now));
+        }
+        let value = self.time_source.current_time().to_current_time(adjust_reason);
+        Ok(Some(gatt.notify_all(self.server_id, self.value_handle, &value)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        cell::RefCell,
+        sync::{Arc, Mutex},
+    };
+
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    use crate::{
+        gatt::{
+            ids::AdvertiserId,
+            mocks::{mock_security_manager::MockSecurityManager, mock_transport::MockAttTransport},
+            server::isolation_manager::IsolationManager,
+        },
+        packets::{
+            AttAttributeDataChild, AttBuilder, AttChild, AttReadRequestBuilder,
+            AttReadResponseBuilder, AttWriteRequestBuilder, AttWriteResponseBuilder,
+            GattClientCharacteristicConfigurationBuilder,
+        },
+        utils::{
+            clock::VirtualClock,
+            packet::{build_att_data, build_att_view_or_crash},
+            task::block_on_locally,
+        },
+    };
+
+    use super::*;
+
+    const TCB_IDX: TransportIndex = TransportIndex(1);
+    const SERVER_ID: ServerId = ServerId(2);
+    const ADVERTISER_ID: AdvertiserId = AdvertiserId(3);
+
+    const TIME: ExactTime256 = ExactTime256 {
+        year: 2023,
+        month: 6,
+        day: 15,
+        hours: 12,
+        minutes: 30,
+        seconds: 45,
+        day_of_week: 4,
+        fractions256: 128,
+    };
+    const TIME_VALUE: [u8; 9] = [0xE7, 0x07, 6, 15, 12, 30, 45, 4, 128];
+
+    #[derive(Default)]
+    struct TestTimeSource {
+        writes: RefCell<Vec<(ExactTime256, AdjustReason)>>,
+    }
+
+    impl TimeSource for TestTimeSource {
+        fn current_time(&self) -> ExactTime256 {
+            TIME
+        }
+
+        fn set_current_time(
+            &self,
+            _: TransportIndex,
+            time: ExactTime256,
+            adjust_reason: AdjustReason,
+        ) -> Result<(), AttErrorCode> {
+            self.writes.borrow_mut().push((time, adjust_reason));
+            Ok(())
+        }
+    }
+
+    struct TestAuthorization(bool);
+
+    #[async_trait(?Send)]
+    impl AuthorizationProvider for TestAuthorization {
+        async fn authorize(
+            &self,
+            _: TransportIndex,
+            _: AttHandle,
+            access: AttributeAccess,
+        ) -> bool {
+            assert_eq!(access, AttributeAccess::Write);
+            self.0
+        }
+    }
+
+    struct TestCts {
+        gatt: GattModule,
+        transport_rx: UnboundedReceiver<(TransportIndex, AttBuilder)>,
+        time_source: Rc<TestTimeSource>,
+        clock: Rc<VirtualClock>,
+        cts: CurrentTimeService,
+    }
+
+    fn start_cts(write_authorization: Option<bool>) -> TestCts {
+        let (transport, transport_rx, _) = MockAttTransport::new();
+        let mut gatt = GattModule::new(
+            Rc::new(transport),
+            Rc::new(MockSecurityManager::new()),
+            Arc::new(Mutex::new(IsolationManager::new())),
+        );
+        gatt.open_gatt_server(SERVER_ID).unwrap();
+        gatt.get_isolation_manager().associate_server_with_advertiser(SERVER_ID, ADVERTISER_ID);
+        let time_source = Rc::new(TestTimeSource::default());
+        let clock = Rc::new(VirtualClock::new());
+        let cts = CurrentTimeService::register(
+            &mut gatt,
+            SERVER_ID,
+            time_source.clone(),
+            write_authorization.map(|authorized| {
+                Rc::new(TestAuthorization(authorized)) as Rc<dyn AuthorizationProvider>
+            }),
+            clock.clone(),
+        )
+        .unwrap();
+        gatt.on_le_connect(TCB_IDX, Some(ADVERTISER_ID)).unwrap();
+        TestCts { gatt, transport_rx, time_source, clock, cts }
+    }
+
+    impl TestCts {
+        async fn request(&mut self, request: impl Into<AttChild>) -> AttChild {
+            self.gatt
+                .get_bearer(TCB_IDX)
+                .unwrap()
+                .handle_packet(build_att_view_or_crash(request).view());
+            self.transport_rx.recv().await.unwrap().1._child_
+        }
+
+        async fn write_time(&mut self, value: &[u8]) -> AttChild {
+            let handle = self.cts.value_handle;
+            self.request(AttWriteRequestBuilder {
+                handle: handle.into(),
+                value: build_att_data(AttAttributeDataChild::RawData(value.into())),
+            })
+            .await
+        }
+
+        async fn subscribe(&mut self) {
+            let handle = AttHandle(self.cts.value_handle.0 + 1);
+            self.request(AttWriteRequestBuilder {
+                handle: handle.into(),
+                value: build_att_data(GattClientCharacteristicConfigurationBuilder {
+                    notification: 1,
+                    indication: 0,
+                }),
+            })
+            .await;
+        }
+
+        /// Adjust the time, returning whether the adjustment was notified
+        async fn adjust(&mut self, adjust_reason: AdjustReason, adjustment: Duration) -> bool {
+            let Some(notification) =
+                self.cts.on_time_adjusted(&self.gatt, adjust_reason, adjustment).unwrap()
+            else {
+                return false;
+            };
+            notification.await;
+            self.transport_rx.recv().await.unwrap();
+            true
+        }
+    }
+
+    fn current_time(adjust_reason: AdjustReason) -> Vec<u8> {
+        let mut value = TIME_VALUE.to_vec();
+        value.push(adjust_reason.bits());
+        value
+    }
+
+    fn error_code(resp: AttChild) -> AttErrorCode {
+        let AttChild::AttErrorResponse(resp) = resp else {
+            unreachable!("{resp:?}");
+        };
+        resp.error_code
+    }
+
+    #[test]
+    fn test_read_current_time() {
+        block_on_locally(async {
+            // arrange
+            let mut cts = start_cts(None);
+            let handle = cts.cts.value_handle;
+
+            // act
+            let resp = cts.request(AttReadRequestBuilder { attribute_handle: handle.into() }).await;
+
+            // assert
+            assert_eq!(
+                resp,
+                AttReadResponseBuilder {
+                    value: build_att_data(AttAttributeDataChild::RawData(
+                        current_time(AdjustReason::empty()).into()
+                    ))
+                }
+                .into()
+            );
+        });
+    }
+
+    #[test]
+    fn test_time_adjustment_notified() {
+        block_on_locally(async {
+            // arrange
+            let mut cts = start_cts(None);
+            cts.subscribe().await;
+
+            // act
+            let notification = cts
+                .cts
+                .on_time_adjusted(&cts.gatt, AdjustReason::CHANGE_OF_TIME_ZONE, Duration::ZERO)
+                .unwrap()
+                .unwrap()
+                .await;
+
+            // assert: the new time is notified with the reason
+            let (_, resp) = cts.transport_rx.recv().await.unwrap();
+            let AttChild::AttHandleValueNotification(resp) = resp._child_ else {
+                unreachable!("{resp:?}");
+            };
+            assert_eq!(
+                resp.value,
+                build_att_data(AttAttributeDataChild::RawData(
+                    current_time(AdjustReason::CHANGE_OF_TIME_ZONE).into()
+                ))
+            );
+            assert!(matches!(notification[&ConnectionId::new(TCB_IDX, SERVER_ID)], Ok(())));
+        });
+    }
+
+    #[test]
+    fn test_small_external_adjustment_not_notified() {
+        block_on_locally(async {
+            // arrange
+            let mut cts = start_cts(None);
+            cts.subscribe().await;
+
+            // act
+            let small_external = cts
+                .adjust(AdjustReason::EXTERNAL_REFERENCE_TIME_UPDATE, Duration::from_secs(59))
+                .await;
+            let small_manual =
+                cts.adjust(AdjustReason::MANUAL_TIME_UPDATE, Duration::from_secs(59)).await;
+
+            // assert
+            assert!(!small_external);
+            assert!(small_manual);
+        });
+    }
+
+    #[test]
+    fn test_external_adjustments_rate_limited() {
+        block_on_locally(async {
+            // arrange
+            let mut cts = start_cts(None);
+            cts.subscribe().await;
+            let external = AdjustReason::EXTERNAL_REFERENCE_TIME_UPDATE;
+            let adjustment = Duration::from_secs(60);
+
+            // act
+            let first = cts.adjust(external, adjustment).await;
+            cts.clock.advance(MIN_EXTERNAL_NOTIFICATION_INTERVAL - Duration::from_secs(1)).await;
+            let too_soon = cts.adjust(external, adjustment).await;
+            let with_time_zone =
+                cts.adjust(external | AdjustReason::CHANGE_OF_TIME_ZONE, adjustment).await;
+            cts.clock.advance(Duration::from_secs(1)).await;
+            let later = cts.adjust(external, adjustment).await;
+
+            // assert
+            assert!(first);
+            assert!(!too_soon);
+            assert!(with_time_zone);
+            assert!(later);
+        });
+    }
+
+    #[test]
+    fn test_authorized_write() {
+        block_on_locally(async {
+            // arrange
+            let mut cts = start_cts(Some(true));
+
+            // act
+            let resp = cts.write_time(&current_time(AdjustReason::MANUAL_TIME_UPDATE)).await;
+
+            // assert
+            assert_eq!(resp, AttWriteResponseBuilder {}.into());
+            assert_eq!(
+                *cts.time_source.writes.borrow(),
+                [(TIME, AdjustReason::MANUAL_TIME_UPDATE)]
+            );
+        });
+    }
+
+    #[test]
+    fn test_unauthorized_write_rejected() {
+        block_on_locally(async {
+            // arrange
+            let mut cts = start_cts(Some(false));
+
+            // act
+            let resp = cts.write_time(&current_time(AdjustReason::MANUAL_TIME_UPDATE)).await;
+
+            // assert
+            assert_eq!(error_code(resp), AttErrorCode::INSUFFICIENT_AUTHORIZATION);
+            assert!(cts.time_source.writes.borrow().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_invalid_write_rejected() {
+        block_on_locally(async {
+            // arrange
+            let mut cts = start_cts(Some(true));
+            let mut invalid_hours = current_time(AdjustReason::MANUAL_TIME_UPDATE);
+            invalid_hours[4] = 24;
+
+            // act
+            let too_short = cts.write_time(&TIME_VALUE).await;
+            let out_of_range = cts.write_time(&invalid_hours).await;
+
+            // assert
+            assert_eq!(error_code(too_short), AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH);
+            assert_eq!(error_code(out_of_range), AttErrorCode::OUT_OF_RANGE);
+            assert!(cts.time_source.writes.borrow().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_not_writable_without_authorization_provider() {
+        block_on_locally(async {
+            // arrange
+            let mut cts = start_cts(None);
+
+            // act
+            let resp = cts.write_time(&current_time(AdjustReason::MANUAL_TIME_UPDATE)).await;
+
+            // assert
+            assert_eq!(error_code(resp), AttErrorCode::WRITE_NOT_PERMITTED);
+        });
+    }
+}
@@ -0,0 +1,752 @@
+//! The device role of the HID over GATT Profile 1.0, exposing a HID Service
+//! (HID Service Specification 1.0) in Report Protocol Mode. The Boot Protocol
+//! Mode characteristics are not included.
+//!
+//! The reports are owned by the platform: reads and writes of their values
+//! are routed to HidDeviceCallbacks, and input reports are notified on the
+//! high-priority path, so they are not delayed by bulk traffic.
+
+use std::{cell::RefCell, collections::HashMap, future::Future, rc::Rc};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use log::warn;
+
+use crate::{
+    core::uuid::Uuid,
+    gatt::{
+        callbacks::{GattWriteRequestType, RawGattDatastore, TransactionDecision},
+        ffi::AttributeBackingType,
+        ids::{AttHandle, ServerId, TransportIndex},
+        server::{
+            gatt_database::{
+                AttPermissions, CharacteristicBuilder, DescriptorBuilder, ServiceBuilder,
+                ServiceToken,
+            },
+            notification_handler::Priority,
+            GattModule, NotificationError,
+        },
+    },
+    packets::{AttAttributeDataChild, AttErrorCode},
+};
+
+/// The UUID used for the HID Service (Assigned Numbers 3.4.2 Services by Name)
+pub const HID_SERVICE_UUID: Uuid = Uuid::new(0x1812);
+/// The UUID used for the HID Information characteristic (Assigned Numbers 3.8.1 Characteristics by Name)
+pub const HID_INFORMATION_UUID: Uuid = Uuid::new(0x2A4A);
+/// The UUID used for the Report Map characteristic (Assigned Numbers 3.8.1 Characteristics by Name)
+pub const REPORT_MAP_UUID: Uuid = Uuid::new(0x2A4B);
+/// The UUID used for the HID Control Point characteristic (Assigned Numbers 3.8.1 Characteristics by Name)
+pub const HID_CONTROL_POINT_UUID: Uuid = Uuid::new(0x2A4C);
+/// The UUID used for the Report characteristic (Assigned Numbers 3.8.1 Characteristics by Name)
+pub const REPORT_UUID: Uuid = Uuid::new(0x2A4D);
+/// The UUID used for the Protocol Mode characteristic (Assigned Numbers 3.8.1 Characteristics by Name)
+pub const PROTOCOL_MODE_UUID: Uuid = Uuid::new(0x2A4E);
+/// The UUID used for the Report Reference descriptor (Assigned Numbers 3.7 Descriptors)
+pub const REPORT_REFERENCE_UUID: Uuid = Uuid::new(0x2908);
+
+/// The type of a report (HIDS 1.0 3.6.2)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ReportType {
+    /// Sent by the device, as notifications
+    Input = 1,
+    /// Written by the host
+    Output = 2,
+    /// Read and written by the host
+    Feature = 3,
+}
+
+/// A report of the device, as described by its Report Reference descriptor
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct HidReport {
+    /// The Report ID from the report map, or 0 if it does not use Report IDs
+    pub id: u8,
+    /// The type of the report
+    pub type_: ReportType,
+}
+
+/// The value of the Protocol Mode characteristic (HIDS 1.0 3.5)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtocolMode {
+    /// Boot Protocol Mode
+    Boot = 0,
+    /// Report Protocol Mode, the default
+    Report = 1,
+}
+
+/// A command written to the HID Control Point (HIDS 1.0 3.4)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HidControlPointCommand {
+    /// The host is entering the Suspend state
+    Suspend = 0,
+    /// The host is exiting the Suspend state
+    ExitSuspend = 1,
+}
+
+/// The value of the HID Information characteristic (HIDS 1.0 3.3)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HidInformation {
+    /// The version of the HID specification implemented, in binary-coded
+    /// decimal
+    pub bcd_hid: u16,
+    /// The country for which the hardware is localized, or 0 if it is not
+    pub country_code: u8,
+    /// Whether the device may wake the host up
+    pub remote_wake: bool,
+    /// Whether the device advertises when bonded but not connected
+    pub normally_connectable: bool,
+}
+
+impl HidInformation {
+    fn to_bytes(self) -> Vec<u8> {
+        let flags = u8::from(self.remote_wake) | u8::from(self.normally_connectable) << 1;
+        let [bcd_hid_lo, bcd_hid_hi] = self.bcd_hid.to_le_bytes();
+        vec![bcd_hid_lo, bcd_hid_hi, self.country_code, flags]
+    }
+}
+
+/// Describes the HID device exposed by the service
+#[derive(Clone, Debug)]
+pub struct HidDeviceDescription {
+    /// The HID report descriptor, served as the Report Map
+    pub report_map: Vec<u8>,
+    /// The HID Information
+    pub information: HidInformation,
+    /// The reports listed in the report map, in the order of their
+    /// characteristics
+    pub reports: Vec<HidReport>,
+    /// Whether every attribute requires a link encrypted with an authenticated
+    /// key, rather than any encrypted link (HOGP 1.0 5.1.2)
+    pub authentication_required: bool,
+}
+
+/// The upper layer owning the reports of a HID device. Errors are returned to
+/// the host.
+pub trait HidDeviceCallbacks {
+    /// The host reads the current value of the given report
+    fn get_report(
+        &self,
+        tcb_idx: TransportIndex,
+        report: HidReport,
+    ) -> Result<Vec<u8>, AttErrorCode>;
+    /// The host writes the given output or feature report
+    fn set_report(
+        &self,
+        tcb_idx: TransportIndex,
+        report: HidReport,
+        data: &[u8],
+    ) -> Result<(), AttErrorCode>;
+    /// The protocol mode currently in use with the given host
+    fn protocol_mode(&self, tcb_idx: TransportIndex) -> ProtocolMode;
+    /// The host switches to the given protocol mode
+    fn set_protocol_mode(&self, tcb_idx: TransportIndex, mode: ProtocolMode);
+    /// The host writes a command to the HID Control Point
+    fn on_control_point(&self, tcb_idx: TransportIndex, command: HidControlPointCommand);
+}
+
+/// The attributes of the service whose values are not static
+#[derive(Clone, Copy, Debug)]
+enum HidAttribute {
+    ProtocolMode,
+    Report(HidReport),
+    ControlPoint,
+}
+
+/// Routes the accesses to the service to the callbacks
+struct HidDatastore {
+    callbacks: Rc<dyn HidDeviceCallbacks>,
+    attributes: Rc<RefCell<HashMap<AttHandle, HidAttribute>>>,
+}
+
+impl HidDatastore {
+    fn attribute(&self, handle: AttHandle) -> Option<HidAttribute> {
+        self.attributes.borrow().get(&handle).copied()
+    }
+
+    /// Route a write (request or command) of the given value to the callbacks
+    fn write_value(
+        &self,
+        tcb_idx: TransportIndex,
+        handle: AttHandle,
+        data: &[u8],
+    ) -> Result<(), AttErrorCode> {
+        match self.attribute(handle) {
+            Some(HidAttribute::ProtocolMode) => {
+                let mode = match data {
+                    [0] => ProtocolMode::Boot,
+                    [1] => ProtocolMode::Report,
+                    _ => return Err(AttErrorCode::VALUE_NOT_ALLOWED),
+                };
+                self.callbacks.set_protocol_mode(tcb_idx, mode);
+                Ok(())
+            }
+            Some(HidAttribute::Report(report)) => self.callbacks.set_report(tcb_idx, report, data),
+            Some(HidAttribute::ControlPoint) => {
+                let command = match data {
+                    [0] => HidControlPointCommand::Suspend,
+                    [1] => HidControlPointCommand::ExitSuspend,
+                    _ => return Err(AttErrorCode::VALUE_NOT_ALLOWED),
+                };
+                self.callbacks.on_control_point(tcb_idx, command);
+                Ok(())
+            }
+            None => unreachable!("unexpected handle written"),
+        }
+    }
+}
+
+// Protocol Mode, HID Control Point and the output reports are written with
+// commands, so this is a RawGattDatastore rather than a GattDatastore
+#[async_trait(?Send)]
+impl RawGattDatastore for HidDatastore {
+    async fn read(
+        &self,
+        tcb_idx: TransportIndex,
+        handle: AttHandle,
+        offset: u32,
+        _: AttributeBackingType,
+    ) -> Result<Vec<u8>, AttErrorCode> {
+        let mut value = match self.attribute(handle) {
+            Some(HidAttribute::ProtocolMode) => {
+                vec![self.callbacks.protocol_mode(tcb_idx) as u8]
+            }
+            Some(HidAttribute::Report(report)) => self.callbacks.get_report(tcb_idx, report)?,
+            _ => unreachable!("unexpected handle read"),
+        };
+        if offset as usize > value.len() {
+            warn!("got read of {handle:?} at offset {offset} past the end of its value");
+            return Err(AttErrorCode::INVALID_OFFSET);
+        }
+        Ok(value.split_off(offset as usize))
+    }
+
+    async fn write(
+        &self,
+        tcb_idx: TransportIndex,
+        handle: AttHandle,
+        _: AttributeBackingType,
+        write_type: GattWriteRequestType,
+        data: &[u8],
+    ) -> Result<(), AttErrorCode> {
+        match write_type {
+            GattWriteRequestType::Prepare { .. } => {
+                warn!("got prepare write attempt on {tcb_idx:?} to HID characteristic {handle:?}");
+                Err(AttErrorCode::WRITE_REQUEST_REJECTED)
+            }
+            GattWriteRequestType::Request { offset: 0 } => self.write_value(tcb_idx, handle, data),
+            GattWriteRequestType::Request { .. } => {
+                warn!(
+                    "got write at non-zero offset on {tcb_idx:?} to HID characteristic {handle:?}"
+                );
+                Err(AttErrorCode::ATTRIBUTE_NOT_LONG)
+            }
+        }
+    }
+
+    fn write_no_response(
+        &self,
+        tcb_idx: TransportIndex,
+        handle: AttHandle,
+        _: AttributeBackingType,
+        data: &[u8],
+    ) {
+        if let Err(err) = self.write_value(tcb_idx, handle, data) {
+            // silently drop, since there's no way to return an error
+            warn!("dropped write command on {tcb_idx:?} to {handle:?}: {err:?}");
+        }
+    }
+
+    async fn execute(&self, _: TransportIndex, _: TransactionDecision) -> Result<(), AttErrorCode> {
+        // prepared writes are rejected, so there is nothing to execute
+        Ok(())
+    }
+}
+
+/// A HID Service registered on a server
+pub struct HidService {
+    server_id: ServerId,
+    token: ServiceToken,
+    /// The value handles of the input reports, by Report ID
+    input_reports: HashMap<u8, AttHandle>,
+}
+
+impl HidService {
+    /// Add a HID Service describing the given device to a server, routing the
+    /// accesses to its reports to the given callbacks
+    pub fn register(
+        gatt: &mut GattModule,
+        server_id: ServerId,
+        device: HidDeviceDescription,
+        callbacks: Rc<dyn HidDeviceCallbacks>,
+    ) -> Result<Self> {
+        if device.reports.is_empty() {
+            bail!("a HID device needs at least one report");
+        }
+        for (i, report) in device.reports.iter().enumerate() {
+            if device.reports[..i].contains(report) {
+                bail!("duplicate report {report:?}");
+            }
+        }
+        let security = if device.authentication_required {
+            AttPermissions::AUTHENTICATION_REQUIRED
+        } else {
+            AttPermissions::ENCRYPTION_REQUIRED
+        };
+
+        // the characteristics, with the number of handles they occupy
+        let mut characteristics = vec![(
+            CharacteristicBuilder::new(
+                PROTOCOL_MODE_UUID,
+                AttPermissions::READABLE | AttPermissions::WRITABLE_WITHOUT_RESPONSE | security,
+            ),
+            2,
+            Some(HidAttribute::ProtocolMode),
+        )];
+        for report in &device.reports {
+            let (permissions, handle_count) = match report.type_ {
+                // the GattDatabase manages the CCCD of input reports
+                ReportType::Input => (AttPermissions::READABLE | AttPermissions::NOTIFY, 4),
+                ReportType::Output => (
+                    AttPermissions::READABLE
+                        | AttPermissions::WRITABLE_WITH_RESPONSE
+                        | AttPermissions::WRITABLE_WITHOUT_RESPONSE,
+                    3,
+                ),
+                ReportType::Feature => {
+                    (AttPermissions::READABLE | AttPermissions::WRITABLE_WITH_RESPONSE, 3)
+                }
+            };
+            characteristics.push((
+                CharacteristicBuilder::new(REPORT_UUID, permissions | security).descriptor(
+                    DescriptorBuilder::new(
+                        REPORT_REFERENCE_UUID,
+                        AttPermissions::READABLE | security,
+                    )
+                    .static_value(vec![report.id, report.type_ as u8]),
+                ),
+                handle_count,
+                Some(HidAttribute::Report(*report)),
+            ));
+        }
+        characteristics.extend([
+            (
+                CharacteristicBuilder::new(REPORT_MAP_UUID, AttPermissions::READABLE | security)
+                    .static_value(device.report_map),
+                2,
+                None,
+            ),
+            (
+                CharacteristicBuilder::new(
+                    HID_INFORMATION_UUID,
+                    AttPermissions::READABLE | security,
+                )
+                .static_value(device.information.to_bytes()),
+                2,
+                None,
+            ),
+            (
+                CharacteristicBuilder::new(
+                    HID_CONTROL_POINT_UUID,
+                    AttPermissions::WRITABLE_WITHOUT_RESPONSE | security,
+                ),
+                2,
+                Some(HidAttribute::ControlPoint),
+            ),
+        ]);
+
+        // the value handles, relative to the service declaration
+        let mut value_offsets = vec![];
+        let mut next_offset = 1;
+        let mut service = ServiceBuilder::new(HID_SERVICE_UUID);
+        for (characteristic, handle_count, _) in &characteristics {
+            value_offsets.push(next_offset + 1);
+            next_offset += handle_count;
+            service = service.characteristic(characteristic.clone());
+        }
+
+        let attributes = Rc::new(RefCell::new(HashMap::new()));
+        let token = gatt.add_gatt_service(
+            server_id,
+            service,
+            HidDatastore { callbacks, attributes: attributes.clone() },
+        )?;
+        let value_handle = |index: usize| AttHandle(token.handle().0 + value_offsets[index]);
+        let mut input_reports = HashMap::new();
+        for (index, (_, _, attribute)) in characteristics.iter().enumerate() {
+            let Some(attribute) = attribute else {
+                continue;
+            };
+            if let HidAttribute::Report(HidReport { id, type_: ReportType::Input }) = attribute {
+                input_reports.insert(*id, value_handle(index));
+            }
+            attributes.borrow_mut().insert(value_handle(index), *attribute);
+        }
+        Ok(Self { server_id, token, input_reports })
+    }
+
+    /// Remove the service from its server
+    pub fn unregister(self, gatt: &mut GattModule) -> Result<()> {
+        gatt.remove_gatt_service(self.server_id, self.token)
+    }
+
+    /// Notify the given input report to the host on the given connection, if
+    /// it has subscribed to it, ahead of other queued notifications. The
+    /// report must fit in a notification (ATT_MTU-3 bytes).
+    pub fn send_input_report(
+        &self,
+        gatt: &GattModule,
+        tcb_idx: TransportIndex,
+        report_id: u8,
+        data: &[u8],
+    ) -> Result<impl Future<Output = Result<(), NotificationError>>> {
+        let Some(handle) = self.input_reports.get(&report_id) else {
+            bail!("no input report with ID {report_id}");
+        };
+        let conn_id = gatt
+            .get_connection_id(tcb_idx)
+            .ok_or_else(|| anyhow!("no connection on {tcb_idx:?}"))?;
+        if conn_id.get_server_id() != self.server_id {
+            bail!("{tcb_idx:?} is not connected to server {:?}", self.server_id);
+        }
+        let bearer = gatt.get_bearer(tcb_idx).unwrap();
+        Ok(bearer.send_notification(
+            *handle,
+            AttAttributeDataChild::RawData(data.into()),
+            Priority::High,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    use crate::{
+        gatt::{
+            ids::AdvertiserId,
+            mocks::{mock_security_manager::MockSecurityManager, mock_transport::MockAttTransport},
+            security_manager::SecurityLevel,
+            server::isolation_manager::IsolationManager,
+        },
+        packets::{
+            AttBuilder, AttChild, AttReadRequestBuilder, AttReadResponseBuilder,
+            AttWriteCommandBuilder, AttWriteRequestBuilder, AttWriteResponseBuilder,
+            GattClientCharacteristicConfigurationBuilder, Serializable,
+        },
+        utils::{
+            packet::{build_att_data, build_att_view_or_crash},
+            task::block_on_locally,
+        },
+    };
+
+    use super::*;
+
+    const TCB_IDX: TransportIndex = TransportIndex(1);
+    const SERVER_ID: ServerId = ServerId(2);
+    const ADVERTISER_ID: AdvertiserId = AdvertiserId(3);
+
+    const REPORT_MAP: [u8; 4] = [0x05, 0x01, 0x09, 0x06];
+    const INPUT_REPORT: HidReport = HidReport { id: 1, type_: ReportType::Input };
+    const OUTPUT_REPORT: HidReport = HidReport { id: 1, type_: ReportType::Output };
+
+    // the value handles of the keyboard, relative to the service declaration
+    const PROTOCOL_MODE_OFFSET: u16 = 2;
+    const INPUT_REPORT_OFFSET: u16 = 4;
+    const OUTPUT_REPORT_OFFSET: u16 = 8;
+    const REPORT_MAP_OFFSET: u16 = 11;
+    const HID_INFORMATION_OFFSET: u16 = 13;
+    const CONTROL_POINT_OFFSET: u16 = 15;
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum HidEvent {
+        SetReport(HidReport, Vec<u8>),
+        SetProtocolMode(ProtocolMode),
+        ControlPoint(HidControlPointCommand),
+    }
+
+    #[derive(Default)]
+    struct TestHidDevice {
+        events: RefCell<Vec<HidEvent>>,
+    }
+
+    impl HidDeviceCallbacks for TestHidDevice {
+        fn get_report(
+            &self,
+            _: TransportIndex,
+            report: HidReport,
+        ) -> Result<Vec<u8>, AttErrorCode> {
+            Ok(vec![report.id, report.type_ as u8])
+        }
+
+        fn set_report(
+            &self,
+            _: TransportIndex,
+            report: HidReport,
+            data: &[u8],
+        ) -> Result<(), AttErrorCode> {
+            self.events.borrow_mut().push(HidEvent::SetReport(report, data.to_vec()));
+            Ok(())
+        }
+
+        fn protocol_mode(&self, _: TransportIndex) -> ProtocolMode {
+            ProtocolMode::Report
+        }
+
+        fn set_protocol_mode(&self, _: TransportIndex, mode: ProtocolMode) {
+            self.events.borrow_mut().push(HidEvent::SetProtocolMode(mode));
+        }
+
+        fn on_control_point(&self, _: TransportIndex, command: HidControlPointCommand) {
+            self.events.borrow_mut().push(HidEvent::ControlPoint(command));
+        }
+    }
+
+    fn keyboard(authentication_required: bool) -> HidDeviceDescription {
+        HidDeviceDescription {
+            report_map: REPORT_MAP.to_vec(),
+            information: HidInformation {
+                bcd_hid: 0x0111,
+                country_code: 0,
+                remote_wake: true,
+                normally_connectable: false,
+            },
+            reports: vec![INPUT_REPORT, OUTPUT_REPORT],
+            authentication_required,
+        }
+    }
+
+    struct TestHid {
+        gatt: GattModule,
+        transport_rx: UnboundedReceiver<(TransportIndex, AttBuilder)>,
+        device: Rc<TestHidDevice>,
+        hid: HidService,
+    }
+
+    fn start_hid(authentication_required: bool, security_level: SecurityLevel) -> TestHid {
+        let (transport, transport_rx, _) = MockAttTransport::new();
+        let security_manager = Rc::new(MockSecurityManager::new());
+        security_manager.set_security_level(TCB_IDX, security_level);
+        let mut gatt = GattModule::new(
+            Rc::new(transport),
+            security_manager,
+            Arc::new(Mutex::new(IsolationManager::new())),
+        );
+        gatt.open_gatt_server(SERVER_ID).unwrap();
+        gatt.get_isolation_manager().associate_server_with_advertiser(SERVER_ID, ADVERTISER_ID);
+        let device = Rc::new(TestHidDevice::default());
+        let hid = HidService::register(
+            &mut gatt,
+            SERVER_ID,
+            keyboard(authentication_required),
+            device.clone(),
+        )
+        .unwrap();
+        gatt.on_le_connect(TCB_IDX, Some(ADVERTISER_ID)).unwrap();
+        TestHid { gatt, transport_rx, device, hid }
+    }
+
+    impl TestHid {
+        fn handle(&self, offset: u16) -> AttHandle {
+            AttHandle(self.hid.token.handle().0 + offset)
+        }
+
+        async fn request(&mut self, request: impl Into<AttChild>) -> AttChild {
+            self.gatt
+                .get_bearer(TCB_IDX)
+                .unwrap()
+                .handle_packet(build_att_view_or_crash(request).view());
+            self.transport_rx.recv().await.unwrap().1._child_
+        }
+
+        fn command(&self, command: impl Into<AttChild>) {
+            self.gatt
+                .get_bearer(TCB_IDX)
+                .unwrap()
+                .handle_packet(build_att_view_or_crash(command).view());
+        }
+    }
+
+    fn read_response(value: &[u8]) -> AttChild {
+        AttReadResponseBuilder {
+            value: build_att_data(AttAttributeDataChild::RawData(value.into())),
+        }
+        .into()
+    }
+
+    #[test]
+    fn test_read_report_map() {
+        block_on_locally(async {
+            // arrange
+            let mut hid = start_hid(false, SecurityLevel::Encrypted);
+            let handle = hid.handle(REPORT_MAP_OFFSET);
+
+            // act
+            let resp = hid.request(AttReadRequestBuilder { attribute_handle: handle.into() }).await;
+
+            // assert
+            assert_eq!(resp, read_response(&REPORT_MAP));
+        });
+    }
+
+    #[test]
+    fn test_read_hid_information() {
+        block_on_locally(async {
+            // arrange
+            let mut hid = start_hid(false, SecurityLevel::Encrypted);
+            let handle = hid.handle(HID_INFORMATION_OFFSET);
+
+            // act
+            let resp = hid.request(AttReadRequestBuilder { attribute_handle: handle.into() }).await;
+
+            // assert
+            assert_eq!(resp, read_response(&[0x11, 0x01, 0x00, 0x01]));
+        });
+    }
+
+    #[test]
+    fn test_read_report_and_reference() {
+        block_on_locally(async {
+            // arrange
+            let mut hid = start_hid(false, SecurityLevel::Encrypted);
+            let handle = hid.handle(INPUT_REPORT_OFFSET);
+            assert_eq!(hid.hid.input_reports[&INPUT_REPORT.id], handle);
+
+            // act
+            let value =
+                hid.request(AttReadRequestBuilder { attribute_handle: handle.into() }).await;
+            let reference = hid
+                .request(AttReadRequestBuilder { attribute_handle: AttHandle(handle.0 + 1).into() })
+                .await;
+
+            // assert
+            assert_eq!(value, read_response(&[0x01, 0x01]));
+            assert_eq!(reference, read_response(&[0x01, 0x01]));
+        });
+    }
+
+    #[test]
+    fn test_output_report_write_routed() {
+        block_on_locally(async {
+            // arrange
+            let mut hid = start_hid(false, SecurityLevel::Encrypted);
+            let handle = hid.handle(OUTPUT_REPORT_OFFSET);
+
+            // act
+            let resp = hid
+                .request(AttWriteRequestBuilder {
+                    handle: handle.into(),
+                    value: build_att_data(AttAttributeDataChild::RawData([0x02].into())),
+                })
+                .await;
+
+            // assert
+            assert_eq!(resp, AttWriteResponseBuilder {}.into());
+            assert_eq!(
+                *hid.device.events.borrow(),
+                [HidEvent::SetReport(OUTPUT_REPORT, vec![0x02])]
+            );
+        });
+    }
+
+    #[test]
+    fn test_protocol_mode_and_control_point_routed() {
+        block_on_locally(async {
+            // arrange
+            let hid = start_hid(false, SecurityLevel::Encrypted);
+            let protocol_mode_handle = hid.handle(PROTOCOL_MODE_OFFSET);
+            let control_point_handle = hid.handle(CONTROL_POINT_OFFSET);
+
+            // act
+            for (handle, value) in
+                [(protocol_mode_handle, 0), (control_point_handle, 0), (control_point_handle, 7)]
+            {
+                hid.command(AttWriteCommandBuilder {
+                    handle: handle.into(),
+                    value: build_att_data(AttAttributeDataChild::RawData([value].into())),
+                });
+            }
+            // let the commands be processed
+            tokio::time::sleep(Duration::from_millis(1)).await;
+
+            // assert: the invalid command was dropped
+            assert_eq!(
+                *hid.device.events.borrow(),
+                [
+                    HidEvent::SetProtocolMode(ProtocolMode::Boot),
+                    HidEvent::ControlPoint(HidControlPointCommand::Suspend)
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn test_authentication_required() {
+        block_on_locally(async {
+            // arrange: the link is encrypted, but not authenticated
+            let mut hid = start_hid(true, SecurityLevel::Encrypted);
+            let handle = hid.handle(REPORT_MAP_OFFSET);
+
+            // act
+            let resp = hid.request(AttReadRequestBuilder { attribute_handle: handle.into() }).await;
+
+            // assert
+            let AttChild::AttErrorResponse(resp) = resp else {
+                unreachable!("{resp:?}");
+            };
+            assert_eq!(resp.error_code, AttErrorCode::INSUFFICIENT_AUTHENTICATION);
+        });
+    }
+
+    #[test]
+    fn test_input_report_notified() {
+        block_on_locally(async {
+            // arrange: the host subscribed to the input report
+            let mut hid = start_hid(false, SecurityLevel::Encrypted);
+            let handle = hid.handle(INPUT_REPORT_OFFSET);
+            hid.request(AttWriteRequestBuilder {
+                handle: AttHandle(handle.0 + 2).into(),
+                value: build_att_data(GattClientCharacteristicConfigurationBuilder {
+                    notification: 1,
+                    indication: 0,
+                }),
+            })
+            .await;
+
+            // act
+            let result = hid
+                .hid
+                .send_input_report(&hid.gatt, TCB_IDX, INPUT_REPORT.id, &[0x04])
+                .unwrap()
+                .await;
+
+            // assert
+            assert!(result.is_ok());
+            let (_, notification) = hid.transport_rx.recv().await.unwrap();
+            assert_eq!(notification.to_vec().unwrap(), [0x1B, handle.0 as u8, 0x00, 0x04]);
+        });
+    }
+
+    #[test]
+    fn test_invalid_descriptions_rejected() {
+        let (transport, _, _) = MockAttTransport::new();
+        let mut gatt = GattModule::new(
+            Rc::new(transport),
+            Rc::new(MockSecurityManager::new()),
+            Arc::new(Mutex::new(IsolationManager::new())),
+        );
+        gatt.open_gatt_server(SERVER_ID).unwrap();
+        let device = Rc::new(TestHidDevice::default());
+
+        let no_reports = HidDeviceDescription { reports: vec![], ..keyboard(false) };
+        let duplicates =
+            HidDeviceDescription { reports: vec![INPUT_REPORT, INPUT_REPORT], ..keyboard(false) };
+
+        assert!(HidService::register(&mut gatt, SERVER_ID, no_reports, device.clone()).is_err());
+        assert!(HidService::register(&mut gatt, SERVER_ID, duplicates, device).is_err());
+    }
+}
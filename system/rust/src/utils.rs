@@ -1,5 +1,8 @@
 //! Utilities that are not specific to a particular module
 
+pub mod aes_cmac;
+pub mod clock;
+pub mod executor;
 pub mod owned_handle;
 pub mod packet;
 
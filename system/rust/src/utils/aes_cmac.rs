@@ -0,0 +1,218 @@
+//! AES-CMAC (RFC 4493), as used by the Bluetooth security toolbox (Core Spec
+//! 5.3 Vol 3H 2.2.5 Function AES-CMAC).
+//!
+//! All inputs and outputs are byte strings in the order specified by RFC 4493
+//! (i.e. most-significant octet first). Callers dealing with little-endian
+//! Bluetooth values are responsible for reversing them.
+
+/// The AES S-box (FIPS 197 5.1.1)
+const SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+/// The round constants used by the AES-128 key expansion (FIPS 197 5.2)
+const RCON: [u8; 10] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36];
+
+const BLOCK_SIZE: usize = 16;
+
+type Block = [u8; BLOCK_SIZE];
+
+/// Multiply by x in GF(2^8)
+fn xtime(b: u8) -> u8 {
+    (b << 1) ^ if b & 0x80 != 0 { 0x1b } else { 0 }
+}
+
+/// Expand an AES-128 key into the 11 round keys (FIPS 197 5.2)
+fn expand_key(key: &Block) -> [Block; 11] {
+    let mut round_keys = [[0; BLOCK_SIZE]; 11];
+    round_keys[0] = *key;
+    for round in 1..11 {
+        let prev = round_keys[round - 1];
+        let mut word = [prev[13], prev[14], prev[15], prev[12]];
+        for b in word.iter_mut() {
+            *b = SBOX[*b as usize];
+        }
+        word[0] ^= RCON[round - 1];
+        for i in 0..BLOCK_SIZE {
+            word[i % 4] ^= prev[i];
+            round_keys[round][i] = word[i % 4];
+        }
+    }
+    round_keys
+}
+
+/// Encrypt a single block with AES-128 (FIPS 197 5.1)
+fn aes128_encrypt(key: &Block, block: &Block) -> Block {
+    let round_keys = expand_key(key);
+    let mut state = *block;
+    let add_round_key = |state: &mut Block, round_key: &Block| {
+        for (s, k) in state.iter_mut().zip(round_key) {
+            *s ^= k;
+        }
+    };
+
+    add_round_key(&mut state, &round_keys[0]);
+    for (round, round_key) in round_keys.iter().enumerate().skip(1) {
+        // SubBytes + ShiftRows (the state is stored column-major)
+        let mut shifted = [0; BLOCK_SIZE];
+        for col in 0..4 {
+            for row in 0..4 {
+                shifted[col * 4 + row] = SBOX[state[((col + row) % 4) * 4 + row] as usize];
+            }
+        }
+        state = shifted;
+        // MixColumns, omitted in the final round
+        if round != 10 {
+            for column in state.chunks_exact_mut(4) {
+                let [a0, a1, a2, a3] = [column[0], column[1], column[2], column[3]];
+                let all = a0 ^ a1 ^ a2 ^ a3;
+                column[0] ^= all ^ xtime(a0 ^ a1);
+                column[1] ^= all ^ xtime(a1 ^ a2);
+                column[2] ^= all ^ xtime(a2 ^ a3);
+                column[3] ^= all ^ xtime(a3 ^ a0);
+            }
+        }
+        add_round_key(&mut state, round_key);
+    }
+    state
+}
+
+/// Multiply a block by x in GF(2^128), as used for subkey generation (RFC 4493
+/// 2.3)
+fn double(block: &Block) -> Block {
+    let mut out = [0; BLOCK_SIZE];
+    for i in 0..BLOCK_SIZE {
+        let carry = block.get(i + 1).map(|next| next >> 7).unwrap_or(0);
+        out[i] = (block[i] << 1) | carry;
+    }
+    if block[0] & 0x80 != 0 {
+        out[BLOCK_SIZE - 1] ^= 0x87;
+    }
+    out
+}
+
+/// Compute the AES-CMAC of the given message (RFC 4493 2.4)
+pub fn aes_cmac(key: &[u8; 16], message: &[u8]) -> [u8; 16] {
+    let k1 = double(&aes128_encrypt(key, &[0; BLOCK_SIZE]));
+    let k2 = double(&k1);
+
+    // the last block is padded if incomplete (or if the message is empty)
+    let n_blocks = message.len().div_ceil(BLOCK_SIZE).max(1);
+    let (complete, tail) = message.split_at((n_blocks - 1) * BLOCK_SIZE);
+    let mut last = [0; BLOCK_SIZE];
+    last[..tail.len()].copy_from_slice(tail);
+    let subkey = if tail.len() == BLOCK_SIZE {
+        k1
+    } else {
+        last[tail.len()] = 0x80;
+        k2
+    };
+    for (b, k) in last.iter_mut().zip(subkey) {
+        *b ^= k;
+    }
+
+    let mut mac = [0; BLOCK_SIZE];
+    for block in complete.chunks_exact(BLOCK_SIZE).chain(std::iter::once(&last[..])) {
+        for (m, b) in mac.iter_mut().zip(block) {
+            *m ^= b;
+        }
+        mac = aes128_encrypt(key, &mac);
+    }
+    mac
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // test vectors from RFC 4493 4. Test Vectors
+    const KEY: [u8; 16] = [
+        0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf, 0x4f,
+        0x3c,
+    ];
+    const MESSAGE: [u8; 64] = [
+        0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93, 0x17,
+        0x2a, 0xae, 0x2d, 0x8a, 0x57, 0x1e, 0x03, 0xac, 0x9c, 0x9e, 0xb7, 0x6f, 0xac, 0x45, 0xaf,
+        0x8e, 0x51, 0x30, 0xc8, 0x1c, 0x46, 0xa3, 0x5c, 0xe4, 0x11, 0xe5, 0xfb, 0xc1, 0x19, 0x1a,
+        0x0a, 0x52, 0xef, 0xf6, 0x9f, 0x24, 0x45, 0xdf, 0x4f, 0x9b, 0x17, 0xad, 0x2b, 0x41, 0x7b,
+        0xe6, 0x6c, 0x37, 0x10,
+    ];
+
+    #[test]
+    fn test_aes128_encrypt() {
+        // FIPS 197 Appendix B
+        let plaintext = [
+            0x32, 0x43, 0xf6, 0xa8, 0x88, 0x5a, 0x30, 0x8d, 0x31, 0x31, 0x98, 0xa2, 0xe0, 0x37,
+            0x07, 0x34,
+        ];
+
+        let ciphertext = aes128_encrypt(&KEY, &plaintext);
+
+        assert_eq!(
+            ciphertext,
+            [
+                0x39, 0x25, 0x84, 0x1d, 0x02, 0xdc, 0x09, 0xfb, 0xdc, 0x11, 0x85, 0x97, 0x19, 0x6a,
+                0x0b, 0x32
+            ]
+        );
+    }
+
+    #[test]
+    fn test_empty_message() {
+        assert_eq!(
+            aes_cmac(&KEY, &[]),
+            [
+                0xbb, 0x1d, 0x69, 0x29, 0xe9, 0x59, 0x37, 0x28, 0x7f, 0xa3, 0x7d, 0x12, 0x9b, 0x75,
+                0x67, 0x46
+            ]
+        );
+    }
+
+    #[test]
+    fn test_single_block_message() {
+        assert_eq!(
+            aes_cmac(&KEY, &MESSAGE[..16]),
+            [
+                0x07, 0x0a, 0x16, 0xb4, 0x6b, 0x4d, 0x41, 0x44, 0xf7, 0x9b, 0xdd, 0x9d, 0xd0, 0x4a,
+                0x28, 0x7c
+            ]
+        );
+    }
+
+    #[test]
+    fn test_partial_block_message() {
+        assert_eq!(
+            aes_cmac(&KEY, &MESSAGE[..40]),
+            [
+                0xdf, 0xa6, 0x67, 0x47, 0xde, 0x9a, 0xe6, 0x30, 0x30, 0xca, 0x32, 0x61, 0x14, 0x97,
+                0xc8, 0x27
+            ]
+        );
+    }
+
+    #[test]
+    fn test_multi_block_message() {
+        assert_eq!(
+            aes_cmac(&KEY, &MESSAGE),
+            [
+                0x51, 0xf0, 0xbe, 0xbf, 0x7e, 0x3b, 0x9d, 0x92, 0xfc, 0x49, 0x74, 0x17, 0x79, 0x36,
+                0x3c, 0xfe
+            ]
+        );
+    }
+}
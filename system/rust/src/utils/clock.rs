@@ -0,0 +1,257 @@
+//! This module provides the source of time used by the timers of the GATT
+//! server (transaction timeouts, indication confirmations, security
+//! elevation, responses from the upper layers...), so that it can be injected.
+//! In production, the TokioClock follows the runtime. In tests (or when
+//! fuzzing), a VirtualClock only moves when advanced, so timeouts can be
+//! exercised deterministically without sleeping in real time.
+
+use std::{
+    cell::{Cell, RefCell},
+    future::Future,
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use tokio::{select, task::yield_now};
+
+/// A source of time, and of timers
+pub trait Clock {
+    /// The current time
+    fn now(&self) -> Instant;
+
+    /// A future that resolves once the given deadline has passed
+    fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Future<Output = ()>>>;
+}
+
+/// The error returned when a future did not complete before its timeout
+#[derive(Debug, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// Run the future until it completes, or fail with Elapsed if the given
+/// duration (as measured by the clock) passes first
+pub fn timeout<F: Future>(
+    clock: &dyn Clock,
+    duration: Duration,
+    future: F,
+) -> impl Future<Output = Result<F::Output, Elapsed>> {
+    timeout_at(clock, clock.now() + duration, future)
+}
+
+/// Run the future until it completes, or fail with Elapsed if the given
+/// deadline (as measured by the clock) passes first. The timer starts when
+/// this is called, rather than when the returned future is first polled.
+pub fn timeout_at<F: Future>(
+    clock: &dyn Clock,
+    deadline: Instant,
+    future: F,
+) -> impl Future<Output = Result<F::Output, Elapsed>> {
+    let sleep = clock.sleep_until(deadline);
+    async move {
+        select! {
+            biased;
+            output = future => Ok(output),
+            _ = sleep => Err(Elapsed),
+        }
+    }
+}
+
+/// The clock of the tokio runtime (which is itself mocked when the runtime
+/// is started paused)
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Future<Output = ()>>> {
+        Box::pin(tokio::time::sleep_until(deadline.into()))
+    }
+}
+
+struct VirtualClockState {
+    now: Cell<Instant>,
+    timers: RefCell<Vec<(Instant, Waker)>>,
+}
+
+/// A clock that only moves when advanced. Clones share the same time.
+#[derive(Clone)]
+pub struct VirtualClock(Rc<VirtualClockState>);
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VirtualClock {
+    /// Constructor, starting at an arbitrary instant
+    pub fn new() -> Self {
+        Self(Rc::new(VirtualClockState {
+            now: Cell::new(Instant::now()),
+            timers: RefCell::new(vec![]),
+        }))
+    }
+
+    /// Move the clock forwards by the given duration. The timers expiring in
+    /// the meantime fire in order of their deadlines, with the clock stopped at
+    /// each deadline, and the tasks they wake get to run before the next one
+    /// fires (so timers they arm in turn also fire, if they expire in time).
+    pub async fn advance(&self, duration: Duration) {
+        let target = self.0.now.get() + duration;
+        while let Some(deadline) = self.next_deadline().filter(|deadline| *deadline <= target) {
+            let now = deadline.max(self.0.now.get());
+            self.0.now.set(now);
+            let expired = {
+                let mut timers = self.0.timers.borrow_mut();
+                let (expired, pending) =
+                    timers.drain(..).partition::<Vec<_>, _>(|(deadline, _)| *deadline <= now);
+                *timers = pending;
+                expired
+            };
+            for (_, waker) in expired {
+                waker.wake();
+            }
+            yield_now().await;
+        }
+        self.0.now.set(target);
+        yield_now().await;
+    }
+
+    /// The deadline of the earliest armed timer, if any
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.0.timers.borrow().iter().map(|(deadline, _)| *deadline).min()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        self.0.now.get()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Future<Output = ()>>> {
+        Box::pin(VirtualSleep { clock: self.0.clone(), deadline })
+    }
+}
+
+struct VirtualSleep {
+    clock: Rc<VirtualClockState>,
+    deadline: Instant,
+}
+
+impl Future for VirtualSleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.clock.now.get() >= self.deadline {
+            return Poll::Ready(());
+        }
+        let mut timers = self.clock.timers.borrow_mut();
+        if !timers
+            .iter()
+            .any(|(deadline, waker)| *deadline == self.deadline && waker.will_wake(cx.waker()))
+        {
+            timers.push((self.deadline, cx.waker().clone()));
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::future::pending;
+
+    use tokio::task::spawn_local;
+
+    use crate::utils::task::block_on_locally;
+
+    use super::*;
+
+    #[test]
+    fn test_sleep_fires_once_advanced_past_deadline() {
+        block_on_locally(async {
+            // arrange
+            let clock = VirtualClock::new();
+            let fired = Rc::new(Cell::new(false));
+            spawn_local({
+                let sleep = clock.sleep_until(clock.now() + Duration::from_secs(1));
+                let fired = fired.clone();
+                async move {
+                    sleep.await;
+                    fired.set(true);
+                }
+            });
+
+            // act
+            clock.advance(Duration::from_millis(999)).await;
+            let fired_early = fired.get();
+            clock.advance(Duration::from_millis(1)).await;
+
+            // assert
+            assert!(!fired_early);
+            assert!(fired.get());
+        });
+    }
+
+    #[test]
+    fn test_timers_fire_in_deadline_order() {
+        block_on_locally(async {
+            // arrange: two timers, armed in the reverse order of their deadlines
+            let clock = VirtualClock::new();
+            let start = clock.now();
+            let fired = Rc::new(RefCell::new(vec![]));
+            for delay in [Duration::from_secs(2), Duration::from_secs(1)] {
+                let sleep = clock.sleep_until(start + delay);
+                let clock = clock.clone();
+                let fired = fired.clone();
+                spawn_local(async move {
+                    sleep.await;
+                    fired.borrow_mut().push(clock.now() - start);
+                });
+            }
+            yield_now().await;
+
+            // act
+            clock.advance(Duration::from_secs(3)).await;
+
+            // assert: each fired at its own deadline
+            assert_eq!(*fired.borrow(), vec![Duration::from_secs(1), Duration::from_secs(2)]);
+            assert_eq!(clock.now() - start, Duration::from_secs(3));
+            assert_eq!(clock.next_deadline(), None);
+        });
+    }
+
+    #[test]
+    fn test_timeout() {
+        block_on_locally(async {
+            // arrange
+            let clock = VirtualClock::new();
+            let stalled = spawn_local(timeout(&clock, Duration::from_secs(1), pending::<()>()));
+
+            // act
+            clock.advance(Duration::from_secs(1)).await;
+
+            // assert
+            assert_eq!(stalled.await.unwrap(), Err(Elapsed));
+            assert_eq!(timeout(&clock, Duration::from_secs(1), async { 1 }).await, Ok(1));
+        });
+    }
+
+    #[test]
+    fn test_timeout_starts_when_created() {
+        block_on_locally(async {
+            // arrange: a timeout that is not polled until after the clock moved
+            let clock = VirtualClock::new();
+            let stalled = timeout(&clock, Duration::from_secs(1), pending::<()>());
+
+            // act
+            clock.advance(Duration::from_secs(1)).await;
+
+            // assert
+            assert_eq!(stalled.await, Err(Elapsed));
+        });
+    }
+}
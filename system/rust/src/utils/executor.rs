@@ -0,0 +1,132 @@
+//! This module provides the executor on which the GATT server spawns its
+//! background tasks (transactions, queued commands, indication completions),
+//! so that it can be injected alongside the Clock providing its timers.
+//! Integrators can then run the server on tokio, on a local pool, or on an
+//! adapter to the Android main loop.
+//!
+//! The tasks of the GATT server share its state through Rc, so they are not
+//! Send: they must all be polled on the thread that owns the GattModule. A
+//! multithreaded host should dedicate one thread (or LocalSet) to the module.
+
+use std::{future::Future, pin::Pin};
+
+use tokio::task::spawn_local;
+
+/// A task spawned onto an Executor
+pub type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+/// Spawns the background tasks of the GATT server
+pub trait Executor {
+    /// Start running the given task in the background. The task is aborted if
+    /// the returned handle is dropped, unless it is detached.
+    fn spawn(&self, task: Task) -> TaskHandle;
+}
+
+/// When this struct is dropped, the task it was returned for is aborted (if
+/// it has not completed yet)
+#[must_use = "the task is aborted when the handle is dropped"]
+pub struct TaskHandle {
+    abort: Option<Box<dyn FnOnce()>>,
+}
+
+impl TaskHandle {
+    /// Constructor, from the function aborting the task
+    pub fn new(abort: impl FnOnce() + 'static) -> Self {
+        Self { abort: Some(Box::new(abort)) }
+    }
+
+    /// Let the task run to completion, even though its handle is dropped
+    pub fn detach(mut self) {
+        self.abort = None;
+    }
+}
+
+impl Drop for TaskHandle {
+    fn drop(&mut self) {
+        if let Some(abort) = self.abort.take() {
+            abort();
+        }
+    }
+}
+
+/// Spawns tasks on the tokio LocalSet of the current thread
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioExecutor;
+
+impl Executor for TokioExecutor {
+    fn spawn(&self, task: Task) -> TaskHandle {
+        let handle = spawn_local(task);
+        TaskHandle::new(move || handle.abort())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::Cell, rc::Rc};
+
+    use tokio::task::yield_now;
+
+    use crate::utils::task::block_on_locally;
+
+    use super::*;
+
+    fn flag_task(flag: &Rc<Cell<bool>>) -> Task {
+        let flag = flag.clone();
+        Box::pin(async move {
+            yield_now().await;
+            flag.set(true);
+        })
+    }
+
+    #[test]
+    fn test_spawned_task_runs() {
+        block_on_locally(async {
+            // arrange
+            let ran = Rc::new(Cell::new(false));
+
+            // act
+            let _handle = TokioExecutor.spawn(flag_task(&ran));
+            for _ in 0..3 {
+                yield_now().await;
+            }
+
+            // assert
+            assert!(ran.get());
+        });
+    }
+
+    #[test]
+    fn test_dropped_handle_aborts_task() {
+        block_on_locally(async {
+            // arrange
+            let ran = Rc::new(Cell::new(false));
+            let handle = TokioExecutor.spawn(flag_task(&ran));
+
+            // act
+            drop(handle);
+            for _ in 0..3 {
+                yield_now().await;
+            }
+
+            // assert
+            assert!(!ran.get());
+        });
+    }
+
+    #[test]
+    fn test_detached_task_runs() {
+        block_on_locally(async {
+            // arrange
+            let ran = Rc::new(Cell::new(false));
+
+            // act
+            TokioExecutor.spawn(flag_task(&ran)).detach();
+            for _ in 0..3 {
+                yield_now().await;
+            }
+
+            // assert
+            assert!(ran.get());
+        });
+    }
+}
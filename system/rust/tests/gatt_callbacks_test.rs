@@ -13,6 +13,7 @@ use bluetooth_core::{
         mocks::mock_callbacks::{MockCallbackEvents, MockCallbacks},
     },
     packets::AttErrorCode,
+    utils::clock::VirtualClock,
 };
 use tokio::{sync::mpsc::UnboundedReceiver, task::spawn_local, time::Instant};
 use utils::start_test;
@@ -224,6 +225,31 @@ fn test_invalid_conn_id() {
     });
 }
 
+#[test]
+fn test_pending_transactions_released_on_disconnect() {
+    start_test(async {
+        // arrange: a read whose request is cancelled along with its bearer
+        let (callback_manager, mut callbacks_rx) = initialize_manager_with_connection();
+        let datastore = callback_manager.get_datastore(SERVER_ID);
+        let pending_read =
+            spawn_local(
+                async move { datastore.read(TCB_IDX, HANDLE_1, OFFSET, BACKING_TYPE).await },
+            );
+        let trans_id = pull_trans_id(&mut callbacks_rx).await;
+        pending_read.abort();
+        let pending_before_disconnect = callback_manager.has_pending_transactions(CONN_ID);
+
+        // act: the peer disconnects, and then Java responds
+        callback_manager.get_datastore(SERVER_ID).on_peer_disconnected(TCB_IDX);
+        let err = callback_manager.send_response(CONN_ID, trans_id, Ok(vec![1, 2])).unwrap_err();
+
+        // assert: the transaction was released
+        assert!(pending_before_disconnect);
+        assert!(!callback_manager.has_pending_transactions(CONN_ID));
+        assert_eq!(err, CallbackResponseError::NonExistentTransaction(trans_id));
+    });
+}
+
 #[test]
 fn test_write_characteristic_callback() {
     start_test(async {
@@ -266,7 +292,13 @@ fn test_write_characteristic_response() {
         let datastore = callback_manager.get_datastore(SERVER_ID);
         let pending_write = spawn_local(async move {
             datastore
-                .write(TCB_IDX, HANDLE_1, BACKING_TYPE, GattWriteRequestType::Request, &data)
+                .write(
+                    TCB_IDX,
+                    HANDLE_1,
+                    BACKING_TYPE,
+                    GattWriteRequestType::Request { offset: 0 },
+                    &data,
+                )
                 .await
         });
         // provide a response with some error code
@@ -302,6 +334,34 @@ fn test_response_timeout() {
     });
 }
 
+#[test]
+fn test_response_timeout_on_virtual_clock() {
+    start_test(async {
+        // arrange
+        let (callbacks, mut callbacks_rx) = MockCallbacks::new();
+        let clock = VirtualClock::new();
+        let callback_manager = Rc::new(CallbackTransactionManager::new_with_clock(
+            Rc::new(callbacks),
+            Rc::new(clock.clone()),
+        ));
+
+        // act: start an operation, and advance the clock up to the timeout
+        let datastore = callback_manager.get_datastore(SERVER_ID);
+        let pending_read =
+            spawn_local(
+                async move { datastore.read(TCB_IDX, HANDLE_1, OFFSET, BACKING_TYPE).await },
+            );
+        pull_trans_id(&mut callbacks_rx).await;
+        clock.advance(Duration::from_secs(15) - Duration::from_millis(1)).await;
+        let pending_before_timeout = !pending_read.is_finished();
+        clock.advance(Duration::from_millis(1)).await;
+
+        // assert: the operation only timed out once the clock reached 15s
+        assert!(pending_before_timeout);
+        assert_eq!(pending_read.await.unwrap(), Err(AttErrorCode::UNLIKELY_ERROR));
+    });
+}
+
 #[test]
 fn test_transaction_cleanup_after_timeout() {
     start_test(async {
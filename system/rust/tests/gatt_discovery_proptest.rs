@@ -0,0 +1,337 @@
+//! Generates random (valid) databases, runs the discovery procedures of a
+//! client against each through the ATT server (Core Spec 5.3 Vol 3G 4.4 to
+//! 4.7), and checks that every attribute is discovered exactly once, that the
+//! services are grouped correctly, and that no response exceeds the MTU.
+//!
+//! The responses are parsed by hand rather than with the PDL views used by
+//! the server, so that these tests also check their wire format.
+
+use std::rc::Rc;
+
+use bluetooth_core::{
+    core::{shared_box::SharedBox, uuid::Uuid},
+    gatt::{
+        ids::TransportIndex,
+        mocks::{mock_datastore::MockDatastore, mock_security_manager::MockSecurityManager},
+        server::{
+            att_database::{AttAttribute, AttDatabase},
+            gatt_database::{
+                AttDatabaseImpl, AttPermissions, CharacteristicBuilder, DescriptorBuilder,
+                GattDatabase, ServiceBuilder, CHARACTERISTIC_UUID,
+                PRIMARY_SERVICE_DECLARATION_UUID,
+            },
+            pdu_processor::AttPduProcessor,
+            signature_verifier::SignatureVerifier,
+        },
+    },
+    packets::{AttErrorCode, AttOpcode, Serializable},
+};
+use proptest::{collection::vec, prelude::*};
+
+const TCB_IDX: TransportIndex = TransportIndex(1);
+
+/// The largest MTU the server offers
+const SERVER_RX_MTU: usize = 517;
+
+/// The number of requests after which a discovery procedure is considered
+/// stuck
+const MAX_REQUESTS: usize = 1000;
+
+#[derive(Clone, Debug)]
+struct CharacteristicSpec {
+    type_: Uuid,
+    permissions: AttPermissions,
+    descriptors: Vec<Uuid>,
+}
+
+#[derive(Clone, Debug)]
+struct ServiceSpec {
+    type_: Uuid,
+    characteristics: Vec<CharacteristicSpec>,
+}
+
+/// A 16-bit UUID within the given range, or an arbitrary 128-bit one. The
+/// ranges used steer clear of the declaration and descriptor types that the
+/// database assigns a meaning to.
+fn uuid(range: std::ops::Range<u16>) -> impl Strategy<Value = Uuid> {
+    prop_oneof![
+        range.prop_map(|uuid| Uuid::new(uuid.into())),
+        any::<[u8; 16]>().prop_map(|bytes| Uuid::try_from_le_slice(&bytes).unwrap()),
+    ]
+}
+
+fn permissions() -> impl Strategy<Value = AttPermissions> {
+    (any::<bool>(), any::<bool>(), any::<bool>()).prop_map(|(writable, notify, indicate)| {
+        let mut permissions = AttPermissions::READABLE;
+        permissions.set(AttPermissions::WRITABLE_WITH_RESPONSE, writable);
+        permissions.set(AttPermissions::NOTIFY, notify);
+        permissions.set(AttPermissions::INDICATE, indicate);
+        permissions
+    })
+}
+
+fn characteristic() -> impl Strategy<Value = CharacteristicSpec> {
+    (uuid(0x4000..0x5000), permissions(), vec(uuid(0x3000..0x4000), 0..4)).prop_map(
+        |(type_, permissions, descriptors)| CharacteristicSpec { type_, permissions, descriptors },
+    )
+}
+
+fn service() -> impl Strategy<Value = ServiceSpec> {
+    (uuid(0x1000..0x2000), vec(characteristic(), 0..6))
+        .prop_map(|(type_, characteristics)| ServiceSpec { type_, characteristics })
+}
+
+fn make_db(services: &[ServiceSpec]) -> SharedBox<GattDatabase> {
+    let gatt_db = SharedBox::new(GattDatabase::new());
+    let (datastore, _) = MockDatastore::new();
+    let datastore = Rc::new(datastore);
+    for service in services {
+        let builder = service.characteristics.iter().fold(
+            ServiceBuilder::new(service.type_),
+            |builder, characteristic| {
+                builder.characteristic(characteristic.descriptors.iter().fold(
+                    CharacteristicBuilder::new(characteristic.type_, characteristic.permissions),
+                    |builder, descriptor| {
+                        builder.descriptor(DescriptorBuilder::new(
+                            *descriptor,
+                            AttPermissions::READABLE,
+                        ))
+                    },
+                ))
+            },
+        );
+        gatt_db.add_service(builder, datastore.clone()).unwrap();
+    }
+    gatt_db
+}
+
+/// Drives the discovery procedures as a client would, checking every
+/// response against the MTU
+struct DiscoveryClient {
+    processor: AttPduProcessor<AttDatabaseImpl>,
+}
+
+impl DiscoveryClient {
+    fn new(gatt_db: &SharedBox<GattDatabase>, client_rx_mtu: u16) -> Self {
+        let mut client = Self {
+            processor: AttPduProcessor::new(
+                gatt_db.get_att_database(TCB_IDX),
+                SignatureVerifier::new(TCB_IDX, Rc::new(MockSecurityManager::new())),
+                SERVER_RX_MTU,
+            ),
+        };
+        let mut request = vec![u8::from(AttOpcode::EXCHANGE_MTU_REQUEST)];
+        request.extend_from_slice(&client_rx_mtu.to_le_bytes());
+        let reply = client.request(request);
+        assert_eq!(reply[0], u8::from(AttOpcode::EXCHANGE_MTU_RESPONSE));
+        client
+    }
+
+    /// Send a request, and return the serialized response
+    fn request(&mut self, request: Vec<u8>) -> Vec<u8> {
+        let reply = tokio_test::block_on(self.processor.process_pdu(&request))
+            .unwrap_or_else(|| panic!("no reply to {request:02x?}"))
+            .to_vec()
+            .unwrap();
+        let mtu = self.processor.mtu();
+        assert!(reply.len() <= mtu, "{reply:02x?} exceeds the MTU of {mtu}");
+        reply
+    }
+
+    /// Send a request for the given range, and split the response of the given
+    /// opcode into its elements (each starting with a handle). Returns None
+    /// once the server finds no more attributes in the range.
+    fn request_elements(
+        &mut self,
+        opcode: AttOpcode,
+        start: u16,
+        end: u16,
+        parameters: &[u8],
+    ) -> Option<Vec<(u16, Vec<u8>)>> {
+        let mut request = vec![u8::from(opcode)];
+        request.extend_from_slice(&start.to_le_bytes());
+        request.extend_from_slice(&end.to_le_bytes());
+        request.extend_from_slice(parameters);
+        let reply = self.request(request);
+
+        if reply[0] == u8::from(AttOpcode::ERROR_RESPONSE) {
+            assert_eq!(reply[1], u8::from(opcode));
+            assert_eq!(reply[4], u8::from(AttErrorCode::ATTRIBUTE_NOT_FOUND));
+            return None;
+        }
+        assert_eq!(reply[0], u8::from(opcode) + 1);
+        let element_len = match opcode {
+            AttOpcode::FIND_INFORMATION_REQUEST => match reply[1] {
+                0x01 => 4,
+                0x02 => 18,
+                format => panic!("unexpected format {format}"),
+            },
+            _ => reply[1] as usize,
+        };
+        let elements = &reply[2..];
+        assert!(!elements.is_empty());
+        assert_eq!(elements.len() % element_len, 0, "truncated element in {reply:02x?}");
+        Some(
+            elements
+                .chunks(element_len)
+                .map(|element| {
+                    (u16::from_le_bytes([element[0], element[1]]), element[2..].to_vec())
+                })
+                .collect(),
+        )
+    }
+
+    /// Run a procedure, requesting the rest of the range after the last handle
+    /// of each response until the server finds no more attributes
+    fn discover_range(
+        &mut self,
+        opcode: AttOpcode,
+        start: u16,
+        end: u16,
+        parameters: &[u8],
+        last_handle: impl Fn(u16, &[u8]) -> u16,
+    ) -> Vec<(u16, Vec<u8>)> {
+        let mut found = vec![];
+        let mut start = start;
+        for _ in 0..MAX_REQUESTS {
+            let Some(elements) = self.request_elements(opcode, start, end, parameters) else {
+                return found;
+            };
+            for (handle, _) in &elements {
+                assert!((start..=end).contains(handle), "{handle:#x} out of {start:#x}..={end:#x}");
+            }
+            let (handle, rest) = elements.last().unwrap();
+            let last = last_handle(*handle, rest);
+            found.extend(elements);
+            if last >= end {
+                return found;
+            }
+            start = last + 1;
+        }
+        panic!("{opcode:?} made no progress");
+    }
+}
+
+fn parse_uuid(bytes: &[u8]) -> Uuid {
+    Uuid::try_from_le_slice(bytes).unwrap()
+}
+
+/// A service found by Discover All Primary Services
+struct DiscoveredService {
+    handle: u16,
+    end_group_handle: u16,
+}
+
+/// Run every discovery procedure, returning the services found, as well as
+/// every attribute found (with its type)
+fn discover(client: &mut DiscoveryClient) -> (Vec<DiscoveredService>, Vec<(u16, Uuid)>) {
+    let mut attributes = vec![];
+    let group_type = PRIMARY_SERVICE_DECLARATION_UUID.to_u16().unwrap().to_le_bytes();
+    let services = client
+        .discover_range(
+            AttOpcode::READ_BY_GROUP_TYPE_REQUEST,
+            0x0001,
+            0xFFFF,
+            &group_type,
+            |_, rest| u16::from_le_bytes([rest[0], rest[1]]),
+        )
+        .into_iter()
+        .map(|(handle, rest)| {
+            attributes.push((handle, PRIMARY_SERVICE_DECLARATION_UUID));
+            DiscoveredService { handle, end_group_handle: u16::from_le_bytes([rest[0], rest[1]]) }
+        })
+        .collect::<Vec<_>>();
+
+    let characteristic_type = CHARACTERISTIC_UUID.to_u16().unwrap().to_le_bytes();
+    for service in &services {
+        if service.handle == service.end_group_handle {
+            continue;
+        }
+        let declarations = client.discover_range(
+            AttOpcode::READ_BY_TYPE_REQUEST,
+            service.handle + 1,
+            service.end_group_handle,
+            &characteristic_type,
+            |handle, _| handle,
+        );
+        for (i, (handle, value)) in declarations.iter().enumerate() {
+            // properties, value handle, and type
+            let value_handle = u16::from_le_bytes([value[1], value[2]]);
+            attributes.push((*handle, CHARACTERISTIC_UUID));
+            attributes.push((value_handle, parse_uuid(&value[3..])));
+
+            // the descriptors lie between the value and the next declaration
+            let end = declarations
+                .get(i + 1)
+                .map(|(next, _)| next - 1)
+                .unwrap_or(service.end_group_handle);
+            if value_handle >= end {
+                continue;
+            }
+            let descriptors = client.discover_range(
+                AttOpcode::FIND_INFORMATION_REQUEST,
+                value_handle + 1,
+                end,
+                &[],
+                |handle, _| handle,
+            );
+            attributes
+                .extend(descriptors.into_iter().map(|(handle, uuid)| (handle, parse_uuid(&uuid))));
+        }
+    }
+    (services, attributes)
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn test_every_attribute_discovered_once(
+        services in vec(service(), 1..6),
+        client_rx_mtu in 23u16..=517,
+    ) {
+        // arrange
+        let gatt_db = make_db(&services);
+        let expected = gatt_db
+            .get_att_database(TCB_IDX)
+            .list_attributes()
+            .into_iter()
+            .map(|AttAttribute { handle, type_, .. }| (handle.0, type_))
+            .collect::<Vec<_>>();
+        let mut client = DiscoveryClient::new(&gatt_db, client_rx_mtu);
+
+        // act
+        let (_, mut discovered) = discover(&mut client);
+
+        // assert
+        discovered.sort_by_key(|(handle, _)| *handle);
+        prop_assert_eq!(discovered, expected);
+    }
+
+    #[test]
+    fn test_group_end_handles(
+        services in vec(service(), 1..6),
+        client_rx_mtu in 23u16..=517,
+    ) {
+        // arrange
+        let gatt_db = make_db(&services);
+        let attributes = gatt_db.get_att_database(TCB_IDX).list_attributes();
+        let mut client = DiscoveryClient::new(&gatt_db, client_rx_mtu);
+
+        // act
+        let (discovered, _) = discover(&mut client);
+
+        // assert: each service ends at the last attribute before the next one
+        prop_assert_eq!(discovered.len(), services.len());
+        for (i, service) in discovered.iter().enumerate() {
+            let next = discovered.get(i + 1).map(|next| next.handle).unwrap_or(u16::MAX);
+            let last = attributes
+                .iter()
+                .map(|attribute| attribute.handle.0)
+                .filter(|handle| (service.handle..next).contains(handle))
+                .max()
+                .unwrap();
+            prop_assert_eq!(service.end_group_handle, last);
+        }
+    }
+}
@@ -1,50 +1,91 @@
+use async_trait::async_trait;
 use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    future::pending,
     rc::Rc,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 
 use bluetooth_core::{
-    core::uuid::Uuid,
+    core::{
+        address::{AddressType, AddressWithType},
+        uuid::Uuid,
+    },
     gatt::{
         self,
+        callbacks::GattDatastore,
+        channel::{TransactionTimeoutEvent, TransmitBackpressure, ATT_TRANSACTION_TIMEOUT},
         ffi::AttributeBackingType,
-        ids::{AdvertiserId, AttHandle, ServerId, TransportIndex},
+        ids::{
+            AdvertiserId, AppId, AttHandle, BearerId, ConnectionId, EattCid, ServerId, Transport,
+            TransportIndex,
+        },
         mocks::{
             mock_datastore::{MockDatastore, MockDatastoreEvents},
+            mock_identity_resolver::MockIdentityResolver,
+            mock_peer_info_provider::MockPeerInfoProvider,
+            mock_security_manager::MockSecurityManager,
             mock_transport::MockAttTransport,
         },
+        security_manager::SecurityLevel,
         server::{
+            access_journal::{AccessJournal, AccessOutcome, PeerIdentity},
+            access_policy::BondedClientsOnly,
+            att_server_bearer::DEFAULT_REQUEST_TIMEOUT,
+            characteristic_streams::CharacteristicStreams,
+            client_configuration::ClientConfiguration,
+            config::{DuplicateRequestPolicy, ErrorRateLimit, GattServerConfig},
+            events::{GattServerEvent, GattServerEventListener},
             gatt_database::{
-                AttPermissions, GattCharacteristicWithHandle, GattDescriptorWithHandle,
-                GattServiceWithHandle, CHARACTERISTIC_UUID, PRIMARY_SERVICE_DECLARATION_UUID,
+                AttPermissions, CharacteristicBuilder, GattCharacteristicWithHandle,
+                GattDescriptorWithHandle, GattServiceWithHandle, ServiceBuilder,
+                CHARACTERISTIC_UUID, PRIMARY_SERVICE_DECLARATION_UUID,
             },
+            interceptors::{Interception, Interceptor, LoggingInterceptor, OperationContext},
+            interop::{InteropRegistry, PeerInfo, PeerPattern, Quirk},
             isolation_manager::IsolationManager,
+            opcode_policy::RestrictedPeers,
             services::{
-                gap::DEVICE_NAME_UUID,
+                dis::{DeviceInformationProvider, PnpId, MANUFACTURER_NAME_UUID},
+                gap::{GapConfiguration, PreferredConnectionParameters, DEVICE_NAME_UUID},
                 gatt::{
-                    CLIENT_CHARACTERISTIC_CONFIGURATION_UUID, GATT_SERVICE_UUID,
-                    SERVICE_CHANGE_UUID,
+                    ServerSupportedFeatures, CLIENT_CHARACTERISTIC_CONFIGURATION_UUID,
+                    GATT_SERVICE_UUID, SERVER_SUPPORTED_FEATURES_UUID, SERVICE_CHANGE_UUID,
                 },
             },
+            trace::{Direction, RingBufferTracer},
             GattModule, IndicationError,
         },
     },
     packets::{
         AttAttributeDataChild, AttBuilder, AttChild, AttErrorCode, AttErrorResponseBuilder,
+        AttExchangeMtuRequestBuilder, AttExchangeMtuResponseBuilder, AttExecuteWriteFlags,
+        AttExecuteWriteRequestBuilder, AttExecuteWriteResponseBuilder,
         AttFindByTypeValueRequestBuilder, AttFindInformationRequestBuilder,
         AttFindInformationResponseChild, AttHandleValueConfirmationBuilder,
-        AttHandleValueIndicationBuilder, AttOpcode, AttReadByTypeRequestBuilder,
-        AttReadRequestBuilder, AttReadResponseBuilder, AttWriteRequestBuilder,
-        AttWriteResponseBuilder, GattCharacteristicDeclarationValueView,
+        AttHandleValueIndicationBuilder, AttHandleValueNotificationBuilder, AttOpcode,
+        AttPrepareWriteRequestBuilder, AttPrepareWriteResponseBuilder, AttReadByTypeRequestBuilder,
+        AttReadByTypeResponseView, AttReadRequestBuilder, AttReadResponseBuilder,
+        AttWriteRequestBuilder, AttWriteResponseBuilder, GattCharacteristicDeclarationValueView,
         GattClientCharacteristicConfigurationBuilder, GattServiceChangedBuilder,
-        GattServiceDeclarationValueBuilder, Packet, Serializable, UuidAsAttDataBuilder,
+        GattServiceDeclarationValueBuilder, OwnedAttView, OwnedPacket, Packet, Serializable,
+        UuidAsAttDataBuilder,
+    },
+    utils::{
+        clock::VirtualClock,
+        executor::{Executor, Task, TaskHandle, TokioExecutor},
+        packet::{build_att_data, build_att_view_or_crash},
     },
-    utils::packet::{build_att_data, build_att_view_or_crash},
 };
 
 use tokio::{
-    sync::mpsc::{error::TryRecvError, UnboundedReceiver},
-    task::spawn_local,
+    sync::{
+        mpsc::{error::TryRecvError, UnboundedReceiver},
+        Notify,
+    },
+    task::{spawn_local, yield_now},
 };
 use utils::start_test;
 
@@ -57,30 +98,61 @@ const ADVERTISER_ID: AdvertiserId = AdvertiserId(3);
 const ANOTHER_TCB_IDX: TransportIndex = TransportIndex(2);
 const ANOTHER_SERVER_ID: ServerId = ServerId(3);
 const ANOTHER_ADVERTISER_ID: AdvertiserId = AdvertiserId(4);
+const EATT_CID: EattCid = EattCid(0x40);
+const EATT_MTU: usize = 64;
 
-const SERVICE_HANDLE: AttHandle = AttHandle(6);
-const CHARACTERISTIC_HANDLE: AttHandle = AttHandle(8);
-const DESCRIPTOR_HANDLE: AttHandle = AttHandle(9);
+// clear of the handles used by the builtin GATT service
+const SERVICE_HANDLE: AttHandle = AttHandle(11);
+const CHARACTERISTIC_HANDLE: AttHandle = AttHandle(13);
+const DESCRIPTOR_HANDLE: AttHandle = AttHandle(14);
+// the CCCD managed by the server, following the last descriptor
+const CCCD_HANDLE: AttHandle = AttHandle(15);
 
 const SERVICE_TYPE: Uuid = Uuid::new(0x0102);
 const CHARACTERISTIC_TYPE: Uuid = Uuid::new(0x0103);
 const DESCRIPTOR_TYPE: Uuid = Uuid::new(0x0104);
 
+const PEER: AddressWithType =
+    AddressWithType { address: [1, 2, 3, 4, 5, 6], address_type: AddressType::Public };
+// resolvable private addresses of PEER, successively used by it
+const PEER_RPAS: [AddressWithType; 2] = [
+    AddressWithType {
+        address: [0x11, 0x22, 0x33, 0x44, 0x55, 0x66],
+        address_type: AddressType::Random,
+    },
+    AddressWithType {
+        address: [0x77, 0x88, 0x99, 0xAA, 0xBB, 0x66],
+        address_type: AddressType::Random,
+    },
+];
+
 const DATA: [u8; 4] = [1, 2, 3, 4];
 const ANOTHER_DATA: [u8; 4] = [5, 6, 7, 8];
 
 fn start_gatt_module() -> (gatt::server::GattModule, UnboundedReceiver<(TransportIndex, AttBuilder)>)
 {
-    let (transport, transport_rx) = MockAttTransport::new();
+    let (gatt, transport_rx, _) = start_gatt_module_with_eatt();
+    (gatt, transport_rx)
+}
+
+#[allow(clippy::type_complexity)]
+fn start_gatt_module_with_eatt() -> (
+    gatt::server::GattModule,
+    UnboundedReceiver<(TransportIndex, AttBuilder)>,
+    UnboundedReceiver<(TransportIndex, EattCid, AttBuilder)>,
+) {
+    let (transport, transport_rx, eatt_rx) = MockAttTransport::new();
     let arbiter = IsolationManager::new();
-    let gatt = GattModule::new(Rc::new(transport), Arc::new(Mutex::new(arbiter)));
+    let gatt = GattModule::new(
+        Rc::new(transport),
+        Rc::new(MockSecurityManager::new()),
+        Arc::new(Mutex::new(arbiter)),
+    );
 
-    (gatt, transport_rx)
+    (gatt, transport_rx, eatt_rx)
 }
 
-fn create_server_and_open_connection(
-    gatt: &mut GattModule,
-) -> UnboundedReceiver<MockDatastoreEvents> {
+fn create_server(gatt: &mut GattModule) -> UnboundedReceiver<MockDatastoreEvents> {
     gatt.open_gatt_server(SERVER_ID).unwrap();
     let (datastore, data_rx) = MockDatastore::new();
     gatt.register_gatt_service(
@@ -104,6 +176,13 @@ fn create_server_and_open_connection(
         datastore,
     )
     .unwrap();
+    data_rx
+}
+
+fn create_server_and_open_connection(
+    gatt: &mut GattModule,
+) -> UnboundedReceiver<MockDatastoreEvents> {
+    let data_rx = create_server(gatt);
     gatt.get_isolation_manager().associate_server_with_advertiser(SERVER_ID, ADVERTISER_ID);
     gatt.on_le_connect(TCB_IDX, Some(ADVERTISER_ID)).unwrap();
     data_rx
@@ -220,6 +299,186 @@ fn test_characteristic_read() {
     })
 }
 
+#[test]
+fn test_unbonded_client_kept_out_by_access_policy() {
+    start_test(async move {
+        // arrange: a server only serving bonded clients
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        let mut data_rx = create_server_and_open_connection(&mut gatt);
+        gatt.set_access_interceptor(SERVER_ID, Rc::new(BondedClientsOnly::new())).unwrap();
+
+        // act: an unbonded client reads the characteristic, then the service
+        // declaration (each request awaiting the previous response)
+        let mut responses = vec![];
+        for handle in [CHARACTERISTIC_HANDLE, SERVICE_HANDLE] {
+            gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+                build_att_view_or_crash(AttReadRequestBuilder { attribute_handle: handle.into() })
+                    .view(),
+            );
+            let (_, resp) = transport_rx.recv().await.unwrap();
+            responses.push(resp);
+        }
+        let [characteristic_resp, service_resp] = responses.try_into().unwrap();
+
+        // assert: only the declaration could be read, without the datastore being
+        // consulted
+        assert_eq!(
+            characteristic_resp._child_,
+            AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::READ_REQUEST,
+                handle_in_error: CHARACTERISTIC_HANDLE.into(),
+                error_code: AttErrorCode::INSUFFICIENT_AUTHENTICATION
+            }
+            .into()
+        );
+        assert_eq!(service_resp.opcode, AttOpcode::READ_RESPONSE);
+        assert_eq!(data_rx.try_recv().unwrap_err(), TryRecvError::Empty);
+    });
+}
+
+/// Rejects every write it intercepts
+struct RejectWrites;
+
+impl Interceptor for RejectWrites {
+    fn before_write(&self, _: &OperationContext, _: &mut Vec<u8>) -> Interception<()> {
+        Interception::Complete(Err(AttErrorCode::WRITE_REQUEST_REJECTED))
+    }
+}
+
+#[test]
+fn test_writes_rejected_by_interceptor_until_removed() {
+    start_test(async move {
+        // arrange: the writes to the service are logged, then rejected
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        let mut data_rx = create_server_and_open_connection(&mut gatt);
+        let interceptor: Rc<dyn Interceptor> = Rc::new(RejectWrites);
+        gatt.add_interceptor(SERVER_ID, SERVICE_HANDLE..=DESCRIPTOR_HANDLE, 1, interceptor.clone())
+            .unwrap();
+        gatt.add_interceptor(
+            SERVER_ID,
+            SERVICE_HANDLE..=DESCRIPTOR_HANDLE,
+            0,
+            Rc::new(LoggingInterceptor),
+        )
+        .unwrap();
+        let write = |gatt: &GattModule| {
+            gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+                build_att_view_or_crash(AttWriteRequestBuilder {
+                    handle: CHARACTERISTIC_HANDLE.into(),
+                    value: build_att_data(AttAttributeDataChild::RawData(DATA.into())),
+                })
+                .view(),
+            )
+        };
+
+        // act: the client writes before and after the interceptor is removed
+        write(&gatt);
+        let (_, rejected_resp) = transport_rx.recv().await.unwrap();
+        let consulted_while_intercepted = data_rx.try_recv().is_ok();
+        gatt.remove_interceptor(SERVER_ID, &interceptor).unwrap();
+        write(&gatt);
+        let Some(MockDatastoreEvents::Write(_, _, _, data, reply)) = data_rx.recv().await else {
+            unreachable!();
+        };
+        reply.send(Ok(())).unwrap();
+        let (_, accepted_resp) = transport_rx.recv().await.unwrap();
+
+        // assert
+        assert_eq!(
+            rejected_resp._child_,
+            AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::WRITE_REQUEST,
+                handle_in_error: CHARACTERISTIC_HANDLE.into(),
+                error_code: AttErrorCode::WRITE_REQUEST_REJECTED
+            }
+            .into()
+        );
+        assert!(!consulted_while_intercepted);
+        assert_eq!(data, DATA.to_vec());
+        assert_eq!(accepted_resp._child_, AttWriteResponseBuilder {}.into());
+        assert!(gatt.remove_interceptor(SERVER_ID, &interceptor).is_err());
+    });
+}
+
+#[test]
+fn test_restricted_peer_limited_by_opcode_policy() {
+    start_test(async move {
+        // arrange: a restricted client
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        let policy = Rc::new(RestrictedPeers::new());
+        policy.restrict(TCB_IDX);
+        gatt.set_opcode_policy(policy.clone());
+        let mut data_rx = create_server_and_open_connection(&mut gatt);
+        gatt.set_access_interceptor(SERVER_ID, policy).unwrap();
+
+        // act: the client writes the characteristic, then reads it
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttWriteRequestBuilder {
+                handle: CHARACTERISTIC_HANDLE.into(),
+                value: build_att_data(AttAttributeDataChild::RawData(DATA.into())),
+            })
+            .view(),
+        );
+        let (_, write_resp) = transport_rx.recv().await.unwrap();
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttReadRequestBuilder {
+                attribute_handle: CHARACTERISTIC_HANDLE.into(),
+            })
+            .view(),
+        );
+        let (_, read_resp) = transport_rx.recv().await.unwrap();
+
+        // assert: the write is not supported at all, and the read is refused
+        // outside of the GAP service, without the datastore being consulted
+        assert_eq!(
+            write_resp._child_,
+            AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::WRITE_REQUEST,
+                handle_in_error: AttHandle(0).into(),
+                error_code: AttErrorCode::REQUEST_NOT_SUPPORTED
+            }
+            .into()
+        );
+        assert_eq!(
+            read_resp._child_,
+            AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::READ_REQUEST,
+                handle_in_error: CHARACTERISTIC_HANDLE.into(),
+                error_code: AttErrorCode::READ_NOT_PERMITTED
+            }
+            .into()
+        );
+        assert_eq!(data_rx.try_recv().unwrap_err(), TryRecvError::Empty);
+    });
+}
+
+#[test]
+fn test_bonded_client_allowed_by_access_policy() {
+    start_test(async move {
+        // arrange: a server only serving bonded clients, to which a bonded client is
+        // connected
+        let (mut gatt, _transport_rx) = start_gatt_module();
+        let mut data_rx = create_server_and_open_connection(&mut gatt);
+        gatt.set_access_interceptor(SERVER_ID, Rc::new(BondedClientsOnly::new())).unwrap();
+        gatt.on_le_bonded(TCB_IDX, PEER).unwrap();
+
+        // act: read the characteristic
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttReadRequestBuilder {
+                attribute_handle: CHARACTERISTIC_HANDLE.into(),
+            })
+            .view(),
+        );
+
+        // assert: the read reached the datastore
+        let MockDatastoreEvents::Read(TCB_IDX, CHARACTERISTIC_HANDLE, _, _) =
+            data_rx.recv().await.unwrap()
+        else {
+            unreachable!()
+        };
+    });
+}
+
 #[test]
 fn test_characteristic_write() {
     start_test(async move {
@@ -266,6 +525,101 @@ fn test_characteristic_write() {
     })
 }
 
+#[test]
+fn test_retransmitted_write_executed_once() {
+    start_test(async move {
+        // arrange: a server resending the reply to retransmitted writes
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        gatt.set_config(GattServerConfig {
+            duplicate_requests: DuplicateRequestPolicy::ResendResponse {
+                window: Duration::from_secs(1),
+            },
+            ..Default::default()
+        })
+        .unwrap();
+        let mut data_rx = create_server_and_open_connection(&mut gatt);
+        let write = build_att_view_or_crash(AttWriteRequestBuilder {
+            handle: CHARACTERISTIC_HANDLE.into(),
+            value: build_att_data(AttAttributeDataChild::RawData(DATA.into())),
+        });
+
+        // act: the client writes, and retransmits the write once answered
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(write.view());
+        let MockDatastoreEvents::Write(_, _, _, _, reply) = data_rx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        reply.send(Ok(())).unwrap();
+        let (_, first) = transport_rx.recv().await.unwrap();
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(write.view());
+        let (_, second) = transport_rx.recv().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+
+        // assert: both were answered, but the datastore only saw one write
+        assert_eq!(first.opcode, AttOpcode::WRITE_RESPONSE);
+        assert_eq!(second.to_vec().unwrap(), first.to_vec().unwrap());
+        assert_eq!(data_rx.try_recv().unwrap_err(), TryRecvError::Empty);
+    })
+}
+
+#[test]
+fn test_request_storm_throttled() {
+    start_test(async move {
+        // arrange: a server throttling clients making more than one failed
+        // request per second
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        gatt.set_config(GattServerConfig {
+            error_rate_limit: Some(ErrorRateLimit {
+                max_errors_per_second: 1,
+                response_delay: Duration::from_secs(1),
+            }),
+            ..Default::default()
+        })
+        .unwrap();
+        let listener = Rc::new(RecordingListener::default());
+        gatt.register_event_listener(listener.clone());
+        let _data_rx = create_server_and_open_connection(&mut gatt);
+        let read = build_att_view_or_crash(AttReadRequestBuilder {
+            attribute_handle: AttHandle(0x100).into(),
+        });
+
+        // act: the client reads an invalid handle three times in a row
+        for _ in 0..2 {
+            gatt.get_bearer(TCB_IDX).unwrap().handle_packet(read.view());
+            transport_rx.recv().await.unwrap();
+        }
+        let throttled_at = tokio::time::Instant::now();
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(read.view());
+        let (_, reply) = transport_rx.recv().await.unwrap();
+
+        // assert: the upper layer was told, and the third read was held back
+        assert!(listener.0.borrow().contains(&GattServerEvent::ErrorRateExceeded {
+            conn_id: ConnectionId::new(TCB_IDX, SERVER_ID),
+            errors: 2
+        }));
+        assert_eq!(reply.opcode, AttOpcode::ERROR_RESPONSE);
+        assert!(throttled_at.elapsed() >= Duration::from_secs(1));
+    })
+}
+
+async fn subscribe_to_indications(
+    gatt: &GattModule,
+    transport_rx: &mut UnboundedReceiver<(TransportIndex, AttBuilder)>,
+) {
+    gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+        build_att_view_or_crash(AttWriteRequestBuilder {
+            handle: CCCD_HANDLE.into(),
+            value: build_att_data(GattClientCharacteristicConfigurationBuilder {
+                notification: 0,
+                indication: 1,
+            }),
+        })
+        .view(),
+    );
+    let AttChild::AttWriteResponse(_) = transport_rx.recv().await.unwrap().1._child_ else {
+        unreachable!()
+    };
+}
+
 #[test]
 fn test_send_indication() {
     start_test(async move {
@@ -275,6 +629,7 @@ fn test_send_indication() {
         let data = AttAttributeDataChild::RawData(DATA.into());
 
         create_server_and_open_connection(&mut gatt);
+        subscribe_to_indications(&gatt, &mut transport_rx).await;
 
         // act
         let pending_indication = spawn_local(
@@ -304,6 +659,55 @@ fn test_send_indication() {
     })
 }
 
+/// A transport that withholds credits until released, recording the bearers
+/// on which they were requested, and those that closed
+#[derive(Default)]
+struct GatedBackpressure {
+    bearers: RefCell<Vec<BearerId>>,
+    closed: RefCell<Vec<BearerId>>,
+    released: Notify,
+}
+
+#[async_trait(?Send)]
+impl TransmitBackpressure for GatedBackpressure {
+    async fn wait_for_credit(&self, bearer: BearerId) {
+        self.bearers.borrow_mut().push(bearer);
+        self.released.notified().await;
+    }
+
+    fn on_bearer_closed(&self, bearer: BearerId) {
+        self.closed.borrow_mut().push(bearer);
+    }
+}
+
+#[test]
+fn test_indication_paced_by_transport_backpressure() {
+    start_test(async move {
+        // arrange
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        let backpressure = Rc::new(GatedBackpressure::default());
+        gatt.set_backpressure(backpressure.clone());
+        create_server_and_open_connection(&mut gatt);
+        subscribe_to_indications(&gatt, &mut transport_rx).await;
+
+        // act: send an indication, and only then release a credit
+        let _pending_indication =
+            spawn_local(gatt.get_bearer(TCB_IDX).unwrap().send_indication(
+                CHARACTERISTIC_HANDLE,
+                AttAttributeDataChild::RawData(DATA.into()),
+            ));
+        yield_now().await;
+        let sent_before_credit = transport_rx.try_recv();
+        backpressure.released.notify_waiters();
+        let (_, resp) = transport_rx.recv().await.unwrap();
+
+        // assert: the indication waited for a credit on the unenhanced bearer
+        assert!(sent_before_credit.is_err());
+        assert_eq!(resp.opcode, AttOpcode::HANDLE_VALUE_INDICATION);
+        assert_eq!(*backpressure.bearers.borrow(), vec![BearerId::unenhanced(TCB_IDX)]);
+    });
+}
+
 #[test]
 fn test_send_indication_and_disconnect() {
     start_test(async move {
@@ -311,6 +715,7 @@ fn test_send_indication_and_disconnect() {
         let (mut gatt, mut transport_rx) = start_gatt_module();
 
         create_server_and_open_connection(&mut gatt);
+        subscribe_to_indications(&gatt, &mut transport_rx).await;
 
         // act: send an indication, then disconnect
         let pending_indication = spawn_local(gatt.get_bearer(TCB_IDX).unwrap().send_indication(
@@ -329,330 +734,2624 @@ fn test_send_indication_and_disconnect() {
 }
 
 #[test]
-fn test_write_to_descriptor() {
+fn test_send_indication_unsubscribed() {
     start_test(async move {
-        // arrange
+        // arrange: a client that has not written to the CCCD
         let (mut gatt, mut transport_rx) = start_gatt_module();
+        create_server_and_open_connection(&mut gatt);
 
-        let data = AttAttributeDataChild::RawData(DATA.into());
-
-        let mut data_rx = create_server_and_open_connection(&mut gatt);
+        // act: send an indication
+        let res = gatt
+            .get_bearer(TCB_IDX)
+            .unwrap()
+            .send_indication(
+                CHARACTERISTIC_HANDLE,
+                AttAttributeDataChild::RawData([1, 2, 3, 4].into()),
+            )
+            .await;
+
+        // assert: the indication was not sent
+        assert!(matches!(res, Err(IndicationError::ClientNotSubscribed)));
+        assert_eq!(transport_rx.try_recv().unwrap_err(), TryRecvError::Empty);
+    })
+}
 
-        // act
+#[test]
+fn test_notify_all_subscribed_connections() {
+    start_test(async move {
+        // arrange: two clients of a service with a notifying characteristic, one of
+        // them subscribed
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        create_server(&mut gatt);
+        let (datastore, _data_rx) = MockDatastore::new();
+        let token = gatt
+            .add_gatt_service(
+                SERVER_ID,
+                ServiceBuilder::new(SERVICE_TYPE).characteristic(CharacteristicBuilder::new(
+                    CHARACTERISTIC_TYPE,
+                    AttPermissions::READABLE | AttPermissions::NOTIFY,
+                )),
+                datastore,
+            )
+            .unwrap();
+        let value_handle = AttHandle(token.handle().0 + 2);
+        gatt.get_isolation_manager().associate_server_with_advertiser(SERVER_ID, ADVERTISER_ID);
+        gatt.on_le_connect(TCB_IDX, Some(ADVERTISER_ID)).unwrap();
+        gatt.on_le_connect(ANOTHER_TCB_IDX, Some(ADVERTISER_ID)).unwrap();
         gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
             build_att_view_or_crash(AttWriteRequestBuilder {
-                handle: DESCRIPTOR_HANDLE.into(),
-                value: build_att_data(data.clone()),
+                handle: AttHandle(value_handle.0 + 1).into(),
+                value: build_att_data(GattClientCharacteristicConfigurationBuilder {
+                    notification: 1,
+                    indication: 0,
+                }),
             })
             .view(),
         );
-        let (tx, written_data) = if let MockDatastoreEvents::Write(
-            TCB_IDX,
-            DESCRIPTOR_HANDLE,
-            AttributeBackingType::Descriptor,
-            written_data,
-            tx,
-        ) = data_rx.recv().await.unwrap()
-        {
-            (tx, written_data)
-        } else {
-            unreachable!()
-        };
-        tx.send(Ok(())).unwrap();
-        let (tcb_idx, resp) = transport_rx.recv().await.unwrap();
+        transport_rx.recv().await.unwrap();
 
-        // assert
+        // act: notify a value longer than the default MTU allows
+        let value = [0x42; 30];
+        let results = gatt.notify_all(SERVER_ID, value_handle, &value).unwrap().await;
+
+        // assert: only the subscribed client was notified, with the truncated value
+        let (tcb_idx, notification) = transport_rx.recv().await.unwrap();
         assert_eq!(tcb_idx, TCB_IDX);
         assert_eq!(
-            resp,
-            AttBuilder {
-                opcode: AttOpcode::WRITE_RESPONSE,
-                _child_: AttWriteResponseBuilder {}.into()
+            notification._child_,
+            AttHandleValueNotificationBuilder {
+                handle: value_handle.into(),
+                value: build_att_data(AttAttributeDataChild::RawData(value[..20].into())),
             }
+            .into()
         );
-        assert_eq!(data.to_vec().unwrap(), written_data)
+        assert_eq!(results.len(), 1);
+        assert!(matches!(results[&ConnectionId::new(TCB_IDX, SERVER_ID)], Ok(())));
+        assert_eq!(transport_rx.try_recv().unwrap_err(), TryRecvError::Empty);
     })
 }
 
+fn create_streaming_server(gatt: &mut GattModule) -> (CharacteristicStreams, AttHandle) {
+    create_server(gatt);
+    let streams = CharacteristicStreams::new();
+    let token = gatt
+        .add_streaming_gatt_service(
+            SERVER_ID,
+            ServiceBuilder::new(SERVICE_TYPE).characteristic(CharacteristicBuilder::new(
+                CHARACTERISTIC_TYPE,
+                AttPermissions::READABLE
+                    | AttPermissions::WRITABLE_WITH_RESPONSE
+                    | AttPermissions::NOTIFY,
+            )),
+            &streams,
+        )
+        .unwrap();
+    gatt.get_isolation_manager().associate_server_with_advertiser(SERVER_ID, ADVERTISER_ID);
+    gatt.on_le_connect(TCB_IDX, Some(ADVERTISER_ID)).unwrap();
+    (streams, AttHandle(token.handle().0 + 2))
+}
+
 #[test]
-fn test_multiple_servers() {
+fn test_streamed_write() {
     start_test(async move {
         // arrange
         let (mut gatt, mut transport_rx) = start_gatt_module();
-        // open the default server (SERVER_ID on CONN_ID)
-        let mut data_rx_1 = create_server_and_open_connection(&mut gatt);
-        // open a second server and connect to it (ANOTHER_SERVER_ID on ANOTHER_CONN_ID)
-        let (datastore, mut data_rx_2) = MockDatastore::new();
-        gatt.open_gatt_server(ANOTHER_SERVER_ID).unwrap();
-        gatt.register_gatt_service(
-            ANOTHER_SERVER_ID,
-            GattServiceWithHandle {
-                handle: SERVICE_HANDLE,
-                type_: SERVICE_TYPE,
-                characteristics: vec![GattCharacteristicWithHandle {
-                    handle: CHARACTERISTIC_HANDLE,
-                    type_: CHARACTERISTIC_TYPE,
-                    permissions: AttPermissions::READABLE,
-                    descriptors: vec![],
-                }],
-            },
-            datastore,
-        )
-        .unwrap();
-        gatt.get_isolation_manager()
-            .associate_server_with_advertiser(ANOTHER_SERVER_ID, ANOTHER_ADVERTISER_ID);
-        gatt.on_le_connect(ANOTHER_TCB_IDX, Some(ANOTHER_ADVERTISER_ID)).unwrap();
+        let (streams, value_handle) = create_streaming_server(&mut gatt);
+        let mut writes = streams.writes(value_handle).unwrap();
 
-        // act: read from both connections
+        // act: the client writes, and the service accepts the value
         gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
-            build_att_view_or_crash(AttReadRequestBuilder {
-                attribute_handle: CHARACTERISTIC_HANDLE.into(),
-            })
-            .view(),
-        );
-        gatt.get_bearer(ANOTHER_TCB_IDX).unwrap().handle_packet(
-            build_att_view_or_crash(AttReadRequestBuilder {
-                attribute_handle: CHARACTERISTIC_HANDLE.into(),
+            build_att_view_or_crash(AttWriteRequestBuilder {
+                handle: value_handle.into(),
+                value: build_att_data(AttAttributeDataChild::RawData(DATA.into())),
             })
             .view(),
         );
-        // service the first read with `data`
-        let MockDatastoreEvents::Read(TCB_IDX, _, _, tx) = data_rx_1.recv().await.unwrap() else {
-            unreachable!()
-        };
-        tx.send(Ok(DATA.to_vec())).unwrap();
-        // and then the second read with `another_data`
-        let MockDatastoreEvents::Read(ANOTHER_TCB_IDX, _, _, tx) = data_rx_2.recv().await.unwrap()
-        else {
-            unreachable!()
-        };
-        tx.send(Ok(ANOTHER_DATA.to_vec())).unwrap();
-
-        // receive both response packets
-        let (tcb_idx_1, resp_1) = transport_rx.recv().await.unwrap();
-        let (tcb_idx_2, resp_2) = transport_rx.recv().await.unwrap();
+        let event = writes.recv().await.unwrap();
+        let value = event.value.clone();
+        event.respond(Ok(())).unwrap();
 
-        // assert: the responses were routed to the correct connections
-        assert_eq!(tcb_idx_1, TCB_IDX);
-        assert_eq!(resp_1._child_.to_vec().unwrap(), DATA);
-        assert_eq!(tcb_idx_2, ANOTHER_TCB_IDX);
-        assert_eq!(resp_2._child_.to_vec().unwrap(), ANOTHER_DATA);
+        // assert
+        assert_eq!(value, DATA.to_vec());
+        let (_, resp) = transport_rx.recv().await.unwrap();
+        assert_eq!(resp._child_, AttWriteResponseBuilder {}.into());
     })
 }
 
 #[test]
-fn test_read_device_name() {
+fn test_streamed_notification() {
     start_test(async move {
-        // arrange
+        // arrange: the client subscribed
         let (mut gatt, mut transport_rx) = start_gatt_module();
-        create_server_and_open_connection(&mut gatt);
-
-        // act: try to read the device name
+        let (streams, value_handle) = create_streaming_server(&mut gatt);
+        let mut notifications = streams.notifications(value_handle);
         gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
-            build_att_view_or_crash(AttReadByTypeRequestBuilder {
+            build_att_view_or_crash(AttWriteRequestBuilder {
+                handle: AttHandle(value_handle.0 + 1).into(),
+                value: build_att_data(GattClientCharacteristicConfigurationBuilder {
+                    notification: 1,
+                    indication: 0,
+                }),
+            })
+            .view(),
+        );
+        transport_rx.recv().await.unwrap();
+
+        // act
+        notifications.notify(DATA.to_vec()).await;
+
+        // assert: the client was notified, and later reads get the same value
+        let (_, notification) = transport_rx.recv().await.unwrap();
+        assert_eq!(
+            notification._child_,
+            AttHandleValueNotificationBuilder {
+                handle: value_handle.into(),
+                value: build_att_data(AttAttributeDataChild::RawData(DATA.into())),
+            }
+            .into()
+        );
+        assert_eq!(streams.value(value_handle), DATA.to_vec());
+    })
+}
+
+#[test]
+fn test_bonded_subscription_restored_on_reconnect() {
+    start_test(async move {
+        // arrange: a bonded client that subscribed, then disconnected
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        create_server_and_open_connection(&mut gatt);
+        gatt.on_le_bonded(TCB_IDX, PEER).unwrap();
+        subscribe_to_indications(&gatt, &mut transport_rx).await;
+        gatt.on_le_disconnect(TCB_IDX).unwrap();
+
+        // act: it reconnects, and is identified as bonded
+        gatt.on_le_connect(TCB_IDX, Some(ADVERTISER_ID)).unwrap();
+        gatt.on_le_bonded(TCB_IDX, PEER).unwrap();
+        spawn_local(gatt.get_bearer(TCB_IDX).unwrap().send_indication(
+            CHARACTERISTIC_HANDLE,
+            AttAttributeDataChild::RawData([1, 2, 3, 4].into()),
+        ));
+
+        // assert: the indication was sent without resubscribing
+        let (_, resp) = transport_rx.recv().await.unwrap();
+        assert_eq!(resp.opcode, AttOpcode::HANDLE_VALUE_INDICATION);
+    })
+}
+
+#[test]
+fn test_bonded_subscription_restored_across_address_rotation() {
+    start_test(async move {
+        // arrange: a bonded client that subscribed from one address, then
+        // disconnected
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        let identity_resolver = Rc::new(MockIdentityResolver::new());
+        for rpa in PEER_RPAS {
+            identity_resolver.add_address(rpa, PEER);
+        }
+        gatt.set_identity_resolver(identity_resolver);
+        create_server_and_open_connection(&mut gatt);
+        gatt.on_le_bonded(TCB_IDX, PEER_RPAS[0]).unwrap();
+        subscribe_to_indications(&gatt, &mut transport_rx).await;
+        gatt.on_le_disconnect(TCB_IDX).unwrap();
+
+        // act: it reconnects from another address, and is identified as bonded
+        gatt.on_le_connect(TCB_IDX, Some(ADVERTISER_ID)).unwrap();
+        gatt.on_le_bonded(TCB_IDX, PEER_RPAS[1]).unwrap();
+        spawn_local(gatt.get_bearer(TCB_IDX).unwrap().send_indication(
+            CHARACTERISTIC_HANDLE,
+            AttAttributeDataChild::RawData([1, 2, 3, 4].into()),
+        ));
+
+        // assert: the indication was sent without resubscribing
+        let (_, resp) = transport_rx.recv().await.unwrap();
+        assert_eq!(resp.opcode, AttOpcode::HANDLE_VALUE_INDICATION);
+    })
+}
+
+#[test]
+fn test_bonded_subscription_restored_at_connection() {
+    start_test(async move {
+        // arrange: a bonded client that subscribed, then disconnected
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        let identity_resolver = Rc::new(MockIdentityResolver::new());
+        gatt.set_identity_resolver(identity_resolver.clone());
+        create_server_and_open_connection(&mut gatt);
+        gatt.on_le_bonded(TCB_IDX, PEER).unwrap();
+        subscribe_to_indications(&gatt, &mut transport_rx).await;
+        gatt.on_le_disconnect(TCB_IDX).unwrap();
+
+        // act: it reconnects from an address that the resolver already resolves
+        identity_resolver.set_identity(TCB_IDX, PEER);
+        gatt.on_le_connect(TCB_IDX, Some(ADVERTISER_ID)).unwrap();
+        spawn_local(gatt.get_bearer(TCB_IDX).unwrap().send_indication(
+            CHARACTERISTIC_HANDLE,
+            AttAttributeDataChild::RawData([1, 2, 3, 4].into()),
+        ));
+
+        // assert: the indication was sent before the bonding was even reported
+        let (_, resp) = transport_rx.recv().await.unwrap();
+        assert_eq!(resp.opcode, AttOpcode::HANDLE_VALUE_INDICATION);
+    })
+}
+
+#[test]
+fn test_unresolved_address_not_restored() {
+    start_test(async move {
+        // arrange: a bonded client that subscribed, then disconnected
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        gatt.set_identity_resolver(Rc::new(MockIdentityResolver::new()));
+        create_server_and_open_connection(&mut gatt);
+        gatt.on_le_bonded(TCB_IDX, PEER_RPAS[0]).unwrap();
+        subscribe_to_indications(&gatt, &mut transport_rx).await;
+        gatt.on_le_disconnect(TCB_IDX).unwrap();
+
+        // act: it reconnects from another address, which cannot be resolved
+        gatt.on_le_connect(TCB_IDX, Some(ADVERTISER_ID)).unwrap();
+        gatt.on_le_bonded(TCB_IDX, PEER_RPAS[1]).unwrap();
+        let res = gatt
+            .get_bearer(TCB_IDX)
+            .unwrap()
+            .send_indication(
+                CHARACTERISTIC_HANDLE,
+                AttAttributeDataChild::RawData([1, 2, 3, 4].into()),
+            )
+            .await;
+
+        // assert: it is treated as a different peer, which never subscribed
+        assert!(matches!(res, Err(IndicationError::ClientNotSubscribed)));
+    })
+}
+
+#[test]
+fn test_write_to_descriptor() {
+    start_test(async move {
+        // arrange
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+
+        let data = AttAttributeDataChild::RawData(DATA.into());
+
+        let mut data_rx = create_server_and_open_connection(&mut gatt);
+
+        // act
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttWriteRequestBuilder {
+                handle: DESCRIPTOR_HANDLE.into(),
+                value: build_att_data(data.clone()),
+            })
+            .view(),
+        );
+        let (tx, written_data) = if let MockDatastoreEvents::Write(
+            TCB_IDX,
+            DESCRIPTOR_HANDLE,
+            AttributeBackingType::Descriptor,
+            written_data,
+            tx,
+        ) = data_rx.recv().await.unwrap()
+        {
+            (tx, written_data)
+        } else {
+            unreachable!()
+        };
+        tx.send(Ok(())).unwrap();
+        let (tcb_idx, resp) = transport_rx.recv().await.unwrap();
+
+        // assert
+        assert_eq!(tcb_idx, TCB_IDX);
+        assert_eq!(
+            resp,
+            AttBuilder {
+                opcode: AttOpcode::WRITE_RESPONSE,
+                _child_: AttWriteResponseBuilder {}.into()
+            }
+        );
+        assert_eq!(data.to_vec().unwrap(), written_data)
+    })
+}
+
+#[test]
+fn test_multiple_servers() {
+    start_test(async move {
+        // arrange
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        // open the default server (SERVER_ID on CONN_ID)
+        let mut data_rx_1 = create_server_and_open_connection(&mut gatt);
+        // open a second server and connect to it (ANOTHER_SERVER_ID on ANOTHER_CONN_ID)
+        let (datastore, mut data_rx_2) = MockDatastore::new();
+        gatt.open_gatt_server(ANOTHER_SERVER_ID).unwrap();
+        gatt.register_gatt_service(
+            ANOTHER_SERVER_ID,
+            GattServiceWithHandle {
+                handle: SERVICE_HANDLE,
+                type_: SERVICE_TYPE,
+                characteristics: vec![GattCharacteristicWithHandle {
+                    handle: CHARACTERISTIC_HANDLE,
+                    type_: CHARACTERISTIC_TYPE,
+                    permissions: AttPermissions::READABLE,
+                    descriptors: vec![],
+                }],
+            },
+            datastore,
+        )
+        .unwrap();
+        gatt.get_isolation_manager()
+            .associate_server_with_advertiser(ANOTHER_SERVER_ID, ANOTHER_ADVERTISER_ID);
+        gatt.on_le_connect(ANOTHER_TCB_IDX, Some(ANOTHER_ADVERTISER_ID)).unwrap();
+
+        // act: read from both connections
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttReadRequestBuilder {
+                attribute_handle: CHARACTERISTIC_HANDLE.into(),
+            })
+            .view(),
+        );
+        gatt.get_bearer(ANOTHER_TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttReadRequestBuilder {
+                attribute_handle: CHARACTERISTIC_HANDLE.into(),
+            })
+            .view(),
+        );
+        // service the first read with `data`
+        let MockDatastoreEvents::Read(TCB_IDX, _, _, tx) = data_rx_1.recv().await.unwrap() else {
+            unreachable!()
+        };
+        tx.send(Ok(DATA.to_vec())).unwrap();
+        // and then the second read with `another_data`
+        let MockDatastoreEvents::Read(ANOTHER_TCB_IDX, _, _, tx) = data_rx_2.recv().await.unwrap()
+        else {
+            unreachable!()
+        };
+        tx.send(Ok(ANOTHER_DATA.to_vec())).unwrap();
+
+        // receive both response packets
+        let (tcb_idx_1, resp_1) = transport_rx.recv().await.unwrap();
+        let (tcb_idx_2, resp_2) = transport_rx.recv().await.unwrap();
+
+        // assert: the responses were routed to the correct connections
+        assert_eq!(tcb_idx_1, TCB_IDX);
+        assert_eq!(resp_1._child_.to_vec().unwrap(), DATA);
+        assert_eq!(tcb_idx_2, ANOTHER_TCB_IDX);
+        assert_eq!(resp_2._child_.to_vec().unwrap(), ANOTHER_DATA);
+    })
+}
+
+#[test]
+fn test_read_device_name() {
+    start_test(async move {
+        // arrange
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        create_server_and_open_connection(&mut gatt);
+
+        // act: try to read the device name
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttReadByTypeRequestBuilder {
+                starting_handle: AttHandle(1).into(),
+                ending_handle: AttHandle(0xFFFF).into(),
+                attribute_type: DEVICE_NAME_UUID.into(),
+            })
+            .view(),
+        );
+        let (tcb_idx, resp) = transport_rx.recv().await.unwrap();
+
+        // assert: the name should not be readable
+        assert_eq!(tcb_idx, TCB_IDX);
+        let AttChild::AttErrorResponse(resp) = resp._child_ else {
+            unreachable!("{resp:?}");
+        };
+        assert_eq!(resp.error_code, AttErrorCode::INSUFFICIENT_AUTHENTICATION);
+    });
+}
+
+struct NamedGapConfiguration;
+
+impl GapConfiguration for NamedGapConfiguration {
+    fn device_name(&self) -> Option<Vec<u8>> {
+        Some(b"device".to_vec())
+    }
+
+    fn appearance(&self) -> u16 {
+        0
+    }
+
+    fn preferred_connection_parameters(&self) -> Option<PreferredConnectionParameters> {
+        None
+    }
+
+    fn is_device_name_writable(&self) -> bool {
+        false
+    }
+
+    fn set_device_name(&self, _: Vec<u8>) {
+        unreachable!()
+    }
+}
+
+#[test]
+fn test_read_configured_device_name() {
+    start_test(async move {
+        // arrange
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        gatt.set_gap_configuration(Rc::new(NamedGapConfiguration));
+        create_server_and_open_connection(&mut gatt);
+
+        // act: read the device name
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttReadByTypeRequestBuilder {
                 starting_handle: AttHandle(1).into(),
                 ending_handle: AttHandle(0xFFFF).into(),
                 attribute_type: DEVICE_NAME_UUID.into(),
             })
             .view(),
         );
-        let (tcb_idx, resp) = transport_rx.recv().await.unwrap();
+        let (_, resp) = transport_rx.recv().await.unwrap();
+
+        // assert: the configured name was served
+        let resp = OwnedAttView::try_parse(resp.to_vec().unwrap().into_boxed_slice()).unwrap();
+        let resp = AttReadByTypeResponseView::try_parse(resp.view()).unwrap();
+        let values = resp
+            .get_data_iter()
+            .map(|element| element.get_value().get_raw_payload().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![b"device".to_vec()]);
+    });
+}
+
+struct TestDeviceInformation;
+
+impl DeviceInformationProvider for TestDeviceInformation {
+    fn manufacturer_name(&self) -> Option<String> {
+        Some("manufacturer".into())
+    }
+
+    fn model_number(&self) -> Option<String> {
+        None
+    }
+
+    fn firmware_revision(&self) -> Option<String> {
+        None
+    }
+
+    fn software_revision(&self) -> Option<String> {
+        None
+    }
+
+    fn pnp_id(&self) -> Option<PnpId> {
+        None
+    }
+}
+
+fn request_manufacturer_names(gatt: &GattModule) {
+    gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+        build_att_view_or_crash(AttReadByTypeRequestBuilder {
+            starting_handle: AttHandle(1).into(),
+            ending_handle: AttHandle(0xFFFF).into(),
+            attribute_type: MANUFACTURER_NAME_UUID.into(),
+        })
+        .view(),
+    );
+}
+
+#[test]
+fn test_read_device_information() {
+    start_test(async move {
+        // arrange
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        gatt.set_device_information_provider(Some(Rc::new(TestDeviceInformation)));
+        create_server_and_open_connection(&mut gatt);
+
+        // act: read the manufacturer name
+        request_manufacturer_names(&gatt);
+        let (_, resp) = transport_rx.recv().await.unwrap();
+
+        // assert: the supplied name was served
+        let resp = OwnedAttView::try_parse(resp.to_vec().unwrap().into_boxed_slice()).unwrap();
+        let resp = AttReadByTypeResponseView::try_parse(resp.view()).unwrap();
+        let values = resp
+            .get_data_iter()
+            .map(|element| element.get_value().get_raw_payload().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![b"manufacturer".to_vec()]);
+    });
+}
+
+#[test]
+fn test_device_information_disabled_by_default() {
+    start_test(async move {
+        // arrange
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        create_server_and_open_connection(&mut gatt);
+
+        // act: look for the manufacturer name
+        request_manufacturer_names(&gatt);
+        let (_, resp) = transport_rx.recv().await.unwrap();
+
+        // assert: the service is not included
+        let AttChild::AttErrorResponse(resp) = resp._child_ else {
+            unreachable!("{resp:?}");
+        };
+        assert_eq!(resp.error_code, AttErrorCode::ATTRIBUTE_NOT_FOUND);
+    });
+}
+
+#[test]
+fn test_read_server_supported_features_with_eatt() {
+    start_test(async move {
+        // arrange
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        gatt.set_eatt_supported(true);
+        create_server_and_open_connection(&mut gatt);
+
+        // act: read the server supported features
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttReadByTypeRequestBuilder {
+                starting_handle: AttHandle(1).into(),
+                ending_handle: AttHandle(0xFFFF).into(),
+                attribute_type: SERVER_SUPPORTED_FEATURES_UUID.into(),
+            })
+            .view(),
+        );
+        let (_, resp) = transport_rx.recv().await.unwrap();
+
+        // assert: EATT support is advertised
+        let resp = OwnedAttView::try_parse(resp.to_vec().unwrap().into_boxed_slice()).unwrap();
+        let resp = AttReadByTypeResponseView::try_parse(resp.view()).unwrap();
+        let values = resp
+            .get_data_iter()
+            .map(|element| element.get_value().get_raw_payload().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![vec![ServerSupportedFeatures::EATT_SUPPORTED.bits()]]);
+    });
+}
+
+#[test]
+fn test_ignored_service_change_indication() {
+    start_test(async move {
+        // arrange
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        create_server_and_open_connection(&mut gatt);
+
+        // act: add a new service
+        let (datastore, _) = MockDatastore::new();
+        gatt.register_gatt_service(
+            SERVER_ID,
+            GattServiceWithHandle {
+                handle: AttHandle(30),
+                type_: SERVICE_TYPE,
+                characteristics: vec![],
+            },
+            datastore,
+        )
+        .unwrap();
+
+        // assert: no packets should be sent
+        assert_eq!(transport_rx.try_recv().unwrap_err(), TryRecvError::Empty);
+    });
+}
+
+/// Discover the Service Changed characteristic of the builtin GATT service,
+/// and subscribe to its indications. Returns the handle of its value.
+async fn subscribe_to_service_changed(
+    gatt: &GattModule,
+    transport_rx: &mut UnboundedReceiver<(TransportIndex, AttBuilder)>,
+) -> AttHandle {
+    // discover the GATT server
+    gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+        build_att_view_or_crash(AttFindByTypeValueRequestBuilder {
+            starting_handle: AttHandle::MIN.into(),
+            ending_handle: AttHandle::MAX.into(),
+            attribute_type: PRIMARY_SERVICE_DECLARATION_UUID.try_into().unwrap(),
+            attribute_value: build_att_data(UuidAsAttDataBuilder {
+                uuid: GATT_SERVICE_UUID.into(),
+            }),
+        })
+        .view(),
+    );
+    let AttChild::AttFindByTypeValueResponse(resp) = transport_rx.recv().await.unwrap().1._child_
+    else {
+        unreachable!()
+    };
+    let (starting_handle, ending_handle) = (
+        resp.handles_info[0].clone().found_attribute_handle,
+        resp.handles_info[0].clone().group_end_handle,
+    );
+    // discover the service changed characteristic
+    gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+        build_att_view_or_crash(AttReadByTypeRequestBuilder {
+            starting_handle,
+            ending_handle,
+            attribute_type: CHARACTERISTIC_UUID.into(),
+        })
+        .view(),
+    );
+    let resp = transport_rx.recv().await.unwrap().1.to_vec().unwrap();
+    let resp = OwnedAttView::try_parse(resp.into_boxed_slice()).unwrap();
+    let resp = AttReadByTypeResponseView::try_parse(resp.view()).unwrap();
+    let service_change_char_handle: AttHandle = resp
+        .get_data_iter()
+        .find_map(|characteristic| {
+            let value = characteristic.get_value().get_raw_payload().collect::<Vec<_>>();
+            let decl =
+                GattCharacteristicDeclarationValueView::try_parse_from_buffer(value.as_slice())
+                    .unwrap();
+
+            if SERVICE_CHANGE_UUID == decl.get_uuid().try_into().unwrap() {
+                Some(decl.get_handle().into())
+            } else {
+                None
+            }
+        })
+        .unwrap();
+    // find the CCC descriptor for the service changed characteristic
+    gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+        build_att_view_or_crash(AttFindInformationRequestBuilder {
+            starting_handle: service_change_char_handle.into(),
+            ending_handle: AttHandle::MAX.into(),
+        })
+        .view(),
+    );
+    let AttChild::AttFindInformationResponse(resp) = transport_rx.recv().await.unwrap().1._child_
+    else {
+        unreachable!()
+    };
+    let AttFindInformationResponseChild::AttFindInformationShortResponse(resp) = resp._child_
+    else {
+        unreachable!()
+    };
+    let service_change_descriptor_handle = resp
+        .data
+        .into_vec()
+        .into_iter()
+        .find_map(|attr| {
+            if attr.uuid == CLIENT_CHARACTERISTIC_CONFIGURATION_UUID.try_into().unwrap() {
+                Some(attr.handle)
+            } else {
+                None
+            }
+        })
+        .unwrap();
+    // register for indications on this handle
+    gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+        build_att_view_or_crash(AttWriteRequestBuilder {
+            handle: service_change_descriptor_handle,
+            value: build_att_data(GattClientCharacteristicConfigurationBuilder {
+                notification: 0,
+                indication: 1,
+            }),
+        })
+        .view(),
+    );
+    let AttChild::AttWriteResponse(_) = transport_rx.recv().await.unwrap().1._child_ else {
+        unreachable!()
+    };
+    service_change_char_handle
+}
+
+#[test]
+fn test_service_change_indication() {
+    start_test(async move {
+        // arrange
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        create_server_and_open_connection(&mut gatt);
+
+        // act: discover the service changed characteristic, and subscribe to it
+        let service_change_char_handle =
+            subscribe_to_service_changed(&gatt, &mut transport_rx).await;
+        // act: add a new service
+        let (datastore, _) = MockDatastore::new();
+        gatt.register_gatt_service(
+            SERVER_ID,
+            GattServiceWithHandle {
+                handle: AttHandle(30),
+                type_: SERVICE_TYPE,
+                characteristics: vec![],
+            },
+            datastore,
+        )
+        .unwrap();
+
+        // assert: we got an indication
+        let AttChild::AttHandleValueIndication(indication) =
+            transport_rx.recv().await.unwrap().1._child_
+        else {
+            unreachable!()
+        };
+        assert_eq!(indication.handle, service_change_char_handle.into());
+        assert_eq!(
+            indication.value,
+            build_att_data(GattServiceChangedBuilder {
+                start_handle: AttHandle(30).into(),
+                end_handle: AttHandle(30).into(),
+            })
+        );
+    });
+}
+
+#[test]
+fn test_service_added_and_removed_while_connected() {
+    start_test(async move {
+        // arrange: a client subscribed to Service Changed
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        create_server_and_open_connection(&mut gatt);
+        let service_change_char_handle =
+            subscribe_to_service_changed(&gatt, &mut transport_rx).await;
+
+        // act: add a service
+        let (datastore, mut data_rx) = MockDatastore::new();
+        let token = gatt
+            .add_gatt_service(
+                SERVER_ID,
+                ServiceBuilder::new(SERVICE_TYPE).characteristic(CharacteristicBuilder::new(
+                    CHARACTERISTIC_TYPE,
+                    AttPermissions::READABLE,
+                )),
+                datastore,
+            )
+            .unwrap();
+        let service_handle = token.handle();
+        let value_handle = AttHandle(service_handle.0 + 2);
+        let (_, added_indication) = transport_rx.recv().await.unwrap();
+        gatt.get_bearer(TCB_IDX)
+            .unwrap()
+            .handle_packet(build_att_view_or_crash(AttHandleValueConfirmationBuilder {}).view());
+
+        // act: read the new characteristic, and remove the service before the read
+        // completes
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttReadRequestBuilder {
+                attribute_handle: value_handle.into(),
+            })
+            .view(),
+        );
+        let MockDatastoreEvents::Read(TCB_IDX, handle, _, data_resp) =
+            data_rx.recv().await.unwrap()
+        else {
+            unreachable!()
+        };
+        gatt.remove_gatt_service(SERVER_ID, token).unwrap();
+        data_resp.send(Ok(DATA.into())).unwrap();
+        let replies = [
+            transport_rx.recv().await.unwrap().1._child_,
+            transport_rx.recv().await.unwrap().1._child_,
+        ];
+
+        // assert: the client was told of both changes, and the read failed
+        let service_changed = AttHandleValueIndicationBuilder {
+            handle: service_change_char_handle.into(),
+            value: build_att_data(GattServiceChangedBuilder {
+                start_handle: service_handle.into(),
+                end_handle: value_handle.into(),
+            }),
+        };
+        assert_eq!(handle, value_handle);
+        assert_eq!(added_indication._child_, service_changed.clone().into());
+        assert!(replies.contains(
+            &AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::READ_REQUEST,
+                handle_in_error: value_handle.into(),
+                error_code: AttErrorCode::INVALID_HANDLE,
+            }
+            .into()
+        ));
+        assert!(replies.contains(&service_changed.into()));
+    });
+}
+
+#[test]
+fn test_removed_handles_invalid_until_service_change_confirmed() {
+    start_test(async move {
+        // arrange: a client subscribed to Service Changed, and a service it learned of
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        create_server_and_open_connection(&mut gatt);
+        subscribe_to_service_changed(&gatt, &mut transport_rx).await;
+        let service = || {
+            ServiceBuilder::new(SERVICE_TYPE).characteristic(CharacteristicBuilder::new(
+                CHARACTERISTIC_TYPE,
+                AttPermissions::READABLE,
+            ))
+        };
+        let (datastore, _data_rx) = MockDatastore::new();
+        let token = gatt.add_gatt_service(SERVER_ID, service(), datastore).unwrap();
+        let value_handle = AttHandle(token.handle().0 + 2);
+        transport_rx.recv().await.unwrap();
+        let confirm = |gatt: &GattModule| {
+            gatt.get_bearer(TCB_IDX)
+                .unwrap()
+                .handle_packet(build_att_view_or_crash(AttHandleValueConfirmationBuilder {}).view())
+        };
+        confirm(&gatt);
+
+        // act: the service is replaced by one at the same handles, and the client reads
+        // the value before confirming the removal
+        gatt.remove_gatt_service(SERVER_ID, token).unwrap();
+        transport_rx.recv().await.unwrap();
+        let (datastore, mut data_rx) = MockDatastore::new();
+        let replacement = gatt.add_gatt_service(SERVER_ID, service(), datastore).unwrap();
+        let read = build_att_view_or_crash(AttReadRequestBuilder {
+            attribute_handle: value_handle.into(),
+        });
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(read.view());
+        let (_, stale_reply) = transport_rx.recv().await.unwrap();
+
+        // assert: the read failed without reaching the replacement
+        assert_eq!(replacement.handle(), AttHandle(value_handle.0 - 2));
+        assert_eq!(
+            stale_reply._child_,
+            AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::READ_REQUEST,
+                handle_in_error: value_handle.into(),
+                error_code: AttErrorCode::INVALID_HANDLE,
+            }
+            .into()
+        );
+        assert_eq!(data_rx.try_recv().unwrap_err(), TryRecvError::Empty);
+
+        // act: the client confirms the removal, and reads again
+        confirm(&gatt);
+        transport_rx.recv().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(read.view());
+
+        // assert: the read reached the replacement
+        assert!(matches!(
+            data_rx.recv().await.unwrap(),
+            MockDatastoreEvents::Read(TCB_IDX, handle, _, _) if handle == value_handle
+        ));
+    });
+}
+
+#[test]
+fn test_reliable_write_characteristic() {
+    start_test(async move {
+        // arrange: a service with a characteristic supporting reliable writes
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        create_server_and_open_connection(&mut gatt);
+        let (datastore, _data_rx) = MockDatastore::new();
+        let token = gatt
+            .add_gatt_service(
+                SERVER_ID,
+                ServiceBuilder::new(SERVICE_TYPE).characteristic(
+                    CharacteristicBuilder::new(
+                        CHARACTERISTIC_TYPE,
+                        AttPermissions::WRITABLE_WITH_RESPONSE,
+                    )
+                    .reliable_write(),
+                ),
+                datastore,
+            )
+            .unwrap();
+        let value_handle = AttHandle(token.handle().0 + 2);
+        let extended_properties_handle = AttHandle(token.handle().0 + 3);
+
+        // act: read the extended properties, and prepare a write
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttReadRequestBuilder {
+                attribute_handle: extended_properties_handle.into(),
+            })
+            .view(),
+        );
+        let (_, extended_properties) = transport_rx.recv().await.unwrap();
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttPrepareWriteRequestBuilder {
+                handle: value_handle.into(),
+                offset: 1,
+                value: build_att_data(AttAttributeDataChild::RawData(DATA.into())),
+            })
+            .view(),
+        );
+        let (_, echo) = transport_rx.recv().await.unwrap();
+
+        // assert: reliable writes are advertised, and the queued write is echoed
+        // back byte-exact
+        assert_eq!(
+            extended_properties._child_,
+            AttReadResponseBuilder {
+                value: build_att_data(AttAttributeDataChild::RawData([1, 0].into()))
+            }
+            .into()
+        );
+        assert_eq!(
+            echo._child_,
+            AttPrepareWriteResponseBuilder {
+                handle: value_handle.into(),
+                offset: 1,
+                value: build_att_data(AttAttributeDataChild::RawData(DATA.into())),
+            }
+            .into()
+        );
+    });
+}
+
+#[test]
+fn test_app_unregistration_removes_its_services() {
+    start_test(async move {
+        // arrange: two apps sharing a server, one of which owns two services
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        create_server_and_open_connection(&mut gatt);
+        let service_change_char_handle =
+            subscribe_to_service_changed(&gatt, &mut transport_rx).await;
+        let (datastore, _data_rx) = MockDatastore::new();
+        let (another_datastore, mut another_data_rx) = MockDatastore::new();
+        gatt.register_gatt_app(SERVER_ID, AppId(1), datastore).unwrap();
+        gatt.register_gatt_app(SERVER_ID, AppId(2), another_datastore).unwrap();
+        let service = || {
+            ServiceBuilder::new(SERVICE_TYPE).characteristic(CharacteristicBuilder::new(
+                CHARACTERISTIC_TYPE,
+                AttPermissions::READABLE,
+            ))
+        };
+        let first = gatt.add_gatt_app_service(SERVER_ID, AppId(1), service()).unwrap();
+        let other = gatt.add_gatt_app_service(SERVER_ID, AppId(2), service()).unwrap();
+        let second = gatt.add_gatt_app_service(SERVER_ID, AppId(1), service()).unwrap();
+        for _ in 0..3 {
+            transport_rx.recv().await.unwrap();
+            gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+                build_att_view_or_crash(AttHandleValueConfirmationBuilder {}).view(),
+            );
+        }
+
+        // act: the first app dies, and the client reads the service of the other
+        gatt.unregister_gatt_app(SERVER_ID, AppId(1)).unwrap();
+        let (_, removed_indication) = transport_rx.recv().await.unwrap();
+        gatt.get_bearer(TCB_IDX)
+            .unwrap()
+            .handle_packet(build_att_view_or_crash(AttHandleValueConfirmationBuilder {}).view());
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttReadRequestBuilder {
+                attribute_handle: AttHandle(other.0 + 2).into(),
+            })
+            .view(),
+        );
+        let MockDatastoreEvents::Read(TCB_IDX, handle, _, data_resp) =
+            another_data_rx.recv().await.unwrap()
+        else {
+            unreachable!()
+        };
+        data_resp.send(Ok(DATA.into())).unwrap();
+        let (_, read_resp) = transport_rx.recv().await.unwrap();
+
+        // assert: the client was told of a single change covering both services,
+        // and the other app still serves its own
+        assert_eq!(
+            removed_indication._child_,
+            AttHandleValueIndicationBuilder {
+                handle: service_change_char_handle.into(),
+                value: build_att_data(GattServiceChangedBuilder {
+                    start_handle: first.into(),
+                    end_handle: AttHandle(second.0 + 2).into(),
+                }),
+            }
+            .into()
+        );
+        assert_eq!(handle, AttHandle(other.0 + 2));
+        assert_eq!(
+            read_resp._child_,
+            AttReadResponseBuilder {
+                value: build_att_data(AttAttributeDataChild::RawData(DATA.into()))
+            }
+            .into()
+        );
+        assert!(gatt.remove_gatt_app_service(SERVER_ID, AppId(1), first).is_err());
+        assert!(gatt.unregister_gatt_service(SERVER_ID, other).is_err());
+    });
+}
+
+#[test]
+fn test_closing_gatt_server_unisolates_advertiser() {
+    start_test(async move {
+        // arrange
+        let (mut gatt, _) = start_gatt_module();
+        gatt.open_gatt_server(SERVER_ID).unwrap();
+        gatt.get_isolation_manager().associate_server_with_advertiser(SERVER_ID, ADVERTISER_ID);
+
+        // act
+        gatt.close_gatt_server(SERVER_ID).unwrap();
+
+        // assert
+        let is_advertiser_isolated =
+            gatt.get_isolation_manager().is_advertiser_isolated(ADVERTISER_ID);
+        assert!(!is_advertiser_isolated);
+    });
+}
+
+#[test]
+fn test_disconnection_unisolates_connection() {
+    start_test(async move {
+        // arrange
+        let (mut gatt, _) = start_gatt_module();
+        create_server_and_open_connection(&mut gatt);
+
+        // act
+        gatt.on_le_disconnect(TCB_IDX).unwrap();
+
+        // assert
+        let is_connection_isolated = gatt.get_isolation_manager().is_connection_isolated(TCB_IDX);
+        assert!(!is_connection_isolated);
+    });
+}
+
+/// A datastore that never answers reads, counting the reads and the
+/// disconnections of its clients
+#[derive(Default)]
+struct UnresponsiveDatastore {
+    reads: Rc<Cell<usize>>,
+    disconnections: Rc<Cell<usize>>,
+}
+
+#[async_trait(?Send)]
+impl GattDatastore for UnresponsiveDatastore {
+    async fn read(
+        &self,
+        _: TransportIndex,
+        _: AttHandle,
+        _: AttributeBackingType,
+    ) -> Result<Vec<u8>, AttErrorCode> {
+        self.reads.set(self.reads.get() + 1);
+        pending().await
+    }
+
+    async fn write(
+        &self,
+        _: TransportIndex,
+        _: AttHandle,
+        _: AttributeBackingType,
+        _: &[u8],
+    ) -> Result<(), AttErrorCode> {
+        Ok(())
+    }
+
+    fn on_peer_disconnected(&self, _: TransportIndex) {
+        self.disconnections.set(self.disconnections.get() + 1);
+    }
+}
+
+#[test]
+fn test_repeated_connections_release_their_state() {
+    start_test(async move {
+        // arrange: a service with a notifying characteristic, whose reads never complete
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        let backpressure = Rc::new(GatedBackpressure::default());
+        gatt.set_backpressure(backpressure.clone());
+        create_server(&mut gatt);
+        let datastore = UnresponsiveDatastore::default();
+        let reads = datastore.reads.clone();
+        let disconnections = datastore.disconnections.clone();
+        let token = gatt
+            .add_gatt_service(
+                SERVER_ID,
+                ServiceBuilder::new(SERVICE_TYPE).characteristic(CharacteristicBuilder::new(
+                    CHARACTERISTIC_TYPE,
+                    AttPermissions::READABLE
+                        | AttPermissions::WRITABLE_WITH_RESPONSE
+                        | AttPermissions::NOTIFY,
+                )),
+                datastore,
+            )
+            .unwrap();
+        let value_handle = AttHandle(token.handle().0 + 2);
+        gatt.get_isolation_manager().associate_server_with_advertiser(SERVER_ID, ADVERTISER_ID);
+
+        // act: the client connects repeatedly, each time subscribing, preparing a
+        // write, and disconnecting with a read in flight
+        let mut notified_on_connect = vec![];
+        for _ in 0..3 {
+            gatt.on_le_connect(TCB_IDX, Some(ADVERTISER_ID)).unwrap();
+            notified_on_connect
+                .push(gatt.notify_all(SERVER_ID, value_handle, &DATA).unwrap().await.len());
+            let bearer = gatt.get_bearer(TCB_IDX).unwrap();
+            bearer.handle_packet(
+                build_att_view_or_crash(AttWriteRequestBuilder {
+                    handle: AttHandle(value_handle.0 + 1).into(),
+                    value: build_att_data(GattClientCharacteristicConfigurationBuilder {
+                        notification: 1,
+                        indication: 0,
+                    }),
+                })
+                .view(),
+            );
+            transport_rx.recv().await.unwrap();
+            bearer.handle_packet(
+                build_att_view_or_crash(AttPrepareWriteRequestBuilder {
+                    handle: value_handle.into(),
+                    offset: 0,
+                    value: build_att_data(AttAttributeDataChild::RawData(DATA.into())),
+                })
+                .view(),
+            );
+            transport_rx.recv().await.unwrap();
+            bearer.handle_packet(
+                build_att_view_or_crash(AttReadRequestBuilder {
+                    attribute_handle: value_handle.into(),
+                })
+                .view(),
+            );
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            gatt.on_le_disconnect(TCB_IDX).unwrap();
+        }
+
+        // assert: every connection started out unsubscribed, the service and the
+        // transport were told of each disconnection, and nothing is left behind
+        assert_eq!(notified_on_connect, vec![0, 0, 0]);
+        assert_eq!(reads.get(), 3);
+        assert_eq!(disconnections.get(), 3);
+        assert_eq!(*backpressure.closed.borrow(), vec![BearerId::unenhanced(TCB_IDX); 3]);
+        assert!(!gatt.has_connection_state(TCB_IDX));
+        assert_eq!(transport_rx.try_recv().unwrap_err(), TryRecvError::Empty);
+    });
+}
+
+#[test]
+fn test_eatt_bearer_transactions_are_concurrent() {
+    start_test(async move {
+        // arrange: a connection with an EATT bearer
+        let (mut gatt, mut transport_rx, mut eatt_rx) = start_gatt_module_with_eatt();
+        let mut data_rx = create_server_and_open_connection(&mut gatt);
+        gatt.on_eatt_bearer_open(TCB_IDX, EATT_CID, EATT_MTU).unwrap();
+
+        // act: start a read on the unenhanced bearer, and then another on the EATT bearer
+        // before the first one completes
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttReadRequestBuilder {
+                attribute_handle: CHARACTERISTIC_HANDLE.into(),
+            })
+            .view(),
+        );
+        let MockDatastoreEvents::Read(TCB_IDX, CHARACTERISTIC_HANDLE, _, first_tx) =
+            data_rx.recv().await.unwrap()
+        else {
+            unreachable!()
+        };
+        gatt.get_eatt_bearer(TCB_IDX, EATT_CID).unwrap().handle_packet(
+            build_att_view_or_crash(AttReadRequestBuilder {
+                attribute_handle: DESCRIPTOR_HANDLE.into(),
+            })
+            .view(),
+        );
+        let MockDatastoreEvents::Read(TCB_IDX, DESCRIPTOR_HANDLE, _, second_tx) =
+            data_rx.recv().await.unwrap()
+        else {
+            unreachable!()
+        };
+        // complete them in the opposite order
+        second_tx.send(Ok(ANOTHER_DATA.to_vec())).unwrap();
+        let (tcb_idx, cid, eatt_resp) = eatt_rx.recv().await.unwrap();
+        first_tx.send(Ok(DATA.to_vec())).unwrap();
+        let (_, resp) = transport_rx.recv().await.unwrap();
+
+        // assert: each response was sent on the bearer of its request
+        assert_eq!((tcb_idx, cid), (TCB_IDX, EATT_CID));
+        assert_eq!(
+            eatt_resp,
+            AttBuilder {
+                opcode: AttOpcode::READ_RESPONSE,
+                _child_: AttReadResponseBuilder {
+                    value: build_att_data(AttAttributeDataChild::RawData(ANOTHER_DATA.into())),
+                }
+                .into()
+            }
+        );
+        assert_eq!(
+            resp,
+            AttBuilder {
+                opcode: AttOpcode::READ_RESPONSE,
+                _child_: AttReadResponseBuilder {
+                    value: build_att_data(AttAttributeDataChild::RawData(DATA.into())),
+                }
+                .into()
+            }
+        );
+    });
+}
+
+#[test]
+fn test_eatt_bearer_closed() {
+    start_test(async move {
+        // arrange: a connection with an EATT bearer
+        let (mut gatt, _, _) = start_gatt_module_with_eatt();
+        create_server_and_open_connection(&mut gatt);
+        gatt.on_eatt_bearer_open(TCB_IDX, EATT_CID, EATT_MTU).unwrap();
+
+        // act: close the EATT bearer
+        gatt.on_eatt_bearer_close(TCB_IDX, EATT_CID).unwrap();
+
+        // assert: the EATT bearer is gone, but the unenhanced bearer remains
+        assert!(gatt.get_eatt_bearer(TCB_IDX, EATT_CID).is_none());
+        assert!(gatt.get_bearer(TCB_IDX).is_some());
+    });
+}
+
+#[test]
+fn test_eatt_bearers_dropped_on_disconnect() {
+    start_test(async move {
+        // arrange: a connection with an EATT bearer
+        let (mut gatt, _, _) = start_gatt_module_with_eatt();
+        create_server_and_open_connection(&mut gatt);
+        gatt.on_eatt_bearer_open(TCB_IDX, EATT_CID, EATT_MTU).unwrap();
+
+        // act: disconnect
+        gatt.on_le_disconnect(TCB_IDX).unwrap();
+
+        // assert: the EATT bearer is gone
+        assert!(gatt.get_eatt_bearer(TCB_IDX, EATT_CID).is_none());
+    });
+}
+
+#[test]
+fn test_eatt_bearer_without_connection() {
+    start_test(async move {
+        // arrange: an open server, with no connection
+        let (mut gatt, _, _) = start_gatt_module_with_eatt();
+        gatt.open_gatt_server(SERVER_ID).unwrap();
+
+        // act: an EATT bearer is opened
+        let res = gatt.on_eatt_bearer_open(TCB_IDX, EATT_CID, EATT_MTU);
+
+        // assert: it was rejected
+        assert!(res.is_err());
+    });
+}
+
+#[test]
+fn test_eatt_bearer_rejected_when_disabled() {
+    start_test(async move {
+        // arrange: a connection to a server with EATT disabled
+        let (mut gatt, _, _) = start_gatt_module_with_eatt();
+        gatt.set_config(GattServerConfig { eatt_enabled: false, ..Default::default() }).unwrap();
+        create_server_and_open_connection(&mut gatt);
+
+        // act: an EATT bearer is opened
+        let res = gatt.on_eatt_bearer_open(TCB_IDX, EATT_CID, EATT_MTU);
+
+        // assert: it was rejected
+        assert!(res.is_err());
+        assert!(gatt.get_eatt_bearer(TCB_IDX, EATT_CID).is_none());
+    });
+}
+
+#[test]
+fn test_eatt_bearers_limited_per_connection() {
+    start_test(async move {
+        // arrange: a connection to a server allowing a single EATT bearer, which
+        // is open
+        let (mut gatt, _, _) = start_gatt_module_with_eatt();
+        gatt.set_config(GattServerConfig { max_eatt_bearers: 1, ..Default::default() }).unwrap();
+        create_server_and_open_connection(&mut gatt);
+        gatt.on_eatt_bearer_open(TCB_IDX, EATT_CID, EATT_MTU).unwrap();
+
+        // act: another EATT bearer is opened
+        let res = gatt.on_eatt_bearer_open(TCB_IDX, EattCid(EATT_CID.0 + 1), EATT_MTU);
+
+        // assert: it was rejected, but the first one remains
+        assert!(res.is_err());
+        assert!(gatt.get_eatt_bearer(TCB_IDX, EATT_CID).is_some());
+    });
+}
+
+#[test]
+fn test_mtu_exchange_with_configured_server_rx_mtu() {
+    start_test(async move {
+        // arrange: a server with a reduced RX MTU
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        gatt.set_server_rx_mtu(100).unwrap();
+        create_server_and_open_connection(&mut gatt);
+
+        // act: the client exchanges the MTU
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttExchangeMtuRequestBuilder { mtu: 200 }).view(),
+        );
+        let (_, resp) = transport_rx.recv().await.unwrap();
+
+        // assert: the configured RX MTU was offered, and is now in use
+        assert_eq!(
+            resp,
+            AttBuilder {
+                opcode: AttOpcode::EXCHANGE_MTU_RESPONSE,
+                _child_: AttExchangeMtuResponseBuilder { mtu: 100 }.into()
+            }
+        );
+        assert_eq!(gatt.get_mtu(TCB_IDX), Some(100));
+    });
+}
+
+#[test]
+fn test_mtu_capped_for_peer_with_quirk() {
+    start_test(async move {
+        // arrange: a peer known by name to mishandle large MTUs
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        let mut registry = InteropRegistry::new();
+        registry.register(PeerPattern::NamePrefix("Car Kit".into()), Quirk::MaxMtu(185));
+        let peers = Rc::new(MockPeerInfoProvider::new());
+        peers.set_peer_info(TCB_IDX, PeerInfo { address: None, name: Some("Car Kit 2000".into()) });
+        gatt.set_interop(Rc::new(registry), peers);
+        create_server_and_open_connection(&mut gatt);
+
+        // act: the client exchanges the largest MTU
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttExchangeMtuRequestBuilder { mtu: 517 }).view(),
+        );
+        let (_, resp) = transport_rx.recv().await.unwrap();
+
+        // assert: the capped MTU was offered, and is now in use
+        assert_eq!(
+            resp,
+            AttBuilder {
+                opcode: AttOpcode::EXCHANGE_MTU_RESPONSE,
+                _child_: AttExchangeMtuResponseBuilder { mtu: 185 }.into()
+            }
+        );
+        assert_eq!(gatt.get_mtu(TCB_IDX), Some(185));
+    });
+}
+
+#[test]
+fn test_indications_capped_for_bonded_peer_with_quirk() {
+    start_test(async move {
+        // arrange: a bonded peer, known by its identity address to drop long
+        // indications
+        let (mut gatt, _transport_rx) = start_gatt_module();
+        let mut registry = InteropRegistry::new();
+        registry.register(PeerPattern::Address(PEER.address), Quirk::MaxNotificationPayload(2));
+        gatt.set_interop(Rc::new(registry), Rc::new(MockPeerInfoProvider::new()));
+        let identity_resolver = Rc::new(MockIdentityResolver::new());
+        identity_resolver.set_identity(TCB_IDX, PEER);
+        gatt.set_identity_resolver(identity_resolver);
+        create_server_and_open_connection(&mut gatt);
+
+        // act
+        let result = gatt
+            .get_bearer(TCB_IDX)
+            .unwrap()
+            .send_indication(CHARACTERISTIC_HANDLE, AttAttributeDataChild::RawData(DATA.into()))
+            .await;
+
+        // assert: the value was rejected, and apps are told to keep theirs short
+        assert!(matches!(result, Err(IndicationError::DataExceedsMtu { mtu: 2 })));
+        assert_eq!(gatt.max_notification_payload(ConnectionId::new(TCB_IDX, SERVER_ID)), Some(2));
+    });
+}
+
+#[test]
+fn test_peer_without_quirks_unaffected() {
+    start_test(async move {
+        // arrange: quirks registered for another peer
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        let mut registry = InteropRegistry::new();
+        registry.register(PeerPattern::NamePrefix("Car Kit".into()), Quirk::MaxMtu(185));
+        gatt.set_interop(Rc::new(registry), Rc::new(MockPeerInfoProvider::new()));
+        create_server_and_open_connection(&mut gatt);
+
+        // act
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttExchangeMtuRequestBuilder { mtu: 517 }).view(),
+        );
+        let (_, resp) = transport_rx.recv().await.unwrap();
+
+        // assert
+        assert_eq!(
+            resp,
+            AttBuilder {
+                opcode: AttOpcode::EXCHANGE_MTU_RESPONSE,
+                _child_: AttExchangeMtuResponseBuilder { mtu: 517 }.into()
+            }
+        );
+    });
+}
+
+#[test]
+fn test_invalid_server_rx_mtu() {
+    let (mut gatt, _) = start_gatt_module();
+
+    assert!(gatt.set_server_rx_mtu(22).is_err());
+    assert!(gatt.set_server_rx_mtu(518).is_err());
+}
+
+#[test]
+fn test_write_exceeding_configured_max_attribute_length() {
+    start_test(async move {
+        // arrange: a server accepting values shorter than DATA
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        gatt.set_config(GattServerConfig {
+            max_attribute_length: DATA.len() - 1,
+            ..Default::default()
+        })
+        .unwrap();
+        let mut data_rx = create_server_and_open_connection(&mut gatt);
+
+        // act
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttWriteRequestBuilder {
+                handle: CHARACTERISTIC_HANDLE.into(),
+                value: build_att_data(AttAttributeDataChild::RawData(DATA.into())),
+            })
+            .view(),
+        );
+        let (_, resp) = transport_rx.recv().await.unwrap();
+
+        // assert: the write was rejected without reaching the datastore
+        assert_eq!(
+            resp,
+            AttBuilder {
+                opcode: AttOpcode::ERROR_RESPONSE,
+                _child_: AttErrorResponseBuilder {
+                    opcode_in_error: AttOpcode::WRITE_REQUEST,
+                    handle_in_error: CHARACTERISTIC_HANDLE.into(),
+                    error_code: AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH,
+                }
+                .into()
+            }
+        );
+        assert_eq!(data_rx.try_recv().unwrap_err(), TryRecvError::Empty);
+    });
+}
+
+#[test]
+fn test_invalid_config() {
+    let (mut gatt, _) = start_gatt_module();
+
+    assert!(gatt
+        .set_config(GattServerConfig { max_attribute_length: 0, ..Default::default() })
+        .is_err());
+    assert!(gatt
+        .set_config(GattServerConfig { max_attribute_length: 513, ..Default::default() })
+        .is_err());
+}
+
+#[test]
+fn test_hostile_client_cannot_exhaust_prepare_queue() {
+    start_test(async move {
+        // arrange: several connections to a server with a small prepare queue
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        gatt.set_config(GattServerConfig { max_prepared_writes: 8, ..Default::default() }).unwrap();
+        let _data_rx = create_server_and_open_connections(&mut gatt);
+        let [hostile, other, ..] = CONNECTIONS;
+
+        // act: one client prepares thousands of fragments, and another then
+        // prepares one of its own
+        let mut rejected = 0;
+        for offset in 0..2000 {
+            prepare_write(&gatt, hostile, offset, &[1]);
+            let (_, resp) = transport_rx.recv().await.unwrap();
+            if resp.opcode == AttOpcode::ERROR_RESPONSE {
+                assert_eq!(
+                    resp._child_,
+                    AttErrorResponseBuilder {
+                        opcode_in_error: AttOpcode::PREPARE_WRITE_REQUEST,
+                        handle_in_error: CHARACTERISTIC_HANDLE.into(),
+                        error_code: AttErrorCode::PREPARE_QUEUE_FULL,
+                    }
+                    .into()
+                );
+                rejected += 1;
+            }
+        }
+        prepare_write(&gatt, other, 0, &DATA);
+        let (_, other_resp) = transport_rx.recv().await.unwrap();
+
+        // assert: only the first fragments of the hostile client were queued, and
+        // the other connection has a queue of its own
+        assert_eq!(rejected, 2000 - 8);
+        assert_eq!(other_resp.opcode, AttOpcode::PREPARE_WRITE_RESPONSE);
+    });
+}
+
+#[test]
+fn test_read_fails_after_configured_request_timeout() {
+    start_test(async move {
+        // arrange: a server whose datastore never supplies attribute values
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        gatt.set_request_timeout(Duration::from_secs(5)).unwrap();
+        let mut data_rx = create_server_and_open_connection(&mut gatt);
+        let start = tokio::time::Instant::now();
+
+        // act: read the characteristic
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttReadRequestBuilder {
+                attribute_handle: CHARACTERISTIC_HANDLE.into(),
+            })
+            .view(),
+        );
+        let _pending = data_rx.recv().await.unwrap();
+        let (tcb_idx, resp) = transport_rx.recv().await.unwrap();
+
+        // assert: the read failed once the configured timeout elapsed
+        assert_eq!(tcb_idx, TCB_IDX);
+        assert!(start.elapsed() >= Duration::from_secs(5));
+        assert!(start.elapsed() < DEFAULT_REQUEST_TIMEOUT);
+        assert_eq!(
+            resp,
+            AttBuilder {
+                opcode: AttOpcode::ERROR_RESPONSE,
+                _child_: AttErrorResponseBuilder {
+                    opcode_in_error: AttOpcode::READ_REQUEST,
+                    handle_in_error: CHARACTERISTIC_HANDLE.into(),
+                    error_code: AttErrorCode::UNLIKELY_ERROR,
+                }
+                .into()
+            }
+        );
+    });
+}
+
+#[test]
+fn test_request_timeout_on_virtual_clock() {
+    start_test(async move {
+        // arrange: a server on a virtual clock, whose datastore never supplies
+        // attribute values
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        let clock = VirtualClock::new();
+        gatt.set_clock(Rc::new(clock.clone()));
+        let mut data_rx = create_server_and_open_connection(&mut gatt);
+
+        // act: read the characteristic, and advance the clock up to the request timeout
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttReadRequestBuilder {
+                attribute_handle: CHARACTERISTIC_HANDLE.into(),
+            })
+            .view(),
+        );
+        let _pending = data_rx.recv().await.unwrap();
+        clock.advance(DEFAULT_REQUEST_TIMEOUT - Duration::from_millis(1)).await;
+        let replied_early = transport_rx.try_recv().is_ok();
+        clock.advance(Duration::from_millis(1)).await;
+
+        // assert: the read only failed once the virtual clock reached the timeout
+        assert!(!replied_early);
+        let (_, resp) = transport_rx.recv().await.unwrap();
+        assert_eq!(resp.opcode, AttOpcode::ERROR_RESPONSE);
+    });
+}
+
+/// Counts the tasks spawned through it, before handing them to tokio
+#[derive(Default)]
+struct CountingExecutor(RefCell<usize>);
+
+impl Executor for CountingExecutor {
+    fn spawn(&self, task: Task) -> TaskHandle {
+        *self.0.borrow_mut() += 1;
+        TokioExecutor.spawn(task)
+    }
+}
+
+#[test]
+fn test_transactions_spawned_on_executor() {
+    start_test(async move {
+        // arrange: a server whose tasks run on a custom executor
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        let executor = Rc::new(CountingExecutor::default());
+        gatt.set_executor(executor.clone());
+        let mut data_rx = create_server_and_open_connection(&mut gatt);
+
+        // act: read the characteristic
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttReadRequestBuilder {
+                attribute_handle: CHARACTERISTIC_HANDLE.into(),
+            })
+            .view(),
+        );
+        let MockDatastoreEvents::Read(_, _, _, data_resp) = data_rx.recv().await.unwrap() else {
+            unreachable!()
+        };
+        data_resp.send(Ok(DATA.to_vec())).unwrap();
+        let (_, resp) = transport_rx.recv().await.unwrap();
+
+        // assert: the transaction ran on the executor
+        assert_eq!(resp.opcode, AttOpcode::READ_RESPONSE);
+        assert_eq!(*executor.0.borrow(), 1);
+    });
+}
+
+#[test]
+fn test_invalid_request_timeout() {
+    let (mut gatt, _) = start_gatt_module();
+
+    assert!(gatt.set_request_timeout(Duration::ZERO).is_err());
+}
+
+#[test]
+fn test_stalled_transaction_closes_bearer() {
+    start_test(async move {
+        // arrange: a server whose datastore never supplies attribute values, with a
+        // request timeout longer than the ATT transaction timeout
+        let (transport, mut transport_rx, _) = MockAttTransport::new();
+        let transport = Rc::new(transport);
+        let mut gatt = GattModule::new(
+            transport.clone(),
+            Rc::new(MockSecurityManager::new()),
+            Arc::new(Mutex::new(IsolationManager::new())),
+        );
+        gatt.set_request_timeout(ATT_TRANSACTION_TIMEOUT * 2).unwrap();
+        let mut data_rx = create_server_and_open_connection(&mut gatt);
+
+        // act: read the characteristic
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttReadRequestBuilder {
+                attribute_handle: CHARACTERISTIC_HANDLE.into(),
+            })
+            .view(),
+        );
+        let _pending = data_rx.recv().await.unwrap();
+        tokio::time::sleep(ATT_TRANSACTION_TIMEOUT + Duration::from_millis(1)).await;
+
+        // assert: the bearer was closed without replying
+        assert_eq!(
+            transport.take_closed_bearers(),
+            vec![TransactionTimeoutEvent {
+                tcb_idx: TCB_IDX,
+                cid: None,
+                opcode: AttOpcode::READ_REQUEST
+            }]
+        );
+        assert_eq!(transport_rx.try_recv(), Err(TryRecvError::Empty));
+    });
+}
+
+#[test]
+fn test_stalled_transaction_closes_only_its_eatt_bearer() {
+    start_test(async move {
+        // arrange: as above, with an EATT bearer
+        let (transport, _transport_rx, mut eatt_rx) = MockAttTransport::new();
+        let transport = Rc::new(transport);
+        let mut gatt = GattModule::new(
+            transport.clone(),
+            Rc::new(MockSecurityManager::new()),
+            Arc::new(Mutex::new(IsolationManager::new())),
+        );
+        gatt.set_request_timeout(ATT_TRANSACTION_TIMEOUT * 2).unwrap();
+        let mut data_rx = create_server_and_open_connection(&mut gatt);
+        gatt.on_eatt_bearer_open(TCB_IDX, EATT_CID, EATT_MTU).unwrap();
+
+        // act: read the characteristic on the EATT bearer
+        gatt.get_eatt_bearer(TCB_IDX, EATT_CID).unwrap().handle_packet(
+            build_att_view_or_crash(AttReadRequestBuilder {
+                attribute_handle: CHARACTERISTIC_HANDLE.into(),
+            })
+            .view(),
+        );
+        let _pending = data_rx.recv().await.unwrap();
+        tokio::time::sleep(ATT_TRANSACTION_TIMEOUT + Duration::from_millis(1)).await;
+
+        // assert: only the EATT bearer was closed
+        assert_eq!(
+            transport.take_closed_bearers(),
+            vec![TransactionTimeoutEvent {
+                tcb_idx: TCB_IDX,
+                cid: Some(EATT_CID),
+                opcode: AttOpcode::READ_REQUEST
+            }]
+        );
+        assert_eq!(eatt_rx.try_recv(), Err(TryRecvError::Empty));
+        assert!(gatt.get_eatt_bearer(TCB_IDX, EATT_CID).unwrap().is_closed());
+        assert!(!gatt.get_bearer(TCB_IDX).unwrap().is_closed());
+    });
+}
+
+// several connections from different peers to the same server
+const CONNECTIONS: [TransportIndex; 3] = [TCB_IDX, ANOTHER_TCB_IDX, TransportIndex(5)];
+
+fn create_server_and_open_connections(
+    gatt: &mut GattModule,
+) -> UnboundedReceiver<MockDatastoreEvents> {
+    let data_rx = create_server_and_open_connection(gatt);
+    for tcb_idx in &CONNECTIONS[1..] {
+        gatt.on_le_connect(*tcb_idx, Some(ADVERTISER_ID)).unwrap();
+    }
+    data_rx
+}
+
+fn prepare_write(gatt: &GattModule, tcb_idx: TransportIndex, offset: u16, value: &[u8]) {
+    gatt.get_bearer(tcb_idx).unwrap().handle_packet(
+        build_att_view_or_crash(AttPrepareWriteRequestBuilder {
+            handle: CHARACTERISTIC_HANDLE.into(),
+            offset,
+            value: build_att_data(AttAttributeDataChild::RawData(value.into())),
+        })
+        .view(),
+    );
+}
+
+fn execute_write(gatt: &GattModule, tcb_idx: TransportIndex) {
+    gatt.get_bearer(tcb_idx).unwrap().handle_packet(
+        build_att_view_or_crash(AttExecuteWriteRequestBuilder {
+            flags: AttExecuteWriteFlags::EXECUTE,
+        })
+        .view(),
+    );
+}
+
+fn read_characteristic(gatt: &GattModule, tcb_idx: TransportIndex) {
+    gatt.get_bearer(tcb_idx).unwrap().handle_packet(
+        build_att_view_or_crash(AttReadRequestBuilder {
+            attribute_handle: CHARACTERISTIC_HANDLE.into(),
+        })
+        .view(),
+    );
+}
+
+/// Receive one reply on each of the given connections, in any order
+async fn recv_reply_per_connection(
+    transport_rx: &mut UnboundedReceiver<(TransportIndex, AttBuilder)>,
+    tcb_idxs: &[TransportIndex],
+) -> HashMap<TransportIndex, AttBuilder> {
+    let mut replies = HashMap::new();
+    for _ in tcb_idxs {
+        let (tcb_idx, resp) = transport_rx.recv().await.unwrap();
+        assert!(tcb_idxs.contains(&tcb_idx));
+        assert!(replies.insert(tcb_idx, resp).is_none(), "two replies on {tcb_idx:?}");
+    }
+    replies
+}
+
+#[test]
+fn test_interleaved_requests_from_multiple_connections() {
+    start_test(async move {
+        // arrange: several connections to the same server
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        let mut data_rx = create_server_and_open_connections(&mut gatt);
+
+        // act: each connection exchanges a different MTU, and then prepares a value
+        // of its own in two fragments, with the requests of all connections interleaved
+        for (i, tcb_idx) in CONNECTIONS.iter().enumerate() {
+            gatt.get_bearer(*tcb_idx).unwrap().handle_packet(
+                build_att_view_or_crash(AttExchangeMtuRequestBuilder { mtu: 100 + i as u16 })
+                    .view(),
+            );
+        }
+        let mtu_replies = recv_reply_per_connection(&mut transport_rx, &CONNECTIONS).await;
+        for offset in [0, 2] {
+            for (i, tcb_idx) in CONNECTIONS.iter().enumerate() {
+                prepare_write(&gatt, *tcb_idx, offset, &[i as u8; 2]);
+            }
+            let replies = recv_reply_per_connection(&mut transport_rx, &CONNECTIONS).await;
+            assert!(replies.values().all(|resp| resp.opcode == AttOpcode::PREPARE_WRITE_RESPONSE));
+        }
+        for tcb_idx in CONNECTIONS {
+            execute_write(&gatt, tcb_idx);
+        }
+        let mut writes = vec![];
+        for _ in CONNECTIONS {
+            let MockDatastoreEvents::Write(tcb_idx, CHARACTERISTIC_HANDLE, _, data, tx) =
+                data_rx.recv().await.unwrap()
+            else {
+                unreachable!()
+            };
+            writes.push((tcb_idx, data, tx));
+        }
+        // complete the writes in the opposite order
+        let mut written = HashMap::new();
+        for (tcb_idx, data, tx) in writes.into_iter().rev() {
+            tx.send(Ok(())).unwrap();
+            written.insert(tcb_idx, data);
+        }
+        let execute_replies = recv_reply_per_connection(&mut transport_rx, &CONNECTIONS).await;
+
+        // assert: each connection negotiated its own MTU, and wrote only its own value
+        for (i, tcb_idx) in CONNECTIONS.iter().enumerate() {
+            assert_eq!(
+                mtu_replies[tcb_idx].opcode,
+                AttOpcode::EXCHANGE_MTU_RESPONSE,
+                "on {tcb_idx:?}"
+            );
+            assert_eq!(gatt.get_mtu(*tcb_idx), Some(100 + i));
+            assert_eq!(written[tcb_idx], vec![i as u8; 4]);
+            assert_eq!(
+                execute_replies[tcb_idx],
+                AttBuilder {
+                    opcode: AttOpcode::EXECUTE_WRITE_RESPONSE,
+                    _child_: AttExecuteWriteResponseBuilder {}.into()
+                }
+            );
+        }
+        assert_eq!(data_rx.try_recv().unwrap_err(), TryRecvError::Empty);
+    });
+}
+
+#[test]
+fn test_misbehaving_connection_does_not_affect_others() {
+    start_test(async move {
+        // arrange: several connections to the same server
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        let mut data_rx = create_server_and_open_connections(&mut gatt);
+        let [misbehaving, others @ ..] = CONNECTIONS;
+
+        // act: one connection leaves a prepared write queued, and then issues a
+        // second request while its read is still outstanding
+        prepare_write(&gatt, misbehaving, 0, &DATA);
+        transport_rx.recv().await.unwrap();
+        read_characteristic(&gatt, misbehaving);
+        let MockDatastoreEvents::Read(_, CHARACTERISTIC_HANDLE, _, _stalled_tx) =
+            data_rx.recv().await.unwrap()
+        else {
+            unreachable!()
+        };
+        read_characteristic(&gatt, misbehaving);
+        // meanwhile, the other connections read and then execute a (empty) queue
+        for tcb_idx in others {
+            read_characteristic(&gatt, tcb_idx);
+            let MockDatastoreEvents::Read(read_tcb_idx, CHARACTERISTIC_HANDLE, _, tx) =
+                data_rx.recv().await.unwrap()
+            else {
+                unreachable!()
+            };
+            assert_eq!(read_tcb_idx, tcb_idx);
+            tx.send(Ok(ANOTHER_DATA.to_vec())).unwrap();
+        }
+        let read_replies = recv_reply_per_connection(&mut transport_rx, &others).await;
+        for tcb_idx in others {
+            execute_write(&gatt, tcb_idx);
+        }
+        let execute_replies = recv_reply_per_connection(&mut transport_rx, &others).await;
+
+        // assert: the other connections were served normally, and never saw the
+        // write prepared by the misbehaving one
+        for tcb_idx in others {
+            assert_eq!(
+                read_replies[&tcb_idx],
+                AttBuilder {
+                    opcode: AttOpcode::READ_RESPONSE,
+                    _child_: AttReadResponseBuilder {
+                        value: build_att_data(AttAttributeDataChild::RawData(ANOTHER_DATA.into())),
+                    }
+                    .into()
+                }
+            );
+            assert_eq!(execute_replies[&tcb_idx].opcode, AttOpcode::EXECUTE_WRITE_RESPONSE);
+        }
+        assert_eq!(data_rx.try_recv().unwrap_err(), TryRecvError::Empty);
+        assert_eq!(transport_rx.try_recv().unwrap_err(), TryRecvError::Empty);
+    });
+}
+
+#[test]
+fn test_disconnection_only_tears_down_its_connection() {
+    start_test(async move {
+        // arrange: several connections to the same server, one with a read outstanding
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        let mut data_rx = create_server_and_open_connections(&mut gatt);
+        let [disconnected, others @ ..] = CONNECTIONS;
+        read_characteristic(&gatt, disconnected);
+        let _stalled = data_rx.recv().await.unwrap();
+
+        // act: disconnect it, and then read on the others
+        gatt.on_le_disconnect(disconnected).unwrap();
+        for tcb_idx in others {
+            read_characteristic(&gatt, tcb_idx);
+            let MockDatastoreEvents::Read(_, _, _, tx) = data_rx.recv().await.unwrap() else {
+                unreachable!()
+            };
+            tx.send(Ok(DATA.to_vec())).unwrap();
+        }
+        let replies = recv_reply_per_connection(&mut transport_rx, &others).await;
+
+        // assert: only the disconnected connection is gone
+        assert!(gatt.get_bearer(disconnected).is_none());
+        assert!(gatt.get_connection_id(disconnected).is_none());
+        for tcb_idx in others {
+            assert_eq!(
+                gatt.get_connection_id(tcb_idx),
+                Some(ConnectionId::new(tcb_idx, SERVER_ID))
+            );
+            assert_eq!(replies[&tcb_idx].opcode, AttOpcode::READ_RESPONSE);
+        }
+        assert_eq!(transport_rx.try_recv().unwrap_err(), TryRecvError::Empty);
+    });
+}
+
+#[test]
+fn test_duplicate_connection_rejected() {
+    start_test(async move {
+        // arrange: a connection which has exchanged its MTU
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        create_server_and_open_connection(&mut gatt);
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttExchangeMtuRequestBuilder { mtu: 100 }).view(),
+        );
+        transport_rx.recv().await.unwrap();
+
+        // act: the same transport is connected again
+        let res = gatt.on_le_connect(TCB_IDX, Some(ADVERTISER_ID));
+
+        // assert: it was rejected, and the existing connection is untouched
+        assert!(res.is_err());
+        assert_eq!(gatt.get_mtu(TCB_IDX), Some(100));
+        assert!(gatt.get_isolation_manager().is_connection_isolated(TCB_IDX));
+    });
+}
+
+#[test]
+fn test_read_requires_link_encryption() {
+    start_test(async move {
+        // arrange: a characteristic requiring encryption, on an unencrypted link
+        let (transport, mut transport_rx, _) = MockAttTransport::new();
+        let security_manager = Rc::new(MockSecurityManager::new());
+        let mut gatt = GattModule::new(
+            Rc::new(transport),
+            security_manager.clone(),
+            Arc::new(Mutex::new(IsolationManager::new())),
+        );
+        gatt.open_gatt_server(SERVER_ID).unwrap();
+        let (datastore, mut data_rx) = MockDatastore::new();
+        gatt.register_gatt_service(
+            SERVER_ID,
+            GattServiceWithHandle {
+                handle: SERVICE_HANDLE,
+                type_: SERVICE_TYPE,
+                characteristics: vec![GattCharacteristicWithHandle {
+                    handle: CHARACTERISTIC_HANDLE,
+                    type_: CHARACTERISTIC_TYPE,
+                    permissions: AttPermissions::READABLE | AttPermissions::ENCRYPTION_REQUIRED,
+                    descriptors: vec![],
+                }],
+            },
+            datastore,
+        )
+        .unwrap();
+        gatt.get_isolation_manager().associate_server_with_advertiser(SERVER_ID, ADVERTISER_ID);
+        gatt.on_le_connect(TCB_IDX, Some(ADVERTISER_ID)).unwrap();
+        let read_request = build_att_view_or_crash(AttReadRequestBuilder {
+            attribute_handle: CHARACTERISTIC_HANDLE.into(),
+        });
+
+        // act: read the characteristic
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(read_request.view());
+        let (_, resp) = transport_rx.recv().await.unwrap();
+
+        // assert: the read was rejected
+        assert_eq!(
+            resp,
+            AttBuilder {
+                opcode: AttOpcode::ERROR_RESPONSE,
+                _child_: AttErrorResponseBuilder {
+                    opcode_in_error: AttOpcode::READ_REQUEST,
+                    handle_in_error: CHARACTERISTIC_HANDLE.into(),
+                    error_code: AttErrorCode::INSUFFICIENT_ENCRYPTION,
+                }
+                .into()
+            }
+        );
+        assert_eq!(data_rx.try_recv().unwrap_err(), TryRecvError::Empty);
+
+        // act: encrypt the link, and read again
+        security_manager.set_security_level(TCB_IDX, SecurityLevel::Encrypted);
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(read_request.view());
+        let MockDatastoreEvents::Read(TCB_IDX, CHARACTERISTIC_HANDLE, _, tx) =
+            data_rx.recv().await.unwrap()
+        else {
+            unreachable!()
+        };
+        tx.send(Ok(DATA.to_vec())).unwrap();
+        let (_, resp) = transport_rx.recv().await.unwrap();
+
+        // assert: the read was served
+        assert_eq!(
+            resp,
+            AttBuilder {
+                opcode: AttOpcode::READ_RESPONSE,
+                _child_: AttReadResponseBuilder {
+                    value: build_att_data(AttAttributeDataChild::RawData(DATA.into()))
+                }
+                .into()
+            }
+        );
+    });
+}
+
+#[test]
+fn test_read_replayed_after_link_encryption() {
+    start_test(async move {
+        // arrange: a characteristic requiring encryption, on an unencrypted link
+        let (transport, mut transport_rx, _) = MockAttTransport::new();
+        let security_manager = Rc::new(MockSecurityManager::new());
+        security_manager.set_security_elevation_supported(true);
+        let mut gatt = GattModule::new(
+            Rc::new(transport),
+            security_manager.clone(),
+            Arc::new(Mutex::new(IsolationManager::new())),
+        );
+        gatt.open_gatt_server(SERVER_ID).unwrap();
+        let (datastore, mut data_rx) = MockDatastore::new();
+        gatt.register_gatt_service(
+            SERVER_ID,
+            GattServiceWithHandle {
+                handle: SERVICE_HANDLE,
+                type_: SERVICE_TYPE,
+                characteristics: vec![GattCharacteristicWithHandle {
+                    handle: CHARACTERISTIC_HANDLE,
+                    type_: CHARACTERISTIC_TYPE,
+                    permissions: AttPermissions::READABLE | AttPermissions::ENCRYPTION_REQUIRED,
+                    descriptors: vec![],
+                }],
+            },
+            datastore,
+        )
+        .unwrap();
+        gatt.get_isolation_manager().associate_server_with_advertiser(SERVER_ID, ADVERTISER_ID);
+        gatt.on_le_connect(TCB_IDX, Some(ADVERTISER_ID)).unwrap();
+
+        // act: read the characteristic
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttReadRequestBuilder {
+                attribute_handle: CHARACTERISTIC_HANDLE.into(),
+            })
+            .view(),
+        );
+        tokio::time::sleep(Duration::from_millis(1)).await;
+
+        // assert: the read is parked while encryption is requested
+        assert_eq!(
+            security_manager.take_security_elevation_requests(),
+            vec![(TCB_IDX, SecurityLevel::Encrypted)]
+        );
+        assert_eq!(transport_rx.try_recv().unwrap_err(), TryRecvError::Empty);
+
+        // act: encrypt the link
+        security_manager.set_security_level(TCB_IDX, SecurityLevel::Encrypted);
+        gatt.on_security_level_changed(TCB_IDX).unwrap();
+        let MockDatastoreEvents::Read(TCB_IDX, CHARACTERISTIC_HANDLE, _, tx) =
+            data_rx.recv().await.unwrap()
+        else {
+            unreachable!()
+        };
+        tx.send(Ok(DATA.to_vec())).unwrap();
+        let (_, resp) = transport_rx.recv().await.unwrap();
+
+        // assert: the read was replayed and served, without an error being sent
+        assert_eq!(
+            resp,
+            AttBuilder {
+                opcode: AttOpcode::READ_RESPONSE,
+                _child_: AttReadResponseBuilder {
+                    value: build_att_data(AttAttributeDataChild::RawData(DATA.into()))
+                }
+                .into()
+            }
+        );
+    });
+}
+
+#[derive(Default)]
+struct RecordingListener(RefCell<Vec<GattServerEvent>>);
+
+impl GattServerEventListener for RecordingListener {
+    fn on_server_event(&self, event: GattServerEvent) {
+        self.0.borrow_mut().push(event);
+    }
+}
+
+#[test]
+fn test_server_events() {
+    start_test(async move {
+        // arrange
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        let listener = Rc::new(RecordingListener::default());
+        gatt.register_event_listener(listener.clone());
+        create_server_and_open_connection(&mut gatt);
+        let conn_id = ConnectionId::new(TCB_IDX, SERVER_ID);
+
+        // act: exchange the MTU, subscribe to indications, and confirm one
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttExchangeMtuRequestBuilder { mtu: 100 }).view(),
+        );
+        transport_rx.recv().await.unwrap();
+        subscribe_to_indications(&gatt, &mut transport_rx).await;
+        let pending_indication =
+            spawn_local(gatt.get_bearer(TCB_IDX).unwrap().send_indication(
+                CHARACTERISTIC_HANDLE,
+                AttAttributeDataChild::RawData(DATA.into()),
+            ));
+        transport_rx.recv().await.unwrap();
+        gatt.get_bearer(TCB_IDX)
+            .unwrap()
+            .handle_packet(build_att_view_or_crash(AttHandleValueConfirmationBuilder {}).view());
+        pending_indication.await.unwrap().unwrap();
+        // then disconnect
+        gatt.on_le_disconnect(TCB_IDX).unwrap();
 
-        // assert: the name should not be readable
-        assert_eq!(tcb_idx, TCB_IDX);
-        let AttChild::AttErrorResponse(resp) = resp._child_ else {
-            unreachable!("{resp:?}");
-        };
-        assert_eq!(resp.error_code, AttErrorCode::INSUFFICIENT_AUTHENTICATION);
+        // assert
+        assert_eq!(
+            *listener.0.borrow(),
+            vec![
+                GattServerEvent::ConnectionOpened { conn_id, transport: Transport::Le },
+                GattServerEvent::MtuChanged { conn_id, mtu: 100 },
+                GattServerEvent::CccdChanged {
+                    conn_id,
+                    handle: CHARACTERISTIC_HANDLE,
+                    bits: ClientConfiguration::INDICATION
+                },
+                GattServerEvent::IndicationConfirmed { conn_id, handle: CHARACTERISTIC_HANDLE },
+                GattServerEvent::ConnectionClosed { conn_id },
+            ]
+        );
     });
 }
 
+fn cccd_changes(listener: &RecordingListener) -> usize {
+    listener
+        .0
+        .borrow()
+        .iter()
+        .filter(|event| matches!(event, GattServerEvent::CccdChanged { .. }))
+        .count()
+}
+
 #[test]
-fn test_ignored_service_change_indication() {
+fn test_cccd_burst_ends_when_client_idle() {
     start_test(async move {
-        // arrange
+        // arrange: a server handling consecutive CCCD writes as a burst
         let (mut gatt, mut transport_rx) = start_gatt_module();
+        gatt.set_config(GattServerConfig {
+            client_configuration_burst_window: Some(Duration::from_millis(100)),
+            ..Default::default()
+        })
+        .unwrap();
+        let listener = Rc::new(RecordingListener::default());
+        gatt.register_event_listener(listener.clone());
         create_server_and_open_connection(&mut gatt);
 
-        // act: add a new service
-        let (datastore, _) = MockDatastore::new();
-        gatt.register_gatt_service(
-            SERVER_ID,
-            GattServiceWithHandle {
-                handle: AttHandle(30),
-                type_: SERVICE_TYPE,
-                characteristics: vec![],
-            },
-            datastore,
-        )
-        .unwrap();
+        // act: the client subscribes, then makes no other request
+        subscribe_to_indications(&gatt, &mut transport_rx).await;
+        let during_burst = cccd_changes(&listener);
+        tokio::time::sleep(Duration::from_millis(101)).await;
 
-        // assert: no packets should be sent
-        assert_eq!(transport_rx.try_recv().unwrap_err(), TryRecvError::Empty);
+        // assert: the subscription was reported once the burst ended
+        assert_eq!(during_burst, 0);
+        assert_eq!(cccd_changes(&listener), 1);
     });
 }
 
 #[test]
-fn test_service_change_indication() {
+fn test_cccd_burst_ends_with_other_request() {
     start_test(async move {
         // arrange
         let (mut gatt, mut transport_rx) = start_gatt_module();
+        gatt.set_config(GattServerConfig {
+            client_configuration_burst_window: Some(Duration::from_secs(1)),
+            ..Default::default()
+        })
+        .unwrap();
+        let listener = Rc::new(RecordingListener::default());
+        gatt.register_event_listener(listener.clone());
         create_server_and_open_connection(&mut gatt);
+        subscribe_to_indications(&gatt, &mut transport_rx).await;
 
-        // act: discover the GATT server
+        // act: the client reads the service declaration right away
         gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
-            build_att_view_or_crash(AttFindByTypeValueRequestBuilder {
-                starting_handle: AttHandle::MIN.into(),
-                ending_handle: AttHandle::MAX.into(),
-                attribute_type: PRIMARY_SERVICE_DECLARATION_UUID.try_into().unwrap(),
-                attribute_value: build_att_data(UuidAsAttDataBuilder {
-                    uuid: GATT_SERVICE_UUID.into(),
-                }),
+            build_att_view_or_crash(AttReadRequestBuilder {
+                attribute_handle: SERVICE_HANDLE.into(),
             })
             .view(),
         );
-        let AttChild::AttFindByTypeValueResponse(resp) =
-            transport_rx.recv().await.unwrap().1._child_
-        else {
-            unreachable!()
-        };
-        let (starting_handle, ending_handle) = (
-            resp.handles_info[0].clone().found_attribute_handle,
-            resp.handles_info[0].clone().group_end_handle,
-        );
-        // act: discover the service changed characteristic
+        transport_rx.recv().await.unwrap();
+
+        // assert: the burst ended without waiting for the window
+        assert_eq!(cccd_changes(&listener), 1);
+    });
+}
+
+#[cfg(not(feature = "le_only"))]
+const BR_EDR_MTU: usize = 60;
+
+#[test]
+#[cfg(not(feature = "le_only"))]
+fn test_br_edr_connection() {
+    start_test(async move {
+        // arrange
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        let listener = Rc::new(RecordingListener::default());
+        gatt.register_event_listener(listener.clone());
+        create_server(&mut gatt);
+
+        // act: connect over BR/EDR, and read the service declaration
+        gatt.on_br_edr_connect(TCB_IDX, SERVER_ID, BR_EDR_MTU).unwrap();
         gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
-            build_att_view_or_crash(AttReadByTypeRequestBuilder {
-                starting_handle,
-                ending_handle,
-                attribute_type: CHARACTERISTIC_UUID.into(),
+            build_att_view_or_crash(AttReadRequestBuilder {
+                attribute_handle: SERVICE_HANDLE.into(),
             })
             .view(),
         );
-        let AttChild::AttReadByTypeResponse(resp) = transport_rx.recv().await.unwrap().1._child_
-        else {
-            unreachable!()
-        };
-        let service_change_char_handle: AttHandle = resp
-            .data
-            .into_vec()
-            .into_iter()
-            .find_map(|characteristic| {
-                let value = characteristic.value.to_vec().unwrap();
-                let decl =
-                    GattCharacteristicDeclarationValueView::try_parse_from_buffer(value.as_slice())
-                        .unwrap();
-
-                if SERVICE_CHANGE_UUID == decl.get_uuid().try_into().unwrap() {
-                    Some(decl.get_handle().into())
-                } else {
-                    None
+        let (tcb_idx, resp) = transport_rx.recv().await.unwrap();
+
+        // assert: the connection is isolated to the server, and over BR/EDR
+        let conn_id = ConnectionId::new(TCB_IDX, SERVER_ID);
+        assert!(gatt.get_isolation_manager().is_connection_isolated(TCB_IDX));
+        assert_eq!(gatt.get_connection_transport(conn_id), Some(Transport::BrEdr));
+        assert_eq!(
+            *listener.0.borrow(),
+            vec![GattServerEvent::ConnectionOpened { conn_id, transport: Transport::BrEdr }]
+        );
+        // and it uses the MTU configured by L2CAP
+        assert_eq!(gatt.get_mtu(TCB_IDX), Some(BR_EDR_MTU));
+        // and the read was served on the fixed channel
+        assert_eq!(tcb_idx, TCB_IDX);
+        assert_eq!(resp.opcode, AttOpcode::READ_RESPONSE);
+    });
+}
+
+#[test]
+#[cfg(not(feature = "le_only"))]
+fn test_no_mtu_exchange_over_br_edr() {
+    start_test(async move {
+        // arrange
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        create_server(&mut gatt);
+        gatt.on_br_edr_connect(TCB_IDX, SERVER_ID, BR_EDR_MTU).unwrap();
+
+        // act: the client tries to exchange the MTU
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttExchangeMtuRequestBuilder { mtu: 100 }).view(),
+        );
+        let (_, resp) = transport_rx.recv().await.unwrap();
+
+        // assert: the exchange was rejected, and the configured MTU is still used
+        assert_eq!(
+            resp,
+            AttBuilder {
+                opcode: AttOpcode::ERROR_RESPONSE,
+                _child_: AttErrorResponseBuilder {
+                    opcode_in_error: AttOpcode::EXCHANGE_MTU_REQUEST,
+                    handle_in_error: AttHandle(0).into(),
+                    error_code: AttErrorCode::REQUEST_NOT_SUPPORTED,
                 }
-            })
+                .into(),
+            }
+        );
+        assert_eq!(gatt.get_mtu(TCB_IDX), Some(BR_EDR_MTU));
+    });
+}
+
+#[test]
+#[cfg(not(feature = "le_only"))]
+fn test_le_only_service_hidden_over_br_edr() {
+    start_test(async move {
+        // arrange: a service visible only over LE
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        create_server(&mut gatt);
+        let (datastore, _data_rx) = MockDatastore::new();
+        let token = gatt
+            .add_gatt_service(
+                SERVER_ID,
+                ServiceBuilder::new(SERVICE_TYPE)
+                    .characteristic(CharacteristicBuilder::new(
+                        CHARACTERISTIC_TYPE,
+                        AttPermissions::READABLE,
+                    ))
+                    .transport(Transport::Le),
+                datastore,
+            )
             .unwrap();
-        // act: find the CCC descriptor for the service changed characteristic
+        gatt.on_br_edr_connect(TCB_IDX, SERVER_ID, BR_EDR_MTU).unwrap();
+
+        // act: a client connected over BR/EDR reads its declaration
         gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
-            build_att_view_or_crash(AttFindInformationRequestBuilder {
-                starting_handle: service_change_char_handle.into(),
-                ending_handle: AttHandle::MAX.into(),
+            build_att_view_or_crash(AttReadRequestBuilder {
+                attribute_handle: token.handle().into(),
             })
             .view(),
         );
-        let AttChild::AttFindInformationResponse(resp) =
-            transport_rx.recv().await.unwrap().1._child_
-        else {
-            unreachable!()
-        };
-        let AttFindInformationResponseChild::AttFindInformationShortResponse(resp) = resp._child_
-        else {
-            unreachable!()
+        let (_, resp) = transport_rx.recv().await.unwrap();
+
+        // assert: the handle does not exist over BR/EDR
+        assert_eq!(
+            resp._child_,
+            AttErrorResponseBuilder {
+                opcode_in_error: AttOpcode::READ_REQUEST,
+                handle_in_error: token.handle().into(),
+                error_code: AttErrorCode::INVALID_HANDLE,
+            }
+            .into()
+        );
+    });
+}
+
+#[test]
+#[cfg(not(feature = "le_only"))]
+fn test_br_edr_connection_below_minimum_mtu() {
+    start_test(async move {
+        // arrange
+        let (mut gatt, _transport_rx) = start_gatt_module();
+        create_server(&mut gatt);
+
+        // act
+        let res = gatt.on_br_edr_connect(TCB_IDX, SERVER_ID, 47);
+
+        // assert: the connection was rejected
+        assert!(res.is_err());
+        assert!(gatt.get_bearer(TCB_IDX).is_none());
+        assert!(!gatt.get_isolation_manager().is_connection_isolated(TCB_IDX));
+    });
+}
+
+#[test]
+#[cfg(not(feature = "le_only"))]
+fn test_br_edr_disconnection() {
+    start_test(async move {
+        // arrange
+        let (mut gatt, _transport_rx) = start_gatt_module();
+        create_server(&mut gatt);
+        gatt.on_br_edr_connect(TCB_IDX, SERVER_ID, BR_EDR_MTU).unwrap();
+
+        // act
+        gatt.on_le_disconnect(TCB_IDX).unwrap();
+
+        // assert
+        assert!(gatt.get_bearer(TCB_IDX).is_none());
+        assert!(!gatt.get_isolation_manager().is_connection_isolated(TCB_IDX));
+    });
+}
+
+#[test]
+fn test_dump_metrics_across_bearers() {
+    start_test(async move {
+        // arrange: a connection with an EATT bearer
+        let (mut gatt, mut transport_rx, mut eatt_rx) = start_gatt_module_with_eatt();
+        create_server_and_open_connection(&mut gatt);
+        gatt.on_eatt_bearer_open(TCB_IDX, EATT_CID, EATT_MTU).unwrap();
+
+        // act: read the service declaration on each bearer
+        let read = build_att_view_or_crash(AttReadRequestBuilder {
+            attribute_handle: SERVICE_HANDLE.into(),
+        });
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(read.view());
+        transport_rx.recv().await.unwrap();
+        gatt.get_eatt_bearer(TCB_IDX, EATT_CID).unwrap().handle_packet(read.view());
+        eatt_rx.recv().await.unwrap();
+        let snapshot = gatt.dump();
+
+        // assert: the counters of both bearers were merged
+        let [connection] = &snapshot.connections[..] else {
+            panic!("expected a single connection, got {snapshot:?}");
         };
-        let service_change_descriptor_handle = resp
-            .data
-            .into_vec()
-            .into_iter()
-            .find_map(|attr| {
-                if attr.uuid == CLIENT_CHARACTERISTIC_CONFIGURATION_UUID.try_into().unwrap() {
-                    Some(attr.handle)
-                } else {
-                    None
-                }
+        assert_eq!(connection.conn_id, ConnectionId::new(TCB_IDX, SERVER_ID));
+        assert_eq!(connection.transport, Transport::Le);
+        // the unenhanced bearer uses the default MTU, since none was exchanged
+        assert_eq!(connection.mtus, vec![23, EATT_MTU]);
+        assert_eq!(connection.counters.requests[&u8::from(AttOpcode::READ_REQUEST)], 2);
+        assert_eq!(connection.counters.transactions, 2);
+        assert!(snapshot.to_string().contains("1 connection(s)"));
+    });
+}
+
+#[test]
+fn test_dump_metrics_without_connections() {
+    start_test(async move {
+        let (mut gatt, _) = start_gatt_module();
+        create_server(&mut gatt);
+
+        let snapshot = gatt.dump();
+
+        assert!(snapshot.connections.is_empty());
+    });
+}
+
+#[test]
+fn test_connection_stats_across_bearers() {
+    start_test(async move {
+        // arrange: a connection with an EATT bearer
+        let (mut gatt, _transport_rx, mut eatt_rx) = start_gatt_module_with_eatt();
+        create_server_and_open_connection(&mut gatt);
+        gatt.on_eatt_bearer_open(TCB_IDX, EATT_CID, EATT_MTU).unwrap();
+
+        // act: read the service declaration on the EATT bearer, a second later
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        gatt.get_eatt_bearer(TCB_IDX, EATT_CID).unwrap().handle_packet(
+            build_att_view_or_crash(AttReadRequestBuilder {
+                attribute_handle: SERVICE_HANDLE.into(),
             })
+            .view(),
+        );
+        let (_, _, reply) = eatt_rx.recv().await.unwrap();
+        let replied_at = tokio::time::Instant::now().into_std();
+        let stats = gatt.connection_stats(ConnectionId::new(TCB_IDX, SERVER_ID)).unwrap();
+
+        // assert
+        assert_eq!(stats.mtu, 23);
+        assert_eq!(stats.bearers, 2);
+        assert_eq!(stats.outstanding_indications, 0);
+        assert_eq!(stats.queued_notifications, 0);
+        assert_eq!(stats.bytes_received, 3);
+        assert_eq!(stats.bytes_sent, reply.to_vec().unwrap().len() as u64);
+        assert_eq!(stats.last_activity, replied_at);
+        assert!(gatt.connection_stats(ConnectionId::new(ANOTHER_TCB_IDX, SERVER_ID)).is_none());
+    });
+}
+
+#[test]
+fn test_max_notification_payload() {
+    start_test(async move {
+        // arrange: a connection whose client exchanged the MTU
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        create_server_and_open_connection(&mut gatt);
+        let conn_id = ConnectionId::new(TCB_IDX, SERVER_ID);
+        let default_payload = gatt.max_notification_payload(conn_id);
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttExchangeMtuRequestBuilder { mtu: 100 }).view(),
+        );
+        transport_rx.recv().await.unwrap();
+
+        // act: the link layer data length is updated
+        let short_pdus_payload = gatt.max_notification_payload(conn_id);
+        gatt.on_le_data_length_changed(TCB_IDX, 251).unwrap();
+        let long_pdus_payload = gatt.max_notification_payload(conn_id);
+
+        // assert
+        assert_eq!(default_payload, Some(20));
+        assert_eq!(short_pdus_payload, Some(74));
+        assert_eq!(long_pdus_payload, Some(97));
+        assert!(gatt.on_le_data_length_changed(TCB_IDX, 252).is_err());
+        assert!(gatt.on_le_data_length_changed(ANOTHER_TCB_IDX, 251).is_err());
+        assert_eq!(
+            gatt.max_notification_payload(ConnectionId::new(ANOTHER_TCB_IDX, SERVER_ID)),
+            None
+        );
+    });
+}
+
+#[test]
+fn test_notify_all_enforces_recommended_payload() {
+    start_test(async move {
+        // arrange: a subscribed client, with an MTU of 100 over 27-octet LL PDUs, of a
+        // server enforcing the recommended payload
+        let (mut gatt, mut transport_rx) = start_gatt_module();
+        gatt.set_config(GattServerConfig {
+            enforce_notification_payload: true,
+            ..Default::default()
+        })
+        .unwrap();
+        create_server(&mut gatt);
+        let (datastore, _data_rx) = MockDatastore::new();
+        let token = gatt
+            .add_gatt_service(
+                SERVER_ID,
+                ServiceBuilder::new(SERVICE_TYPE).characteristic(CharacteristicBuilder::new(
+                    CHARACTERISTIC_TYPE,
+                    AttPermissions::READABLE | AttPermissions::NOTIFY,
+                )),
+                datastore,
+            )
             .unwrap();
-        // act: register for indications on this handle
+        let value_handle = AttHandle(token.handle().0 + 2);
+        gatt.get_isolation_manager().associate_server_with_advertiser(SERVER_ID, ADVERTISER_ID);
+        gatt.on_le_connect(TCB_IDX, Some(ADVERTISER_ID)).unwrap();
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
+            build_att_view_or_crash(AttExchangeMtuRequestBuilder { mtu: 100 }).view(),
+        );
+        transport_rx.recv().await.unwrap();
         gatt.get_bearer(TCB_IDX).unwrap().handle_packet(
             build_att_view_or_crash(AttWriteRequestBuilder {
-                handle: service_change_descriptor_handle,
+                handle: AttHandle(value_handle.0 + 1).into(),
                 value: build_att_data(GattClientCharacteristicConfigurationBuilder {
-                    notification: 0,
-                    indication: 1,
+                    notification: 1,
+                    indication: 0,
                 }),
             })
             .view(),
         );
-        let AttChild::AttWriteResponse(_) = transport_rx.recv().await.unwrap().1._child_ else {
-            unreachable!()
-        };
-        // act: add a new service
-        let (datastore, _) = MockDatastore::new();
-        gatt.register_gatt_service(
-            SERVER_ID,
-            GattServiceWithHandle {
-                handle: AttHandle(30),
-                type_: SERVICE_TYPE,
-                characteristics: vec![],
-            },
-            datastore,
-        )
-        .unwrap();
+        transport_rx.recv().await.unwrap();
 
-        // assert: we got an indication
-        let AttChild::AttHandleValueIndication(indication) =
-            transport_rx.recv().await.unwrap().1._child_
-        else {
-            unreachable!()
-        };
-        assert_eq!(indication.handle, service_change_char_handle.into());
+        // act: notify a value of the ATT_MTU-3
+        let value = [0x42; 97];
+        let results = gatt.notify_all(SERVER_ID, value_handle, &value).unwrap().await;
+
+        // assert: the value was truncated to fill three LL PDUs
+        let (_, notification) = transport_rx.recv().await.unwrap();
         assert_eq!(
-            indication.value,
-            build_att_data(GattServiceChangedBuilder {
-                start_handle: AttHandle(30).into(),
-                end_handle: AttHandle(30).into(),
-            })
+            notification._child_,
+            AttHandleValueNotificationBuilder {
+                handle: value_handle.into(),
+                value: build_att_data(AttAttributeDataChild::RawData(value[..74].into())),
+            }
+            .into()
         );
+        assert!(matches!(results[&ConnectionId::new(TCB_IDX, SERVER_ID)], Ok(())));
     });
 }
 
 #[test]
-fn test_closing_gatt_server_unisolates_advertiser() {
+fn test_debug_dump() {
     start_test(async move {
-        // arrange
-        let (mut gatt, _) = start_gatt_module();
-        gatt.open_gatt_server(SERVER_ID).unwrap();
-        gatt.get_isolation_manager().associate_server_with_advertiser(SERVER_ID, ADVERTISER_ID);
+        // arrange: a subscribed client with a read in progress on an EATT bearer
+        let (mut gatt, mut transport_rx, _eatt_rx) = start_gatt_module_with_eatt();
+        let mut data_rx = create_server_and_open_connection(&mut gatt);
+        subscribe_to_indications(&gatt, &mut transport_rx).await;
+        gatt.on_eatt_bearer_open(TCB_IDX, EATT_CID, EATT_MTU).unwrap();
+        gatt.get_eatt_bearer(TCB_IDX, EATT_CID).unwrap().handle_packet(
+            build_att_view_or_crash(AttReadRequestBuilder {
+                attribute_handle: CHARACTERISTIC_HANDLE.into(),
+            })
+            .view(),
+        );
+        data_rx.recv().await.unwrap();
 
         // act
-        gatt.close_gatt_server(SERVER_ID).unwrap();
+        let mut dump = String::new();
+        gatt.debug_dump(&mut dump).unwrap();
 
         // assert
-        let is_advertiser_isolated =
-            gatt.get_isolation_manager().is_advertiser_isolated(ADVERTISER_ID);
-        assert!(!is_advertiser_isolated);
+        assert!(dump.contains("1 server(s) open"), "{dump}");
+        assert!(dump.contains(&format!("{SERVER_ID}: 3 service(s)")), "{dump}");
+        assert!(dump.contains("0x000b..=0x000f 0102"), "{dump}");
+        assert!(dump.contains("1 connection(s)"), "{dump}");
+        assert!(dump.contains("MTU 23, 2 bearer(s)"), "{dump}");
+        assert!(dump.contains(&format!("subscribed to {CHARACTERISTIC_HANDLE}: ")), "{dump}");
+        assert!(
+            dump.contains(&format!("pending {:?} on {EATT_CID}", AttOpcode::READ_REQUEST)),
+            "{dump}"
+        );
     });
 }
 
 #[test]
-fn test_disconnection_unisolates_connection() {
+fn test_pdus_traced_per_connection_and_bearer() {
     start_test(async move {
         // arrange
-        let (mut gatt, _) = start_gatt_module();
+        let (mut gatt, mut transport_rx, mut eatt_rx) = start_gatt_module_with_eatt();
+        let tracer = Rc::new(RingBufferTracer::default());
+        gatt.set_tracer(tracer.clone());
         create_server_and_open_connection(&mut gatt);
+        gatt.on_eatt_bearer_open(TCB_IDX, EATT_CID, EATT_MTU).unwrap();
 
-        // act
-        gatt.on_le_disconnect(TCB_IDX).unwrap();
+        // act: read the service declaration on each bearer
+        let read = build_att_view_or_crash(AttReadRequestBuilder {
+            attribute_handle: SERVICE_HANDLE.into(),
+        });
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(read.view());
+        transport_rx.recv().await.unwrap();
+        gatt.get_eatt_bearer(TCB_IDX, EATT_CID).unwrap().handle_packet(read.view());
+        eatt_rx.recv().await.unwrap();
 
         // assert
-        let is_connection_isolated = gatt.get_isolation_manager().is_connection_isolated(TCB_IDX);
-        assert!(!is_connection_isolated);
+        let conn_id = ConnectionId::new(TCB_IDX, SERVER_ID);
+        assert_eq!(
+            tracer
+                .records()
+                .iter()
+                .map(|record| (record.conn_id, record.cid, record.direction, record.opcode))
+                .collect::<Vec<_>>(),
+            vec![
+                (conn_id, None, Direction::Rx, AttOpcode::READ_REQUEST),
+                (conn_id, None, Direction::Tx, AttOpcode::READ_RESPONSE),
+                (conn_id, Some(EATT_CID), Direction::Rx, AttOpcode::READ_REQUEST),
+                (conn_id, Some(EATT_CID), Direction::Tx, AttOpcode::READ_RESPONSE),
+            ]
+        );
+        let mut dump = String::new();
+        gatt.debug_dump(&mut dump).unwrap();
+        assert!(dump.contains("last 4 ATT PDU(s)"), "{dump}");
+    });
+}
+
+#[test]
+fn test_attribute_accesses_journaled() {
+    start_test(async move {
+        // arrange: a bonded client
+        let (mut gatt, mut transport_rx, mut eatt_rx) = start_gatt_module_with_eatt();
+        let journal = Rc::new(AccessJournal::default());
+        gatt.set_access_journal(journal.clone());
+        create_server_and_open_connection(&mut gatt);
+        gatt.on_le_bonded(TCB_IDX, PEER).unwrap();
+        gatt.on_eatt_bearer_open(TCB_IDX, EATT_CID, EATT_MTU).unwrap();
+
+        // act: read the service declaration on each bearer
+        let read = build_att_view_or_crash(AttReadRequestBuilder {
+            attribute_handle: SERVICE_HANDLE.into(),
+        });
+        gatt.get_bearer(TCB_IDX).unwrap().handle_packet(read.view());
+        transport_rx.recv().await.unwrap();
+        gatt.get_eatt_bearer(TCB_IDX, EATT_CID).unwrap().handle_packet(read.view());
+        eatt_rx.recv().await.unwrap();
+
+        // assert
+        let conn_id = ConnectionId::new(TCB_IDX, SERVER_ID);
+        assert_eq!(
+            journal
+                .accesses_to(SERVER_ID, SERVICE_HANDLE)
+                .iter()
+                .map(|record| (record.conn_id, record.cid, record.peer, record.outcome))
+                .collect::<Vec<_>>(),
+            vec![
+                (conn_id, None, PeerIdentity::Address(PEER), AccessOutcome::Succeeded),
+                (conn_id, Some(EATT_CID), PeerIdentity::Address(PEER), AccessOutcome::Succeeded),
+            ]
+        );
+        let mut dump = String::new();
+        gatt.debug_dump(&mut dump).unwrap();
+        assert!(dump.contains("last 2 attribute access(es)"), "{dump}");
     });
 }
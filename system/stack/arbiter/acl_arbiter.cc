@@ -22,6 +22,10 @@
 #include <iterator>
 
 #include "osi/include/allocator.h"
+#include "stack/btm/btm_ble_sec.h"
+#include "stack/btm/btm_dev.h"
+#include "stack/btm/btm_sec.h"
+#include "stack/eatt/eatt.h"
 #include "stack/gatt/gatt_int.h"
 #include "stack/include/l2c_api.h"
 #include "stack/include/l2cdefs.h"
@@ -35,14 +39,38 @@ namespace {
 struct RustArbiterCallbacks {
   ::rust::Fn<void(uint8_t tcb_idx, uint8_t advertiser)> on_le_connect;
   ::rust::Fn<void(uint8_t tcb_idx)> on_le_disconnect;
+  ::rust::Fn<void(uint8_t tcb_idx, uint16_t mtu)> on_br_edr_connect;
+  ::rust::Fn<void(uint8_t tcb_idx)> on_br_edr_disconnect;
   ::rust::Fn<InterceptAction(uint8_t tcb_idx, ::rust::Vec<uint8_t> buffer)>
       intercept_packet;
   ::rust::Fn<void(uint8_t tcb_idx)> on_outgoing_mtu_req;
   ::rust::Fn<void(uint8_t tcb_idx, size_t mtu)> on_incoming_mtu_resp;
   ::rust::Fn<void(uint8_t tcb_idx, size_t mtu)> on_incoming_mtu_req;
+  ::rust::Fn<void(uint8_t tcb_idx, uint16_t cid, uint16_t mtu)>
+      on_eatt_bearer_open;
+  ::rust::Fn<void(uint8_t tcb_idx, uint16_t cid)> on_eatt_bearer_close;
+  ::rust::Fn<InterceptAction(uint8_t tcb_idx, uint16_t cid,
+                             ::rust::Vec<uint8_t> buffer)>
+      intercept_eatt_packet;
+  ::rust::Fn<void(uint8_t tcb_idx, LinkSecurityLevel security_level,
+                  uint8_t key_size, ::rust::Slice<const uint8_t> csrk,
+                  uint32_t sign_counter)>
+      on_link_security_changed;
 };
 
 RustArbiterCallbacks callbacks_{};
+
+void OnSecurityElevationComplete(RawAddress bd_addr, tBT_TRANSPORT transport,
+                                 void* /* p_ref_data */, tBTM_STATUS result) {
+  tGATT_TCB* p_tcb = gatt_find_tcb_by_addr(bd_addr, transport);
+  if (p_tcb == nullptr) {
+    log::warn("Security elevation completed for unknown peer:{}", bd_addr);
+    return;
+  }
+  log::info("Security elevation of peer:{} completed with status:{}", bd_addr,
+            result);
+  GetArbiter().OnLinkSecurityChanged(p_tcb->tcb_idx);
+}
 }  // namespace
 
 void AclArbiter::OnLeConnect(uint8_t tcb_idx, uint16_t advertiser_id) {
@@ -50,6 +78,7 @@ void AclArbiter::OnLeConnect(uint8_t tcb_idx, uint16_t advertiser_id) {
   return;
 #endif
   log::info("Notifying Rust of LE connection");
+  OnLinkSecurityChanged(tcb_idx);
   callbacks_.on_le_connect(tcb_idx, advertiser_id);
 }
 
@@ -61,6 +90,23 @@ void AclArbiter::OnLeDisconnect(uint8_t tcb_idx) {
   callbacks_.on_le_disconnect(tcb_idx);
 }
 
+void AclArbiter::OnBrEdrConnect(uint8_t tcb_idx, uint16_t mtu) {
+#ifdef TARGET_FLOSS
+  return;
+#endif
+  log::info("Notifying Rust of BR/EDR connection with MTU {}", mtu);
+  OnLinkSecurityChanged(tcb_idx);
+  callbacks_.on_br_edr_connect(tcb_idx, mtu);
+}
+
+void AclArbiter::OnBrEdrDisconnect(uint8_t tcb_idx) {
+#ifdef TARGET_FLOSS
+  return;
+#endif
+  log::info("Notifying Rust of BR/EDR disconnection");
+  callbacks_.on_br_edr_disconnect(tcb_idx);
+}
+
 InterceptAction AclArbiter::InterceptAttPacket(uint8_t tcb_idx,
                                                const BT_HDR* packet) {
 #ifdef TARGET_FLOSS
@@ -100,6 +146,78 @@ void AclArbiter::OnIncomingMtuReq(uint8_t tcb_idx, size_t mtu) {
   callbacks_.on_incoming_mtu_req(tcb_idx, mtu);
 }
 
+void AclArbiter::OnEattBearerOpen(uint8_t tcb_idx, uint16_t cid,
+                                  uint16_t mtu) {
+#ifdef TARGET_FLOSS
+  return;
+#endif
+  log::info("Notifying Rust of EATT bearer 0x{:04x} opened with MTU {}", cid,
+            mtu);
+  callbacks_.on_eatt_bearer_open(tcb_idx, cid, mtu);
+}
+
+void AclArbiter::OnEattBearerClose(uint8_t tcb_idx, uint16_t cid) {
+#ifdef TARGET_FLOSS
+  return;
+#endif
+  log::info("Notifying Rust of EATT bearer 0x{:04x} closed", cid);
+  callbacks_.on_eatt_bearer_close(tcb_idx, cid);
+}
+
+InterceptAction AclArbiter::InterceptEattPacket(uint8_t tcb_idx, uint16_t cid,
+                                                const BT_HDR* packet) {
+#ifdef TARGET_FLOSS
+  return InterceptAction::FORWARD;
+#endif
+  log::debug("Intercepting EATT packet on 0x{:04x} and forwarding to Rust",
+             cid);
+
+  uint8_t* packet_start = (uint8_t*)(packet + 1) + packet->offset;
+  uint8_t* packet_end = packet_start + packet->len;
+
+  auto vec = ::rust::Vec<uint8_t>();
+  std::copy(packet_start, packet_end, std::back_inserter(vec));
+  return callbacks_.intercept_eatt_packet(tcb_idx, cid, std::move(vec));
+}
+
+void AclArbiter::OnLinkSecurityChanged(uint8_t tcb_idx) {
+#ifdef TARGET_FLOSS
+  return;
+#endif
+  tGATT_TCB* p_tcb = gatt_get_tcb_by_idx(tcb_idx);
+  if (p_tcb == nullptr) {
+    log::error("Not reporting link security since connection no longer exists");
+    return;
+  }
+
+  // as in gatt_get_link_encrypt_status()
+  auto security_level = LinkSecurityLevel::NO_SECURITY;
+  uint8_t key_size = 0;
+  if (BTM_IsEncrypted(p_tcb->peer_bda, p_tcb->transport) &&
+      BTM_IsLinkKeyKnown(p_tcb->peer_bda, p_tcb->transport)) {
+    security_level = BTM_IsLinkKeyAuthed(p_tcb->peer_bda, p_tcb->transport)
+                         ? LinkSecurityLevel::AUTHENTICATED
+                         : LinkSecurityLevel::ENCRYPTED;
+    key_size = btm_ble_read_sec_key_size(p_tcb->peer_bda);
+  }
+
+  // signed writes are only defined over LE
+  ::rust::Slice<const uint8_t> csrk;
+  uint32_t sign_counter = 0;
+  tBTM_SEC_DEV_REC* p_rec = btm_find_dev(p_tcb->peer_bda);
+  if (p_tcb->transport == BT_TRANSPORT_LE && p_rec != nullptr &&
+      (p_rec->sec_rec.ble_keys.key_type & BTM_LE_KEY_PCSRK)) {
+    csrk = {p_rec->sec_rec.ble_keys.pcsrk.data(),
+            p_rec->sec_rec.ble_keys.pcsrk.size()};
+    sign_counter = p_rec->sec_rec.ble_keys.counter;
+  }
+
+  log::debug("Notifying Rust of link security {} with key size {}",
+             static_cast<int>(security_level), key_size);
+  callbacks_.on_link_security_changed(tcb_idx, security_level, key_size, csrk,
+                                      sign_counter);
+}
+
 void AclArbiter::SendPacketToPeer(uint8_t tcb_idx,
                                   ::rust::Vec<uint8_t> buffer) {
 #ifdef TARGET_FLOSS
@@ -116,8 +234,14 @@ void AclArbiter::SendPacketToPeer(uint8_t tcb_idx,
     std::copy(buffer.begin(), buffer.end(), p);
     p_buf->offset = L2CAP_MIN_OFFSET;
     p_buf->len = buffer.size();
-    if (L2CA_SendFixedChnlData(L2CAP_ATT_CID, p_tcb->peer_bda, p_buf) !=
-        L2CAP_DW_SUCCESS) {
+    if (p_tcb->att_lcid != L2CAP_ATT_CID) {
+      // over BR/EDR, ATT runs on a dynamic channel
+      if (L2CA_DataWrite(p_tcb->att_lcid, p_buf) != L2CAP_DW_SUCCESS) {
+        log::warn("Unable to send L2CAP data peer:{} cid:{} len:{}",
+                  p_tcb->peer_bda, p_tcb->att_lcid, p_buf->len);
+      }
+    } else if (L2CA_SendFixedChnlData(L2CAP_ATT_CID, p_tcb->peer_bda, p_buf) !=
+               L2CAP_DW_SUCCESS) {
       log::warn("Unable to send L2CAP data peer:{} fixed_cid:{} len:{}",
                 p_tcb->peer_bda, L2CAP_ATT_CID, p_buf->len);
     }
@@ -126,17 +250,140 @@ void AclArbiter::SendPacketToPeer(uint8_t tcb_idx,
   }
 }
 
+void AclArbiter::SendPacketToPeerOnChannel(uint8_t tcb_idx, uint16_t cid,
+                                           ::rust::Vec<uint8_t> buffer) {
+#ifdef TARGET_FLOSS
+  return;
+#endif
+  tGATT_TCB* p_tcb = gatt_get_tcb_by_idx(tcb_idx);
+  if (p_tcb != nullptr) {
+    BT_HDR* p_buf =
+        (BT_HDR*)osi_malloc(sizeof(BT_HDR) + buffer.size() + L2CAP_MIN_OFFSET);
+    if (p_buf == nullptr) {
+      log::fatal("OOM when sending packet");
+    }
+    auto p = (uint8_t*)(p_buf + 1) + L2CAP_MIN_OFFSET;
+    std::copy(buffer.begin(), buffer.end(), p);
+    p_buf->offset = L2CAP_MIN_OFFSET;
+    p_buf->len = buffer.size();
+    if (L2CA_DataWrite(cid, p_buf) != L2CAP_DW_SUCCESS) {
+      log::warn("Unable to send L2CAP data peer:{} cid:{} len:{}",
+                p_tcb->peer_bda, cid, p_buf->len);
+    }
+  } else {
+    log::error("Dropping packet since connection no longer exists");
+  }
+}
+
+void AclArbiter::ClosePeerBearer(uint8_t tcb_idx) {
+#ifdef TARGET_FLOSS
+  return;
+#endif
+  tGATT_TCB* p_tcb = gatt_get_tcb_by_idx(tcb_idx);
+  if (p_tcb != nullptr) {
+    if (!gatt_disconnect(p_tcb)) {
+      log::warn("Unable to close ATT bearer peer:{}", p_tcb->peer_bda);
+    }
+  } else {
+    log::error("Not closing bearer since connection no longer exists");
+  }
+}
+
+void AclArbiter::ClosePeerBearerOnChannel(uint8_t tcb_idx, uint16_t cid) {
+#ifdef TARGET_FLOSS
+  return;
+#endif
+  tGATT_TCB* p_tcb = gatt_get_tcb_by_idx(tcb_idx);
+  if (p_tcb != nullptr) {
+    // the channel is owned by the EATT module, which tells us once it is closed
+    bluetooth::eatt::EattExtension::GetInstance()->Disconnect(p_tcb->peer_bda,
+                                                              cid);
+  } else {
+    log::error("Not closing bearer since connection no longer exists");
+  }
+}
+
+void AclArbiter::SetPeerSignCounter(uint8_t tcb_idx, uint32_t sign_counter) {
+#ifdef TARGET_FLOSS
+  return;
+#endif
+  tGATT_TCB* p_tcb = gatt_get_tcb_by_idx(tcb_idx);
+  if (p_tcb == nullptr) {
+    log::error("Not updating sign counter since connection no longer exists");
+    return;
+  }
+  tBTM_SEC_DEV_REC* p_rec = btm_find_dev(p_tcb->peer_bda);
+  if (p_rec == nullptr ||
+      !(p_rec->sec_rec.ble_keys.key_type & BTM_LE_KEY_PCSRK)) {
+    log::warn("Not updating sign counter of peer:{} without a signing key",
+              p_tcb->peer_bda);
+    return;
+  }
+  // the counter only moves forward, as in BTM_BleVerifySignature()
+  if (sign_counter > p_rec->sec_rec.ble_keys.counter) {
+    p_rec->sec_rec.ble_keys.counter = sign_counter;
+  }
+}
+
+void AclArbiter::RequestSecurityElevation(uint8_t tcb_idx,
+                                          LinkSecurityLevel security_level) {
+#ifdef TARGET_FLOSS
+  return;
+#endif
+  tGATT_TCB* p_tcb = gatt_get_tcb_by_idx(tcb_idx);
+  if (p_tcb == nullptr) {
+    log::error("Not elevating security since connection no longer exists");
+    return;
+  }
+
+  // as in gatt_determine_sec_act()
+  tBTM_BLE_SEC_ACT sec_act = BTM_BLE_SEC_ENCRYPT;
+  if (security_level == LinkSecurityLevel::AUTHENTICATED) {
+    if (!BTM_IsLinkKeyAuthed(p_tcb->peer_bda, p_tcb->transport)) {
+      sec_act = BTM_BLE_SEC_ENCRYPT_MITM;
+    }
+  } else if (!BTM_IsLinkKeyKnown(p_tcb->peer_bda, p_tcb->transport)) {
+    sec_act = BTM_BLE_SEC_ENCRYPT_NO_MITM;
+  }
+
+  tBTM_STATUS status =
+      BTM_SetEncryption(p_tcb->peer_bda, p_tcb->transport,
+                        OnSecurityElevationComplete, nullptr, sec_act);
+  if (status != BTM_SUCCESS && status != BTM_CMD_STARTED) {
+    log::warn("Unable to elevate security of peer:{} status:{}",
+              p_tcb->peer_bda, status);
+    // the completion callback may never come, so Rust must not wait for it
+    OnLinkSecurityChanged(tcb_idx);
+  }
+}
+
 void StoreCallbacksFromRust(
     ::rust::Fn<void(uint8_t tcb_idx, uint8_t advertiser)> on_le_connect,
     ::rust::Fn<void(uint8_t tcb_idx)> on_le_disconnect,
+    ::rust::Fn<void(uint8_t tcb_idx, uint16_t mtu)> on_br_edr_connect,
+    ::rust::Fn<void(uint8_t tcb_idx)> on_br_edr_disconnect,
     ::rust::Fn<InterceptAction(uint8_t tcb_idx, ::rust::Vec<uint8_t> buffer)>
         intercept_packet,
     ::rust::Fn<void(uint8_t tcb_idx)> on_outgoing_mtu_req,
     ::rust::Fn<void(uint8_t tcb_idx, size_t mtu)> on_incoming_mtu_resp,
-    ::rust::Fn<void(uint8_t tcb_idx, size_t mtu)> on_incoming_mtu_req) {
+    ::rust::Fn<void(uint8_t tcb_idx, size_t mtu)> on_incoming_mtu_req,
+    ::rust::Fn<void(uint8_t tcb_idx, uint16_t cid, uint16_t mtu)>
+        on_eatt_bearer_open,
+    ::rust::Fn<void(uint8_t tcb_idx, uint16_t cid)> on_eatt_bearer_close,
+    ::rust::Fn<InterceptAction(uint8_t tcb_idx, uint16_t cid,
+                               ::rust::Vec<uint8_t> buffer)>
+        intercept_eatt_packet,
+    ::rust::Fn<void(uint8_t tcb_idx, LinkSecurityLevel security_level,
+                    uint8_t key_size, ::rust::Slice<const uint8_t> csrk,
+                    uint32_t sign_counter)>
+        on_link_security_changed) {
   log::info("Received callbacks from Rust, registering in Arbiter");
-  callbacks_ = {on_le_connect,       on_le_disconnect,     intercept_packet,
-                on_outgoing_mtu_req, on_incoming_mtu_resp, on_incoming_mtu_req};
+  callbacks_ = {on_le_connect,         on_le_disconnect,
+                on_br_edr_connect,     on_br_edr_disconnect,
+                intercept_packet,      on_outgoing_mtu_req,
+                on_incoming_mtu_resp,  on_incoming_mtu_req,
+                on_eatt_bearer_open,   on_eatt_bearer_close,
+                intercept_eatt_packet, on_link_security_changed};
 }
 
 void SendPacketToPeer(uint8_t tcb_idx, ::rust::Vec<uint8_t> buffer) {
@@ -145,6 +392,41 @@ void SendPacketToPeer(uint8_t tcb_idx, ::rust::Vec<uint8_t> buffer) {
                                               tcb_idx, std::move(buffer)));
 }
 
+void SendPacketToPeerOnChannel(uint8_t tcb_idx, uint16_t cid,
+                               ::rust::Vec<uint8_t> buffer) {
+  do_in_main_thread(FROM_HERE,
+                    base::BindOnce(&AclArbiter::SendPacketToPeerOnChannel,
+                                   base::Unretained(&GetArbiter()), tcb_idx, cid,
+                                   std::move(buffer)));
+}
+
+void ClosePeerBearer(uint8_t tcb_idx) {
+  do_in_main_thread(FROM_HERE, base::BindOnce(&AclArbiter::ClosePeerBearer,
+                                              base::Unretained(&GetArbiter()),
+                                              tcb_idx));
+}
+
+void ClosePeerBearerOnChannel(uint8_t tcb_idx, uint16_t cid) {
+  do_in_main_thread(FROM_HERE,
+                    base::BindOnce(&AclArbiter::ClosePeerBearerOnChannel,
+                                   base::Unretained(&GetArbiter()), tcb_idx,
+                                   cid));
+}
+
+void SetPeerSignCounter(uint8_t tcb_idx, uint32_t sign_counter) {
+  do_in_main_thread(FROM_HERE, base::BindOnce(&AclArbiter::SetPeerSignCounter,
+                                              base::Unretained(&GetArbiter()),
+                                              tcb_idx, sign_counter));
+}
+
+void RequestSecurityElevation(uint8_t tcb_idx,
+                              LinkSecurityLevel security_level) {
+  do_in_main_thread(FROM_HERE,
+                    base::BindOnce(&AclArbiter::RequestSecurityElevation,
+                                   base::Unretained(&GetArbiter()), tcb_idx,
+                                   security_level));
+}
+
 AclArbiter& GetArbiter() {
   static auto singleton = AclArbiter();
   return singleton;
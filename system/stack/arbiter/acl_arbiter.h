@@ -37,17 +37,44 @@ enum class InterceptAction {
   DROP
 };
 
+enum class LinkSecurityLevel {
+  /// The link is not encrypted
+  NO_SECURITY,
+  /// The link is encrypted with an unauthenticated key
+  ENCRYPTED,
+  /// The link is encrypted with an authenticated (MITM-protected) key
+  AUTHENTICATED
+};
+
 class AclArbiter {
  public:
   void OnLeConnect(uint8_t tcb_idx, uint16_t advertiser_id);
   void OnLeDisconnect(uint8_t tcb_idx);
+  void OnBrEdrConnect(uint8_t tcb_idx, uint16_t mtu);
+  void OnBrEdrDisconnect(uint8_t tcb_idx);
   InterceptAction InterceptAttPacket(uint8_t tcb_idx, const BT_HDR* packet);
 
   void OnOutgoingMtuReq(uint8_t tcb_idx);
   void OnIncomingMtuResp(uint8_t tcb_idx, size_t mtu);
   void OnIncomingMtuReq(uint8_t tcb_idx, size_t mtu);
 
+  void OnEattBearerOpen(uint8_t tcb_idx, uint16_t cid, uint16_t mtu);
+  void OnEattBearerClose(uint8_t tcb_idx, uint16_t cid);
+  InterceptAction InterceptEattPacket(uint8_t tcb_idx, uint16_t cid,
+                                      const BT_HDR* packet);
+
+  void OnLinkSecurityChanged(uint8_t tcb_idx);
+
   void SendPacketToPeer(uint8_t tcb_idx, ::rust::Vec<uint8_t> buffer);
+  void SendPacketToPeerOnChannel(uint8_t tcb_idx, uint16_t cid,
+                                 ::rust::Vec<uint8_t> buffer);
+
+  void ClosePeerBearer(uint8_t tcb_idx);
+  void ClosePeerBearerOnChannel(uint8_t tcb_idx, uint16_t cid);
+
+  void SetPeerSignCounter(uint8_t tcb_idx, uint32_t sign_counter);
+  void RequestSecurityElevation(uint8_t tcb_idx,
+                                LinkSecurityLevel security_level);
 
   AclArbiter() = default;
   AclArbiter(AclArbiter&& other) = default;
@@ -58,13 +85,34 @@ class AclArbiter {
 void StoreCallbacksFromRust(
     ::rust::Fn<void(uint8_t tcb_idx, uint8_t advertiser)> on_le_connect,
     ::rust::Fn<void(uint8_t tcb_idx)> on_le_disconnect,
+    ::rust::Fn<void(uint8_t tcb_idx, uint16_t mtu)> on_br_edr_connect,
+    ::rust::Fn<void(uint8_t tcb_idx)> on_br_edr_disconnect,
     ::rust::Fn<InterceptAction(uint8_t tcb_idx, ::rust::Vec<uint8_t> buffer)>
         intercept_packet,
     ::rust::Fn<void(uint8_t tcb_idx)> on_outgoing_mtu_req,
     ::rust::Fn<void(uint8_t tcb_idx, size_t mtu)> on_incoming_mtu_resp,
-    ::rust::Fn<void(uint8_t tcb_idx, size_t mtu)> on_incoming_mtu_req);
+    ::rust::Fn<void(uint8_t tcb_idx, size_t mtu)> on_incoming_mtu_req,
+    ::rust::Fn<void(uint8_t tcb_idx, uint16_t cid, uint16_t mtu)>
+        on_eatt_bearer_open,
+    ::rust::Fn<void(uint8_t tcb_idx, uint16_t cid)> on_eatt_bearer_close,
+    ::rust::Fn<InterceptAction(uint8_t tcb_idx, uint16_t cid,
+                               ::rust::Vec<uint8_t> buffer)>
+        intercept_eatt_packet,
+    ::rust::Fn<void(uint8_t tcb_idx, LinkSecurityLevel security_level,
+                    uint8_t key_size, ::rust::Slice<const uint8_t> csrk,
+                    uint32_t sign_counter)>
+        on_link_security_changed);
 
 void SendPacketToPeer(uint8_t tcb_idx, ::rust::Vec<uint8_t> buffer);
+void SendPacketToPeerOnChannel(uint8_t tcb_idx, uint16_t cid,
+                               ::rust::Vec<uint8_t> buffer);
+
+void ClosePeerBearer(uint8_t tcb_idx);
+void ClosePeerBearerOnChannel(uint8_t tcb_idx, uint16_t cid);
+
+void SetPeerSignCounter(uint8_t tcb_idx, uint32_t sign_counter);
+void RequestSecurityElevation(uint8_t tcb_idx,
+                              LinkSecurityLevel security_level);
 
 AclArbiter& GetArbiter();
 
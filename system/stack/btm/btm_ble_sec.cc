@@ -941,6 +941,7 @@ void btm_sec_save_le_key(const RawAddress& bd_addr, tBTM_LE_KEY_TYPE key_type,
             p_rec->sec_rec.ble_keys.key_type, p_rec->sec_rec.sec_flags,
             p_rec->sec_rec.ble_keys.srk_sec_level,
             p_rec->sec_rec.ble_keys.counter);
+        gatt_notify_peer_signing_key(bd_addr);
         break;
 
       case BTM_LE_KEY_LENC:
@@ -28,6 +28,7 @@
 #include "main/shim/entry.h"
 #include "osi/include/alarm.h"
 #include "osi/include/allocator.h"
+#include "stack/arbiter/acl_arbiter.h"
 #include "stack/btm/btm_sec.h"
 #include "stack/gatt/gatt_int.h"
 #include "stack/include/bt_hdr.h"
@@ -111,8 +112,26 @@ struct eatt_impl {
     return (it == eatt_dev->eatt_channels.end()) ? nullptr : it->second.get();
   }
 
+  /* Let the Rust GATT server know of the channels it may serve */
+  void notify_channel_opened(eatt_device* eatt_dev, EattChannel* channel) {
+    shim::arbiter::GetArbiter().OnEattBearerOpen(
+        eatt_dev->eatt_tcb_->tcb_idx, channel->cid_,
+        std::min(channel->tx_mtu_, channel->rx_mtu_));
+  }
+
+  void notify_channel_closed(eatt_device* eatt_dev, EattChannel* channel) {
+    /* Channels that were never opened were not notified either */
+    if (channel->state_ == EattChannelState::EATT_CHANNEL_PENDING ||
+        eatt_dev->eatt_tcb_ == nullptr) {
+      return;
+    }
+    shim::arbiter::GetArbiter().OnEattBearerClose(
+        eatt_dev->eatt_tcb_->tcb_idx, channel->cid_);
+  }
+
   void remove_channel_by_cid(eatt_device* eatt_dev, uint16_t lcid) {
     auto channel = eatt_dev->eatt_channels[lcid];
+    notify_channel_closed(eatt_dev, channel.get());
     if (!channel->cl_cmd_q_.empty()) {
       log::warn("Channel {:c}, for device {} is not empty on disconnection.",
                 lcid, channel->bda_);
@@ -183,6 +202,7 @@ struct eatt_impl {
 
       chan->EattChannelSetState(EattChannelState::EATT_CHANNEL_OPENED);
       eatt_dev->eatt_tcb_->eatt++;
+      notify_channel_opened(eatt_dev, chan.get());
 
       log::info("Channel connected CID 0x{:x}", cid);
     }
@@ -421,6 +441,7 @@ struct eatt_impl {
     log::assert_that(eatt_dev->bda_ == channel->bda_,
                      "assert failed: eatt_dev->bda_ == channel->bda_");
     eatt_dev->eatt_tcb_->eatt++;
+    notify_channel_opened(eatt_dev, channel);
 
     log::info("Channel connected CID 0x{:04x}", lcid);
 
@@ -531,6 +552,14 @@ struct eatt_impl {
       return;
     }
 
+    if (shim::arbiter::GetArbiter().InterceptEattPacket(
+            eatt_dev->eatt_tcb_->tcb_idx, lcid, data_p) ==
+        shim::arbiter::InterceptAction::DROP) {
+      /* Handled by the Rust GATT server */
+      osi_free(data_p);
+      return;
+    }
+
     gatt_data_process(*eatt_dev->eatt_tcb_, channel->cid_, data_p);
     osi_free(data_p);
   }
@@ -931,6 +960,7 @@ struct eatt_impl {
     auto iter = eatt_dev->eatt_channels.begin();
     while (iter != eatt_dev->eatt_channels.end()) {
       uint16_t cid = iter->first;
+      notify_channel_closed(eatt_dev, iter->second.get());
       disconnect_channel(cid);
       /* When initiating disconnection, stack will not notify us that it is
        * done. We need to assume success
@@ -30,6 +30,7 @@
 #include "internal_include/bt_target.h"
 #include "osi/include/allocator.h"
 #include "osi/include/osi.h"
+#include "stack/arbiter/acl_arbiter.h"
 #include "stack/btm/btm_ble_sec.h"
 #include "stack/btm/btm_sec.h"
 #include "stack/include/bt_hdr.h"
@@ -215,6 +216,8 @@ void gatt_notify_enc_cmpl(const RawAddress& bd_addr) {
     return;
   }
 
+  bluetooth::shim::arbiter::GetArbiter().OnLinkSecurityChanged(p_tcb->tcb_idx);
+
   for (uint8_t i = 0; i < GATT_MAX_APPS; i++) {
     if (gatt_cb.cl_rcb[i].in_use && gatt_cb.cl_rcb[i].app_cb.p_enc_cmpl_cb) {
       (*gatt_cb.cl_rcb[i].app_cb.p_enc_cmpl_cb)(gatt_cb.cl_rcb[i].gatt_if,
@@ -235,6 +238,26 @@ void gatt_notify_enc_cmpl(const RawAddress& bd_addr) {
     p_tcb->pending_enc_clcb = new_pending_clcbs;
   }
 }
+
+/*******************************************************************************
+ *
+ * Function         gatt_notify_peer_signing_key
+ *
+ * Description      notification that the peer has distributed its signing key
+ *                  (CSRK), which is usually done after the link is encrypted.
+ *
+ * Returns
+ *
+ ******************************************************************************/
+void gatt_notify_peer_signing_key(const RawAddress& bd_addr) {
+  tGATT_TCB* p_tcb = gatt_find_tcb_by_addr(bd_addr, BT_TRANSPORT_LE);
+  if (!p_tcb) {
+    log::verbose("notify GATT for signing key of unknown device");
+    return;
+  }
+
+  bluetooth::shim::arbiter::GetArbiter().OnLinkSecurityChanged(p_tcb->tcb_idx);
+}
 /*******************************************************************************
  *
  * Function         gatt_set_sec_act
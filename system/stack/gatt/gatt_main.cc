@@ -858,6 +858,9 @@ void gatt_l2cif_config_cfm_cback(uint16_t lcid, uint16_t /* initiator */,
 
   /* send callback */
   gatt_send_conn_cback(p_tcb);
+
+  bluetooth::shim::arbiter::GetArbiter().OnBrEdrConnect(p_tcb->tcb_idx,
+                                                        p_tcb->payload_size);
 }
 
 /** This is the L2CAP config indication callback function */
@@ -883,6 +886,8 @@ void gatt_l2cif_disconnect_ind_cback(uint16_t lcid, bool /* ack_needed */) {
     if (btm_sec_is_a_bonded_dev(p_tcb->peer_bda))
       gatt_add_a_bonded_dev_for_srv_chg(p_tcb->peer_bda);
   }
+  bluetooth::shim::arbiter::GetArbiter().OnBrEdrDisconnect(p_tcb->tcb_idx);
+
   /* send disconnect callback */
   gatt_cleanup_upon_disc(p_tcb->peer_bda, GATT_CONN_TERMINATE_PEER_USER,
                          BT_TRANSPORT_BR_EDR);
@@ -903,6 +908,8 @@ static void gatt_l2cif_disconnect(uint16_t lcid) {
       gatt_add_a_bonded_dev_for_srv_chg(p_tcb->peer_bda);
   }
 
+  bluetooth::shim::arbiter::GetArbiter().OnBrEdrDisconnect(p_tcb->tcb_idx);
+
   gatt_cleanup_upon_disc(p_tcb->peer_bda, GATT_CONN_TERMINATE_LOCAL_HOST,
                          BT_TRANSPORT_BR_EDR);
 }
@@ -912,8 +919,13 @@ static void gatt_l2cif_data_ind_cback(uint16_t lcid, BT_HDR* p_buf) {
   /* look up clcb for this channel */
   tGATT_TCB* p_tcb = gatt_find_tcb_by_cid(lcid);
   if (p_tcb && gatt_get_ch_state(p_tcb) == GATT_CH_OPEN) {
-    /* process the data */
-    gatt_data_process(*p_tcb, lcid, p_buf);
+    auto decision = bluetooth::shim::arbiter::GetArbiter().InterceptAttPacket(
+        p_tcb->tcb_idx, p_buf);
+
+    /* process the data, unless it was handled by the Rust GATT server */
+    if (decision != bluetooth::shim::arbiter::InterceptAction::DROP) {
+      gatt_data_process(*p_tcb, lcid, p_buf);
+    }
   }
 
   osi_free(p_buf);
@@ -1312,6 +1312,9 @@ void gatt_free(void);
 // initiated outside GATT.
 void gatt_notify_enc_cmpl(const RawAddress& bd_addr);
 
+// Notification that the peer has distributed its signing key (CSRK).
+void gatt_notify_peer_signing_key(const RawAddress& bd_addr);
+
 /** Reset bg device list. If called after controller reset, set |after_reset| to
  * true, as there is no need to wipe controller acceptlist in this case. */
 void gatt_reset_bgdev_list(bool after_reset);
@@ -25,6 +25,10 @@ void AclArbiter::OnLeConnect(uint8_t /* tcb_idx */,
 
 void AclArbiter::OnLeDisconnect(uint8_t /* tcb_idx */) {}
 
+void AclArbiter::OnBrEdrConnect(uint8_t /* tcb_idx */, uint16_t /* mtu */) {}
+
+void AclArbiter::OnBrEdrDisconnect(uint8_t /* tcb_idx */) {}
+
 InterceptAction AclArbiter::InterceptAttPacket(uint8_t /* tcb_idx */,
                                                const BT_HDR* /* packet */) {
   return InterceptAction::FORWARD;
@@ -36,6 +40,19 @@ void AclArbiter::OnIncomingMtuResp(uint8_t /* tcb_idx */, size_t /* mtu */) {}
 
 void AclArbiter::OnIncomingMtuReq(uint8_t /* tcb_idx */, size_t /* mtu */) {}
 
+void AclArbiter::OnEattBearerOpen(uint8_t /* tcb_idx */, uint16_t /* cid */,
+                                  uint16_t /* mtu */) {}
+
+void AclArbiter::OnEattBearerClose(uint8_t /* tcb_idx */, uint16_t /* cid */) {}
+
+InterceptAction AclArbiter::InterceptEattPacket(uint8_t /* tcb_idx */,
+                                                uint16_t /* cid */,
+                                                const BT_HDR* /* packet */) {
+  return InterceptAction::FORWARD;
+}
+
+void AclArbiter::OnLinkSecurityChanged(uint8_t /* tcb_idx */) {}
+
 AclArbiter& GetArbiter() {
   static auto singleton = AclArbiter();
   return singleton;
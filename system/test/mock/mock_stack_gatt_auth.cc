@@ -45,6 +45,9 @@ tGATT_STATUS gatt_get_link_encrypt_status(tGATT_TCB& /* tcb */) {
 void gatt_notify_enc_cmpl(const RawAddress& /* bd_addr */) {
   inc_func_call_count(__func__);
 }
+void gatt_notify_peer_signing_key(const RawAddress& /* bd_addr */) {
+  inc_func_call_count(__func__);
+}
 void gatt_set_sec_act(tGATT_TCB* /* p_tcb */, tGATT_SEC_ACTION /* sec_act */) {
   inc_func_call_count(__func__);
 }